@@ -0,0 +1,114 @@
+// Measures ping round trips to a node while it is flooded with store requests
+// whose values have to be verified first, and lookups by the flooding node
+// itself, once with Rpc priorities and once with every message in order.
+//
+//     cargo run --release --example rpc_latency
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use noktulo::crypto::SecretKey;
+use noktulo::kad::{IdScheme, Key, NetworkDescriptor, Node, NodeInfo, Rpc, TOKEN_KEY_LEN};
+use noktulo::user::history::HeadRecord;
+use noktulo::user::post_id::PostId;
+use noktulo::user::user::Address;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, Mutex};
+
+const STORES: usize = 200;
+const PINGS: usize = 50;
+
+async fn node(bootstrap: &[NodeInfo], prioritized: bool) -> Node {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut rpc = Rpc::new(socket);
+    rpc.set_prioritized(prioritized);
+    let rpc = Arc::new(Mutex::new(rpc));
+    let (tx, _rx) = mpsc::unbounded_channel();
+    let network = NetworkDescriptor::new("latency", TOKEN_KEY_LEN, IdScheme::Random)
+        .with_validator(Arc::new(|data| {
+            HeadRecord::from_bytes(data)
+                .and_then(|head| head.verify())
+                .is_ok()
+        }));
+    Node::start(&network, Key::random(TOKEN_KEY_LEN), rpc, tx, bootstrap).await
+}
+
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    sorted[(sorted.len() - 1) * p / 100]
+}
+
+fn report(what: &str, mut latencies: Vec<Duration>, timeouts: usize) {
+    latencies.sort();
+    println!("  {} {}s, {} timed out", latencies.len() + timeouts, what, timeouts);
+    if !latencies.is_empty() {
+        println!(
+            "  {} latency p50 {:?}, p99 {:?}, max {:?}",
+            what,
+            percentile(&latencies, 50),
+            percentile(&latencies, 99),
+            latencies.last().unwrap()
+        );
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    for prioritized in [true, false] {
+        println!("{}:", if prioritized { "prioritized" } else { "in order" });
+        run(prioritized).await;
+    }
+}
+
+async fn run(prioritized: bool) {
+    let target = node(&[], prioritized).await;
+    let target_info = target.node_info().clone();
+    let flooder = node(std::slice::from_ref(&target_info), prioritized).await;
+    let pinger = node(std::slice::from_ref(&target_info), prioritized).await;
+
+    let sk = SecretKey::random();
+    let addr = Address::from(sk.public_key());
+    let signature = sk.sign(&HeadRecord::signed_message(&addr, PostId(1), 0));
+    let record = HeadRecord::new(&sk.public_key(), PostId(1), 0, signature).to_bytes();
+
+    let started = Instant::now();
+    let mut stores = Vec::new();
+    for _ in 0..STORES {
+        let flooder = flooder.clone();
+        let target_info = target_info.clone();
+        let record = record.clone();
+        stores.push(tokio::spawn(async move {
+            flooder
+                .store(target_info, Key::random(TOKEN_KEY_LEN), &record)
+                .await
+        }));
+    }
+
+    let mut latencies = Vec::new();
+    let mut lookups = Vec::new();
+    let (mut timeouts, mut lookup_timeouts) = (0, 0);
+    for _ in 0..PINGS {
+        let sent = Instant::now();
+        match pinger.ping(target_info.clone()).await {
+            Some(()) => latencies.push(sent.elapsed()),
+            None => timeouts += 1,
+        }
+        // queued behind the flooder's own stores unless prioritized
+        let sent = Instant::now();
+        let id = Key::random(TOKEN_KEY_LEN);
+        match flooder.find_node(target_info.clone(), id).await {
+            Some(_) => lookups.push(sent.elapsed()),
+            None => lookup_timeouts += 1,
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let mut stored = 0;
+    for store in stores {
+        if let Ok(Some(())) = store.await {
+            stored += 1;
+        }
+    }
+
+    println!("  {} stores answered in {:?}", stored, started.elapsed());
+    report("ping", latencies, timeouts);
+    report("lookup", lookups, lookup_timeouts);
+}
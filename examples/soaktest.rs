@@ -0,0 +1,401 @@
+// Runs a network of in-process nodes for a long while: the stable ones each
+// publish as an author, every node follows a few of the authors, and the
+// others leave and are replaced on a schedule. Fails when too few posts reach
+// the followers which were up for them, when a node's pending requests pile
+// up or leak, or when the memory of the process grows past its ceiling.
+//
+//     cargo run --release --example soaktest -- --nodes 20 --minutes 180
+//
+// Every node runs on a runtime of its own, so that leaving drops its tasks
+// and sockets as an exit would.
+use std::collections::{HashMap, HashSet};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use noktulo::kad::PENDING_LIMIT;
+use noktulo::prelude::*;
+use noktulo::service::BootstrapAddr;
+use noktulo::user::post::PostRef;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use tokio::runtime::{Builder, Runtime};
+use tokio::sync::broadcast::error::RecvError;
+
+// authors each node follows
+const FOLLOWS: usize = 3;
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(5);
+// a follower counts from this long after it subscribed
+const SETTLE: Duration = Duration::from_secs(20);
+// and a post is counted as lost when it did not arrive within this
+const GRACE: Duration = Duration::from_secs(30);
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Options {
+    nodes: usize,
+    // the first ones, which publish and never leave
+    stable: usize,
+    minutes: u64,
+    churn_secs: u64,
+    min_delivery: f64,
+    max_rss_mb: u64,
+    // the last RSS over the one a quarter into the run
+    max_growth: f64,
+}
+
+impl Options {
+    fn from_args() -> Options {
+        let mut options = Options {
+            nodes: 12,
+            stable: 4,
+            minutes: 30,
+            churn_secs: 30,
+            min_delivery: 0.9,
+            max_rss_mb: 1024,
+            max_growth: 1.5,
+        };
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        for pair in args.chunks(2) {
+            let value = pair.get(1).map(String::as_str).unwrap_or("");
+            let parsed = match pair[0].as_str() {
+                "--nodes" => value.parse().map(|v| options.nodes = v).is_ok(),
+                "--stable" => value.parse().map(|v| options.stable = v).is_ok(),
+                "--minutes" => value.parse().map(|v| options.minutes = v).is_ok(),
+                "--churn-secs" => value.parse().map(|v| options.churn_secs = v).is_ok(),
+                "--min-delivery" => value.parse().map(|v| options.min_delivery = v).is_ok(),
+                "--max-rss-mb" => value.parse().map(|v| options.max_rss_mb = v).is_ok(),
+                "--max-growth" => value.parse().map(|v| options.max_growth = v).is_ok(),
+                _ => false,
+            };
+            if !parsed {
+                eprintln!("Invalid argument {} {}", pair[0], value);
+                std::process::exit(2);
+            }
+        }
+        if options.stable == 0 || options.stable > options.nodes {
+            eprintln!("--stable has to be between 1 and --nodes");
+            std::process::exit(2);
+        }
+        options
+    }
+}
+
+// one run of a node, from its start until it leaves
+struct Life {
+    follows: Vec<Address>,
+    ready_at: Instant,
+    left_at: Option<Instant>,
+}
+
+// What was published and who received it. Posts are settled once GRACE has
+// passed, so that the ledger itself stays small over a long run.
+#[derive(Default)]
+struct Ledger {
+    lives: Vec<Life>,
+    published: HashMap<PostRef, Instant>,
+    // the lives each post reached, and when it first arrived anywhere
+    received: HashMap<PostRef, (Instant, HashSet<usize>)>,
+    expected: u64,
+    delivered: u64,
+}
+
+impl Ledger {
+    fn receive(&mut self, life: usize, post_ref: PostRef) {
+        let entry = self
+            .received
+            .entry(post_ref)
+            .or_insert_with(|| (Instant::now(), HashSet::new()));
+        entry.1.insert(life);
+    }
+
+    fn settle(&mut self, now: Instant) {
+        let due: Vec<(PostRef, Instant)> = self
+            .published
+            .iter()
+            .filter(|(_, at)| **at + GRACE <= now)
+            .map(|(post_ref, at)| (post_ref.clone(), *at))
+            .collect();
+        for (post_ref, at) in due {
+            self.published.remove(&post_ref);
+            let reached = self.received.remove(&post_ref).map(|(_, lives)| lives);
+            for (i, life) in self.lives.iter().enumerate() {
+                let up = life.ready_at <= at && life.left_at.is_none_or(|left| left >= at + GRACE);
+                if up && life.follows.contains(&post_ref.addr) {
+                    self.expected += 1;
+                    if reached.as_ref().is_some_and(|lives| lives.contains(&i)) {
+                        self.delivered += 1;
+                    }
+                }
+            }
+        }
+        // arrivals of posts whose publish was never recorded
+        self.received
+            .retain(|_, (first, _)| *first + GRACE * 2 > now);
+    }
+
+    fn ratio(&self) -> f64 {
+        match self.expected {
+            0 => 1.0,
+            expected => self.delivered as f64 / expected as f64,
+        }
+    }
+}
+
+struct SoakNode {
+    runtime: Runtime,
+    controller: Arc<NetworkController>,
+    life: usize,
+}
+
+impl SoakNode {
+    fn start(
+        bootstrap: Option<SocketAddr>,
+        follows: Vec<Address>,
+        ledger: &Arc<Mutex<Ledger>>,
+    ) -> (SoakNode, SocketAddr) {
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let (config, nodeinfo_addr) = config(bootstrap);
+        let controller = Arc::new(runtime.block_on(NetworkController::init(config)));
+        let life = {
+            let mut ledger = ledger.lock().unwrap();
+            ledger.lives.push(Life {
+                follows: follows.clone(),
+                ready_at: Instant::now() + SETTLE,
+                left_at: None,
+            });
+            ledger.lives.len() - 1
+        };
+
+        let (net, ledger) = (controller.clone(), ledger.clone());
+        runtime.spawn(async move {
+            let subscriber = net.subscriptions().await.subscriber();
+            let mut rx = subscriber.get_receiver();
+            let mut handles = Vec::new();
+            for addr in follows {
+                handles.push(subscriber.subscribe(addr).await);
+            }
+            loop {
+                match rx.recv().await {
+                    Ok(sigpost) => ledger.lock().unwrap().receive(life, sigpost.post_ref()),
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+        let node = SoakNode {
+            runtime,
+            controller,
+            life,
+        };
+        (node, nodeinfo_addr)
+    }
+
+    // publishes as the author every PUBLISH_INTERVAL
+    fn publish(&self, mut author: UserHandle, ledger: &Arc<Mutex<Ledger>>) {
+        let (net, ledger) = (self.controller.clone(), ledger.clone());
+        self.runtime.spawn(async move {
+            let publisher = net.register_author(&author.registration().unwrap()).await;
+            for i in 0u64.. {
+                let jitter = rand::thread_rng().gen_range(0..1000);
+                tokio::time::sleep(PUBLISH_INTERVAL + Duration::from_millis(jitter)).await;
+                let sigpost = author
+                    .hoot(format!("soak {}", i), None, None, vec![])
+                    .unwrap();
+                let msg = serde_json::to_vec(&sigpost).unwrap();
+                if publisher.publish(&msg, &sigpost.addr).await.is_ok() {
+                    let mut ledger = ledger.lock().unwrap();
+                    ledger.published.insert(sigpost.post_ref(), Instant::now());
+                }
+            }
+        });
+    }
+
+    fn leave(self, ledger: &Arc<Mutex<Ledger>>) {
+        ledger.lock().unwrap().lives[self.life].left_at = Some(Instant::now());
+        self.runtime.shutdown_background();
+    }
+
+    // requests waiting for a reply, and the ones which lost their timeout
+    fn pending(&self) -> (u64, u64) {
+        let counts = self.controller.stats_snapshot().pending_requests;
+        let settled = counts.answered + counts.timed_out + counts.evicted + counts.leaked;
+        (counts.inserted.saturating_sub(settled), counts.leaked)
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn config(bootstrap: Option<SocketAddr>) -> (Config, SocketAddr) {
+    let nodeinfo_addr: SocketAddr = format!("127.0.0.1:{}", free_port()).parse().unwrap();
+    let config = Config {
+        bind_addr: "127.0.0.1:0".parse().unwrap(),
+        // the seed shares one socket, the others do not, so both are soaked
+        pubsub_bind_addr: bootstrap.map(|_| "127.0.0.1:0".parse().unwrap()),
+        nodeinfo_addr: Some(nodeinfo_addr),
+        bootstrap: bootstrap.into_iter().map(BootstrapAddr::from).collect(),
+        bootstrap_lists: Vec::new(),
+        lan_discovery: false,
+        networks: default_networks(),
+        kad: KadConfig::default(),
+        tunables_path: None,
+        port_mapping: false,
+        peer_store_path: None,
+        store_limit: None,
+        bandwidth_limit: None,
+        store_dir: None,
+        rng_seed: None,
+        archive_posts: false,
+        operator_key: None,
+        local_reload: false,
+        node_id_path: None,
+    };
+    (config, nodeinfo_addr)
+}
+
+fn author(name: &str) -> UserHandle {
+    let sk = SecretKey::random();
+    let attr = UserAttribute::new(name, 0, "");
+    let signature = sk.sign(&attr.signed_message());
+    let sig_attr = SignedUserAttribute::new(sk.public_key().into(), attr, signature);
+    UserHandle::new(sig_attr, sk, HashMap::new(), &[])
+}
+
+// the resident memory of the process, where /proc tells it
+fn rss_mb() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb / 1024)
+}
+
+fn fail(reason: String) -> ! {
+    eprintln!("FAILED: {}", reason);
+    std::process::exit(1);
+}
+
+fn main() {
+    env_logger::init();
+    let options = Options::from_args();
+    let ledger = Arc::new(Mutex::new(Ledger::default()));
+    let authors: Vec<UserHandle> = (0..options.stable)
+        .map(|i| author(&format!("author{}", i)))
+        .collect();
+    let addrs: Vec<Address> = authors.iter().map(|author| author.addr()).collect();
+    let follows = || {
+        let mut rng = rand::thread_rng();
+        let n = FOLLOWS.min(addrs.len());
+        addrs
+            .choose_multiple(&mut rng, n)
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+
+    let (seed, seed_addr) = SoakNode::start(None, follows(), &ledger);
+    let mut nodes = vec![seed];
+    for _ in 1..options.nodes {
+        nodes.push(SoakNode::start(Some(seed_addr), follows(), &ledger).0);
+    }
+    for (node, author) in nodes.iter().zip(authors) {
+        node.publish(author, &ledger);
+    }
+    println!(
+        "{} nodes up, {} of them publishing, for {} minutes",
+        options.nodes, options.stable, options.minutes
+    );
+
+    let started = Instant::now();
+    let duration = Duration::from_secs(options.minutes * 60);
+    let churn = Duration::from_secs(options.churn_secs);
+    let (mut next_churn, mut next_report) = (started + churn, started + REPORT_INTERVAL);
+    let (mut restarts, mut warm_rss, mut peak_rss) = (0, None, 0);
+    loop {
+        let now = Instant::now();
+        if now >= started + duration {
+            break;
+        }
+        if now >= next_churn && options.nodes > options.stable {
+            let i = rand::thread_rng().gen_range(options.stable..options.nodes);
+            let (node, _) = SoakNode::start(Some(seed_addr), follows(), &ledger);
+            std::mem::replace(&mut nodes[i], node).leave(&ledger);
+            restarts += 1;
+            next_churn = now + churn;
+        }
+        if now >= next_report {
+            let mut ledger_now = ledger.lock().unwrap();
+            ledger_now.settle(now);
+            let (expected, ratio) = (ledger_now.expected, ledger_now.ratio());
+            drop(ledger_now);
+
+            let mut most_pending = 0;
+            for node in &nodes {
+                let (pending, leaked) = node.pending();
+                if leaked > 0 {
+                    fail(format!(
+                        "{} requests lost their timeout on one node",
+                        leaked
+                    ));
+                }
+                most_pending = most_pending.max(pending);
+            }
+            if most_pending > PENDING_LIMIT as u64 {
+                fail(format!("{} requests pending on one node", most_pending));
+            }
+            let rss = rss_mb();
+            if let Some(rss) = rss {
+                peak_rss = peak_rss.max(rss);
+                if rss > options.max_rss_mb {
+                    fail(format!(
+                        "{} MiB resident, over {} MiB",
+                        rss, options.max_rss_mb
+                    ));
+                }
+                if warm_rss.is_none() && now >= started + duration / 4 {
+                    warm_rss = Some(rss);
+                }
+            }
+            println!(
+                "[{:>4}m] delivered {:.3} of {} expected, {} restarts, at most {} pending, {} MiB",
+                started.elapsed().as_secs() / 60,
+                ratio,
+                expected,
+                restarts,
+                most_pending,
+                rss.map_or("?".to_string(), |rss| rss.to_string())
+            );
+            next_report = now + REPORT_INTERVAL;
+        }
+        std::thread::sleep(Duration::from_millis(200));
+    }
+
+    let mut ledger = ledger.lock().unwrap();
+    ledger.settle(Instant::now());
+    let ratio = ledger.ratio();
+    if ratio < options.min_delivery {
+        fail(format!(
+            "delivered {:.3} of {} expected, under {}",
+            ratio, ledger.expected, options.min_delivery
+        ));
+    }
+    if let (Some(warm), Some(last)) = (warm_rss, rss_mb()) {
+        if last as f64 > warm as f64 * options.max_growth {
+            fail(format!(
+                "{} MiB resident, from {} MiB a quarter in",
+                last, warm
+            ));
+        }
+    }
+    println!(
+        "passed: delivered {:.3} of {} expected, {} restarts, peak {} MiB",
+        ratio, ledger.expected, restarts, peak_rss
+    );
+}
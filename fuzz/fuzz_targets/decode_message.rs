@@ -0,0 +1,13 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use noktulo::kad::fuzz::decode;
+use noktulo::kad::MESSAGE_LEN;
+
+// a datagram as a peer may send it
+fuzz_target!(|data: &[u8]| {
+    if let Ok(rmsg) = decode(data, MESSAGE_LEN) {
+        // whatever was read is written and read again the same way
+        let encoded = serde_json::to_vec(&rmsg).unwrap();
+        assert!(decode(&encoded, usize::MAX).is_ok());
+    }
+});
@@ -0,0 +1,79 @@
+#![no_main]
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use once_cell::sync::Lazy;
+use std::sync::Mutex;
+use tokio::runtime::Runtime;
+
+use noktulo::kad::fuzz::{NodeHarness, Request};
+use noktulo::kad::{Key, MESSAGE_LEN, TOKEN_KEY_LEN};
+
+// Keys of the node's length get past its checks; any others are refused
+#[derive(Debug, Arbitrary)]
+enum FuzzKey {
+    Id([u8; TOKEN_KEY_LEN]),
+    Raw(Vec<u8>),
+}
+
+impl From<FuzzKey> for Key {
+    fn from(k: FuzzKey) -> Key {
+        match k {
+            FuzzKey::Id(id) => Key::from(id),
+            FuzzKey::Raw(k) => Key::from(&k[..k.len().min(MESSAGE_LEN)]),
+        }
+    }
+}
+
+// The requests of a peer, as Rpc hands them over. Payloads are cut to a
+// datagram, like the ones off the socket.
+#[derive(Debug, Arbitrary)]
+enum FuzzRequest {
+    Ping,
+    Store(FuzzKey, Vec<u8>),
+    FindNode(FuzzKey),
+    FindValue(FuzzKey),
+    Unicast(Vec<u8>),
+    Broadcast(Vec<u8>),
+    Multicast(FuzzKey, Vec<u8>),
+    ReceiptedMulticast(FuzzKey, Vec<u8>),
+    Join(FuzzKey),
+    Leave(FuzzKey),
+}
+
+impl From<FuzzRequest> for Request {
+    fn from(req: FuzzRequest) -> Request {
+        let payload = |mut v: Vec<u8>| {
+            v.truncate(MESSAGE_LEN);
+            v
+        };
+        match req {
+            FuzzRequest::Ping => Request::Ping,
+            FuzzRequest::Store(k, v) => Request::Store(k.into(), payload(v)),
+            FuzzRequest::FindNode(k) => Request::FindNode(k.into()),
+            FuzzRequest::FindValue(k) => Request::FindValue(k.into()),
+            FuzzRequest::Unicast(v) => Request::Unicast(payload(v)),
+            FuzzRequest::Broadcast(v) => Request::Broadcast(payload(v)),
+            FuzzRequest::Multicast(k, v) => Request::Multicast(k.into(), payload(v)),
+            FuzzRequest::ReceiptedMulticast(k, v) => {
+                Request::ReceiptedMulticast(k.into(), payload(v))
+            }
+            FuzzRequest::Join(k) => Request::Join(k.into()),
+            FuzzRequest::Leave(k) => Request::Leave(k.into()),
+        }
+    }
+}
+
+// One node for the whole run, so that what it keeps between requests is
+// checked against its limits too
+static RUNTIME: Lazy<Runtime> = Lazy::new(|| Runtime::new().unwrap());
+static HARNESS: Lazy<Mutex<NodeHarness>> =
+    Lazy::new(|| Mutex::new(RUNTIME.block_on(NodeHarness::start())));
+
+fuzz_target!(|input: Vec<(FuzzKey, FuzzRequest)>| {
+    let mut harness = HARNESS.lock().unwrap();
+    RUNTIME.block_on(async {
+        for (src_id, req) in input {
+            harness.handle(req.into(), src_id.into()).await;
+        }
+    });
+});
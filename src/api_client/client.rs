@@ -0,0 +1,287 @@
+use futures::sink::SinkExt;
+use futures::stream::{SplitSink, StreamExt};
+use chrono::Utc;
+use log::warn;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+use crate::api_server::wire::{self, SharedEncoding};
+use crate::api_server::{
+    ClientMessage, ErrorCode, ServerMessage, SignedAdminCommand, UserInfo, WireEncoding,
+};
+use crate::crypto::{Signer, SignerError};
+use crate::service::Recommendation;
+use crate::user::post::SignedPost;
+use crate::user::user::{Address, RegistrationRecord};
+use crate::util::stats::Stats;
+
+type WsSink = SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>;
+
+pub struct ApiClient {
+    sink: WsSink,
+    // every server message except the pushed ones, in order
+    replies: UnboundedReceiver<ServerMessage>,
+    pushes: UnboundedReceiver<ServerMessage>,
+    encoding: SharedEncoding,
+    // the token of the last EstablishedSession, and when it expires in ms
+    session: Option<(String, u64)>,
+}
+
+impl ApiClient {
+    pub async fn connect(url: &str) -> Result<ApiClient, ApiClientError> {
+        let (websocket, _) = connect_async(url).await?;
+        let (sink, mut stream) = websocket.split();
+        let (reply_tx, replies) = mpsc::unbounded_channel();
+        let (push_tx, pushes) = mpsc::unbounded_channel();
+        let encoding = SharedEncoding::default();
+
+        let reader_encoding = encoding.clone();
+        tokio::spawn(async move {
+            while let Some(Ok(msg)) = stream.next().await {
+                if let Message::Text(s) = msg {
+                    let s = wire::decode(&s, reader_encoding.get());
+                    match serde_json::from_str::<ServerMessage>(&s) {
+                        Ok(
+                            msg @ (ServerMessage::Subscribed(_)
+                            | ServerMessage::PostCreated(_)
+                            | ServerMessage::PostDeleted { .. }
+                            | ServerMessage::ProfileUpdated { .. }),
+                        ) => {
+                            let _ = push_tx.send(msg);
+                        }
+                        Ok(msg) => {
+                            if reply_tx.send(msg).is_err() {
+                                break;
+                            }
+                        }
+                        Err(_) => warn!("Malformed message from server, ignoring."),
+                    }
+                }
+            }
+        });
+
+        Ok(ApiClient {
+            sink,
+            replies,
+            pushes,
+            encoding,
+            session: None,
+        })
+    }
+
+    async fn send(&mut self, msg: &ClientMessage) -> Result<(), ApiClientError> {
+        let text = wire::encode(&serde_json::to_string(msg).unwrap(), self.encoding.get());
+        self.sink.send(Message::Text(text)).await?;
+        Ok(())
+    }
+
+    async fn reply(&mut self) -> Result<ServerMessage, ApiClientError> {
+        match self.replies.recv().await {
+            Some(ServerMessage::Error { code, message, .. }) => {
+                Err(ApiClientError::Server(code, message))
+            }
+            Some(msg) => Ok(msg),
+            None => Err(ApiClientError::Closed),
+        }
+    }
+
+    async fn expect_success(&mut self) -> Result<(), ApiClientError> {
+        match self.reply().await? {
+            ServerMessage::Success => Ok(()),
+            msg => Err(ApiClientError::Unexpected(format!("{:?}", msg))),
+        }
+    }
+
+    // asks the server to write byte fields in encoding from its reply on, to
+    // push typed events, see recv_event, and to issue session tokens, see
+    // session
+    pub async fn hello(
+        &mut self,
+        encoding: WireEncoding,
+        typed_events: bool,
+        sessions: bool,
+    ) -> Result<(), ApiClientError> {
+        self.send(&ClientMessage::Hello {
+            encodings: vec![encoding],
+            typed_events,
+            sessions,
+        })
+        .await?;
+        match self.reply().await? {
+            ServerMessage::Hello { encoding, .. } => {
+                self.encoding.set(encoding);
+                Ok(())
+            }
+            msg => Err(ApiClientError::Unexpected(format!("{:?}", msg))),
+        }
+    }
+
+    // runs the challenge handshake for the signer's key, then registers it
+    pub async fn establish(&mut self, signer: &dyn Signer) -> Result<(), ApiClientError> {
+        let pubkey = signer.public_key();
+        self.send(&ClientMessage::EstablishReq {
+            addr: Address::from(pubkey.clone()).into(),
+            pubkey,
+        })
+        .await?;
+
+        let challenge = match self.reply().await? {
+            ServerMessage::Challenge(challenge) => challenge,
+            msg => return Err(ApiClientError::Unexpected(format!("{:?}", msg))),
+        };
+        let sig = signer.sign_async(&challenge).await?;
+        self.send(&ClientMessage::ChallengeResponce(sig)).await?;
+
+        match self.reply().await? {
+            ServerMessage::Established => {}
+            ServerMessage::EstablishedSession { token, expires_at } => {
+                self.session = Some((token, expires_at));
+            }
+            msg => return Err(ApiClientError::Unexpected(format!("{:?}", msg))),
+        }
+
+        let pubkey = signer.public_key();
+        let registered_at = Utc::now().timestamp() as u64;
+        let message = RegistrationRecord::signed_message(&pubkey.clone().into(), registered_at);
+        let signature = signer.sign_async(&message).await?;
+        let record = RegistrationRecord::new(&pubkey, registered_at, signature);
+        self.send(&ClientMessage::Register(record)).await?;
+        self.expect_success().await
+    }
+
+    // establishes the address of token again on a new connection, with the
+    // subscriptions the old one had when it closed
+    pub async fn resume(&mut self, token: &str) -> Result<(), ApiClientError> {
+        self.send(&ClientMessage::Resume(token.to_string())).await?;
+        match self.reply().await? {
+            ServerMessage::EstablishedSession { token, expires_at } => {
+                self.session = Some((token, expires_at));
+                Ok(())
+            }
+            msg => Err(ApiClientError::Unexpected(format!("{:?}", msg))),
+        }
+    }
+
+    // the latest session token and when it expires, if hello asked for them
+    pub fn session(&self) -> Option<&(String, u64)> {
+        self.session.as_ref()
+    }
+
+    pub async fn post(&mut self, sigpost: SignedPost) -> Result<(), ApiClientError> {
+        self.send(&ClientMessage::Post(Box::new(sigpost))).await?;
+        self.expect_success().await
+    }
+
+    pub async fn subscribe(&mut self, addr: Address) -> Result<(), ApiClientError> {
+        self.send(&ClientMessage::SubscribeReq(addr)).await?;
+        self.expect_success().await
+    }
+
+    pub async fn subscribe_filtered(
+        &mut self,
+        addr: Address,
+        filter: &str,
+    ) -> Result<(), ApiClientError> {
+        self.send(&ClientMessage::FilteredSubscribeReq {
+            addr,
+            filter: filter.to_string(),
+        })
+        .await?;
+        self.expect_success().await
+    }
+
+    pub async fn unsubscribe(&mut self, addr: Address) -> Result<(), ApiClientError> {
+        self.send(&ClientMessage::UnsubscribeReq(addr)).await?;
+        self.expect_success().await
+    }
+
+    // the profile of addr, from the server's cache or the user DHT
+    pub async fn user_info(&mut self, addr: Address) -> Result<UserInfo, ApiClientError> {
+        self.send(&ClientMessage::GetUserInfo(addr)).await?;
+        match self.reply().await? {
+            ServerMessage::UserInfo(info) => Ok(info),
+            msg => Err(ApiClientError::Unexpected(format!("{:?}", msg))),
+        }
+    }
+
+    pub async fn stats(&mut self) -> Result<Stats, ApiClientError> {
+        self.send(&ClientMessage::GetStats).await?;
+        match self.reply().await? {
+            ServerMessage::Stats(stats) => Ok(stats),
+            msg => Err(ApiClientError::Unexpected(format!("{:?}", msg))),
+        }
+    }
+
+    pub async fn recommend_follows(
+        &mut self,
+        limit: usize,
+    ) -> Result<Vec<Recommendation>, ApiClientError> {
+        self.send(&ClientMessage::RecommendFollowsReq { limit })
+            .await?;
+        match self.reply().await? {
+            ServerMessage::Recommendations(ret) => Ok(ret),
+            msg => Err(ApiClientError::Unexpected(format!("{:?}", msg))),
+        }
+    }
+
+    // asks the server to read its tunables file again; the names of the
+    // changed tunables are returned
+    pub async fn reload_config(&mut self) -> Result<Vec<String>, ApiClientError> {
+        self.send(&ClientMessage::ReloadConfig).await?;
+        match self.reply().await? {
+            ServerMessage::ConfigReloaded(changed) => Ok(changed),
+            msg => Err(ApiClientError::Unexpected(format!("{:?}", msg))),
+        }
+    }
+
+    // a command signed by the node's operator key; the reply depends on the
+    // command, see AdminCommand
+    pub async fn admin(
+        &mut self,
+        signed: SignedAdminCommand,
+    ) -> Result<ServerMessage, ApiClientError> {
+        self.send(&ClientMessage::Admin(signed)).await?;
+        self.reply().await
+    }
+
+    // Next post delivered for any subscription. Other typed events are
+    // skipped, so use either this or recv_event.
+    pub async fn recv_post(&mut self) -> Option<SignedPost> {
+        loop {
+            match self.pushes.recv().await? {
+                ServerMessage::Subscribed(sigpost) | ServerMessage::PostCreated(sigpost) => {
+                    return Some(sigpost)
+                }
+                ServerMessage::PostDeleted { delete, .. } => return Some(delete),
+                _ => continue,
+            }
+        }
+    }
+
+    // next pushed message: Subscribed, or a typed event if asked for in hello
+    pub async fn recv_event(&mut self) -> Option<ServerMessage> {
+        self.pushes.recv().await
+    }
+
+    // closes the connection, after which its session can be resumed
+    pub async fn close(mut self) -> Result<(), ApiClientError> {
+        self.sink.close().await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ApiClientError {
+    #[error("WebSocket error: {0}")]
+    WebSocket(#[from] tungstenite::error::Error),
+    #[error("Server error ({0:?}): {1}")]
+    Server(ErrorCode, String),
+    #[error("Unexpected message from server: {0}")]
+    Unexpected(String),
+    #[error("Connection closed")]
+    Closed,
+    #[error(transparent)]
+    Signer(#[from] SignerError),
+}
@@ -0,0 +1,159 @@
+use serde::{Deserialize, Serialize};
+use serde_big_array::BigArray;
+use std::net::IpAddr;
+
+use crate::crypto::{PublicKey, Signer, SignerError};
+use crate::kad::Key;
+
+use super::message::ErrorCode;
+use super::ADMIN_COMMAND_TTL;
+
+// What the operator of a headless node asks of it through ClientMessage::Admin
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AdminCommand {
+    // reads the tunables file again, as ReloadConfig; answered with
+    // ConfigReloaded
+    Reload,
+    // drops the DHT traffic of a peer for BAN_TIME; answered with Success
+    BanPeer(IpAddr),
+    // the node logs to stderr, for whatever runs it to rotate, so this is
+    // answered with Unsupported
+    RotateLogs,
+    // answered with Stats
+    DumpStats,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedAdminCommand {
+    pub command: AdminCommand,
+    // the node the command is for, NetworkController::node_id, so that it
+    // cannot be sent to the other nodes of the same operator
+    pub node_id: Key,
+    // ms since the epoch; later than the last command the server took
+    pub issued_at: u64,
+    #[serde(with = "BigArray")]
+    pub signature: [u8; 64],
+}
+
+impl SignedAdminCommand {
+    pub fn new(
+        command: AdminCommand,
+        node_id: Key,
+        issued_at: u64,
+        signer: &dyn Signer,
+    ) -> Result<SignedAdminCommand, SignerError> {
+        let message = SignedAdminCommand::signed_message(&command, &node_id, issued_at);
+        let signature = signer.sign(&message)?;
+        Ok(SignedAdminCommand {
+            command,
+            node_id,
+            issued_at,
+            signature,
+        })
+    }
+
+    // the message signed by the operator key
+    pub fn signed_message(command: &AdminCommand, node_id: &Key, issued_at: u64) -> Vec<u8> {
+        [
+            &b"noktulo:admin:"[..],
+            node_id.as_bytes(),
+            &issued_at.to_be_bytes()[..],
+            &serde_json::to_vec(command).unwrap()[..],
+        ]
+        .concat()
+    }
+}
+
+// Takes the commands signed by the operator key for this node, each once: a
+// command must be issued within ADMIN_COMMAND_TTL of now, and later than the
+// last one taken, so that a command seen on the wire cannot be sent again,
+// here or to another node.
+pub(super) struct AdminGuard {
+    operator: Option<PublicKey>,
+    node_id: Key,
+    last_issued_at: u64,
+}
+
+impl AdminGuard {
+    pub fn new(operator: Option<PublicKey>, node_id: Key) -> AdminGuard {
+        AdminGuard {
+            operator,
+            node_id,
+            last_issued_at: 0,
+        }
+    }
+
+    pub fn accept(&mut self, signed: &SignedAdminCommand, now_ms: u64) -> Result<(), ErrorCode> {
+        let operator = self.operator.as_ref().ok_or(ErrorCode::Forbidden)?;
+        if signed.node_id != self.node_id {
+            return Err(ErrorCode::OtherNode);
+        }
+        let message =
+            SignedAdminCommand::signed_message(&signed.command, &signed.node_id, signed.issued_at);
+        operator
+            .verify(&signed.signature, &message)
+            .map_err(|_| ErrorCode::InvalidSignature)?;
+        if signed.issued_at + ADMIN_COMMAND_TTL <= now_ms
+            || signed.issued_at > now_ms + ADMIN_COMMAND_TTL
+            || signed.issued_at <= self.last_issued_at
+        {
+            return Err(ErrorCode::StaleCommand);
+        }
+        self.last_issued_at = signed.issued_at;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::SecretKey;
+
+    #[test]
+    fn admin_guard_test() {
+        let operator = SecretKey::random();
+        let now = 1700000000000;
+        let node_id = Key::from([3u8; 32]);
+        let mut guard = AdminGuard::new(Some(operator.public_key()), node_id.clone());
+        let sign = |command, issued_at, signer: &SecretKey| {
+            SignedAdminCommand::new(command, node_id.clone(), issued_at, signer).unwrap()
+        };
+        let stats = sign(AdminCommand::DumpStats, now, &operator);
+        assert_eq!(guard.accept(&stats, now), Ok(()));
+        // each once, and in order
+        assert_eq!(guard.accept(&stats, now), Err(ErrorCode::StaleCommand));
+        let earlier = sign(AdminCommand::Reload, now - 1, &operator);
+        assert_eq!(guard.accept(&earlier, now), Err(ErrorCode::StaleCommand));
+        let later = sign(AdminCommand::Reload, now + 1, &operator);
+        assert_eq!(guard.accept(&later, now + ADMIN_COMMAND_TTL), Ok(()));
+        let old = sign(AdminCommand::Reload, now + 2, &operator);
+        assert_eq!(
+            guard.accept(&old, now + 2 + ADMIN_COMMAND_TTL),
+            Err(ErrorCode::StaleCommand)
+        );
+
+        // signed by someone else, or for another command
+        let other = SecretKey::random();
+        let forged = sign(AdminCommand::Reload, now + 3, &other);
+        assert_eq!(guard.accept(&forged, now), Err(ErrorCode::InvalidSignature));
+        let mut swapped = sign(AdminCommand::DumpStats, now + 3, &operator);
+        swapped.command = AdminCommand::BanPeer("10.0.0.1".parse().unwrap());
+        assert_eq!(
+            guard.accept(&swapped, now),
+            Err(ErrorCode::InvalidSignature)
+        );
+
+        // for another node of the same operator, or moved to this one
+        let other_node = Key::from([4u8; 32]);
+        let elsewhere =
+            SignedAdminCommand::new(AdminCommand::Reload, other_node, now + 3, &operator).unwrap();
+        assert_eq!(guard.accept(&elsewhere, now), Err(ErrorCode::OtherNode));
+        let mut moved = elsewhere.clone();
+        moved.node_id = node_id.clone();
+        assert_eq!(guard.accept(&moved, now), Err(ErrorCode::InvalidSignature));
+
+        // no operator key, no commands
+        let mut guard = AdminGuard::new(None, node_id.clone());
+        assert_eq!(guard.accept(&stats, now), Err(ErrorCode::Forbidden));
+    }
+}
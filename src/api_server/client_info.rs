@@ -1,11 +1,16 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex as StdMutex};
 
 use tokio::sync::mpsc::{error::SendError, UnboundedSender};
 use tokio_tungstenite::tungstenite::Message;
 
-use crate::{crypto::PublicKey, user::user::Address};
+use crate::service::Filter;
+use crate::{crypto::PublicKey, user::block::BlockList, user::user::Address};
 
-use super::message::ServerMessage;
+use super::message::{ErrorCode, ServerMessage};
+use super::wire::SharedEncoding;
+use super::PROFILE_LOOKUPS_PER_MINUTE;
 
 #[derive(Clone)]
 enum ClientStatus {
@@ -18,27 +23,97 @@ enum ClientStatus {
 }
 pub struct ClientInfo {
     tx: UnboundedSender<Message>,
+    peer: SocketAddr,
     registered: HashMap<Address, PublicKey>,
-    subscripted: Vec<Address>,
+    // with the filter of each, so that Resume subscribes the same way
+    subscripted: Vec<(Address, Option<Filter>)>,
+    // established addresses pushed their mentions, see ServerMessage::Mentioned
+    mentioned: Vec<Address>,
     status: ClientStatus,
+    encoding: SharedEncoding,
+    // asked for in Hello, see ServerMessage::push
+    typed_events: bool,
+    // asked for in Hello, see ServerMessage::EstablishedSession
+    sessions: bool,
+    // the token last issued to this connection, and when it expires in ms
+    session: Option<(String, u64)>,
+    // when the posts of the last minute were accepted, in ms
+    posted: VecDeque<u64>,
+    // when the profile lookups of the last minute were made, in ms
+    looked_up: VecDeque<u64>,
+    // shared with the routes of this connection, see ClientMessage::Block
+    blocked: Arc<StdMutex<BlockList>>,
+    // see ClientMessage::ListAdd
+    lists: BTreeMap<String, Vec<Address>>,
 }
 
 impl ClientInfo {
-    pub fn new(tx: UnboundedSender<Message>) -> ClientInfo {
+    pub fn new(tx: UnboundedSender<Message>, peer: SocketAddr) -> ClientInfo {
         ClientInfo {
             tx,
+            peer,
             registered: HashMap::new(),
             subscripted: Vec::new(),
+            mentioned: Vec::new(),
             status: ClientStatus::NotEstablished,
+            encoding: SharedEncoding::default(),
+            typed_events: false,
+            sessions: false,
+            session: None,
+            posted: VecDeque::new(),
+            looked_up: VecDeque::new(),
+            blocked: Arc::new(StdMutex::new(BlockList::new())),
+            lists: BTreeMap::new(),
         }
     }
 
+    // connected from the server's own host
+    pub fn is_local(&self) -> bool {
+        self.peer.ip().is_loopback()
+    }
+
+    // Counts a post against per_minute. Err holds the seconds until the
+    // oldest post of the last minute stops counting.
+    pub fn take_post_slot(&mut self, now_ms: u64, per_minute: Option<u32>) -> Result<(), u64> {
+        match per_minute {
+            Some(per_minute) => take_slot(&mut self.posted, now_ms, per_minute),
+            None => Ok(()),
+        }
+    }
+
+    // the same for profile lookups which go to the DHT
+    pub fn take_lookup_slot(&mut self, now_ms: u64) -> Result<(), u64> {
+        take_slot(&mut self.looked_up, now_ms, PROFILE_LOOKUPS_PER_MINUTE)
+    }
+
     pub fn send(&self, msg: Message) -> Result<(), SendError<Message>> {
         self.tx.send(msg)
     }
 
-    pub fn subscripted_list(&mut self) -> &mut Vec<Address> {
-        &mut self.subscripted
+    pub fn add_subscription(&mut self, addr: Address, filter: Option<Filter>) {
+        self.subscripted.push((addr, filter));
+    }
+
+    // every subscription to addr, filtered or not
+    pub fn remove_subscriptions(&mut self, addr: &Address) {
+        self.subscripted.retain(|(a, _)| a != addr);
+    }
+
+    pub fn subscriptions(&self) -> &[(Address, Option<Filter>)] {
+        &self.subscripted
+    }
+
+    pub fn subscripted_addrs(&self) -> Vec<Address> {
+        self.subscripted.iter().map(|(addr, _)| addr.clone()).collect()
+    }
+
+    // false if addr is pushed its mentions already, as it was established before
+    pub fn watch_mentions(&mut self, addr: &Address) -> bool {
+        if self.mentioned.contains(addr) {
+            return false;
+        }
+        self.mentioned.push(addr.clone());
+        true
     }
 
     pub fn send_challenge(
@@ -52,13 +127,15 @@ impl ClientInfo {
         ))
     }
 
-    pub fn send_invalid(&self) -> Result<(), SendError<Message>> {
-        self.send(Message::Text(
-            serde_json::to_string(&ServerMessage::Invalid).unwrap(),
-        ))
+    pub fn send_message(&self, msg: &ServerMessage) -> Result<(), SendError<Message>> {
+        self.send(Message::Text(serde_json::to_string(msg).unwrap()))
     }
 
-    pub fn verify_challenge_sig(&mut self, sig: [u8; 64]) -> Result<PublicKey, ()> {
+    pub fn send_error(&self, code: ErrorCode) -> Result<(), SendError<Message>> {
+        self.send_message(&ServerMessage::error(code))
+    }
+
+    pub fn verify_challenge_sig(&mut self, sig: [u8; 64]) -> Result<PublicKey, ErrorCode> {
         if let ClientStatus::SentChallenge { pubkey, challenge } = self.status.clone() {
             if pubkey.verify(&sig, &challenge[..]).is_ok() {
                 self.registered
@@ -68,22 +145,106 @@ impl ClientInfo {
                 self.status = ClientStatus::Established;
                 Ok(pubkey)
             } else {
-                Err(())
+                Err(ErrorCode::InvalidSignature)
             }
         } else {
-            Err(())
+            Err(ErrorCode::NoPendingChallenge)
         }
     }
 
+    // established with a session token instead of a signed challenge
+    pub fn resume(&mut self, pubkey: PublicKey) {
+        self.registered
+            .entry(Address::from(pubkey.clone()))
+            .or_insert(pubkey);
+        self.status = ClientStatus::Established;
+    }
+
+    pub fn session(&self) -> Option<&(String, u64)> {
+        self.session.as_ref()
+    }
+
+    pub fn set_session(&mut self, token: String, expires_at: u64) {
+        self.session = Some((token, expires_at));
+    }
+
+    // the first address established on this connection
+    pub fn addr(&self) -> Option<Address> {
+        self.registered.keys().next().cloned()
+    }
+
     pub fn is_established(&self) -> bool {
         !self.registered.is_empty()
     }
 
+    pub fn block_list(&self) -> Arc<StdMutex<BlockList>> {
+        self.blocked.clone()
+    }
+
+    pub fn lists(&self) -> &BTreeMap<String, Vec<Address>> {
+        &self.lists
+    }
+
+    pub fn add_to_list(&mut self, list: String, addr: Address) {
+        let members = self.lists.entry(list).or_default();
+        if !members.contains(&addr) {
+            members.push(addr);
+        }
+    }
+
+    // a list is gone with its last member
+    pub fn remove_from_list(&mut self, list: &str, addr: &Address) {
+        if let Some(members) = self.lists.get_mut(list) {
+            members.retain(|member| member != addr);
+            if members.is_empty() {
+                self.lists.remove(list);
+            }
+        }
+    }
+
     pub fn get_sender(&self) -> UnboundedSender<Message> {
         self.tx.clone()
     }
 
+    // messages to the client are encoded in it where they are sent
+    pub fn encoding(&self) -> SharedEncoding {
+        self.encoding.clone()
+    }
+
+    pub fn typed_events(&self) -> bool {
+        self.typed_events
+    }
+
+    pub fn set_typed_events(&mut self, typed_events: bool) {
+        self.typed_events = typed_events;
+    }
+
+    pub fn sessions(&self) -> bool {
+        self.sessions
+    }
+
+    pub fn set_sessions(&mut self, sessions: bool) {
+        self.sessions = sessions;
+    }
+
     pub fn get_pubkey(&self, addr: &Address) -> Option<PublicKey> {
         self.registered.get(addr).map(|pk| pk.clone())
     }
 }
+
+// times holds when the requests of the last minute were made, in ms
+pub(super) fn take_slot(
+    times: &mut VecDeque<u64>,
+    now_ms: u64,
+    per_minute: u32,
+) -> Result<(), u64> {
+    while matches!(times.front(), Some(t) if t + 60000 <= now_ms) {
+        times.pop_front();
+    }
+    if times.len() >= per_minute as usize {
+        let oldest = times.front().copied().unwrap_or(now_ms);
+        return Err((oldest + 60000 - now_ms).div_ceil(1000));
+    }
+    times.push_back(now_ms);
+    Ok(())
+}
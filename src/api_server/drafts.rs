@@ -0,0 +1,108 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::user::post::Hoot;
+use crate::user::user::Address;
+
+use super::DRAFTS_PER_ADDR;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Draft {
+    pub id: u64,
+    pub hoot: Hoot,
+    pub saved_at: u64,
+}
+
+// Unsent hoots of the established addresses, kept by the server so that a
+// client may pick them up from another connection
+#[derive(Debug, Default)]
+pub struct DraftStore {
+    drafts: HashMap<Address, Vec<Draft>>,
+    next_id: u64,
+}
+
+impl DraftStore {
+    pub fn new() -> DraftStore {
+        DraftStore::default()
+    }
+
+    // Replaces the draft id of addr, or keeps hoot as a new one when id is
+    // None. Returns the id, or None when id is not a draft of addr or addr
+    // already has DRAFTS_PER_ADDR.
+    pub fn save(&mut self, addr: &Address, id: Option<u64>, hoot: Hoot, now_ms: u64) -> Option<u64> {
+        let drafts = self.drafts.entry(addr.clone()).or_default();
+        match id {
+            Some(id) => {
+                let draft = drafts.iter_mut().find(|d| d.id == id)?;
+                draft.hoot = hoot;
+                draft.saved_at = now_ms;
+                Some(id)
+            }
+            None => {
+                if drafts.len() >= DRAFTS_PER_ADDR {
+                    return None;
+                }
+                self.next_id += 1;
+                drafts.push(Draft {
+                    id: self.next_id,
+                    hoot,
+                    saved_at: now_ms,
+                });
+                Some(self.next_id)
+            }
+        }
+    }
+
+    // latest saved first
+    pub fn list(&self, addr: &Address) -> Vec<Draft> {
+        let mut drafts = self.drafts.get(addr).cloned().unwrap_or_default();
+        drafts.sort_by(|a, b| b.saved_at.cmp(&a.saved_at).then(b.id.cmp(&a.id)));
+        drafts
+    }
+
+    pub fn remove(&mut self, addr: &Address, id: u64) -> bool {
+        let Some(drafts) = self.drafts.get_mut(addr) else {
+            return false;
+        };
+        let len = drafts.len();
+        drafts.retain(|d| d.id != id);
+        let removed = drafts.len() < len;
+        if drafts.is_empty() {
+            self.drafts.remove(addr);
+        }
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::SecretKey;
+
+    #[test]
+    fn draft_store_test() {
+        let alice = Address::from(SecretKey::random().public_key());
+        let bob = Address::from(SecretKey::random().public_key());
+        let mut store = DraftStore::new();
+
+        let a = store.save(&alice, None, Hoot::new("a".to_string()), 1).unwrap();
+        let b = store.save(&alice, None, Hoot::new("b".to_string()), 2).unwrap();
+        assert_eq!(store.save(&alice, Some(a), Hoot::new("a2".to_string()), 3), Some(a));
+        // not bob's
+        assert_eq!(store.save(&bob, Some(b), Hoot::new("x".to_string()), 4), None);
+        assert!(store.list(&bob).is_empty());
+
+        let drafts = store.list(&alice);
+        assert_eq!(drafts.iter().map(|d| d.id).collect::<Vec<_>>(), vec![a, b]);
+        assert_eq!(drafts[0].hoot.text, "a2");
+
+        assert!(!store.remove(&bob, a));
+        assert!(store.remove(&alice, a));
+        assert_eq!(store.list(&alice).len(), 1);
+
+        for i in 1..DRAFTS_PER_ADDR {
+            assert!(store.save(&alice, None, Hoot::new(i.to_string()), 5).is_some());
+        }
+        assert_eq!(store.save(&alice, None, Hoot::new("full".to_string()), 6), None);
+    }
+}
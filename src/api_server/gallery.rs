@@ -0,0 +1,334 @@
+use chrono::{TimeZone, Utc};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+
+use crate::service::{AccountStatus, BackfillEvent, NetworkController, HISTORY_BACKFILL};
+use crate::user::post::{PostKind, SignedPost};
+use crate::user::user::Address;
+
+use super::{GALLERY_CACHE_SIZE, GALLERY_CACHE_TTL, GALLERY_REQUEST_LIMIT, HTTP_READ_TIMEOUT};
+
+#[derive(Debug, Clone)]
+pub struct GalleryConfig {
+    // recent posts shown per account
+    pub posts: usize,
+    pub cache_ttl: Duration,
+    // asks search engines to stay away, with robots.txt and on every page
+    pub noindex: bool,
+}
+
+impl Default for GalleryConfig {
+    fn default() -> GalleryConfig {
+        GalleryConfig {
+            posts: HISTORY_BACKFILL,
+            cache_ttl: Duration::from_millis(GALLERY_CACHE_TTL),
+            noindex: true,
+        }
+    }
+}
+
+// Read-only HTML pages of an account's archived posts at /u/{addr}, for
+// people without a client. Only posts verified against the account key are
+// shown.
+#[derive(Clone)]
+pub struct Gallery {
+    net: Arc<NetworkController>,
+    config: GalleryConfig,
+    cache: Arc<Mutex<HashMap<Address, (Instant, String)>>>,
+}
+
+impl Gallery {
+    pub fn new(net: Arc<NetworkController>, config: GalleryConfig) -> Gallery {
+        Gallery {
+            net,
+            config,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub async fn start(self, bind_addr: String) -> std::io::Result<SocketAddr> {
+        let listener = TcpListener::bind(bind_addr).await?;
+        let local_addr = listener.local_addr()?;
+        info!("Gallery listening on {}", local_addr);
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((socket, _)) => {
+                        let gallery = self.clone();
+                        tokio::spawn(async move { gallery.handle(socket).await });
+                    }
+                    Err(e) => warn!("Gallery connection error: {}", e),
+                }
+            }
+        });
+        Ok(local_addr)
+    }
+
+    async fn handle(&self, socket: TcpStream) {
+        let mut stream = BufReader::new(socket.take(GALLERY_REQUEST_LIMIT as u64));
+        let read = timeout(Duration::from_millis(HTTP_READ_TIMEOUT), read_head(&mut stream));
+        let response = match read.await {
+            Ok(Some(first_line)) => {
+                let mut params = first_line.split_whitespace();
+                match (params.next(), params.next()) {
+                    (Some("GET"), Some(path)) => self.get(path).await,
+                    _ => self.response("405 Method Not Allowed", "text/plain", "", ""),
+                }
+            }
+            _ => self.response("400 Bad Request", "text/plain", "", ""),
+        };
+        let socket = stream.get_mut().get_mut();
+        let _ = socket.write_all(response.as_bytes()).await;
+        let _ = socket.shutdown().await;
+    }
+
+    async fn get(&self, path: &str) -> String {
+        if path == "/robots.txt" {
+            let rules = if self.config.noindex {
+                "User-agent: *\nDisallow: /\n"
+            } else {
+                "User-agent: *\nAllow: /\n"
+            };
+            return self.response("200 OK", "text/plain", "", rules);
+        }
+        let addr = match path.strip_prefix("/u/").map(Address::parse_strict) {
+            Some(Ok(addr)) => addr,
+            Some(Err(e)) => {
+                return self.page("400 Bad Request", "", "Invalid address", &escape(&e.to_string()))
+            }
+            None => return self.page("404 Not Found", "", "Not found", ""),
+        };
+
+        let mut cache = self.cache.lock().await;
+        if let Some((rendered_at, response)) = cache.get(&addr) {
+            if rendered_at.elapsed() < self.config.cache_ttl {
+                return response.clone();
+            }
+        }
+        drop(cache);
+
+        let response = self.account_page(&addr).await;
+        cache = self.cache.lock().await;
+        let ttl = self.config.cache_ttl;
+        cache.retain(|_, (rendered_at, _)| rendered_at.elapsed() < ttl);
+        if cache.len() < GALLERY_CACHE_SIZE {
+            cache.insert(addr, (Instant::now(), response.clone()));
+        }
+        response
+    }
+
+    async fn account_page(&self, addr: &Address) -> String {
+        match self.net.get_account(addr.clone()).await {
+            Some(AccountStatus::Active(_)) => (),
+            Some(AccountStatus::Deleted(_)) => {
+                return self.page("410 Gone", "", "Account deleted", "")
+            }
+            Some(AccountStatus::Migrated(record)) => {
+                let location = format!("Location: /u/{}\r\n", record.new_addr().to_string());
+                return self.page("301 Moved Permanently", &location, "Account moved", "");
+            }
+            None => return self.page("404 Not Found", "", "Account not found", ""),
+        }
+
+        let mut events = self.net.backfill(addr.clone(), self.config.posts);
+        let mut sigposts = Vec::new();
+        while let Some(event) = events.recv().await {
+            if let BackfillEvent::Finished(posts) = event {
+                sigposts = posts;
+            }
+        }
+        sigposts.reverse();
+
+        let title = match sigposts.first() {
+            Some(sigpost) => format!(
+                "{} @{}",
+                escape(&sigpost.post.user_attr.name),
+                addr.to_string()
+            ),
+            None => format!("@{}", addr.to_string()),
+        };
+        let body: String = sigposts.iter().map(render_post).collect();
+        let body = if body.is_empty() {
+            "<p>No posts yet</p>".to_string()
+        } else {
+            body
+        };
+        self.page("200 OK", "", &title, &body)
+    }
+
+    fn page(&self, status: &str, headers: &str, title: &str, body: &str) -> String {
+        let robots = if self.config.noindex {
+            "<meta name=\"robots\" content=\"noindex\">\n"
+        } else {
+            ""
+        };
+        let html = format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n{}<title>{}</title>\n\
+             </head>\n<body>\n<h1>{}</h1>\n{}</body>\n</html>\n",
+            robots, title, title, body
+        );
+        self.response(status, "text/html", headers, &html)
+    }
+
+    fn response(&self, status: &str, content_type: &str, headers: &str, body: &str) -> String {
+        let robots = if self.config.noindex {
+            "X-Robots-Tag: noindex\r\n"
+        } else {
+            ""
+        };
+        format!(
+            "HTTP/1.1 {}\r\n\
+             Content-Type: {}; charset=UTF-8\r\n\
+             Content-Length: {}\r\n\
+             Cache-Control: public, max-age={}\r\n\
+             {}{}Connection: close\r\n\r\n{}",
+            status,
+            content_type,
+            body.len(),
+            self.config.cache_ttl.as_secs(),
+            robots,
+            headers,
+            body
+        )
+    }
+}
+
+fn render_post(sigpost: &SignedPost) -> String {
+    let time = Utc
+        .timestamp_opt(sigpost.post.created_at as i64, 0)
+        .single()
+        .map(|t| t.format("%Y-%m-%d %H:%M UTC").to_string())
+        .unwrap_or_default();
+    let content = match &sigpost.post.content {
+        PostKind::Hoot(hoot) => match hoot.link_preview() {
+            Some(link) => format!(
+                "{}<p><a href=\"{}\" rel=\"nofollow noopener\">{}</a>{}</p>\n",
+                paragraphs(&hoot.text),
+                escape(&link.url),
+                escape(link.title.as_deref().unwrap_or(&link.url)),
+                link.description
+                    .as_deref()
+                    .map(|d| format!("<br><small>{}</small>", escape(d)))
+                    .unwrap_or_default()
+            ),
+            None => paragraphs(&hoot.text),
+        },
+        PostKind::ReHoot(inner) => format!(
+            "<p>Rehooted {} @{}</p>\n<blockquote>\n{}</blockquote>\n",
+            escape(&inner.post.user_attr.name),
+            inner.addr.to_string(),
+            match &inner.post.content {
+                PostKind::Hoot(hoot) => paragraphs(&hoot.text),
+                _ => String::new(),
+            }
+        ),
+        PostKind::ReHootRef(original) => {
+            format!("<p>Rehooted a post of @{}</p>\n", original.addr.to_string())
+        }
+        _ => return String::new(),
+    };
+    let content = match sigpost.content_warning() {
+        Some(cw) => format!(
+            "<details>\n<summary>{}</summary>\n{}</details>\n",
+            escape(&cw),
+            content
+        ),
+        None => content,
+    };
+    format!(
+        "<article>\n<p><small>#{} {}</small></p>\n{}</article>\n",
+        sigpost.post.id, time, content
+    )
+}
+
+fn paragraphs(text: &str) -> String {
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| format!("<p>{}</p>\n", escape(line)))
+        .collect()
+}
+
+fn escape(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => ret.push_str("&amp;"),
+            '<' => ret.push_str("&lt;"),
+            '>' => ret.push_str("&gt;"),
+            '"' => ret.push_str("&quot;"),
+            '\'' => ret.push_str("&#39;"),
+            c => ret.push(c),
+        }
+    }
+    ret
+}
+
+// The request line, once the headers after it are read; they are not needed.
+// None if the request ends before them, or is longer than the stream lets through.
+async fn read_head<R>(stream: &mut R) -> Option<String>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    let mut first_line = String::new();
+    let mut line = String::new();
+    stream.read_line(&mut first_line).await.ok()?;
+    loop {
+        line.clear();
+        match stream.read_line(&mut line).await.ok()? {
+            0 => return None,
+            _ if line.trim().is_empty() => return Some(first_line),
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::SecretKey;
+    use crate::user::post::{Hoot, Post};
+    use crate::user::post_id::PostId;
+    use crate::user::user::UserAttribute;
+
+    #[test]
+    fn render_test() {
+        let sk = SecretKey::random();
+        let mut hoot = Hoot::new("<script>alert(1)</script>\nsecond line".to_string());
+        hoot.content_warning = Some("spoiler & more".to_string());
+        let post = Post {
+            user_attr: UserAttribute::new("alice", 0, ""),
+            id: PostId(7),
+            content: PostKind::Hoot(hoot),
+            created_at: 0,
+        };
+        let signature = sk.sign(&post.signed_message());
+        let sigpost = SignedPost::new(Address::from(sk.public_key()), post, signature);
+
+        let html = render_post(&sigpost);
+        assert!(!html.contains("<script>"));
+        assert!(html.contains("<p>&lt;script&gt;alert(1)&lt;/script&gt;</p>"));
+        assert!(html.contains("<p>second line</p>"));
+        assert!(html.contains("<summary>spoiler &amp; more</summary>"));
+        assert!(html.contains("1970-01-01 00:00 UTC"));
+    }
+
+    #[tokio::test]
+    async fn read_head_test() {
+        let raw = b"GET /u/abc HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let first_line = read_head(&mut &raw[..]).await.unwrap();
+        assert_eq!(first_line, "GET /u/abc HTTP/1.1\r\n");
+
+        // headers without an end, or cut off at the limit
+        assert!(read_head(&mut &b"GET / HTTP/1.1\r\nHost: localhost\r\n"[..]).await.is_none());
+        let endless = [&raw[..12], &[b'a'; GALLERY_REQUEST_LIMIT]].concat();
+        let mut stream = BufReader::new((&endless[..]).take(GALLERY_REQUEST_LIMIT as u64));
+        assert!(read_head(&mut stream).await.is_none());
+    }
+}
@@ -1,29 +1,260 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::crypto::PublicKey;
 use serde_big_array::BigArray;
+use thiserror::Error;
 
 use crate::user::{
-    post::SignedPost,
-    user::{Address, SignedUserAttribute},
+    post::{Hoot, PostKind, SignedPost},
+    post_id::PostId,
+    user::{Address, RegistrationRecord, SignedUserAttribute, UserAttribute},
+};
+use crate::service::{
+    AuthorStats, DraftReport, Notification, Recommendation, SignatureStatus, SyncStatus, UserMatch,
 };
+use crate::util::stats::Stats;
+
+use super::admin::SignedAdminCommand;
+use super::drafts::Draft;
+use super::wire::WireEncoding;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ClientMessage {
-    EstablishReq { addr: [u8; 32], pubkey: [u8; 32] },
+    EstablishReq { addr: [u8; 32], pubkey: PublicKey },
     ChallengeResponce(#[serde(with = "BigArray")] [u8; 64]),
-    PublicKey([u8; 32]),
-    Post(SignedPost),
+    PublicKey(PublicKey),
+    Post(Box<SignedPost>),
     SubscribeReq(Address),
+    FilteredSubscribeReq { addr: Address, filter: String },
     UnsubscribeReq(Address),
     GetUserInfo(Address),
+    GetStats,
+    RecommendFollowsReq { limit: usize },
+    SearchUser { query: String, limit: usize },
+    GetPost { addr: Address, id: PostId },
+    GetHistory { addr: Address, limit: usize },
+    // after establishing, to register the key in the user DHT
+    Register(RegistrationRecord),
+    // the encodings the client reads, best first; answered with Hello
+    Hello {
+        encodings: Vec<WireEncoding>,
+        // push PostCreated and the other typed events instead of Subscribed,
+        // for subscriptions made after this
+        #[serde(default, skip_serializing_if = "is_false")]
+        typed_events: bool,
+        // answer ChallengeResponce with EstablishedSession instead of
+        // Established, for a token to Resume with
+        #[serde(default, skip_serializing_if = "is_false")]
+        sessions: bool,
+    },
+    // a token of EstablishedSession in place of the challenge handshake,
+    // after a reconnect; establishes its address again and restores the
+    // subscriptions the connection had when it closed. Answered with
+    // EstablishedSession, carrying a new token
+    Resume(String),
+    // reads the server's tunables file again, only from the server's host
+    ReloadConfig,
+    // a command of the node's operator, taken on any connection, established
+    // or not, when signed by Config::operator_key; see AdminCommand
+    Admin(SignedAdminCommand),
+    // drops the posts of addr, and the posts carrying them, from every
+    // subscription of this connection; answered with Success
+    Block(Address),
+    Unblock(Address),
+    // lists of addresses kept for this connection, like the lists of
+    // followings in the CLI; answered with Success
+    ListAdd { list: String, addr: Address },
+    ListRemove { list: String, addr: Address },
+    // answered with Lists
+    GetLists,
+    // the recent posts the server routed from the members of list, answered
+    // with History
+    GetListPosts { list: String, limit: usize },
+    // how the hoot would come out posted by the established address; answered
+    // with DraftReport
+    ValidateDraft(Box<Hoot>),
+    // keeps a draft on the server, a new one when id is None; answered with
+    // DraftSaved
+    SaveDraft {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        hoot: Box<Hoot>,
+    },
+    // answered with Drafts
+    ListDrafts,
+    // answered with Success
+    DeleteDraft(u64),
+    // whether addr posted after head, the newest post the client has from
+    // it, or after the newest one the relay keeps when head is None;
+    // answered with SyncStatus
+    GetSyncStatus {
+        addr: Address,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        head: Option<PostId>,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ServerMessage {
     Success,
-    Denied,
-    Invalid,
+    Error {
+        code: ErrorCode,
+        message: String,
+        #[serde(default)]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        retry_after: Option<u64>,
+    },
     Subscribed(SignedPost),
-    UserInfo(SignedUserAttribute),
+    UserInfo(UserInfo),
     Challenge([u8; 32]),
     Established,
+    // Established, to clients which asked for sessions in Hello; expires_at
+    // is in ms since the epoch
+    EstablishedSession { token: String, expires_at: u64 },
+    Stats(Stats),
+    Recommendations(Vec<Recommendation>),
+    UserCandidates(Vec<UserMatch>),
+    Post(SignedPost),
+    // oldest first
+    History(Vec<SignedPost>),
+    // the encoding of every message after this one, in both directions
+    Hello {
+        encoding: WireEncoding,
+        #[serde(default, skip_serializing_if = "is_false")]
+        typed_events: bool,
+    },
+    // the names of the tunables which changed
+    ConfigReloaded(Vec<String>),
+    // Pushed instead of Subscribed to clients which asked for typed events.
+    // Account posts, DeleteAccount and Migrate, are PostCreated too.
+    PostCreated(SignedPost),
+    // delete is the author's signed Delete post of id
+    PostDeleted { id: PostId, delete: SignedPost },
+    // the author's profile changed since their last post; sent before it
+    ProfileUpdated { addr: Address, attr: UserAttribute },
+    // a verified post mentioning or replying to an established address, from
+    // a subscribed author; the others are left in the mailbox
+    Mentioned(Notification),
+    // every list of the connection with its members
+    Lists(BTreeMap<String, Vec<Address>>),
+    DraftReport(DraftReport),
+    DraftSaved { id: u64 },
+    // latest saved first
+    Drafts(Vec<Draft>),
+    SyncStatus { addr: Address, status: SyncStatus },
+}
+
+// What GetUserInfo is answered with. Servers before status sent a signed
+// attribute, which still decodes as one without status.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserInfo {
+    pub addr: Address,
+    pub attr: UserAttribute,
+    // 64 bytes over attr, when the profile was found signed on its own; one
+    // read from a post is signed with the post
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<ProfileStatus>,
+    // the posts of addr the server verified, as of the reply
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<AuthorStats>,
+}
+
+impl From<SignedUserAttribute> for UserInfo {
+    fn from(sig_attr: SignedUserAttribute) -> UserInfo {
+        UserInfo {
+            addr: sig_attr.addr,
+            attr: sig_attr.attr,
+            signature: Some(sig_attr.signature.to_vec()),
+            status: None,
+            stats: None,
+        }
+    }
+}
+
+// where the server found a profile
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProfileSource {
+    // the profile the author published in the user DHT
+    Dht,
+    // the latest post in the author's history
+    PostLog,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProfileStatus {
+    pub source: ProfileSource,
+    // the signature checked against the key registered for addr
+    pub verification: SignatureStatus,
+    // seconds since the epoch, when the profile was signed
+    pub signed_at: u64,
+    // ms since the epoch, when the server fetched it; older than the request
+    // when it was answered from the server's cache
+    pub fetched_at: u64,
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+impl ServerMessage {
+    pub fn error(code: ErrorCode) -> ServerMessage {
+        ServerMessage::Error {
+            code,
+            message: code.to_string(),
+            retry_after: None,
+        }
+    }
+
+    // how a post of a subscription is pushed to a client
+    pub fn push(sigpost: SignedPost, typed_events: bool) -> ServerMessage {
+        match sigpost.post.content {
+            _ if !typed_events => ServerMessage::Subscribed(sigpost),
+            PostKind::Delete(id) => ServerMessage::PostDeleted {
+                id,
+                delete: sigpost,
+            },
+            _ => ServerMessage::PostCreated(sigpost),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Error)]
+pub enum ErrorCode {
+    #[error("Malformed message")]
+    Malformed,
+    #[error("Unsupported request")]
+    Unsupported,
+    #[error("Connection is not established")]
+    NotEstablished,
+    #[error("Invalid public key")]
+    InvalidPublicKey,
+    #[error("Address does not match the public key")]
+    AddressMismatch,
+    #[error("No challenge is pending")]
+    NoPendingChallenge,
+    #[error("Invalid signature")]
+    InvalidSignature,
+    #[error("Address is not registered on this connection")]
+    UnknownAddress,
+    #[error("Too many requests")]
+    RateLimited,
+    #[error("Invalid filter")]
+    InvalidFilter,
+    #[error("Not found")]
+    NotFound,
+    #[error("Not allowed from this host")]
+    Forbidden,
+    #[error("Invalid configuration")]
+    InvalidConfig,
+    #[error("Too many drafts")]
+    TooManyDrafts,
+    #[error("Invalid or expired session token")]
+    InvalidToken,
+    #[error("Admin command issued too long ago, or taken already")]
+    StaleCommand,
+    #[error("Admin command is for another node")]
+    OtherNode,
 }
@@ -1,4 +1,60 @@
+mod admin;
 mod client_info;
+mod drafts;
+mod gallery;
 mod message;
+mod relay_cache;
+mod rest;
 mod server;
+mod session;
 mod subscription_router;
+pub mod wire;
+
+pub use admin::{AdminCommand, SignedAdminCommand};
+pub use drafts::{Draft, DraftStore};
+pub use gallery::{Gallery, GalleryConfig};
+pub use message::{
+    ClientMessage, ErrorCode, ProfileSource, ProfileStatus, ServerMessage, UserInfo,
+};
+pub use relay_cache::{RelayCache, RelayCacheConfig};
+pub use rest::{ChallengeReply, ChallengeRequest, RestApi, RestError, TokenReply, TokenRequest};
+pub use server::{ApiServer, ApiServerError};
+pub use wire::WireEncoding;
+
+// a message, and a frame, of the WebSocket API
+pub const API_MESSAGE_LIMIT: usize = 256 * 1024;
+pub const GALLERY_CACHE_TTL: u64 = 60000; // 1 minute
+// accounts whose pages are kept rendered
+pub const GALLERY_CACHE_SIZE: usize = 1024;
+// posts per address kept by a relay
+pub const RELAY_CACHE_POSTS: usize = 50;
+pub const RELAY_CACHE_QUOTA: u64 = 64 * 1024 * 1024; // 64 MiB
+// profiles resolved for GetUserInfo are kept this long
+pub const PROFILE_CACHE_TTL: u64 = 600000; // 10 minutes
+pub const PROFILE_CACHE_SIZE: usize = 4096;
+// GetUserInfo requests per client which miss the cache and go to the DHT
+pub const PROFILE_LOOKUPS_PER_MINUTE: u32 = 30;
+// drafts kept by the server per established address
+pub const DRAFTS_PER_ADDR: usize = 100;
+// session tokens of the WebSocket API, see ServerMessage::EstablishedSession
+pub const SESSION_TOKEN_TTL: u64 = 3600000; // 1 hour
+// closed connections whose subscriptions are kept for Resume
+pub const SAVED_SESSIONS_LIMIT: usize = 4096;
+// admin commands are taken within this of when they were issued, either way
+pub const ADMIN_COMMAND_TTL: u64 = 60000; // 1 minute
+// a REST request, its headers and body together
+pub const REST_REQUEST_LIMIT: usize = 64 * 1024;
+// the request line and headers of a gallery request
+pub const GALLERY_REQUEST_LIMIT: usize = 8 * 1024;
+// a REST or gallery request is read within this, or the connection is dropped
+pub const HTTP_READ_TIMEOUT: u64 = 10000; // 10 seconds
+// a challenge of the REST API is signed within this, and its token lasts this long
+pub const REST_CHALLENGE_TTL: u64 = 15000; // 15 seconds
+pub const REST_TOKEN_TTL: u64 = 3600000; // 1 hour
+// REST tokens kept at once
+pub const REST_SESSIONS_LIMIT: usize = 4096;
+// REST challenges waiting to be signed at once, and asked for by one peer IP
+pub const REST_CHALLENGES_LIMIT: usize = 1024;
+pub const REST_CHALLENGES_PER_MINUTE: u32 = 10;
+// posts of a feed a REST request may ask for
+pub const REST_FEED_LIMIT: usize = 100;
@@ -0,0 +1,253 @@
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use std::io;
+use std::path::PathBuf;
+
+use crate::user::post::{PostKind, SignedPost};
+use crate::user::post_id::PostId;
+use crate::user::user::Address;
+use crate::util::atomic_file;
+
+use super::{RELAY_CACHE_POSTS, RELAY_CACHE_QUOTA};
+
+#[derive(Debug, Clone)]
+pub struct RelayCacheConfig {
+    // one file per address is kept here
+    pub dir: PathBuf,
+    pub posts_per_addr: usize,
+    // bytes on disk; the least recently used addresses are evicted above it
+    pub quota: u64,
+}
+
+impl Default for RelayCacheConfig {
+    fn default() -> RelayCacheConfig {
+        RelayCacheConfig {
+            dir: PathBuf::from("localdata/relay"),
+            posts_per_addr: RELAY_CACHE_POSTS,
+            quota: RELAY_CACHE_QUOTA,
+        }
+    }
+}
+
+struct CachedPosts {
+    posts: VecDeque<SignedPost>,
+    size: u64,
+    last_used: u64,
+}
+
+// The latest verified posts of the addresses the server subscribes to, kept
+// on disk so that clients get history without a DHT lookup. Callers verify
+// posts before inserting them.
+pub struct RelayCache {
+    config: RelayCacheConfig,
+    // config.quota unless set_quota overrides it
+    quota: u64,
+    addrs: HashMap<Address, CachedPosts>,
+    total: u64,
+    // bumped on every use, orders addresses for eviction
+    tick: u64,
+}
+
+impl RelayCache {
+    pub async fn open(config: RelayCacheConfig) -> io::Result<RelayCache> {
+        tokio::fs::create_dir_all(&config.dir).await?;
+        let mut cache = RelayCache {
+            quota: config.quota,
+            config,
+            addrs: HashMap::new(),
+            total: 0,
+            tick: 0,
+        };
+
+        let mut dir = tokio::fs::read_dir(&cache.config.dir).await?;
+        while let Some(file) = dir.next_entry().await? {
+            let path = file.path();
+            let addr = match path
+                .file_stem()
+                .and_then(|stem| hex::decode(stem.to_string_lossy().as_bytes()).ok())
+                .and_then(|bytes| TryInto::<[u8; 32]>::try_into(bytes).ok())
+            {
+                Some(bytes) => Address::from(bytes),
+                None => continue,
+            };
+            // a broken file is dropped, it is only a cache
+            let bytes = atomic_file::read(&path).await.unwrap_or_default();
+            let posts: VecDeque<SignedPost> = match serde_json::from_slice(&bytes) {
+                Ok(posts) => posts,
+                Err(_) => {
+                    atomic_file::remove(&path).await?;
+                    continue;
+                }
+            };
+            cache.total += bytes.len() as u64;
+            cache.addrs.insert(
+                addr,
+                CachedPosts {
+                    posts,
+                    size: bytes.len() as u64,
+                    last_used: 0,
+                },
+            );
+        }
+        cache.evict(None).await?;
+        Ok(cache)
+    }
+
+    pub fn size(&self) -> u64 {
+        self.total
+    }
+
+    pub fn posts_per_addr(&self) -> usize {
+        self.config.posts_per_addr
+    }
+
+    // None goes back to the configured quota
+    pub async fn set_quota(&mut self, quota: Option<u64>) -> io::Result<()> {
+        self.quota = quota.unwrap_or(self.config.quota);
+        self.evict(None).await
+    }
+
+    pub async fn insert(&mut self, sigpost: SignedPost) -> io::Result<()> {
+        let addr = sigpost.addr.clone();
+        if sigpost.post.content == PostKind::DeleteAccount {
+            return self.remove(&addr).await;
+        }
+
+        self.tick += 1;
+        let limit = self.config.posts_per_addr;
+        let path = self.path(&addr);
+        let cached = self.addrs.entry(addr.clone()).or_insert(CachedPosts {
+            posts: VecDeque::new(),
+            size: 0,
+            last_used: 0,
+        });
+        cached.last_used = self.tick;
+        match &sigpost.post.content {
+            PostKind::Delete(id) => cached.posts.retain(|p| p.post.id != *id),
+            _ => {
+                if cached.posts.iter().any(|p| p.post.id == sigpost.post.id) {
+                    return Ok(());
+                }
+                cached.posts.push_back(sigpost);
+                while cached.posts.len() > limit {
+                    cached.posts.pop_front();
+                }
+            }
+        }
+
+        let bytes = serde_json::to_vec(&cached.posts).unwrap();
+        atomic_file::write(&path, &bytes).await?;
+        self.total = self.total - cached.size + bytes.len() as u64;
+        cached.size = bytes.len() as u64;
+        self.evict(Some(&addr)).await
+    }
+
+    // the latest limit posts of addr, oldest first
+    pub fn recent(&mut self, addr: &Address, limit: usize) -> Vec<SignedPost> {
+        self.tick += 1;
+        match self.addrs.get_mut(addr) {
+            Some(cached) => {
+                cached.last_used = self.tick;
+                let skip = cached.posts.len().saturating_sub(limit);
+                cached.posts.iter().skip(skip).cloned().collect()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    pub fn get(&mut self, addr: &Address, id: PostId) -> Option<SignedPost> {
+        self.tick += 1;
+        let cached = self.addrs.get_mut(addr)?;
+        cached.last_used = self.tick;
+        cached.posts.iter().find(|p| p.post.id == id).cloned()
+    }
+
+    pub async fn remove(&mut self, addr: &Address) -> io::Result<()> {
+        if let Some(cached) = self.addrs.remove(addr) {
+            self.total -= cached.size;
+            atomic_file::remove(&self.path(addr)).await?;
+        }
+        Ok(())
+    }
+
+    // drops the least recently used addresses until the quota is met, except keep
+    async fn evict(&mut self, keep: Option<&Address>) -> io::Result<()> {
+        while self.total > self.quota {
+            let oldest = self
+                .addrs
+                .iter()
+                .filter(|(addr, _)| Some(*addr) != keep)
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(addr, _)| addr.clone());
+            match oldest {
+                Some(addr) => self.remove(&addr).await?,
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn path(&self, addr: &Address) -> PathBuf {
+        let addr_bytes: [u8; 32] = addr.clone().into();
+        self.config
+            .dir
+            .join(format!("{}.json", hex::encode(addr_bytes)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::SecretKey;
+    use crate::user::post::{Hoot, Post};
+    use crate::user::user::UserAttribute;
+
+    fn post(sk: &SecretKey, id: u128) -> SignedPost {
+        let post = Post {
+            user_attr: UserAttribute::new("a", 0, ""),
+            id: PostId(id),
+            content: PostKind::Hoot(Hoot::new(format!("post {}", id))),
+            created_at: 0,
+        };
+        let signature = sk.sign(&post.signed_message());
+        SignedPost::new(Address::from(sk.public_key()), post, signature)
+    }
+
+    #[tokio::test]
+    async fn relay_cache_test() {
+        let dir = std::env::temp_dir().join(format!("noktulo_relay_{}", std::process::id()));
+        let config = RelayCacheConfig {
+            dir: dir.clone(),
+            posts_per_addr: 3,
+            quota: u64::MAX,
+        };
+        let (alice, bob) = (SecretKey::random(), SecretKey::random());
+        let alice_addr = Address::from(alice.public_key());
+
+        let mut cache = RelayCache::open(config.clone()).await.unwrap();
+        for id in 0..5 {
+            cache.insert(post(&alice, id)).await.unwrap();
+        }
+        let ids: Vec<_> = cache.recent(&alice_addr, 10).iter().map(|p| p.post.id.0).collect();
+        assert_eq!(ids, vec![2, 3, 4]);
+        for id in 0..3 {
+            cache.insert(post(&bob, id)).await.unwrap();
+        }
+
+        // survives a restart
+        let mut cache = RelayCache::open(config).await.unwrap();
+        assert_eq!(cache.get(&alice_addr, PostId(4)).unwrap().post.id, PostId(4));
+
+        // over the quota, the least recently used address goes
+        let carol = SecretKey::random();
+        cache.insert(post(&carol, 0)).await.unwrap();
+        cache.recent(&alice_addr, 1);
+        cache.set_quota(Some(cache.size())).await.unwrap();
+        cache.insert(post(&carol, 1)).await.unwrap();
+        assert!(!cache.addrs.contains_key(&Address::from(bob.public_key())));
+        assert_eq!(cache.recent(&alice_addr, 10).len(), 3);
+        assert!(cache.size() <= cache.quota);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,609 @@
+use chrono::Utc;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_big_array::BigArray;
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex as StdMutex};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{timeout, Duration};
+
+use crate::crypto::PublicKey;
+use crate::service::HISTORY_BACKFILL;
+use crate::user::post::SignedPost;
+use crate::user::post_id::PostId;
+use crate::user::user::Address;
+use crate::util::base64;
+use crate::util::crypto_pool;
+
+use super::client_info::take_slot;
+use super::message::{ErrorCode, ServerMessage};
+use super::server::ApiServer;
+use super::{
+    HTTP_READ_TIMEOUT, PROFILE_LOOKUPS_PER_MINUTE, REST_CHALLENGES_LIMIT,
+    REST_CHALLENGES_PER_MINUTE, REST_CHALLENGE_TTL, REST_FEED_LIMIT, REST_REQUEST_LIMIT,
+    REST_SESSIONS_LIMIT, REST_TOKEN_TTL,
+};
+
+// POST /challenge
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChallengeRequest {
+    pub pubkey: PublicKey,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChallengeReply {
+    // names the challenge in the TokenRequest
+    pub nonce: String,
+    pub challenge: [u8; 32],
+}
+
+// POST /token, with the challenge signed by the key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenRequest {
+    pub nonce: String,
+    pub pubkey: PublicKey,
+    #[serde(with = "BigArray")]
+    pub signature: [u8; 64],
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenReply {
+    // sent back as "Authorization: Bearer {token}"
+    pub token: String,
+    // ms since the epoch
+    pub expires_at: u64,
+}
+
+// the body of every response which is not 2xx
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RestError {
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+}
+
+// an address which signed a challenge, like an established connection
+struct Session {
+    addr: Address,
+    pubkey: PublicKey,
+    expires_at: u64,
+    // when the posts and profile lookups of the last minute were made, in ms
+    posted: VecDeque<u64>,
+    looked_up: VecDeque<u64>,
+}
+
+// a challenge sent for a key, not signed yet
+struct PendingChallenge {
+    pubkey: PublicKey,
+    challenge: [u8; 32],
+    expires_at: u64,
+}
+
+#[derive(Default)]
+struct Sessions {
+    // by the nonce sent with each, so that asking again replaces nothing
+    challenges: HashMap<String, PendingChallenge>,
+    // when each peer IP asked for the challenges of the last minute, in ms
+    asked: HashMap<IpAddr, VecDeque<u64>>,
+    tokens: HashMap<String, Session>,
+}
+
+impl Sessions {
+    fn expire(&mut self, now_ms: u64) {
+        self.challenges
+            .retain(|_, pending| pending.expires_at > now_ms);
+        self.asked
+            .retain(|_, times| matches!(times.back(), Some(t) if t + 60000 > now_ms));
+        self.tokens.retain(|_, session| session.expires_at > now_ms);
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+    token: Option<String>,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: &'static str,
+    headers: String,
+    body: String,
+}
+
+impl Response {
+    fn json<T: Serialize>(status: &'static str, value: &T) -> Response {
+        Response {
+            status,
+            headers: String::new(),
+            body: serde_json::to_string(value).unwrap(),
+        }
+    }
+
+    fn error(code: ErrorCode) -> Response {
+        Response::from_message(ServerMessage::error(code))
+    }
+
+    fn to_http(&self) -> String {
+        format!(
+            "HTTP/1.1 {}\r\n\
+             Content-Type: application/json; charset=UTF-8\r\n\
+             Content-Length: {}\r\n\
+             Cache-Control: no-store\r\n\
+             {}Connection: close\r\n\r\n{}",
+            self.status,
+            self.body.len(),
+            self.headers,
+            self.body
+        )
+    }
+
+    // a message of the WebSocket API as it is sent over HTTP, errors with
+    // their status
+    fn from_message(msg: ServerMessage) -> Response {
+        let (code, message, retry_after) = match msg {
+            ServerMessage::Error {
+                code,
+                message,
+                retry_after,
+            } => (code, message, retry_after),
+            msg => return Response::json("200 OK", &msg),
+        };
+        let mut response = Response::json(
+            status_of(code),
+            &RestError {
+                code,
+                message,
+                retry_after,
+            },
+        );
+        if let Some(wait) = retry_after {
+            response.headers = format!("Retry-After: {}\r\n", wait);
+        }
+        response
+    }
+}
+
+fn status_of(code: ErrorCode) -> &'static str {
+    match code {
+        ErrorCode::Malformed
+        | ErrorCode::InvalidPublicKey
+        | ErrorCode::AddressMismatch
+        | ErrorCode::InvalidFilter
+        | ErrorCode::InvalidConfig => "400 Bad Request",
+        ErrorCode::NotEstablished
+        | ErrorCode::NoPendingChallenge
+        | ErrorCode::InvalidSignature
+        | ErrorCode::InvalidToken
+        | ErrorCode::StaleCommand => "401 Unauthorized",
+        ErrorCode::UnknownAddress | ErrorCode::Forbidden | ErrorCode::OtherNode => "403 Forbidden",
+        ErrorCode::NotFound => "404 Not Found",
+        ErrorCode::Unsupported => "405 Method Not Allowed",
+        ErrorCode::RateLimited | ErrorCode::TooManyDrafts => "429 Too Many Requests",
+    }
+}
+
+// The WebSocket API over plain HTTP and JSON, for clients which only fetch a
+// feed or post now and then. Its sessions are made the same way, by signing a
+// challenge, and last REST_TOKEN_TTL:
+//   POST /challenge            ChallengeRequest, answered with ChallengeReply
+//   POST /token                TokenRequest, answered with TokenReply
+// Then, with "Authorization: Bearer {token}":
+// An addr in a path has its '/' written as %2F.
+//   GET  /users/{addr}         UserInfo, as GetUserInfo
+//   GET  /feed/{addr}?since={id}&limit={n}
+//                              the posts after since, oldest first, as GetHistory
+//   GET  /posts/{addr}/{id}    SignedPost, as GetPost
+//   POST /posts                a SignedPost of the session's address, as Post
+#[derive(Clone)]
+pub struct RestApi {
+    server: ApiServer,
+    sessions: Arc<StdMutex<Sessions>>,
+}
+
+impl RestApi {
+    pub fn new(server: ApiServer) -> RestApi {
+        RestApi {
+            server,
+            sessions: Arc::new(StdMutex::new(Sessions::default())),
+        }
+    }
+
+    pub async fn start(self, bind_addr: String) -> std::io::Result<SocketAddr> {
+        let listener = TcpListener::bind(bind_addr).await?;
+        let local_addr = listener.local_addr()?;
+        info!("REST API listening on {}", local_addr);
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((socket, _)) => {
+                        let api = self.clone();
+                        tokio::spawn(async move { api.handle(socket).await });
+                    }
+                    Err(e) => warn!("REST API connection error: {}", e),
+                }
+            }
+        });
+        Ok(local_addr)
+    }
+
+    async fn handle(&self, socket: TcpStream) {
+        let peer = match socket.peer_addr() {
+            Ok(peer) => peer.ip(),
+            Err(_) => return,
+        };
+        // the headers and the body together are at most REST_REQUEST_LIMIT
+        let mut stream = BufReader::new(socket.take(REST_REQUEST_LIMIT as u64));
+        let read = timeout(Duration::from_millis(HTTP_READ_TIMEOUT), read_request(&mut stream));
+        let response = match read.await {
+            Ok(Some(req)) => self.route(req, peer).await,
+            _ => Response::error(ErrorCode::Malformed),
+        };
+        let socket = stream.get_mut().get_mut();
+        let _ = socket.write_all(response.to_http().as_bytes()).await;
+        let _ = socket.shutdown().await;
+    }
+
+    async fn route(&self, req: Request, peer: IpAddr) -> Response {
+        // addresses are base64, so their '/' come as %2F
+        let segments: Vec<String> = req
+            .path
+            .trim_matches('/')
+            .split('/')
+            .map(percent_decode)
+            .collect();
+        let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+        match (req.method.as_str(), segments.as_slice()) {
+            ("POST", ["challenge"]) => self.challenge(&req.body, peer),
+            ("POST", ["token"]) => self.token(&req.body).await,
+            ("GET", ["users", addr]) => self.user(&req, addr).await,
+            ("GET", ["feed", addr]) => self.feed(&req, addr).await,
+            ("GET", ["posts", addr, id]) => self.get_post(&req, addr, id).await,
+            ("POST", ["posts"]) => self.post(&req).await,
+            (_, ["challenge" | "token" | "posts"])
+            | (_, ["users" | "feed", _])
+            | (_, ["posts", _, _]) => Response::error(ErrorCode::Unsupported),
+            _ => Response::error(ErrorCode::NotFound),
+        }
+    }
+
+    // Anyone may ask, so each peer IP gets REST_CHALLENGES_PER_MINUTE, and
+    // at most REST_CHALLENGES_LIMIT wait to be signed at once.
+    fn challenge(&self, body: &[u8], peer: IpAddr) -> Response {
+        let req: ChallengeRequest = match serde_json::from_slice(body) {
+            Ok(req) => req,
+            Err(_) => return Response::error(ErrorCode::Malformed),
+        };
+        let now_ms = Utc::now().timestamp_millis() as u64;
+        let (mut nonce, mut challenge) = ([0; 16], [0; 32]);
+        let entropy = self.server.controller().entropy();
+        entropy.fill_bytes(&mut nonce);
+        entropy.fill_bytes(&mut challenge);
+        let nonce = String::from_utf8(base64::encode(&nonce)).unwrap();
+
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.expire(now_ms);
+        let new_peer = !sessions.asked.contains_key(&peer);
+        if sessions.challenges.len() >= REST_CHALLENGES_LIMIT
+            || (new_peer && sessions.asked.len() >= REST_CHALLENGES_LIMIT)
+        {
+            return Response::error(ErrorCode::RateLimited);
+        }
+        let asked = sessions.asked.entry(peer).or_default();
+        if let Err(wait) = take_slot(asked, now_ms, REST_CHALLENGES_PER_MINUTE) {
+            return Response::from_message(ServerMessage::Error {
+                code: ErrorCode::RateLimited,
+                message: ErrorCode::RateLimited.to_string(),
+                retry_after: Some(wait),
+            });
+        }
+        let pending = PendingChallenge {
+            pubkey: req.pubkey,
+            challenge,
+            expires_at: now_ms + REST_CHALLENGE_TTL,
+        };
+        sessions.challenges.insert(nonce.clone(), pending);
+        Response::json("200 OK", &ChallengeReply { nonce, challenge })
+    }
+
+    async fn token(&self, body: &[u8]) -> Response {
+        let req: TokenRequest = match serde_json::from_slice(body) {
+            Ok(req) => req,
+            Err(_) => return Response::error(ErrorCode::Malformed),
+        };
+        let now_ms = Utc::now().timestamp_millis() as u64;
+        // a challenge is signed once
+        let pending = {
+            let mut sessions = self.sessions.lock().unwrap();
+            sessions.expire(now_ms);
+            sessions.challenges.remove(&req.nonce)
+        };
+        let challenge = match pending {
+            Some(pending) if pending.pubkey == req.pubkey => pending.challenge,
+            _ => return Response::error(ErrorCode::NoPendingChallenge),
+        };
+        let (pubkey, signature) = (req.pubkey.clone(), req.signature);
+        if crypto_pool::run(move || pubkey.verify(&signature, &challenge[..]))
+            .await
+            .is_err()
+        {
+            return Response::error(ErrorCode::InvalidSignature);
+        }
+
+        let addr = Address::from(req.pubkey.clone());
+        let mut token = [0; 32];
+        self.server.controller().entropy().fill_bytes(&mut token);
+        let token = String::from_utf8(base64::encode(&token)).unwrap();
+        let expires_at = now_ms + REST_TOKEN_TTL;
+        {
+            let mut sessions = self.sessions.lock().unwrap();
+            if sessions.tokens.len() >= REST_SESSIONS_LIMIT {
+                return Response::error(ErrorCode::RateLimited);
+            }
+            let session = Session {
+                addr: addr.clone(),
+                pubkey: req.pubkey,
+                expires_at,
+                posted: VecDeque::new(),
+                looked_up: VecDeque::new(),
+            };
+            sessions.tokens.insert(token.clone(), session);
+        }
+        self.server.controller().publisher().add_author(addr).await;
+        Response::json("200 OK", &TokenReply { token, expires_at })
+    }
+
+    // the address and key of the session of req
+    fn session(&self, req: &Request) -> Result<(Address, PublicKey), Response> {
+        let now_ms = Utc::now().timestamp_millis() as u64;
+        let sessions = self.sessions.lock().unwrap();
+        match req
+            .token
+            .as_ref()
+            .and_then(|token| sessions.tokens.get(token))
+        {
+            Some(session) if session.expires_at > now_ms => {
+                Ok((session.addr.clone(), session.pubkey.clone()))
+            }
+            _ => Err(Response::error(ErrorCode::NotEstablished)),
+        }
+    }
+
+    async fn user(&self, req: &Request, addr: &str) -> Response {
+        if let Err(response) = self.session(req) {
+            return response;
+        }
+        let addr = match Address::parse_strict(addr) {
+            Ok(addr) => addr,
+            Err(_) => return Response::error(ErrorCode::Malformed),
+        };
+        let now_ms = Utc::now().timestamp_millis() as u64;
+        let take_lookup_slot = || {
+            let mut sessions = self.sessions.lock().unwrap();
+            match req
+                .token
+                .as_ref()
+                .and_then(|token| sessions.tokens.get_mut(token))
+            {
+                Some(session) => {
+                    take_slot(&mut session.looked_up, now_ms, PROFILE_LOOKUPS_PER_MINUTE)
+                }
+                None => Ok(()),
+            }
+        };
+        match self.server.user_info(addr, now_ms, take_lookup_slot).await {
+            Ok(info) => Response::json("200 OK", &info),
+            Err(e) => Response::from_message(e),
+        }
+    }
+
+    async fn feed(&self, req: &Request, addr: &str) -> Response {
+        if let Err(response) = self.session(req) {
+            return response;
+        }
+        let addr = match Address::parse_strict(addr) {
+            Ok(addr) => addr,
+            Err(_) => return Response::error(ErrorCode::Malformed),
+        };
+        let since = match req.query.get("since").map(|s| s.parse::<PostId>()) {
+            Some(Ok(id)) => Some(id),
+            Some(Err(_)) => return Response::error(ErrorCode::Malformed),
+            None => None,
+        };
+        let limit = match req.query.get("limit").map(|s| s.parse::<usize>()) {
+            Some(Ok(limit)) => limit.min(REST_FEED_LIMIT),
+            Some(Err(_)) => return Response::error(ErrorCode::Malformed),
+            None => HISTORY_BACKFILL,
+        };
+        let mut posts = self.server.history(addr, limit).await;
+        if let Some(since) = since {
+            posts.retain(|sigpost| sigpost.post.id > since);
+        }
+        Response::json("200 OK", &posts)
+    }
+
+    async fn get_post(&self, req: &Request, addr: &str, id: &str) -> Response {
+        if let Err(response) = self.session(req) {
+            return response;
+        }
+        let (addr, id) = match (Address::parse_strict(addr), id.parse::<PostId>()) {
+            (Ok(addr), Ok(id)) => (addr, id),
+            _ => return Response::error(ErrorCode::Malformed),
+        };
+        match self.server.get_post(addr, id).await {
+            Some(sigpost) => Response::json("200 OK", &sigpost),
+            None => Response::error(ErrorCode::NotFound),
+        }
+    }
+
+    async fn post(&self, req: &Request) -> Response {
+        let (addr, pubkey) = match self.session(req) {
+            Ok(session) => session,
+            Err(response) => return response,
+        };
+        let sigpost: SignedPost = match serde_json::from_slice(&req.body) {
+            Ok(sigpost) => sigpost,
+            Err(_) => return Response::error(ErrorCode::Malformed),
+        };
+        if sigpost.addr != addr {
+            return Response::error(ErrorCode::UnknownAddress);
+        }
+        let now_ms = Utc::now().timestamp_millis() as u64;
+        if let Some(per_minute) = self.server.controller().tunables().posts_per_minute {
+            let mut sessions = self.sessions.lock().unwrap();
+            let slot = match req
+                .token
+                .as_ref()
+                .and_then(|token| sessions.tokens.get_mut(token))
+            {
+                Some(session) => take_slot(&mut session.posted, now_ms, per_minute),
+                None => Ok(()),
+            };
+            if let Err(wait) = slot {
+                return Response::from_message(ServerMessage::Error {
+                    code: ErrorCode::RateLimited,
+                    message: ErrorCode::RateLimited.to_string(),
+                    retry_after: Some(wait),
+                });
+            }
+        }
+        let location = format!(
+            "Location: /posts/{}/{}\r\n",
+            sigpost.addr.to_string(),
+            sigpost.post.id
+        );
+        match self.server.publish_post(sigpost, pubkey).await {
+            Ok(()) => Response {
+                status: "201 Created",
+                headers: location,
+                body: "{}".to_string(),
+            },
+            Err(code) => Response::error(code),
+        }
+    }
+}
+
+// None if it is not HTTP, or longer than REST_REQUEST_LIMIT
+async fn read_request<R>(stream: &mut R) -> Option<Request>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    let mut first_line = String::new();
+    stream.read_line(&mut first_line).await.ok()?;
+    let mut params = first_line.split_whitespace();
+    let (method, target) = (params.next()?.to_string(), params.next()?);
+    let (path, query) = parse_target(target);
+
+    let (mut content_length, mut token) = (0, None);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if stream.read_line(&mut line).await.ok()? == 0 {
+            return None;
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':')?;
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => content_length = value.parse().ok()?,
+            "authorization" => token = value.strip_prefix("Bearer ").map(str::to_string),
+            _ => (),
+        }
+    }
+    if content_length > REST_REQUEST_LIMIT {
+        return None;
+    }
+    let mut body = vec![0; content_length];
+    stream.read_exact(&mut body).await.ok()?;
+    Some(Request {
+        method,
+        path,
+        query,
+        token,
+        body,
+    })
+}
+
+// the path and the query parameters of a request target
+fn parse_target(target: &str) -> (String, HashMap<String, String>) {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let query = query
+        .split('&')
+        .filter_map(|param| param.split_once('='))
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    (path.to_string(), query)
+}
+
+// a malformed escape is kept as it is
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(b)) => {
+                decoded.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn read_request_test() {
+        let raw = b"POST /feed/abc?since=01ARZ3NDEKTSV4RRFFQ69G5FAV&limit=5 HTTP/1.1\r\n\
+                    Host: localhost\r\nAuthorization: Bearer tok+en/=\r\n\
+                    Content-Length: 2\r\n\r\n{}";
+        let req = read_request(&mut &raw[..]).await.unwrap();
+        assert_eq!(req.method, "POST");
+        assert_eq!(req.path, "/feed/abc");
+        assert_eq!(req.query["since"], "01ARZ3NDEKTSV4RRFFQ69G5FAV");
+        assert_eq!(req.query["limit"], "5");
+        assert_eq!(req.token.as_deref(), Some("tok+en/="));
+        assert_eq!(req.body, b"{}");
+
+        // cut short, or not HTTP at all
+        assert!(read_request(&mut &raw[..raw.len() - 1]).await.is_none());
+        assert!(read_request(&mut &b"\x00\x01"[..]).await.is_none());
+        let huge = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            REST_REQUEST_LIMIT + 1
+        );
+        assert!(read_request(&mut huge.as_bytes()).await.is_none());
+
+        let response = Response::from_message(ServerMessage::Error {
+            code: ErrorCode::RateLimited,
+            message: String::new(),
+            retry_after: Some(7),
+        });
+        assert!(response
+            .to_http()
+            .starts_with("HTTP/1.1 429 Too Many Requests\r\n"));
+        assert!(response.to_http().contains("Retry-After: 7\r\n"));
+
+        assert_eq!(percent_decode("ab%2Fc+d%2f"), "ab/c+d/");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz"), "%zz");
+    }
+}
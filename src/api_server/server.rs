@@ -1,8 +1,8 @@
+use chrono::Utc;
 use log::{error, info};
-use rand::prelude::*;
-use rand_chacha::ChaCha20Rng;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::mpsc::error::SendError;
-use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
 use tokio_stream::wrappers::UnboundedReceiverStream;
 
 use std::collections::HashMap;
@@ -15,22 +15,66 @@ use tokio::io;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::Mutex;
 use tokio_tungstenite::tungstenite::{self, Message};
-use tokio_tungstenite::{accept_async, WebSocketStream};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::{accept_async_with_config, WebSocketStream};
 
 use crate::crypto::PublicKey;
-use crate::service::{Config, NetworkController, Publisher, Subscriber};
-use crate::user::user::Address;
+use crate::service::{
+    recommend_follows, validate_draft, BackfillEvent, Config, ConfigEvent, Filter,
+    NetworkController, Publisher, SignatureStatus, Subscriber, UserIndex,
+};
+use crate::user::post::{SignedPost, VerifyError};
+use crate::user::post_id::PostId;
+use crate::user::user::{Address, UserAttribute};
+use crate::util::crypto_pool;
 
+use super::admin::{AdminCommand, AdminGuard};
 use super::client_info::ClientInfo;
-use super::message::{ClientMessage, ServerMessage};
+use super::drafts::DraftStore;
+use super::gallery::{Gallery, GalleryConfig};
+use super::message::{
+    ClientMessage, ErrorCode, ProfileSource, ProfileStatus, ServerMessage, UserInfo,
+};
+use super::relay_cache::{RelayCache, RelayCacheConfig};
+use super::rest::RestApi;
+use super::session::SessionKeys;
 use super::subscription_router::Router;
+use super::wire;
+use super::{
+    API_MESSAGE_LIMIT, PROFILE_CACHE_SIZE, PROFILE_CACHE_TTL, SAVED_SESSIONS_LIMIT,
+    SESSION_TOKEN_TTL,
+};
 
 #[derive(Clone)]
 pub struct ApiServer {
     net: Arc<NetworkController>,
-    publishers: Arc<Mutex<HashMap<Address, Publisher>>>,
+    publisher: Arc<Publisher>,
     subscriber: Arc<Subscriber>,
     router: Arc<Mutex<Router>>,
+    // profiles of the authors of verified posts, for SearchUser
+    users: Arc<Mutex<UserIndex>>,
+    // recent posts of subscribed addresses, when relaying is enabled
+    relay: Option<Arc<Mutex<RelayCache>>>,
+    // the block filter of the tunables, kept parsed
+    blocked: Arc<Mutex<Option<Filter>>>,
+    // profiles resolved for GetUserInfo, kept PROFILE_CACHE_TTL from when
+    // they were fetched
+    profiles: Arc<Mutex<HashMap<Address, UserInfo>>>,
+    drafts: Arc<Mutex<DraftStore>>,
+    // signs the session tokens of EstablishedSession
+    session_keys: Arc<SessionKeys>,
+    // the subscriptions of closed connections by their token, for Resume
+    saved_sessions: Arc<Mutex<HashMap<String, SavedSession>>>,
+    // checks ClientMessage::Admin against Config::operator_key
+    admin: Arc<Mutex<AdminGuard>>,
+    // Config::local_reload
+    local_reload: bool,
+}
+
+struct SavedSession {
+    // ms since the epoch, when the token does
+    expires_at: u64,
+    subscriptions: Vec<(Address, Option<Filter>)>,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -41,24 +85,86 @@ pub enum ApiServerError {
     WebSocket(tungstenite::error::Error),
     #[error("Sender error: {0}")]
     Sender(SendError<Message>),
+    #[error("Relay cache error: {0}")]
+    RelayCache(io::Error),
 }
 
 impl ApiServer {
     pub async fn new(config: Config) -> ApiServer {
+        let operator_key = config.operator_key.clone();
+        let local_reload = config.local_reload;
         let net = NetworkController::init(config).await;
-        let publishers = Arc::new(Mutex::new(HashMap::new()));
-        let subscriber = Arc::new(net.create_subscriber().await);
-        let router = Arc::new(Mutex::new(Router::new(subscriber.clone())));
+        if operator_key.is_some() {
+            info!("Taking admin commands for node {:?}", net.node_id());
+        }
+        let admin = AdminGuard::new(operator_key, net.node_id());
+        let publisher = net.publisher();
+        let subscriber = net.subscriptions().await.subscriber();
+        let blocked = Arc::new(Mutex::new(None));
+        let router = Router::new(subscriber.clone(), net.ingestor(), blocked.clone());
+        let router = Arc::new(Mutex::new(router));
+        let session_keys = Arc::new(SessionKeys::new(net.entropy().as_ref()));
 
         ApiServer {
             net: Arc::new(net),
-            publishers,
+            publisher,
             subscriber,
             router,
+            users: Arc::new(Mutex::new(UserIndex::new())),
+            relay: None,
+            blocked,
+            profiles: Arc::new(Mutex::new(HashMap::new())),
+            drafts: Arc::new(Mutex::new(DraftStore::new())),
+            session_keys,
+            saved_sessions: Arc::new(Mutex::new(HashMap::new())),
+            admin: Arc::new(Mutex::new(admin)),
+            local_reload,
         }
     }
 
-    pub async fn start(self, bind_addr: String) -> Result<(), ApiServerError> {
+    // the node the server runs on, to shut it down or read its stats
+    pub fn controller(&self) -> Arc<NetworkController> {
+        self.net.clone()
+    }
+
+    // Keeps the latest verified posts of every subscribed address on disk, so
+    // that subscribing clients get history at once and GetPost and GetHistory
+    // are answered without the DHT.
+    pub async fn with_relay_cache(
+        mut self,
+        config: RelayCacheConfig,
+    ) -> Result<ApiServer, ApiServerError> {
+        let cache = RelayCache::open(config)
+            .await
+            .map_err(ApiServerError::RelayCache)?;
+        self.relay = Some(Arc::new(Mutex::new(cache)));
+        Ok(self)
+    }
+
+    // Serves the public HTML pages on their own port, next to the WebSocket API.
+    // Returns the address actually bound.
+    pub async fn start_gallery(
+        &self,
+        bind_addr: String,
+        config: GalleryConfig,
+    ) -> Result<SocketAddr, ApiServerError> {
+        Gallery::new(self.net.clone(), config)
+            .start(bind_addr)
+            .await
+            .map_err(ApiServerError::Tcp)
+    }
+
+    // Serves the same API over HTTP and JSON on its own port, see RestApi.
+    // Returns the address actually bound.
+    pub async fn start_rest(&self, bind_addr: String) -> Result<SocketAddr, ApiServerError> {
+        RestApi::new(self.clone())
+            .start(bind_addr)
+            .await
+            .map_err(ApiServerError::Tcp)
+    }
+
+    // returns the address actually bound, so that port 0 can be used
+    pub async fn start(self, bind_addr: String) -> Result<SocketAddr, ApiServerError> {
         let listener = TcpListener::bind(bind_addr).await;
 
         if listener.is_err() {
@@ -66,11 +172,16 @@ impl ApiServer {
         }
 
         let listener = listener.ok().unwrap();
+        let local_addr = listener.local_addr().map_err(ApiServerError::Tcp)?;
 
         {
             let mut router = self.router.lock().await;
             router.start();
         }
+        tokio::spawn(follow_tunables(self.net.clone(), self.blocked.clone(), self.relay.clone()));
+        let (net, subscriber) = (self.net.clone(), self.subscriber.clone());
+        let blocked = self.blocked.clone();
+        tokio::spawn(ingest_subscribed(net, subscriber, self.relay.clone(), blocked));
 
         let server = self.clone();
 
@@ -80,7 +191,12 @@ impl ApiServer {
                     Ok((socket, addr)) => {
                         info!("TCP connection established: {}", addr);
 
-                        match accept_async(socket).await {
+                        let config = WebSocketConfig {
+                            max_message_size: Some(API_MESSAGE_LIMIT),
+                            max_frame_size: Some(API_MESSAGE_LIMIT),
+                            ..WebSocketConfig::default()
+                        };
+                        match accept_async_with_config(socket, Some(config)).await {
                             Ok(websocket) => {
                                 info!("WebSocket connection established: {}", addr);
                                 let server = server.clone();
@@ -100,46 +216,63 @@ impl ApiServer {
             }
         });
 
-        Ok(())
+        Ok(local_addr)
     }
 
     async fn handle_connection(self, websocket: WebSocketStream<TcpStream>, addr: SocketAddr) {
         let (outgoing, mut incoming) = websocket.split();
         let (tx, rx) = unbounded_channel();
 
-        let mut info = ClientInfo::new(tx);
+        let mut info = ClientInfo::new(tx, addr);
+        let encoding = info.encoding();
 
         let rxstream = UnboundedReceiverStream::new(rx);
 
-        let to_client = rxstream.map(|msg| Ok(msg)).forward(outgoing);
+        // posts from the subscription router are encoded here too
+        let to_client = rxstream
+            .map(move |msg| match msg {
+                Message::Text(s) => Message::Text(wire::encode(&s, encoding.get())),
+                msg => msg,
+            })
+            .map(Ok)
+            .forward(outgoing);
 
         let server = self.clone();
 
         let from_client = tokio::spawn(async move {
-            while let Some(msg) = incoming.next().await {
-                match msg {
-                    Ok(msg) => match msg {
-                        Message::Text(s) => {
-                            if let Ok(msg) = serde_json::from_str::<ClientMessage>(&s) {
-                                server.handle_client_message(&mut info, msg).await?;
-                            } else {
-                                continue;
+            let read: Result<(), ApiServerError> = async {
+                while let Some(msg) = incoming.next().await {
+                    match msg {
+                        Ok(msg) => match msg {
+                            Message::Text(s) => {
+                                let s = wire::decode(&s, info.encoding().get());
+                                if let Ok(msg) = serde_json::from_str::<ClientMessage>(&s) {
+                                    server.handle_client_message(&mut info, msg).await?;
+                                } else {
+                                    info.send_error(ErrorCode::Malformed)
+                                        .map_err(ApiServerError::Sender)?;
+                                }
                             }
-                        }
-                        Message::Ping(payload) => {
-                            info.send(Message::Pong(payload))
-                                .map_err(|e| ApiServerError::Sender(e))?;
-                        }
-                        Message::Close(cf) => {
-                            info.send(Message::Close(cf))
-                                .map_err(|e| ApiServerError::Sender(e))?;
-                        }
-                        _ => continue,
-                    },
-                    Err(e) => return Err(ApiServerError::WebSocket(e)),
+                            Message::Ping(payload) => {
+                                info.send(Message::Pong(payload))
+                                    .map_err(ApiServerError::Sender)?;
+                            }
+                            Message::Close(cf) => {
+                                info.send(Message::Close(cf))
+                                    .map_err(ApiServerError::Sender)?;
+                            }
+                            _ => continue,
+                        },
+                        Err(e) => return Err(ApiServerError::WebSocket(e)),
+                    }
                 }
+                Ok(())
             }
-            Ok(())
+            .await;
+            // the subscriptions are kept for a while, for the client to Resume
+            server.save_session(&info).await;
+            server.disconnect(&info).await;
+            read
         });
 
         tokio::select! {
@@ -148,82 +281,733 @@ impl ApiServer {
         }
     }
 
+    // a subscription of the connection, with the router and in info
+    async fn route(&self, info: &mut ClientInfo, addr: Address, filter: Option<Filter>) {
+        let router = self.router.lock().await;
+        let (tx, blocked) = (info.get_sender(), info.block_list());
+        router
+            .subscribe(addr.clone(), tx, filter.clone(), info.typed_events(), blocked)
+            .await;
+        info.add_subscription(addr, filter);
+    }
+
+    // once established, by a challenge or a session token
+    async fn add_author(&self, info: &mut ClientInfo, pk: PublicKey) {
+        let addr = Address::from(pk);
+        self.publisher.add_author(addr.clone()).await;
+        if info.watch_mentions(&addr) {
+            self.push_mentions(addr, info.get_sender()).await;
+        }
+    }
+
+    // a new token for pk, which the connection is saved under when it closes
+    fn send_session(
+        &self,
+        info: &mut ClientInfo,
+        pk: &PublicKey,
+    ) -> Result<(), SendError<Message>> {
+        let expires_at = Utc::now().timestamp_millis() as u64 + SESSION_TOKEN_TTL;
+        let token = self.session_keys.issue(pk, expires_at, self.net.entropy().as_ref());
+        info.set_session(token.clone(), expires_at);
+        info.send_message(&ServerMessage::EstablishedSession { token, expires_at })
+    }
+
+    async fn run_admin(&self, command: AdminCommand) -> ServerMessage {
+        match command {
+            AdminCommand::Reload => match self.net.reload().await {
+                Ok(changed) => ServerMessage::ConfigReloaded(changed),
+                Err(e) => ServerMessage::Error {
+                    code: ErrorCode::InvalidConfig,
+                    message: e.to_string(),
+                    retry_after: None,
+                },
+            },
+            AdminCommand::BanPeer(ip) => {
+                self.net.ban_peer(ip).await;
+                ServerMessage::Success
+            }
+            AdminCommand::RotateLogs => ServerMessage::error(ErrorCode::Unsupported),
+            AdminCommand::DumpStats => ServerMessage::Stats(self.net.stats_snapshot()),
+        }
+    }
+
+    // The routes of a closed connection go at once, instead of when a post
+    // fails to reach it.
+    async fn disconnect(&self, info: &ClientInfo) {
+        let router = self.router.lock().await;
+        router.disconnect(&info.subscripted_addrs(), &info.get_sender()).await;
+    }
+
+    // Keeps the subscriptions of a closed connection until its token expires.
+    // The expired are dropped first, then the soonest to expire past the limit.
+    async fn save_session(&self, info: &ClientInfo) {
+        let (token, expires_at) = match info.session() {
+            Some(session) => session.clone(),
+            None => return,
+        };
+        let now_ms = Utc::now().timestamp_millis() as u64;
+        let mut saved = self.saved_sessions.lock().await;
+        saved.retain(|_, s| s.expires_at > now_ms);
+        while saved.len() >= SAVED_SESSIONS_LIMIT {
+            let soonest = saved
+                .iter()
+                .min_by_key(|(_, s)| s.expires_at)
+                .map(|(token, _)| token.clone())
+                .unwrap();
+            saved.remove(&soonest);
+        }
+        let subscriptions = info.subscriptions().to_vec();
+        saved.insert(token, SavedSession { expires_at, subscriptions });
+    }
+
+    // until the client disconnects
+    async fn push_mentions(&self, addr: Address, tx: UnboundedSender<Message>) {
+        let mut notifications = self.net.notifier().await.watch(addr);
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = tx.closed() => break,
+                    notification = notifications.recv() => match notification {
+                        Some(notification) => {
+                            let msg = ServerMessage::Mentioned(notification);
+                            let text = serde_json::to_string(&msg).unwrap();
+                            if tx.send(Message::Text(text)).is_err() {
+                                break;
+                            }
+                        }
+                        None => break,
+                    },
+                }
+            }
+        });
+    }
+
+    async fn cached_posts(&self, addr: &Address) -> Vec<SignedPost> {
+        match &self.relay {
+            Some(relay) => {
+                let mut relay = relay.lock().await;
+                let limit = relay.posts_per_addr();
+                relay.recent(addr, limit)
+            }
+            None => Vec::new(),
+        }
+    }
+
     async fn handle_client_message(
         &self,
         info: &mut ClientInfo,
         msg: ClientMessage,
     ) -> Result<(), ApiServerError> {
         match msg {
-            ClientMessage::EstablishReq { addr, pubkey } => match PublicKey::from_bytes(&pubkey) {
-                Ok(pubkey) => {
-                    let addr = Address::new(addr);
-                    let addr2 = Address::from(pubkey.clone());
-                    if addr == addr2 {
-                        let mut challenge = [0; 32];
-                        ChaCha20Rng::from_entropy().fill_bytes(&mut challenge);
-                        info.send_challenge(pubkey, challenge)
-                            .map_err(|e| ApiServerError::Sender(e))?;
+            // pubkey is a valid point already, otherwise the message would not have parsed
+            ClientMessage::EstablishReq { addr, pubkey } => {
+                if Address::new(addr) == Address::from(pubkey.clone()) {
+                    let mut challenge = [0; 32];
+                    self.net.entropy().fill_bytes(&mut challenge);
+                    info.send_challenge(pubkey, challenge)
+                        .map_err(ApiServerError::Sender)?;
+                } else {
+                    info.send_error(ErrorCode::AddressMismatch)
+                        .map_err(ApiServerError::Sender)?;
+                }
+            }
+            ClientMessage::ChallengeResponce(sig) => match info.verify_challenge_sig(sig) {
+                Ok(pk) => {
+                    if info.sessions() {
+                        self.send_session(info, &pk)
+                            .map_err(ApiServerError::Sender)?;
                     } else {
-                        info.send_invalid().map_err(|e| ApiServerError::Sender(e))?;
+                        info.send_message(&ServerMessage::Established)
+                            .map_err(ApiServerError::Sender)?;
                     }
+
+                    // the key is registered in the user DHT once the client
+                    // sends a Register, which only its owner can sign
+                    self.add_author(info, pk).await;
                 }
-                Err(_) => {
-                    info.send_invalid().map_err(|e| ApiServerError::Sender(e))?;
+                Err(code) => {
+                    info.send_error(code).map_err(ApiServerError::Sender)?;
                 }
             },
-            ClientMessage::ChallengeResponce(sig) => {
-                if let Ok(pk) = info.verify_challenge_sig(sig) {
-                    info.send(Message::Text(
-                        serde_json::to_string(&ServerMessage::Established).unwrap(),
-                    ))
-                    .map_err(|e| ApiServerError::Sender(e))?;
-
-                    let mut publishers = self.publishers.lock().await;
-                    publishers
-                        .entry(Address::from(pk.clone()))
-                        .or_insert(self.net.create_publisher(&pk).await);
+            ClientMessage::SubscribeReq(addr) => {
+                if info.is_established() {
+                    self.route(info, addr.clone(), None).await;
+                    let (typed, blocked) = (info.typed_events(), info.block_list());
+                    info.send_message(&ServerMessage::Success)
+                        .map_err(ApiServerError::Sender)?;
+                    for sigpost in self.cached_posts(&addr).await {
+                        if blocked.lock().unwrap().blocks(&sigpost) {
+                            continue;
+                        }
+                        info.send_message(&ServerMessage::push(sigpost, typed))
+                            .map_err(ApiServerError::Sender)?;
+                    }
                 } else {
-                    info.send_invalid().map_err(|e| ApiServerError::Sender(e))?;
+                    info.send_error(ErrorCode::NotEstablished)
+                        .map_err(ApiServerError::Sender)?;
                 }
             }
-            ClientMessage::SubscribeReq(addr) => {
+            ClientMessage::FilteredSubscribeReq { addr, filter } => {
+                if !info.is_established() {
+                    info.send_error(ErrorCode::NotEstablished)
+                        .map_err(ApiServerError::Sender)?;
+                } else {
+                    match Filter::parse(&filter) {
+                        Ok(filter) => {
+                            self.route(info, addr.clone(), Some(filter.clone())).await;
+                            let (typed, blocked) = (info.typed_events(), info.block_list());
+                            info.send_message(&ServerMessage::Success)
+                                .map_err(ApiServerError::Sender)?;
+                            for sigpost in self.cached_posts(&addr).await {
+                                if filter.matches(&sigpost)
+                                    && !blocked.lock().unwrap().blocks(&sigpost)
+                                {
+                                    info.send_message(&ServerMessage::push(sigpost, typed))
+                                        .map_err(ApiServerError::Sender)?;
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            info.send_message(&ServerMessage::Error {
+                                code: ErrorCode::InvalidFilter,
+                                message: e.to_string(),
+                                retry_after: None,
+                            })
+                            .map_err(ApiServerError::Sender)?;
+                        }
+                    }
+                }
+            }
+            // every subscription of the connection to addr, filtered or not
+            ClientMessage::UnsubscribeReq(addr) => {
                 if info.is_established() {
                     let router = self.router.lock().await;
-                    router.subscribe(addr, info.get_sender()).await;
-                    info.send(Message::Text(
-                        serde_json::to_string(&ServerMessage::Success).unwrap(),
-                    ))
-                    .map_err(|e| ApiServerError::Sender(e))?;
+                    router.unsubscribe(addr.clone(), info.get_sender()).await;
+                    info.remove_subscriptions(&addr);
+                    info.send_message(&ServerMessage::Success)
                 } else {
-                    info.send_invalid().map_err(|e| ApiServerError::Sender(e))?;
+                    info.send_error(ErrorCode::NotEstablished)
+                }
+                .map_err(ApiServerError::Sender)?;
+            }
+            // every encoding is supported, so the client's first choice is taken
+            ClientMessage::Hello {
+                encodings,
+                typed_events,
+                sessions,
+            } => {
+                let encoding = encodings.first().copied().unwrap_or_default();
+                info.encoding().set(encoding);
+                info.set_typed_events(typed_events);
+                info.set_sessions(sessions);
+                info.send_message(&ServerMessage::Hello {
+                    encoding,
+                    typed_events,
+                })
+                    .map_err(ApiServerError::Sender)?;
+            }
+            ClientMessage::Resume(token) => {
+                let now_ms = Utc::now().timestamp_millis() as u64;
+                let verified = {
+                    let (session_keys, token) = (self.session_keys.clone(), token.clone());
+                    crypto_pool::run(move || session_keys.verify(&token, now_ms)).await
+                };
+                match verified {
+                    Some(pk) => {
+                        // routed before the reply, so that nothing posted
+                        // after it is missed
+                        info.resume(pk.clone());
+                        let saved = self.saved_sessions.lock().await.remove(&token);
+                        for (addr, filter) in saved.map(|s| s.subscriptions).unwrap_or_default() {
+                            self.route(info, addr, filter).await;
+                        }
+                        self.send_session(info, &pk)
+                            .map_err(ApiServerError::Sender)?;
+                        self.add_author(info, pk).await;
+                    }
+                    None => {
+                        info.send_error(ErrorCode::InvalidToken)
+                            .map_err(ApiServerError::Sender)?;
+                    }
+                }
+            }
+            ClientMessage::Register(record) => {
+                if !info.is_established() {
+                    info.send_error(ErrorCode::NotEstablished)
+                        .map_err(ApiServerError::Sender)?;
+                } else if info.get_pubkey(&record.addr()).is_none() {
+                    info.send_error(ErrorCode::UnknownAddress)
+                        .map_err(ApiServerError::Sender)?;
+                } else {
+                    let verified = {
+                        let record = record.clone();
+                        crypto_pool::run(move || record.verify()).await
+                    };
+                    match verified {
+                        Ok(_) => {
+                            self.net.register_author(&record).await;
+                            info.send_message(&ServerMessage::Success)
+                        }
+                        Err(_) => info.send_error(ErrorCode::InvalidSignature),
+                    }
+                    .map_err(ApiServerError::Sender)?;
                 }
             }
             ClientMessage::Post(post) => {
+                let now_ms = Utc::now().timestamp_millis() as u64;
+                let per_minute = self.net.tunables().posts_per_minute;
+                if !info.is_established() {
+                    info.send_error(ErrorCode::NotEstablished)
+                        .map_err(ApiServerError::Sender)?;
+                } else if let Err(wait) = info.take_post_slot(now_ms, per_minute) {
+                    info.send_message(&ServerMessage::Error {
+                        code: ErrorCode::RateLimited,
+                        message: ErrorCode::RateLimited.to_string(),
+                        retry_after: Some(wait),
+                    })
+                    .map_err(ApiServerError::Sender)?;
+                } else if let Some(pk) = info.get_pubkey(&post.addr) {
+                    match self.publish_post(*post, pk).await {
+                        Ok(()) => info.send_message(&ServerMessage::Success),
+                        Err(code) => info.send_error(code),
+                    }
+                    .map_err(ApiServerError::Sender)?;
+                } else {
+                    info.send_error(ErrorCode::UnknownAddress)
+                        .map_err(ApiServerError::Sender)?;
+                }
+            }
+            ClientMessage::RecommendFollowsReq { limit } => {
+                if let Some(me) = info.addr() {
+                    let posts = self.router.lock().await.recent_posts().await;
+                    let following = info.subscripted_addrs();
+                    let ret = recommend_follows(&posts, &me, |a| following.contains(a), limit);
+                    info.send_message(&ServerMessage::Recommendations(ret))
+                        .map_err(ApiServerError::Sender)?;
+                } else {
+                    info.send_error(ErrorCode::NotEstablished)
+                        .map_err(ApiServerError::Sender)?;
+                }
+            }
+            ClientMessage::SearchUser { query, limit } => {
                 if info.is_established() {
-                    if let Some(pk) = info.get_pubkey(&post.addr) {
-                        match post.verify(&pk) {
-                            Ok(()) => {
-                                let mut publishers = self.publishers.lock().await;
-                                if let Some(publisher) =  publishers.get_mut(&post.addr) {
-                                    publisher.publish(msg, dst)
-                                }
-                            }
-                            Err(_) => {
-                                info.send_invalid().map_err(|e|ApiServerError::Sender(e))?;
-                            }
-                        }
+                    let found = self.users.lock().await.find_user(&query, limit);
+                    info.send_message(&ServerMessage::UserCandidates(found))
+                        .map_err(ApiServerError::Sender)?;
+                } else {
+                    info.send_error(ErrorCode::NotEstablished)
+                        .map_err(ApiServerError::Sender)?;
+                }
+            }
+            ClientMessage::GetPost { addr, id } => {
+                if !info.is_established() {
+                    info.send_error(ErrorCode::NotEstablished)
+                        .map_err(ApiServerError::Sender)?;
+                } else {
+                    match self.get_post(addr, id).await {
+                        Some(sigpost) => info.send_message(&ServerMessage::Post(sigpost)),
+                        None => info.send_error(ErrorCode::NotFound),
+                    }
+                    .map_err(ApiServerError::Sender)?;
+                }
+            }
+            ClientMessage::GetHistory { addr, limit } => {
+                if !info.is_established() {
+                    info.send_error(ErrorCode::NotEstablished)
+                        .map_err(ApiServerError::Sender)?;
+                } else {
+                    let posts = self.history(addr, limit).await;
+                    info.send_message(&ServerMessage::History(posts))
+                        .map_err(ApiServerError::Sender)?;
+                }
+            }
+            ClientMessage::GetUserInfo(addr) => {
+                if !info.is_established() {
+                    info.send_error(ErrorCode::NotEstablished)
+                        .map_err(ApiServerError::Sender)?;
+                } else {
+                    let now_ms = Utc::now().timestamp_millis() as u64;
+                    let found = self
+                        .user_info(addr, now_ms, || info.take_lookup_slot(now_ms))
+                        .await;
+                    match found {
+                        Ok(found) => info.send_message(&ServerMessage::UserInfo(found)),
+                        Err(e) => info.send_message(&e),
+                    }
+                    .map_err(ApiServerError::Sender)?;
+                }
+            }
+            ClientMessage::GetStats => {
+                if info.is_established() {
+                    info.send_message(&ServerMessage::Stats(self.net.stats_snapshot()))
+                        .map_err(ApiServerError::Sender)?;
+                } else {
+                    info.send_error(ErrorCode::NotEstablished)
+                        .map_err(ApiServerError::Sender)?;
+                }
+            }
+            ClientMessage::ReloadConfig => {
+                if !(self.local_reload && info.is_local()) {
+                    info.send_error(ErrorCode::Forbidden)
+                        .map_err(ApiServerError::Sender)?;
+                } else {
+                    match self.net.reload().await {
+                        Ok(changed) => info.send_message(&ServerMessage::ConfigReloaded(changed)),
+                        Err(e) => info.send_message(&ServerMessage::Error {
+                            code: ErrorCode::InvalidConfig,
+                            message: e.to_string(),
+                            retry_after: None,
+                        }),
+                    }
+                    .map_err(ApiServerError::Sender)?;
+                }
+            }
+            ClientMessage::Admin(signed) => {
+                let now_ms = Utc::now().timestamp_millis() as u64;
+                let accepted = self.admin.lock().await.accept(&signed, now_ms);
+                match accepted {
+                    Ok(()) => {
+                        info!("Admin command from the operator: {:?}", signed.command);
+                        let reply = self.run_admin(signed.command).await;
+                        info.send_message(&reply)
+                    }
+                    Err(code) => info.send_error(code),
+                }
+                .map_err(ApiServerError::Sender)?;
+            }
+            ClientMessage::Block(addr) => {
+                if info.is_established() {
+                    info.block_list().lock().unwrap().block(addr);
+                    info.send_message(&ServerMessage::Success)
+                } else {
+                    info.send_error(ErrorCode::NotEstablished)
+                }
+                .map_err(ApiServerError::Sender)?;
+            }
+            ClientMessage::Unblock(addr) => {
+                if info.is_established() {
+                    info.block_list().lock().unwrap().unblock(&addr);
+                    info.send_message(&ServerMessage::Success)
+                } else {
+                    info.send_error(ErrorCode::NotEstablished)
+                }
+                .map_err(ApiServerError::Sender)?;
+            }
+            ClientMessage::ListAdd { list, addr } => {
+                if info.is_established() {
+                    info.add_to_list(list, addr);
+                    info.send_message(&ServerMessage::Success)
+                } else {
+                    info.send_error(ErrorCode::NotEstablished)
+                }
+                .map_err(ApiServerError::Sender)?;
+            }
+            ClientMessage::ListRemove { list, addr } => {
+                if info.is_established() {
+                    info.remove_from_list(&list, &addr);
+                    info.send_message(&ServerMessage::Success)
+                } else {
+                    info.send_error(ErrorCode::NotEstablished)
+                }
+                .map_err(ApiServerError::Sender)?;
+            }
+            ClientMessage::GetLists => {
+                if info.is_established() {
+                    info.send_message(&ServerMessage::Lists(info.lists().clone()))
+                } else {
+                    info.send_error(ErrorCode::NotEstablished)
+                }
+                .map_err(ApiServerError::Sender)?;
+            }
+            ClientMessage::GetListPosts { list, limit } => {
+                if !info.is_established() {
+                    info.send_error(ErrorCode::NotEstablished)
+                        .map_err(ApiServerError::Sender)?;
+                } else {
+                    let members = info.lists().get(&list).cloned().unwrap_or_default();
+                    let mut posts: Vec<SignedPost> = self
+                        .router
+                        .lock()
+                        .await
+                        .recent_posts()
+                        .await
+                        .into_iter()
+                        .filter(|sigpost| members.contains(&sigpost.addr))
+                        .collect();
+                    // the latest limit, oldest first
+                    posts.drain(..posts.len().saturating_sub(limit));
+                    info.send_message(&ServerMessage::History(posts))
+                        .map_err(ApiServerError::Sender)?;
+                }
+            }
+            ClientMessage::ValidateDraft(hoot) => {
+                if let Some(me) = info.addr() {
+                    let users = self.users.lock().await;
+                    // the profile of the latest post seen from me, if any
+                    let attr = users
+                        .get(&me)
+                        .cloned()
+                        .unwrap_or_else(|| UserAttribute::new("", 0, ""));
+                    let report = validate_draft(&hoot, &me, &attr, users.names());
+                    drop(users);
+                    info.send_message(&ServerMessage::DraftReport(report))
+                } else {
+                    info.send_error(ErrorCode::NotEstablished)
+                }
+                .map_err(ApiServerError::Sender)?;
+            }
+            ClientMessage::SaveDraft { id, hoot } => {
+                if let Some(me) = info.addr() {
+                    let now_ms = Utc::now().timestamp_millis() as u64;
+                    match self.drafts.lock().await.save(&me, id, *hoot, now_ms) {
+                        Some(id) => info.send_message(&ServerMessage::DraftSaved { id }),
+                        None if id.is_some() => info.send_error(ErrorCode::NotFound),
+                        None => info.send_error(ErrorCode::TooManyDrafts),
+                    }
+                } else {
+                    info.send_error(ErrorCode::NotEstablished)
+                }
+                .map_err(ApiServerError::Sender)?;
+            }
+            ClientMessage::ListDrafts => {
+                if let Some(me) = info.addr() {
+                    let drafts = self.drafts.lock().await.list(&me);
+                    info.send_message(&ServerMessage::Drafts(drafts))
+                } else {
+                    info.send_error(ErrorCode::NotEstablished)
+                }
+                .map_err(ApiServerError::Sender)?;
+            }
+            ClientMessage::DeleteDraft(id) => {
+                if let Some(me) = info.addr() {
+                    if self.drafts.lock().await.remove(&me, id) {
+                        info.send_message(&ServerMessage::Success)
                     } else {
-                        info.send(Message::Text(
-                            serde_json::to_string(&ServerMessage::Denied).unwrap(),
-                        ))
-                        .map_err(|e| ApiServerError::Sender(e))?;
+                        info.send_error(ErrorCode::NotFound)
                     }
                 } else {
-                    info.send_invalid().map_err(|e|ApiServerError::Sender(e))?;
+                    info.send_error(ErrorCode::NotEstablished)
                 }
+                .map_err(ApiServerError::Sender)?;
+            }
+            ClientMessage::GetSyncStatus { addr, head } => {
+                if !info.is_established() {
+                    info.send_error(ErrorCode::NotEstablished)
+                        .map_err(ApiServerError::Sender)?;
+                } else {
+                    let head = match (head, &self.relay) {
+                        (Some(head), _) => Some(head),
+                        (None, Some(relay)) => {
+                            relay.lock().await.recent(&addr, 1).last().map(|p| p.post.id)
+                        }
+                        (None, None) => None,
+                    };
+                    let status = self.net.sync_status(&addr, head).await;
+                    info.send_message(&ServerMessage::SyncStatus { addr, status })
+                        .map_err(ApiServerError::Sender)?;
+                }
+            }
+            _ => {
+                info.send_error(ErrorCode::Unsupported)
+                    .map_err(ApiServerError::Sender)?;
             }
-            _ => (),
         }
         Ok(())
     }
+
+    // Verifies post against pk, the key its author established, and publishes it
+    pub(super) async fn publish_post(
+        &self,
+        post: SignedPost,
+        pk: PublicKey,
+    ) -> Result<(), ErrorCode> {
+        let verified = {
+            let (post, pk) = (post.clone(), pk.clone());
+            crypto_pool::run(move || post.verify(&pk)).await
+        };
+        if let Err(e) = verified {
+            return Err(match e {
+                VerifyError::Address => ErrorCode::AddressMismatch,
+                VerifyError::Signature(_) | VerifyError::Size | VerifyError::Version(_) => {
+                    ErrorCode::InvalidSignature
+                }
+            });
+        }
+        self.users
+            .lock()
+            .await
+            .insert(post.addr.clone(), post.post.user_attr.clone());
+        let msg = serde_json::to_vec(&post).unwrap();
+        let published = match post.direct_to() {
+            Some(to) => self.publisher.send_direct(&msg, &post.addr, to).await,
+            None => self.publisher.publish(&msg, &post.addr).await,
+        };
+        if published.is_err() {
+            return Err(ErrorCode::UnknownAddress);
+        }
+        self.net.deliver_mentions(&pk, &post).await;
+        Ok(())
+    }
+
+    pub(super) async fn get_post(&self, addr: Address, id: PostId) -> Option<SignedPost> {
+        let cached = match &self.relay {
+            Some(relay) => relay.lock().await.get(&addr, id),
+            None => None,
+        };
+        match cached {
+            Some(sigpost) => Some(sigpost),
+            None => self.net.get_post(addr, id).await,
+        }
+    }
+
+    // the latest limit posts of addr, oldest first, from the relay cache or else the DHT
+    pub(super) async fn history(&self, addr: Address, limit: usize) -> Vec<SignedPost> {
+        let mut posts = match &self.relay {
+            Some(relay) => relay.lock().await.recent(&addr, limit),
+            None => Vec::new(),
+        };
+        if posts.is_empty() {
+            let mut events = self.net.backfill(addr, limit);
+            while let Some(event) = events.recv().await {
+                if let BackfillEvent::Finished(found) = event {
+                    posts = found;
+                }
+            }
+            posts.reverse();
+        }
+        posts
+    }
+
+    // The profile of addr with its stats, from the cache, or else from the DHT
+    // when take_lookup_slot allows. Err is the error to answer with.
+    pub(super) async fn user_info(
+        &self,
+        addr: Address,
+        now_ms: u64,
+        take_lookup_slot: impl FnOnce() -> Result<(), u64>,
+    ) -> Result<UserInfo, ServerMessage> {
+        let stats = self.net.author_stats(&addr);
+        if let Some(cached) = self.cached_profile(&addr, now_ms).await {
+            return Ok(UserInfo { stats, ..cached });
+        }
+        if let Err(wait) = take_lookup_slot() {
+            return Err(ServerMessage::Error {
+                code: ErrorCode::RateLimited,
+                message: ErrorCode::RateLimited.to_string(),
+                retry_after: Some(wait),
+            });
+        }
+        match self.fetch_profile(addr, now_ms).await {
+            Some(found) => Ok(UserInfo { stats, ..found }),
+            None => Err(ServerMessage::error(ErrorCode::NotFound)),
+        }
+    }
+
+    async fn cached_profile(&self, addr: &Address, now_ms: u64) -> Option<UserInfo> {
+        let profiles = self.profiles.lock().await;
+        let cached = profiles.get(addr)?;
+        let fetched_at = cached.status.as_ref().map_or(0, |status| status.fetched_at);
+        (fetched_at + PROFILE_CACHE_TTL > now_ms).then(|| cached.clone())
+    }
+
+    // The profile the author published, checked against the key of addr.
+    // Failing that the one carried by the author's latest post, and whether the
+    // key it is signed with is the one registered for addr.
+    async fn fetch_profile(&self, addr: Address, now_ms: u64) -> Option<UserInfo> {
+        let found = match self.net.get_profile(&addr).await {
+            Some(profile) => {
+                let signed_at = profile.attr.created_at;
+                UserInfo {
+                    status: Some(ProfileStatus {
+                        source: ProfileSource::Dht,
+                        verification: SignatureStatus::Valid,
+                        signed_at,
+                        fetched_at: now_ms,
+                    }),
+                    ..UserInfo::from(profile)
+                }
+            }
+            None => {
+                let sigpost = self.net.latest_post(&addr).await?;
+                let report = self.net.verify_post(&sigpost, &HashMap::new(), None).await;
+                UserInfo {
+                    addr: addr.clone(),
+                    attr: sigpost.post.user_attr.clone(),
+                    signature: None,
+                    stats: None,
+                    status: Some(ProfileStatus {
+                        source: ProfileSource::PostLog,
+                        verification: report.signature,
+                        signed_at: sigpost.post.created_at,
+                        fetched_at: now_ms,
+                    }),
+                }
+            }
+        };
+        let mut profiles = self.profiles.lock().await;
+        profiles.retain(|_, cached| {
+            let fetched_at = cached.status.as_ref().map_or(0, |status| status.fetched_at);
+            fetched_at + PROFILE_CACHE_TTL > now_ms
+        });
+        if profiles.len() < PROFILE_CACHE_SIZE {
+            profiles.insert(addr, found.clone());
+        }
+        Some(found)
+    }
+}
+
+// Keeps the block filter and the relay quota in line with the tunables
+async fn follow_tunables(
+    net: Arc<NetworkController>,
+    blocked: Arc<Mutex<Option<Filter>>>,
+    relay: Option<Arc<Mutex<RelayCache>>>,
+) {
+    let mut events = net.config_events();
+    loop {
+        let tunables = net.tunables();
+        *blocked.lock().await = tunables.block_filter();
+        if let Some(relay) = &relay {
+            if let Err(e) = relay.lock().await.set_quota(tunables.relay_quota).await {
+                error!("Relay cache error: {}", e);
+            }
+        }
+        loop {
+            match events.recv().await {
+                Ok(ConfigEvent::Reloaded(_)) | Err(RecvError::Lagged(_)) => break,
+                Ok(ConfigEvent::Rejected(_)) => continue,
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+// Verifies the posts of subscribed addresses, which counts them in the
+// author stats of GetUserInfo, and keeps them in the cache when relaying.
+async fn ingest_subscribed(
+    net: Arc<NetworkController>,
+    subscriber: Arc<Subscriber>,
+    relay: Option<Arc<Mutex<RelayCache>>>,
+    blocked: Arc<Mutex<Option<Filter>>>,
+) {
+    let mut rx = subscriber.get_receiver();
+    let ingestor = net.ingestor();
+    loop {
+        let sigpost = match rx.recv().await {
+            Ok(sigpost) => sigpost,
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break,
+        };
+        if sigpost.direct_to().is_some() {
+            continue;
+        }
+        if matches!(&*blocked.lock().await, Some(f) if f.matches(&sigpost)) {
+            continue;
+        }
+        let verified = ingestor.ingest_decoded(sigpost).await;
+        if let (Ok(verified), Some(relay)) = (verified, &relay) {
+            if let Err(e) = relay.lock().await.insert(verified.sigpost).await {
+                error!("Relay cache error: {}", e);
+            }
+        }
+    }
 }
@@ -0,0 +1,75 @@
+use std::convert::TryInto;
+
+use crate::crypto::{PublicKey, SecretKey};
+use crate::util::base64;
+use crate::util::rng::Entropy;
+
+// the key, expiry and nonce a token signs over, and the signature
+const TOKEN_LEN: usize = 32 + 8 + 16 + 64;
+
+// Mints the session tokens of EstablishedSession, which stand for a signed
+// challenge until they expire. They are signed with a key made when
+// the server starts, so only its own verify, and none outlive a restart.
+pub(super) struct SessionKeys {
+    key: SecretKey,
+    pubkey: PublicKey,
+}
+
+impl SessionKeys {
+    pub fn new(entropy: &dyn Entropy) -> SessionKeys {
+        let key = SecretKey::random_from(entropy);
+        let pubkey = key.public_key();
+        SessionKeys { key, pubkey }
+    }
+
+    // a token for pubkey until expires_at, ms since the epoch
+    pub fn issue(&self, pubkey: &PublicKey, expires_at: u64, entropy: &dyn Entropy) -> String {
+        let mut nonce = [0; 16];
+        entropy.fill_bytes(&mut nonce);
+        let mut token = [&pubkey.to_bytes()[..], &expires_at.to_be_bytes(), &nonce].concat();
+        let signature = self.key.sign(&token);
+        token.extend_from_slice(&signature);
+        String::from_utf8(base64::encode(&token)).unwrap()
+    }
+
+    // the key token was issued for, unless it is forged, malformed or expired
+    pub fn verify(&self, token: &str, now_ms: u64) -> Option<PublicKey> {
+        let token = base64::decode(token.as_bytes()).ok()?;
+        if token.len() != TOKEN_LEN {
+            return None;
+        }
+        let (signed, signature) = token.split_at(TOKEN_LEN - 64);
+        self.pubkey
+            .verify(signature.try_into().unwrap(), signed)
+            .ok()?;
+        let expires_at = u64::from_be_bytes(signed[32..40].try_into().unwrap());
+        if expires_at <= now_ms {
+            return None;
+        }
+        PublicKey::from_bytes(signed[..32].try_into().unwrap()).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::rng::SystemEntropy;
+
+    #[test]
+    fn session_token_test() {
+        let keys = SessionKeys::new(&SystemEntropy);
+        let pubkey = SecretKey::random().public_key();
+        let token = keys.issue(&pubkey, 1000, &SystemEntropy);
+        assert_eq!(keys.verify(&token, 999), Some(pubkey.clone()));
+        assert_eq!(keys.verify(&token, 1000), None);
+
+        // another server's, tampered with, or not a token at all
+        let other = SessionKeys::new(&SystemEntropy);
+        assert_eq!(other.verify(&token, 0), None);
+        let mut bytes = base64::decode(token.as_bytes()).unwrap();
+        bytes[35] ^= 1;
+        let tampered = String::from_utf8(base64::encode(&bytes)).unwrap();
+        assert_eq!(keys.verify(&tampered, 0), None);
+        assert_eq!(keys.verify("not a token", 0), None);
+    }
+}
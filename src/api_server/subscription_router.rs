@@ -1,29 +1,63 @@
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex as StdMutex};
 use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::broadcast::Receiver;
 use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::Mutex;
 use tokio_tungstenite::tungstenite::Message;
 
-use crate::service::Subscriber;
-use crate::user::post::SignedPost;
-use crate::user::user::Address;
+use crate::service::{Filter, Ingestor, Subscriber, SubscriptionHandle};
+use crate::user::block::BlockList;
+use crate::user::post::{PostKind, SignedPost};
+use crate::user::user::{Address, UserAttribute};
 
 use super::message::ServerMessage;
 
+type RoutingMap = HashMap<Address, Route>;
+
+// the clients of one author, and the subscription kept for them
+struct Route {
+    clients: Vec<RouteClient>,
+    // the profile of the author's last routed post
+    last_attr: Option<UserAttribute>,
+    _subscription: SubscriptionHandle,
+}
+
+struct RouteClient {
+    tx: UnboundedSender<Message>,
+    filter: Option<Filter>,
+    typed_events: bool,
+    // the connection's own, see ClientMessage::Block
+    blocked: Arc<StdMutex<BlockList>>,
+}
+
+// how many of the latest routed posts are kept for recommendations
+const RECENT_POSTS_LEN: usize = 1000;
+
 pub struct Router {
-    routing_map: Arc<Mutex<HashMap<Address, Vec<UnboundedSender<Message>>>>>,
+    routing_map: Arc<Mutex<RoutingMap>>,
+    recent: Arc<Mutex<VecDeque<SignedPost>>>,
     subscriber: Arc<Subscriber>,
+    // checks deletes, which are passed on only when signed by the author
+    ingestor: Arc<Ingestor>,
+    // posts matching it are dropped, see Tunables::block_filter
+    blocked: Arc<Mutex<Option<Filter>>>,
     is_started: bool,
 }
 
 impl Router {
-    pub fn new(subscriber: Arc<Subscriber>) -> Router {
+    pub fn new(
+        subscriber: Arc<Subscriber>,
+        ingestor: Ingestor,
+        blocked: Arc<Mutex<Option<Filter>>>,
+    ) -> Router {
         Router {
             routing_map: Arc::new(Mutex::new(HashMap::new())),
+            recent: Arc::new(Mutex::new(VecDeque::new())),
             subscriber,
+            ingestor: Arc::new(ingestor),
+            blocked,
             is_started: false,
         }
     }
@@ -38,25 +72,86 @@ impl Router {
         let mut rx = self.subscriber.get_receiver();
 
         let routing_map = self.routing_map.clone();
+        let recent = self.recent.clone();
+        let blocked = self.blocked.clone();
+        let ingestor = self.ingestor.clone();
         tokio::spawn(async move {
             loop {
                 match rx.recv().await {
                     Ok(msg) => {
+                        // sealed for their recipients, not for the subscribers of the sender
+                        if msg.direct_to().is_some() {
+                            continue;
+                        }
+                        if matches!(&*blocked.lock().await, Some(f) if f.matches(&msg)) {
+                            continue;
+                        }
+                        let deleted = match msg.post.content {
+                            PostKind::Delete(id) => Some(id),
+                            _ => None,
+                        };
+                        // a delete with the address of the author, not signed by
+                        // it, would take the post away from everyone
+                        if deleted.is_some()
+                            && ingestor.ingest_decoded(msg.clone()).await.is_err()
+                        {
+                            continue;
+                        }
+                        let mut recent = recent.lock().await;
+                        match deleted {
+                            Some(id) => {
+                                recent.retain(|p| !(p.addr == msg.addr && p.post.id == id))
+                            }
+                            None => {
+                                if recent.len() == RECENT_POSTS_LEN {
+                                    recent.pop_front();
+                                }
+                                recent.push_back(msg.clone());
+                            }
+                        }
+                        drop(recent);
+
                         let mut routing_map = routing_map.lock().await;
-                        match routing_map.get_mut(&msg.addr) {
-                            Some(v) => {
-                                let mut remove_list = Vec::new();
-                                for (i, tx) in v.iter().enumerate() {
-                                    if let Err(_) = tx.send(Message::Text(serde_json::to_string(&ServerMessage::Subscribed(msg.clone())).unwrap())) {
-                                        remove_list.push(i);
+                        if let Some(route) = routing_map.get_mut(&msg.addr) {
+                            let attr = &msg.post.user_attr;
+                            let profile = match route.last_attr.replace(attr.clone()) {
+                                Some(last) if last != *attr => {
+                                    let update = ServerMessage::ProfileUpdated {
+                                        addr: msg.addr.clone(),
+                                        attr: attr.clone(),
+                                    };
+                                    Some(serde_json::to_string(&update).unwrap())
+                                }
+                                _ => None,
+                            };
+                            let text = |typed| {
+                                serde_json::to_string(&ServerMessage::push(msg.clone(), typed))
+                                    .unwrap()
+                            };
+                            let (plain, typed) = (text(false), text(true));
+                            route.clients.retain(|client| {
+                                if client.blocked.lock().unwrap().blocks(&msg) {
+                                    return !client.tx.is_closed();
+                                }
+                                // a delete goes to every client which may have the post
+                                if let (Some(filter), None) = (&client.filter, deleted) {
+                                    if !filter.matches(&msg) {
+                                        return !client.tx.is_closed();
                                     }
                                 }
-                                for i in remove_list.iter() {
-                                    v.swap_remove(*i);
+                                if !client.typed_events {
+                                    return client.tx.send(Message::Text(plain.clone())).is_ok();
                                 }
+                                if let Some(profile) = &profile {
+                                    let _ = client.tx.send(Message::Text(profile.clone()));
+                                }
+                                client.tx.send(Message::Text(typed.clone())).is_ok()
+                            });
+                            // nobody is left, dropping the route unsubscribes
+                            if route.clients.is_empty() {
+                                routing_map.remove(&msg.addr);
                             }
-                            None => (),
-                        };
+                        }
                     }
                     Err(e) => {
                         match e {
@@ -69,19 +164,61 @@ impl Router {
         });
     }
 
-    pub async fn subscribe(&self, addr: Address, tx: UnboundedSender<Message>) {
+    pub async fn recent_posts(&self) -> Vec<SignedPost> {
+        self.recent.lock().await.iter().cloned().collect()
+    }
+
+    // typed_events as the client asked for in Hello
+    pub async fn subscribe(
+        &self,
+        addr: Address,
+        tx: UnboundedSender<Message>,
+        filter: Option<Filter>,
+        typed_events: bool,
+        blocked: Arc<StdMutex<BlockList>>,
+    ) {
+        let client = RouteClient {
+            tx,
+            filter,
+            typed_events,
+            blocked,
+        };
         let mut routing_map = self.routing_map.lock().await;
-        routing_map.entry(addr.clone()).or_insert(Vec::new()).push(tx);
-        self.subscriber.subscribe(addr).await;
+        match routing_map.entry(addr.clone()) {
+            Entry::Occupied(mut route) => route.get_mut().clients.push(client),
+            Entry::Vacant(route) => {
+                route.insert(Route {
+                    clients: vec![client],
+                    last_attr: None,
+                    _subscription: self.subscriber.subscribe(addr).await,
+                });
+            }
+        }
     }
 
     pub async fn unsubscribe(&self, addr: Address, tx: UnboundedSender<Message>) {
         let mut routing_map = self.routing_map.lock().await;
-        if let Some(v) = routing_map.get_mut(&addr) {
-            v.retain(|e| !e.same_channel(&tx));
-            if v.is_empty() {
+        if let Some(route) = routing_map.get_mut(&addr) {
+            route.clients.retain(|client| !client.tx.same_channel(&tx));
+            if route.clients.is_empty() {
                 routing_map.remove(&addr);
-                self.subscriber.stop_subscription(&addr).await; 
+            }
+        }
+    }
+
+    // Every route of a closed connection, given the addresses it subscribed
+    // to. Other closed clients of those routes go too, and routes left with
+    // nobody are dropped, which stops their subscriptions.
+    pub async fn disconnect(&self, addrs: &[Address], tx: &UnboundedSender<Message>) {
+        let mut routing_map = self.routing_map.lock().await;
+        for addr in addrs {
+            if let Some(route) = routing_map.get_mut(addr) {
+                route
+                    .clients
+                    .retain(|client| !client.tx.same_channel(tx) && !client.tx.is_closed());
+                if route.clients.is_empty() {
+                    routing_map.remove(addr);
+                }
             }
         }
     }
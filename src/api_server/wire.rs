@@ -0,0 +1,170 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::util::base64;
+
+// Fields holding keys, signatures and addresses, and the messages which are one
+const BYTE_FIELDS: [&str; 10] = [
+    "pubkey",
+    "old_pubkey",
+    "new_pubkey",
+    "signature",
+    "old_signature",
+    "new_signature",
+    "address",
+    "addr",
+    "Challenge",
+    "ChallengeResponce",
+];
+
+// How byte fields are written on a connection, agreed on with Hello.
+// Browser clients prefer base64 strings to arrays of numbers, which are about
+// twice as long and need converting before use.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WireEncoding {
+    #[default]
+    Numbers,
+    Base64,
+}
+
+// The encoding of one connection, shared by its reader and its writer
+#[derive(Debug, Clone, Default)]
+pub struct SharedEncoding(Arc<AtomicBool>);
+
+impl SharedEncoding {
+    pub fn get(&self) -> WireEncoding {
+        if self.0.load(Ordering::Relaxed) {
+            WireEncoding::Base64
+        } else {
+            WireEncoding::Numbers
+        }
+    }
+
+    pub fn set(&self, encoding: WireEncoding) {
+        self.0.store(encoding == WireEncoding::Base64, Ordering::Relaxed);
+    }
+}
+
+// A message as the server encodes it, in the given encoding. Signed posts
+// are kept as they are, so that clients can check signatures over the same
+// bytes the author signed; numbers like post ids only pass through Value
+// unchanged with serde_json's arbitrary_precision.
+pub fn encode(text: &str, encoding: WireEncoding) -> String {
+    if encoding == WireEncoding::Numbers {
+        return text.to_string();
+    }
+    match serde_json::from_str::<Value>(text) {
+        Ok(mut value) => {
+            to_base64(&mut value);
+            value.to_string()
+        }
+        Err(_) => text.to_string(),
+    }
+}
+
+// Undoes encode. A byte field which is not valid base64 is left as it is,
+// and the message fails to parse later.
+pub fn decode(text: &str, encoding: WireEncoding) -> String {
+    if encoding == WireEncoding::Numbers {
+        return text.to_string();
+    }
+    match serde_json::from_str::<Value>(text) {
+        Ok(mut value) => {
+            from_base64(&mut value);
+            value.to_string()
+        }
+        Err(_) => text.to_string(),
+    }
+}
+
+fn to_base64(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, field) in map.iter_mut() {
+                if key == "post" {
+                    continue;
+                }
+                match as_bytes(field) {
+                    Some(bytes) if BYTE_FIELDS.contains(&key.as_str()) => {
+                        *field = Value::String(String::from_utf8(base64::encode(&bytes)).unwrap());
+                    }
+                    _ => to_base64(field),
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(to_base64),
+        _ => (),
+    }
+}
+
+fn from_base64(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, field) in map.iter_mut() {
+                if key == "post" {
+                    continue;
+                }
+                let decoded = match field {
+                    Value::String(s) if BYTE_FIELDS.contains(&key.as_str()) => {
+                        base64::decode(s.as_bytes()).ok()
+                    }
+                    _ => None,
+                };
+                match decoded {
+                    Some(bytes) => *field = bytes.into_iter().map(Value::from).collect(),
+                    None => from_base64(field),
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(from_base64),
+        _ => (),
+    }
+}
+
+fn as_bytes(value: &Value) -> Option<Vec<u8>> {
+    let items = value.as_array()?;
+    if items.is_empty() {
+        return None;
+    }
+    items
+        .iter()
+        .map(|item| item.as_u64().filter(|b| *b <= 255).map(|b| b as u8))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_server::{ClientMessage, ServerMessage};
+
+    #[test]
+    fn wire_encoding_test() {
+        let challenge = serde_json::to_string(&ServerMessage::Challenge([7; 32])).unwrap();
+        let encoded = encode(&challenge, WireEncoding::Base64);
+        assert_eq!(
+            encoded,
+            r#"{"Challenge":"BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc="}"#
+        );
+        assert_eq!(decode(&encoded, WireEncoding::Base64), challenge);
+        assert_eq!(encode(&challenge, WireEncoding::Numbers), challenge);
+
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/vectors/client_message/post.json");
+        let post = std::fs::read_to_string(path).unwrap();
+        let post = post.trim_end();
+        let encoded = encode(post, WireEncoding::Base64);
+        assert!(encoded.len() < post.len() * 2 / 3);
+        // the signed part stays byte for byte
+        assert!(encoded.contains(r#""post":{"user_attr":{"name":"alice""#));
+        assert_eq!(decode(&encoded, WireEncoding::Base64), post);
+        let decoded = decode(&encoded, WireEncoding::Base64);
+        assert!(serde_json::from_str::<ClientMessage>(&decoded).is_ok());
+
+        // ULID post ids do not fit in a u64
+        let post = post.replacen(r#""id":1,"#, r#""id":2055933643165128431234567890123,"#, 1);
+        let encoded = encode(&post, WireEncoding::Base64);
+        assert!(encoded.contains("2055933643165128431234567890123"));
+        assert_eq!(decode(&encoded, WireEncoding::Base64), post);
+    }
+}
@@ -1,3 +1,7 @@
+mod relay;
+mod repl;
 mod timeline;
 
-pub use timeline::Timeline;
\ No newline at end of file
+pub use relay::{run as run_relay, RelayError, RelayOptions, RELAY_USAGE};
+pub use repl::{Repl, END_OF_TEXT};
+pub use timeline::{Timeline, TIMELINE_PAGE};
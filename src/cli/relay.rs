@@ -0,0 +1,272 @@
+use log::{info, warn};
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use thiserror::Error;
+
+use crate::api_server::{ApiServer, ApiServerError, RelayCacheConfig};
+use crate::crypto::PublicKey;
+use crate::kad::KadConfig;
+use crate::service::{
+    default_networks, BootstrapAddr, BootstrapListSource, Config, NetworkController,
+};
+
+// A node which hosts no accounts: it joins both DHTs to store and pass on
+// records and multicasts for others, so that volunteers can strengthen the
+// network without running a client.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RelayOptions {
+    pub bind_addr: SocketAddr,
+    pub pubsub_bind_addr: Option<SocketAddr>,
+    // None to not answer nodeinfo requests, so that it is not a bootstrap node
+    pub nodeinfo_addr: Option<SocketAddr>,
+    pub bootstrap: Vec<BootstrapAddr>,
+    pub bootstrap_lists: Vec<BootstrapListSource>,
+    // bytes of records stored for others, per DHT
+    pub store_limit: Option<usize>,
+    // outgoing bytes per second
+    pub bandwidth_limit: Option<u64>,
+    // serve the WebSocket API here, answering history requests from a relay cache
+    pub api_addr: Option<String>,
+    pub relay_cache_quota: Option<u64>,
+    // sign delivery receipts for the posts this relay takes
+    pub delivery_receipts: bool,
+    // takes admin commands signed by this key over the API, see Config
+    pub operator_key: Option<PublicKey>,
+    // takes ReloadConfig from API clients on this host, see Config
+    pub local_reload: bool,
+    pub data_dir: PathBuf,
+}
+
+impl Default for RelayOptions {
+    fn default() -> RelayOptions {
+        RelayOptions {
+            bind_addr: "0.0.0.0:6270".parse().unwrap(),
+            pubsub_bind_addr: Some("0.0.0.0:6272".parse().unwrap()),
+            nodeinfo_addr: Some("0.0.0.0:6271".parse().unwrap()),
+            bootstrap: Vec::new(),
+            bootstrap_lists: Vec::new(),
+            store_limit: None,
+            bandwidth_limit: None,
+            api_addr: None,
+            relay_cache_quota: None,
+            delivery_receipts: false,
+            operator_key: None,
+            local_reload: false,
+            data_dir: PathBuf::from("localdata"),
+        }
+    }
+}
+
+pub const RELAY_USAGE: &str = "usage: noktulo --relay [--bind ADDR] [--pubsub-bind ADDR] \
+[--nodeinfo ADDR | --no-nodeinfo] [--bootstrap HOST:PORT]... \
+[--bootstrap-list URL#MAINTAINER_KEY]... [--store-limit MIB] \
+[--bandwidth KIB_PER_SEC] [--api ADDR [--relay-quota MIB] [--operator KEY] [--local-reload]] \
+[--receipts] [--data-dir DIR]";
+
+impl RelayOptions {
+    // the arguments after --relay
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<RelayOptions, RelayError> {
+        let mut options = RelayOptions::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--no-nodeinfo" {
+                options.nodeinfo_addr = None;
+                continue;
+            }
+            if arg == "--receipts" {
+                options.delivery_receipts = true;
+                continue;
+            }
+            if arg == "--local-reload" {
+                options.local_reload = true;
+                continue;
+            }
+            let value = args.next().ok_or_else(|| RelayError::Missing(arg.clone()))?;
+            let invalid = || RelayError::Invalid(arg.clone(), value.clone());
+            match arg.as_str() {
+                "--bind" => options.bind_addr = value.parse().map_err(|_| invalid())?,
+                "--pubsub-bind" => {
+                    options.pubsub_bind_addr = Some(value.parse().map_err(|_| invalid())?)
+                }
+                "--nodeinfo" => options.nodeinfo_addr = Some(value.parse().map_err(|_| invalid())?),
+                "--bootstrap" => options.bootstrap.push(value.parse().map_err(|_| invalid())?),
+                "--bootstrap-list" => {
+                    options.bootstrap_lists.push(value.parse().map_err(|_| invalid())?)
+                }
+                "--store-limit" => {
+                    let mib: usize = value.parse().map_err(|_| invalid())?;
+                    options.store_limit = Some(mib << 20);
+                }
+                "--bandwidth" => {
+                    let kib: u64 = value.parse().map_err(|_| invalid())?;
+                    options.bandwidth_limit = Some(kib << 10);
+                }
+                "--api" => options.api_addr = Some(value),
+                "--relay-quota" => {
+                    let mib: u64 = value.parse().map_err(|_| invalid())?;
+                    options.relay_cache_quota = Some(mib << 20);
+                }
+                "--operator" => {
+                    let key = hex::decode(&value).map_err(|_| invalid())?;
+                    let key = PublicKey::try_from(&key[..]).map_err(|_| invalid())?;
+                    options.operator_key = Some(key);
+                }
+                "--data-dir" => options.data_dir = PathBuf::from(value),
+                _ => return Err(RelayError::Unknown(arg)),
+            }
+        }
+        if options.relay_cache_quota.is_some() && options.api_addr.is_none() {
+            return Err(RelayError::QuotaWithoutApi);
+        }
+        if options.operator_key.is_some() && options.api_addr.is_none() {
+            return Err(RelayError::OperatorWithoutApi);
+        }
+        Ok(options)
+    }
+
+    pub fn config(&self) -> Config {
+        Config {
+            bind_addr: self.bind_addr,
+            pubsub_bind_addr: self.pubsub_bind_addr,
+            nodeinfo_addr: self.nodeinfo_addr,
+            bootstrap: self.bootstrap.clone(),
+            bootstrap_lists: self.bootstrap_lists.clone(),
+            lan_discovery: false,
+            networks: default_networks(),
+            kad: KadConfig {
+                delivery_receipts: self.delivery_receipts,
+                ..KadConfig::default()
+            },
+            tunables_path: Some(self.data_dir.join("tunables.json")),
+            port_mapping: true,
+            peer_store_path: Some(self.data_dir.join("peers")),
+            store_limit: self.store_limit,
+            bandwidth_limit: self.bandwidth_limit,
+            store_dir: Some(self.data_dir.join("store")),
+            rng_seed: None,
+            archive_posts: true,
+            operator_key: self.operator_key.clone(),
+            local_reload: self.local_reload,
+            node_id_path: Some(self.data_dir.join("node_id")),
+        }
+    }
+}
+
+// Runs until Ctrl-C
+pub async fn run(options: RelayOptions) -> Result<(), RelayError> {
+    tokio::fs::create_dir_all(&options.data_dir)
+        .await
+        .map_err(RelayError::Io)?;
+    let controller = match &options.api_addr {
+        Some(addr) => {
+            let mut cache_config = RelayCacheConfig {
+                dir: options.data_dir.join("relay"),
+                ..RelayCacheConfig::default()
+            };
+            if let Some(quota) = options.relay_cache_quota {
+                cache_config.quota = quota;
+            }
+            let server = ApiServer::new(options.config())
+                .await
+                .with_relay_cache(cache_config)
+                .await
+                .map_err(|e| RelayError::Server(Box::new(e)))?;
+            let controller = server.controller();
+            let addr = server
+                .start(addr.clone())
+                .await
+                .map_err(|e| RelayError::Server(Box::new(e)))?;
+            info!("Serving the API at {}", addr);
+            controller
+        }
+        None => Arc::new(NetworkController::init(options.config()).await),
+    };
+    info!(
+        "Relaying, storing at most {} and sending at most {} per second",
+        options
+            .store_limit
+            .map_or("unlimited".to_string(), |limit| format!("{} bytes", limit)),
+        options
+            .bandwidth_limit
+            .map_or("unlimited".to_string(), |limit| format!("{} bytes", limit)),
+    );
+
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        warn!("Cannot wait for Ctrl-C: {}", e);
+    }
+    controller.shutdown().await;
+    println!("{}", controller.stats_snapshot());
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+pub enum RelayError {
+    #[error("Unknown option: {0}")]
+    Unknown(String),
+    #[error("{0} needs a value")]
+    Missing(String),
+    #[error("Invalid value for {0}: {1}")]
+    Invalid(String, String),
+    #[error("--relay-quota needs --api")]
+    QuotaWithoutApi,
+    #[error("--operator needs --api")]
+    OperatorWithoutApi,
+    #[error(transparent)]
+    Io(std::io::Error),
+    #[error(transparent)]
+    Server(Box<ApiServerError>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn relay_options_test() {
+        assert_eq!(RelayOptions::parse(Vec::new()).unwrap(), RelayOptions::default());
+
+        let options = RelayOptions::parse(args(
+            "--no-nodeinfo --bootstrap seed.example.org:6271 --store-limit 64 \
+             --bandwidth 256 --api 0.0.0.0:8080 --receipts",
+        ))
+        .unwrap();
+        assert_eq!(options.nodeinfo_addr, None);
+        assert_eq!(options.bootstrap[0].host(), "seed.example.org");
+        assert_eq!(options.store_limit, Some(64 << 20));
+        assert_eq!(options.config().bandwidth_limit, Some(256 << 10));
+        assert_eq!(options.api_addr.as_deref(), Some("0.0.0.0:8080"));
+        assert!(options.config().kad.delivery_receipts);
+
+        assert!(matches!(RelayOptions::parse(args("--store-limit")), Err(RelayError::Missing(_))));
+        assert!(matches!(
+            RelayOptions::parse(args("--bandwidth fast")),
+            Err(RelayError::Invalid(_, _))
+        ));
+        assert!(matches!(RelayOptions::parse(args("--accounts 1")), Err(RelayError::Unknown(_))));
+
+        let key = crate::crypto::SecretKey::random().public_key();
+        let list = format!("https://seeds.example.org/nodes.json#{}", key);
+        let options = RelayOptions::parse(args(&format!("--bootstrap-list {}", list))).unwrap();
+        assert_eq!(options.config().bootstrap_lists[0].maintainer, key);
+        assert!(matches!(
+            RelayOptions::parse(args("--bootstrap-list https://seeds.example.org/nodes.json")),
+            Err(RelayError::Invalid(_, _))
+        ));
+
+        let options = RelayOptions::parse(args(&format!("--api 0.0.0.0:8080 --operator {}", key)));
+        assert_eq!(options.unwrap().config().operator_key, Some(key.clone()));
+        let options = RelayOptions::parse(args("--api 0.0.0.0:8080 --local-reload")).unwrap();
+        assert!(options.config().local_reload);
+        assert!(!RelayOptions::parse(args("")).unwrap().config().local_reload);
+        assert!(matches!(
+            RelayOptions::parse(args(&format!("--operator {}", key))),
+            Err(RelayError::OperatorWithoutApi)
+        ));
+    }
+}
@@ -0,0 +1,214 @@
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::borrow::Cow;
+use std::path::PathBuf;
+
+use crate::user::user::Address;
+
+// ends a multi-line hoot
+pub const END_OF_TEXT: &str = ".";
+
+// Completes command names at the start of a line, and addresses or petnames
+// of the followings anywhere. A petname is replaced with its address.
+#[derive(Debug, Clone, Default)]
+pub struct CliHelper {
+    commands: Vec<String>,
+    known: Vec<(Option<String>, String)>,
+    // the line is a password, shown as stars and not completed
+    masking: bool,
+}
+
+impl CliHelper {
+    fn candidates(&self, word: &str, line_start: bool) -> Vec<Pair> {
+        let mut candidates = Vec::new();
+        if line_start {
+            for cmd in self.commands.iter().filter(|cmd| cmd.starts_with(word)) {
+                candidates.push(Pair {
+                    display: cmd.clone(),
+                    replacement: cmd.clone(),
+                });
+            }
+        }
+
+        let addr_word = word.strip_prefix('@').unwrap_or(word);
+        let name_word = word.to_lowercase();
+        for (name, addr) in &self.known {
+            let name_match = name
+                .as_ref()
+                .is_some_and(|name| !word.is_empty() && name.to_lowercase().starts_with(&name_word));
+            if addr.starts_with(addr_word) || name_match {
+                let display = match name {
+                    Some(name) => format!("{} @{}", name, addr),
+                    None => format!("@{}", addr),
+                };
+                candidates.push(Pair {
+                    display,
+                    replacement: addr.clone(),
+                });
+            }
+        }
+        candidates
+    }
+}
+
+impl Completer for CliHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        if self.masking {
+            return Ok((pos, Vec::new()));
+        }
+        let start = line[..pos]
+            .rfind(char::is_whitespace)
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        let line_start = line[..start].trim().is_empty();
+        Ok((start, self.candidates(&line[start..pos], line_start)))
+    }
+}
+
+impl Hinter for CliHelper {
+    type Hint = String;
+}
+
+impl Highlighter for CliHelper {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        match self.masking {
+            true => Cow::Owned("*".repeat(line.chars().count())),
+            false => Cow::Borrowed(line),
+        }
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize, _forced: bool) -> bool {
+        self.masking
+    }
+}
+
+impl Validator for CliHelper {}
+
+impl Helper for CliHelper {}
+
+// Line editing with history for the interactive client.
+// Ctrl-C and Ctrl-D come back as `ReadlineError::Interrupted` and `ReadlineError::Eof`,
+// so that the caller can save its state before leaving.
+pub struct Repl {
+    editor: Editor<CliHelper, DefaultHistory>,
+    history_path: PathBuf,
+}
+
+impl Repl {
+    pub fn new(history_path: PathBuf) -> Result<Repl, ReadlineError> {
+        let mut editor = Editor::new()?;
+        editor.set_helper(Some(CliHelper::default()));
+        // there is no history on the first run
+        let _ = editor.load_history(&history_path);
+        Ok(Repl {
+            editor,
+            history_path,
+        })
+    }
+
+    pub fn set_commands(&mut self, commands: &[&str]) {
+        if let Some(helper) = self.editor.helper_mut() {
+            helper.commands = commands.iter().map(|cmd| cmd.to_string()).collect();
+        }
+    }
+
+    pub fn set_known<'a, I>(&mut self, known: I)
+    where
+        I: IntoIterator<Item = (Option<&'a str>, &'a Address)>,
+    {
+        if let Some(helper) = self.editor.helper_mut() {
+            helper.known = known
+                .into_iter()
+                .map(|(name, addr)| (name.map(str::to_string), addr.to_string()))
+                .collect();
+        }
+    }
+
+    pub fn readline(&mut self, prompt: &str) -> Result<String, ReadlineError> {
+        let line = self.editor.readline(prompt)?;
+        if !line.trim().is_empty() {
+            self.editor.add_history_entry(line.as_str())?;
+        }
+        Ok(line)
+    }
+
+    // A line which is not added to the history, like a seed phrase; shown as
+    // stars when masked, like a password
+    pub fn read_secret(&mut self, prompt: &str, masked: bool) -> Result<String, ReadlineError> {
+        self.set_masking(masked);
+        let line = self.editor.readline(prompt);
+        self.set_masking(false);
+        line
+    }
+
+    fn set_masking(&mut self, masking: bool) {
+        if let Some(helper) = self.editor.helper_mut() {
+            helper.masking = masking;
+        }
+    }
+
+    // Reads lines until one consisting of END_OF_TEXT. Not added to the history.
+    pub fn read_multiline(&mut self) -> Result<String, ReadlineError> {
+        let mut lines = Vec::new();
+        loop {
+            let prompt = if lines.is_empty() { ": " } else { "| " };
+            let line = self.editor.readline(prompt)?;
+            if line.trim_end() == END_OF_TEXT {
+                break;
+            }
+            lines.push(line);
+        }
+        Ok(lines.join("\n"))
+    }
+
+    pub fn save_history(&mut self) -> Result<(), ReadlineError> {
+        self.editor.save_history(&self.history_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::SecretKey;
+
+    #[test]
+    fn complete_test() {
+        let addr = Address::from(SecretKey::random().public_key());
+        let addr_s = addr.to_string();
+        let mut helper = CliHelper {
+            commands: vec!["follow".to_string(), "hoot".to_string()],
+            known: vec![(Some("Alice".to_string()), addr_s.clone())],
+            masking: false,
+        };
+
+        let found = helper.candidates("fo", true);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].replacement, "follow");
+        assert!(helper.candidates("fo", false).is_empty());
+
+        // petnames complete to the address
+        let found = helper.candidates("al", false);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].replacement, addr_s);
+        let found = helper.candidates(&format!("@{}", &addr_s[..4]), false);
+        assert_eq!(found[0].replacement, addr_s);
+
+        helper.known[0].0 = None;
+        assert!(helper.candidates("al", false).is_empty());
+
+        helper.masking = true;
+        assert_eq!(helper.highlight("pässword", 0), "********");
+    }
+}
@@ -1,25 +1,197 @@
-use crate::user::post::{PostKind, SignedPost};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::service::AuthorStats;
+use crate::user::aliases::AliasBook;
+use crate::user::post::{PostKind, PostRef, SignedPost};
+use crate::user::post_id::PostId;
+use crate::user::time_format::TimeFormat;
+use crate::user::user::Address;
+
+// the latest posts kept when the timeline is saved
+pub const TIMELINE_LIMIT: usize = 1000;
+// posts shown by each page of `history`
+pub const TIMELINE_PAGE: usize = 10;
+
+// a post as saved, with when it arrived
+#[derive(Serialize, Deserialize)]
+struct StoredPost {
+    sigpost: SignedPost,
+    received_at: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredTimeline {
+    posts: Vec<StoredPost>,
+    deleted: VecDeque<PostRef>,
+    // timelines saved before it have the stats of their posts only
+    #[serde(default)]
+    authors: Vec<(Address, AuthorStats)>,
+}
 
 pub struct Timeline {
     posts: Vec<SignedPost>,
+    // when each post arrived, ms since the epoch
+    received_at: HashMap<(Address, PostId), u64>,
+    collapse_sensitive: bool,
+    // petnames shown in front of the posts of their addresses
+    aliases: AliasBook,
+    // how the times of posts are shown, see the time command
+    time_format: TimeFormat,
+    // the latest TIMELINE_LIMIT posts deleted by their authors, so that
+    // they are not taken in again when backfilled, oldest first
+    deleted: VecDeque<PostRef>,
+    // every post which came in, also the ones dropped from the timeline since
+    authors: HashMap<Address, AuthorStats>,
 }
 
 impl Timeline {
     pub fn new() -> Timeline {
-        Timeline { posts: Vec::new() }
+        Timeline {
+            posts: Vec::new(),
+            received_at: HashMap::new(),
+            collapse_sensitive: false,
+            aliases: AliasBook::new(),
+            time_format: TimeFormat::default(),
+            deleted: VecDeque::new(),
+            authors: HashMap::new(),
+        }
+    }
+
+    // a saved timeline; anything unreadable gives an empty one
+    pub fn from_bytes(bytes: &[u8]) -> Timeline {
+        let mut timeline = Timeline::new();
+        // timelines were saved as the posts alone, which still loads
+        let posts = match serde_json::from_slice::<StoredTimeline>(bytes) {
+            Ok(stored) => {
+                timeline.deleted = stored.deleted;
+                timeline.authors = stored.authors.into_iter().collect();
+                stored.posts
+            }
+            Err(_) => serde_json::from_slice(bytes).unwrap_or_default(),
+        };
+        let recount = timeline.authors.is_empty();
+        for StoredPost { sigpost, received_at } in posts {
+            if recount {
+                timeline.record(&sigpost);
+            }
+            timeline
+                .received_at
+                .insert((sigpost.addr.clone(), sigpost.post.id), received_at);
+            timeline.posts.push(sigpost);
+        }
+        timeline
+    }
+
+    // the latest TIMELINE_LIMIT posts, in the order they arrived, and the tombstones
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let skip = self.posts.len().saturating_sub(TIMELINE_LIMIT);
+        let posts: Vec<StoredPost> = self.posts[skip..]
+            .iter()
+            .map(|sigpost| StoredPost {
+                sigpost: sigpost.clone(),
+                received_at: self.received_at(sigpost).unwrap_or_default(),
+            })
+            .collect();
+        let stored = StoredTimeline {
+            posts,
+            deleted: self.deleted.clone(),
+            authors: self
+                .authors
+                .iter()
+                .map(|(addr, stats)| (addr.clone(), *stats))
+                .collect(),
+        };
+        serde_json::to_vec(&stored).unwrap()
+    }
+
+    pub fn set_collapse_sensitive(&mut self, collapse: bool) {
+        self.collapse_sensitive = collapse;
+    }
+
+    pub fn set_aliases(&mut self, aliases: AliasBook) {
+        self.aliases = aliases;
+    }
+
+    pub fn set_time_format(&mut self, time_format: TimeFormat) {
+        self.time_format = time_format;
+    }
+
+    pub fn render(&self, sigpost: &SignedPost) -> String {
+        self.render_post(sigpost, self.collapse_sensitive)
+    }
+
+    // in full, even if sensitive posts are collapsed
+    pub fn render_expanded(&self, sigpost: &SignedPost) -> String {
+        self.render_post(sigpost, false)
+    }
+
+    fn render_post(&self, sigpost: &SignedPost, collapse: bool) -> String {
+        let now = Utc::now().timestamp() as u64;
+        let header = sigpost.header_with(&self.time_format, now);
+        let rendered = match sigpost.content_warning() {
+            Some(cw) if collapse => {
+                format!("{}\nCW: {} (collapsed, use `expand`)\n", header, cw)
+            }
+            _ => format!("{}\n{}", header, sigpost.post),
+        };
+        match self.aliases.name_of(&sigpost.addr) {
+            Some(petname) => format!("~{} {}", petname, rendered),
+            None => rendered,
+        }
     }
 
     pub fn push(&mut self, sigpost: SignedPost) {
+        // a backfilled post may arrive again through the subscription
+        if self.get_by_id_of(&sigpost.addr, sigpost.post.id).is_some() {
+            return;
+        }
+        if self.deleted.contains(&sigpost.post_ref()) {
+            return;
+        }
         match sigpost.post.content {
-            PostKind::Delete(_) => (),
+            PostKind::Delete(id) => {
+                if self.delete(&sigpost.addr, id) {
+                    println!("@{} deleted their post {}", sigpost.addr.to_string(), id);
+                }
+            }
+            PostKind::DeleteAccount
+            | PostKind::Migrate(_)
+            | PostKind::DirectMessage(_)
+            | PostKind::GroupMessage(_) => (),
             _ => {
-                println!("{}", sigpost);
+                println!("{}", self.render(&sigpost));
+                let now = Utc::now().timestamp_millis() as u64;
+                self.received_at
+                    .insert((sigpost.addr.clone(), sigpost.post.id), now);
+                self.record(&sigpost);
                 self.posts.push(sigpost);
             }
         }
     }
 
-    pub fn get_by_id(&self, id: u128) -> Option<SignedPost> {
+    // Removes the post id of author, leaving a tombstone in its place; a
+    // delete signed by anyone else never matches a post. false if the post
+    // was not in the timeline.
+    pub fn delete(&mut self, author: &Address, id: PostId) -> bool {
+        let post_ref = PostRef {
+            addr: author.clone(),
+            id,
+        };
+        if !self.deleted.contains(&post_ref) {
+            if self.deleted.len() == TIMELINE_LIMIT {
+                self.deleted.pop_front();
+            }
+            self.deleted.push_back(post_ref.clone());
+        }
+        self.received_at.remove(&(author.clone(), id));
+        let len = self.posts.len();
+        self.posts.retain(|sigpost| sigpost.post_ref() != post_ref);
+        self.posts.len() != len
+    }
+
+    pub fn get_by_id(&self, id: PostId) -> Option<SignedPost> {
         let i = self
             .posts
             .iter()
@@ -27,7 +199,133 @@ impl Timeline {
         Some(self.posts[i].clone())
     }
 
+    fn get_by_id_of(&self, addr: &Address, id: PostId) -> Option<&SignedPost> {
+        self.posts
+            .iter()
+            .find(|sigpost| sigpost.addr == *addr && sigpost.post.id == id)
+    }
+
+    fn record(&mut self, sigpost: &SignedPost) {
+        match self.authors.get_mut(&sigpost.addr) {
+            Some(stats) => stats.record(sigpost),
+            None => {
+                if let Some(stats) = AuthorStats::of(sigpost) {
+                    self.authors.insert(sigpost.addr.clone(), stats);
+                }
+            }
+        }
+    }
+
+    // what came in from addr, for its profile
+    pub fn author_stats(&self, addr: &Address) -> Option<AuthorStats> {
+        self.authors.get(addr).copied()
+    }
+
+    pub fn received_at(&self, sigpost: &SignedPost) -> Option<u64> {
+        self.received_at
+            .get(&(sigpost.addr.clone(), sigpost.post.id))
+            .copied()
+    }
+
     pub fn get(&self, index: usize) -> Option<&SignedPost> {
-        self.posts.get(self.posts.len() - index - 1)
+        self.posts.get(self.posts.len().checked_sub(index + 1)?)
+    }
+
+    pub fn posts(&self) -> &[SignedPost] {
+        &self.posts
+    }
+
+    // limit posts from offset on, newest first by when they were written
+    pub fn get_page(&self, offset: usize, limit: usize) -> Vec<&SignedPost> {
+        let mut posts: Vec<&SignedPost> = self.posts.iter().collect();
+        posts.sort_by_key(|sigpost| std::cmp::Reverse(sigpost.post.created_at));
+        posts.into_iter().skip(offset).take(limit).collect()
+    }
+
+    // the same, of the posts of members only, as for a list of followings
+    pub fn get_page_of(
+        &self,
+        members: &HashSet<Address>,
+        offset: usize,
+        limit: usize,
+    ) -> Vec<&SignedPost> {
+        let mut posts: Vec<&SignedPost> = self
+            .posts
+            .iter()
+            .filter(|sigpost| members.contains(&sigpost.addr))
+            .collect();
+        posts.sort_by_key(|sigpost| std::cmp::Reverse(sigpost.post.created_at));
+        posts.into_iter().skip(offset).take(limit).collect()
+    }
+
+    // how many posts of members there are
+    pub fn count_of(&self, members: &HashSet<Address>) -> usize {
+        self.posts
+            .iter()
+            .filter(|sigpost| members.contains(&sigpost.addr))
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::test_user;
+    use crate::user::time_format::Zone;
+
+    #[test]
+    fn timeline_test() {
+        let mut alice = test_user("alice");
+        let mut timeline = Timeline::new();
+        for (text, created_at) in [("first", 300), ("second", 100), ("third", 200)] {
+            let mut sigpost = alice.hoot(text.to_string(), None, None, vec![]).unwrap();
+            sigpost.post.created_at = created_at;
+            timeline.push(sigpost);
+        }
+
+        let saved = Timeline::from_bytes(&timeline.to_bytes());
+        assert_eq!(saved.posts(), timeline.posts());
+        let stats = saved.author_stats(&alice.addr()).unwrap();
+        assert_eq!((stats.posts, stats.first_post_at, stats.last_post_at), (3, 100, 300));
+        assert_eq!(saved.received_at(&saved.posts()[0]), timeline.received_at(&saved.posts()[0]));
+        let page: Vec<u64> = saved.get_page(1, 5).iter().map(|p| p.post.created_at).collect();
+        assert_eq!(page, vec![200, 100]);
+        assert!(saved.get_page(3, 5).is_empty());
+        let members: HashSet<Address> = vec![alice.addr()].into_iter().collect();
+        assert_eq!(saved.get_page_of(&members, 1, 5).len(), 2);
+        assert_eq!(saved.count_of(&members), 3);
+        assert!(saved.get_page_of(&HashSet::new(), 0, 5).is_empty());
+        assert!(Timeline::from_bytes(b"garbage").posts().is_empty());
+        timeline.set_time_format(TimeFormat::new(Zone::Utc, "%Y-%m-%d %H:%M").unwrap());
+        assert!(timeline.render(&timeline.posts()[0]).contains(" [1970-01-01 00:05]:\n"));
+
+        // saved before tombstones were
+        let old: Vec<StoredPost> = serde_json::from_slice::<StoredTimeline>(&timeline.to_bytes())
+            .unwrap()
+            .posts;
+        let old = Timeline::from_bytes(&serde_json::to_vec(&old).unwrap());
+        assert_eq!(old.posts(), timeline.posts());
+        assert_eq!(old.author_stats(&alice.addr()), Some(stats));
+    }
+
+    #[test]
+    fn delete_test() {
+        let (mut alice, mut mallory) = (test_user("alice"), test_user("alice"));
+        let mut timeline = Timeline::new();
+        let hoot = alice.hoot("oops".to_string(), None, None, vec![]).unwrap();
+        timeline.push(hoot.clone());
+
+        // only its author deletes a post
+        timeline.push(mallory.create_post(PostKind::Delete(hoot.post.id)).unwrap());
+        assert_eq!(timeline.posts().len(), 1);
+        timeline.push(alice.del(hoot.post.id).unwrap().unwrap());
+        assert!(timeline.posts().is_empty());
+
+        // the tombstone keeps it out when it comes in again, also once saved
+        timeline.push(hoot.clone());
+        assert!(timeline.posts().is_empty());
+        let mut saved = Timeline::from_bytes(&timeline.to_bytes());
+        saved.push(hoot);
+        assert!(saved.posts().is_empty());
     }
 }
@@ -0,0 +1,61 @@
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::IsIdentity;
+use ed25519_dalek::{ExpandedSecretKey, Signature, Verifier};
+use sha2::{Digest, Sha512};
+use std::convert::TryInto;
+
+use super::ed25519::Ed25519Error;
+
+// The constant-time backend of ed25519, with the dalek feature. Signatures,
+// keys and shared secrets are the same bytes the reference implementation
+// gives, see the tests of ed25519.
+
+fn keys(sk: &[u8; 32]) -> (ed25519_dalek::SecretKey, ed25519_dalek::PublicKey) {
+    // any 32 bytes are a secret key
+    let secret = ed25519_dalek::SecretKey::from_bytes(sk).unwrap();
+    let public = ed25519_dalek::PublicKey::from(&secret);
+    (secret, public)
+}
+
+pub(super) fn public_key(sk: &[u8; 32]) -> [u8; 32] {
+    keys(sk).1.to_bytes()
+}
+
+pub(super) fn sign(sk: &[u8; 32], message: &[u8]) -> [u8; 64] {
+    let (secret, public) = keys(sk);
+    ExpandedSecretKey::from(&secret)
+        .sign(message, &public)
+        .to_bytes()
+}
+
+pub(super) fn check_point(pk: &[u8; 32]) -> Result<(), Ed25519Error> {
+    match CompressedEdwardsY(*pk).decompress() {
+        Some(_) => Ok(()),
+        None => Err(Ed25519Error::Point),
+    }
+}
+
+pub(super) fn verify(pk: &[u8; 32], signature: &[u8; 64], m: &[u8]) -> Result<(), Ed25519Error> {
+    let public = ed25519_dalek::PublicKey::from_bytes(pk).map_err(|_| Ed25519Error::Point)?;
+    public
+        .verify(m, &Signature::from(*signature))
+        .map_err(|_| Ed25519Error::Signature)
+}
+
+// The encoded point sk*pk, refusing the neutral point. The clamped scalar is
+// taken as it is, unreduced, as the reference implementation does.
+pub(super) fn shared_point(sk: &[u8; 32], pk: &[u8; 32]) -> Result<[u8; 32], Ed25519Error> {
+    let point = CompressedEdwardsY(*pk)
+        .decompress()
+        .ok_or(Ed25519Error::Point)?;
+    let mut bits: [u8; 32] = Sha512::digest(sk)[..32].try_into().unwrap();
+    bits[0] &= 248;
+    bits[31] &= 127;
+    bits[31] |= 64;
+    let shared = point * Scalar::from_bits(bits);
+    if shared.is_identity() {
+        return Err(Ed25519Error::Point);
+    }
+    Ok(shared.compress().to_bytes())
+}
@@ -1,14 +1,24 @@
 use num_bigint::{BigUint, ToBigUint};
 use once_cell::sync::Lazy;
-use rand::prelude::*;
-use rand_chacha::ChaCha20Rng;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 //use sha3::{Digest, Sha3_512};
 use sha2::{Digest, Sha512};
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 use std::fmt;
 use std::ops::Add;
 use thiserror::Error;
 
+use crate::util::rng::{Entropy, SystemEntropy};
+
+// The keys sign, verify and derive shared secrets through a backend picked at
+// compile time. The default is the reference implementation below, on
+// BigUint, which is neither fast nor constant-time; the dalek feature uses
+// ed25519-dalek, which gives the same bytes.
+#[cfg(feature = "dalek")]
+use super::dalek as backend;
+#[cfg(not(feature = "dalek"))]
+use self::reference as backend;
+
 const B: u64 = 256;
 
 fn h(m: &[u8]) -> [u8; 64] {
@@ -82,8 +92,12 @@ pub struct SecretKey {
 
 impl SecretKey {
     pub fn random() -> SecretKey {
+        SecretKey::random_from(&SystemEntropy)
+    }
+
+    pub fn random_from(entropy: &dyn Entropy) -> SecretKey {
         let mut sk = [0; 32];
-        ChaCha20Rng::from_entropy().fill_bytes(&mut sk);
+        entropy.fill_bytes(&mut sk);
         SecretKey { sk }
     }
 
@@ -96,55 +110,24 @@ impl SecretKey {
     }
 
     pub fn sign(&self, message: &[u8]) -> [u8; 64] {
-        // 512bit
-        let h = h(&self.sk);
-        // 下位256bitを取り出して整数とする
-        let mut s = BigUint::from_bytes_le(&h[..(B as usize) / 8]);
-
-        // 下位3bitを消す
-        s.set_bit(0, false);
-        s.set_bit(1, false);
-        s.set_bit(2, false);
-
-        // b-2 bit目は立ててb-1 bit目は無視する
-        s.set_bit(B - 2, true);
-        s.set_bit(B - 1, false);
-
-        // 上位256bitを取り出す
-        let r = h_int(&[&h[(B as usize) / 8..], message].concat());
-        let rr = (*BASE_POINT).clone().scalar_mul(r.clone()).encode();
-
-        let pk = self.public_key().to_bytes();
-        let k = h_int(&[&rr[..], &pk[..], message].concat());
-
-        let ss = (r + k * s) % (*L).clone();
-        let ss_bytes:[u8;32]=ss.to_bytes_le().try_into().unwrap();
-
-        [&rr[..], &ss_bytes[..]]
-            .concat()
-            .try_into()
-            .unwrap()
+        backend::sign(&self.sk, message)
     }
 
     pub fn public_key(&self) -> PublicKey {
-        // 512bit
-        let h = h(&self.sk);
-        // 下位256bitを取り出して整数とする
-        let mut a = BigUint::from_bytes_le(&h[..(B as usize) / 8]);
-
-        // 下位3bitを消す
-        a.set_bit(0, false);
-        a.set_bit(1, false);
-        a.set_bit(2, false);
-
-        // b-2 bit目は立ててb-1 bit目は無視する
-        a.set_bit(B - 2, true);
-        a.set_bit(B - 1, false);
-
-        let pk = (*BASE_POINT).clone().scalar_mul(a);
-        assert!(pk.is_on_curve());
+        PublicKey {
+            pk: backend::public_key(&self.sk),
+        }
+    }
 
-        PublicKey { pk: pk.encode() }
+    // A secret shared with the holder of pubkey, who gets the same one from
+    // this key's public key: Diffie-Hellman on the Edwards curve, a*(bG) = b*(aG).
+    // The scalar is a multiple of 8, so a point of small order gives the
+    // neutral point, which is refused.
+    pub fn shared_secret(&self, pubkey: &PublicKey) -> Result<[u8; 32], Ed25519Error> {
+        let point = backend::shared_point(&self.sk, &pubkey.pk)?;
+        Ok(h(&[&b"noktulo:dh"[..], &point[..]].concat())[..32]
+            .try_into()
+            .unwrap())
     }
 }
 
@@ -167,6 +150,28 @@ impl From<SecretKey> for [u8; 32] {
     }
 }
 
+impl TryFrom<&[u8]> for SecretKey {
+    type Error = Ed25519Error;
+
+    fn try_from(bytes: &[u8]) -> Result<SecretKey, Ed25519Error> {
+        let sk = bytes.try_into().map_err(|_| Ed25519Error::Length)?;
+        Ok(SecretKey { sk })
+    }
+}
+
+// serialized as the plain byte array, as the key files always have been
+impl Serialize for SecretKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.sk.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<SecretKey, D::Error> {
+        Ok(SecretKey::from(<[u8; 32]>::deserialize(deserializer)?))
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct PublicKey {
     pk: [u8; 32],
@@ -174,10 +179,8 @@ pub struct PublicKey {
 
 impl PublicKey {
     pub fn from_bytes(bytes: &[u8; 32]) -> Result<PublicKey, Ed25519Error> {
-        match Ed25519Point::decode(bytes) {
-            Ok(_) => Ok(PublicKey { pk: bytes.clone() }),
-            Err(e) => Err(e),
-        }
+        backend::check_point(bytes)?;
+        Ok(PublicKey { pk: *bytes })
     }
 
     pub fn to_bytes(&self) -> [u8; 32] {
@@ -185,16 +188,7 @@ impl PublicKey {
     }
 
     pub fn verify(&self, signature: &[u8; 64], m: &[u8]) -> Result<(), Ed25519Error> {
-        let r = Ed25519Point::decode(signature[..(B as usize) / 8].try_into().unwrap())?;
-        let a = Ed25519Point::decode(&self.pk)?;
-        let s = BigUint::from_bytes_le(&signature[(B as usize) / 8..]);
-        let k = h_int(&[&r.encode()[..], &self.pk[..], m].concat());
-
-        if (*BASE_POINT).clone().scalar_mul(s) != r + a.scalar_mul(k) {
-            Err(Ed25519Error::Signature)
-        } else {
-            Ok(())
-        }
+        backend::verify(&self.pk, signature, m)
     }
 }
 
@@ -210,6 +204,34 @@ impl From<PublicKey> for [u8; 32] {
     }
 }
 
+impl TryFrom<&[u8]> for PublicKey {
+    type Error = Ed25519Error;
+
+    fn try_from(bytes: &[u8]) -> Result<PublicKey, Ed25519Error> {
+        PublicKey::from_bytes(bytes.try_into().map_err(|_| Ed25519Error::Length)?)
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.pk))
+    }
+}
+
+impl Serialize for PublicKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.pk.serialize(serializer)
+    }
+}
+
+// only valid curve points deserialize
+impl<'de> Deserialize<'de> for PublicKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<PublicKey, D::Error> {
+        let bytes = <[u8; 32]>::deserialize(deserializer)?;
+        PublicKey::from_bytes(&bytes).map_err(de::Error::custom)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Ed25519Point {
     x: BigUint,
@@ -303,12 +325,95 @@ impl Add for Ed25519Point {
     }
 }
 
+// The reference implementation, following the RFC 8032 pseudo-code. Kept
+// with the dalek feature too, to check that feature against it.
+#[cfg_attr(feature = "dalek", allow(dead_code))]
+mod reference {
+    use super::*;
+
+    // the secret scalar, from the lower half of the hash of the key
+    fn scalar(sk: &[u8; 32]) -> BigUint {
+        // 512bit
+        let h = h(sk);
+        // 下位256bitを取り出して整数とする
+        let mut s = BigUint::from_bytes_le(&h[..(B as usize) / 8]);
+
+        // 下位3bitを消す
+        s.set_bit(0, false);
+        s.set_bit(1, false);
+        s.set_bit(2, false);
+
+        // b-2 bit目は立ててb-1 bit目は無視する
+        s.set_bit(B - 2, true);
+        s.set_bit(B - 1, false);
+
+        s
+    }
+
+    pub(super) fn public_key(sk: &[u8; 32]) -> [u8; 32] {
+        let pk = (*BASE_POINT).clone().scalar_mul(scalar(sk));
+        assert!(pk.is_on_curve());
+
+        pk.encode()
+    }
+
+    pub(super) fn sign(sk: &[u8; 32], message: &[u8]) -> [u8; 64] {
+        // 512bit
+        let h = h(sk);
+        let s = scalar(sk);
+
+        // 上位256bitを取り出す
+        let r = h_int(&[&h[(B as usize) / 8..], message].concat());
+        let rr = (*BASE_POINT).clone().scalar_mul(r.clone()).encode();
+
+        let pk = public_key(sk);
+        let k = h_int(&[&rr[..], &pk[..], message].concat());
+
+        let ss = (r + k * s) % (*L).clone();
+        let mut ss_bytes = ss.to_bytes_le();
+        ss_bytes.resize(32, 0);
+
+        [&rr[..], &ss_bytes[..]]
+            .concat()
+            .try_into()
+            .unwrap()
+    }
+
+    pub(super) fn check_point(pk: &[u8; 32]) -> Result<(), Ed25519Error> {
+        Ed25519Point::decode(pk).map(|_| ())
+    }
+
+    pub(super) fn verify(pk: &[u8; 32], signature: &[u8; 64], m: &[u8]) -> Result<(), Ed25519Error> {
+        let r = Ed25519Point::decode(signature[..(B as usize) / 8].try_into().unwrap())?;
+        let a = Ed25519Point::decode(pk)?;
+        let s = BigUint::from_bytes_le(&signature[(B as usize) / 8..]);
+        let k = h_int(&[&r.encode()[..], &pk[..], m].concat());
+
+        if (*BASE_POINT).clone().scalar_mul(s) != r + a.scalar_mul(k) {
+            Err(Ed25519Error::Signature)
+        } else {
+            Ok(())
+        }
+    }
+
+    // the encoded point sk*pk, refusing the neutral point
+    pub(super) fn shared_point(sk: &[u8; 32], pk: &[u8; 32]) -> Result<[u8; 32], Ed25519Error> {
+        let point = Ed25519Point::decode(pk)?.scalar_mul(scalar(sk));
+        if point.x == 0.to_biguint().unwrap() && point.y == 1.to_biguint().unwrap() {
+            return Err(Ed25519Error::Point);
+        }
+        Ok(point.encode())
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum Ed25519Error {
     #[error("Invalid point")]
     Point,
     #[error("Invalid signature")]
     Signature,
+    #[error("Invalid key length")]
+    Length,
 }
 
 #[cfg(test)]
@@ -347,6 +452,17 @@ mod tests {
         assert!(pk.verify(&signature, &m).is_ok());
     }
 
+    // about one signature in sixteen has an s shorter than 32 bytes
+    #[test]
+    fn test_sign_short_scalar() {
+        let sk = SecretKey::from_bytes(&[1; 32]);
+        let pk = sk.public_key();
+        for i in 0..64u8 {
+            let signature = sk.sign(&[i]);
+            assert!(pk.verify(&signature, &[i]).is_ok());
+        }
+    }
+
     #[test]
     fn test_encdec() {
         let sk = SecretKey::random();
@@ -354,6 +470,31 @@ mod tests {
         assert!(Ed25519Point::decode(&pk.pk).is_ok());
     }
 
+    #[test]
+    fn test_serde() {
+        let sk = SecretKey::random();
+        let pk = sk.public_key();
+        let json = serde_json::to_string(&pk).unwrap();
+        assert_eq!(json, serde_json::to_string(&pk.to_bytes()).unwrap());
+        assert_eq!(serde_json::from_str::<PublicKey>(&json).unwrap(), pk);
+        assert_eq!(
+            serde_json::from_str::<SecretKey>(&serde_json::to_string(&sk).unwrap()).unwrap(),
+            sk
+        );
+
+        // not a point on the curve
+        let invalid = (0..=255u8)
+            .map(|b| [b; 32])
+            .find(|bytes| PublicKey::from_bytes(bytes).is_err())
+            .unwrap();
+        let invalid = serde_json::to_string(&invalid).unwrap();
+        assert!(serde_json::from_str::<PublicKey>(&invalid).is_err());
+
+        assert_eq!(PublicKey::try_from(&pk.to_bytes()[..]).unwrap(), pk);
+        assert!(PublicKey::try_from(&pk.to_bytes()[1..]).is_err());
+        assert_eq!(pk.to_string(), hex::encode(pk.to_bytes()));
+    }
+
     #[test]
     fn test_etc() {
         assert!(B >= 10);
@@ -394,6 +535,52 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_shared_secret() {
+        let (alice, bob) = (SecretKey::random(), SecretKey::random());
+        let shared = alice.shared_secret(&bob.public_key()).unwrap();
+        assert_eq!(shared, bob.shared_secret(&alice.public_key()).unwrap());
+        assert_ne!(shared, alice.shared_secret(&alice.public_key()).unwrap());
+
+        // the neutral point, of order 1
+        let neutral = Ed25519Point::new(0u8.into(), 1u8.into()).unwrap().encode();
+        let neutral = PublicKey::from_bytes(&neutral).unwrap();
+        assert!(alice.shared_secret(&neutral).is_err());
+    }
+
+    // the dalek backend gives the bytes of the reference one
+    #[cfg(feature = "dalek")]
+    #[test]
+    fn test_dalek_compat() {
+        use crate::crypto::dalek;
+
+        let neutral = Ed25519Point::new(0u8.into(), 1u8.into()).unwrap().encode();
+        let other = SecretKey::random().to_bytes();
+        for i in 0..8u8 {
+            let sk = SecretKey::random().to_bytes();
+            let m = vec![i; i as usize * 10];
+            let pk = reference::public_key(&sk);
+            assert_eq!(dalek::public_key(&sk), pk);
+            let signature = reference::sign(&sk, &m);
+            assert_eq!(dalek::sign(&sk, &m), signature);
+            assert!(dalek::verify(&pk, &signature, &m).is_ok());
+            let mut forged = signature;
+            forged[40] ^= 1;
+            assert!(dalek::verify(&pk, &forged, &m).is_err());
+            assert!(reference::verify(&pk, &forged, &m).is_err());
+
+            let shared = reference::shared_point(&other, &pk).unwrap();
+            assert_eq!(dalek::shared_point(&other, &pk).unwrap(), shared);
+            assert!(dalek::shared_point(&sk, &neutral).is_err());
+        }
+        for b in 0..=255u8 {
+            assert_eq!(
+                dalek::check_point(&[b; 32]).is_ok(),
+                reference::check_point(&[b; 32]).is_ok()
+            );
+        }
+    }
+
     #[test]
     fn test_masked() {
         let sk = SecretKey::random();
@@ -0,0 +1,135 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use thiserror::Error;
+
+use super::secretbox::{self, SecretBoxError};
+use super::signer::{Signer, SignerError};
+use super::{PublicKey, SecretKey};
+use crate::util::rng::{Entropy, SystemEntropy};
+
+// Argon2id costs for new keystores, memory in KiB; the ones of a sealed key
+// are stored with it, so these can be raised later
+pub const KEYSTORE_MEMORY: u32 = 19 * 1024;
+pub const KEYSTORE_PASSES: u32 = 2;
+pub const KEYSTORE_SALT_LEN: usize = 16;
+
+// A signing key sealed with a password, for keeping it on disk. The public
+// key stays readable, so that a locked account still knows its address.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SealedKey {
+    pub pubkey: PublicKey,
+    pub salt: [u8; KEYSTORE_SALT_LEN],
+    pub memory: u32,
+    pub passes: u32,
+    // secretbox of the key, with the key derived from the password
+    pub sealed: Vec<u8>,
+}
+
+impl SealedKey {
+    pub fn seal(sk: &SecretKey, password: &str) -> SealedKey {
+        SealedKey::seal_with(sk, password, KEYSTORE_MEMORY, KEYSTORE_PASSES, &SystemEntropy)
+            .expect("the default costs are valid")
+    }
+
+    pub fn seal_with(
+        sk: &SecretKey,
+        password: &str,
+        memory: u32,
+        passes: u32,
+        entropy: &dyn Entropy,
+    ) -> Result<SealedKey, KeystoreError> {
+        let mut salt = [0u8; KEYSTORE_SALT_LEN];
+        entropy.fill_bytes(&mut salt);
+        let key = derive_key(password, &salt, memory, passes)?;
+        Ok(SealedKey {
+            pubkey: sk.public_key(),
+            salt,
+            memory,
+            passes,
+            sealed: secretbox::seal(&key, &sk.to_bytes()),
+        })
+    }
+
+    pub fn open(&self, password: &str) -> Result<SecretKey, KeystoreError> {
+        let key = derive_key(password, &self.salt, self.memory, self.passes)?;
+        let bytes = secretbox::open(&key, &self.sealed).map_err(|e| match e {
+            SecretBoxError::Tag => KeystoreError::WrongPassword,
+            SecretBoxError::Size => KeystoreError::Corrupted,
+        })?;
+        let sk = SecretKey::try_from(&bytes[..]).map_err(|_| KeystoreError::Corrupted)?;
+        if sk.public_key() != self.pubkey {
+            return Err(KeystoreError::Corrupted);
+        }
+        Ok(sk)
+    }
+}
+
+// a locked key signs nothing, see UserHandle::unlock
+impl Signer for SealedKey {
+    fn public_key(&self) -> PublicKey {
+        self.pubkey.clone()
+    }
+
+    fn sign(&self, _message: &[u8]) -> Result<[u8; 64], SignerError> {
+        Err(SignerError::Locked)
+    }
+
+    fn derive_key(&self, _info: &[u8]) -> Result<[u8; 32], SignerError> {
+        Err(SignerError::Locked)
+    }
+}
+
+// costs out of the range Argon2 takes mean a damaged keystore
+fn derive_key(
+    password: &str,
+    salt: &[u8],
+    memory: u32,
+    passes: u32,
+) -> Result<[u8; 32], KeystoreError> {
+    let params = Params::new(memory, passes, 1, Some(32)).map_err(|_| KeystoreError::Corrupted)?;
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(password.as_bytes(), salt, &mut key)
+        .map_err(|_| KeystoreError::Corrupted)?;
+    Ok(key)
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum KeystoreError {
+    #[error("Wrong password")]
+    WrongPassword,
+    #[error("The sealed key is damaged")]
+    Corrupted,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::rng::SeededEntropy;
+
+    #[test]
+    fn test_keystore() {
+        let sk = SecretKey::random();
+        let sealed = SealedKey::seal_with(&sk, "hunter2", 8, 1, &SeededEntropy::new(1)).unwrap();
+        assert_eq!(sealed.open("hunter2").unwrap(), sk);
+        assert_eq!(sealed.open("hunter3"), Err(KeystoreError::WrongPassword));
+        assert!(!sealed.sealed.windows(32).any(|w| w == sk.to_bytes()));
+        assert_eq!(Signer::public_key(&sealed), sk.public_key());
+        assert!(matches!(sealed.sign(b"m"), Err(SignerError::Locked)));
+
+        let json = serde_json::to_string(&sealed).unwrap();
+        let decoded: SealedKey = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.open("hunter2").unwrap(), sk);
+
+        // another salt, another sealing
+        let again = SealedKey::seal_with(&sk, "hunter2", 8, 1, &SeededEntropy::new(2)).unwrap();
+        assert_ne!(again.sealed, sealed.sealed);
+        let mut swapped = again.clone();
+        swapped.pubkey = SecretKey::random().public_key();
+        assert_eq!(swapped.open("hunter2"), Err(KeystoreError::Corrupted));
+        let mut damaged = again.clone();
+        damaged.passes = 0;
+        assert_eq!(damaged.open("hunter2"), Err(KeystoreError::Corrupted));
+    }
+}
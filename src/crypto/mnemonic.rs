@@ -0,0 +1,198 @@
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use pbkdf2::pbkdf2_hmac;
+use sha2::{Digest, Sha256, Sha512};
+use std::convert::TryInto;
+use thiserror::Error;
+use unicode_normalization::UnicodeNormalization;
+
+use super::SecretKey;
+use crate::util::rng::{Entropy, SystemEntropy};
+
+// The seed phrases are BIP39 ones, on its English word list, so that a phrase
+// written down for another wallet reads the same. The key is the SLIP-10
+// master key of the seed.
+static WORDS: Lazy<Vec<&'static str>> =
+    Lazy::new(|| include_str!("bip39_english.txt").lines().collect());
+
+const SEED_ROUNDS: u32 = 2048;
+const SLIP10_CURVE: &[u8] = b"ed25519 seed";
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum MnemonicError {
+    #[error("A seed phrase has 12, 15, 18, 21 or 24 words, not {0}")]
+    Length(usize),
+    #[error("Unknown word: {0}")]
+    UnknownWord(String),
+    #[error("Wrong checksum, a word is mistyped or out of order")]
+    Checksum,
+}
+
+// A new phrase of words words, 12 to 24 in steps of 3
+pub fn generate_mnemonic(words: usize) -> Result<String, MnemonicError> {
+    generate_mnemonic_from(words, &SystemEntropy)
+}
+
+pub fn generate_mnemonic_from(
+    words: usize,
+    entropy: &dyn Entropy,
+) -> Result<String, MnemonicError> {
+    if !valid_length(words) {
+        return Err(MnemonicError::Length(words));
+    }
+    let mut bytes = vec![0; words * 4 / 3];
+    entropy.fill_bytes(&mut bytes);
+    Ok(entropy_to_mnemonic(&bytes))
+}
+
+// every 11 bits of the entropy and its checksum, the first bits of its
+// SHA-256, one bit per 32 of entropy, make a word
+pub fn entropy_to_mnemonic(entropy: &[u8]) -> String {
+    let checksum = Sha256::digest(entropy);
+    let bit = |i: usize| {
+        let byte = if i < entropy.len() * 8 {
+            entropy[i / 8]
+        } else {
+            checksum[i / 8 - entropy.len()]
+        };
+        (byte >> (7 - i % 8)) & 1
+    };
+    let words = (entropy.len() * 8 + entropy.len() / 4) / 11;
+    (0..words)
+        .map(|w| {
+            let index = (0..11).fold(0usize, |acc, i| acc << 1 | bit(w * 11 + i) as usize);
+            WORDS[index]
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// The entropy of a phrase, checking its words and checksum. Words are split
+// on any whitespace and matched case-insensitively.
+pub fn mnemonic_to_entropy(phrase: &str) -> Result<Vec<u8>, MnemonicError> {
+    let phrase = normalize(phrase);
+    let words: Vec<&str> = phrase.split(' ').filter(|w| !w.is_empty()).collect();
+    if !valid_length(words.len()) {
+        return Err(MnemonicError::Length(words.len()));
+    }
+    let mut bits = Vec::with_capacity(words.len() * 11);
+    for word in &words {
+        let index = WORDS
+            .binary_search(word)
+            .map_err(|_| MnemonicError::UnknownWord(word.to_string()))?;
+        bits.extend((0..11).rev().map(|i| (index >> i) & 1 == 1));
+    }
+    let entropy: Vec<u8> = bits[..words.len() * 32 / 3]
+        .chunks(8)
+        .map(|byte| byte.iter().fold(0u8, |acc, b| acc << 1 | *b as u8))
+        .collect();
+    if entropy_to_mnemonic(&entropy) != words.join(" ") {
+        return Err(MnemonicError::Checksum);
+    }
+    Ok(entropy)
+}
+
+// The 64-byte BIP39 seed of a checked phrase and an optional passphrase
+pub fn mnemonic_to_seed(phrase: &str, passphrase: &str) -> Result<[u8; 64], MnemonicError> {
+    let entropy = mnemonic_to_entropy(phrase)?;
+    let phrase = entropy_to_mnemonic(&entropy);
+    let salt = format!("mnemonic{}", passphrase.nfkd());
+    let mut seed = [0u8; 64];
+    pbkdf2_hmac::<Sha512>(phrase.as_bytes(), salt.as_bytes(), SEED_ROUNDS, &mut seed);
+    Ok(seed)
+}
+
+impl SecretKey {
+    // The key a seed phrase stands for; the same phrase and passphrase give it
+    // back on any device. A wrong passphrase gives another valid key, not an
+    // error.
+    pub fn from_mnemonic(phrase: &str, passphrase: &str) -> Result<SecretKey, MnemonicError> {
+        let seed = mnemonic_to_seed(phrase, passphrase)?;
+        let mut hmac = Hmac::<Sha512>::new_from_slice(SLIP10_CURVE).unwrap();
+        hmac.update(&seed);
+        let master = hmac.finalize().into_bytes();
+        Ok(SecretKey::from_bytes(&master[..32].try_into().unwrap()))
+    }
+}
+
+fn valid_length(words: usize) -> bool {
+    (12..=24).contains(&words) && words.is_multiple_of(3)
+}
+
+fn normalize(s: &str) -> String {
+    s.nfkd()
+        .collect::<String>()
+        .to_lowercase()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mnemonic_vectors() {
+        assert_eq!(WORDS.len(), 2048);
+        let vectors = [
+            (
+                "00000000000000000000000000000000",
+                "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about",
+                "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04",
+            ),
+            (
+                "7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f",
+                "legal winner thank year wave sausage worth useful legal winner thank yellow",
+                "2e8905819b8723fe2c1d161860e5ee1830318dbf49a83bd451cfb8440c28bd6fa457fe1296106559a3c80937a1c1069be3a3a5bd381ee6260e8d9739fce1f607",
+            ),
+        ];
+        for (entropy, phrase, seed) in vectors {
+            let entropy = hex::decode(entropy).unwrap();
+            assert_eq!(entropy_to_mnemonic(&entropy), phrase);
+            assert_eq!(mnemonic_to_entropy(phrase).unwrap(), entropy);
+            assert_eq!(hex::encode(mnemonic_to_seed(phrase, "TREZOR").unwrap()), seed);
+        }
+        assert_eq!(
+            entropy_to_mnemonic(&[0xff; 32]),
+            "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo vote"
+        );
+    }
+
+    #[test]
+    fn test_mnemonic_errors() {
+        let phrase = generate_mnemonic(24).unwrap();
+        assert_eq!(phrase.split(' ').count(), 24);
+        let shouted = format!("  {}\n", phrase.to_uppercase().replace(' ', "  "));
+        assert_eq!(
+            SecretKey::from_mnemonic(&shouted, "").unwrap(),
+            SecretKey::from_mnemonic(&phrase, "").unwrap()
+        );
+        assert_ne!(
+            SecretKey::from_mnemonic(&phrase, "x").unwrap(),
+            SecretKey::from_mnemonic(&phrase, "").unwrap()
+        );
+
+        assert_eq!(generate_mnemonic(13), Err(MnemonicError::Length(13)));
+        assert_eq!(mnemonic_to_entropy("abandon about"), Err(MnemonicError::Length(2)));
+        assert_eq!(
+            mnemonic_to_entropy(&("abandon ".repeat(11) + "abandonn")),
+            Err(MnemonicError::UnknownWord("abandonn".to_string()))
+        );
+        assert_eq!(
+            mnemonic_to_entropy(&"abandon ".repeat(12)),
+            Err(MnemonicError::Checksum)
+        );
+    }
+
+    #[test]
+    fn test_slip10_master() {
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let mut hmac = Hmac::<Sha512>::new_from_slice(SLIP10_CURVE).unwrap();
+        hmac.update(&seed);
+        assert_eq!(
+            hex::encode(&hmac.finalize().into_bytes()[..32]),
+            "2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7"
+        );
+    }
+}
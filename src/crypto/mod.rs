@@ -1,3 +1,18 @@
 mod ed25519;
+#[cfg(feature = "dalek")]
+mod dalek;
+mod signer;
+mod keystore;
+mod mnemonic;
+#[doc(hidden)]
+pub mod secretbox;
 
-//pub use ed25519::{SecretKey, PublicKey,Ed25519Error};
\ No newline at end of file
+pub use ed25519::{SecretKey, PublicKey,Ed25519Error};
+pub use keystore::{
+    KeystoreError, SealedKey, KEYSTORE_MEMORY, KEYSTORE_PASSES, KEYSTORE_SALT_LEN,
+};
+pub use mnemonic::{
+    entropy_to_mnemonic, generate_mnemonic, generate_mnemonic_from, mnemonic_to_entropy,
+    mnemonic_to_seed, MnemonicError,
+};
+pub use signer::{ExternalSigner, Signer, SignerError};
\ No newline at end of file
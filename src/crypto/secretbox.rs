@@ -0,0 +1,62 @@
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use rand::prelude::*;
+use rand_chacha::ChaCha20Rng;
+use thiserror::Error;
+
+pub const SECRETBOX_NONCE_LEN: usize = 24;
+pub const SECRETBOX_TAG_LEN: usize = 16;
+
+// Symmetric encryption for backups, sealed keys and direct messages.
+// XChaCha20-Poly1305; its nonces are long enough to be picked at random.
+// Layout: nonce(24) | ciphertext | tag(16)
+pub fn seal(key: &[u8; 32], plaintext: &[u8]) -> Vec<u8> {
+    let mut nonce = [0u8; SECRETBOX_NONCE_LEN];
+    ChaCha20Rng::from_entropy().fill_bytes(&mut nonce);
+    let ciphertext = XChaCha20Poly1305::new(key.into())
+        .encrypt(XNonce::from_slice(&nonce), plaintext)
+        .expect("plaintext is too long");
+    [&nonce[..], &ciphertext[..]].concat()
+}
+
+pub fn open(key: &[u8; 32], sealed: &[u8]) -> Result<Vec<u8>, SecretBoxError> {
+    if sealed.len() < SECRETBOX_NONCE_LEN + SECRETBOX_TAG_LEN {
+        return Err(SecretBoxError::Size);
+    }
+    let (nonce, ciphertext) = sealed.split_at(SECRETBOX_NONCE_LEN);
+    XChaCha20Poly1305::new(key.into())
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| SecretBoxError::Tag)
+}
+
+#[derive(Debug, Error)]
+pub enum SecretBoxError {
+    #[error("Sealed data is too short")]
+    Size,
+    #[error("Wrong key, or the data was changed")]
+    Tag,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secretbox_test() {
+        let key = [3u8; 32];
+        let sealed = seal(&key, b"followings");
+        assert_eq!(sealed.len(), 10 + SECRETBOX_NONCE_LEN + SECRETBOX_TAG_LEN);
+        assert_eq!(open(&key, &sealed).unwrap(), b"followings");
+        // a new nonce every time
+        assert_ne!(seal(&key, b"followings"), sealed);
+
+        assert!(matches!(open(&[4u8; 32], &sealed), Err(SecretBoxError::Tag)));
+        let mut changed = sealed.clone();
+        changed[SECRETBOX_NONCE_LEN] ^= 1;
+        assert!(matches!(open(&key, &changed), Err(SecretBoxError::Tag)));
+        let mut changed = sealed.clone();
+        changed[0] ^= 1;
+        assert!(matches!(open(&key, &changed), Err(SecretBoxError::Tag)));
+        assert!(matches!(open(&key, &sealed[..20]), Err(SecretBoxError::Size)));
+    }
+}
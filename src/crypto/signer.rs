@@ -0,0 +1,204 @@
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::convert::TryInto;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::Duration;
+use futures::future::BoxFuture;
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+use super::{Ed25519Error, PublicKey, SecretKey};
+
+// Anything which can produce signatures for one key.
+// The secret itself may live in memory, a hardware token or a signing daemon.
+pub trait Signer: Send + Sync {
+    fn public_key(&self) -> PublicKey;
+    fn sign(&self, message: &[u8]) -> Result<[u8; 64], SignerError>;
+    // The same, for async callers. Signers which wait on something else, as
+    // ExternalSigner does, wait without holding up the runtime's thread.
+    fn sign_async<'a>(&'a self, message: &'a [u8]) -> BoxFuture<'a, Result<[u8; 64], SignerError>> {
+        Box::pin(async move { self.sign(message) })
+    }
+    // A symmetric key for one purpose, named by info, derived from the
+    // secret. Only signers holding the secret themselves can do this.
+    fn derive_key(&self, info: &[u8]) -> Result<[u8; 32], SignerError>;
+}
+
+impl Signer for SecretKey {
+    fn public_key(&self) -> PublicKey {
+        SecretKey::public_key(self)
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<[u8; 64], SignerError> {
+        Ok(SecretKey::sign(self, message))
+    }
+
+    fn derive_key(&self, info: &[u8]) -> Result<[u8; 32], SignerError> {
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(b"noktulo:derive-key"), &self.to_bytes())
+            .expand(info, &mut key)
+            .expect("32 bytes is a valid length");
+        Ok(key)
+    }
+}
+
+const EXTERNAL_SIGNER_TIME_OUT: u64 = 30000;
+
+// Signs through a local daemon listening on a unix socket.
+// One request per connection, line based:
+//   "pubkey\n"        -> "<hex public key>\n"
+//   "sign <hex msg>\n" -> "<hex signature>\n" or "error <reason>\n"
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExternalSigner {
+    pub socket: PathBuf,
+    pub pubkey: PublicKey,
+}
+
+impl ExternalSigner {
+    pub async fn connect(socket: PathBuf) -> Result<ExternalSigner, SignerError> {
+        let rep = request_async(&socket, "pubkey").await?;
+        let pubkey = PublicKey::from_bytes(&decode_hex::<32>(&rep)?)?;
+        Ok(ExternalSigner { socket, pubkey })
+    }
+
+    // never hands out a signature the daemon made with some other key
+    fn checked(&self, message: &[u8], rep: &str) -> Result<[u8; 64], SignerError> {
+        let signature = decode_hex::<64>(rep)?;
+        self.public_key().verify(&signature, message)?;
+        Ok(signature)
+    }
+}
+
+impl Signer for ExternalSigner {
+    fn public_key(&self) -> PublicKey {
+        self.pubkey.clone()
+    }
+
+    // Waits for the daemon on the calling thread, whatever runs on it; async
+    // callers use sign_async.
+    fn sign(&self, message: &[u8]) -> Result<[u8; 64], SignerError> {
+        let rep = request(&self.socket, &format!("sign {}", hex::encode(message)))?;
+        self.checked(message, &rep)
+    }
+
+    fn sign_async<'a>(&'a self, message: &'a [u8]) -> BoxFuture<'a, Result<[u8; 64], SignerError>> {
+        Box::pin(async move {
+            let line = format!("sign {}", hex::encode(message));
+            let rep = request_async(&self.socket, &line).await?;
+            self.checked(message, &rep)
+        })
+    }
+
+    // the daemon never hands out anything but signatures
+    fn derive_key(&self, _info: &[u8]) -> Result<[u8; 32], SignerError> {
+        Err(SignerError::Unsupported)
+    }
+}
+
+fn request(socket: &PathBuf, line: &str) -> Result<String, SignerError> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(Duration::from_millis(EXTERNAL_SIGNER_TIME_OUT)))?;
+    stream.write_all(format!("{}\n", line).as_bytes())?;
+
+    let mut rep = String::new();
+    BufReader::new(stream).read_line(&mut rep)?;
+    parse_reply(&rep)
+}
+
+async fn request_async(socket: &PathBuf, line: &str) -> Result<String, SignerError> {
+    let request = async {
+        let mut stream = tokio::net::UnixStream::connect(socket).await?;
+        stream.write_all(format!("{}\n", line).as_bytes()).await?;
+
+        let mut rep = String::new();
+        tokio::io::BufReader::new(stream).read_line(&mut rep).await?;
+        parse_reply(&rep)
+    };
+    let timeout = Duration::from_millis(EXTERNAL_SIGNER_TIME_OUT);
+    tokio::time::timeout(timeout, request)
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+}
+
+fn parse_reply(rep: &str) -> Result<String, SignerError> {
+    let rep = rep.trim();
+    match rep.strip_prefix("error ") {
+        Some(reason) => Err(SignerError::Rejected(reason.to_string())),
+        None => Ok(rep.to_string()),
+    }
+}
+
+fn decode_hex<const N: usize>(s: &str) -> Result<[u8; N], SignerError> {
+    hex::decode(s)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| SignerError::Malformed(s.to_string()))
+}
+
+#[derive(Debug, Error)]
+pub enum SignerError {
+    #[error("Signer is unreachable: {0}")]
+    Io(#[from] io::Error),
+    #[error("Signer refused to sign: {0}")]
+    Rejected(String),
+    #[error("Malformed reply from signer: {0}")]
+    Malformed(String),
+    #[error("The key is locked, unlock it with its password")]
+    Locked,
+    #[error("The signer cannot derive keys")]
+    Unsupported,
+    #[error("The account has no key")]
+    NoKey,
+    #[error(transparent)]
+    Ed25519(#[from] Ed25519Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    // on the current-thread runtime, which has no other thread to wait on
+    #[tokio::test]
+    async fn external_signer_test() {
+        let path = std::env::temp_dir().join(format!("noktulo-signer-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let sk = SecretKey::random();
+        let daemon_sk = sk.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut line = String::new();
+                BufReader::new(&stream).read_line(&mut line).unwrap();
+                let rep = match line.trim().split_once(' ') {
+                    Some(("sign", msg)) => hex::encode(daemon_sk.sign(&hex::decode(msg).unwrap())),
+                    _ => daemon_sk.public_key().to_string(),
+                };
+                writeln!(stream, "{}", rep).unwrap();
+            }
+        });
+
+        let signer = ExternalSigner::connect(path.clone()).await.unwrap();
+        assert_eq!(Signer::public_key(&signer), sk.public_key());
+        let sig = Signer::sign(&signer, b"hello").unwrap();
+        assert!(sk.public_key().verify(&sig, b"hello").is_ok());
+        let sig = signer.sign_async(b"hello async").await.unwrap();
+        assert!(sk.public_key().verify(&sig, b"hello async").is_ok());
+        assert!(matches!(signer.derive_key(b"backup"), Err(SignerError::Unsupported)));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn derive_key_test() {
+        let sk = SecretKey::random();
+        let key = Signer::derive_key(&sk, b"backup").unwrap();
+        assert_eq!(Signer::derive_key(&sk.clone(), b"backup").unwrap(), key);
+        assert_ne!(Signer::derive_key(&sk, b"other").unwrap(), key);
+        assert_ne!(Signer::derive_key(&SecretKey::random(), b"backup").unwrap(), key);
+    }
+}
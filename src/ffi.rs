@@ -0,0 +1,290 @@
+// A C ABI over the client core, for apps which cannot link Rust directly:
+// identities, signing and checking posts, addresses and ClientMessages.
+// Build it as a library with
+//     cargo rustc --release --features ffi --crate-type staticlib
+// (or cdylib). Keys are passed as 32 byte buffers, posts and messages as
+// NUL-terminated JSON. Strings returned are owned by the caller, who gives
+// them back with noktulo_string_free; null means the input was invalid.
+// Functions returning i32 return NOKTULO_OK or one of the errors below.
+// Every pointer has to be valid for what it is used for, or null.
+// A panic never crosses into the caller: it returns NOKTULO_PANIC, or null.
+
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use crate::api_server::ClientMessage;
+use crate::api_server::wire::{self, WireEncoding};
+use crate::crypto::{PublicKey, SecretKey};
+use crate::user::post::{Post, SignedPost};
+use crate::user::user::Address;
+
+pub const NOKTULO_OK: i32 = 0;
+pub const NOKTULO_NULL: i32 = -1;
+pub const NOKTULO_INVALID: i32 = -2;
+pub const NOKTULO_SIGNATURE: i32 = -3;
+pub const NOKTULO_PANIC: i32 = -4;
+
+// Runs f, giving on_panic if it panics; unwinding out of an extern "C"
+// function is undefined behavior.
+fn catch<T>(on_panic: T, f: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(on_panic)
+}
+
+unsafe fn read_key(key: *const u8) -> Option<[u8; 32]> {
+    if key.is_null() {
+        return None;
+    }
+    let mut ret = [0; 32];
+    ptr::copy_nonoverlapping(key, ret.as_mut_ptr(), 32);
+    Some(ret)
+}
+
+unsafe fn write_key(key: &[u8; 32], out: *mut u8) -> i32 {
+    if out.is_null() {
+        return NOKTULO_NULL;
+    }
+    ptr::copy_nonoverlapping(key.as_ptr(), out, 32);
+    NOKTULO_OK
+}
+
+unsafe fn read_str<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None;
+    }
+    CStr::from_ptr(s).to_str().ok()
+}
+
+unsafe fn read_pubkey(pubkey: *const u8) -> Option<PublicKey> {
+    PublicKey::try_from(&read_key(pubkey)?[..]).ok()
+}
+
+fn into_c(s: String) -> *mut c_char {
+    CString::new(s).map_or(ptr::null_mut(), CString::into_raw)
+}
+
+fn message_to_c(msg: &ClientMessage, encoding: WireEncoding) -> *mut c_char {
+    into_c(wire::encode(&serde_json::to_string(msg).unwrap(), encoding))
+}
+
+fn encoding(base64: bool) -> WireEncoding {
+    if base64 {
+        WireEncoding::Base64
+    } else {
+        WireEncoding::Numbers
+    }
+}
+
+/// Writes a new secret key to secret_out.
+///
+/// # Safety
+///
+/// secret_out is null or points to 32 writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn noktulo_identity_create(secret_out: *mut u8) -> i32 {
+    catch(NOKTULO_PANIC, || write_key(&SecretKey::random().to_bytes(), secret_out))
+}
+
+/// Writes the public key of secret to pubkey_out.
+///
+/// # Safety
+///
+/// secret is null or points to 32 readable bytes, pubkey_out is null or
+/// points to 32 writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn noktulo_public_key(secret: *const u8, pubkey_out: *mut u8) -> i32 {
+    catch(NOKTULO_PANIC, || match read_key(secret) {
+        Some(sk) => write_key(&SecretKey::from_bytes(&sk).public_key().to_bytes(), pubkey_out),
+        None => NOKTULO_NULL,
+    })
+}
+
+/// The address of pubkey as users write it.
+///
+/// # Safety
+///
+/// pubkey is null or points to 32 readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn noktulo_address_encode(pubkey: *const u8) -> *mut c_char {
+    catch(ptr::null_mut(), || match read_pubkey(pubkey) {
+        Some(pk) => into_c(Address::from(pk).to_string()),
+        None => ptr::null_mut(),
+    })
+}
+
+/// The 32 bytes of a written address, once its checksum is checked.
+///
+/// # Safety
+///
+/// addr is null or a NUL-terminated string, addr_out is null or points to
+/// 32 writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn noktulo_address_decode(addr: *const c_char, addr_out: *mut u8) -> i32 {
+    catch(NOKTULO_PANIC, || {
+        let addr = match read_str(addr) {
+            Some(addr) => addr,
+            None => return NOKTULO_NULL,
+        };
+        match Address::from_str(addr) {
+            Ok(addr) => write_key(&addr.into(), addr_out),
+            Err(_) => NOKTULO_INVALID,
+        }
+    })
+}
+
+/// A Post as JSON in, the SignedPost the secret key makes of it out.
+///
+/// # Safety
+///
+/// secret is null or points to 32 readable bytes, post is null or a
+/// NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn noktulo_sign_post(secret: *const u8, post: *const c_char) -> *mut c_char {
+    catch(ptr::null_mut(), || {
+        let (sk, post) = match (read_key(secret), read_str(post)) {
+            (Some(sk), Some(post)) => (SecretKey::from_bytes(&sk), post),
+            _ => return ptr::null_mut(),
+        };
+        let post: Post = match serde_json::from_str(post) {
+            Ok(post) => post,
+            Err(_) => return ptr::null_mut(),
+        };
+        let signature = sk.sign(&post.signed_message());
+        let sigpost = SignedPost::new(Address::from(sk.public_key()), post, signature);
+        into_c(serde_json::to_string(&sigpost).unwrap())
+    })
+}
+
+/// Checks a SignedPost, as JSON, against pubkey.
+///
+/// # Safety
+///
+/// pubkey is null or points to 32 readable bytes, sigpost is null or a
+/// NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn noktulo_verify_post(pubkey: *const u8, sigpost: *const c_char) -> i32 {
+    catch(NOKTULO_PANIC, || {
+        let (pk, sigpost) = match (read_pubkey(pubkey), read_str(sigpost)) {
+            (Some(pk), Some(sigpost)) => (pk, sigpost),
+            _ => return NOKTULO_NULL,
+        };
+        match SignedPost::from_bytes(sigpost.as_bytes()) {
+            Ok(sigpost) if sigpost.verify(&pk).is_ok() => NOKTULO_OK,
+            Ok(_) => NOKTULO_SIGNATURE,
+            Err(_) => NOKTULO_INVALID,
+        }
+    })
+}
+
+/// The EstablishReq which starts the handshake for pubkey. base64 picks the
+/// encoding agreed on with Hello, numbers otherwise.
+///
+/// # Safety
+///
+/// pubkey is null or points to 32 readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn noktulo_establish_req(pubkey: *const u8, base64: bool) -> *mut c_char {
+    catch(ptr::null_mut(), || match read_pubkey(pubkey) {
+        Some(pk) => {
+            let msg = ClientMessage::EstablishReq {
+                addr: Address::from(pk.clone()).into(),
+                pubkey: pk,
+            };
+            message_to_c(&msg, encoding(base64))
+        }
+        None => ptr::null_mut(),
+    })
+}
+
+/// The answer to the 32 byte challenge the server sent.
+///
+/// # Safety
+///
+/// secret and challenge are each null or point to 32 readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn noktulo_challenge_response(
+    secret: *const u8,
+    challenge: *const u8,
+    base64: bool,
+) -> *mut c_char {
+    catch(ptr::null_mut(), || match (read_key(secret), read_key(challenge)) {
+        (Some(sk), Some(challenge)) => {
+            let sig = SecretKey::from_bytes(&sk).sign(&challenge);
+            message_to_c(&ClientMessage::ChallengeResponce(sig), encoding(base64))
+        }
+        _ => ptr::null_mut(),
+    })
+}
+
+/// Any other ClientMessage, written as JSON in the numbers encoding, checked
+/// and written again in the encoding of the connection.
+///
+/// # Safety
+///
+/// msg is null or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn noktulo_client_message(msg: *const c_char, base64: bool) -> *mut c_char {
+    catch(ptr::null_mut(), || {
+        match read_str(msg).and_then(|msg| serde_json::from_str::<ClientMessage>(msg).ok()) {
+            Some(msg) => message_to_c(&msg, encoding(base64)),
+            None => ptr::null_mut(),
+        }
+    })
+}
+
+/// Gives back a string returned by the functions above.
+///
+/// # Safety
+///
+/// s is null or a string one of them returned, not freed before.
+#[no_mangle]
+pub unsafe extern "C" fn noktulo_string_free(s: *mut c_char) {
+    catch((), || {
+        if !s.is_null() {
+            drop(CString::from_raw(s));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user::post::{Hoot, PostKind};
+    use crate::user::post_id::PostId;
+    use crate::user::user::UserAttribute;
+
+    #[test]
+    fn ffi_test() {
+        unsafe {
+            let (mut secret, mut pubkey, mut addr) = ([0; 32], [0; 32], [0; 32]);
+            assert_eq!(noktulo_identity_create(secret.as_mut_ptr()), NOKTULO_OK);
+            assert_eq!(noktulo_public_key(secret.as_ptr(), pubkey.as_mut_ptr()), NOKTULO_OK);
+
+            let written = noktulo_address_encode(pubkey.as_ptr());
+            assert_eq!(noktulo_address_decode(written, addr.as_mut_ptr()), NOKTULO_OK);
+            noktulo_string_free(written);
+            let pk = PublicKey::try_from(&pubkey[..]).unwrap();
+            assert_eq!(Address::new(addr), Address::from(pk));
+
+            let post = Post {
+                user_attr: UserAttribute::new("alice", 0, ""),
+                id: PostId::generate(0, None),
+                content: PostKind::Hoot(Hoot::new("hi".to_string())),
+                created_at: 0,
+            };
+            let post = CString::new(serde_json::to_string(&post).unwrap()).unwrap();
+            let sigpost = noktulo_sign_post(secret.as_ptr(), post.as_ptr());
+            assert_eq!(noktulo_verify_post(pubkey.as_ptr(), sigpost), NOKTULO_OK);
+            let other = SecretKey::random().public_key().to_bytes();
+            assert_eq!(noktulo_verify_post(other.as_ptr(), sigpost), NOKTULO_SIGNATURE);
+            noktulo_string_free(sigpost);
+
+            let msg = CString::new(r#"{"SubscribeReq":"bogus"}"#).unwrap();
+            assert!(noktulo_client_message(msg.as_ptr(), false).is_null());
+            assert_eq!(noktulo_address_decode(ptr::null(), addr.as_mut_ptr()), NOKTULO_NULL);
+        }
+        assert_eq!(catch(NOKTULO_PANIC, || panic!("in the core")), NOKTULO_PANIC);
+    }
+}
@@ -0,0 +1,93 @@
+use futures::future::BoxFuture;
+use log::warn;
+use std::io;
+use std::path::PathBuf;
+
+use super::key::Key;
+use crate::util::atomic_file;
+
+// the keys and values a backend holds
+pub type StoredValues = Vec<(Key, Vec<u8>)>;
+
+// Where a Store keeps its values besides memory, so that they outlive the
+// process. Values are put after the store took them, under its lock, so puts
+// of one key come in order.
+pub trait StoreBackend: Send + Sync {
+    // every value put before
+    fn load(&self) -> BoxFuture<'_, io::Result<StoredValues>>;
+    fn put(&self, key: Key, value: Vec<u8>) -> BoxFuture<'_, io::Result<()>>;
+}
+
+// One file per key in a directory, named by the key in hex and written with
+// atomic_file
+pub struct FileBackend {
+    dir: PathBuf,
+}
+
+impl FileBackend {
+    pub fn new(dir: PathBuf) -> FileBackend {
+        FileBackend { dir }
+    }
+
+    fn path(&self, key: &Key) -> PathBuf {
+        self.dir.join(hex::encode(key.as_bytes()))
+    }
+}
+
+impl StoreBackend for FileBackend {
+    fn load(&self) -> BoxFuture<'_, io::Result<StoredValues>> {
+        Box::pin(async move {
+            let mut ret = Vec::new();
+            let mut dir = match tokio::fs::read_dir(&self.dir).await {
+                Ok(dir) => dir,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(ret),
+                Err(e) => return Err(e),
+            };
+            while let Some(entry) = dir.next_entry().await? {
+                // backups and temporary files have a suffix, and are skipped
+                let key = match hex::decode(entry.file_name().to_string_lossy().as_bytes()) {
+                    Ok(bytes) => Key::from(&bytes[..]),
+                    Err(_) => continue,
+                };
+                match atomic_file::read(&entry.path()).await {
+                    Ok(value) => ret.push((key, value)),
+                    Err(e) => warn!("Stored value {:?} is lost: {}", key, e),
+                }
+            }
+            Ok(ret)
+        })
+    }
+
+    fn put(&self, key: Key, value: Vec<u8>) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            tokio::fs::create_dir_all(&self.dir).await?;
+            atomic_file::write(&self.path(&key), &value).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kad::{Store, TOKEN_KEY_LEN};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn file_backend_test() {
+        let dir = std::env::temp_dir().join(format!("noktulo_store_{}", std::process::id()));
+        let backend: Arc<dyn StoreBackend> = Arc::new(FileBackend::new(dir.clone()));
+        let (k, other) = (Key::random(TOKEN_KEY_LEN), Key::random(TOKEN_KEY_LEN));
+        backend.put(k.clone(), b"old".to_vec()).await.unwrap();
+        backend.put(k.clone(), b"new".to_vec()).await.unwrap();
+        backend.put(other.clone(), b"invalid".to_vec()).await.unwrap();
+        // a value of another key length does not belong here
+        backend.put(Key::random(4), b"short".to_vec()).await.unwrap();
+
+        let predicate = Arc::new(|v: &[u8]| v != b"invalid");
+        let mut store = Store::new(TOKEN_KEY_LEN, predicate).with_backend(backend);
+        assert_eq!(store.restore().await.unwrap(), 1);
+        assert_eq!(store.get(&k), Some(&b"new".to_vec()));
+        assert_eq!(store.get(&other), None);
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,74 @@
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+// Outgoing bytes per second, shared by the sockets of a node. A message over
+// the cap waits for its turn rather than being dropped, so a capped node is
+// only slower; messages queue up by priority meanwhile. Up to one second of
+// traffic may go out at once after a quiet spell.
+#[derive(Debug, Default)]
+pub struct BandwidthLimit {
+    state: Mutex<Bucket>,
+}
+
+#[derive(Debug, Default)]
+struct Bucket {
+    // bytes per second, None for no cap
+    rate: Option<u64>,
+    // bytes that may go out now; negative when sends wait for earlier ones
+    allowance: f64,
+    last: Option<Instant>,
+}
+
+impl BandwidthLimit {
+    pub fn new() -> BandwidthLimit {
+        BandwidthLimit::default()
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.state.lock().unwrap().rate
+    }
+
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut bucket = self.state.lock().unwrap();
+        bucket.rate = rate.filter(|rate| *rate > 0);
+        bucket.allowance = bucket.rate.unwrap_or(0) as f64;
+        bucket.last = None;
+    }
+
+    // Takes bytes from the allowance; how long to wait before sending them
+    pub fn reserve(&self, now: Instant, bytes: usize) -> Option<Duration> {
+        let mut bucket = self.state.lock().unwrap();
+        let rate = bucket.rate? as f64;
+        if let Some(last) = bucket.last {
+            let refill = now.saturating_duration_since(last).as_secs_f64() * rate;
+            bucket.allowance = (bucket.allowance + refill).min(rate);
+        }
+        bucket.last = Some(now);
+        bucket.allowance -= bytes as f64;
+        (bucket.allowance < 0.0).then(|| Duration::from_secs_f64(-bucket.allowance / rate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bandwidth_limit_test() {
+        let limit = BandwidthLimit::new();
+        let start = Instant::now();
+        assert_eq!(limit.reserve(start, 1 << 20), None);
+
+        limit.set_rate(Some(1000));
+        // a second's worth goes at once, then sends wait for each other
+        assert_eq!(limit.reserve(start, 1000), None);
+        assert_eq!(limit.reserve(start, 500), Some(Duration::from_millis(500)));
+        assert_eq!(limit.reserve(start, 500), Some(Duration::from_millis(1000)));
+        let later = start + Duration::from_millis(1500);
+        assert_eq!(limit.reserve(later, 750), Some(Duration::from_millis(250)));
+        // quiet spells do not save up more than a second
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!(limit.reserve(much_later, 1000), None);
+        assert!(limit.reserve(much_later, 1).is_some());
+    }
+}
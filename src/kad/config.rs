@@ -0,0 +1,46 @@
+use tokio::time::Duration;
+
+use super::{
+    ALPHA_PARAM, K_PARAM, MAX_CONCURRENT_LOOKUPS, MAX_CONCURRENT_REQUESTS, MAX_OUTBOUND_REQUESTS,
+    MESSAGE_LEN, TIME_OUT,
+};
+
+// Kademlia parameters of a deployment. Every node of a network should agree
+// on message_len, since longer messages are dropped as malformed; k, alpha
+// and the timeout only change how this node behaves, as do the limits below,
+// which are per Node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KadConfig {
+    // bucket size, and how many nodes lookups and FindNode replies return
+    pub k: usize,
+    // lookups ask this many nodes at once
+    pub alpha: usize,
+    // until a request without a reply times out
+    pub timeout: Duration,
+    // bytes of the longest datagram sent or accepted
+    pub message_len: usize,
+    // requests handled at once; more coming in are dropped unanswered
+    pub max_concurrent_requests: usize,
+    // requests sent at once and waiting for a reply; more wait their turn
+    pub max_outbound_requests: usize,
+    // lookups walking at once; more wait their turn
+    pub max_concurrent_lookups: usize,
+    // sign a DeliveryReceipt for the multicasts accepted in a region of
+    // this node, when the sender asks for one
+    pub delivery_receipts: bool,
+}
+
+impl Default for KadConfig {
+    fn default() -> KadConfig {
+        KadConfig {
+            k: K_PARAM,
+            alpha: ALPHA_PARAM,
+            timeout: Duration::from_millis(TIME_OUT),
+            message_len: MESSAGE_LEN,
+            max_concurrent_requests: MAX_CONCURRENT_REQUESTS,
+            max_outbound_requests: MAX_OUTBOUND_REQUESTS,
+            max_concurrent_lookups: MAX_CONCURRENT_LOOKUPS,
+            delivery_receipts: false,
+        }
+    }
+}
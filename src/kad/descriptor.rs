@@ -0,0 +1,157 @@
+use log::warn;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::store::{Store, StorePredicate};
+use super::{Key, NodeInfo};
+use crate::util::rng::Entropy;
+
+// How the nodes of a network pick their ids
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdScheme {
+    Random,
+    // a group key followed by random bytes, so that the node is found by
+    // multicasts to the group
+    Prefixed,
+}
+
+// What the nodes of one net_id have to agree on: the length of ids and keys,
+// how ids are made, and which values are stored.
+#[derive(Clone)]
+pub struct NetworkDescriptor {
+    pub net_id: String,
+    pub key_length: usize,
+    pub id_scheme: IdScheme,
+    validator: StorePredicate,
+}
+
+impl NetworkDescriptor {
+    // stores nothing until a validator is set
+    pub fn new(net_id: &str, key_length: usize, id_scheme: IdScheme) -> NetworkDescriptor {
+        NetworkDescriptor {
+            net_id: net_id.to_string(),
+            key_length,
+            id_scheme,
+            validator: Arc::new(|_| false),
+        }
+    }
+
+    pub fn with_validator(mut self, validator: StorePredicate) -> NetworkDescriptor {
+        self.validator = validator;
+        self
+    }
+
+    pub fn validator(&self) -> StorePredicate {
+        self.validator.clone()
+    }
+
+    pub fn store(&self) -> Store {
+        Store::new(self.key_length, self.validator.clone())
+    }
+
+    // a new node id; the prefix is only used by IdScheme::Prefixed
+    pub fn node_id(&self, prefix: Option<&Key>, entropy: &dyn Entropy) -> Key {
+        match (self.id_scheme, prefix) {
+            (IdScheme::Prefixed, Some(prefix)) => {
+                let mut id = prefix.clone();
+                id.resize_with_random(self.key_length, entropy);
+                id
+            }
+            _ => Key::random_from(self.key_length, entropy),
+        }
+    }
+
+    // the key of data in this network: data itself if it has the length, its hash otherwise
+    pub fn key(&self, data: &[u8]) -> Key {
+        if data.len() == self.key_length {
+            Key::from(data)
+        } else {
+            Key::hash(data, self.key_length)
+        }
+    }
+
+    pub fn accepts(&self, node_info: &NodeInfo) -> bool {
+        node_info.net_id == self.net_id && node_info.id.len() == self.key_length
+    }
+}
+
+// The networks a node knows, by net_id
+#[derive(Clone, Default)]
+pub struct NetworkRegistry {
+    networks: HashMap<String, NetworkDescriptor>,
+}
+
+impl NetworkRegistry {
+    pub fn new() -> NetworkRegistry {
+        NetworkRegistry::default()
+    }
+
+    // replaces the descriptor registered for the same net_id
+    pub fn with(mut self, descriptor: NetworkDescriptor) -> NetworkRegistry {
+        self.register(descriptor);
+        self
+    }
+
+    pub fn register(&mut self, descriptor: NetworkDescriptor) -> Option<NetworkDescriptor> {
+        self.networks.insert(descriptor.net_id.clone(), descriptor)
+    }
+
+    pub fn get(&self, net_id: &str) -> Option<&NetworkDescriptor> {
+        self.networks.get(net_id)
+    }
+
+    // the node infos of net_id with ids that fit it
+    pub fn bootstrap(&self, net_id: &str, node_infos: &[NodeInfo]) -> Vec<NodeInfo> {
+        let descriptor = match self.get(net_id) {
+            Some(descriptor) => descriptor,
+            None => return Vec::new(),
+        };
+        let (ret, skipped): (Vec<_>, Vec<_>) = node_infos
+            .iter()
+            .filter(|ni| ni.net_id == net_id)
+            .cloned()
+            .partition(|ni| descriptor.accepts(ni));
+        if !skipped.is_empty() {
+            warn!("{} nodes of {} have ids of another length", skipped.len(), net_id);
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::rng::SeededEntropy;
+
+    #[test]
+    fn network_registry_test() {
+        let short = NetworkDescriptor::new("short", 16, IdScheme::Random);
+        let registry = NetworkRegistry::new()
+            .with(NetworkDescriptor::new("groups", 64, IdScheme::Prefixed))
+            .with(short.clone());
+
+        let node_info = |net_id: &str, len: usize| NodeInfo {
+            id: Key::random(len),
+            addr: "127.0.0.1:1".parse().unwrap(),
+            net_id: net_id.to_string(),
+        };
+        let found = registry.bootstrap(
+            "short",
+            &[node_info("short", 16), node_info("short", 32), node_info("groups", 16)],
+        );
+        assert_eq!(found.len(), 1);
+        assert!(registry.bootstrap("unknown", &[node_info("unknown", 16)]).is_empty());
+
+        let prefix = Key::from(&[7u8; 32][..]);
+        let groups = registry.get("groups").unwrap();
+        let id = groups.node_id(Some(&prefix), &SeededEntropy::new(1));
+        assert_eq!(id.len(), 64);
+        assert!(prefix.is_prefix(&id));
+        assert_eq!(groups.node_id(Some(&prefix), &SeededEntropy::new(1)), id);
+        assert_eq!(short.node_id(Some(&prefix), &SeededEntropy::new(1)).len(), 16);
+
+        assert_eq!(short.key(&[1; 16]), Key::from(&[1u8; 16][..]));
+        assert_eq!(short.key(b"longer than the key").len(), 16);
+        assert!(!short.store().predicate()(b"value"));
+    }
+}
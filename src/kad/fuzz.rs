@@ -0,0 +1,134 @@
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::sync::Mutex;
+use tokio::time::Duration;
+
+use super::config::KadConfig;
+use super::descriptor::{IdScheme, NetworkDescriptor};
+use super::key::Key;
+use super::node::Node;
+use super::routing::NodeInfo;
+use super::rpc::{RetryPolicy, Rpc};
+use super::TOKEN_KEY_LEN;
+use crate::service::TESTNET_USER_DHT;
+
+// Entry points for the cargo-fuzz targets in fuzz/, which feed what a peer
+// may send into the wire format and into a node
+pub use super::node::{FindValueResult, Reply, Request};
+pub use super::rpc::{decode, DecodeError};
+
+// small enough that the fuzzer fills the store up
+const HARNESS_STORE_LIMIT: usize = 64 * 1024;
+
+// A node on loopback, without peers, whose requests to the sources it is
+// given go to a socket that never answers
+pub struct NodeHarness {
+    node: Node,
+    sink: UdpSocket,
+    multicasts: UnboundedReceiver<Vec<u8>>,
+}
+
+impl NodeHarness {
+    pub async fn start() -> NodeHarness {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut rpc = Rpc::new(socket);
+        rpc.set_kad_config(KadConfig {
+            timeout: Duration::from_millis(20),
+            delivery_receipts: true,
+            ..KadConfig::default()
+        });
+        rpc.set_retry_policy(RetryPolicy {
+            attempts: 1,
+            ..RetryPolicy::default()
+        });
+        rpc.set_store_limit(Some(HARNESS_STORE_LIMIT));
+        let network = NetworkDescriptor::new(TESTNET_USER_DHT, TOKEN_KEY_LEN, IdScheme::Random)
+            .with_validator(Arc::new(|_| true));
+        let (tx, multicasts) = mpsc::unbounded_channel();
+        let rpc = Arc::new(Mutex::new(rpc));
+        let node = Node::start(&network, Key::random(TOKEN_KEY_LEN), rpc, tx, &[]).await;
+        NodeHarness {
+            node,
+            sink: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            multicasts,
+        }
+    }
+
+    pub fn node(&self) -> &Node {
+        &self.node
+    }
+
+    // Handles req as if it came from src_id, of any length, and panics when
+    // the node keeps more than its limits allow afterwards
+    pub async fn handle(&mut self, req: Request, src_id: Key) -> Reply {
+        let src = NodeInfo {
+            id: src_id,
+            addr: self.sink.local_addr().unwrap(),
+            net_id: self.node.node_info().net_id.clone(),
+        };
+        let rep = self.node.handle_req(req, src).await;
+        self.node.assert_bounded().await;
+        while self.multicasts.try_recv().is_ok() {}
+        rep
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::prelude::*;
+    use rand_chacha::ChaCha20Rng;
+
+    fn key(rng: &mut ChaCha20Rng) -> Key {
+        // mostly the right length, so that requests get past the checks
+        let len = if rng.gen_bool(0.8) {
+            TOKEN_KEY_LEN
+        } else {
+            rng.gen_range(0..TOKEN_KEY_LEN * 2)
+        };
+        let mut data = vec![0; len];
+        rng.fill_bytes(&mut data);
+        Key::from(&data[..])
+    }
+
+    #[tokio::test]
+    async fn handle_req_fuzz_test() {
+        let mut rng = ChaCha20Rng::seed_from_u64(0);
+        let mut harness = NodeHarness::start().await;
+        let own = harness.node().node_info().id.clone();
+        let mut sources: Vec<Key> = Vec::new();
+
+        for _ in 0..2000 {
+            let src = match sources.choose(&mut rng) {
+                Some(src) if rng.gen_bool(0.5) => src.clone(),
+                _ => key(&mut rng),
+            };
+            sources.push(src.clone());
+            let mut value = vec![0; rng.gen_range(0..4096)];
+            rng.fill_bytes(&mut value);
+            // prefixes of the node's id reach the multicast paths, and the
+            // ones of the source its joins
+            let cut = |id: &Key, rng: &mut ChaCha20Rng| {
+                let mut prefix = id.clone();
+                prefix.resize(rng.gen_range(0..=id.len()));
+                prefix
+            };
+            let prefix = cut(&own, &mut rng);
+            let req = match rng.gen_range(0..10) {
+                0 => Request::Ping,
+                1 => Request::Store(key(&mut rng), value),
+                2 => Request::FindNode(key(&mut rng)),
+                3 => Request::FindValue(key(&mut rng)),
+                4 => Request::Unicast(value),
+                5 => Request::Broadcast(value),
+                6 => Request::Multicast(prefix, value),
+                // signing a receipt is slow in debug builds, so mostly none are
+                7 => Request::ReceiptedMulticast(key(&mut rng), value),
+                8 => Request::Join(cut(&src, &mut rng)),
+                _ => Request::Leave(prefix),
+            };
+            harness.handle(req, src).await;
+        }
+    }
+}
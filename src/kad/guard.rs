@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use tokio::time::{Duration, Instant};
+
+use super::{BAN_TIME, GUARD_LIMIT, MALFORMED_BAN_LIMIT, MALFORMED_BAN_TIME, MALFORMED_LIMIT};
+
+#[derive(Debug)]
+struct PeerRecord {
+    malformed: u32,
+    banned_until: Option<Instant>,
+    // banned by an operator rather than for malformed messages
+    banned_by_hand: bool,
+    last_seen: Instant,
+}
+
+impl PeerRecord {
+    fn new(now: Instant) -> PeerRecord {
+        PeerRecord {
+            malformed: 0,
+            banned_until: None,
+            banned_by_hand: false,
+            last_seen: now,
+        }
+    }
+}
+
+// Counts malformed messages per peer IP and bans a peer for a while once it
+// sends too many. Keyed by IP, since changing the source port is free.
+// Since the IP of a UDP datagram can be forged, such bans are short, few at a
+// time, and the map of IPs is bounded.
+#[derive(Debug)]
+pub struct PeerGuard {
+    peers: HashMap<IpAddr, PeerRecord>,
+    limit: usize,
+}
+
+impl Default for PeerGuard {
+    fn default() -> PeerGuard {
+        PeerGuard::with_limit(GUARD_LIMIT)
+    }
+}
+
+impl PeerGuard {
+    pub fn new() -> PeerGuard {
+        PeerGuard::default()
+    }
+
+    pub fn with_limit(limit: usize) -> PeerGuard {
+        PeerGuard {
+            peers: HashMap::new(),
+            limit,
+        }
+    }
+
+    pub fn is_banned(&mut self, ip: IpAddr, now: Instant) -> bool {
+        match self.peers.get(&ip).and_then(|record| record.banned_until) {
+            Some(until) if until > now => true,
+            Some(_) => {
+                // ban is over, start counting from scratch
+                self.peers.remove(&ip);
+                false
+            }
+            None => false,
+        }
+    }
+
+    // Returns true when this message gets the peer banned.
+    pub fn record_malformed(&mut self, ip: IpAddr, now: Instant) -> bool {
+        let record = self.record(ip, now);
+        record.malformed += 1;
+        if record.malformed < MALFORMED_LIMIT || record.banned_until.is_some() {
+            return false;
+        }
+        if self.malformed_bans(now) >= MALFORMED_BAN_LIMIT {
+            return false;
+        }
+        let until = now + Duration::from_millis(MALFORMED_BAN_TIME);
+        self.record(ip, now).banned_until = Some(until);
+        true
+    }
+
+    // for BAN_TIME from now, however few malformed messages ip sent
+    pub fn ban(&mut self, ip: IpAddr, now: Instant) {
+        let record = self.record(ip, now);
+        record.banned_until = Some(now + Duration::from_millis(BAN_TIME));
+        record.banned_by_hand = true;
+    }
+
+    fn malformed_bans(&self, now: Instant) -> usize {
+        self.peers
+            .values()
+            .filter(|record| !record.banned_by_hand)
+            .filter(|record| matches!(record.banned_until, Some(until) if until > now))
+            .count()
+    }
+
+    // the record of ip, making room for it by dropping the least recently
+    // seen peer, preferring one no operator banned
+    fn record(&mut self, ip: IpAddr, now: Instant) -> &mut PeerRecord {
+        if !self.peers.contains_key(&ip) && self.peers.len() >= self.limit {
+            let oldest = self
+                .peers
+                .iter()
+                .min_by_key(|(_, record)| (record.banned_by_hand, record.last_seen))
+                .map(|(ip, _)| *ip);
+            if let Some(oldest) = oldest {
+                self.peers.remove(&oldest);
+            }
+        }
+        let record = self.peers.entry(ip).or_insert_with(|| PeerRecord::new(now));
+        record.last_seen = now;
+        record
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ban_test() {
+        let mut guard = PeerGuard::new();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        let now = Instant::now();
+
+        for _ in 1..MALFORMED_LIMIT {
+            assert!(!guard.record_malformed(ip, now));
+        }
+        assert!(!guard.is_banned(ip, now));
+        assert!(guard.record_malformed(ip, now));
+        assert!(guard.is_banned(ip, now));
+        assert!(!guard.is_banned(other, now));
+
+        let later = now + Duration::from_millis(MALFORMED_BAN_TIME);
+        assert!(!guard.is_banned(ip, later));
+        assert!(!guard.record_malformed(ip, later));
+
+        guard.ban(other, now);
+        assert!(guard.is_banned(other, later));
+        assert!(!guard.is_banned(other, now + Duration::from_millis(BAN_TIME)));
+    }
+
+    #[test]
+    fn limit_test() {
+        let mut guard = PeerGuard::with_limit(4);
+        let ip = |i: u8| IpAddr::from([10, 0, 0, i]);
+        let now = Instant::now();
+
+        guard.ban(ip(0), now);
+        for i in 1..=8 {
+            guard.record_malformed(ip(i), now + Duration::from_millis(i as u64));
+        }
+        assert_eq!(guard.peers.len(), 4);
+        // the operator's ban outlives the peers seen since
+        assert!(guard.is_banned(ip(0), now));
+        assert!(!guard.peers.contains_key(&ip(1)));
+        assert!(guard.peers.contains_key(&ip(8)));
+    }
+
+    #[test]
+    fn ban_limit_test() {
+        let mut guard = PeerGuard::with_limit(MALFORMED_BAN_LIMIT * 2);
+        let ip = |i: usize| IpAddr::from([10, 0, (i >> 8) as u8, i as u8]);
+        let now = Instant::now();
+
+        for i in 0..=MALFORMED_BAN_LIMIT {
+            for _ in 0..MALFORMED_LIMIT {
+                guard.record_malformed(ip(i), now);
+            }
+        }
+        assert!(guard.is_banned(ip(MALFORMED_BAN_LIMIT - 1), now));
+        assert!(!guard.is_banned(ip(MALFORMED_BAN_LIMIT), now));
+    }
+}
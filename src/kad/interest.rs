@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use tokio::time::{Duration, Instant};
+
+use super::key::Key;
+use super::routing::NodeInfo;
+
+// Nodes which said with Join that they take part in a prefix region, so that
+// multicasts to it reach them before routing tables have caught up. A Join
+// lapses after the ttl unless it is sent again, and Leave ends it at once.
+pub struct Interests {
+    prefixes: HashMap<Key, Vec<(NodeInfo, Instant)>>,
+    per_prefix: usize,
+    max_prefixes: usize,
+    ttl: Duration,
+}
+
+impl Interests {
+    pub fn new(per_prefix: usize, max_prefixes: usize, ttl: Duration) -> Interests {
+        Interests {
+            prefixes: HashMap::new(),
+            per_prefix,
+            max_prefixes,
+            ttl,
+        }
+    }
+
+    // false if there was no room for it
+    pub fn join(&mut self, prefix: Key, node_info: NodeInfo, now: Instant) -> bool {
+        if !self.prefixes.contains_key(&prefix) && self.prefixes.len() >= self.max_prefixes {
+            self.expire(now);
+            if self.prefixes.len() >= self.max_prefixes {
+                return false;
+            }
+        }
+        let ttl = self.ttl;
+        let members = self.prefixes.entry(prefix).or_default();
+        members.retain(|(ni, at)| ni.id != node_info.id && now.duration_since(*at) < ttl);
+        if members.len() >= self.per_prefix {
+            return false;
+        }
+        members.push((node_info, now));
+        true
+    }
+
+    pub fn leave(&mut self, prefix: &Key, id: &Key) {
+        if let Some(members) = self.prefixes.get_mut(prefix) {
+            members.retain(|(ni, _)| ni.id != *id);
+            if members.is_empty() {
+                self.prefixes.remove(prefix);
+            }
+        }
+    }
+
+    // the nodes whose Join for prefix has not lapsed
+    pub fn members(&self, prefix: &Key, now: Instant) -> Vec<NodeInfo> {
+        match self.prefixes.get(prefix) {
+            Some(members) => members
+                .iter()
+                .filter(|(_, at)| now.duration_since(*at) < self.ttl)
+                .map(|(ni, _)| ni.clone())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // joins kept, lapsed ones included until they are expired
+    #[cfg(any(test, feature = "fuzzing"))]
+    pub fn len(&self) -> usize {
+        self.prefixes.values().map(Vec::len).sum()
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let ttl = self.ttl;
+        self.prefixes.retain(|_, members| {
+            members.retain(|(_, at)| now.duration_since(*at) < ttl);
+            !members.is_empty()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(prefix: &Key, port: u16) -> NodeInfo {
+        let mut id = prefix.clone();
+        id.resize(64);
+        NodeInfo {
+            id: Key::hash(&[id.as_bytes(), &port.to_be_bytes()[..]].concat(), 64),
+            addr: format!("127.0.0.1:{}", port).parse().unwrap(),
+            net_id: "test".to_string(),
+        }
+    }
+
+    #[test]
+    fn interests_test() {
+        let start = Instant::now();
+        let ttl = Duration::from_secs(60);
+        let mut interests = Interests::new(2, 1, ttl);
+        let (prefix, other) = (Key::random(32), Key::random(32));
+        let (a, b, c) = (node(&prefix, 1), node(&prefix, 2), node(&prefix, 3));
+
+        assert!(interests.join(prefix.clone(), a.clone(), start));
+        // joining again renews, it does not take another place
+        assert!(interests.join(prefix.clone(), a.clone(), start));
+        assert!(interests.join(prefix.clone(), b.clone(), start));
+        assert!(!interests.join(prefix.clone(), c.clone(), start));
+        assert!(!interests.join(other.clone(), node(&other, 4), start));
+        assert_eq!(interests.members(&prefix, start).len(), 2);
+
+        interests.leave(&prefix, &a.id);
+        assert_eq!(interests.members(&prefix, start), vec![b]);
+        // lapsed, which makes room for another prefix
+        assert!(interests.members(&prefix, start + ttl).is_empty());
+        assert!(interests.join(other.clone(), node(&other, 4), start + ttl));
+    }
+}
@@ -1,19 +1,22 @@
-use rand::prelude::*;
-use rand_chacha::ChaCha20Rng;
 use serde::{Deserialize, Serialize};
 use sha3::{Digest, Sha3_512};
 use std::convert::TryFrom;
 use std::fmt::{Debug, Error, Formatter};
 use std::ops::BitXor;
 
+use crate::util::rng::{Entropy, SystemEntropy};
+
 #[derive(Hash, Ord, PartialOrd, Eq, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Key(Vec<u8>);
 
 impl Key {
     pub fn random(len: usize) -> Key {
+        Key::random_from(len, &SystemEntropy)
+    }
+
+    pub fn random_from(len: usize, entropy: &dyn Entropy) -> Key {
         let mut data = vec![0; len];
-        let mut rng = ChaCha20Rng::from_entropy();
-        rng.fill_bytes(&mut data);
+        entropy.fill_bytes(&mut data);
         Key(data)
     }
 
@@ -31,11 +34,10 @@ impl Key {
         self.0.resize(new_len, 0);
     }
 
-    pub fn resize_with_random(&mut self, new_len: usize) {
+    pub fn resize_with_random(&mut self, new_len: usize, entropy: &dyn Entropy) {
         let len = self.0.len();
         self.0.resize(new_len, 0);
-        let mut rng = ChaCha20Rng::from_entropy();
-        rng.fill_bytes(&mut self.0[len..new_len]);
+        entropy.fill_bytes(&mut self.0[len..new_len]);
     }
 
     pub fn zeroes_in_prefix(&self) -> usize {
@@ -69,6 +71,10 @@ impl Key {
     pub fn len(&self) -> usize {
         self.0.len()
     }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
 }
 
 impl BitXor for Key {
@@ -3,14 +3,74 @@ mod rpc;
 mod routing;
 mod key;
 mod store;
+mod guard;
+mod descriptor;
+mod priority;
+mod peer_store;
+mod bandwidth;
+mod backend;
+mod pending;
+mod config;
+mod interest;
+mod receipt;
+mod seen;
+#[cfg(any(test, feature = "fuzzing"))]
+pub mod fuzz;
 
 pub use node::Node;
+pub use receipt::DeliveryReceipt;
 pub use key::Key;
 pub use routing::NodeInfo;
-pub use rpc::Rpc;
+pub use rpc::{RetryPolicy, Rpc, RpcMessage};
+pub use config::KadConfig;
+pub use store::{Store, StoreConflict, StoreRefusal};
+pub use descriptor::{IdScheme, NetworkDescriptor, NetworkRegistry};
+pub use priority::Priority;
+pub use peer_store::{PeerRecord, PeerStore, PeerStoreFile};
+pub use bandwidth::BandwidthLimit;
+pub use backend::{FileBackend, StoreBackend, StoredValues};
 
 pub const TOKEN_KEY_LEN: usize = 20;
 pub const K_PARAM: usize = 8;
+// lookups ask this many nodes at once
+pub const ALPHA_PARAM: usize = 3;
 pub const MESSAGE_LEN: usize = 8196;
 pub const TIME_OUT: u64 = 5000;
-pub const BROADCAST_TIME_OUT: u64 = 3000000; // 5 minutes
\ No newline at end of file
+// per Node, so that a flood of requests cannot spawn tasks without end; see KadConfig
+pub const MAX_CONCURRENT_REQUESTS: usize = 256;
+pub const MAX_OUTBOUND_REQUESTS: usize = 128;
+pub const MAX_CONCURRENT_LOOKUPS: usize = 16;
+// requests waiting for a reply per socket; the oldest are dropped beyond it
+pub const PENDING_LIMIT: usize = 65536;
+// in TIME_OUTs, how long a pending request may be left before it is taken as leaked
+pub const PENDING_EXPIRY: u32 = 4;
+pub const BROADCAST_TIME_OUT: u64 = 3000000; // 5 minutes
+// multicast hashes kept per Node; the oldest are dropped beyond it
+pub const SEEN_TOKENS_LIMIT: usize = 65536;
+// messages waiting to be sent or dispatched per priority; newer ones are dropped beyond it
+pub const QUEUE_LIMIT: usize = 4096;
+// turns of Control, Lookup and Bulk messages in each round of a busy queue
+pub const PRIORITY_WEIGHTS: [u32; 3] = [8, 4, 1];
+pub const MALFORMED_LIMIT: u32 = 16;
+// source addresses can be spoofed, so a ban for malformed messages is short
+pub const MALFORMED_BAN_TIME: u64 = 60000; // 1 minute
+pub const BAN_TIME: u64 = 600000; // 10 minutes
+// peer IPs counted by the guard; the least recently seen go first
+pub const GUARD_LIMIT: usize = 4096;
+// peers banned for malformed messages at once; no more are banned beyond it
+pub const MALFORMED_BAN_LIMIT: usize = 256;
+pub const RETRY_ATTEMPTS: u32 = 2;
+pub const RETRY_BACKOFF: u64 = 250;
+// failed requests in a row before a peer leaves the routing table
+pub const DEMOTE_AFTER: u32 = 2;
+// peers kept between runs; the least recently seen go first
+pub const PEER_STORE_LIMIT: usize = 1000;
+// a Join is remembered this long, and sent again every JOIN_INTERVAL
+pub const JOIN_TTL: u64 = 600000; // 10 minutes
+pub const JOIN_INTERVAL: u64 = 240000; // 4 minutes
+// prefixes a node keeps the joined nodes of; K_PARAM nodes for each
+pub const JOIN_PREFIX_LIMIT: usize = 1024;
+
+// Version 0 is the format without a version field. Peers still on it are
+// answered in it; messages from newer versions that cannot be read are dropped.
+pub const PROTOCOL_VERSION: u32 = 1;
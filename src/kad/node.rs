@@ -1,19 +1,33 @@
-use log::info;
+use chrono::Utc;
+use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
-use std::collections::{BinaryHeap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
 use std::sync::Arc;
+use std::sync::Mutex as StdMutex;
 use tokio::sync::mpsc::UnboundedReceiver;
 use tokio::sync::mpsc::{self, UnboundedSender};
-use tokio::sync::Mutex;
-use tokio::time::{sleep, Duration};
+use tokio::sync::{Mutex, Semaphore};
+use tokio::time::Duration;
 
+use crate::crypto::SecretKey;
 use crate::kad::TOKEN_KEY_LEN;
+use crate::util::clock::Clock;
+use crate::util::crypto_pool;
 
 use super::key::Key;
+use super::peer_store::PeerStore;
 use super::routing::{NodeInfo, RoutingTable};
 use super::rpc::{ReqHandle, Rpc};
-use super::store::Store;
-use super::{BROADCAST_TIME_OUT, K_PARAM};
+use super::descriptor::NetworkDescriptor;
+use super::store::{Store, StoreRefusal};
+use super::config::KadConfig;
+use super::interest::Interests;
+use super::receipt::DeliveryReceipt;
+use super::seen::SeenTokens;
+use super::{
+    BROADCAST_TIME_OUT, DEMOTE_AFTER, JOIN_PREFIX_LIMIT, JOIN_TTL, SEEN_TOKENS_LIMIT,
+};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Request {
@@ -22,8 +36,15 @@ pub enum Request {
     FindNode(Key),
     FindValue(Key),
     Unicast(Vec<u8>),
+    // Deprecated: flooding the whole overlay is replaced by topic multicast.
+    // Kept so that requests from older peers still deserialize; they are dropped.
     Broadcast(Vec<u8>),
     Multicast(Key, Vec<u8>),
+    // a Multicast answered with Reply::Receipt
+    ReceiptedMulticast(Key, Vec<u8>),
+    // the sender takes part in the prefix region, and wants its multicasts
+    Join(Key),
+    Leave(Key),
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -37,6 +58,18 @@ pub enum Reply {
     Ping,
     FindNode(Vec<(NodeInfo, Key)>),
     FindValue(FindValueResult),
+    // None unless the node accepted the multicast and signs receipts
+    Receipt(Option<DeliveryReceipt>),
+}
+
+// How a request to a peer ended, for deciding whether to keep it in the routing table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Answered,
+    // every attempt timed out; may just be lost packets
+    TimedOut,
+    // the peer is up, but answered with the wrong kind of reply
+    Unexpected,
 }
 
 #[derive(Clone)]
@@ -44,39 +77,79 @@ pub struct Node {
     key_length: usize,
     routes: Arc<Mutex<RoutingTable>>,
     store: Arc<Mutex<Store>>,
-    broadcast_tokens: Arc<Mutex<HashSet<Key>>>,
+    broadcast_tokens: Arc<Mutex<SeenTokens>>,
+    interests: Arc<Mutex<Interests>>,
+    // failed requests in a row, per peer id
+    failures: Arc<Mutex<HashMap<Key, u32>>>,
     rpc: Arc<Mutex<Rpc>>,
     tx: UnboundedSender<Vec<u8>>,
     node_info: NodeInfo,
+    clock: Arc<dyn Clock>,
+    peer_store: Arc<StdMutex<PeerStore>>,
+    kad: KadConfig,
+    // permits for the requests being handled, the requests sent and the
+    // lookups walking, as many as KadConfig allows
+    handling: Arc<Semaphore>,
+    outbound: Arc<Semaphore>,
+    lookups: Arc<Semaphore>,
+    // signs delivery receipts, when KadConfig asks for them; Rpc::receipt_key
+    receipt_key: Option<Arc<SecretKey>>,
 }
 
 impl Node {
     pub async fn start(
-        net_id: String,
-        key_length: usize,
+        network: &NetworkDescriptor,
+        node_id: Key,
+        rpc: Arc<Mutex<Rpc>>,
+        multicast_tx: UnboundedSender<Vec<u8>>,
+        bootstrap: &[NodeInfo],
+    ) -> Node {
+        let store = network.store();
+        Node::start_with_store(network, node_id, store, rpc, multicast_tx, bootstrap).await
+    }
+
+    // for stores with a conflict rule, which has to be in place before the
+    // first request comes in
+    pub async fn start_with_store(
+        network: &NetworkDescriptor,
         node_id: Key,
-        store_requirement: Arc<dyn Fn(&[u8]) -> bool + Sync + Send>,
+        mut store: Store,
         rpc: Arc<Mutex<Rpc>>,
         multicast_tx: UnboundedSender<Vec<u8>>,
         bootstrap: &[NodeInfo],
     ) -> Node {
+        let key_length = network.key_length;
         assert_eq!(key_length, node_id.len());
         let (tx, rx) = mpsc::unbounded_channel();
         let mut rpc_raw = rpc.lock().await;
         let socket = rpc_raw.socket.clone();
+        let clock = rpc_raw.clock();
+        let peer_store = rpc_raw.peer_store();
+        let kad = rpc_raw.kad_config();
+        let receipt_key = kad.delivery_receipts.then(|| {
+            rpc_raw
+                .receipt_key()
+                .unwrap_or_else(|| Arc::new(SecretKey::random_from(rpc_raw.entropy().as_ref())))
+        });
+        store.set_limit(rpc_raw.store_limit());
+        match store.restore().await {
+            Ok(0) => (),
+            Ok(n) => info!("{} stored values restored", n),
+            Err(e) => warn!("Stored values are not restored: {}", e),
+        }
 
         let node_info = NodeInfo {
             id: node_id.clone(),
             addr: socket.local_addr().unwrap(),
-            net_id: net_id,
+            net_id: network.net_id.clone(),
         };
 
         rpc_raw.add(node_info.clone(), tx.clone()).await;
         rpc_raw.start_server().await;
         drop(rpc_raw);
 
-        let mut routes = RoutingTable::new(&node_info.clone(), key_length);
-        for ni in bootstrap.iter() {
+        let mut routes = RoutingTable::new(&node_info.clone(), key_length, kad.k);
+        for ni in bootstrap.iter().filter(|ni| network.accepts(ni)) {
             routes.update(ni.clone());
         }
 
@@ -88,11 +161,27 @@ impl Node {
         let node = Node {
             key_length,
             routes: Arc::new(Mutex::new(routes)),
-            store: Arc::new(Mutex::new(Store::new(key_length, store_requirement))),
-            broadcast_tokens: Arc::new(Mutex::new(HashSet::new())),
+            store: Arc::new(Mutex::new(store)),
+            broadcast_tokens: Arc::new(Mutex::new(SeenTokens::new(
+                SEEN_TOKENS_LIMIT,
+                Duration::from_millis(BROADCAST_TIME_OUT),
+            ))),
+            interests: Arc::new(Mutex::new(Interests::new(
+                kad.k,
+                JOIN_PREFIX_LIMIT,
+                Duration::from_millis(JOIN_TTL),
+            ))),
+            failures: Arc::new(Mutex::new(HashMap::new())),
             rpc: rpc.clone(),
             tx: multicast_tx,
             node_info,
+            clock,
+            peer_store,
+            kad,
+            handling: Arc::new(Semaphore::new(kad.max_concurrent_requests.max(1))),
+            outbound: Arc::new(Semaphore::new(kad.max_outbound_requests.max(1))),
+            lookups: Arc::new(Semaphore::new(kad.max_concurrent_lookups.max(1))),
+            receipt_key,
         };
 
         node.clone().start_req_handler(rx).await;
@@ -102,14 +191,46 @@ impl Node {
         node
     }
 
+    pub fn node_info(&self) -> &NodeInfo {
+        &self.node_info
+    }
+
+    // adds peers of this network found after starting, like a new bootstrap
+    // node, and looks up the neighbourhood through them
+    pub async fn add_peers(&self, node_infos: &[NodeInfo]) {
+        let mut added = false;
+        {
+            let mut routes = self.routes.lock().await;
+            for ni in node_infos {
+                if ni.net_id == self.node_info.net_id && ni.id.len() == self.key_length {
+                    routes.update(ni.clone());
+                    added = true;
+                }
+            }
+        }
+        if added {
+            self.lookup_nodes(self.node_info.id.clone()).await;
+        }
+    }
+
     pub async fn start_req_handler(self, mut rx: UnboundedReceiver<ReqHandle>) {
         tokio::spawn(async move {
             while let Some(req_handle) = rx.recv().await {
+                // beyond the limit requests are dropped, as if the datagrams
+                // were lost, and their senders time out
+                let permit = match self.handling.clone().try_acquire_owned() {
+                    Ok(permit) => permit,
+                    Err(_) => {
+                        debug!("Too many requests at once, dropping one");
+                        continue;
+                    }
+                };
                 let node = self.clone();
                 tokio::spawn(async move {
                     let rep =
                         node.handle_req(req_handle.get_req().clone(), req_handle.get_src().clone());
                     req_handle.rep(rep.await, node.node_info.clone()).await;
+                    drop(permit);
                 });
             }
             info!("Channnel closed, since sender is dead.");
@@ -117,23 +238,37 @@ impl Node {
     }
 
     pub async fn handle_req(&self, req: Request, src: NodeInfo) -> Reply {
+        // Rpc drops these already, but the routing table asserts on them
+        if src.id.len() != self.key_length {
+            warn!("Request from a source id of invalid length, ignoring.");
+            return Reply::Ping;
+        }
+
         let mut routes = self.routes.lock().await;
 
+        let is_new = !routes.contains(&src);
         let res = routes.update(src.clone());
 
         drop(routes);
 
+        // a peer which just joined may now be responsible for some of our records
+        if is_new && res.is_none() {
+            let node = self.clone();
+            let src = src.clone();
+            self.spawn_follow_up("handing records off", async move { node.handoff(src).await });
+        }
+
         // update routes
         if let Some(e) = res {
             let node = self.clone();
-            tokio::spawn(async move {
-                let mut routes = node.routes.lock().await;
+            let src = src.clone();
+            self.spawn_follow_up("checking a full bucket", async move {
                 // ping the old node and re-update routes
-                if let None = node.ping(e.clone()).await {
+                if node.ping(e.clone()).await.is_none() {
+                    let mut routes = node.routes.lock().await;
                     routes.remove(&e);
                     routes.update(src);
                 }
-                drop(routes);
             });
         }
 
@@ -143,8 +278,31 @@ impl Node {
                 if self.key_length != k.len() {
                     println!("INFO: Store request which has invalid key length, ignoring.");
                 } else {
-                    let mut store = self.store.lock().await;
-                    store.insert(k, v).unwrap_or_default();
+                    let predicate = self.store.lock().await.predicate();
+                    let (valid, v) = crypto_pool::run(move || (predicate(&v), v)).await;
+                    if valid {
+                        let mut store = self.store.lock().await;
+                        match store.insert_verified(k.clone(), v.clone()) {
+                            // under the lock, so that the backend gets puts in order
+                            Ok(()) => {
+                                if let Some(backend) = store.backend() {
+                                    if let Err(e) = backend.put(k, v).await {
+                                        warn!("A stored value is not kept on disk: {}", e);
+                                    }
+                                }
+                            }
+                            Err(StoreRefusal::Conflict) => {
+                                warn!("Overwriting a stored value is refused.");
+                                self.rpc.lock().await.stats().record_rejected_overwrite();
+                            }
+                            Err(StoreRefusal::Full) => {
+                                debug!("The store is full, a value is refused.");
+                                self.rpc.lock().await.stats().record_store_full();
+                            }
+                        }
+                    } else {
+                        warn!("Invalid value is tried to insert.");
+                    }
                 }
                 Reply::Ping
             }
@@ -154,7 +312,7 @@ impl Node {
                     Reply::FindNode(Vec::new())
                 } else {
                     let routes = self.routes.lock().await;
-                    Reply::FindNode(routes.closest_nodes(id, K_PARAM))
+                    Reply::FindNode(routes.closest_nodes(id, self.kad.k))
                 }
             }
             Request::FindValue(k) => {
@@ -172,7 +330,7 @@ impl Node {
                     None => {
                         let routes = self.routes.lock().await;
                         Reply::FindValue(FindValueResult::Nodes(
-                            routes.closest_nodes(hash, K_PARAM),
+                            routes.closest_nodes(hash, self.kad.k),
                         ))
                     }
                 };
@@ -182,69 +340,51 @@ impl Node {
                 ret
             }
             Request::Unicast(msg) => {
-                if let Err(_) = self.tx.send(msg) {
+                if self.tx.send(msg).is_err() {
                     info!("Closing channel, since receiver is dead.");
                 }
 
                 Reply::Ping
             }
-            Request::Broadcast(msg) => {
-                if let Err(_) = self.tx.send(msg.clone()) {
-                    info!("Closing channel, since receiver is dead.");
-                }
-
-                let broadcast_tokens = self.broadcast_tokens.lock().await;
-                let hash = Key::hash(&msg, TOKEN_KEY_LEN);
-                let is_relay = !broadcast_tokens.contains(&hash);
-
-                drop(broadcast_tokens);
-
-                if is_relay {
-                    let node = self.clone();
-                    tokio::spawn(async move { node.broadcast(&msg).await });
-
-                    let node = self.clone();
-                    tokio::spawn(async move {
-                        sleep(Duration::from_millis(BROADCAST_TIME_OUT)).await;
-
-                        let mut broadcast_tokens = node.broadcast_tokens.lock().await;
-                        broadcast_tokens.remove(&hash);
-                        drop(broadcast_tokens);
-                    });
-                } else {
-                    info!("Message already broadcast, ignoring");
-                }
-
+            Request::Broadcast(_) => {
+                info!("Deprecated broadcast request, dropping");
+                // still answered, so that old peers keep us in their routing tables
                 Reply::Ping
             }
             Request::Multicast(k, msg) => {
-                if k.is_prefix(&self.node_info.id) {
-                    if let Err(_) = self.tx.send(msg.clone()) {
-                        info!("Closing channel, since receiver is dead.");
+                self.accept_multicast(k, msg).await;
+                Reply::Ping
+            }
+            Request::ReceiptedMulticast(k, msg) => {
+                let receipt = match &self.receipt_key {
+                    Some(sk) if k.is_prefix(&self.node_info.id) => {
+                        let (sk, id) = (sk.clone(), self.node_info.id.clone());
+                        let (prefix, signed) = (k.clone(), msg.clone());
+                        let now = Utc::now().timestamp() as u64;
+                        Some(
+                            crypto_pool::run(move || {
+                                DeliveryReceipt::sign(&sk, &id, &prefix, &signed, now)
+                            })
+                            .await,
+                        )
                     }
-                    let broadcast_tokens = self.broadcast_tokens.lock().await;
-                    let hash = Key::hash(&msg, TOKEN_KEY_LEN);
-                    let is_relay = !broadcast_tokens.contains(&hash);
-
-                    drop(broadcast_tokens);
-
-                    if is_relay {
-                        let node = self.clone();
-                        tokio::spawn(async move { node.multicast(&k, &msg).await });
-
-                        let node = self.clone();
-                        tokio::spawn(async move {
-                            sleep(Duration::from_millis(BROADCAST_TIME_OUT)).await;
-
-                            let mut broadcast_tokens = node.broadcast_tokens.lock().await;
-                            broadcast_tokens.remove(&hash);
-                            drop(broadcast_tokens);
-                        });
-                    } else {
-                        info!("Message already multicast, ignoring");
+                    _ => None,
+                };
+                self.accept_multicast(k, msg).await;
+                Reply::Receipt(receipt)
+            }
+            // only nodes inside the region may join it
+            Request::Join(prefix) => {
+                if prefix.is_prefix(&src.id) {
+                    let now = self.clock.now();
+                    if !self.interests.lock().await.join(prefix, src, now) {
+                        debug!("Join dropped, too many nodes joined");
                     }
                 }
-
+                Reply::Ping
+            }
+            Request::Leave(prefix) => {
+                self.interests.lock().await.leave(&prefix, &src.id);
                 Reply::Ping
             }
         };
@@ -252,6 +392,76 @@ impl Node {
         ret
     }
 
+    // Passes msg on to the receiver and relays it, when this node is in the
+    // region of k and has not seen it lately
+    async fn accept_multicast(&self, k: Key, msg: Vec<u8>) {
+        if !k.is_prefix(&self.node_info.id) {
+            return;
+        }
+        if self.tx.send(msg.clone()).is_err() {
+            info!("Closing channel, since receiver is dead.");
+        }
+        let hash = Key::hash(&msg, TOKEN_KEY_LEN);
+        let now = self.clock.now();
+        let is_relay = self.broadcast_tokens.lock().await.insert(hash, now);
+
+        if is_relay {
+            let node = self.clone();
+            self.spawn_follow_up("relaying a multicast", async move {
+                node.multicast(&k, &msg).await;
+            });
+        } else {
+            info!("Message already multicast, ignoring");
+        }
+    }
+
+    // Runs what a request brings about, like relaying a multicast, under a
+    // handling permit of its own, so that it counts against the limit too.
+    // Dropped when there is none left.
+    fn spawn_follow_up<F>(&self, what: &str, work: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        match self.handling.clone().try_acquire_owned() {
+            Ok(permit) => {
+                tokio::spawn(async move {
+                    work.await;
+                    drop(permit);
+                });
+            }
+            Err(_) => debug!("Too many requests at once, not {}", what),
+        }
+    }
+
+    // Pushes the stored records which the new peer is one of the K closest nodes for.
+    // Only the closest holder pushes, so that the peer does not get every copy.
+    async fn handoff(&self, peer: NodeInfo) {
+        let store = self.store.lock().await;
+        let records: Vec<_> = store.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        drop(store);
+
+        let routes = self.routes.lock().await;
+        let records: Vec<_> = records
+            .into_iter()
+            .filter(|(k, _)| {
+                let closest = routes.closest_nodes(k.to_hash(), self.kad.k);
+                let has_peer = closest.iter().any(|(ni, _)| ni.id == peer.id);
+                let first_holder = closest.iter().find(|(ni, _)| ni.id != peer.id);
+                has_peer && matches!(first_holder, Some((ni, _)) if *ni == self.node_info)
+            })
+            .collect();
+        drop(routes);
+
+        if !records.is_empty() {
+            info!("Handing {} records off to a new peer", records.len());
+        }
+        for (k, v) in records {
+            if self.store(peer.clone(), k, &v).await.is_none() {
+                break;
+            }
+        }
+    }
+
     pub async fn ping_raw(&self, dst: NodeInfo) -> UnboundedReceiver<Option<Reply>> {
         self.rpc
             .lock()
@@ -314,144 +524,188 @@ impl Node {
             .await
     }
 
-    pub async fn broadcast_raw(
+    async fn multicast_req(
         &self,
         dst: NodeInfo,
+        k: &Key,
         msg: &[u8],
-    ) -> UnboundedReceiver<Option<Reply>> {
-        self.rpc
-            .lock()
-            .await
-            .send_req(
-                Request::Broadcast(msg.to_vec()),
-                self.node_info.clone(),
-                dst,
-            )
-            .await
+        receipts: bool,
+    ) -> Option<Reply> {
+        if !receipts {
+            return self.multicast_raw(dst, k, msg).await.recv().await.unwrap();
+        }
+        let req = Request::ReceiptedMulticast(k.clone(), msg.to_vec());
+        let mut rx = self.rpc.lock().await.send_req(req, self.node_info.clone(), dst).await;
+        rx.recv().await.unwrap()
     }
 
-    pub async fn ping(&self, dst: NodeInfo) -> Option<()> {
-        let rep = self.ping_raw(dst.clone()).await.recv().await.unwrap();
-        let mut routes = self.routes.lock().await;
-        if let Some(Reply::Ping) = rep {
-            routes.update(dst);
-            Some(())
-        } else {
-            routes.remove(&dst);
-            None
+    // Sends req, retrying with backoff while it times out.
+    async fn request(&self, dst: &NodeInfo, req: Request) -> Option<Reply> {
+        let retry = self.rpc.lock().await.retry_policy();
+        let mut backoff = retry.backoff;
+        for attempt in 0..retry.attempts.max(1) {
+            if attempt > 0 {
+                self.clock.sleep(backoff).await;
+                backoff = (backoff * 2).min(retry.max_backoff);
+            }
+            let permit = self.outbound.acquire().await.unwrap();
+            let sent = self.clock.now();
+            // the lock is let go before waiting, or every request of this
+            // node would wait for the reply to the one before
+            let mut rx = self
+                .rpc
+                .lock()
+                .await
+                .send_req(req.clone(), self.node_info.clone(), dst.clone())
+                .await;
+            let rep = rx.recv().await.unwrap();
+            drop(permit);
+            if rep.is_some() {
+                let rtt = (self.clock.now() - sent).as_millis() as u64;
+                let now = Utc::now().timestamp() as u64;
+                self.peer_store.lock().unwrap().record_success(dst, rtt, now);
+                return rep;
+            }
+            debug!("Request to {} timed out (attempt {})", dst.addr, attempt + 1);
         }
+        self.peer_store.lock().unwrap().record_failure(dst);
+        None
     }
 
-    pub async fn store(&self, dst: NodeInfo, k: Key, v: &[u8]) -> Option<()> {
-        let rep = self
-            .store_raw(dst.clone(), k, v)
-            .await
-            .recv()
-            .await
-            .unwrap();
+    // A peer stays in the routing table until DEMOTE_AFTER requests in a row
+    // time out, or it answers nonsense. Only the peers in the table are
+    // counted: the others have nothing to be demoted from, and lookups ask
+    // node ids handed out by anyone, which may never answer.
+    async fn record_outcome(&self, dst: NodeInfo, outcome: Outcome) {
         let mut routes = self.routes.lock().await;
-        if let Some(Reply::Ping) = rep {
-            routes.update(dst);
-            Some(())
-        } else {
-            routes.remove(&dst);
-            None
+        let mut failures = self.failures.lock().await;
+        let demote = match outcome {
+            Outcome::Answered => {
+                failures.remove(&dst.id);
+                false
+            }
+            Outcome::TimedOut if dst.id.len() == self.key_length && routes.contains(&dst) => {
+                let n = failures.entry(dst.id.clone()).or_insert(0);
+                *n += 1;
+                let demote = *n >= DEMOTE_AFTER;
+                // and forgets those which left the table some other way
+                if failures.len() > self.routes_limit() {
+                    failures.retain(|id, _| routes.contains_id(id));
+                }
+                demote
+            }
+            Outcome::TimedOut => {
+                failures.remove(&dst.id);
+                false
+            }
+            Outcome::Unexpected => true,
+        };
+        if demote {
+            failures.remove(&dst.id);
         }
-    }
+        drop(failures);
 
-    pub async fn find_node(&self, dst: NodeInfo, id: Key) -> Option<Vec<(NodeInfo, Key)>> {
-        let rep = self
-            .find_node_raw(dst.clone(), id)
-            .await
-            .recv()
-            .await
-            .unwrap();
-        let mut routes = self.routes.lock().await;
-        if let Some(Reply::FindNode(entries)) = rep {
+        if outcome == Outcome::Answered {
             routes.update(dst);
-            Some(entries)
-        } else {
+        } else if demote {
             routes.remove(&dst);
-            None
         }
     }
 
-    pub async fn find_value(&self, dst: NodeInfo, k: Key) -> Option<FindValueResult> {
-        let rep = self
-            .find_value_raw(dst.clone(), k)
-            .await
-            .recv()
-            .await
-            .unwrap();
-        let mut routes = self.routes.lock().await;
-        if let Some(Reply::FindValue(res)) = rep {
-            routes.update(dst);
-            Some(res)
-        } else {
-            routes.remove(&dst);
-            None
-        }
+    // how many peers the routing table holds at most
+    fn routes_limit(&self) -> usize {
+        self.key_length * 8 * self.kad.k
     }
 
-    pub async fn unicast(&self, dst: NodeInfo, msg: &[u8]) -> Option<()> {
-        let rep = self
-            .unicast_raw(dst.clone(), msg)
-            .await
-            .recv()
-            .await
-            .unwrap();
-        let mut routes = self.routes.lock().await;
-        if let Some(Reply::Ping) = rep {
-            routes.update(dst);
-            Some(())
-        } else {
-            routes.remove(&dst);
-            None
+    fn outcome(rep: &Option<Reply>, expected: bool) -> Outcome {
+        match rep {
+            None => Outcome::TimedOut,
+            Some(_) if expected => Outcome::Answered,
+            Some(_) => Outcome::Unexpected,
         }
     }
 
-    pub async fn broadcast(&self, msg: &[u8]) -> Vec<NodeInfo> {
-        let mut broadcast_tokens = self.broadcast_tokens.lock().await;
-        broadcast_tokens.insert(Key::hash(msg, TOKEN_KEY_LEN));
-        drop(broadcast_tokens);
+    pub async fn ping(&self, dst: NodeInfo) -> Option<()> {
+        let rep = self.request(&dst, Request::Ping).await;
+        let outcome = Node::outcome(&rep, matches!(rep, Some(Reply::Ping)));
+        self.record_outcome(dst, outcome).await;
+        (outcome == Outcome::Answered).then_some(())
+    }
 
-        let mut ret = Vec::new();
-        let mut reps = Vec::new();
+    pub async fn store(&self, dst: NodeInfo, k: Key, v: &[u8]) -> Option<()> {
+        let rep = self.request(&dst, Request::Store(k, v.to_vec())).await;
+        let outcome = Node::outcome(&rep, matches!(rep, Some(Reply::Ping)));
+        self.record_outcome(dst, outcome).await;
+        (outcome == Outcome::Answered).then_some(())
+    }
 
-        let mut routes = self.routes.lock().await;
-        for bucket in routes.get_buckets() {
-            for dst in bucket.iter() {
-                if *dst == self.node_info {
-                    continue;
-                }
-                reps.push((
-                    self.broadcast_raw(dst.clone(), msg)
-                        .await
-                        .recv()
-                        .await
-                        .unwrap(),
-                    dst.clone(),
-                ));
-            }
+    pub async fn find_node(&self, dst: NodeInfo, id: Key) -> Option<Vec<(NodeInfo, Key)>> {
+        let rep = self.request(&dst, Request::FindNode(id)).await;
+        let outcome = Node::outcome(&rep, matches!(rep, Some(Reply::FindNode(_))));
+        self.record_outcome(dst, outcome).await;
+        match rep {
+            Some(Reply::FindNode(entries)) => Some(entries),
+            _ => None,
         }
+    }
 
-        for (rep, dst) in reps.drain(..) {
-            if let Some(Reply::Ping) = rep {
-                ret.push(dst.clone());
-                routes.update(dst);
-            } else {
-                routes.remove(&dst);
-            }
+    pub async fn find_value(&self, dst: NodeInfo, k: Key) -> Option<FindValueResult> {
+        let rep = self.request(&dst, Request::FindValue(k)).await;
+        let outcome = Node::outcome(&rep, matches!(rep, Some(Reply::FindValue(_))));
+        self.record_outcome(dst, outcome).await;
+        match rep {
+            Some(Reply::FindValue(res)) => Some(res),
+            _ => None,
         }
-        drop(routes);
+    }
 
-        ret
+    pub async fn unicast(&self, dst: NodeInfo, msg: &[u8]) -> Option<()> {
+        let rep = self.request(&dst, Request::Unicast(msg.to_vec())).await;
+        let outcome = Node::outcome(&rep, matches!(rep, Some(Reply::Ping)));
+        self.record_outcome(dst, outcome).await;
+        (outcome == Outcome::Answered).then_some(())
     }
 
     pub async fn multicast(&self, prefix: &Key, msg: &[u8]) -> Vec<NodeInfo> {
-        let mut broadcast_tokens = self.broadcast_tokens.lock().await;
-        broadcast_tokens.insert(Key::hash(msg, TOKEN_KEY_LEN));
-        drop(broadcast_tokens);
+        self.multicast_to(prefix, msg, false)
+            .await
+            .into_iter()
+            .map(|(node_info, _)| node_info)
+            .collect()
+    }
+
+    // The same, asking the nodes for a DeliveryReceipt. Each node which took
+    // the message comes with its receipt, if it signed a valid one for its
+    // own id, and not one of another node's it passed on.
+    pub async fn multicast_with_receipts(
+        &self,
+        prefix: &Key,
+        msg: &[u8],
+    ) -> Vec<(NodeInfo, Option<DeliveryReceipt>)> {
+        let accepted = self.multicast_to(prefix, msg, true).await;
+        let (prefix, msg) = (prefix.clone(), msg.to_vec());
+        crypto_pool::run(move || {
+            accepted
+                .into_iter()
+                .map(|(node_info, receipt)| {
+                    let receipt = receipt.filter(|receipt| {
+                        receipt.node_id == node_info.id && receipt.verify(&prefix, &msg)
+                    });
+                    (node_info, receipt)
+                })
+                .collect()
+        })
+        .await
+    }
+
+    async fn multicast_to(
+        &self,
+        prefix: &Key,
+        msg: &[u8],
+        receipts: bool,
+    ) -> Vec<(NodeInfo, Option<DeliveryReceipt>)> {
+        let now = self.clock.now();
+        self.broadcast_tokens.lock().await.insert(Key::hash(msg, TOKEN_KEY_LEN), now);
 
         let mut id = prefix.clone();
         id.resize(self.node_info.id.len());
@@ -459,24 +713,30 @@ impl Node {
         let mut ret = Vec::new();
 
         let candidates = self.lookup_nodes(id).await;
-        let target: Vec<_> = candidates
+        let mut target: Vec<NodeInfo> = candidates
             .iter()
             .filter(|(_, d)| d.zeroes_in_prefix() >= prefix.len() * 8)
+            .map(|(node_info, _)| node_info.clone())
             .collect();
+        let now = self.clock.now();
+        for node_info in self.interests.lock().await.members(prefix, now) {
+            if !target.contains(&node_info) {
+                target.push(node_info);
+            }
+        }
 
         if target.is_empty() {
             for (node_info, _) in candidates.iter().rev() {
+                let permit = self.outbound.acquire().await.unwrap();
                 let rep = self
-                    .multicast_raw(node_info.clone(), prefix, msg)
-                    .await
-                    .recv()
-                    .await
-                    .unwrap();
+                    .multicast_req(node_info.clone(), prefix, msg, receipts)
+                    .await;
+                drop(permit);
                 let mut routes = self.routes.lock().await;
 
-                if let Some(Reply::Ping) = rep {
+                if let Some(receipt) = Node::multicast_accepted(rep, receipts) {
                     routes.update(node_info.clone());
-                    ret.push(node_info.clone());
+                    ret.push((node_info.clone(), receipt));
                     break;
                 } else {
                     routes.remove(&node_info);
@@ -485,25 +745,22 @@ impl Node {
             }
         } else {
             let mut joins = Vec::new();
-            for (node_info, _) in target.iter() {
+            for node_info in target.iter() {
                 let node = self.clone();
                 let node_info = node_info.clone();
                 let prefix = prefix.clone();
                 let msg = Vec::from(msg);
                 joins.push(tokio::spawn(async move {
-                    node.multicast_raw(node_info, &prefix, &msg[..])
-                        .await
-                        .recv()
-                        .await
-                        .unwrap()
+                    let _permit = node.outbound.acquire().await.unwrap();
+                    node.multicast_req(node_info, &prefix, &msg[..], receipts).await
                 }));
             }
-            for (handle, (node_info, _)) in joins.into_iter().zip(target) {
+            for (handle, node_info) in joins.into_iter().zip(target) {
                 let rep = handle.await.unwrap();
                 let mut routes = self.routes.lock().await;
-                if let Some(Reply::Ping) = rep {
+                if let Some(receipt) = Node::multicast_accepted(rep, receipts) {
                     routes.update(node_info.clone());
-                    ret.push(node_info.clone());
+                    ret.push((node_info.clone(), receipt));
                 } else {
                     routes.remove(&node_info);
                 }
@@ -513,98 +770,122 @@ impl Node {
         ret
     }
 
-    pub async fn lookup_nodes(&self, id: Key) -> Vec<(NodeInfo, Key)> {
-        let mut queried = HashSet::new();
-        let mut ret = HashSet::new();
+    // Some, with the receipt if any, when the reply is the one expected
+    fn multicast_accepted(
+        rep: Option<Reply>,
+        receipts: bool,
+    ) -> Option<Option<DeliveryReceipt>> {
+        match rep {
+            Some(Reply::Ping) if !receipts => Some(None),
+            Some(Reply::Receipt(receipt)) if receipts => Some(receipt),
+            _ => None,
+        }
+    }
 
-        // Add the closest nodes we know
-        let routes = self.routes.lock().await;
-        let mut to_query = BinaryHeap::from(routes.closest_nodes(id.clone(), K_PARAM));
-        drop(routes);
+    // Tells the k closest nodes of the prefix region that this node takes part
+    // in it, so that their multicasts reach it before routing tables have
+    // caught up. Has to be sent again within JOIN_TTL.
+    pub async fn join(&self, prefix: &Key) {
+        self.announce(prefix, Request::Join(prefix.clone())).await;
+    }
 
-        for entry in &to_query {
-            queried.insert(entry.clone());
-        }
+    pub async fn leave(&self, prefix: &Key) {
+        self.announce(prefix, Request::Leave(prefix.clone())).await;
+    }
 
-        let mut joins = Vec::new();
-        let mut queries = Vec::new();
-        let mut results = Vec::new();
-        for entry in to_query.drain() {
-            queries.push(entry);
-        }
-        for &(ref ni, _) in &queries {
-            let ni = ni.clone();
-            let id = id.clone();
-            let node = self.clone();
-            joins.push(tokio::spawn(async move {
-                node.find_node(ni.clone(), id.clone()).await
-            }));
-        }
-        for j in joins {
-            results.push(j.await.unwrap());
-        }
-        for (res, query) in results.into_iter().zip(queries) {
-            if let Some(_) = res {
-                ret.insert(query);
-            }
-        }
+    async fn announce(&self, prefix: &Key, req: Request) {
+        let mut id = prefix.clone();
+        id.resize(self.node_info.id.len());
+        let sends = self
+            .lookup_nodes(id)
+            .await
+            .into_iter()
+            .take(self.kad.k)
+            .map(|(node_info, _)| {
+                let (node, req) = (self.clone(), req.clone());
+                async move { node.request(&node_info, req).await }
+            });
+        futures::future::join_all(sends).await;
+    }
 
-        let mut ret = ret.into_iter().collect::<Vec<_>>();
-        ret.sort_by(|a, b| a.1.cmp(&b.1));
-        ret.truncate(K_PARAM);
-        ret
+    pub async fn lookup_nodes(&self, id: Key) -> Vec<(NodeInfo, Key)> {
+        self.iterative_lookup(id, None).await.1
     }
 
     pub async fn lookup_value(&self, k: Key) -> (Option<Vec<u8>>, Vec<(NodeInfo, Key)>) {
-        let id = k.to_hash();
-        let mut queried = HashSet::new();
-        let mut ret = HashSet::new();
+        self.iterative_lookup(k.to_hash(), Some(k)).await
+    }
 
+    // Walks toward id: the alpha closest nodes of the shortlist not asked
+    // yet are asked at once, and the nodes they return join it, until the k
+    // closest have all been asked. Those which do not answer leave
+    // the shortlist. With value_key, FindValue is asked instead, and the first
+    // value found ends the walk. Returns the closest nodes that answered.
+    async fn iterative_lookup(
+        &self,
+        id: Key,
+        value_key: Option<Key>,
+    ) -> (Option<Vec<u8>>, Vec<(NodeInfo, Key)>) {
+        let _permit = self.lookups.acquire().await.unwrap();
         let routes = self.routes.lock().await;
-        let mut to_query = BinaryHeap::from(routes.closest_nodes(id, K_PARAM));
+        let mut shortlist: BTreeMap<Key, NodeInfo> = routes
+            .closest_nodes(id.clone(), self.kad.k)
+            .into_iter()
+            .map(|(node_info, distance)| (distance, node_info))
+            .collect();
         drop(routes);
-        for entry in &to_query {
-            queried.insert(entry.clone());
-        }
-
-        let mut joins = Vec::new();
-        let mut queries = Vec::new();
-        let mut results = Vec::new();
-        for entry in to_query.drain() {
-            queries.push(entry);
-        }
-        for &(ref ni, _) in &queries {
-            let k = k.clone();
-            let ni = ni.clone();
-            let node = self.clone();
-            joins.push(tokio::spawn(
-                async move { node.find_value(ni.clone(), k).await },
-            ));
-        }
-        for j in joins {
-            results.push(j.await.unwrap());
-        }
-        for (res, query) in results.into_iter().zip(queries) {
-            if let Some(fvres) = res {
-                match fvres {
-                    FindValueResult::Nodes(_) => {
-                        ret.insert(query);
+        let mut queried = HashSet::new();
+        let mut answered = Vec::new();
+
+        loop {
+            let batch: Vec<(Key, NodeInfo)> = shortlist
+                .iter()
+                .take(self.kad.k)
+                .filter(|(distance, _)| !queried.contains(*distance))
+                .take(self.kad.alpha)
+                .map(|(distance, node_info)| (distance.clone(), node_info.clone()))
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+            let mut joins = Vec::new();
+            for (distance, node_info) in &batch {
+                queried.insert(distance.clone());
+                let (node, node_info) = (self.clone(), node_info.clone());
+                let (id, value_key) = (id.clone(), value_key.clone());
+                joins.push(tokio::spawn(async move {
+                    match value_key {
+                        Some(k) => node.find_value(node_info, k).await,
+                        None => node.find_node(node_info, id).await.map(FindValueResult::Nodes),
+                    }
+                }));
+            }
+            for ((distance, node_info), join) in batch.into_iter().zip(joins) {
+                match join.await.unwrap() {
+                    Some(FindValueResult::Value(val)) => {
+                        answered.sort_by(|a: &(NodeInfo, Key), b| a.1.cmp(&b.1));
+                        answered.truncate(self.kad.k);
+                        return (Some(val), answered);
+                    }
+                    Some(FindValueResult::Nodes(found)) => {
+                        for (found, _) in found {
+                            // the distance in the reply is not trusted
+                            if found.net_id == self.node_info.net_id && found.id.len() == id.len() {
+                                shortlist.entry(found.id.clone() ^ id.clone()).or_insert(found);
+                            }
+                        }
+                        answered.push((node_info, distance));
                     }
-                    FindValueResult::Value(val) => {
-                        let mut ret = ret.into_iter().collect::<Vec<_>>();
-                        ret.sort_by(|a, b| a.1.cmp(&b.1));
-                        ret.truncate(K_PARAM);
-                        return (Some(val), ret);
+                    None => {
+                        shortlist.remove(&distance);
                     }
                 }
             }
         }
 
-        let mut ret = ret.into_iter().collect::<Vec<_>>();
-        ret.sort_by(|a, b| a.1.cmp(&b.1));
-        ret.truncate(K_PARAM);
-
-        (None, ret)
+        answered.sort_by(|a, b| a.1.cmp(&b.1));
+        answered.truncate(self.kad.k);
+        (None, answered)
     }
 
     pub async fn put(&self, k: Key, v: &[u8]) {
@@ -640,6 +921,32 @@ impl Node {
         }
     }
 
+    // takes out the multicast hashes which lapsed, returning how many; run
+    // by the maintenance scheduler
+    pub async fn sweep_broadcast_tokens(&self) -> usize {
+        let now = self.clock.now();
+        let mut broadcast_tokens = self.broadcast_tokens.lock().await;
+        let swept = broadcast_tokens.expire(now);
+        if swept > 0 {
+            debug!("{} multicast hashes lapsed, {} kept", swept, broadcast_tokens.len());
+        }
+        swept
+    }
+
+    // Checks that what the node keeps for its peers is within its limits,
+    // whatever they sent; see kad::fuzz
+    #[cfg(any(test, feature = "fuzzing"))]
+    pub(super) async fn assert_bounded(&self) {
+        let routes: usize = self.routes.lock().await.get_buckets().iter().map(Vec::len).sum();
+        assert!(routes <= self.routes_limit());
+        assert!(self.failures.lock().await.len() <= self.routes_limit());
+        assert!(self.interests.lock().await.len() <= JOIN_PREFIX_LIMIT * self.kad.k);
+        assert!(self.broadcast_tokens.lock().await.len() <= SEEN_TOKENS_LIMIT);
+        if let Some(limit) = self.rpc.lock().await.store_limit() {
+            assert!(self.store.lock().await.bytes() <= limit);
+        }
+    }
+
     pub async fn show_routes(&self) {
         println!("buckets:");
         for bucket in self.routes.lock().await.get_buckets().iter() {
@@ -669,3 +976,311 @@ impl Node {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kad::IdScheme;
+    use crate::service::TESTNET_USER_DHT;
+    use crate::util::rng::SystemEntropy;
+    use tokio::net::UdpSocket;
+
+    fn network() -> NetworkDescriptor {
+        NetworkDescriptor::new(TESTNET_USER_DHT, TOKEN_KEY_LEN, IdScheme::Random)
+            .with_validator(Arc::new(|_| true))
+    }
+
+    async fn start_node(bootstrap: &[NodeInfo]) -> Node {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let rpc = Arc::new(Mutex::new(Rpc::new(socket)));
+        let (tx, _rx) = mpsc::unbounded_channel();
+        Node::start(&network(), Key::random(TOKEN_KEY_LEN), rpc, tx, bootstrap).await
+    }
+
+    #[tokio::test]
+    async fn deprecated_broadcast_test() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let rpc = Arc::new(Mutex::new(Rpc::new(socket)));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let node = Node::start(&network(), Key::random(TOKEN_KEY_LEN), rpc, tx, &[]).await;
+
+        let src = NodeInfo {
+            id: Key::random(TOKEN_KEY_LEN),
+            addr: "127.0.0.1:1".parse().unwrap(),
+            net_id: TESTNET_USER_DHT.to_string(),
+        };
+        let rep = node
+            .handle_req(Request::Broadcast(b"flood".to_vec()), src)
+            .await;
+        assert!(matches!(rep, Reply::Ping));
+        assert!(rx.try_recv().is_err());
+        assert_eq!(node.broadcast_tokens.lock().await.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn handoff_test() {
+        let old = start_node(&[]).await;
+        let k = Key::random(TOKEN_KEY_LEN);
+        old.store(old.node_info.clone(), k.clone(), b"record")
+            .await
+            .unwrap();
+
+        // joining looks up its own id through the old node, which hands the record off
+        let new = start_node(std::slice::from_ref(&old.node_info)).await;
+        for _ in 0..50 {
+            if new.store.lock().await.get(&k).is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(new.store.lock().await.get(&k), Some(&b"record".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn iterative_lookup_test() {
+        // a only knows b, and b only c: a's lookup reaches c through b
+        let c = start_node(&[]).await;
+        let b = start_node(std::slice::from_ref(&c.node_info)).await;
+        let a = start_node(std::slice::from_ref(&b.node_info)).await;
+        assert!(a.routes.lock().await.contains(&c.node_info));
+        let found = a.lookup_nodes(c.node_info.id.clone()).await;
+        assert_eq!(found[0].0, c.node_info);
+    }
+
+    #[tokio::test]
+    async fn join_test() {
+        let start = |id: Key, bootstrap: Vec<NodeInfo>| async move {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let rpc = Arc::new(Mutex::new(Rpc::new(socket)));
+            let (tx, _rx) = mpsc::unbounded_channel();
+            Node::start(&network(), id, rpc, tx, &bootstrap).await
+        };
+        let prefix = Key::random(4);
+        let mut id = prefix.clone();
+        id.resize(TOKEN_KEY_LEN);
+
+        let publisher = start(Key::random(TOKEN_KEY_LEN), Vec::new()).await;
+        let subscriber = start(id, vec![publisher.node_info.clone()]).await;
+        subscriber.join(&prefix).await;
+        let now = publisher.clock.now();
+        let joined = publisher.interests.lock().await.members(&prefix, now);
+        assert_eq!(joined, vec![subscriber.node_info.clone()]);
+
+        // outside the region, a Join is ignored
+        let outsider = start(Key::random(TOKEN_KEY_LEN), vec![publisher.node_info.clone()]).await;
+        let other = Key::random(4);
+        outsider.join(&other).await;
+        assert!(publisher.interests.lock().await.members(&other, now).is_empty());
+
+        subscriber.leave(&prefix).await;
+        assert!(publisher.interests.lock().await.members(&prefix, now).is_empty());
+    }
+
+    #[tokio::test]
+    async fn delivery_receipt_test() {
+        let prefix = Key::random(4);
+        let mut id = prefix.clone();
+        id.resize(TOKEN_KEY_LEN);
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut rpc = Rpc::new(socket);
+        rpc.set_kad_config(KadConfig { delivery_receipts: true, ..KadConfig::default() });
+        // kept between runs by the caller
+        let key = SecretKey::random();
+        rpc.set_receipt_key(Arc::new(key.clone()));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let rpc = Arc::new(Mutex::new(rpc));
+        let receiver = Node::start(&network(), id, rpc, tx, &[]).await;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let rpc = Arc::new(Mutex::new(Rpc::new(socket)));
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let bootstrap = vec![receiver.node_info.clone()];
+        let sender = Node::start(&network(), Key::random(TOKEN_KEY_LEN), rpc, tx, &bootstrap).await;
+
+        let accepted = sender.multicast_with_receipts(&prefix, b"post").await;
+        assert_eq!(accepted.len(), 1);
+        let (node_info, receipt) = &accepted[0];
+        assert_eq!(*node_info, receiver.node_info);
+        let receipt = receipt.as_ref().unwrap();
+        assert_eq!(receipt.node_id, receiver.node_info.id);
+        assert_eq!(receipt.pubkey, key.public_key());
+        assert!(receipt.verify(&prefix, b"post"));
+        assert_eq!(rx.recv().await.unwrap(), b"post".to_vec());
+    }
+
+    #[tokio::test]
+    async fn replayed_receipt_test() {
+        let prefix = Key::random(4);
+        let mut id = prefix.clone();
+        id.resize_with_random(TOKEN_KEY_LEN, &SystemEntropy);
+        let mut other_id = prefix.clone();
+        other_id.resize_with_random(TOKEN_KEY_LEN, &SystemEntropy);
+
+        // a peer which answers the first multicast with a receipt another
+        // node signed, valid but not for its id, and the second with its own
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let node_info = NodeInfo {
+            id: id.clone(),
+            addr: peer.local_addr().unwrap(),
+            net_id: TESTNET_USER_DHT.to_string(),
+        };
+        let receipts = [
+            DeliveryReceipt::sign(&SecretKey::random(), &other_id, &prefix, b"post", 0),
+            DeliveryReceipt::sign(&SecretKey::random(), &id, &prefix, b"post", 0),
+        ];
+        tokio::spawn(async move {
+            let mut buf = [0; 65536];
+            let mut receipts = receipts.iter();
+            loop {
+                let (n, from) = peer.recv_from(&mut buf).await.unwrap();
+                let mut msg: serde_json::Value = serde_json::from_slice(&buf[..n]).unwrap();
+                let reply = if msg["msg"]["Request"]["ReceiptedMulticast"].is_null() {
+                    // knows no other nodes
+                    serde_json::json!({ "Reply": { "FindNode": [] } })
+                } else {
+                    serde_json::json!({ "Reply": { "Receipt": receipts.next() } })
+                };
+                let src = msg["src"].take();
+                msg["src"] = msg["dst"].take();
+                msg["dst"] = src;
+                msg["msg"] = reply;
+                peer.send_to(&serde_json::to_vec(&msg).unwrap(), from).await.unwrap();
+            }
+        });
+
+        let sender = start_node(&[]).await;
+        sender.routes.lock().await.update(node_info.clone());
+        let accepted = sender.multicast_with_receipts(&prefix, b"post").await;
+        assert_eq!(accepted, vec![(node_info.clone(), None)]);
+        let accepted = sender.multicast_with_receipts(&prefix, b"post").await;
+        assert_eq!(accepted.len(), 1);
+        assert_eq!(accepted[0].1.as_ref().unwrap().node_id, id);
+    }
+
+    #[tokio::test]
+    async fn kad_config_test() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut rpc = Rpc::new(socket);
+        rpc.set_kad_config(KadConfig { k: 2, ..KadConfig::default() });
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let rpc = Arc::new(Mutex::new(rpc));
+        let node = Node::start(&network(), Key::random(TOKEN_KEY_LEN), rpc, tx, &[]).await;
+
+        let src = NodeInfo {
+            id: Key::random(TOKEN_KEY_LEN),
+            addr: "127.0.0.1:1".parse().unwrap(),
+            net_id: TESTNET_USER_DHT.to_string(),
+        };
+        for port in 2..10 {
+            node.routes.lock().await.update(NodeInfo {
+                id: Key::random(TOKEN_KEY_LEN),
+                addr: format!("127.0.0.1:{}", port).parse().unwrap(),
+                ..src.clone()
+            });
+        }
+        let rep = node
+            .handle_req(Request::FindNode(Key::random(TOKEN_KEY_LEN)), src)
+            .await;
+        assert!(matches!(rep, Reply::FindNode(nodes) if nodes.len() == 2));
+    }
+
+    #[tokio::test]
+    async fn retry_test() {
+        let client = start_node(&[]).await;
+        let server = start_node(&[]).await;
+
+        // a relay in front of the server that loses the first request
+        let proxy = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        let server_addr = server.node_info.addr;
+        tokio::spawn(async move {
+            let mut buf = [0; 65536];
+            let mut client_addr = None;
+            let mut dropped = false;
+            loop {
+                let (n, from) = proxy.recv_from(&mut buf).await.unwrap();
+                if from == server_addr {
+                    if let Some(addr) = client_addr {
+                        proxy.send_to(&buf[..n], addr).await.unwrap();
+                    }
+                } else if !dropped {
+                    dropped = true;
+                } else {
+                    client_addr = Some(from);
+                    proxy.send_to(&buf[..n], server_addr).await.unwrap();
+                }
+            }
+        });
+
+        let dst = NodeInfo {
+            addr: proxy_addr,
+            ..server.node_info.clone()
+        };
+        assert!(client.ping(dst.clone()).await.is_some());
+        assert!(client.routes.lock().await.contains(&dst));
+    }
+
+    #[tokio::test]
+    async fn request_limit_test() {
+        let kad = KadConfig {
+            timeout: Duration::from_millis(200),
+            max_concurrent_requests: 1,
+            ..KadConfig::default()
+        };
+        let mut nodes = Vec::new();
+        for _ in 0..2 {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut rpc = Rpc::new(socket);
+            rpc.set_kad_config(kad);
+            let (tx, _rx) = mpsc::unbounded_channel();
+            let rpc = Arc::new(Mutex::new(rpc));
+            nodes.push(Node::start(&network(), Key::random(TOKEN_KEY_LEN), rpc, tx, &[]).await);
+        }
+        let (client, server) = (&nodes[0], &nodes[1]);
+
+        // a server busy with one request drops the next one
+        let busy = server.handling.clone().try_acquire_owned().unwrap();
+        let dst = server.node_info.clone();
+        assert!(client.ping_raw(dst.clone()).await.recv().await.unwrap().is_none());
+        drop(busy);
+        assert!(client.ping_raw(dst).await.recv().await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn demote_test() {
+        let node = start_node(&[]).await;
+        let peer = NodeInfo {
+            id: Key::random(TOKEN_KEY_LEN),
+            addr: "127.0.0.1:1".parse().unwrap(),
+            net_id: TESTNET_USER_DHT.to_string(),
+        };
+        node.record_outcome(peer.clone(), Outcome::Answered).await;
+        assert!(node.routes.lock().await.contains(&peer));
+
+        // one timeout is forgiven, and an answer resets the count
+        node.record_outcome(peer.clone(), Outcome::TimedOut).await;
+        node.record_outcome(peer.clone(), Outcome::Answered).await;
+        node.record_outcome(peer.clone(), Outcome::TimedOut).await;
+        assert!(node.routes.lock().await.contains(&peer));
+        node.record_outcome(peer.clone(), Outcome::TimedOut).await;
+        assert!(!node.routes.lock().await.contains(&peer));
+
+        // a wrong reply demotes at once
+        node.record_outcome(peer.clone(), Outcome::Answered).await;
+        node.record_outcome(peer.clone(), Outcome::Unexpected).await;
+        assert!(!node.routes.lock().await.contains(&peer));
+
+        // peers out of the table are not counted, however many time out
+        for port in 2..1000 {
+            let stranger = NodeInfo {
+                id: Key::random(TOKEN_KEY_LEN),
+                addr: format!("127.0.0.1:{}", port).parse().unwrap(),
+                net_id: TESTNET_USER_DHT.to_string(),
+            };
+            node.record_outcome(stranger, Outcome::TimedOut).await;
+        }
+        assert!(node.failures.lock().await.is_empty());
+        node.assert_bounded().await;
+    }
+}
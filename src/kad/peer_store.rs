@@ -0,0 +1,271 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex as StdMutex};
+
+use super::key::Key;
+use super::routing::NodeInfo;
+use super::PEER_STORE_LIMIT;
+use crate::util::atomic_file;
+
+// What is known of a peer which answered at least once
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PeerRecord {
+    pub node_info: NodeInfo,
+    // seconds since the epoch
+    pub last_seen: u64,
+    // moving average of the round trips, in ms
+    pub rtt: u64,
+    pub successes: u32,
+    pub failures: u32,
+}
+
+impl PeerRecord {
+    // answered requests out of all, starting from an even guess so that one
+    // lucky answer does not beat a long record
+    pub fn success_rate(&self) -> f64 {
+        (self.successes as f64 + 1.0) / ((self.successes + self.failures) as f64 + 2.0)
+    }
+}
+
+// Every peer that answered, kept between runs, so that a node starts from the
+// peers it knows best rather than from the bootstrap servers.
+#[derive(Debug, Clone, Default)]
+pub struct PeerStore {
+    peers: HashMap<(String, Key), PeerRecord>,
+    // changed since the last take_changes
+    dirty: bool,
+}
+
+impl PeerStore {
+    pub fn new() -> PeerStore {
+        PeerStore::default()
+    }
+
+    // anything unreadable gives an empty store
+    pub fn from_bytes(bytes: &[u8]) -> PeerStore {
+        let records: Vec<PeerRecord> = serde_json::from_slice(bytes).unwrap_or_default();
+        PeerStore {
+            peers: records
+                .into_iter()
+                .map(|r| ((r.node_info.net_id.clone(), r.node_info.id.clone()), r))
+                .collect(),
+            dirty: false,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(&self.peers.values().collect::<Vec<_>>()).unwrap()
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    // the bytes to write if anything changed since the last call
+    pub fn take_changes(&mut self) -> Option<Vec<u8>> {
+        if !self.dirty {
+            return None;
+        }
+        self.dirty = false;
+        Some(self.to_bytes())
+    }
+
+    // for a write of take_changes which failed, so that the next one retries
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    pub fn record_success(&mut self, node_info: &NodeInfo, rtt: u64, now: u64) {
+        let key = (node_info.net_id.clone(), node_info.id.clone());
+        let record = self.peers.entry(key).or_insert_with(|| PeerRecord {
+            node_info: node_info.clone(),
+            last_seen: now,
+            rtt,
+            successes: 0,
+            failures: 0,
+        });
+        // a peer may come back at another address
+        record.node_info = node_info.clone();
+        record.last_seen = now;
+        record.rtt = (record.rtt * 3 + rtt) / 4;
+        record.successes = record.successes.saturating_add(1);
+        self.dirty = true;
+        if self.peers.len() > PEER_STORE_LIMIT {
+            self.evict();
+        }
+    }
+
+    // peers which never answered are not kept
+    pub fn record_failure(&mut self, node_info: &NodeInfo) {
+        let key = (node_info.net_id.clone(), node_info.id.clone());
+        if let Some(record) = self.peers.get_mut(&key) {
+            record.failures = record.failures.saturating_add(1);
+            self.dirty = true;
+        }
+    }
+
+    // the peers of net_id, best first: most reliable, then fastest, then latest
+    pub fn best(&self, net_id: &str, count: usize) -> Vec<&PeerRecord> {
+        let mut ret: Vec<&PeerRecord> = self
+            .peers
+            .values()
+            .filter(|r| r.node_info.net_id == net_id)
+            .collect();
+        ret.sort_by(|a, b| {
+            b.success_rate()
+                .partial_cmp(&a.success_rate())
+                .unwrap()
+                .then(a.rtt.cmp(&b.rtt))
+                .then(b.last_seen.cmp(&a.last_seen))
+        });
+        ret.truncate(count);
+        ret
+    }
+
+    // peers that answered at or after since
+    pub fn seen_since(&self, since: u64) -> usize {
+        self.peers.values().filter(|r| r.last_seen >= since).count()
+    }
+
+    // drops the least recently seen peer
+    fn evict(&mut self) {
+        let oldest = self
+            .peers
+            .iter()
+            .min_by_key(|(_, r)| r.last_seen)
+            .map(|(key, _)| key.clone());
+        if let Some(key) = oldest {
+            self.peers.remove(&key);
+        }
+    }
+}
+
+// A PeerStore kept in a file. Every answer changes the store, so rather than
+// writing each change, they pile up in memory until flush writes them at
+// once; the controller flushes on an interval and at shutdown.
+#[derive(Clone)]
+pub struct PeerStoreFile {
+    path: PathBuf,
+    store: Arc<StdMutex<PeerStore>>,
+}
+
+impl PeerStoreFile {
+    // starts empty if the file is missing or unreadable
+    pub async fn open(path: &Path) -> PeerStoreFile {
+        let store = PeerStore::from_bytes(&atomic_file::read(path).await.unwrap_or_default());
+        PeerStoreFile {
+            path: path.to_path_buf(),
+            store: Arc::new(StdMutex::new(store)),
+        }
+    }
+
+    pub fn store(&self) -> Arc<StdMutex<PeerStore>> {
+        self.store.clone()
+    }
+
+    // Writes the changes since the last flush, false if there were none. The
+    // write replaces the file atomically, so a crash in the middle leaves the
+    // last flushed store; a failed one keeps the changes for the next flush.
+    pub async fn flush(&self) -> io::Result<bool> {
+        let bytes = match self.store.lock().unwrap().take_changes() {
+            Some(bytes) => bytes,
+            None => return Ok(false),
+        };
+        match atomic_file::write(&self.path, &bytes).await {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                self.store.lock().unwrap().mark_dirty();
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kad::TOKEN_KEY_LEN;
+
+    #[test]
+    fn peer_store_test() {
+        let peer = |port| NodeInfo {
+            id: Key::random(TOKEN_KEY_LEN),
+            addr: format!("127.0.0.1:{}", port).parse().unwrap(),
+            net_id: "test".to_string(),
+        };
+        let (steady, fast, flaky) = (peer(1), peer(2), peer(3));
+        let mut store = PeerStore::new();
+        for _ in 0..4 {
+            store.record_success(&steady, 80, 100);
+            store.record_success(&fast, 10, 100);
+            store.record_success(&flaky, 5, 200);
+            store.record_failure(&flaky);
+        }
+        store.record_failure(&flaky);
+        // never answered, so not kept
+        store.record_failure(&peer(4));
+
+        let best: Vec<&NodeInfo> = store.best("test", 3).iter().map(|r| &r.node_info).collect();
+        assert_eq!(best, vec![&fast, &steady, &flaky]);
+        assert!(store.best("other", 3).is_empty());
+        assert_eq!(store.seen_since(150), 1);
+
+        let copy = PeerStore::from_bytes(&store.to_bytes());
+        assert_eq!(copy.len(), 3);
+        assert_eq!(copy.best("test", 1)[0].node_info, fast);
+    }
+
+    #[tokio::test]
+    async fn peer_store_file_test() {
+        let dir = std::env::temp_dir().join(format!("noktulo_peers_{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("peers");
+        let peer = |port| NodeInfo {
+            id: Key::random(TOKEN_KEY_LEN),
+            addr: format!("127.0.0.1:{}", port).parse().unwrap(),
+            net_id: "test".to_string(),
+        };
+        let (first, second, third) = (peer(1), peer(2), peer(3));
+
+        let file = PeerStoreFile::open(&path).await;
+        assert!(!file.flush().await.unwrap());
+        // batched: many changes, one write
+        for _ in 0..10 {
+            file.store().lock().unwrap().record_success(&first, 10, 100);
+        }
+        assert!(file.flush().await.unwrap());
+        assert!(!file.flush().await.unwrap());
+        file.store().lock().unwrap().record_success(&second, 10, 200);
+        assert!(file.flush().await.unwrap());
+        let len = |file: PeerStoreFile| file.store().lock().unwrap().len();
+
+        // a crash while writing leaves a torn temporary file, or at worst a
+        // damaged one, and the store of the flush before is read
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        tokio::fs::write(&tmp, b"noktulo:sum1torn").await.unwrap();
+        assert_eq!(len(PeerStoreFile::open(&path).await), 2);
+        tokio::fs::write(&path, b"noktulo:sum1damaged").await.unwrap();
+        assert_eq!(len(PeerStoreFile::open(&path).await), 1);
+
+        // a failed write keeps the changes for the next flush
+        file.store().lock().unwrap().record_success(&third, 10, 300);
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+        assert!(file.flush().await.is_err());
+        assert!(file.store().lock().unwrap().is_dirty());
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        assert!(file.flush().await.unwrap());
+        assert_eq!(len(PeerStoreFile::open(&path).await), 3);
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::{HashMap, VecDeque};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::{Duration, Instant};
+
+use super::key::Key;
+use super::node::Reply;
+
+// where the reply to a request goes; None when it timed out
+pub type ReplySender = UnboundedSender<Option<Reply>>;
+
+// Requests waiting for a reply, by token. An entry leaves when its reply
+// comes or it times out; whichever takes it out owns its sender, so a reply
+// racing a timeout is sent once. Entries left past the expiry, which means
+// their timeout was lost, and the oldest ones while the map is full are
+// taken out too, so that they cannot pile up.
+pub struct PendingMap {
+    entries: HashMap<Key, (ReplySender, Instant)>,
+    // tokens in the order they were inserted; entries gone already are
+    // skipped when met
+    order: VecDeque<(Key, Instant)>,
+    capacity: usize,
+    expiry: Duration,
+}
+
+impl PendingMap {
+    pub fn new(capacity: usize, expiry: Duration) -> PendingMap {
+        PendingMap {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+            expiry,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn contains(&self, token: &Key) -> bool {
+        self.entries.contains_key(token)
+    }
+
+    // returns the senders of the oldest entries, which went to make room
+    pub fn insert(&mut self, token: Key, tx: ReplySender, now: Instant) -> Vec<ReplySender> {
+        let mut evicted = Vec::new();
+        while self.entries.len() >= self.capacity {
+            match self.order.pop_front() {
+                Some((old, inserted_at)) => {
+                    if let Some(tx) = self.take(&old, inserted_at) {
+                        evicted.push(tx);
+                    }
+                }
+                None => break,
+            }
+        }
+        self.order.push_back((token.clone(), now));
+        self.entries.insert(token, (tx, now));
+        evicted
+    }
+
+    // the sender of token, for its reply or its timeout
+    pub fn complete(&mut self, token: &Key) -> Option<ReplySender> {
+        self.entries.remove(token).map(|(tx, _)| tx)
+    }
+
+    // takes out the entries older than the expiry
+    pub fn expire(&mut self, now: Instant) -> Vec<ReplySender> {
+        let mut ret = Vec::new();
+        while let Some((token, inserted_at)) = self.order.front().cloned() {
+            if self.entries.get(&token).map(|(_, at)| *at) == Some(inserted_at)
+                && now.saturating_duration_since(inserted_at) < self.expiry
+            {
+                break;
+            }
+            self.order.pop_front();
+            if let Some(tx) = self.take(&token, inserted_at) {
+                ret.push(tx);
+            }
+        }
+        ret
+    }
+
+    // only the entry inserted at that time, not a later one under the same token
+    fn take(&mut self, token: &Key, inserted_at: Instant) -> Option<ReplySender> {
+        match self.entries.get(token) {
+            Some((_, at)) if *at == inserted_at => self.complete(token),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kad::TOKEN_KEY_LEN;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn pending_map_test() {
+        let start = Instant::now();
+        let expiry = Duration::from_secs(10);
+        let mut pending = PendingMap::new(4, expiry);
+
+        // a reply storm: every token is answered once, repeats and unknown
+        // tokens find nothing, and receivers which went away leave no entry
+        let tokens: Vec<_> = (0..4).map(|_| Key::random(TOKEN_KEY_LEN)).collect();
+        for token in &tokens {
+            let (tx, rx) = mpsc::unbounded_channel();
+            drop(rx);
+            assert!(pending.insert(token.clone(), tx, start).is_empty());
+        }
+        for token in tokens.iter().chain(&tokens) {
+            if let Some(tx) = pending.complete(token) {
+                assert!(tx.send(None).is_err());
+            }
+        }
+        assert!(pending.complete(&Key::random(TOKEN_KEY_LEN)).is_none());
+        assert_eq!(pending.len(), 0);
+
+        // full: the oldest goes
+        let tokens: Vec<_> = (0..5).map(|_| Key::random(TOKEN_KEY_LEN)).collect();
+        let mut evicted = 0;
+        for (i, token) in tokens.iter().enumerate() {
+            let now = start + Duration::from_millis(i as u64);
+            evicted += pending.insert(token.clone(), mpsc::unbounded_channel().0, now).len();
+        }
+        assert_eq!(evicted, 1);
+        assert!(!pending.contains(&tokens[0]));
+        assert_eq!(pending.len(), 4);
+
+        // a reply racing the timeout: whichever comes first takes the sender
+        let tx = pending.complete(&tokens[1]);
+        assert!(tx.is_some());
+        assert!(pending.complete(&tokens[1]).is_none());
+
+        // entries whose timeout was lost expire, the others are kept
+        assert_eq!(pending.expire(start + expiry + Duration::from_millis(2)).len(), 1);
+        assert!(pending.complete(&tokens[2]).is_none());
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending.expire(start + expiry * 2).len(), 2);
+        assert_eq!(pending.len(), 0);
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+
+use super::node::{Reply, Request};
+use super::rpc::Message;
+use super::{PRIORITY_WEIGHTS, QUEUE_LIMIT};
+
+// What a message is for. Control traffic keeps routing tables alive and lookups
+// find peers and values, so when messages queue up both get more turns than
+// bulk data like stores and multicasts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Control,
+    Lookup,
+    Bulk,
+}
+
+impl Priority {
+    pub fn of(msg: &Message) -> Priority {
+        match msg {
+            Message::Kill => Priority::Control,
+            Message::Request(Request::Ping)
+            | Message::Request(Request::Join(_))
+            | Message::Request(Request::Leave(_)) => Priority::Control,
+            Message::Request(Request::FindNode(_)) | Message::Request(Request::FindValue(_)) => {
+                Priority::Lookup
+            }
+            Message::Request(_) => Priority::Bulk,
+            // a reply is all its request waits for, and stores are answered with Ping
+            Message::Reply(Reply::Ping) => Priority::Control,
+            Message::Reply(_) => Priority::Lookup,
+        }
+    }
+}
+
+// First in, first out within a priority. Each priority holds at most limit
+// items, and what comes beyond it is dropped. While several have items, they
+// take turns by PRIORITY_WEIGHTS, so bulk data is slowed down but never starved.
+pub struct PriorityQueue<T> {
+    queues: [VecDeque<T>; 3],
+    // turns left to each priority in this round
+    credits: [u32; 3],
+    limit: usize,
+}
+
+impl<T> PriorityQueue<T> {
+    pub fn new() -> PriorityQueue<T> {
+        PriorityQueue::with_limit(QUEUE_LIMIT)
+    }
+
+    pub fn with_limit(limit: usize) -> PriorityQueue<T> {
+        PriorityQueue {
+            queues: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            credits: PRIORITY_WEIGHTS,
+            limit,
+        }
+    }
+
+    // false if the item was dropped, its priority being full
+    pub fn push(&mut self, priority: Priority, item: T) -> bool {
+        let queue = &mut self.queues[priority as usize];
+        if queue.len() >= self.limit {
+            return false;
+        }
+        queue.push_back(item);
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        let queues = &self.queues;
+        let turn = |credits: &[u32; 3]| (0..3).find(|i| credits[*i] > 0 && !queues[*i].is_empty());
+        // a new round once every priority with items has used its turns
+        let i = match turn(&self.credits) {
+            Some(i) => i,
+            None => {
+                self.credits = PRIORITY_WEIGHTS;
+                turn(&self.credits)?
+            }
+        };
+        self.credits[i] -= 1;
+        self.queues[i].pop_front()
+    }
+}
+
+impl<T> Default for PriorityQueue<T> {
+    fn default() -> PriorityQueue<T> {
+        PriorityQueue::new()
+    }
+}
+
+// A PriorityQueue between tasks; pop waits for an item
+pub struct SharedQueue<T> {
+    queue: Mutex<PriorityQueue<T>>,
+    ready: Notify,
+}
+
+impl<T> SharedQueue<T> {
+    pub fn new() -> SharedQueue<T> {
+        SharedQueue {
+            queue: Mutex::new(PriorityQueue::new()),
+            ready: Notify::new(),
+        }
+    }
+
+    // false if the item was dropped, its priority being full
+    pub fn push(&self, priority: Priority, item: T) -> bool {
+        let pushed = self.queue.lock().unwrap().push(priority, item);
+        if pushed {
+            self.ready.notify_one();
+        }
+        pushed
+    }
+
+    pub async fn pop(&self) -> T {
+        loop {
+            if let Some(item) = self.queue.lock().unwrap().pop() {
+                return item;
+            }
+            // a push since the pop above leaves a permit, so none is missed
+            self.ready.notified().await;
+        }
+    }
+}
+
+impl<T> Default for SharedQueue<T> {
+    fn default() -> SharedQueue<T> {
+        SharedQueue::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kad::{Key, TOKEN_KEY_LEN};
+
+    #[test]
+    fn priority_queue_test() {
+        let store = Message::Request(Request::Store(Key::random(TOKEN_KEY_LEN), vec![0; 64]));
+        let find = Message::Request(Request::FindNode(Key::random(TOKEN_KEY_LEN)));
+        let ping = Message::Request(Request::Ping);
+        assert_eq!(Priority::of(&store), Priority::Bulk);
+        assert_eq!(Priority::of(&find), Priority::Lookup);
+        assert_eq!(Priority::of(&Message::Reply(Reply::Ping)), Priority::Control);
+
+        let mut queue = PriorityQueue::new();
+        for (i, msg) in [&store, &store, &find, &ping, &find].iter().enumerate() {
+            assert!(queue.push(Priority::of(msg), i));
+        }
+        let order: Vec<usize> = std::iter::from_fn(|| queue.pop()).collect();
+        assert_eq!(order, vec![3, 2, 4, 0, 1]);
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn queue_limit_test() {
+        let mut queue = PriorityQueue::with_limit(2);
+        assert!(queue.push(Priority::Bulk, 0));
+        assert!(queue.push(Priority::Bulk, 1));
+        assert!(!queue.push(Priority::Bulk, 2));
+        // each priority has its own limit
+        assert!(queue.push(Priority::Control, 3));
+        let order: Vec<usize> = std::iter::from_fn(|| queue.pop()).collect();
+        assert_eq!(order, vec![3, 0, 1]);
+    }
+
+    #[test]
+    fn starvation_test() {
+        let mut queue = PriorityQueue::with_limit(1000);
+        for i in 0..100 {
+            queue.push(Priority::Control, i);
+            queue.push(Priority::Bulk, 1000 + i);
+        }
+        // control keeps coming, yet bulk gets one turn in each round
+        let round = PRIORITY_WEIGHTS.iter().sum::<u32>() as usize;
+        let first: Vec<usize> = (0..round).filter_map(|_| queue.pop()).collect();
+        assert_eq!(
+            first.iter().filter(|i| **i >= 1000).count(),
+            PRIORITY_WEIGHTS[Priority::Bulk as usize] as usize
+        );
+    }
+}
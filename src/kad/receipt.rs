@@ -0,0 +1,92 @@
+use serde::{Deserialize, Serialize};
+use serde_big_array::BigArray;
+use std::convert::TryInto;
+
+use crate::crypto::{PublicKey, SecretKey};
+
+use super::key::Key;
+
+const RECEIPT_MAGIC: &[u8] = b"noktulo:receipt:";
+
+// A node's signed statement that it accepted a multicast to a region it is
+// in, as of accepted_at (seconds since the epoch). Publishers keep them to
+// audit which regions take their posts. The key is the node's own, kept
+// between runs next to Config.node_id_path, so receipts tell nodes apart,
+// before and after a restart, but not who runs them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeliveryReceipt {
+    pub node_id: Key,
+    pub pubkey: PublicKey,
+    pub prefix: Key,
+    // 32 bytes of the SHA3-512 of the message
+    pub hash: Key,
+    pub accepted_at: u64,
+    #[serde(with = "BigArray")]
+    pub signature: [u8; 64],
+}
+
+impl DeliveryReceipt {
+    pub fn sign(
+        sk: &SecretKey,
+        node_id: &Key,
+        prefix: &Key,
+        msg: &[u8],
+        accepted_at: u64,
+    ) -> DeliveryReceipt {
+        let hash = DeliveryReceipt::hash_of(msg);
+        let signed = DeliveryReceipt::signed_message(node_id, prefix, &hash, accepted_at);
+        DeliveryReceipt {
+            node_id: node_id.clone(),
+            pubkey: sk.public_key(),
+            prefix: prefix.clone(),
+            hash,
+            accepted_at,
+            signature: sk.sign(&signed),
+        }
+    }
+
+    pub fn hash_of(msg: &[u8]) -> Key {
+        Key::hash(msg, 32)
+    }
+
+    // magic | node_id | prefix | hash | accepted_at(8, BE), the ids with
+    // their lengths in front
+    pub fn signed_message(node_id: &Key, prefix: &Key, hash: &Key, accepted_at: u64) -> Vec<u8> {
+        let mut message = RECEIPT_MAGIC.to_vec();
+        for key in [node_id, prefix, hash] {
+            message.push(key.len().try_into().unwrap_or(u8::MAX));
+            message.extend_from_slice(key.as_bytes());
+        }
+        message.extend_from_slice(&accepted_at.to_be_bytes());
+        message
+    }
+
+    // whether it is a receipt for msg multicast to prefix
+    pub fn verify(&self, prefix: &Key, msg: &[u8]) -> bool {
+        if self.prefix != *prefix || self.hash != DeliveryReceipt::hash_of(msg) {
+            return false;
+        }
+        let signed =
+            DeliveryReceipt::signed_message(&self.node_id, &self.prefix, &self.hash, self.accepted_at);
+        self.pubkey.verify(&self.signature, &signed).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn receipt_test() {
+        let sk = SecretKey::random();
+        let (node_id, prefix) = (Key::from([1u8; 32]), Key::from([1u8; 4]));
+        let receipt = DeliveryReceipt::sign(&sk, &node_id, &prefix, b"post", 100);
+        assert!(receipt.verify(&prefix, b"post"));
+        assert!(!receipt.verify(&prefix, b"another post"));
+        assert!(!receipt.verify(&Key::from([2u8; 4]), b"post"));
+
+        let mut forged = receipt.clone();
+        forged.accepted_at = 200;
+        assert!(!forged.verify(&prefix, b"post"));
+    }
+}
@@ -1,5 +1,4 @@
 use super::key::Key;
-use super::K_PARAM;
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
 use std::vec::Vec;
@@ -14,12 +13,13 @@ pub struct NodeInfo {
 #[derive(Debug)]
 pub struct RoutingTable {
     key_len: usize,
+    bucket_size: usize,
     node_info: NodeInfo,
     buckets: Vec<Vec<NodeInfo>>,
 }
 
 impl RoutingTable {
-    pub fn new(node_info: &NodeInfo, key_len: usize) -> RoutingTable {
+    pub fn new(node_info: &NodeInfo, key_len: usize, bucket_size: usize) -> RoutingTable {
         assert_eq!(node_info.id.len(), key_len);
         let mut buckets = Vec::new();
         for _ in 0..key_len * 8 {
@@ -27,6 +27,7 @@ impl RoutingTable {
         }
         let mut ret = RoutingTable {
             key_len,
+            bucket_size,
             node_info: node_info.clone(),
             buckets,
         };
@@ -45,7 +46,7 @@ impl RoutingTable {
                 bucket.push(tmp);
             }
             None => {
-                if bucket.len() < K_PARAM {
+                if bucket.len() < self.bucket_size {
                     bucket.push(node_info);
                 } else {
                     // if bucket is full, return the first element, and caller pings the node and re-update routes
@@ -57,6 +58,19 @@ impl RoutingTable {
         None
     }
 
+    pub fn contains(&self, node_info: &NodeInfo) -> bool {
+        assert_eq!(self.key_len, node_info.id.len());
+        self.contains_id(&node_info.id)
+    }
+
+    pub fn contains_id(&self, id: &Key) -> bool {
+        if id.len() != self.key_len {
+            return false;
+        }
+        let bucket_index = self.lookup_bucket_index(id.clone());
+        self.buckets[bucket_index].iter().any(|x| x.id == *id)
+    }
+
     pub fn closest_nodes(&self, item: Key, count: usize) -> Vec<(NodeInfo, Key)> {
         assert_eq!(self.key_len, item.len());
         if count == 0 {
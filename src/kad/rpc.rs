@@ -1,33 +1,61 @@
 use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
 use serde_json;
-use std::collections::HashMap;
 use std::io;
-use std::net::SocketAddr;
-use std::str;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::sync::Mutex as StdMutex;
+use thiserror::Error;
 use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream, UdpSocket};
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use tokio::sync::Mutex;
-use tokio::time::{sleep, Duration};
+use tokio::time::Duration;
 
+use super::bandwidth::BandwidthLimit;
+use super::config::KadConfig;
+use super::guard::PeerGuard;
 use super::key::Key;
 use super::node::{Reply, Request};
+use super::peer_store::PeerStore;
+use super::pending::PendingMap;
+use super::priority::{Priority, SharedQueue};
 use super::routing::NodeInfo;
 
-use super::{MESSAGE_LEN, TIME_OUT, TOKEN_KEY_LEN};
+use super::{
+    MESSAGE_LEN, PENDING_EXPIRY, PENDING_LIMIT, PROTOCOL_VERSION, RETRY_ATTEMPTS, RETRY_BACKOFF,
+    TIME_OUT, TOKEN_KEY_LEN,
+};
+use crate::crypto::SecretKey;
 use crate::service::*;
+use crate::util::clock::{Clock, SystemClock};
+use crate::util::rng::{Entropy, SystemEntropy};
+use crate::util::stats::{PendingEvent, StatsRecorder};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RpcMessage {
+    // absent in the first wire format, which is version 0
+    #[serde(default, skip_serializing_if = "is_legacy")]
+    version: u32,
     token: Key,
     src: NodeInfo,
     dst: NodeInfo,
     msg: Message,
 }
 
+fn is_legacy(version: &u32) -> bool {
+    *version == 0
+}
+
+// Just enough of a message to tell which version it is in.
+#[derive(Deserialize)]
+struct Header {
+    #[serde(default)]
+    version: u32,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Message {
     Kill,
@@ -36,6 +64,7 @@ pub enum Message {
 }
 
 pub struct ReqHandle {
+    version: u32,
     token: Key,
     src: NodeInfo,
     req: Request,
@@ -52,7 +81,9 @@ impl ReqHandle {
     }
 
     pub async fn rep(self, rep: Reply, src: NodeInfo) {
+        // answer older peers in their own format
         let rep_rmsg = RpcMessage {
+            version: self.version.min(PROTOCOL_VERSION),
             token: self.token,
             src,
             dst: self.src.clone(),
@@ -62,21 +93,192 @@ impl ReqHandle {
     }
 }
 
+// How Node retries requests which time out. A reply of any kind is not retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    // sends in total, the first one included
+    pub attempts: u32,
+    // before the first retry, doubled for each one after it
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            attempts: RETRY_ATTEMPTS,
+            backoff: Duration::from_millis(RETRY_BACKOFF),
+            max_backoff: Duration::from_millis(RETRY_BACKOFF * 8),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Rpc {
     pub socket: Arc<UdpSocket>,
     is_start: Arc<Mutex<bool>>,
-    pending: Arc<Mutex<HashMap<Key, UnboundedSender<Option<Reply>>>>>,
+    pending: Arc<Mutex<PendingMap>>,
     node_infos: Arc<Mutex<Vec<(NodeInfo, UnboundedSender<ReqHandle>)>>>,
+    clock: Arc<dyn Clock>,
+    entropy: Arc<dyn Entropy>,
+    stats: Arc<StatsRecorder>,
+    peer_store: Arc<StdMutex<PeerStore>>,
+    bandwidth: Arc<BandwidthLimit>,
+    // bytes each node started on this Rpc stores at most
+    store_limit: Option<usize>,
+    guard: Arc<Mutex<PeerGuard>>,
+    retry: RetryPolicy,
+    kad: KadConfig,
+    // signs the delivery receipts of the nodes started on this Rpc; None
+    // gives each of them a key of its own for the run
+    receipt_key: Option<Arc<SecretKey>>,
+    // encoded messages waiting for the socket, and whether a task sends them
+    outbox: Arc<SharedQueue<(Vec<u8>, SocketAddr, String)>>,
+    sending: Arc<AtomicBool>,
+    prioritized: bool,
 }
 
 impl Rpc {
     pub fn new(socket: UdpSocket) -> Rpc {
+        Rpc::with_clock(socket, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(socket: UdpSocket, clock: Arc<dyn Clock>) -> Rpc {
+        Rpc {
+            socket: Arc::new(socket),
+            is_start: Arc::new(Mutex::new(false)),
+            pending: Arc::new(Mutex::new(Rpc::pending_map(Duration::from_millis(TIME_OUT)))),
+            node_infos: Arc::new(Mutex::new(Vec::new())),
+            clock,
+            entropy: Arc::new(SystemEntropy),
+            stats: Arc::new(StatsRecorder::new()),
+            peer_store: Arc::new(StdMutex::new(PeerStore::new())),
+            bandwidth: Arc::new(BandwidthLimit::new()),
+            store_limit: None,
+            guard: Arc::new(Mutex::new(PeerGuard::new())),
+            retry: RetryPolicy::default(),
+            kad: KadConfig::default(),
+            receipt_key: None,
+            outbox: Arc::new(SharedQueue::new()),
+            sending: Arc::new(AtomicBool::new(false)),
+            prioritized: true,
+        }
+    }
+
+    // An Rpc on another socket, sharing the clock, entropy, stats, peers, bans,
+    // limits, retry policy and Kademlia parameters, so that one DHT's traffic
+    // does not queue behind another's.
+    pub fn sibling(&self, socket: UdpSocket) -> Rpc {
         Rpc {
             socket: Arc::new(socket),
             is_start: Arc::new(Mutex::new(false)),
-            pending: Arc::new(Mutex::new(HashMap::new())),
+            pending: Arc::new(Mutex::new(Rpc::pending_map(self.kad.timeout))),
             node_infos: Arc::new(Mutex::new(Vec::new())),
+            clock: self.clock.clone(),
+            entropy: self.entropy.clone(),
+            stats: self.stats.clone(),
+            peer_store: self.peer_store.clone(),
+            bandwidth: self.bandwidth.clone(),
+            store_limit: self.store_limit,
+            guard: self.guard.clone(),
+            retry: self.retry,
+            kad: self.kad,
+            receipt_key: self.receipt_key.clone(),
+            outbox: Arc::new(SharedQueue::new()),
+            sending: Arc::new(AtomicBool::new(false)),
+            prioritized: self.prioritized,
+        }
+    }
+
+    fn pending_map(timeout: Duration) -> PendingMap {
+        PendingMap::new(PENDING_LIMIT, timeout * PENDING_EXPIRY)
+    }
+
+    // requests waiting for a reply on this socket
+    pub async fn pending_len(&self) -> usize {
+        self.pending.lock().await.len()
+    }
+
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+
+    // what tokens and the ids of nodes started on this Rpc are drawn from
+    pub fn entropy(&self) -> Arc<dyn Entropy> {
+        self.entropy.clone()
+    }
+
+    pub fn set_entropy(&mut self, entropy: Arc<dyn Entropy>) {
+        self.entropy = entropy;
+    }
+
+    pub fn stats(&self) -> Arc<StatsRecorder> {
+        self.stats.clone()
+    }
+
+    pub fn receipt_key(&self) -> Option<Arc<SecretKey>> {
+        self.receipt_key.clone()
+    }
+
+    // Set before the nodes are started, for receipts which name the same
+    // key after a restart
+    pub fn set_receipt_key(&mut self, key: Arc<SecretKey>) {
+        self.receipt_key = Some(key);
+    }
+
+    // every peer that answered a request, on any socket
+    pub fn peer_store(&self) -> Arc<StdMutex<PeerStore>> {
+        self.peer_store.clone()
+    }
+
+    // Set before the nodes are made, to carry the peers of earlier runs
+    pub fn set_peer_store(&mut self, peer_store: Arc<StdMutex<PeerStore>>) {
+        self.peer_store = peer_store;
+    }
+
+    // outgoing bytes per second of this Rpc and its siblings together
+    pub fn set_bandwidth_limit(&self, rate: Option<u64>) {
+        self.bandwidth.set_rate(rate);
+    }
+
+    pub fn store_limit(&self) -> Option<usize> {
+        self.store_limit
+    }
+
+    // Set before the nodes are started
+    pub fn set_store_limit(&mut self, limit: Option<usize>) {
+        self.store_limit = limit;
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        self.retry
+    }
+
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+
+    pub fn kad_config(&self) -> KadConfig {
+        self.kad
+    }
+
+    // Set before the nodes are made and the server started, which read it then
+    pub fn set_kad_config(&mut self, kad: KadConfig) {
+        self.kad = kad;
+        self.pending = Arc::new(Mutex::new(Rpc::pending_map(kad.timeout)));
+    }
+
+    // Off, messages are sent and dispatched in the order they come, as before
+    // priorities; only for measuring what they save. Set before starting.
+    pub fn set_prioritized(&mut self, prioritized: bool) {
+        self.prioritized = prioritized;
+    }
+
+    fn priority(&self, msg: &Message) -> Priority {
+        if self.prioritized {
+            Priority::of(msg)
+        } else {
+            Priority::Bulk
         }
     }
 
@@ -84,21 +286,65 @@ impl Rpc {
         let mut is_start = self.is_start.lock().await;
         if !(*is_start) {
             *is_start = true;
+            // Datagrams are read and decoded as they come, then dispatched
+            // by priority, so that pings and lookups do not wait behind a
+            // burst of stores.
+            let inbox = Arc::new(SharedQueue::new());
+            let closed = Arc::new(AtomicBool::new(false));
             let rpc = self.clone();
+            let (reader_inbox, reader_closed) = (inbox.clone(), closed.clone());
             tokio::spawn(async move {
-                loop {
-                    let mut buf = [0; MESSAGE_LEN];
-                    let (len, src_addr) = rpc.socket.recv_from(&mut buf).await.unwrap();
-                    let mut rmsg: RpcMessage;
-                    match serde_json::from_str(str::from_utf8(&buf[..len]).unwrap()) {
-                        Ok(e) => rmsg = e,
-                        Err(_) => {
-                            warn!("Message with invalid json, ignoring.");
+                while !reader_closed.load(Ordering::Relaxed) {
+                    // one spare byte to tell an oversized datagram from a full one
+                    let mut buf = vec![0; rpc.kad.message_len + 1];
+                    let (len, src_addr) = match rpc.socket.recv_from(&mut buf).await {
+                        Ok(e) => e,
+                        Err(e) => {
+                            warn!("Failed to receive message: {}", e);
+                            continue;
+                        }
+                    };
+                    rpc.stats.record_received(len, src_addr);
+                    if rpc
+                        .guard
+                        .lock()
+                        .await
+                        .is_banned(src_addr.ip(), rpc.clock.now())
+                    {
+                        continue;
+                    }
+
+                    let mut rmsg = match decode(&buf[..len], rpc.kad.message_len) {
+                        Ok(e) => e,
+                        Err(DecodeError::Unsupported(version)) => {
+                            // Not counted as malformed, but anyone can claim a
+                            // newer version, so it counts towards a ban all the same.
+                            info!(
+                                "Message in unsupported protocol version {} from {}, ignoring.",
+                                version, src_addr
+                            );
+                            rpc.stats.record_peer_version(src_addr, version);
+                            rpc.strike(src_addr).await;
+                            continue;
+                        }
+                        Err(e) => {
+                            warn!("Malformed message from {}, ignoring: {}", src_addr, e);
+                            rpc.record_malformed(src_addr).await;
                             continue;
                         }
                     };
                     rmsg.src.addr = src_addr;
+                    rpc.stats.record_peer_version(src_addr, rmsg.version);
+                    if !reader_inbox.push(rpc.priority(&rmsg.msg), (rmsg, len)) {
+                        rpc.stats.record_dropped();
+                    }
+                }
+            });
 
+            let rpc = self.clone();
+            tokio::spawn(async move {
+                loop {
+                    let (rmsg, len) = inbox.pop().await;
                     debug!(
                         "|  IN | {:?} {:?} <== {:?}",
                         rmsg.token, rmsg.msg, rmsg.src.id
@@ -116,6 +362,15 @@ impl Rpc {
                                 warn!("Message from different net_id received, ignoring.");
                                 continue;
                             }
+                            rpc.stats.record_net_received(&node_info.0.net_id, len);
+                            if rmsg.src.id.len() != node_info.0.id.len() {
+                                warn!(
+                                    "Message with invalid source id from {}, ignoring.",
+                                    rmsg.src.addr
+                                );
+                                rpc.record_malformed(rmsg.src.addr).await;
+                                continue;
+                            }
 
                             match rmsg.msg {
                                 Message::Kill => {
@@ -123,12 +378,13 @@ impl Rpc {
                                 }
                                 Message::Request(req) => {
                                     let req_handle = ReqHandle {
+                                        version: rmsg.version,
                                         token: rmsg.token,
                                         src: rmsg.src,
                                         req,
                                         rpc: rpc.clone(),
                                     };
-                                    if let Err(_) = node_info.1.send(req_handle) {
+                                    if node_info.1.send(req_handle).is_err() {
                                         info!("Closing channel, since receiver is dead.");
                                         node_infos.swap_remove(index);
                                     }
@@ -143,6 +399,7 @@ impl Rpc {
                                 "Message received, but dst id does not match any nodes, ignoring."
                             );
                             if node_infos.is_empty() {
+                                closed.store(true, Ordering::Relaxed);
                                 break;
                             } else {
                                 continue;
@@ -177,35 +434,78 @@ impl Rpc {
         drop(node_infos);
     }
 
+    async fn record_malformed(&self, addr: SocketAddr) {
+        self.stats.record_malformed();
+        self.strike(addr).await;
+    }
+
+    // a message from addr that could not be used, towards banning it
+    async fn strike(&self, addr: SocketAddr) {
+        let mut guard = self.guard.lock().await;
+        if guard.record_malformed(addr.ip(), self.clock.now()) {
+            warn!(
+                "Too many malformed messages from {}, banning it for a while.",
+                addr.ip()
+            );
+            self.stats.record_ban();
+        }
+    }
+
+    // drops what ip sends for BAN_TIME, on every sibling of this Rpc
+    pub async fn ban(&self, ip: IpAddr) {
+        self.guard.lock().await.ban(ip, self.clock.now());
+        warn!("Banning {} for a while.", ip);
+        self.stats.record_ban();
+    }
+
     async fn handle_rep(self, token: Key, rep: Reply) {
         tokio::spawn(async move {
-            let mut pending = self.pending.lock().await;
-            let send_res = match pending.get(&token) {
+            let tx = self.pending.lock().await.complete(&token);
+            match tx {
                 Some(tx) => {
                     info!("Reply received: {:?}", token);
-                    tx.send(Some(rep))
-                }
-                None => {
-                    warn!("Unsolicited reply received, ignoring: {:?}", token);
-                    return;
+                    self.stats.record_pending(PendingEvent::Answered);
+                    // the requester may have stopped waiting
+                    let _ = tx.send(Some(rep));
                 }
-            };
-            if let Ok(_) = send_res {
-                pending.remove(&token);
+                None => warn!("Unsolicited reply received, ignoring: {:?}", token),
             }
         });
     }
 
+    // queued by priority; one task per socket sends them
     async fn send_msg(&self, rmsg: &RpcMessage, addr: SocketAddr) {
-        let enc_msg = serde_json::to_string(rmsg).unwrap();
-        self.socket
-            .send_to(&enc_msg.as_bytes(), addr)
-            .await
-            .unwrap();
+        let enc_msg = serde_json::to_vec(rmsg).unwrap();
         debug!(
             "| OUT | {:?} {:?} ==> {:?} ",
             rmsg.token, rmsg.msg, rmsg.dst.id
         );
+        let pushed = self.outbox.push(
+            self.priority(&rmsg.msg),
+            (enc_msg, addr, rmsg.src.net_id.clone()),
+        );
+        if !pushed {
+            self.stats.record_dropped();
+        }
+        if !self.sending.swap(true, Ordering::Relaxed) {
+            let (socket, outbox) = (self.socket.clone(), self.outbox.clone());
+            let (stats, bandwidth, clock) =
+                (self.stats.clone(), self.bandwidth.clone(), self.clock.clone());
+            tokio::spawn(async move {
+                loop {
+                    let (enc_msg, addr, net_id) = outbox.pop().await;
+                    if let Some(wait) = bandwidth.reserve(clock.now(), enc_msg.len()) {
+                        clock.sleep(wait).await;
+                    }
+                    if let Err(e) = socket.send_to(&enc_msg, addr).await {
+                        warn!("Failed to send message to {}: {}", addr, e);
+                        continue;
+                    }
+                    stats.record_sent(enc_msg.len());
+                    stats.record_net_sent(&net_id, enc_msg.len());
+                }
+            });
+        }
     }
 
     pub async fn send_req(
@@ -216,12 +516,22 @@ impl Rpc {
     ) -> UnboundedReceiver<Option<Reply>> {
         let (tx, rx) = mpsc::unbounded_channel();
         let mut pending = self.pending.lock().await;
-        let mut token = Key::random(TOKEN_KEY_LEN);
-        while pending.contains_key(&token) {
-            token = Key::random(TOKEN_KEY_LEN);
+        let now = self.clock.now();
+        for tx in pending.expire(now) {
+            warn!("A pending request lost its timeout");
+            self.stats.record_pending(PendingEvent::Leaked);
+            let _ = tx.send(None);
+        }
+        let mut token = Key::random_from(TOKEN_KEY_LEN, self.entropy.as_ref());
+        while pending.contains(&token) {
+            token = Key::random_from(TOKEN_KEY_LEN, self.entropy.as_ref());
+        }
+        for tx in pending.insert(token.clone(), tx, now) {
+            self.stats.record_pending(PendingEvent::Evicted);
+            let _ = tx.send(None);
         }
-        pending.insert(token.clone(), tx.clone());
         drop(pending);
+        self.stats.record_pending(PendingEvent::Inserted);
 
         let node_infos = self.node_infos.lock().await;
         if let None = node_infos.iter().find(|(x, _)| *x == src) {
@@ -229,7 +539,9 @@ impl Rpc {
         }
         drop(node_infos);
 
+        let net_id = src.net_id.clone();
         let rmsg = RpcMessage {
+            version: PROTOCOL_VERSION,
             token: token.clone(),
             src,
             dst,
@@ -239,34 +551,51 @@ impl Rpc {
 
         let rpc = self.clone();
         let token = token.clone();
+        let timeout = self.clock.sleep(self.kad.timeout);
         tokio::spawn(async move {
-            sleep(Duration::from_millis(TIME_OUT)).await;
-            if let Ok(_) = tx.send(None) {
-                let mut pending = rpc.pending.lock().await;
-                if let Some(_) = pending.remove(&token) {
-                    info!("Removed pending token: {:?}", token);
-                };
+            timeout.await;
+            let tx = rpc.pending.lock().await.complete(&token);
+            if let Some(tx) = tx {
+                info!("Removed pending token: {:?}", token);
+                rpc.stats.record_pending(PendingEvent::TimedOut);
+                rpc.stats.record_net_timeout(&net_id);
+                let _ = tx.send(None);
             }
         });
         rx
     }
 
-    async fn node_infos(&self) -> Vec<NodeInfo> {
+    // the nodes served on this socket
+    pub async fn node_infos(&self) -> Vec<NodeInfo> {
         let node_infos = self.node_infos.lock().await;
         node_infos.iter().map(|(ni, _)| ni.clone()).collect()
     }
 
     pub async fn start_nodeinfo_server(&self, addr: SocketAddr) -> io::Result<()> {
-        let rpc = self.clone();
+        Rpc::serve_nodeinfo(vec![self.clone()], addr).await
+    }
+
+    // serves the nodes of every rpc, for DHTs on separate sockets
+    pub async fn serve_nodeinfo(rpcs: Vec<Rpc>, addr: SocketAddr) -> io::Result<()> {
         let listener = TcpListener::bind(addr).await?;
         tokio::spawn(async move {
             loop {
-                let (socket, _) = listener.accept().await.unwrap();
-                let rpc = rpc.clone();
+                let socket = match listener.accept().await {
+                    Ok((socket, _)) => socket,
+                    Err(e) => {
+                        warn!("Failed to accept a nodeinfo connection: {}", e);
+                        continue;
+                    }
+                };
+                let rpcs = rpcs.clone();
                 tokio::spawn(async move {
-                    let mut stream = BufReader::new(socket);
+                    // a first line longer than a message is not a request
+                    let mut stream = BufReader::new(socket.take(MESSAGE_LEN as u64));
                     let mut first_line = String::new();
-                    stream.read_line(&mut first_line).await.unwrap();
+                    if let Err(e) = stream.read_line(&mut first_line).await {
+                        debug!("Failed to read a nodeinfo request: {}", e);
+                        return;
+                    }
 
                     let mut params = first_line.split_whitespace();
                     let method = params.next();
@@ -274,7 +603,10 @@ impl Rpc {
 
                     match (method, query) {
                         (Some("GET"), Some(query)) => {
-                            let mut node_infos = rpc.node_infos().await;
+                            let mut node_infos = Vec::new();
+                            for rpc in rpcs.iter() {
+                                node_infos.append(&mut rpc.node_infos().await);
+                            }
                             match query {
                                 "test" => {
                                     node_infos = node_infos
@@ -299,27 +631,33 @@ impl Rpc {
                                 _ => (),
                             }
                             let msg = serde_json::to_string(&node_infos).unwrap();
-                            stream
+                            let res = stream
+                                .get_mut()
                                 .get_mut()
                                 .write_all(
                                     format!(
-                                        "HTTP/1.1 200 OK\r\n
-                                    Content-Type: application/json; charset=UTF-8\r\n
-                                    Content-Length: {}\r\n\r\n{}",
+                                        "HTTP/1.1 200 OK\r\n\
+                                         Content-Type: application/json; charset=UTF-8\r\n\
+                                         Content-Length: {}\r\n\r\n{}",
                                         msg.len(),
                                         msg
                                     )
                                     .as_bytes(),
                                 )
-                                .await
-                                .unwrap();
+                                .await;
+                            if let Err(e) = res {
+                                debug!("Failed to answer a nodeinfo request: {}", e);
+                            }
                         }
                         _ => {
-                            stream
+                            let res = stream
+                                .get_mut()
                                 .get_mut()
                                 .write_all("HTTP/1.1 400 Bad Request\r\n\r\n".as_bytes())
-                                .await
-                                .unwrap();
+                                .await;
+                            if let Err(e) = res {
+                                debug!("Failed to answer a nodeinfo request: {}", e);
+                            }
                         }
                     }
                 });
@@ -331,18 +669,258 @@ impl Rpc {
 
     pub async fn get_nodeinfos(addr: SocketAddr) -> io::Result<Vec<NodeInfo>> {
         let mut stream = TcpStream::connect(addr).await?;
-        stream.write_all("GET test".as_bytes()).await?;
+        stream.write_all("GET test\r\n\r\n".as_bytes()).await?;
 
         let mut buf = String::new();
-        let mut stream = BufReader::new(stream);
-        stream.read_line(&mut buf).await?; // HTTP/1.1 200 OK\r\n
-        stream.read_line(&mut buf).await?; // Content-Type: application/json; charset=UTF-8\r\n
-        stream.read_line(&mut buf).await?; // Content-Length: {}\r\n
-        stream.read_line(&mut buf).await?; // \r\n
-        stream.read_to_string(&mut buf).await?; // Content
+        stream.read_to_string(&mut buf).await?;
 
-        let node_infos = serde_json::from_str(&buf)?;
+        // skip the status line and headers
+        let content = match buf.split_once("\r\n\r\n") {
+            Some((_, content)) => content,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid response")),
+        };
+        let node_infos = serde_json::from_str(content)?;
 
         Ok(node_infos)
     }
 }
+
+// Must not panic on anything a peer can send.
+// A newer peer's message that cannot be read is Unsupported rather than malformed.
+pub fn decode(buf: &[u8], message_len: usize) -> Result<RpcMessage, DecodeError> {
+    if buf.len() > message_len {
+        return Err(DecodeError::TooLong(buf.len()));
+    }
+    let rmsg: RpcMessage = match serde_json::from_slice(buf) {
+        Ok(rmsg) => rmsg,
+        Err(e) => {
+            return match serde_json::from_slice::<Header>(buf) {
+                Ok(header) if header.version > PROTOCOL_VERSION => {
+                    Err(DecodeError::Unsupported(header.version))
+                }
+                _ => Err(DecodeError::Json(e)),
+            }
+        }
+    };
+    if rmsg.token.len() != TOKEN_KEY_LEN {
+        return Err(DecodeError::InvalidToken);
+    }
+    Ok(rmsg)
+}
+
+#[derive(Debug, Error)]
+pub enum DecodeError {
+    #[error("Message is too long ({0} bytes)")]
+    TooLong(usize),
+    #[error("Invalid json: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid token length")]
+    InvalidToken,
+    #[error("Unsupported protocol version {0}")]
+    Unsupported(u32),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kad::{MALFORMED_LIMIT, MESSAGE_LEN};
+    use crate::util::clock::MockClock;
+    use rand::prelude::*;
+    use rand_chacha::ChaCha20Rng;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn timeout_test() {
+        let clock = MockClock::new();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let src = NodeInfo {
+            id: Key::random(TOKEN_KEY_LEN),
+            addr: socket.local_addr().unwrap(),
+            net_id: TESTNET_USER_DHT.to_string(),
+        };
+        let mut rpc = Rpc::with_clock(socket, Arc::new(clock.clone()));
+        let (tx, _rx) = mpsc::unbounded_channel();
+        rpc.add(src.clone(), tx).await;
+
+        // never answers
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let dst = NodeInfo {
+            id: Key::random(TOKEN_KEY_LEN),
+            addr: silent.local_addr().unwrap(),
+            net_id: TESTNET_USER_DHT.to_string(),
+        };
+
+        let mut rx = rpc.send_req(Request::Ping, src.clone(), dst.clone()).await;
+        // a requester which stops waiting leaves no entry behind either
+        drop(rpc.send_req(Request::Ping, src, dst).await);
+        assert!(rx.try_recv().is_err());
+        assert_eq!(rpc.pending_len().await, 2);
+        clock.advance(Duration::from_millis(TIME_OUT));
+        assert!(rx.recv().await.unwrap().is_none());
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(rpc.pending_len().await, 0);
+        assert_eq!(rpc.stats().snapshot().pending_requests.timed_out, 2);
+    }
+
+    fn node(socket: &UdpSocket) -> NodeInfo {
+        NodeInfo {
+            id: Key::random(TOKEN_KEY_LEN),
+            addr: socket.local_addr().unwrap(),
+            net_id: TESTNET_USER_DHT.to_string(),
+        }
+    }
+
+    #[test]
+    fn decode_fuzz_test() {
+        let mut rng = ChaCha20Rng::seed_from_u64(0);
+        let valid = serde_json::to_vec(&RpcMessage {
+            version: PROTOCOL_VERSION,
+            token: Key::random(TOKEN_KEY_LEN),
+            src: NodeInfo {
+                id: Key::random(TOKEN_KEY_LEN),
+                addr: "127.0.0.1:1".parse().unwrap(),
+                net_id: TESTNET_USER_DHT.to_string(),
+            },
+            dst: NodeInfo {
+                id: Key::random(TOKEN_KEY_LEN),
+                addr: "127.0.0.1:2".parse().unwrap(),
+                net_id: TESTNET_USER_DHT.to_string(),
+            },
+            msg: Message::Request(Request::Ping),
+        })
+        .unwrap();
+        assert!(decode(&valid, MESSAGE_LEN).is_ok());
+
+        for _ in 0..10000 {
+            let mut buf = vec![0; rng.gen_range(0..MESSAGE_LEN + 16)];
+            rng.fill_bytes(&mut buf);
+            let _ = decode(&buf, MESSAGE_LEN);
+
+            // mostly valid json is more likely to reach odd corners
+            let mut buf = valid.clone();
+            for _ in 0..rng.gen_range(1..4) {
+                let i = rng.gen_range(0..buf.len());
+                buf[i] = rng.gen();
+            }
+            buf.truncate(rng.gen_range(0..=buf.len()));
+            let _ = decode(&buf, MESSAGE_LEN);
+        }
+        assert!(matches!(
+            decode(&vec![b' '; MESSAGE_LEN + 1], MESSAGE_LEN),
+            Err(DecodeError::TooLong(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn version_test() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_node = node(&socket);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let rpc = Rpc::open(socket, server_node.clone(), tx).await;
+        let server_addr = rpc.socket.local_addr().unwrap();
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_node = node(&peer);
+        let ping = |version| {
+            serde_json::to_value(&RpcMessage {
+                version,
+                token: Key::random(TOKEN_KEY_LEN),
+                src: peer_node.clone(),
+                dst: server_node.clone(),
+                msg: Message::Request(Request::Ping),
+            })
+            .unwrap()
+        };
+
+        // a newer peer with a request we do not know is not malformed, though
+        // enough of those get it banned like malformed ones
+        let mut unknown = ping(PROTOCOL_VERSION + 1);
+        unknown["msg"] = serde_json::json!({"Request": "Gossip"});
+        let unknown = serde_json::to_vec(&unknown).unwrap();
+        assert!(matches!(decode(&unknown, MESSAGE_LEN), Err(DecodeError::Unsupported(_))));
+        for _ in 1..MALFORMED_LIMIT {
+            peer.send_to(&unknown, server_addr).await.unwrap();
+        }
+
+        // a version 0 peer is answered without the version field
+        let legacy = ping(0);
+        assert!(legacy.get("version").is_none());
+        peer.send_to(&serde_json::to_vec(&legacy).unwrap(), server_addr)
+            .await
+            .unwrap();
+        let req = timeout(Duration::from_secs(5), rx.recv()).await.unwrap();
+        req.unwrap().rep(Reply::Ping, server_node.clone()).await;
+        let mut buf = [0; MESSAGE_LEN];
+        let len = timeout(Duration::from_secs(5), peer.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let reply: serde_json::Value = serde_json::from_slice(&buf[..len]).unwrap();
+        assert!(reply.get("version").is_none());
+
+        let stats = rpc.stats().snapshot();
+        assert_eq!(stats.malformed_received, 0);
+        assert_eq!(stats.peer_versions.get(&0), Some(&1));
+
+        peer.send_to(&unknown, server_addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(rpc.stats().snapshot().peers_banned, 1);
+    }
+
+    #[tokio::test]
+    async fn malformed_ban_test() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_node = node(&socket);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let rpc = Rpc::open(socket, server_node.clone(), tx).await;
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let ping = serde_json::to_vec(&RpcMessage {
+            version: PROTOCOL_VERSION,
+            token: Key::random(TOKEN_KEY_LEN),
+            src: node(&peer),
+            dst: server_node,
+            msg: Message::Request(Request::Ping),
+        })
+        .unwrap();
+        let mut rng = ChaCha20Rng::seed_from_u64(0);
+        let mut garbage = |n| {
+            (0..n)
+                .map(|_| {
+                    let mut buf = vec![0xff; rng.gen_range(1..64)];
+                    rng.fill_bytes(&mut buf[1..]);
+                    buf
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // a few bad messages are tolerated
+        for buf in garbage(MALFORMED_LIMIT - 1) {
+            peer.send_to(&buf, rpc.socket.local_addr().unwrap())
+                .await
+                .unwrap();
+        }
+        peer.send_to(&ping, rpc.socket.local_addr().unwrap())
+            .await
+            .unwrap();
+        let req = timeout(Duration::from_secs(5), rx.recv()).await.unwrap();
+        assert!(matches!(req.unwrap().get_req(), Request::Ping));
+
+        // one more and the peer is ignored
+        for buf in garbage(1) {
+            peer.send_to(&buf, rpc.socket.local_addr().unwrap())
+                .await
+                .unwrap();
+        }
+        peer.send_to(&ping, rpc.socket.local_addr().unwrap())
+            .await
+            .unwrap();
+        assert!(timeout(Duration::from_millis(500), rx.recv())
+            .await
+            .is_err());
+
+        let stats = rpc.stats().snapshot();
+        assert_eq!(stats.malformed_received, MALFORMED_LIMIT as u64);
+        assert_eq!(stats.peers_banned, 1);
+    }
+}
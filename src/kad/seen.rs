@@ -0,0 +1,123 @@
+use std::collections::{HashMap, VecDeque};
+use tokio::time::{Duration, Instant};
+
+use super::key::Key;
+
+// The hashes of the multicasts seen lately, so that each is relayed once. A
+// hash lapses after the ttl; the maintenance sweep takes the lapsed ones
+// out, and the oldest go while it is full, so that a flood of messages
+// cannot grow it without end. Like PendingMap, the order of insertion is
+// kept to find the oldest, and hashes gone already are skipped when met.
+pub struct SeenTokens {
+    seen: HashMap<Key, Instant>,
+    order: VecDeque<(Key, Instant)>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+impl SeenTokens {
+    pub fn new(capacity: usize, ttl: Duration) -> SeenTokens {
+        SeenTokens {
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+            capacity: capacity.max(1),
+            ttl,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn contains(&self, token: &Key, now: Instant) -> bool {
+        matches!(self.seen.get(token), Some(at) if now.saturating_duration_since(*at) < self.ttl)
+    }
+
+    // false if token was seen and has not lapsed, which leaves it as it is
+    pub fn insert(&mut self, token: Key, now: Instant) -> bool {
+        if self.contains(&token, now) {
+            return false;
+        }
+        while self.seen.len() >= self.capacity {
+            match self.order.pop_front() {
+                Some((old, inserted_at)) => self.take(&old, inserted_at),
+                None => break,
+            }
+        }
+        self.order.push_back((token.clone(), now));
+        self.seen.insert(token, now);
+        true
+    }
+
+    // takes out the lapsed hashes, returning how many
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let before = self.seen.len();
+        while let Some((token, inserted_at)) = self.order.front().cloned() {
+            if self.seen.get(&token) == Some(&inserted_at)
+                && now.saturating_duration_since(inserted_at) < self.ttl
+            {
+                break;
+            }
+            self.order.pop_front();
+            self.take(&token, inserted_at);
+        }
+        before - self.seen.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Key> {
+        self.seen.keys()
+    }
+
+    // only the hash inserted at that time, not one inserted again since
+    fn take(&mut self, token: &Key, inserted_at: Instant) {
+        if self.seen.get(token) == Some(&inserted_at) {
+            self.seen.remove(token);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kad::TOKEN_KEY_LEN;
+
+    #[test]
+    fn seen_tokens_test() {
+        let start = Instant::now();
+        let ttl = Duration::from_secs(300);
+        let mut seen = SeenTokens::new(4, ttl);
+        let token = Key::random(TOKEN_KEY_LEN);
+        assert!(seen.insert(token.clone(), start));
+        assert!(!seen.insert(token.clone(), start + Duration::from_secs(1)));
+        assert!(seen.contains(&token, start + ttl - Duration::from_secs(1)));
+
+        // lapsed, it is seen anew
+        assert!(!seen.contains(&token, start + ttl));
+        assert!(seen.insert(token.clone(), start + ttl));
+        assert_eq!(seen.len(), 1);
+        assert_eq!(seen.expire(start + ttl * 2), 1);
+        assert_eq!(seen.len(), 0);
+    }
+
+    #[test]
+    fn flood_test() {
+        // a sustained flood of distinct messages, faster than they lapse
+        let start = Instant::now();
+        let ttl = Duration::from_secs(300);
+        let mut seen = SeenTokens::new(1000, ttl);
+        for i in 0..100_000u64 {
+            let now = start + Duration::from_millis(i);
+            assert!(seen.insert(Key::hash(&i.to_be_bytes(), TOKEN_KEY_LEN), now));
+            assert!(seen.len() <= 1000);
+        }
+        assert_eq!(seen.len(), 1000);
+        assert!(seen.order.len() <= 1000);
+        // the latest are kept
+        let latest = Key::hash(&99_999u64.to_be_bytes(), TOKEN_KEY_LEN);
+        assert!(!seen.insert(latest, start + Duration::from_secs(100)));
+
+        // and once it stops, the sweep empties it
+        assert_eq!(seen.expire(start + Duration::from_secs(100) + ttl), 1000);
+        assert!(seen.seen.is_empty() && seen.order.is_empty());
+    }
+}
@@ -1,35 +1,113 @@
-use log::warn;
-
+use super::backend::StoreBackend;
 use super::Key;
 use std::collections::hash_map::Iter;
 use std::collections::HashMap;
+use std::io;
 use std::sync::Arc;
 
+pub type StorePredicate = Arc<dyn Fn(&[u8]) -> bool + Sync + Send>;
+// Whether a value may be stored under a key, given the value already there if
+// any. Runs under the store lock, on values which passed the predicate, so it
+// has to be cheap.
+pub type StoreConflict = Arc<dyn Fn(&Key, Option<&[u8]>, &[u8]) -> bool + Sync + Send>;
+
+// Why a value was not stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreRefusal {
+    // the conflict rule kept the stored value
+    Conflict,
+    // it would take the store over its limit
+    Full,
+}
+
 #[derive(Clone)]
 pub struct Store {
     key_len: usize,
     store: HashMap<Key, Vec<u8>>,
-    store_predicate: Arc<dyn Fn(&[u8]) -> bool + Sync + Send>,
+    store_predicate: StorePredicate,
+    store_conflict: Option<StoreConflict>,
+    // bytes of the stored values, and the most they may take
+    bytes: usize,
+    limit: Option<usize>,
+    backend: Option<Arc<dyn StoreBackend>>,
 }
 
 impl Store {
-    pub fn new(key_len: usize, store_predicate: Arc<dyn Fn(&[u8]) -> bool + Sync + Send>) -> Store {
+    pub fn new(key_len: usize, store_predicate: StorePredicate) -> Store {
         Store {
             key_len,
             store: HashMap::new(),
             store_predicate,
+            store_conflict: None,
+            bytes: 0,
+            limit: None,
+            backend: None,
         }
     }
 
-    pub fn insert(&mut self, k: Key, v: Vec<u8>) -> Result<(), &'static str> {
-        assert_eq!(self.key_len,k.len());
-        if (self.store_predicate)(&v) {
-            self.store.insert(k, v);
-            Ok(())
-        } else {
-            warn!("Invalid value is tried to insert.");
-            Err("Invalid value is tried to insert.")
+    // values are kept in backend too, and read back by restore
+    pub fn with_backend(mut self, backend: Arc<dyn StoreBackend>) -> Store {
+        self.backend = Some(backend);
+        self
+    }
+
+    // where values taken by insert_verified have to be put
+    pub fn backend(&self) -> Option<Arc<dyn StoreBackend>> {
+        self.backend.clone()
+    }
+
+    // Takes the values of the backend back, checked like new ones; returns how
+    // many were taken
+    pub async fn restore(&mut self) -> io::Result<usize> {
+        let backend = match &self.backend {
+            Some(backend) => backend.clone(),
+            None => return Ok(0),
+        };
+        let mut restored = 0;
+        for (k, v) in backend.load().await? {
+            if k.len() == self.key_len
+                && (self.store_predicate)(&v)
+                && self.insert_verified(k, v).is_ok()
+            {
+                restored += 1;
+            }
+        }
+        Ok(restored)
+    }
+
+    pub fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn with_conflict(mut self, store_conflict: StoreConflict) -> Store {
+        self.store_conflict = Some(store_conflict);
+        self
+    }
+
+    pub fn predicate(&self) -> StorePredicate {
+        self.store_predicate.clone()
+    }
+
+    // for values already checked against the predicate, off the lock
+    pub fn insert_verified(&mut self, k: Key, v: Vec<u8>) -> Result<(), StoreRefusal> {
+        assert_eq!(self.key_len, k.len());
+        let old = self.store.get(&k);
+        if let Some(conflict) = &self.store_conflict {
+            if !conflict(&k, old.map(|old| &old[..]), &v) {
+                return Err(StoreRefusal::Conflict);
+            }
+        }
+        let bytes = self.bytes - old.map_or(0, |old| old.len()) + v.len();
+        if self.limit.is_some_and(|limit| bytes > limit) {
+            return Err(StoreRefusal::Full);
         }
+        self.bytes = bytes;
+        self.store.insert(k, v);
+        Ok(())
     }
 
     pub fn get(&self, k: &Key) -> Option<&Vec<u8>> {
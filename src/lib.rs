@@ -1,10 +1,26 @@
+//! A decentralized microblogging network over two Kademlia DHTs.
+//!
+//! [`prelude`] is the stable API: opening a [`NetworkController`](service::NetworkController),
+//! posting and following through a [`UserHandle`](service::UserHandle), and serving clients
+//! with an [`ApiServer`](api_server::ApiServer). Hidden modules are internals that change
+//! between minor releases; `kad` is documented with the `unstable-kad` feature, for tools
+//! which run DHT nodes directly, but is still not covered by semver.
+
+pub mod prelude;
+
+#[cfg_attr(not(feature = "unstable-kad"), doc(hidden))]
 pub mod kad;
 pub mod crypto;
 pub mod user;
+#[doc(hidden)]
 pub mod util;
 pub mod service;
+#[doc(hidden)]
 pub mod cli;
 pub mod api_server;
+pub mod api_client;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 
 #[cfg(test)]
 mod tests {
@@ -15,4 +31,4 @@ mod tests {
         let now = Local::now();
         println!("{}",now);
     }
-}
\ No newline at end of file
+}
@@ -1,93 +1,229 @@
 use chrono::Utc;
 use log::warn;
-use noktulo::cli::Timeline;
-use noktulo::service::{Config, NetworkController, UserHandle};
-use noktulo::user::user::{Address, SignedUserAttribute, UserAttribute};
+use rustyline::error::ReadlineError;
+use noktulo::cli::{
+    run_relay, RelayOptions, Repl, Timeline, END_OF_TEXT, RELAY_USAGE, TIMELINE_PAGE,
+};
+use noktulo::service::{
+    default_networks, fetch_preview, AccountFeed, BackfillEvent, Config, IdentityCache,
+    IdentityChange, IdentityChangeKind, IdentityLog, IngestError, KeySource, NetworkController,
+    NotificationKind, Publisher, Session, SubscriptionHandle, UnlockedUserHandle, UserHandle,
+    UserIndex, WatchHandle, HISTORY_BACKFILL, INGEST_MAX_AGE,
+};
+use noktulo::kad::KadConfig;
+use noktulo::user::aliases::AliasBook;
+use noktulo::user::block::BlockList;
+use noktulo::user::following::{self, Following};
+use noktulo::user::invite::{Invite, INVITE_URI_PREFIX};
+use noktulo::user::link::find_url;
+use noktulo::user::mention::MentionPolicy;
+use noktulo::util::atomic_file;
+use noktulo::user::post::{Hoot, PostKind, SignedPost};
+use noktulo::user::post_id::PostId;
+use noktulo::user::time_format::{TimeFormat, Zone};
+use noktulo::user::user::{
+    Address, MigrationRecord, SignedUserAttribute, UserAttribute,
+};
 use serde_json;
-use noktulo::crypto::{PublicKey,SecretKey};
+use noktulo::crypto::{generate_mnemonic, ExternalSigner, SecretKey, Signer};
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::io::{self, Write};
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use tokio::fs::{File, OpenOptions, create_dir};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::sync::Arc;
+use tokio::fs::{File, create_dir, remove_file};
+use tokio::io::AsyncWriteExt;
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
     env_logger::init();
-    let mut app = CLI::init().await.unwrap();
+    let mut args = std::env::args().skip(1);
+    let first = args.next();
+    if first.as_deref() == Some("--relay") {
+        let options = match RelayOptions::parse(args) {
+            Ok(options) => options,
+            Err(e) => {
+                eprintln!("{}\n{}", e, RELAY_USAGE);
+                std::process::exit(2);
+            }
+        };
+        return run_relay(options).await.map_err(io::Error::other);
+    }
+    // LAN discovery tells anyone on the network about the node, so it is opt-in
+    let lan_discovery = first.as_deref() == Some("--lan");
+    let mut app = CLI::init(lan_discovery).await.unwrap();
     return app.cli().await;
 }
 
 struct CLI {
     controller: NetworkController,
     user_handles: Vec<UserHandle>,
-    pubkey_dict: HashMap<Address, PublicKey>,
+    // browsing without an account, kept until it is upgraded
+    watch_handle: Option<WatchHandle>,
+    // keys and profiles of other accounts, see the cache command
+    identities: IdentityCache,
+    // petnames given to addresses, see the alias command
+    aliases: AliasBook,
+    // moves and deletions of followed accounts, see the identity-log command
+    identity_log: IdentityLog,
+    // how the times of posts are shown, see the time command
+    time_format: TimeFormat,
+    repl: Repl,
+    // set by Ctrl-C or Ctrl-D at the command prompt
+    exiting: bool,
 }
 
+// for a watch-only session there is no path, and nothing is saved
+async fn save_timeline(path: Option<&Path>, timeline: &Timeline) {
+    if let Some(path) = path {
+        if let Err(e) = atomic_file::write(path, &timeline.to_bytes()).await {
+            println!("Cannot save the timeline: {}", e);
+        }
+    }
+}
+
+// the accounts, with their secret keys
+const USERS_FILE: &str = "localdata/users";
+// new local keys come from a seed phrase of this many words
+const SEED_PHRASE_WORDS: usize = 24;
+// password prompts before going back to the account list
+const UNLOCK_ATTEMPTS: usize = 3;
+const ALIASES_FILE: &str = "localdata/aliases";
+const IDENTITY_LOG_FILE: &str = "localdata/identity_log";
+const TIME_FORMAT_FILE: &str = "localdata/time_format";
+
+const SESSION_COMMANDS: [&str; 18] = [
+    "update", "history", "stats", "expand", "show", "collapse", "time", "follow", "unfollow",
+    "search", "cache", "alias", "unalias", "identity-log", "list", "note", "upgrade", "quit",
+];
+
+// refused in a watch-only session
+const ACCOUNT_COMMANDS: [&str; 21] = [
+    "hoot",
+    "dm",
+    "dms",
+    "receipts",
+    "block",
+    "unblock",
+    "invite",
+    "cw",
+    "previews",
+    "suggest",
+    "mailbox",
+    "notifications",
+    "mentions",
+    "backup",
+    "restore",
+    "rehoot",
+    "del",
+    "export",
+    "delete-account",
+    "migrate-account",
+    "password",
+];
+
 impl CLI {
-    pub async fn init() -> io::Result<CLI> {
+    pub async fn init(lan_discovery: bool) -> io::Result<CLI> {
         let config = Config {
             bind_addr: SocketAddr::from_str("0.0.0.0:6270").unwrap(),
+            pubsub_bind_addr: Some(SocketAddr::from_str("0.0.0.0:6272").unwrap()),
             nodeinfo_addr: Some(SocketAddr::from_str("0.0.0.0:6271").unwrap()),
             bootstrap: Vec::new(),
+            bootstrap_lists: Vec::new(),
+            lan_discovery,
+            networks: default_networks(),
+            kad: KadConfig::default(),
+            tunables_path: Some(PathBuf::from("localdata/tunables.json")),
+            port_mapping: true,
+            peer_store_path: Some(PathBuf::from("localdata/peers")),
+            store_limit: None,
+            bandwidth_limit: None,
+            store_dir: Some(PathBuf::from("localdata/store")),
+            rng_seed: None,
+            archive_posts: true,
+            operator_key: None,
+            local_reload: false,
+            node_id_path: Some(PathBuf::from("localdata/node_id")),
         };
         let net = NetworkController::init(config).await;
 
         let _ = create_dir("localdata").await;
 
-        let mut userfile = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open("localdata/users")
-            .await
-            .unwrap();
-        let mut buf = vec![];
-        userfile.read_to_end(&mut buf).await?;
+        // The accounts and their keys: an unreadable file is left alone for the
+        // user to recover, rather than overwritten with no accounts
+        let user_handles: Vec<UserHandle> = match atomic_file::read(Path::new(USERS_FILE)).await {
+            Ok(buf) => serde_json::from_slice(&buf).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", USERS_FILE, e))
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(io::Error::new(e.kind(), format!("{}: {}", USERS_FILE, e))),
+        };
 
-        let user_handles: Vec<UserHandle> = match serde_json::from_slice(&buf) {
-            Ok(e) => e,
-            Err(_) => {
-                userfile.set_len(0).await.unwrap(); // truncate
-                vec![]
-            }
+        let identities = match atomic_file::read(Path::new("localdata/pubkeys")).await {
+            Ok(buf) => IdentityCache::from_bytes(&buf),
+            Err(_) => IdentityCache::new(),
         };
 
-        let mut pubkey_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open("localdata/pubkeys")
-            .await?;
-        let mut buf = vec![];
-        pubkey_file.read_to_end(&mut buf).await?;
-
-        let pk_bytes: Vec<[u8; 32]> = match serde_json::from_slice(&buf) {
-            Ok(e) => e,
-            Err(_) => {
-                pubkey_file.set_len(0).await.unwrap();
-                vec![]
-            }
+        let aliases = match atomic_file::read(Path::new(ALIASES_FILE)).await {
+            Ok(buf) => AliasBook::from_bytes(&buf),
+            Err(_) => AliasBook::new(),
+        };
+
+        let identity_log = match atomic_file::read(Path::new(IDENTITY_LOG_FILE)).await {
+            Ok(buf) => IdentityLog::from_bytes(&buf),
+            Err(_) => IdentityLog::new(),
+        };
+
+        let time_format = match atomic_file::read(Path::new(TIME_FORMAT_FILE)).await {
+            Ok(buf) => serde_json::from_slice(&buf).unwrap_or_default(),
+            Err(_) => TimeFormat::default(),
+        };
+
+        let watch_handle = match atomic_file::read(Path::new("localdata/watch")).await {
+            Ok(buf) => serde_json::from_slice(&buf).ok(),
+            Err(_) => None,
         };
 
-        let mut pubkey_dict = HashMap::new();
-        for bytes in pk_bytes {
-            if let Ok(pk) = PublicKey::from_bytes(&bytes) {
-                let addr = Address::from(pk.clone());
-                pubkey_dict.insert(addr, pk);
+        if let Ok(buf) = atomic_file::read(Path::new("localdata/stats")).await {
+            if let Ok(stats) = serde_json::from_slice(&buf) {
+                net.stats().set_base(stats);
             }
         }
 
+        let mut repl = Repl::new(PathBuf::from("localdata/history")).map_err(io::Error::other)?;
+        repl.set_commands(&[&SESSION_COMMANDS[..], &ACCOUNT_COMMANDS[..]].concat());
+
         Ok(CLI {
             controller: net,
             user_handles,
-            pubkey_dict,
+            watch_handle,
+            identities,
+            aliases,
+            identity_log,
+            time_format,
+            repl,
+            exiting: false,
         })
     }
 
     pub async fn cli(&mut self) -> io::Result<()> {
+        // files from before the keystore hold the keys in plaintext
+        let mut sealed = false;
+        for i in 0..self.user_handles.len() {
+            if self.user_handles[i].needs_sealing() {
+                let name = self.user_handles[i].sig_attr.attr.name.clone();
+                println!("The key of {} is stored unencrypted, set a password for it", name);
+                let password = self.read_new_password()?;
+                self.user_handles[i].seal_key(&password).map_err(io::Error::other)?;
+                sealed = true;
+            }
+        }
+        if sealed {
+            self.save_users().await?;
+        }
+
         loop {
             println!("Select a user:");
             for (i, u) in self.user_handles.iter().enumerate() {
@@ -96,109 +232,312 @@ impl CLI {
             println!(
                 r"or
 [{}] Create a new account
+[{}] Browse without an account
 [{}] Quit
         ",
                 self.user_handles.len(),
-                self.user_handles.len() + 1
+                self.user_handles.len() + 1,
+                self.user_handles.len() + 2
             );
 
-            print!("Input: ");
-            io::stdout().flush().unwrap();
-            let mut s = String::new();
-            io::stdin().read_line(&mut s).unwrap();
-            let index: usize = s.trim().parse().unwrap();
+            let index = match self.repl.readline("Input: ") {
+                Ok(s) => s.trim().parse().unwrap_or(usize::MAX),
+                Err(_) => break,
+            };
 
             if index < self.user_handles.len() {
-                let user_handle = self.user_handles[index].clone();
-                let new_handle = self.timeline(user_handle).await;
-                self.user_handles[index] = new_handle;
+                let user_handle = match self.unlock(&self.user_handles[index].clone()) {
+                    Some(user_handle) => user_handle,
+                    None => continue,
+                };
+                match self.timeline(Session::Account(Box::new(user_handle))).await {
+                    Some(Session::Account(new_handle)) => {
+                        self.user_handles[index] = new_handle.lock()
+                    }
+                    Some(Session::Watch(_)) => unreachable!(),
+                    None => {
+                        self.user_handles.remove(index);
+                        self.save_users().await?;
+                    }
+                }
             } else if index == self.user_handles.len() {
                 self.create_new_user().await?;
             } else if index == self.user_handles.len() + 1 {
+                let watch_handle = self.watch_handle.clone().unwrap_or_default();
+                match self.timeline(Session::Watch(watch_handle)).await {
+                    Some(Session::Watch(watch_handle)) => self.watch_handle = Some(watch_handle),
+                    // upgraded to a full account
+                    Some(Session::Account(user_handle)) => {
+                        self.user_handles.push(user_handle.lock());
+                        self.watch_handle = None;
+                        self.save_users().await?;
+                    }
+                    None => self.watch_handle = None,
+                }
+                self.save_watch().await?;
+            } else if index == self.user_handles.len() + 2 {
                 break;
             } else {
                 println!("invalid index!");
             }
+            if self.exiting {
+                break;
+            }
         }
 
-        let mut userfile = File::create("localdata/users").await?;
-        userfile
-            .write_all(
-                serde_json::to_string(&self.user_handles)
-                    .unwrap()
-                    .as_bytes(),
-            )
-            .await?;
+        self.save_users().await?;
+        self.save_identities().await?;
+        self.controller.shutdown().await;
+        if let Err(e) = self.repl.save_history() {
+            warn!("Failed to save the command history: {}", e);
+        }
+
+        let stats = serde_json::to_vec(&self.controller.stats_snapshot()).unwrap();
+        atomic_file::write(Path::new("localdata/stats"), &stats).await?;
 
         Ok(())
     }
 
-    pub async fn timeline(&mut self, mut user_handle: UserHandle) -> UserHandle {
-        let mut timeline = Timeline::new();
+    async fn save_users(&self) -> io::Result<()> {
+        let users = serde_json::to_vec(&self.user_handles).unwrap();
+        atomic_file::write(Path::new(USERS_FILE), &users).await
+    }
 
-        let pk = PublicKey::from(SecretKey::from(user_handle.signing_key));
+    async fn save_identities(&self) -> io::Result<()> {
+        atomic_file::write(Path::new("localdata/pubkeys"), &self.identities.to_bytes()).await
+    }
 
-        let publisher = self.controller.create_publisher(&pk).await;
-        let mut subscriber = self.controller.create_subscriber().await;
+    async fn save_aliases(&self) -> io::Result<()> {
+        atomic_file::write(Path::new(ALIASES_FILE), &self.aliases.to_bytes()).await
+    }
+
+    async fn save_time_format(&self) -> io::Result<()> {
+        let buf = serde_json::to_vec(&self.time_format).unwrap();
+        atomic_file::write(Path::new(TIME_FORMAT_FILE), &buf).await
+    }
 
-        for (addr, _) in user_handle.followings.iter() {
-            subscriber.subscribe(addr.clone()).await;
+    async fn save_watch(&self) -> io::Result<()> {
+        let path = Path::new("localdata/watch");
+        match &self.watch_handle {
+            Some(watch_handle) => {
+                atomic_file::write(path, &serde_json::to_vec(watch_handle).unwrap()).await
+            }
+            None => atomic_file::remove(path).await,
+        }
+    }
+
+    // None if the account has been deleted or moved away
+    async fn timeline(&mut self, mut session: Session) -> Option<Session> {
+        // an account's timeline is kept between runs, a watch-only one is not
+        let timeline_path = match &session {
+            Session::Account(user_handle) => Some(PathBuf::from(format!(
+                "localdata/timeline_{}",
+                user_handle.addr().to_string()
+            ))),
+            Session::Watch(_) => None,
+        };
+        let mut timeline = match &timeline_path {
+            Some(path) => Timeline::from_bytes(&atomic_file::read(path).await.unwrap_or_default()),
+            None => Timeline::new(),
+        };
+        timeline.set_collapse_sensitive(*session.collapse_sensitive());
+        timeline.set_aliases(self.aliases.clone());
+        timeline.set_time_format(self.time_format.clone());
+
+        // only accounts publish
+        let mut publisher = match &session {
+            Session::Account(user_handle) => self.register(user_handle).await,
+            Session::Watch(_) => None,
+        };
+        // mentions and replies from followed accounts, see the notifications command
+        let mut notifications = match &session {
+            Session::Account(user_handle) => {
+                Some(self.controller.notifier().await.watch(user_handle.addr()))
+            }
+            Session::Watch(_) => None,
+        };
+        // direct messages to the account, see the dms command
+        let mut inbox = match &session {
+            Session::Account(user_handle) => Some(self.subscribe_direct(user_handle).await),
+            Session::Watch(_) => None,
+        };
+        // the posts of blocked accounts are dropped before anything sees them
+        let blocked = match &session {
+            Session::Account(user_handle) => user_handle.blocked.clone(),
+            Session::Watch(_) => BlockList::new(),
+        };
+        self.controller.subscriptions().await.subscriber().set_block_list(blocked);
+        // shares subscriptions with the other accounts, dropping it unsubscribes
+        let mut feed = self.controller.subscriptions().await.feed();
+        let ingestor = self.controller.ingestor();
+        ingestor.remember_keys(self.identities.pubkeys().values());
+        let mut users = UserIndex::new();
+        // accounts followed through an invite, until their profile is checked
+        let mut invites: HashMap<Address, Invite> = HashMap::new();
+
+        for (addr, following) in session.followings().iter() {
+            feed.follow(addr.clone()).await;
+            if let Some(attr) = &following.attr {
+                users.insert(addr.clone(), attr.clone());
+            }
         }
+        // what was posted while offline, as far back as the ingestor takes it,
+        // shown by the first update; only fetched from the authors whose head
+        // record moved past the newest post seen from them
+        let since = (Utc::now().timestamp() as u64).saturating_sub(INGEST_MAX_AGE);
+        let controller = &self.controller;
+        let statuses = session.followings().iter().map(|(addr, following)| async move {
+            let status = controller.sync_status(addr, following.head).await;
+            status.needs_backfill().then(|| addr.clone())
+        });
+        let behind: Vec<Address> = futures::future::join_all(statuses)
+            .await
+            .into_iter()
+            .flatten()
+            .collect();
+        let mut missed = feed.fetch_missed_of(&behind, since).await;
 
         loop {
-            print!("> ");
-            io::stdout().flush().unwrap();
-            let mut command = String::new();
-            io::stdin().read_line(&mut command).unwrap();
+            self.repl.set_known(
+                session
+                    .followings()
+                    .iter()
+                    .map(|(addr, following)| {
+                        (following.attr.as_ref().map(|attr| attr.name.as_str()), addr)
+                    })
+                    .chain(self.aliases.iter().map(|(petname, addr)| (Some(petname), addr))),
+            );
+            let command = match self.repl.readline("> ") {
+                Ok(command) => command,
+                Err(_) => {
+                    self.exiting = true;
+                    break;
+                }
+            };
             let command_t = command.trim();
 
             match command_t {
                 "update" => {
-                    let sigposts = subscriber.get_new_message().await;
+                    let mut sigposts = std::mem::take(&mut missed);
+                    sigposts.extend(feed.new_posts());
                     for sigpost in sigposts {
-                        let pubkey;
-                        if let Some(pk) = self.pubkey_dict.get(&sigpost.addr) {
-                            pubkey = pk.clone();
-                        } else {
-                            if let Some(pk) = self.controller.get_pubkey(sigpost.addr.clone()).await
-                            {
-                                pubkey = pk;
-                                self.pubkey_dict.insert(sigpost.addr.clone(), pubkey.clone());
-                            } else {
+                        if session.deleted_accounts().contains(&sigpost.addr) {
+                            continue;
+                        }
+                        let now = Utc::now().timestamp() as u64;
+                        let addr = sigpost.addr.clone();
+                        let sigpost = match ingestor.ingest_decoded(sigpost).await {
+                            Ok(verified) => {
+                                if verified.key_source == KeySource::Dht {
+                                    self.identities.insert(verified.pubkey, now);
+                                }
+                                verified.sigpost
+                            }
+                            Err(IngestError::AccountDeleted) => {
+                                self.log_identity_change(IdentityChangeKind::Deleted(addr.clone()))
+                                    .await;
+                                session.mark_deleted(addr.clone());
+                                feed.unfollow(&addr).await;
+                                continue;
+                            }
+                            Err(IngestError::AccountMoved(record)) => {
+                                self.follow_migration(&mut session, &mut feed, &record).await;
+                                continue;
+                            }
+                            Err(IngestError::UnknownKey) => {
                                 warn!("Not found the public key, ignoring.");
                                 continue;
                             }
-                        }
-
-                        if sigpost.verify(&pubkey).is_ok() {
-                            user_handle
-                                .followings
-                                .insert(sigpost.addr.clone(), Some(sigpost.post.user_attr.clone()));
+                            Err(_) => continue,
+                        };
+                        self.identities.saw(&sigpost.addr, &sigpost.post.user_attr, now);
+                        let delay = (Utc::now().timestamp_millis() as u64)
+                            .saturating_sub(sigpost.post.created_at * 1000);
+                        self.controller
+                            .stats()
+                            .record_delivery(&sigpost.addr.to_string(), delay);
+                        if let PostKind::DeleteAccount = sigpost.post.content {
+                            println!(
+                                "{} @{} deleted the account",
+                                sigpost.post.user_attr.name,
+                                sigpost.addr.to_string()
+                            );
+                            self.identities.forget(&sigpost.addr);
+                            let deleted = IdentityChangeKind::Deleted(sigpost.addr.clone());
+                            self.log_identity_change(deleted).await;
+                            session.mark_deleted(sigpost.addr.clone());
+                            feed.unfollow(&sigpost.addr).await;
+                        } else if let PostKind::Migrate(record) = &sigpost.post.content {
+                            if record.old_addr() == sigpost.addr {
+                                self.follow_migration(&mut session, &mut feed, record).await;
+                            }
+                        } else {
+                            if let Some(invite) = invites.remove(&sigpost.addr) {
+                                if !invite.matches_profile(&sigpost.post.user_attr) {
+                                    println!(
+                                        "Warning: @{} has another profile than in the invite",
+                                        sigpost.addr.to_string()
+                                    );
+                                }
+                            }
+                            let following = session
+                                .followings_mut()
+                                .entry(sigpost.addr.clone())
+                                .or_insert_with(|| Following::new(None, now));
+                            following.attr = Some(sigpost.post.user_attr.clone());
+                            following.saw_post(sigpost.post.id);
+                            users.insert(sigpost.addr.clone(), sigpost.post.user_attr.clone());
                             timeline.push(sigpost);
                         }
                     }
+                    // petnames follow accounts which moved
+                    timeline.set_aliases(self.aliases.clone());
+                    save_timeline(timeline_path.as_deref(), &timeline).await;
+                }
+                cmd if cmd.split_whitespace().next() == Some("history") => {
+                    // the posts received so far, newest first, a page at a time;
+                    // `history work 2` pages through the followings in the list work
+                    let mut args = cmd.split_whitespace().skip(1).peekable();
+                    let list = args.next_if(|arg| arg.parse::<usize>().is_err());
+                    let page = match args.next().map(str::parse::<usize>) {
+                        None => 1,
+                        Some(Ok(page)) if page > 0 => page,
+                        Some(_) => {
+                            println!("Usage: history [list] [page]");
+                            continue;
+                        }
+                    };
+                    let offset = (page - 1) * TIMELINE_PAGE;
+                    let (sigposts, count) = match list {
+                        Some(list) => {
+                            let members = following::list_members(session.followings(), list);
+                            if members.is_empty() {
+                                println!("No followings in the list {}", list);
+                                continue;
+                            }
+                            let sigposts = timeline.get_page_of(&members, offset, TIMELINE_PAGE);
+                            (sigposts, timeline.count_of(&members))
+                        }
+                        None => (timeline.get_page(offset, TIMELINE_PAGE), timeline.posts().len()),
+                    };
+                    let pages = count.div_ceil(TIMELINE_PAGE);
+                    if sigposts.is_empty() {
+                        println!("No posts on page {}", page);
+                        continue;
+                    }
+                    for sigpost in sigposts {
+                        println!("{}", timeline.render(sigpost));
+                    }
+                    println!("Page {} of {}", page, pages);
                 }
-                "hoot" => {
-                    let mut text = String::new();
-                    io::stdin().read_line(&mut text).unwrap();
-                    let sigpost = user_handle.hoot(text, None, None, vec![]);
-                    
-                    publisher
-                        .publish(&serde_json::to_vec(&sigpost).unwrap(), &user_handle.addr())
-                        .await;
+                "stats" => {
+                    println!("{}", self.controller.stats_snapshot());
                 }
-                "rehoot" => {
-                    let mut index_s = String::new();
-                    io::stdin().read_line(&mut index_s).unwrap();
-                    if let Ok(index) = index_s.parse::<usize>() {
+                "expand" => {
+                    let index_s = self.read_arg("index: ");
+                    if let Ok(index) = index_s.trim().parse::<usize>() {
                         if let Some(sigpost) = timeline.get(index) {
-                            let sigpost = user_handle.rehoot(sigpost.clone());
-                            publisher
-                                .publish(
-                                    &serde_json::to_vec(&sigpost).unwrap(),
-                                    &user_handle.addr(),
-                                )
-                                .await;
+                            println!("{}", timeline.render_expanded(sigpost));
                         } else {
                             println!("Not found");
                         }
@@ -206,102 +545,1095 @@ impl CLI {
                         println!("Invalid input");
                     }
                 }
-                "del" => {
-                    let mut id_s = String::new();
-                    io::stdin().read_line(&mut id_s).unwrap();
-                    if let Ok(id) = id_s.parse::<u128>() {
-                        if let Some(sigpost) = user_handle.del(id) {
-                            publisher
-                                .publish(
-                                    &serde_json::to_vec(&sigpost).unwrap(),
-                                    &user_handle.addr(),
-                                )
+                "show" => {
+                    // an index into the timeline, or a post id
+                    let arg = self.read_arg("index or id: ");
+                    let arg = arg.trim();
+                    let sigpost = arg
+                        .parse::<usize>()
+                        .ok()
+                        .and_then(|index| timeline.get(index).cloned())
+                        .or_else(|| timeline.get_by_id(arg.parse::<PostId>().ok()?));
+                    match sigpost {
+                        Some(sigpost) => {
+                            let received_at = timeline.received_at(&sigpost);
+                            let report = self
+                                .controller
+                                .verify_post(&sigpost, &self.identities.pubkeys(), received_at)
                                 .await;
-                        } else {
-                            println!("Not found");
+                            println!("{}", timeline.render_expanded(&sigpost));
+                            print!("{}", report);
+                            if let Some(stats) = timeline.author_stats(&sigpost.addr) {
+                                println!("@{}: {}", sigpost.addr.to_string(), stats);
+                            }
+                        }
+                        None => println!("Not found"),
+                    }
+                }
+                "collapse" => {
+                    let flag = self.read_arg("on/off: ");
+                    match flag.trim() {
+                        "on" => *session.collapse_sensitive() = true,
+                        "off" => *session.collapse_sensitive() = false,
+                        _ => {
+                            println!("Invalid input");
+                            continue;
                         }
-                    } else {
-                        println!("Invalid input");
                     }
+                    timeline.set_collapse_sensitive(*session.collapse_sensitive());
                 }
                 "follow" => {
-                    let mut addr_s = String::new();
-                    io::stdin().read_line(&mut addr_s).unwrap();
-                    if let Ok(addr) = Address::from_str(&addr_s) {
-                        if !user_handle.followings.contains_key(&addr) {
-                            user_handle.followings.insert(addr.clone(), None);
+                    let addr_s = self.read_arg("address, petname or invite: ");
+                    // an invite bootstraps from its peers before following
+                    let addr = if addr_s.trim().starts_with(INVITE_URI_PREFIX) {
+                        match Invite::parse(&addr_s) {
+                            Ok(invite) => {
+                                self.controller.add_peers(&invite.peers).await;
+                                let addr = invite.addr.clone();
+                                invites.insert(addr.clone(), invite);
+                                Some(addr)
+                            }
+                            Err(e) => {
+                                println!("{}", e);
+                                None
+                            }
                         }
-                        subscriber.subscribe(addr).await;
                     } else {
-                        println!("Invalid address");
+                        self.parse_address(addr_s.trim(), &session)
+                    };
+                    if let Some(mut addr) = addr {
+                        if let Some(resolved) = self.controller.resolve_user(addr.clone()).await {
+                            let current = resolved.addr;
+                            if current != addr {
+                                println!("The account has moved to @{}", current.to_string());
+                                addr = current;
+                            }
+                        }
+                        if session.deleted_accounts().contains(&addr) {
+                            println!("The account has been deleted");
+                            continue;
+                        }
+                        if !session.followings().contains_key(&addr) {
+                            // the name is known before the first post comes in
+                            let attr = self.controller.get_profile(&addr).await.map(|p| p.attr);
+                            if let Some(attr) = &attr {
+                                println!("Following {} @{}", attr.name, addr.to_string());
+                            }
+                            let now = Utc::now().timestamp() as u64;
+                            let following = Following::new(attr, now);
+                            session.followings_mut().insert(addr.clone(), following);
+                            self.backfill(addr.clone(), &mut timeline).await;
+                            save_timeline(timeline_path.as_deref(), &timeline).await;
+                        }
+                        feed.follow(addr).await;
                     }
                 }
                 "unfollow" => {
-                    let mut addr_s = String::new();
-                    io::stdin().read_line(&mut addr_s).unwrap();
-                    if let Ok(addr) = Address::from_str(&addr_s) {
-                        if user_handle.followings.contains_key(&addr) {
-                            user_handle.followings.remove(&addr);
+                    let addr_s = self.read_arg("address or petname: ");
+                    if let Some(addr) = self.parse_address(addr_s.trim(), &session) {
+                        if session.followings().contains_key(&addr) {
+                            session.followings_mut().remove(&addr);
                         }
-                        subscriber.stop_subscription(&addr).await;
-                    } else {
-                        println!("Invalid address");
+                        feed.unfollow(&addr).await;
+                    }
+                }
+                "search" => {
+                    let query = self.read_arg("name: ");
+                    let found = users.find_user(&query, 10);
+                    if found.is_empty() {
+                        println!("Not found");
+                    }
+                    for m in found {
+                        println!(
+                            "{} @{} ({:.0}%)",
+                            m.name,
+                            m.addr.to_string(),
+                            m.confidence * 100.0
+                        );
+                    }
+                }
+                cmd if cmd.split_whitespace().next() == Some("cache") => {
+                    let args: Vec<_> = cmd.split_whitespace().skip(1).collect();
+                    match args.as_slice() {
+                        [] => {
+                            if self.identities.is_empty() {
+                                println!("No cached identities");
+                            }
+                            for identity in self.identities.list_cached_identities() {
+                                let name = identity.attr.as_ref().map_or("?", |a| &a.name);
+                                let last_seen = match identity.last_seen {
+                                    0 => "never".to_string(),
+                                    t => TimeFormat::date().absolute(t),
+                                };
+                                println!(
+                                    "{} @{} (last seen {})",
+                                    name,
+                                    identity.addr().to_string(),
+                                    last_seen
+                                );
+                            }
+                        }
+                        ["prune", days] => match days.parse::<u64>() {
+                            Ok(days) => {
+                                let now = Utc::now().timestamp() as u64;
+                                let before = now.saturating_sub(days * 86400);
+                                let pruned = self.identities.prune(before);
+                                println!("Removed {} identities", pruned.len());
+                            }
+                            Err(_) => println!("Invalid number of days"),
+                        },
+                        ["forget", addr] => {
+                            if let Some(addr) = self.parse_address(addr, &session) {
+                                // profiles shown by search go too
+                                users.remove(&addr);
+                                if !self.identities.forget(&addr) {
+                                    println!("Not cached");
+                                }
+                            }
+                        }
+                        _ => {
+                            println!("Usage: cache [prune <days> | forget <address>]");
+                            continue;
+                        }
+                    }
+                    if let Err(e) = self.save_identities().await {
+                        println!("{}", e);
+                    }
+                }
+                cmd if cmd.split_whitespace().next() == Some("time") => {
+                    let args: Vec<_> = cmd.split_whitespace().skip(1).collect();
+                    let format = self.time_format.clone();
+                    let changed = match args.as_slice() {
+                        [] => {
+                            println!(
+                                "zone: {}, pattern: {}, relative: {}",
+                                format.zone(),
+                                format.pattern(),
+                                if format.is_relative() { "on" } else { "off" }
+                            );
+                            continue;
+                        }
+                        ["zone", zone] => Zone::parse(zone).map(|zone| format.with_zone(zone)),
+                        ["relative", "on"] => Ok(format.with_relative(true)),
+                        ["relative", "off"] => Ok(format.with_relative(false)),
+                        ["pattern", ..] => {
+                            let pattern = cmd.trim().splitn(3, ' ').nth(2).unwrap_or("").trim();
+                            TimeFormat::new(format.zone(), pattern)
+                                .map(|new| new.with_relative(format.is_relative()))
+                        }
+                        _ => {
+                            println!(
+                                "Usage: time [zone <local|utc|+hh:mm> | relative <on|off> | pattern <strftime>]"
+                            );
+                            continue;
+                        }
+                    };
+                    match changed {
+                        Ok(format) => self.time_format = format,
+                        Err(e) => {
+                            println!("{}", e);
+                            continue;
+                        }
+                    }
+                    timeline.set_time_format(self.time_format.clone());
+                    if let Err(e) = self.save_time_format().await {
+                        println!("{}", e);
+                    }
+                }
+                cmd if cmd.split_whitespace().next() == Some("alias") => {
+                    let args: Vec<_> = cmd.split_whitespace().skip(1).collect();
+                    match args.as_slice() {
+                        [] => {
+                            if self.aliases.is_empty() {
+                                println!("No petnames");
+                            }
+                            for (petname, addr) in self.aliases.iter() {
+                                println!("~{} @{}", petname, addr.to_string());
+                            }
+                            continue;
+                        }
+                        [petname, addr] => {
+                            let addr = match self.parse_address(addr, &session) {
+                                Some(addr) => addr,
+                                None => continue,
+                            };
+                            match self.aliases.set(petname, addr) {
+                                Ok(Some(old)) => {
+                                    println!("~{} was @{}", petname, old.to_string())
+                                }
+                                Ok(None) => (),
+                                Err(e) => {
+                                    println!("{}", e);
+                                    continue;
+                                }
+                            }
+                        }
+                        _ => {
+                            println!("Usage: alias [<petname> <address>]");
+                            continue;
+                        }
+                    }
+                    timeline.set_aliases(self.aliases.clone());
+                    if let Err(e) = self.save_aliases().await {
+                        println!("{}", e);
+                    }
+                }
+                cmd if cmd.split_whitespace().next() == Some("unalias") => {
+                    let petname = match cmd.split_whitespace().nth(1) {
+                        Some(petname) => petname.strip_prefix('~').unwrap_or(petname),
+                        None => {
+                            println!("Usage: unalias <petname>");
+                            continue;
+                        }
+                    };
+                    if self.aliases.remove(petname).is_none() {
+                        println!("No such petname");
+                        continue;
+                    }
+                    timeline.set_aliases(self.aliases.clone());
+                    if let Err(e) = self.save_aliases().await {
+                        println!("{}", e);
+                    }
+                }
+                cmd if cmd.split_whitespace().next() == Some("list") => {
+                    let args: Vec<&str> = cmd.split_whitespace().skip(1).collect();
+                    match args[..] {
+                        [] => {
+                            let sizes = following::list_sizes(session.followings());
+                            if sizes.is_empty() {
+                                println!("No lists, use `list add NAME` to make one");
+                            }
+                            for (list, size) in sizes {
+                                println!("{} ({} followings)", list, size);
+                            }
+                        }
+                        [list] => {
+                            let mut members: Vec<_> = session
+                                .followings()
+                                .iter()
+                                .filter(|(_, following)| following.in_list(list))
+                                .collect();
+                            if members.is_empty() {
+                                println!("No followings in the list {}", list);
+                            }
+                            members.sort_by_key(|(_, following)| following.followed_at);
+                            for (addr, following) in members {
+                                let name = following.attr.as_ref().map_or("", |a| a.name.as_str());
+                                print!("{} @{}", name, addr.to_string());
+                                match &following.note {
+                                    Some(note) => println!(" ({})", note),
+                                    None => println!(),
+                                }
+                            }
+                        }
+                        [op @ ("add" | "remove"), list] => {
+                            let addr_s = self.read_arg("address or petname: ");
+                            let addr = match self.parse_address(addr_s.trim(), &session) {
+                                Some(addr) => addr,
+                                None => continue,
+                            };
+                            match session.followings_mut().get_mut(&addr) {
+                                Some(following) if op == "add" => {
+                                    following.lists.insert(list.to_string());
+                                }
+                                Some(following) => {
+                                    following.lists.remove(list);
+                                }
+                                None => println!("@{} is not followed", addr.to_string()),
+                            }
+                        }
+                        _ => println!("Usage: list [NAME | add NAME | remove NAME]"),
+                    }
+                }
+                "note" => {
+                    let addr_s = self.read_arg("address or petname: ");
+                    let addr = match self.parse_address(addr_s.trim(), &session) {
+                        Some(addr) => addr,
+                        None => continue,
+                    };
+                    if !session.followings().contains_key(&addr) {
+                        println!("@{} is not followed", addr.to_string());
+                        continue;
+                    }
+                    // kept on this device only; an empty note removes it
+                    let note = self.read_arg("note: ");
+                    let note = Some(note.trim().to_string()).filter(|note| !note.is_empty());
+                    if let Some(following) = session.followings_mut().get_mut(&addr) {
+                        following.note = note;
+                    }
+                }
+                "identity-log" => {
+                    if self.identity_log.is_empty() {
+                        println!("No followed account has moved or been deleted");
+                    }
+                    for change in self.identity_log.iter() {
+                        println!("{}", change);
+                    }
+                }
+                "upgrade" => {
+                    let watch_handle = match &session {
+                        Session::Watch(watch_handle) => watch_handle.clone(),
+                        Session::Account(_) => {
+                            println!("Already signed in");
+                            continue;
+                        }
+                    };
+                    match self.new_account(watch_handle).await {
+                        Ok(user_handle) => {
+                            publisher = self.register(&user_handle).await;
+                            let notifier = self.controller.notifier().await;
+                            notifications = Some(notifier.watch(user_handle.addr()));
+                            inbox = Some(self.subscribe_direct(&user_handle).await);
+                            session = Session::Account(Box::new(user_handle));
+                        }
+                        Err(e) => println!("{}", e),
+                    }
+                }
+                "notifications" => match notifications.as_mut() {
+                    // the ones received since the last time, whatever the timeline shows
+                    Some(notifications) => {
+                        let received = notifications.new_notifications();
+                        if received.is_empty() {
+                            println!("No new mentions, replies or rehoots");
+                        }
+                        for notification in received {
+                            let what = match notification.kind {
+                                NotificationKind::Reply => "Replied to you",
+                                NotificationKind::Mention => "Mentioned you",
+                                NotificationKind::ReHoot => "Rehooted you",
+                            };
+                            println!("{}:\n{}", what, timeline.render(&notification.sigpost));
+                        }
+                    }
+                    None => println!("This needs an account, use `upgrade` to create one"),
+                },
+                "dm" => match (&session, &publisher) {
+                    (Session::Account(user_handle), Some(publisher)) => {
+                        let addr_s = self.read_arg("to, address or petname: ");
+                        let to = match self.parse_address(addr_s.trim(), &session) {
+                            Some(to) => to,
+                            None => continue,
+                        };
+                        let pubkey = match self.identities.get(&to).cloned() {
+                            Some(pubkey) => Some(pubkey),
+                            None => self.controller.get_pubkey(to.clone()).await,
+                        };
+                        let pubkey = match pubkey {
+                            Some(pubkey) => pubkey,
+                            None => {
+                                println!("The key of @{} is unknown", to.to_string());
+                                continue;
+                            }
+                        };
+                        let text = match self.read_text() {
+                            Some(text) => text,
+                            None => continue,
+                        };
+                        match user_handle.direct_message(&pubkey, &text) {
+                            Ok(sigpost) => {
+                                let msg = serde_json::to_vec(&sigpost).unwrap();
+                                let from = user_handle.addr();
+                                if let Err(e) = publisher.send_direct(&msg, &from, &to).await {
+                                    println!("{}", e);
+                                }
+                            }
+                            Err(e) => println!("{}", e),
+                        }
+                    }
+                    _ => println!("This needs an account, use `upgrade` to create one"),
+                },
+                "dms" => match (&session, inbox.as_mut()) {
+                    // the ones received since the last time; they are not kept
+                    (Session::Account(user_handle), Some(inbox)) => {
+                        let mut received = Vec::new();
+                        while let Some(sigpost) = inbox.try_recv() {
+                            received.push(sigpost);
+                        }
+                        if received.is_empty() {
+                            println!("No new direct messages");
+                        }
+                        for sigpost in received {
+                            let verified = match ingestor.ingest_decoded(sigpost).await {
+                                Ok(verified) => verified,
+                                Err(e) => {
+                                    warn!("Direct message dropped: {}", e);
+                                    continue;
+                                }
+                            };
+                            match user_handle.open_direct(&verified.sigpost, &verified.pubkey) {
+                                Ok(text) => {
+                                    println!("{}\n{}", verified.sigpost.header(), text)
+                                }
+                                Err(e) => println!("{}", e),
+                            }
+                        }
+                    }
+                    _ => println!("This needs an account, use `upgrade` to create one"),
+                },
+                "block" | "unblock" => {
+                    if matches!(session, Session::Watch(_)) {
+                        println!("This needs an account, use `upgrade` to create one");
+                        continue;
+                    }
+                    let addr_s = self.read_arg("address or petname: ");
+                    let addr = match self.parse_address(addr_s.trim(), &session) {
+                        Some(addr) => addr,
+                        None => continue,
+                    };
+                    if let Session::Account(user_handle) = &mut session {
+                        let (changed, done) = if command_t == "block" {
+                            (user_handle.blocked.block(addr.clone()), "Blocked")
+                        } else {
+                            (user_handle.blocked.unblock(&addr), "Unblocked")
+                        };
+                        if !changed {
+                            println!("Nothing changed for @{}", addr.to_string());
+                            continue;
+                        }
+                        let subscriber = self.controller.subscriptions().await.subscriber();
+                        subscriber.set_block_list(user_handle.blocked.clone());
+                        println!("{} @{}", done, addr.to_string());
                     }
                 }
                 "quit" => break,
-                _ => (),
+                cmd => match (&mut session, &publisher) {
+                    (Session::Account(user_handle), Some(publisher)) => {
+                        if !self
+                            .account_command(cmd, user_handle, publisher, &timeline)
+                            .await
+                        {
+                            return None;
+                        }
+                    }
+                    _ => {
+                        let name = cmd.split_whitespace().next().unwrap_or("");
+                        if ACCOUNT_COMMANDS.contains(&name) {
+                            println!("This needs an account, use `upgrade` to create one");
+                        }
+                    }
+                },
+            }
+        }
+        Some(session)
+    }
+
+    // Commands which sign something. false once the account has been deleted or moved.
+    async fn account_command(
+        &mut self,
+        cmd: &str,
+        user_handle: &mut UserHandle,
+        publisher: &Publisher,
+        timeline: &Timeline,
+    ) -> bool {
+        match cmd {
+            "hoot" => {
+                let text = match self.read_text() {
+                    Some(text) => text,
+                    None => return true,
+                };
+                let mention_to = match user_handle.resolve_mentions(&text) {
+                    Ok(mention_to) => mention_to,
+                    Err(e) => {
+                        println!("{}, not posted", e);
+                        for addr in e.suggestions() {
+                            println!("Did you mean @{}?", addr.to_string());
+                        }
+                        return true;
+                    }
+                };
+                let mut hoot = Hoot::new(text);
+                hoot.mention_to = mention_to;
+                if user_handle.link_previews {
+                    if let Some(url) = find_url(&hoot.text) {
+                        match fetch_preview(url).await {
+                            Ok(preview) => hoot.link = Some(preview),
+                            Err(e) => println!("No preview: {}", e),
+                        }
+                    }
+                }
+                match user_handle.create_post(PostKind::Hoot(hoot)) {
+                    Ok(sigpost) => self.publish(user_handle, publisher, sigpost).await,
+                    Err(e) => println!("{}", e),
+                }
+            }
+            "cw" => {
+                let warning = self.read_arg("Content warning: ");
+                let warning = warning.trim();
+                let warning = if warning.is_empty() {
+                    None
+                } else {
+                    Some(warning.to_string())
+                };
+                let text = match self.read_text() {
+                    Some(text) => text,
+                    None => return true,
+                };
+                match user_handle.hoot_with_warning(text, warning) {
+                    Ok(sigpost) => self.publish(user_handle, publisher, sigpost).await,
+                    Err(e) => println!("{}", e),
+                }
+            }
+            "invite" => {
+                let peers = self.controller.invite_peers();
+                if peers.is_empty() {
+                    println!("No peer has answered yet, the invite only follows you");
+                }
+                let invite = Invite::new(user_handle.addr(), &user_handle.sig_attr.attr, &peers);
+                println!("{}", invite);
+            }
+            "previews" => {
+                let flag = self.read_arg("fetch link previews, on/off: ");
+                match flag.trim() {
+                    "on" => user_handle.link_previews = true,
+                    "off" => user_handle.link_previews = false,
+                    _ => println!("Invalid input"),
+                }
+            }
+            "suggest" => {
+                let recommendations = user_handle.recommend_follows(timeline.posts(), 10);
+                if recommendations.is_empty() {
+                    println!("No suggestions yet");
+                }
+                for r in recommendations {
+                    println!(
+                        "@{} ({} rehoots, {} mentions)",
+                        r.addr.to_string(),
+                        r.rehoots,
+                        r.mentions
+                    );
+                }
+            }
+            cmd if cmd.split_whitespace().next() == Some("mailbox") => {
+                let items = self.controller.read_mailbox(&user_handle.addr()).await;
+                let (mentions, requests) = user_handle.sort_mailbox(items);
+                let mut args = cmd.split_whitespace().skip(1).peekable();
+                // `mailbox requests` for the mentions the policy keeps apart
+                let (items, kind) = match args.next_if_eq(&"requests") {
+                    Some(_) => (requests, "mention requests"),
+                    None => {
+                        if !requests.is_empty() {
+                            println!("{} mention requests, see mailbox requests", requests.len());
+                        }
+                        (mentions, "mentions")
+                    }
+                };
+                match args.next() {
+                    None => {
+                        if items.is_empty() {
+                            println!("No {}", kind);
+                        }
+                        for item in items.iter() {
+                            println!("{}", item.sigpost);
+                        }
+                    }
+                    // drops what was read, not what arrived since
+                    Some("clear") => {
+                        let cleared = self
+                            .controller
+                            .compact_mailbox(user_handle, |item| !items.contains(item))
+                            .await;
+                        match cleared {
+                            Ok(_) => println!("Cleared {} {}", items.len(), kind),
+                            Err(e) => println!("{}", e),
+                        }
+                    }
+                    Some(_) => println!("Usage: mailbox [requests] [clear]"),
+                }
+            }
+            "mentions" => {
+                let policy = self.read_arg("take mentions from, everyone/followings: ");
+                match policy.trim() {
+                    "everyone" => user_handle.mention_policy = MentionPolicy::Everyone,
+                    "followings" => user_handle.mention_policy = MentionPolicy::Followings,
+                    _ => println!("Invalid input"),
+                }
+            }
+            // ask the nodes taking posts for signed receipts, to see which take them
+            "receipts" => {
+                let on = !publisher.receipts_requested();
+                publisher.request_receipts(on);
+                println!("Delivery receipts {}", if on { "on" } else { "off" });
+            }
+            "backup" => match self.controller.backup_account(user_handle).await {
+                Ok(chunks) => println!("Backed up in {} parts", chunks),
+                Err(e) => println!("{}", e),
+            },
+            "restore" => match self.controller.fetch_backup(user_handle).await {
+                Ok(Some(contents)) => {
+                    let followings = contents.followings.len();
+                    user_handle.restore(contents);
+                    println!(
+                        "Restored {} followings, subscribed to when the account is selected again",
+                        followings
+                    );
+                }
+                Ok(None) => println!("No backup found"),
+                Err(e) => println!("{}", e),
+            },
+            "rehoot" => {
+                let index_s = self.read_arg("index: ");
+                if let Ok(index) = index_s.trim().parse::<usize>() {
+                    if let Some(sigpost) = timeline.get(index) {
+                        // timeline posts were checked with a cached key
+                        match self.identities.get(&sigpost.addr).cloned() {
+                            Some(pk) => match user_handle.rehoot(sigpost.clone(), &pk) {
+                                Ok(sigpost) => self.publish(user_handle, publisher, sigpost).await,
+                                Err(e) => println!("{}", e),
+                            },
+                            None => println!("The key of @{} is unknown", sigpost.addr.to_string()),
+                        }
+                    } else {
+                        println!("Not found");
+                    }
+                } else {
+                    println!("Invalid input");
+                }
+            }
+            "del" => {
+                let id_s = self.read_arg("id: ");
+                if let Ok(id) = id_s.trim().parse::<PostId>() {
+                    match user_handle.del(id) {
+                        Ok(Some(sigpost)) => self.publish(user_handle, publisher, sigpost).await,
+                        Ok(None) => println!("Not found"),
+                        Err(e) => println!("{}", e),
+                    }
+                } else {
+                    println!("Invalid input");
+                }
+            }
+            cmd if cmd.split_whitespace().next() == Some("export") => {
+                let args: Vec<_> = cmd.split_whitespace().skip(1).collect();
+                let format = match args.as_slice() {
+                    [] => "as2",
+                    ["--format", format] => format,
+                    _ => {
+                        println!("Usage: export [--format as2]");
+                        return true;
+                    }
+                };
+                if format != "as2" {
+                    println!("Unsupported format: {}", format);
+                    return true;
+                }
+
+                let addr_bytes: [u8; 32] = user_handle.addr().into();
+                let path = format!("localdata/export_{}.json", hex::encode(addr_bytes));
+                let exported =
+                    serde_json::to_string_pretty(&user_handle.export_activitystreams()).unwrap();
+                let res = match File::create(&path).await {
+                    Ok(mut file) => file.write_all(exported.as_bytes()).await,
+                    Err(e) => Err(e),
+                };
+                match res {
+                    Ok(()) => println!("Exported to {}", path),
+                    Err(e) => println!("Export failed: {}", e),
+                }
+            }
+            "delete-account" => {
+                let confirm = self.read_arg(&format!(
+                    "This deletes @{} for good. Type the user name to confirm: ",
+                    user_handle.addr().to_string()
+                ));
+                if confirm.trim() != user_handle.sig_attr.attr.name {
+                    println!("Cancelled");
+                    return true;
+                }
+
+                let (tombstone, sigpost) = match user_handle.delete_account() {
+                    Ok(ret) => ret,
+                    Err(e) => {
+                        println!("{}", e);
+                        return true;
+                    }
+                };
+                if let Err(e) = publisher
+                    .publish(&serde_json::to_vec(&sigpost).unwrap(), &user_handle.addr())
+                    .await
+                {
+                    println!("{}", e);
+                }
+                self.controller.delete_account(&tombstone).await;
+
+                let addr_bytes: [u8; 32] = user_handle.addr().into();
+                let _ = remove_file(format!("localdata/export_{}.json", hex::encode(addr_bytes)))
+                    .await;
+                println!("Account deleted");
+                return false;
+            }
+            "password" => {
+                if user_handle.external_signer.is_some() {
+                    println!("The key is held by the external signer");
+                    return true;
+                }
+                let password = match self.read_new_password() {
+                    Ok(password) => password,
+                    Err(e) => {
+                        println!("{}", e);
+                        return true;
+                    }
+                };
+                match user_handle.seal_key(&password) {
+                    Ok(()) => println!("Changed the password"),
+                    Err(e) => println!("{}", e),
+                }
+            }
+            "migrate-account" => {
+                let confirm = self.read_arg(&format!(
+                    "This moves @{} to a new key. Type the user name to confirm: ",
+                    user_handle.addr().to_string()
+                ));
+                if confirm.trim() != user_handle.sig_attr.attr.name {
+                    println!("Cancelled");
+                    return true;
+                }
+
+                // asked first, as the new key is lost if it cannot be sealed
+                println!("Set a password for the new key");
+                let password = match self.read_new_password() {
+                    Ok(password) => password,
+                    Err(e) => {
+                        println!("{}", e);
+                        return true;
+                    }
+                };
+
+                let (record, sigpost, mut moved) = match user_handle.migrate(SecretKey::random()) {
+                    Ok(ret) => ret,
+                    Err(e) => {
+                        println!("{}", e);
+                        return true;
+                    }
+                };
+                if let Err(e) = publisher
+                    .publish(&serde_json::to_vec(&sigpost).unwrap(), &user_handle.addr())
+                    .await
+                {
+                    println!("{}", e);
+                }
+                self.register(&moved).await;
+                self.controller.migrate_account(&record).await;
+
+                if let Err(e) = moved.seal_key(&password) {
+                    println!("{}", e);
+                }
+                moved.lock();
+                println!(
+                    "Moved to @{}, select the new account to continue",
+                    moved.addr().to_string()
+                );
+                self.user_handles.push(moved);
+                return false;
+            }
+            _ => (),
+        }
+        true
+    }
+
+    // None if the signer refuses, then the account cannot post
+    async fn register(&self, user_handle: &UserHandle) -> Option<Arc<Publisher>> {
+        match user_handle.registration() {
+            Ok(registration) => {
+                let publisher = self.controller.register_author(&registration).await;
+                self.controller
+                    .register_profile(&registration.pubkey, &user_handle.sig_attr)
+                    .await;
+                Some(publisher)
+            }
+            Err(e) => {
+                println!("{}", e);
+                None
+            }
+        }
+    }
+
+    // Multicasts the post to followers and archives it for later ones. Whoever
+    // it mentions finds it in their mailbox too.
+    async fn publish(&self, user_handle: &UserHandle, publisher: &Publisher, sigpost: SignedPost) {
+        let report = match publisher
+            .publish(&serde_json::to_vec(&sigpost).unwrap(), &user_handle.addr())
+            .await
+        {
+            Ok(report) => report,
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
+        };
+        let unreached = report.unreached().len();
+        if unreached > 0 {
+            println!("Not taken in {} of {} regions", unreached, report.regions.len());
+        }
+        if publisher.receipts_requested() {
+            println!(
+                "Taken by {} nodes, {} of them signed a receipt",
+                report.accepted(),
+                report.receipts().len()
+            );
+        }
+        match user_handle.head_record() {
+            Ok(Some(head)) => match user_handle.history_entry(&sigpost) {
+                Ok(entry) => self.controller.archive(&entry, &head).await,
+                Err(e) => println!("{}", e),
+            },
+            Ok(None) => (),
+            Err(e) => println!("{}", e),
+        }
+        match user_handle.pubkey() {
+            Ok(pubkey) => self.controller.deliver_mentions(&pubkey, &sigpost).await,
+            Err(e) => println!("{}", e),
+        }
+    }
+
+    // follows an account to its new address
+    async fn follow_migration(
+        &mut self,
+        session: &mut Session,
+        feed: &mut AccountFeed,
+        record: &MigrationRecord,
+    ) {
+        match session.apply_migration(record) {
+            Ok(true) => {
+                let (old_addr, new_addr) = (record.old_addr(), record.new_addr());
+                let now = Utc::now().timestamp() as u64;
+                // the record is signed by both keys, so the new one is trusted
+                // as the old one was, and keeps its profile and petname
+                self.identities.migrate(record, now);
+                if self.aliases.rebind(&old_addr, new_addr.clone()).is_some() {
+                    if let Err(e) = self.save_aliases().await {
+                        println!("{}", e);
+                    }
+                }
+                let moved = IdentityChangeKind::Moved {
+                    old: old_addr.clone(),
+                    new: new_addr.clone(),
+                };
+                println!("{}", self.log_identity_change(moved).await);
+                feed.unfollow(&old_addr).await;
+                feed.follow(new_addr).await;
+            }
+            Ok(false) => (),
+            Err(e) => warn!("Invalid migration record: {}", e),
+        }
+    }
+
+    // Records a change applied to a followed account, under the petname it
+    // has now, and returns it to be shown.
+    async fn log_identity_change(&mut self, kind: IdentityChangeKind) -> IdentityChange {
+        let addr = match &kind {
+            IdentityChangeKind::Moved { new, .. } => new,
+            IdentityChangeKind::Deleted(addr) => addr,
+        };
+        let change = IdentityChange {
+            at: Utc::now().timestamp() as u64,
+            petname: self.aliases.name_of(addr).map(str::to_string),
+            kind,
+        };
+        self.identity_log.push(change.clone());
+        let bytes = self.identity_log.to_bytes();
+        if let Err(e) = atomic_file::write(Path::new(IDENTITY_LOG_FILE), &bytes).await {
+            warn!("Cannot save the identity log: {}", e);
+        }
+        change
+    }
+
+    // fills the timeline with what a newly followed author posted before
+    async fn backfill(&self, addr: Address, timeline: &mut Timeline) {
+        let mut events = self.controller.backfill(addr, HISTORY_BACKFILL);
+        let mut progress = false;
+        while let Some(event) = events.recv().await {
+            match event {
+                BackfillEvent::Started { total } => {
+                    println!("Fetching up to {} past posts", total)
+                }
+                BackfillEvent::Progress { done, total } => {
+                    print!("\r{}/{}", done, total);
+                    io::stdout().flush().unwrap();
+                    progress = true;
+                }
+                BackfillEvent::Finished(sigposts) => {
+                    // the history may end before the limit
+                    if progress {
+                        println!();
+                    }
+                    for sigpost in sigposts {
+                        timeline.push(sigpost);
+                    }
+                }
+            }
+        }
+    }
+
+    // an argument of a command; Ctrl-C or Ctrl-D cancels it with an empty line
+    fn read_arg(&mut self, prompt: &str) -> String {
+        self.repl.readline(prompt).unwrap_or_default()
+    }
+
+    // A password for sealing a key, typed twice. Ctrl-C or Ctrl-D gives an
+    // error rather than an empty password.
+    fn read_new_password(&mut self) -> io::Result<String> {
+        let cancelled = |_| io::Error::new(io::ErrorKind::Interrupted, "No password was set");
+        loop {
+            let password = self.repl.read_secret("New password: ", true).map_err(cancelled)?;
+            let again = self.repl.read_secret("Again: ", true).map_err(cancelled)?;
+            if password == again {
+                return Ok(password);
+            }
+            println!("The passwords differ");
+        }
+    }
+
+    // None if the password was not given
+    fn unlock(&mut self, user_handle: &UserHandle) -> Option<UnlockedUserHandle> {
+        if !user_handle.is_locked() {
+            return UnlockedUserHandle::new(user_handle.clone()).ok();
+        }
+        for _ in 0..UNLOCK_ATTEMPTS {
+            let password = self.repl.read_secret("Password: ", true).ok()?;
+            match user_handle.unlock(&password) {
+                Ok(unlocked) => return Some(unlocked),
+                Err(e) => println!("{}", e),
+            }
+        }
+        None
+    }
+
+    // the text of a hoot, None if cancelled
+    fn read_text(&mut self) -> Option<String> {
+        println!("(end with a line containing only `{}`)", END_OF_TEXT);
+        match self.repl.read_multiline() {
+            Ok(text) => Some(text),
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => {
+                println!("Cancelled");
+                None
+            }
+            Err(e) => {
+                println!("{}", e);
+                None
+            }
+        }
+    }
+
+    // shares the node with the subscriptions to the account's own posts
+    async fn subscribe_direct(&self, user_handle: &UserHandle) -> SubscriptionHandle {
+        let subscriber = self.controller.subscriptions().await.subscriber();
+        subscriber.subscribe_direct(user_handle.addr()).await
+    }
+
+    // an address, or a petname given to one, with or without its ~
+    fn parse_address(&self, s: &str, session: &Session) -> Option<Address> {
+        if let Some(addr) = self.aliases.resolve(s.strip_prefix('~').unwrap_or(s)) {
+            return Some(addr.clone());
+        }
+        match Address::parse_strict(s) {
+            Ok(addr) => Some(addr),
+            Err(e) => {
+                println!("Invalid address: {}", e);
+                let known: Vec<_> = session
+                    .followings()
+                    .keys()
+                    .chain(self.identities.addrs())
+                    .cloned()
+                    .collect();
+                for addr in Address::suggest(s, &known) {
+                    println!("Did you mean {}?", addr.to_string());
+                }
+                None
             }
         }
-        user_handle
     }
 
     pub async fn create_new_user(&mut self) -> io::Result<UserHandle> {
-        let secret_key = SecretKey::random();
-        let public_key = PublicKey::from(secret_key.clone());
-        let addr = Address::from(public_key.clone());
+        let user_handle = self.new_account(WatchHandle::new()).await?.lock();
+        self.user_handles.push(user_handle.clone());
+        self.save_users().await?;
+        Ok(user_handle)
+    }
 
-        let mut name = String::new();
-        let mut description = String::new();
+    // a new identity, following whatever the watch-only session followed
+    async fn new_account(&mut self, watch_handle: WatchHandle) -> io::Result<UnlockedUserHandle> {
+        let socket = self
+            .repl
+            .read_secret(
+                "External signer socket, secret key in hex or seed phrase (empty for a new seed phrase): ",
+                false,
+            )
+            .unwrap_or_default();
+        let socket = socket.trim();
+        let imported = hex::decode(socket)
+            .ok()
+            .and_then(|bytes| TryInto::<[u8; 32]>::try_into(bytes).ok());
+        let is_phrase = socket.contains(char::is_whitespace);
+        let external_signer = if socket.is_empty() || imported.is_some() || is_phrase {
+            None
+        } else {
+            Some(
+                ExternalSigner::connect(PathBuf::from(socket))
+                    .await
+                    .map_err(io::Error::other)?,
+            )
+        };
+        let secret_key = match imported {
+            Some(bytes) => {
+                println!("Imported the key, `restore` fetches the backup of the account");
+                SecretKey::from(bytes)
+            }
+            None if is_phrase => {
+                let passphrase = self
+                    .repl
+                    .read_secret("Passphrase (empty for none): ", true)
+                    .unwrap_or_default();
+                let secret_key = SecretKey::from_mnemonic(socket, &passphrase)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                println!("Recovered the key, `restore` fetches the backup of the account");
+                secret_key
+            }
+            None if external_signer.is_none() => {
+                let phrase = generate_mnemonic(SEED_PHRASE_WORDS).unwrap();
+                let passphrase = self
+                    .repl
+                    .read_secret("Passphrase for the seed phrase (empty for none): ", true)
+                    .unwrap_or_default();
+                println!("Write down the seed phrase, it recovers the account with the passphrase:");
+                println!("{}", phrase);
+                SecretKey::from_mnemonic(&phrase, &passphrase).unwrap()
+            }
+            None => SecretKey::random(),
+        };
+        let signer: Box<dyn Signer> = match &external_signer {
+            Some(signer) => Box::new(signer.clone()),
+            None => Box::new(secret_key.clone()),
+        };
+        let public_key = signer.public_key();
+        let addr = Address::from(public_key.clone());
 
-        print!("Name: ");
-        io::stdout().flush().unwrap();
-        io::stdin().read_line(&mut name).unwrap();
-        name = name.trim().to_string();
-        print!("Profile: ");
-        io::stdout().flush().unwrap();
-        io::stdin().read_line(&mut description).unwrap();
-        description = description.trim().to_string();
+        let name = self.read_arg("Name: ").trim().to_string();
+        let description = self.read_arg("Profile: ").trim().to_string();
 
         let created_at: u64 = Utc::now().timestamp().try_into().unwrap();
 
         let user_attr = UserAttribute::new(&name, created_at, &description);
 
-        let signature = secret_key.sign(&serde_json::to_vec(&user_attr).unwrap());
+        let signature = signer
+            .sign(&user_attr.signed_message())
+            .map_err(io::Error::other)?;
         let sig_attr = SignedUserAttribute::new(addr, user_attr, signature);
         sig_attr.verify(&public_key).unwrap();
 
-        let user_handle =
-            UserHandle::new(sig_attr, secret_key.into(), HashMap::new(), &Vec::new());
-        self.user_handles.push(user_handle.clone());
-
-        let mut userfile = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open("localdata/users")
-            .await?;
-        userfile.set_len(0).await?;
-        userfile
-            .write_all(
-                serde_json::to_string(&self.user_handles)
-                    .unwrap()
-                    .as_bytes(),
-            )
-            .await?;
+        let mut user_handle = match external_signer {
+            Some(external_signer) => {
+                watch_handle.upgrade_with_external_signer(sig_attr, external_signer)
+            }
+            None => watch_handle.upgrade(sig_attr, secret_key),
+        };
+        if user_handle.needs_sealing() {
+            println!("Set a password for the key, asked for when selecting the account");
+            let password = self.read_new_password()?;
+            user_handle.seal_key(&password).map_err(io::Error::other)?;
+        }
 
         println!("Created new user: {} @{}",user_handle.sig_attr.attr.name,user_handle.sig_attr.addr.to_string());
 
-        Ok(user_handle)
+        UnlockedUserHandle::new(user_handle).map_err(io::Error::other)
     }
 
     /* pub async fn run(&mut self) -> io::Result<()> {
@@ -417,9 +1749,6 @@ impl CLI {
                         handle.unicast(dummy_info.clone(), args[3].as_bytes()).await
                     );
                 }
-                "bc" => {
-                    println!("{:?}", handle.broadcast(args[1].as_bytes(),).await);
-                }
                 "sr" => {
                     handle.show_routes().await;
                 }
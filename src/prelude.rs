@@ -0,0 +1,18 @@
+//! The types most programs need, kept stable between minor releases.
+//!
+//! ```no_run
+//! use noktulo::prelude::*;
+//! ```
+
+pub use crate::api_client::{ApiClient, ApiClientError};
+pub use crate::api_server::{ApiServer, ApiServerError, ClientMessage, ServerMessage};
+pub use crate::crypto::{PublicKey, SecretKey, Signer};
+pub use crate::kad::{KadConfig, NodeInfo};
+pub use crate::service::{
+    default_networks, Config, NetworkController, Session, UserHandle, WatchHandle,
+};
+pub use crate::user::following::Following;
+pub use crate::user::post::{Post, PostKind, SignedPost};
+pub use crate::user::post_id::PostId;
+pub use crate::user::time_format::{TimeFormat, Zone};
+pub use crate::user::user::{Address, SignedUserAttribute, UserAttribute};
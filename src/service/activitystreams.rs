@@ -0,0 +1,216 @@
+use chrono::{TimeZone, Utc};
+use serde_json::{json, Value};
+
+use crate::user::post::{PostKind, SignedPost};
+use crate::user::post_id::PostId;
+use crate::user::user::Address;
+
+pub const CONTEXT: &str = "https://www.w3.org/ns/activitystreams";
+pub const PUBLIC: &str = "https://www.w3.org/ns/activitystreams#Public";
+
+pub fn actor_uri(addr: &Address) -> String {
+    format!("noktulo:{}", addr.to_string())
+}
+
+pub fn post_uri(addr: &Address, id: PostId) -> String {
+    format!("noktulo:{}/posts/{}", addr.to_string(), id)
+}
+
+// None when created_at is past what chrono can represent, which a
+// forged or corrupt post can claim
+fn published(created_at: u64) -> Option<String> {
+    Utc.timestamp_opt(created_at as i64, 0)
+        .single()
+        .map(|t| t.to_rfc3339())
+}
+
+// leaves "published" out rather than null
+fn drop_unknown_time(mut obj: Value) -> Value {
+    if let Some(map) = obj.as_object_mut() {
+        if map.get("published").is_some_and(Value::is_null) {
+            map.remove("published");
+        }
+    }
+    obj
+}
+
+fn note(sigpost: &SignedPost) -> Value {
+    let mut obj = json!({
+        "type": "Note",
+        "id": post_uri(&sigpost.addr, sigpost.post.id),
+        "attributedTo": actor_uri(&sigpost.addr),
+        "published": published(sigpost.post.created_at),
+        "to": [PUBLIC],
+    });
+
+    if let PostKind::Hoot(hoot) = &sigpost.post.content {
+        obj["content"] = json!(hoot.text.trim_end());
+        if let Some(to) = &hoot.reply_to {
+            obj["inReplyTo"] = json!(post_uri(&to.addr, to.post.id));
+        }
+        let mut attachments = Vec::new();
+        if let Some(quoted) = &hoot.quoted_posts {
+            attachments.push(json!({
+                "type": "Link",
+                "href": post_uri(&quoted.addr, quoted.post.id),
+            }));
+        }
+        if let Some(link) = hoot.link_preview() {
+            let mut attachment = json!({ "type": "Link", "href": link.url });
+            if let Some(title) = &link.title {
+                attachment["name"] = json!(title);
+            }
+            if let Some(description) = &link.description {
+                attachment["summary"] = json!(description);
+            }
+            attachments.push(attachment);
+        }
+        if !attachments.is_empty() {
+            obj["attachment"] = json!(attachments);
+        }
+        if !hoot.mention_to.is_empty() {
+            let tags: Vec<_> = hoot
+                .mention_to
+                .iter()
+                .map(|addr| {
+                    json!({
+                        "type": "Mention",
+                        "href": actor_uri(addr),
+                        "name": format!("@{}", addr.to_string()),
+                    })
+                })
+                .collect();
+            obj["tag"] = json!(tags);
+        }
+    }
+
+    drop_unknown_time(obj)
+}
+
+pub fn activity(sigpost: &SignedPost) -> Value {
+    let actor = actor_uri(&sigpost.addr);
+    let id = post_uri(&sigpost.addr, sigpost.post.id);
+    let published = published(sigpost.post.created_at);
+
+    let activity = match &sigpost.post.content {
+        PostKind::Hoot(_) => json!({
+            "type": "Create",
+            "id": format!("{}/activity", id),
+            "actor": actor,
+            "published": published,
+            "to": [PUBLIC],
+            "object": note(sigpost),
+        }),
+        PostKind::ReHoot(inner) => json!({
+            "type": "Announce",
+            "id": id,
+            "actor": actor,
+            "published": published,
+            "to": [PUBLIC],
+            "object": post_uri(&inner.addr, inner.post.id),
+        }),
+        PostKind::ReHootRef(original) => json!({
+            "type": "Announce",
+            "id": id,
+            "actor": actor,
+            "published": published,
+            "to": [PUBLIC],
+            "object": post_uri(&original.addr, original.id),
+        }),
+        PostKind::Delete(target) => json!({
+            "type": "Delete",
+            "id": id,
+            "actor": actor,
+            "published": published,
+            "object": {
+                "type": "Tombstone",
+                "id": post_uri(&sigpost.addr, *target),
+            },
+        }),
+        PostKind::DeleteAccount => json!({
+            "type": "Delete",
+            "id": id,
+            "actor": actor,
+            "published": published,
+            "object": actor,
+        }),
+        PostKind::Migrate(record) => json!({
+            "type": "Move",
+            "id": id,
+            "actor": actor,
+            "published": published,
+            "object": actor,
+            "target": actor_uri(&record.new_addr()),
+        }),
+        // the text is sealed, only whom it went to is told
+        PostKind::DirectMessage(dm) => json!({
+            "type": "Create",
+            "id": format!("{}/activity", id),
+            "actor": actor,
+            "published": published,
+            "to": [actor_uri(&dm.to)],
+            "object": {
+                "type": "Note",
+                "id": id,
+                "attributedTo": actor,
+                "to": [actor_uri(&dm.to)],
+            },
+        }),
+        PostKind::GroupMessage(gm) => json!({
+            "type": "Create",
+            "id": format!("{}/activity", id),
+            "actor": actor,
+            "published": published,
+            "to": [actor_uri(&gm.to)],
+            "object": {
+                "type": "Note",
+                "id": id,
+                "attributedTo": actor,
+                "to": [actor_uri(&gm.to)],
+            },
+        }),
+    };
+    drop_unknown_time(activity)
+}
+
+pub fn outbox(addr: &Address, posts: &[SignedPost]) -> Value {
+    let items: Vec<_> = posts.iter().rev().map(activity).collect();
+    json!({
+        "@context": CONTEXT,
+        "type": "OrderedCollection",
+        "id": format!("{}/outbox", actor_uri(addr)),
+        "totalItems": items.len(),
+        "orderedItems": items,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::test_user;
+
+    #[test]
+    fn outbox_test() {
+        let mut handle = test_user("alice");
+
+        let hoot = handle
+            .hoot("hello".to_string(), None, None, vec![])
+            .unwrap();
+        handle.rehoot(hoot.clone(), &handle.pubkey().unwrap()).unwrap();
+        handle.del(hoot.post.id).unwrap();
+
+        let v = handle.export_activitystreams();
+        assert_eq!(v["type"], "OrderedCollection");
+        assert_eq!(v["totalItems"], 2);
+        assert_eq!(v["orderedItems"][0]["type"], "Delete");
+        assert_eq!(v["orderedItems"][0]["object"]["id"], post_uri(&hoot.addr, hoot.post.id));
+        assert_eq!(v["orderedItems"][1]["type"], "Announce");
+
+        // a time chrono cannot represent is left out instead of panicking
+        let mut far = hoot;
+        far.post.created_at = u64::MAX / 2;
+        let v = activity(&far);
+        assert!(v.get("published").is_none());
+        assert!(v["object"].get("published").is_none());
+    }
+}
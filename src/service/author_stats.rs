@@ -0,0 +1,128 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::user::post::{PostKind, SignedPost};
+use crate::user::time_format::TimeFormat;
+use crate::user::user::Address;
+
+use super::ingest::SeenPosts;
+
+// What was seen of an author so far, for profile pages. Times are the
+// created_at of the posts, seconds since the epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthorStats {
+    pub posts: u64,
+    pub first_post_at: u64,
+    pub last_post_at: u64,
+}
+
+impl AuthorStats {
+    // None for a post which shows on no profile, like a delete
+    pub fn of(sigpost: &SignedPost) -> Option<AuthorStats> {
+        counts(sigpost).then_some(AuthorStats {
+            posts: 1,
+            first_post_at: sigpost.post.created_at,
+            last_post_at: sigpost.post.created_at,
+        })
+    }
+
+    // a backfilled post may be older than the ones seen before it
+    pub fn record(&mut self, sigpost: &SignedPost) {
+        if !counts(sigpost) {
+            return;
+        }
+        let created_at = sigpost.post.created_at;
+        self.posts += 1;
+        self.first_post_at = self.first_post_at.min(created_at);
+        self.last_post_at = self.last_post_at.max(created_at);
+    }
+}
+
+fn counts(sigpost: &SignedPost) -> bool {
+    !matches!(
+        sigpost.post.content,
+        PostKind::Delete(_)
+            | PostKind::DeleteAccount
+            | PostKind::Migrate(_)
+            | PostKind::DirectMessage(_)
+            | PostKind::GroupMessage(_)
+    )
+}
+
+impl fmt::Display for AuthorStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let date = |at: u64| TimeFormat::date().absolute(at);
+        write!(
+            f,
+            "{} posts seen, from {} to {}",
+            self.posts,
+            date(self.first_post_at),
+            date(self.last_post_at)
+        )
+    }
+}
+
+// The stats of every author whose posts were ingested. The ingestors of one
+// node share it, so a post they all take in is counted once.
+#[derive(Default)]
+pub struct AuthorStatsBook {
+    authors: HashMap<Address, AuthorStats>,
+    counted: SeenPosts,
+}
+
+impl AuthorStatsBook {
+    pub fn new() -> AuthorStatsBook {
+        AuthorStatsBook::default()
+    }
+
+    // the post has to be verified
+    pub fn record(&mut self, sigpost: &SignedPost) {
+        if !counts(sigpost) || !self.counted.insert(sigpost.post_ref()) {
+            return;
+        }
+        match self.authors.get_mut(&sigpost.addr) {
+            Some(stats) => stats.record(sigpost),
+            None => {
+                if let Some(stats) = AuthorStats::of(sigpost) {
+                    self.authors.insert(sigpost.addr.clone(), stats);
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, addr: &Address) -> Option<AuthorStats> {
+        self.authors.get(addr).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::test_user;
+
+    #[test]
+    fn author_stats_test() {
+        let mut alice = test_user("alice");
+        let mut book = AuthorStatsBook::new();
+        assert_eq!(book.get(&alice.addr()), None);
+
+        let mut hoots = Vec::new();
+        for (text, created_at) in [("second", 200), ("third", 300), ("first", 100)] {
+            let mut sigpost = alice.hoot(text.to_string(), None, None, vec![]).unwrap();
+            sigpost.post.created_at = created_at;
+            book.record(&sigpost);
+            hoots.push(sigpost);
+        }
+        // ingested again by another consumer, and a delete
+        book.record(&hoots[0]);
+        book.record(&alice.del(hoots[0].post.id).unwrap().unwrap());
+
+        let expected = AuthorStats {
+            posts: 3,
+            first_post_at: 100,
+            last_post_at: 300,
+        };
+        assert_eq!(book.get(&alice.addr()), Some(expected));
+    }
+}
@@ -0,0 +1,359 @@
+use log::{info, warn};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_big_array::BigArray;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use thiserror::Error;
+use tokio::net;
+
+use crate::crypto::{PublicKey, Signer, SignerError};
+use crate::kad::{NodeInfo, Rpc};
+
+use super::http::{self, HttpError};
+use super::{BOOTSTRAP_LIST_LIMIT, BOOTSTRAP_LIST_TIME_OUT};
+
+// A bootstrap node as an operator writes it: an address, or a host name and a
+// port, so that a node whose IP changes is still found through DNS.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BootstrapAddr {
+    host: String,
+    port: u16,
+}
+
+impl BootstrapAddr {
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    // an IP address needs no lookup, nor looking up again
+    pub fn is_name(&self) -> bool {
+        self.socket_addr().is_none()
+    }
+
+    fn socket_addr(&self) -> Option<SocketAddr> {
+        SocketAddr::from_str(&self.to_string()).ok()
+    }
+
+    // every A and AAAA record of the host
+    pub async fn resolve(&self) -> io::Result<Vec<SocketAddr>> {
+        if let Some(addr) = self.socket_addr() {
+            return Ok(vec![addr]);
+        }
+        let mut addrs: Vec<SocketAddr> = Vec::new();
+        for addr in net::lookup_host((self.host.as_str(), self.port)).await? {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+        Ok(addrs)
+    }
+
+    // Asks the resolved addresses for their nodes in turn, until one answers.
+    // Returns the address which answered too.
+    pub async fn fetch_nodeinfos(&self) -> Result<(SocketAddr, Vec<NodeInfo>), BootstrapError> {
+        let addrs = self.resolve().await.map_err(BootstrapError::Resolve)?;
+        if addrs.is_empty() {
+            return Err(BootstrapError::NoRecords);
+        }
+        for addr in addrs {
+            match Rpc::get_nodeinfos(addr).await {
+                Ok(node_infos) => return Ok((addr, node_infos)),
+                Err(e) => info!("Bootstrap node {} ({}) did not answer: {}", self, addr, e),
+            }
+        }
+        Err(BootstrapError::NoAnswer)
+    }
+}
+
+// node infos of every bootstrap node which answered
+pub async fn fetch_bootstrap(addrs: &[BootstrapAddr]) -> Vec<NodeInfo> {
+    let mut ret = Vec::new();
+    for addr in addrs {
+        match addr.fetch_nodeinfos().await {
+            Ok((_, mut node_infos)) => ret.append(&mut node_infos),
+            Err(e) => warn!("Bootstrap node {}: {}", addr, e),
+        }
+    }
+    ret
+}
+
+// Bootstrap nodes a community publishes as a JSON document on any web host,
+// signed by its maintainer, so that the host cannot change the list, and
+// with an expiry, so that a stale copy is not served forever.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BootstrapList {
+    pub nodes: Vec<NodeInfo>,
+    pub issued_at: u64,
+    pub expires_at: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedBootstrapList {
+    pub maintainer: PublicKey,
+    pub list: BootstrapList,
+    #[serde(with = "BigArray")]
+    pub signature: [u8; 64],
+}
+
+impl SignedBootstrapList {
+    pub fn sign(
+        signer: &dyn Signer,
+        list: BootstrapList,
+    ) -> Result<SignedBootstrapList, SignerError> {
+        let signature = signer.sign(&SignedBootstrapList::signed_message(&list))?;
+        Ok(SignedBootstrapList {
+            maintainer: signer.public_key(),
+            list,
+            signature,
+        })
+    }
+
+    pub fn signed_message(list: &BootstrapList) -> Vec<u8> {
+        [&b"noktulo:bootstrap:"[..], &serde_json::to_vec(list).unwrap()].concat()
+    }
+
+    // the nodes, if the list is signed by maintainer and not expired at now
+    pub fn verify(&self, maintainer: &PublicKey, now: u64) -> Result<&[NodeInfo], BootstrapError> {
+        if self.maintainer != *maintainer {
+            return Err(BootstrapError::Maintainer);
+        }
+        self.maintainer
+            .verify(&self.signature, &SignedBootstrapList::signed_message(&self.list))
+            .map_err(|_| BootstrapError::Signature)?;
+        if self.list.expires_at <= now {
+            return Err(BootstrapError::Expired(self.list.expires_at));
+        }
+        Ok(&self.list.nodes)
+    }
+}
+
+// Where a bootstrap list is fetched from, http or https, and the key of the
+// maintainer who has to have signed it. Written "url#maintainer key in hex".
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BootstrapListSource {
+    pub url: String,
+    pub maintainer: PublicKey,
+}
+
+impl BootstrapListSource {
+    pub async fn fetch(&self, now: u64) -> Result<Vec<NodeInfo>, BootstrapError> {
+        let body = http::get(&self.url, BOOTSTRAP_LIST_LIMIT, BOOTSTRAP_LIST_TIME_OUT).await?;
+        let signed: SignedBootstrapList =
+            serde_json::from_slice(&body).map_err(|_| BootstrapError::Malformed)?;
+        Ok(signed.verify(&self.maintainer, now)?.to_vec())
+    }
+}
+
+impl FromStr for BootstrapListSource {
+    type Err = BootstrapError;
+
+    fn from_str(s: &str) -> Result<BootstrapListSource, BootstrapError> {
+        let invalid = || BootstrapError::Invalid(s.to_string());
+        let (url, key) = s.rsplit_once('#').ok_or_else(invalid)?;
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(invalid());
+        }
+        let key = hex::decode(key).map_err(|_| invalid())?;
+        let maintainer = PublicKey::try_from(&key[..]).map_err(|_| invalid())?;
+        Ok(BootstrapListSource {
+            url: url.to_string(),
+            maintainer,
+        })
+    }
+}
+
+impl fmt::Display for BootstrapListSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}#{}", self.url, self.maintainer)
+    }
+}
+
+impl Serialize for BootstrapListSource {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for BootstrapListSource {
+    fn deserialize<D>(deserializer: D) -> Result<BootstrapListSource, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+// the nodes of every list which was fetched and checked
+pub async fn fetch_bootstrap_lists(sources: &[BootstrapListSource], now: u64) -> Vec<NodeInfo> {
+    let mut ret = Vec::new();
+    for source in sources {
+        match source.fetch(now).await {
+            Ok(mut node_infos) => ret.append(&mut node_infos),
+            Err(e) => warn!("Bootstrap list {}: {}", source.url, e),
+        }
+    }
+    ret
+}
+
+impl From<SocketAddr> for BootstrapAddr {
+    fn from(addr: SocketAddr) -> BootstrapAddr {
+        BootstrapAddr {
+            host: addr.ip().to_string(),
+            port: addr.port(),
+        }
+    }
+}
+
+impl FromStr for BootstrapAddr {
+    type Err = BootstrapError;
+
+    fn from_str(s: &str) -> Result<BootstrapAddr, BootstrapError> {
+        if let Ok(addr) = SocketAddr::from_str(s) {
+            return Ok(BootstrapAddr::from(addr));
+        }
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| BootstrapError::Invalid(s.to_string()))?;
+        let port = port
+            .parse()
+            .map_err(|_| BootstrapError::Invalid(s.to_string()))?;
+        // a bare IPv6 address has to be in brackets, and is caught above
+        if host.is_empty() || host.contains(':') || host.contains(char::is_whitespace) {
+            return Err(BootstrapError::Invalid(s.to_string()));
+        }
+        Ok(BootstrapAddr {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for BootstrapAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+// written as "host:port" in tunables files
+impl Serialize for BootstrapAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for BootstrapAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<BootstrapAddr, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum BootstrapError {
+    #[error("Not a host:port pair: {0}")]
+    Invalid(String),
+    #[error("Cannot resolve: {0}")]
+    Resolve(io::Error),
+    #[error("No addresses found")]
+    NoRecords,
+    #[error("No resolved address answered")]
+    NoAnswer,
+    #[error("Cannot fetch the list: {0}")]
+    Fetch(#[from] HttpError),
+    #[error("The list is not a signed bootstrap list")]
+    Malformed,
+    #[error("The list is signed by another maintainer")]
+    Maintainer,
+    #[error("Invalid signature on the list")]
+    Signature,
+    #[error("The list expired at {0}")]
+    Expired(u64),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::SecretKey;
+    use crate::kad::Key;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn bootstrap_addr_test() {
+        let named: BootstrapAddr = "seed.example.org:6271".parse().unwrap();
+        assert_eq!((named.host(), named.port()), ("seed.example.org", 6271));
+        assert!(named.is_name());
+        let v6: BootstrapAddr = "[::1]:6271".parse().unwrap();
+        assert!(!v6.is_name());
+        assert_eq!(v6.to_string(), "[::1]:6271");
+        for invalid in ["seed.example.org", "::1:6271", "seed:port", ":6271"] {
+            assert!(invalid.parse::<BootstrapAddr>().is_err(), "{}", invalid);
+        }
+
+        let json = serde_json::to_string(&vec![named.clone(), v6.clone()]).unwrap();
+        assert_eq!(json, r#"["seed.example.org:6271","[::1]:6271"]"#);
+        assert_eq!(
+            serde_json::from_str::<Vec<BootstrapAddr>>(&json).unwrap(),
+            vec![named, v6]
+        );
+
+        // resolved without any DNS server
+        let local: BootstrapAddr = "localhost:6271".parse().unwrap();
+        let addrs = local.resolve().await.unwrap();
+        assert!(addrs.iter().all(|addr| addr.ip().is_loopback() && addr.port() == 6271));
+    }
+
+    #[tokio::test]
+    async fn bootstrap_list_test() {
+        let (maintainer, other) = (SecretKey::random(), SecretKey::random());
+        let list = BootstrapList {
+            nodes: vec![NodeInfo {
+                id: Key::random(32),
+                addr: "127.0.0.1:6270".parse().unwrap(),
+                net_id: "test_user_dht".to_string(),
+            }],
+            issued_at: 100,
+            expires_at: 200,
+        };
+        let signed = SignedBootstrapList::sign(&maintainer, list).unwrap();
+        assert_eq!(signed.verify(&maintainer.public_key(), 150).unwrap().len(), 1);
+        let pubkey = maintainer.public_key();
+        assert!(matches!(signed.verify(&pubkey, 200), Err(BootstrapError::Expired(200))));
+        let other = other.public_key();
+        assert!(matches!(signed.verify(&other, 150), Err(BootstrapError::Maintainer)));
+        let mut forged = signed.clone();
+        forged.list.expires_at = 1000;
+        assert!(matches!(forged.verify(&pubkey, 150), Err(BootstrapError::Signature)));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let s = format!(
+            "http://{}/nodes.json#{}",
+            listener.local_addr().unwrap(),
+            maintainer.public_key()
+        );
+        let source: BootstrapListSource = s.parse().unwrap();
+        assert_eq!(source.to_string(), s);
+        assert!("ftp://example.org#00".parse::<BootstrapListSource>().is_err());
+        let body = serde_json::to_string(&signed).unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 1024];
+            assert!(socket.read(&mut buf).await.unwrap() > 0);
+            let response = format!("HTTP/1.1 200 OK\r\n\r\n{}", body);
+            socket.write_all(response.as_bytes()).await.unwrap();
+        });
+        assert_eq!(fetch_bootstrap_lists(&[source], 150).await, signed.list.nodes);
+    }
+}
@@ -0,0 +1,169 @@
+use serde::{Deserialize, Serialize};
+
+use crate::user::mention::{self, MentionError};
+use crate::user::post::{Hoot, Post, PostKind, SignedPost};
+use crate::user::post_id::PostId;
+use crate::user::user::{Address, UserAttribute};
+use crate::util::canonical;
+
+use super::MAX_POST_LEN;
+
+// What an `@` token of a draft resolves to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MentionResolution {
+    pub token: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub addr: Option<Address>,
+    // the accounts the token may mean, when it does not resolve
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub candidates: Vec<Address>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DraftWarning {
+    // no text, nothing quoted
+    Empty,
+    // the bytes the post would take as published, over MAX_POST_LEN
+    TooLarge(usize),
+    UnknownMention(String),
+    AmbiguousMention(String),
+    // the link preview would not be shown, for this reason
+    InvalidLink(String),
+}
+
+// How a draft would come out as a post, for a composer to show before signing
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DraftReport {
+    // bytes the post takes as published, which ingestors hold to MAX_POST_LEN,
+    // and of its canonical encoding, which gets signed; upper bounds, since
+    // the id is not drawn yet
+    pub encoded_len: usize,
+    pub signed_len: usize,
+    pub mentions: Vec<MentionResolution>,
+    // the addresses the post would be delivered to as mentions, the ones
+    // listed in the hoot and the ones resolved from its text
+    pub mention_to: Vec<Address>,
+    pub warnings: Vec<DraftWarning>,
+}
+
+impl DraftReport {
+    pub fn is_ok(&self) -> bool {
+        self.warnings.is_empty()
+    }
+}
+
+// Checks hoot as author, with user_attr, would post it. The `@` tokens of its
+// text are resolved with the names of known.
+pub fn validate_draft<'a, I>(
+    hoot: &Hoot,
+    author: &Address,
+    user_attr: &UserAttribute,
+    known: I,
+) -> DraftReport
+where
+    I: IntoIterator<Item = (&'a str, &'a Address)>,
+{
+    let mut warnings = Vec::new();
+    if hoot.text.trim().is_empty() && hoot.quoted_posts.is_none() {
+        warnings.push(DraftWarning::Empty);
+    }
+
+    let mut mention_to = hoot.mention_to.clone();
+    let mut mentions = Vec::new();
+    for (token, resolved) in mention::resolve_each(&hoot.text, known) {
+        let resolution = match resolved {
+            Ok(addr) => {
+                if !mention_to.contains(&addr) {
+                    mention_to.push(addr.clone());
+                }
+                MentionResolution {
+                    token,
+                    addr: Some(addr),
+                    candidates: Vec::new(),
+                }
+            }
+            Err(e) => {
+                warnings.push(match &e {
+                    MentionError::Unknown(..) => DraftWarning::UnknownMention(token.clone()),
+                    MentionError::Ambiguous(..) => DraftWarning::AmbiguousMention(token.clone()),
+                });
+                MentionResolution {
+                    token,
+                    addr: None,
+                    candidates: e.suggestions().to_vec(),
+                }
+            }
+        };
+        mentions.push(resolution);
+    }
+
+    if let Some(link) = &hoot.link {
+        if let Err(e) = link.validate() {
+            warnings.push(DraftWarning::InvalidLink(e.to_string()));
+        }
+    }
+
+    let mut hoot = hoot.clone();
+    hoot.mention_to = mention_to.clone();
+    let post = Post {
+        user_attr: user_attr.clone(),
+        id: PostId(u128::MAX),
+        content: PostKind::Hoot(hoot),
+        created_at: u64::MAX,
+    };
+    let signed_len = canonical::to_vec(&post).len();
+    let encoded_len = serde_json::to_vec(&SignedPost::new(author.clone(), post, [0xff; 64]))
+        .unwrap()
+        .len();
+    if encoded_len > MAX_POST_LEN {
+        warnings.push(DraftWarning::TooLarge(encoded_len));
+    }
+
+    DraftReport {
+        encoded_len,
+        signed_len,
+        mentions,
+        mention_to,
+        warnings,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::SecretKey;
+    use crate::user::link::LinkPreview;
+
+    #[test]
+    fn validate_draft_test() {
+        let author = Address::from(SecretKey::random().public_key());
+        let (alice, bob, bob2) = (
+            Address::from(SecretKey::random().public_key()),
+            Address::from(SecretKey::random().public_key()),
+            Address::from(SecretKey::random().public_key()),
+        );
+        let known = vec![("Alice", &alice), ("bob", &bob), ("Bob", &bob2)];
+        let attr = UserAttribute::new("me", 0, "");
+
+        let hoot = Hoot::new("hi @alice".to_string());
+        let report = validate_draft(&hoot, &author, &attr, known.clone());
+        assert!(report.is_ok());
+        assert_eq!(report.mention_to, vec![alice.clone()]);
+        assert_eq!(report.mentions[0].addr, Some(alice.clone()));
+        assert!(report.signed_len < report.encoded_len);
+
+        let mut hoot = Hoot::new("@bob @carol".to_string());
+        hoot.link = Some(LinkPreview::new("ftp://example.com"));
+        let report = validate_draft(&hoot, &author, &attr, known.clone());
+        assert_eq!(report.mentions[0].candidates.len(), 2);
+        assert!(report.mention_to.is_empty());
+        assert!(report.warnings.contains(&DraftWarning::AmbiguousMention("bob".to_string())));
+        assert!(report.warnings.contains(&DraftWarning::UnknownMention("carol".to_string())));
+        assert!(matches!(report.warnings.last(), Some(DraftWarning::InvalidLink(_))));
+
+        let report = validate_draft(&Hoot::new(" ".to_string()), &author, &attr, known.clone());
+        assert_eq!(report.warnings, vec![DraftWarning::Empty]);
+        let report = validate_draft(&Hoot::new("a".repeat(MAX_POST_LEN)), &author, &attr, known);
+        assert_eq!(report.warnings, vec![DraftWarning::TooLarge(report.encoded_len)]);
+    }
+}
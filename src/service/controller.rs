@@ -1,81 +1,872 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    net::{IpAddr, SocketAddr, SocketAddrV4},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use log::info;
-use tokio::{net::UdpSocket, sync::Mutex};
-//use crate::crypto::PublicKey;
-use ed25519_dalek::PublicKey;
+use crate::crypto::{PublicKey, SecretKey, SignerError};
+use chrono::Utc;
+use log::{info, warn};
+use tokio::{
+    net::UdpSocket,
+    sync::{broadcast, mpsc, Mutex, OnceCell},
+    time::Duration,
+};
 
 use crate::{
-    kad::{NodeInfo, Rpc},
-    service::{Publisher, Subscriber, UserDHT, PUBSUB_DHT_KEY_LENGTH, USER_DHT_KEY_LENGTH},
-    user::user::Address,
+    kad::{
+        FileBackend, KadConfig, Key, NetworkDescriptor, NetworkRegistry, NodeInfo, PeerStore,
+        PeerStoreFile, Rpc, StoreBackend,
+    },
+    service::{
+        backfill, export_metrics, fetch_bootstrap, fetch_bootstrap_lists, lan_discovery,
+        AccountStatus, AuthorStats, AuthorStatsBook, BackfillEvent, BootstrapAddr, BootstrapListSource, ConfigEvent, Ingestor, Notifier, PortMapper, PortMapping, Publisher,
+        Scheduler, Subscriber, SubscriptionManager, SyncStatus, Tunables,
+        TunablesError, UserDHT, UserHandle, VerificationReport, BOOTSTRAP_RESOLVE_INTERVAL,
+        BOOTSTRAP_RESOLVE_JITTER, BROADCAST_SWEEP_INTERVAL, BROADCAST_SWEEP_JITTER,
+        CONFIG_EVENTS_CAPACITY, INVITE_PEERS_PER_DHT, LAN_DISCOVERY_GROUP, LAN_DISCOVERY_PORT,
+        LAN_DISCOVERY_WAIT, MAINTENANCE_TICK, NODE_ID_LEN, PEER_STORE_BOOTSTRAP, PEER_STORE_SAVE_INTERVAL,
+        OTLP_EXPORT_INTERVAL, OTLP_EXPORT_JITTER, PEER_STORE_SAVE_JITTER, RECEIPT_KEY_FILE, REPUBLISH_INTERVAL,
+        REPUBLISH_JITTER,
+        TESTNET_PUBSUB_DHT, TESTNET_USER_DHT,
+    },
+    user::backup::{self, BackupContents, BackupError},
+    user::history::{HeadRecord, HistoryEntry},
+    user::mailbox::MailboxItem,
+    user::post::SignedPost,
+    user::post_id::PostId,
+    user::user::{
+        AccountTombstone, Address, MigrationRecord, ProfileRecord, RegistrationRecord,
+        SignedUserAttribute,
+    },
+    util::atomic_file,
+    util::rng::{self, Entropy},
+    util::stats::{Stats, StatsRecorder},
 };
 
 pub struct NetworkController {
+    // the pubsub DHT's, which is the user DHT's unless it has its own socket
     rpc: Arc<Mutex<Rpc>>,
 
     user_dht: UserDHT,
-    pubsub_dht_bootstrap: Vec<NodeInfo>,
+    pubsub_net: NetworkDescriptor,
+    pubsub_dht_bootstrap: Arc<Mutex<Vec<NodeInfo>>>,
+    registrations: Arc<Mutex<Vec<RegistrationRecord>>>,
+    profiles: Arc<Mutex<Vec<ProfileRecord>>>,
+    publisher: Arc<Publisher>,
+    scheduler: Scheduler,
+    stats: Arc<StatsRecorder>,
+    entropy: Arc<dyn Entropy>,
+    reloader: Reloader,
+    port_mapper: PortMapper,
+    peer_store: Arc<std::sync::Mutex<PeerStore>>,
+    peer_file: Option<PeerStoreFile>,
+    archive_posts: bool,
+    // created on first use, so that a relay runs no subscriber
+    subscriptions: OnceCell<SubscriptionManager>,
+    // watches what the subscriptions receive, created with them
+    notifier: OnceCell<Arc<Notifier>>,
+    // what the ingestors of the node took in from each author
+    author_stats: Arc<std::sync::Mutex<AuthorStatsBook>>,
+    // picks the shard of each author its subscribers listen on
+    subscriber_id: Key,
+}
+
+// Where an address lives now, and what was seen of the account there
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedUser {
+    pub addr: Address,
+    pub pubkey: PublicKey,
+    // None when no post of it was ingested yet
+    pub stats: Option<AuthorStats>,
 }
 
 impl NetworkController {
     pub async fn init(config: Config) -> NetworkController {
-        let mut bootstrap_nodeinfo = Vec::new();
-        for addr in config.bootstrap {
-            let ret = Rpc::get_nodeinfos(addr).await;
-            if let Ok(mut v) = ret {
-                bootstrap_nodeinfo.append(&mut v);
+        let tunables = match &config.tunables_path {
+            Some(path) if path.exists() => Tunables::load(path).await.unwrap_or_else(|e| {
+                warn!("{}, starting with the default tunables", e);
+                Tunables::default()
+            }),
+            _ => Tunables::default(),
+        };
+        if let Some(level) = tunables.log_level() {
+            log::set_max_level(level);
+        }
+
+        let user_net = config
+            .networks
+            .get(TESTNET_USER_DHT)
+            .expect("the user DHT is not registered")
+            .clone();
+        let pubsub_net = config
+            .networks
+            .get(TESTNET_PUBSUB_DHT)
+            .expect("the pubsub DHT is not registered")
+            .clone();
+
+        // The best peers of earlier runs go first; the bootstrap servers are
+        // only asked when there are none, or none of them answers.
+        let peer_file = match &config.peer_store_path {
+            Some(path) => Some(PeerStoreFile::open(path).await),
+            None => None,
+        };
+        let peer_store = match &peer_file {
+            Some(peer_file) => peer_file.store(),
+            None => Arc::new(std::sync::Mutex::new(PeerStore::new())),
+        };
+        let mut bootstrap_nodeinfo: Vec<NodeInfo> = {
+            let peer_store = peer_store.lock().unwrap();
+            [&user_net.net_id, &pubsub_net.net_id]
+                .iter()
+                .flat_map(|net_id| peer_store.best(net_id, PEER_STORE_BOOTSTRAP))
+                .map(|record| record.node_info.clone())
+                .collect()
+        };
+        let from_store = !bootstrap_nodeinfo.is_empty();
+        let bootstrap: Vec<BootstrapAddr> =
+            config.bootstrap.iter().chain(tunables.bootstrap.iter()).cloned().collect();
+        if from_store {
+            info!("Bootstrapping from {} stored peers", bootstrap_nodeinfo.len());
+        } else {
+            bootstrap_nodeinfo = fetch_bootstrap(&bootstrap).await;
+            let now = Utc::now().timestamp() as u64;
+            bootstrap_nodeinfo.extend(fetch_bootstrap_lists(&config.bootstrap_lists, now).await);
+        }
+        let lan_group = SocketAddrV4::new(LAN_DISCOVERY_GROUP.into(), LAN_DISCOVERY_PORT);
+        if config.lan_discovery {
+            let wait = Duration::from_millis(LAN_DISCOVERY_WAIT);
+            match lan_discovery::discover(lan_group, wait).await {
+                Ok(mut v) => bootstrap_nodeinfo.append(&mut v),
+                Err(e) => warn!("LAN discovery failed: {}", e),
             }
         }
 
-        let user_dht_bootstrap: Vec<_> = bootstrap_nodeinfo
-            .iter()
-            .filter(|ni| ni.id.len() == USER_DHT_KEY_LENGTH)
-            .cloned()
-            .collect();
-        let pubsub_dht_bootstrap: Vec<_> = bootstrap_nodeinfo
-            .iter()
-            .filter(|ni| ni.id.len() == PUBSUB_DHT_KEY_LENGTH)
-            .cloned()
-            .collect();
+        let user_dht_bootstrap = config.networks.bootstrap(TESTNET_USER_DHT, &bootstrap_nodeinfo);
+        let pubsub_dht_bootstrap =
+            config.networks.bootstrap(TESTNET_PUBSUB_DHT, &bootstrap_nodeinfo);
 
         let socket = UdpSocket::bind(config.bind_addr).await.unwrap();
-        let rpc = Rpc::new(socket);
+        let mut rpc = Rpc::new(socket);
+        rpc.set_kad_config(config.kad);
+        rpc.set_entropy(rng::from_seed(config.rng_seed));
+        rpc.set_peer_store(peer_store.clone());
+        rpc.set_store_limit(config.store_limit);
+        rpc.set_bandwidth_limit(config.bandwidth_limit);
+        let subscriber_id = match &config.node_id_path {
+            Some(path) => {
+                let entropy = rpc.entropy();
+                let id = load_or_draw(path, NODE_ID_LEN, "node id", entropy.as_ref()).await;
+                if config.kad.delivery_receipts {
+                    let path = path.with_file_name(RECEIPT_KEY_FILE);
+                    let bytes = load_or_draw(&path, 32, "receipt key", entropy.as_ref()).await;
+                    let key = SecretKey::from_bytes(&bytes.try_into().unwrap());
+                    rpc.set_receipt_key(Arc::new(key));
+                }
+                Key::from(&id[..])
+            }
+            None => Key::random_from(NODE_ID_LEN, rpc.entropy().as_ref()),
+        };
+        let pubsub_rpc = match config.pubsub_bind_addr {
+            Some(addr) => rpc.sibling(UdpSocket::bind(addr).await.unwrap()),
+            None => rpc.clone(),
+        };
         if let Some(addr) = config.nodeinfo_addr {
-            rpc.start_nodeinfo_server(addr).await.unwrap();
+            Rpc::serve_nodeinfo(vec![rpc.clone(), pubsub_rpc.clone()], addr)
+                .await
+                .unwrap();
+        }
+        let port_mapper = PortMapper::new();
+        if config.port_mapping {
+            let mut ports = vec![rpc.socket.local_addr().unwrap().port()];
+            ports.push(pubsub_rpc.socket.local_addr().unwrap().port());
+            ports.dedup();
+            // discovering the gateway takes seconds, so the node starts meanwhile
+            let mapper = port_mapper.clone();
+            tokio::spawn(async move { mapper.start(&ports).await });
+        }
+        if config.lan_discovery {
+            let rpcs = vec![rpc.clone(), pubsub_rpc.clone()];
+            if let Err(e) = lan_discovery::serve(rpcs, lan_group).await {
+                warn!("LAN discovery is not answered: {}", e);
+            }
         }
 
-        let user_dht = UserDHT::start(Arc::new(Mutex::new(rpc.clone())), &user_dht_bootstrap).await;
+        let started = Utc::now().timestamp() as u64;
+        let backend = config.store_dir.as_ref().map(|dir| {
+            Arc::new(FileBackend::new(dir.join(&user_net.net_id))) as Arc<dyn StoreBackend>
+        });
+        let user_dht = UserDHT::start(
+            Arc::new(Mutex::new(rpc.clone())),
+            &user_net,
+            &user_dht_bootstrap,
+            backend,
+        )
+        .await;
+        let publisher = Publisher::new(
+            Arc::new(Mutex::new(pubsub_rpc.clone())),
+            &pubsub_net,
+            &pubsub_dht_bootstrap,
+        )
+        .await;
+
+        let registrations = Arc::new(Mutex::new(Vec::new()));
+        let profiles = Arc::new(Mutex::new(Vec::new()));
+        let scheduler = Scheduler::with_clock(Duration::from_millis(MAINTENANCE_TICK), rpc.clock());
+
+        let dht = user_dht.clone();
+        let records = registrations.clone();
+        let published = profiles.clone();
+        scheduler
+            .register(
+                "republish_pubkeys",
+                Duration::from_millis(REPUBLISH_INTERVAL),
+                Duration::from_millis(REPUBLISH_JITTER),
+                move || {
+                    let dht = dht.clone();
+                    let records = records.clone();
+                    let published = published.clone();
+                    async move {
+                        let records: Vec<RegistrationRecord> = records.lock().await.clone();
+                        for record in records.iter() {
+                            dht.register_pubkey(record).await;
+                        }
+                        let published: Vec<ProfileRecord> = published.lock().await.clone();
+                        for record in published.iter() {
+                            dht.register_profile(&record.pubkey, &record.profile).await;
+                        }
+                    }
+                },
+            )
+            .await;
+        if let Some(peer_file) = peer_file.clone() {
+            scheduler
+                .register(
+                    "save_peers",
+                    Duration::from_millis(PEER_STORE_SAVE_INTERVAL),
+                    Duration::from_millis(PEER_STORE_SAVE_JITTER),
+                    move || {
+                        let peer_file = peer_file.clone();
+                        async move { flush_peers(&peer_file).await }
+                    },
+                )
+                .await;
+        }
+        scheduler.start().await;
+
+        let publisher = Arc::new(publisher);
+        let pubsub_dht_bootstrap = Arc::new(Mutex::new(pubsub_dht_bootstrap));
+        let reloader = Reloader {
+            path: config.tunables_path,
+            tunables: Arc::new(std::sync::Mutex::new(tunables)),
+            events: broadcast::channel(CONFIG_EVENTS_CAPACITY).0,
+            networks: config.networks,
+            user_dht: user_dht.clone(),
+            publisher: publisher.clone(),
+            pubsub_dht_bootstrap: pubsub_dht_bootstrap.clone(),
+            config_bootstrap: config.bootstrap,
+            config_bootstrap_lists: config.bootstrap_lists,
+            resolved: Arc::new(Mutex::new(HashMap::new())),
+        };
+        // the addresses found now, to tell later which ones are new
+        reloader.refresh_bootstrap().await;
+        if from_store && peer_store.lock().unwrap().seen_since(started) == 0 {
+            info!("No stored peer answered, falling back to the bootstrap servers");
+            reloader.add_bootstrap(&bootstrap).await;
+            let now = Utc::now().timestamp() as u64;
+            let listed = fetch_bootstrap_lists(&reloader.config_bootstrap_lists, now).await;
+            reloader.add_peers(&listed).await;
+        }
+        let refresher = reloader.clone();
+        scheduler
+            .register(
+                "resolve_bootstrap",
+                Duration::from_millis(BOOTSTRAP_RESOLVE_INTERVAL),
+                Duration::from_millis(BOOTSTRAP_RESOLVE_JITTER),
+                move || {
+                    let refresher = refresher.clone();
+                    async move { refresher.refresh_bootstrap().await }
+                },
+            )
+            .await;
+        let (dht, relay) = (user_dht.clone(), publisher.clone());
+        scheduler
+            .register(
+                "sweep_broadcast_tokens",
+                Duration::from_millis(BROADCAST_SWEEP_INTERVAL),
+                Duration::from_millis(BROADCAST_SWEEP_JITTER),
+                move || {
+                    let (dht, relay) = (dht.clone(), relay.clone());
+                    async move {
+                        dht.sweep_broadcast_tokens().await;
+                        relay.sweep_broadcast_tokens().await;
+                    }
+                },
+            )
+            .await;
+        // reads the endpoint every time, so that a reload turns the export on or off
+        let (tunables, stats) = (reloader.tunables.clone(), rpc.stats());
+        let started_ns = Utc::now().timestamp_millis() as u64 * 1_000_000;
+        scheduler
+            .register(
+                "export_metrics",
+                Duration::from_millis(OTLP_EXPORT_INTERVAL),
+                Duration::from_millis(OTLP_EXPORT_JITTER),
+                move || {
+                    let endpoint = tunables.lock().unwrap().otlp_endpoint();
+                    let snapshot = stats.snapshot();
+                    async move {
+                        if let Some(endpoint) = endpoint {
+                            let now_ns = Utc::now().timestamp_millis() as u64 * 1_000_000;
+                            let exported =
+                                export_metrics(&endpoint, &snapshot, started_ns, now_ns).await;
+                            if let Err(e) = exported {
+                                warn!("Cannot export the metrics: {}", e);
+                            }
+                        }
+                    }
+                },
+            )
+            .await;
+        #[cfg(unix)]
+        if reloader.path.is_some() {
+            reloader.clone().reload_on_sighup();
+        }
 
         NetworkController {
-            rpc: Arc::new(Mutex::new(rpc)),
+            stats: rpc.stats(),
+            entropy: rpc.entropy(),
+            rpc: Arc::new(Mutex::new(pubsub_rpc)),
             user_dht,
+            pubsub_net,
             pubsub_dht_bootstrap,
+            registrations,
+            profiles,
+            publisher,
+            scheduler,
+            reloader,
+            port_mapper,
+            peer_store,
+            peer_file,
+            archive_posts: config.archive_posts,
+            subscriptions: OnceCell::new(),
+            notifier: OnceCell::new(),
+            author_stats: Arc::new(std::sync::Mutex::new(AuthorStatsBook::new())),
+            subscriber_id,
+        }
+    }
+
+    // every peer that answered, in this run or an earlier one
+    pub fn peer_store(&self) -> Arc<std::sync::Mutex<PeerStore>> {
+        self.peer_store.clone()
+    }
+
+    // the peers to put into an invite: the most reliable stored ones of each DHT
+    pub fn invite_peers(&self) -> Vec<NodeInfo> {
+        let user_net = self.reloader.networks.get(TESTNET_USER_DHT).unwrap();
+        let peer_store = self.peer_store.lock().unwrap();
+        [&user_net.net_id, &self.pubsub_net.net_id]
+            .iter()
+            .flat_map(|net_id| peer_store.best(net_id, INVITE_PEERS_PER_DHT))
+            .map(|record| record.node_info.clone())
+            .collect()
+    }
+
+    // bootstraps from the peers of an invite, on top of the current ones
+    pub async fn add_peers(&self, node_infos: &[NodeInfo]) {
+        self.reloader.add_peers(node_infos).await;
+    }
+
+    // the ports mapped on the router so far, see Config::port_mapping
+    pub async fn port_mappings(&self) -> Vec<PortMapping> {
+        self.port_mapper.mappings().await
+    }
+
+    // Gives up what the node holds outside the process before it exits, like
+    // port mappings, which would otherwise stay until they expire.
+    pub async fn shutdown(&self) {
+        self.port_mapper.remove_all().await;
+        self.flush().await;
+    }
+
+    // Writes what changed in the peer store since the last write, which
+    // otherwise waits for the next PEER_STORE_SAVE_INTERVAL.
+    pub async fn flush(&self) {
+        if let Some(peer_file) = &self.peer_file {
+            flush_peers(peer_file).await;
         }
     }
 
-    pub async fn create_publisher(&self, pubkey: &PublicKey) -> Publisher {
-        self.user_dht.register_pubkey(pubkey).await;
+    // drops the DHT traffic of ip for a while, as if it sent malformed messages
+    pub async fn ban_peer(&self, ip: IpAddr) {
+        self.rpc.lock().await.ban(ip).await;
+    }
+
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+
+    pub fn stats(&self) -> Arc<StatsRecorder> {
+        self.stats.clone()
+    }
+
+    // the id admin commands are signed for, kept in Config.node_id_path
+    pub fn node_id(&self) -> Key {
+        self.subscriber_id.clone()
+    }
+
+    // the node's source of random bytes, seeded when Config.rng_seed is set
+    pub fn entropy(&self) -> Arc<dyn Entropy> {
+        self.entropy.clone()
+    }
+
+    pub fn stats_snapshot(&self) -> Stats {
+        self.stats.snapshot()
+    }
+
+    pub fn publisher(&self) -> Arc<Publisher> {
+        self.publisher.clone()
+    }
+
+    // the tunables in effect now
+    pub fn tunables(&self) -> Tunables {
+        self.reloader.tunables.lock().unwrap().clone()
+    }
+
+    pub fn config_events(&self) -> broadcast::Receiver<ConfigEvent> {
+        self.reloader.events.subscribe()
+    }
+
+    // Reads the tunables file again, as on SIGHUP. Returns the names of the
+    // changed fields; on an error the running tunables are kept.
+    pub async fn reload(&self) -> Result<Vec<String>, TunablesError> {
+        self.reloader.reload().await
+    }
+
+    pub async fn apply_tunables(&self, tunables: Tunables) -> Result<Vec<String>, TunablesError> {
+        tunables.validate()?;
+        Ok(self.reloader.apply(tunables).await)
+    }
+
+    // The registration has to be signed by the author's key, see
+    // UserHandle::registration.
+    pub async fn register_author(&self, registration: &RegistrationRecord) -> Arc<Publisher> {
+        self.user_dht.register_pubkey(registration).await;
         info!("Registered a public key");
-        Publisher::new(
-            Address::from(pubkey.clone()),
-            self.rpc.clone(),
-            &self.pubsub_dht_bootstrap,
-        )
-        .await
+        let addr = registration.addr();
+        let mut registrations = self.registrations.lock().await;
+        registrations.retain(|r| r.addr() != addr);
+        registrations.push(registration.clone());
+        drop(registrations);
+        self.publisher.add_author(addr).await;
+        if self.archive_posts {
+            self.publisher.archive_posts(&registration.pubkey).await;
+        }
+        self.publisher.clone()
+    }
+
+    // Publishes the profile of a local account, and again with the pubkeys
+    // until the account is deleted or moves.
+    pub async fn register_profile(&self, pubkey: &PublicKey, profile: &SignedUserAttribute) {
+        self.user_dht.register_profile(pubkey, profile).await;
+        let mut profiles = self.profiles.lock().await;
+        profiles.retain(|p| p.profile.addr != profile.addr);
+        profiles.push(ProfileRecord::new(pubkey, profile));
+    }
+
+    // the profile published for addr, checked against the key it is signed with
+    pub async fn get_profile(&self, addr: &Address) -> Option<SignedUserAttribute> {
+        self.user_dht.get_profile(addr).await
     }
 
     pub async fn create_subscriber(&self) -> Subscriber {
-        Subscriber::new(self.rpc.clone(), &self.pubsub_dht_bootstrap).await
+        // the local publisher has to learn about local subscription nodes too,
+        // otherwise posts never reach subscribers on the same host
+        let mut bootstrap = self.pubsub_dht_bootstrap.lock().await.clone();
+        bootstrap.push(self.publisher.node_info());
+        let mut subscriber = Subscriber::new(self.rpc.clone(), &self.pubsub_net, &bootstrap).await;
+        subscriber.set_id(self.subscriber_id.clone());
+        subscriber
+    }
+
+    // the subscriber every local account and API session shares, so that an
+    // address followed by several of them gets one subscription node
+    pub async fn subscriptions(&self) -> SubscriptionManager {
+        self.subscriptions
+            .get_or_init(|| async {
+                let subscriber = Arc::new(self.create_subscriber().await);
+                let relay = subscriber.clone();
+                self.scheduler
+                    .register(
+                        "sweep_subscription_tokens",
+                        Duration::from_millis(BROADCAST_SWEEP_INTERVAL),
+                        Duration::from_millis(BROADCAST_SWEEP_JITTER),
+                        move || {
+                            let relay = relay.clone();
+                            async move {
+                                relay.sweep_broadcast_tokens().await;
+                            }
+                        },
+                    )
+                    .await;
+                SubscriptionManager::new(subscriber)
+            })
+            .await
+            .clone()
+    }
+
+    // mentions of and replies to local accounts, among the posts of the
+    // subscriptions; see Notifier::watch
+    pub async fn notifier(&self) -> Arc<Notifier> {
+        self.notifier
+            .get_or_init(|| async {
+                let notifier = Arc::new(Notifier::new());
+                let posts = self.subscriptions().await.subscriber().get_receiver();
+                notifier.clone().listen(posts, self.ingestor());
+                notifier
+            })
+            .await
+            .clone()
     }
 
     pub async fn get_pubkey(&self, addr: Address) -> Option<PublicKey> {
         self.user_dht.get_pubkey(addr).await
     }
+
+    pub async fn get_account(&self, addr: Address) -> Option<AccountStatus> {
+        self.user_dht.get_account(addr).await
+    }
+
+    // the current address and public key, following migrations
+    pub async fn resolve_user(&self, addr: Address) -> Option<ResolvedUser> {
+        let (addr, pubkey) = self.user_dht.resolve_user(addr).await?;
+        let stats = self.author_stats(&addr);
+        Some(ResolvedUser {
+            addr,
+            pubkey,
+            stats,
+        })
+    }
+
+    // the posts of addr ingested by the node, see Ingestor::with_stats
+    pub fn author_stats(&self, addr: &Address) -> Option<AuthorStats> {
+        self.author_stats.lock().unwrap().get(addr)
+    }
+
+    // keeps a published post fetchable for new followers
+    pub async fn archive(&self, entry: &HistoryEntry, head: &HeadRecord) {
+        self.user_dht.archive(entry, head).await;
+    }
+
+    // Leaves the post in the mailbox of everyone it mentions or replies to,
+    // or of the author of the post it rehoots
+    pub async fn deliver_mentions(&self, pubkey: &PublicKey, sigpost: &SignedPost) {
+        let item = MailboxItem::new(pubkey, sigpost.clone());
+        for addr in MailboxItem::recipients(sigpost) {
+            if let Err(e) = self.user_dht.append_to_mailbox(&addr, &item).await {
+                warn!("Mention of @{} not delivered: {}", addr.to_string(), e);
+            }
+        }
+    }
+
+    // the posts mentioning addr or replying to it, oldest first
+    pub async fn read_mailbox(&self, addr: &Address) -> Vec<MailboxItem> {
+        self.user_dht.read_mailbox(addr).await
+    }
+
+    // Rewrites the account's mailbox with only the items keep returns true
+    // for. Returns how many were kept.
+    pub async fn compact_mailbox(
+        &self,
+        user_handle: &UserHandle,
+        keep: impl Fn(&MailboxItem) -> bool,
+    ) -> Result<usize, SignerError> {
+        let epoch = match self.user_dht.get_mailbox_index(&user_handle.addr()).await {
+            Some(index) => index.epoch + 1,
+            None => 1,
+        };
+        let index = user_handle.mailbox_index(epoch)?;
+        Ok(self.user_dht.compact_mailbox(&index, keep).await)
+    }
+
+    // Stores the account's followings and settings in the user DHT,
+    // encrypted so that only the account key opens them. Returns the number
+    // of chunks written.
+    pub async fn backup_account(&self, user_handle: &UserHandle) -> Result<usize, BackupError> {
+        let now = Utc::now().timestamp() as u64;
+        let records =
+            backup::seal_backup(user_handle.signer()?.as_ref(), &user_handle.backup(), now)?;
+        self.user_dht.put_backup(&records).await;
+        Ok(records.len())
+    }
+
+    // The latest backup of the account, None if there is none
+    pub async fn fetch_backup(
+        &self,
+        user_handle: &UserHandle,
+    ) -> Result<Option<BackupContents>, BackupError> {
+        match self.user_dht.get_backup(&user_handle.addr()).await {
+            Some(records) => backup::open_backup(user_handle.signer()?.as_ref(), &records).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // Checks a post and the posts embedded in it, with the keys in known or
+    // else the ones registered in the user DHT.
+    pub async fn verify_post(
+        &self,
+        sigpost: &SignedPost,
+        known: &HashMap<Address, PublicKey>,
+        received_at: Option<u64>,
+    ) -> VerificationReport {
+        VerificationReport::build(&self.user_dht, sigpost, known, received_at).await
+    }
+
+    // a pipeline of its own for posts from elsewhere, see Ingestor
+    pub fn ingestor(&self) -> Ingestor {
+        Ingestor::new(self.user_dht.clone()).with_stats(self.author_stats.clone())
+    }
+
+    // the latest post in the author's history, verified with the key it carries
+    pub async fn latest_post(&self, addr: &Address) -> Option<SignedPost> {
+        let head = self.user_dht.get_head(addr).await?;
+        self.get_post(addr.clone(), head.latest).await
+    }
+
+    // Compares local, the newest post seen from addr, with its head record in
+    // the user DHT
+    pub async fn sync_status(&self, addr: &Address, local: Option<PostId>) -> SyncStatus {
+        SyncStatus::compare(local, self.user_dht.get_head(addr).await.as_ref())
+    }
+
+    // one archived post, verified
+    pub async fn get_post(&self, addr: Address, id: PostId) -> Option<SignedPost> {
+        let entry = self.user_dht.get_history_entry(&addr, id).await?;
+        Some(entry.sigpost)
+    }
+
+    // Fetches recent posts of an author in the background, reporting progress on the receiver.
+    pub fn backfill(&self, addr: Address, limit: usize) -> mpsc::UnboundedReceiver<BackfillEvent> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(backfill(self.user_dht.clone(), addr, limit, tx));
+        rx
+    }
+
+    // Publish the tombstone and stop acting for the account.
+    // The final DeleteAccount post has to be published before calling this.
+    pub async fn delete_account(&self, tombstone: &AccountTombstone) {
+        let mut registrations = self.registrations.lock().await;
+        registrations.retain(|r| r.addr() != tombstone.addr);
+        drop(registrations);
+        self.forget_profile(&tombstone.addr).await;
+        self.publisher.remove_author(&tombstone.addr).await;
+        self.user_dht.revoke(tombstone).await;
+        info!("Revoked an account");
+    }
+
+    // Publish the record and stop acting for the old address. The final Migrate
+    // post has to be published before calling this, and the new address
+    // registered with register_author.
+    pub async fn migrate_account(&self, record: &MigrationRecord) {
+        let old_addr = record.old_addr();
+        let mut registrations = self.registrations.lock().await;
+        registrations.retain(|r| r.addr() != old_addr);
+        drop(registrations);
+        self.forget_profile(&old_addr).await;
+        self.publisher.remove_author(&old_addr).await;
+        self.user_dht.migrate(record).await;
+        info!("Migrated an account");
+    }
+
+    async fn forget_profile(&self, addr: &Address) {
+        self.profiles.lock().await.retain(|p| p.profile.addr != *addr);
+    }
+}
+
+// the len bytes stored at path, or new ones stored there; what names them
+// in the warnings
+async fn load_or_draw(path: &Path, len: usize, what: &str, entropy: &dyn Entropy) -> Vec<u8> {
+    match atomic_file::read(path).await {
+        Ok(bytes) if bytes.len() == len => return bytes,
+        Ok(_) => warn!("{} is not a {}, drawing a new one", path.display(), what),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => warn!("Cannot read {}: {}", path.display(), e),
+    }
+    let mut bytes = vec![0; len];
+    entropy.fill_bytes(&mut bytes);
+    if let Some(dir) = path.parent() {
+        let _ = tokio::fs::create_dir_all(dir).await;
+    }
+    if let Err(e) = atomic_file::write(path, &bytes).await {
+        warn!("Cannot keep the {} in {}: {}", what, path.display(), e);
+    }
+    bytes
 }
 
 pub struct Config {
     pub bind_addr: SocketAddr,
+    // a socket of its own for the pubsub DHT, so that heavy post traffic does
+    // not delay user DHT lookups; None shares bind_addr
+    pub pubsub_bind_addr: Option<SocketAddr>,
     pub nodeinfo_addr: Option<SocketAddr>,
-    pub bootstrap: Vec<SocketAddr>,
+    // looked up again every BOOTSTRAP_RESOLVE_INTERVAL when given by name
+    pub bootstrap: Vec<BootstrapAddr>,
+    // signed lists of bootstrap nodes on web hosts, asked along with bootstrap
+    pub bootstrap_lists: Vec<BootstrapListSource>,
+    // find peers on the local network too, and answer their queries
+    pub lan_discovery: bool,
+    // key lengths and id schemes of the user and the pubsub DHT, see default_networks
+    pub networks: NetworkRegistry,
+    // bucket size, lookup parallelism, RPC timeout and message limit of both
+    // DHTs; KadConfig::default() is what the testnet uses
+    pub kad: KadConfig,
+    // a JSON file of Tunables, read again on SIGHUP
+    pub tunables_path: Option<PathBuf>,
+    // map the DHT ports on the router with NAT-PMP or UPnP, until shutdown
+    pub port_mapping: bool,
+    // where peers that answered are kept between runs, to bootstrap from
+    pub peer_store_path: Option<PathBuf>,
+    // bytes of DHT records each DHT stores for others; None for no limit
+    pub store_limit: Option<usize>,
+    // outgoing DHT traffic in bytes per second; None for no cap
+    pub bandwidth_limit: Option<u64>,
+    // the user DHT's records are kept in a directory per net_id here, to
+    // outlive restarts; None keeps them in memory only
+    pub store_dir: Option<PathBuf>,
+    // seeds node ids, request tokens and challenges, so that a simulation
+    // runs the same way again; None draws them from the OS, as a real node must
+    pub rng_seed: Option<u64>,
+    // keep the latest posts of local authors in the pubsub DHT too, for
+    // followers which were offline when they were multicast
+    pub archive_posts: bool,
+    // the key which signs the admin commands of the API server, see
+    // ClientMessage::Admin; None refuses them all
+    pub operator_key: Option<PublicKey>,
+    // lets API clients on the server's own host send ReloadConfig without the
+    // operator key; anyone else who can reach loopback can too, so it is off
+    // unless asked for
+    pub local_reload: bool,
+    // a random id of this node kept here between runs, so that it follows
+    // each author on the same shard after a restart; None draws one per run.
+    // With KadConfig::delivery_receipts, the key signing them is kept next
+    // to it in RECEIPT_KEY_FILE
+    pub node_id_path: Option<PathBuf>,
+}
+
+async fn flush_peers(peer_file: &PeerStoreFile) {
+    if let Err(e) = peer_file.flush().await {
+        warn!("Cannot save the peers: {}", e);
+    }
+}
+
+// what a reload changes, shared with the SIGHUP task
+#[derive(Clone)]
+struct Reloader {
+    path: Option<PathBuf>,
+    tunables: Arc<std::sync::Mutex<Tunables>>,
+    events: broadcast::Sender<ConfigEvent>,
+    networks: NetworkRegistry,
+    user_dht: UserDHT,
+    publisher: Arc<Publisher>,
+    pubsub_dht_bootstrap: Arc<Mutex<Vec<NodeInfo>>>,
+    config_bootstrap: Vec<BootstrapAddr>,
+    config_bootstrap_lists: Vec<BootstrapListSource>,
+    // what each bootstrap host name resolved to last
+    resolved: Arc<Mutex<HashMap<BootstrapAddr, Vec<SocketAddr>>>>,
+}
+
+impl Reloader {
+    async fn reload(&self) -> Result<Vec<String>, TunablesError> {
+        let path = self.path.as_ref().ok_or(TunablesError::NoFile)?;
+        match Tunables::load(path).await {
+            Ok(tunables) => Ok(self.apply(tunables).await),
+            Err(e) => {
+                warn!("Tunables not reloaded: {}", e);
+                let _ = self.events.send(ConfigEvent::Rejected(e.to_string()));
+                Err(e)
+            }
+        }
+    }
+
+    // tunables has to be validated
+    async fn apply(&self, tunables: Tunables) -> Vec<String> {
+        let old = std::mem::replace(&mut *self.tunables.lock().unwrap(), tunables.clone());
+        let changed = old.changes(&tunables);
+        // a level which is removed stays in effect until the next one is set
+        if let Some(level) = tunables.log_level() {
+            log::set_max_level(level);
+        }
+        let added: Vec<BootstrapAddr> = tunables
+            .bootstrap
+            .iter()
+            .filter(|addr| !old.bootstrap.contains(addr))
+            .cloned()
+            .collect();
+        if !added.is_empty() {
+            self.add_bootstrap(&added).await;
+        }
+        info!("Tunables reloaded, changed: {:?}", changed);
+        let _ = self.events.send(ConfigEvent::Reloaded(changed.clone()));
+        changed
+    }
+
+    async fn add_bootstrap(&self, addrs: &[BootstrapAddr]) {
+        self.add_peers(&fetch_bootstrap(addrs).await).await;
+    }
+
+    async fn add_peers(&self, node_infos: &[NodeInfo]) {
+        let user_dht = self.networks.bootstrap(TESTNET_USER_DHT, node_infos);
+        self.user_dht.add_peers(&user_dht).await;
+        let pubsub_dht = self.networks.bootstrap(TESTNET_PUBSUB_DHT, node_infos);
+        self.publisher.add_peers(&pubsub_dht).await;
+        self.pubsub_dht_bootstrap.lock().await.extend(pubsub_dht);
+    }
+
+    // Looks the bootstrap host names up again. A name which resolves to an
+    // address not seen before is bootstrapped from again, as when its node
+    // moved; one which fails to resolve keeps its old addresses.
+    async fn refresh_bootstrap(&self) {
+        let mut names = self.config_bootstrap.clone();
+        names.extend(self.tunables.lock().unwrap().bootstrap.iter().cloned());
+        names.retain(|addr| addr.is_name());
+        let mut moved = Vec::new();
+        let mut resolved = self.resolved.lock().await;
+        for name in names {
+            match name.resolve().await {
+                Ok(addrs) => {
+                    if let Some(old) = resolved.insert(name.clone(), addrs.clone()) {
+                        if addrs.iter().any(|addr| !old.contains(addr)) {
+                            info!("Bootstrap node {} moved to {:?}", name, addrs);
+                            moved.push(name);
+                        }
+                    }
+                }
+                Err(e) => warn!("Cannot resolve bootstrap node {}: {}", name, e),
+            }
+        }
+        drop(resolved);
+        if !moved.is_empty() {
+            self.add_bootstrap(&moved).await;
+        }
+    }
+
+    #[cfg(unix)]
+    fn reload_on_sighup(self) {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                warn!("Tunables are not reloaded on SIGHUP: {}", e);
+                return;
+            }
+        };
+        tokio::spawn(async move {
+            while hangups.recv().await.is_some() {
+                let _ = self.reload().await;
+            }
+        });
+    }
 }
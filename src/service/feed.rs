@@ -0,0 +1,194 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::ops::Bound;
+use std::str::FromStr;
+use thiserror::Error;
+
+use crate::user::post::{PostKind, PostRef, SignedPost};
+use crate::user::post_id::PostId;
+use crate::user::user::Address;
+
+// newest first, then by author and id so that posts written in the same second
+// keep one order
+type FeedKey = (Reverse<u64>, [u8; 32], PostId);
+
+fn feed_key(created_at: u64, addr: &Address, id: PostId) -> FeedKey {
+    (Reverse(created_at), addr.clone().into(), id)
+}
+
+// Where a page of a MergedFeed ended. Posts are ordered by what they carry
+// rather than by position, so a cursor still points at the same place after
+// newer posts come in or older ones are deleted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeedCursor {
+    created_at: u64,
+    addr: Address,
+    id: PostId,
+}
+
+impl FeedCursor {
+    fn key(&self) -> FeedKey {
+        feed_key(self.created_at, &self.addr, self.id)
+    }
+}
+
+// "created_at.address.id", for clients to hand back as they got it
+impl fmt::Display for FeedCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.created_at, self.addr.to_string(), self.id)
+    }
+}
+
+impl FromStr for FeedCursor {
+    type Err = FeedCursorError;
+
+    fn from_str(s: &str) -> Result<FeedCursor, FeedCursorError> {
+        let parts: Vec<&str> = s.split('.').collect();
+        let (created_at, addr, id) = match parts.as_slice() {
+            [created_at, addr, id] => (created_at, addr, id),
+            _ => return Err(FeedCursorError),
+        };
+        Ok(FeedCursor {
+            created_at: created_at.parse().map_err(|_| FeedCursorError)?,
+            addr: Address::from_str(addr).map_err(|_| FeedCursorError)?,
+            id: id.parse().map_err(|_| FeedCursorError)?,
+        })
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Invalid feed cursor")]
+pub struct FeedCursorError;
+
+// The posts of several followed addresses merged into the one feed a client
+// shows: each post once, newest first, and without what was deleted, whichever
+// order the posts and the deletions came in. Posts go in checked already, see
+// Ingestor. Posts are never edited in place, an id always names the same post.
+#[derive(Debug, Default)]
+pub struct MergedFeed {
+    posts: BTreeMap<FeedKey, SignedPost>,
+    // deleted posts, so that a copy arriving late stays out
+    deleted: HashSet<PostRef>,
+    deleted_accounts: HashSet<Address>,
+}
+
+impl MergedFeed {
+    pub fn new() -> MergedFeed {
+        MergedFeed::default()
+    }
+
+    pub fn merge<S>(streams: impl IntoIterator<Item = S>) -> MergedFeed
+    where
+        S: IntoIterator<Item = SignedPost>,
+    {
+        let mut feed = MergedFeed::new();
+        for sigpost in streams.into_iter().flatten() {
+            feed.insert(sigpost);
+        }
+        feed
+    }
+
+    // false if it changed nothing: a duplicate, or a post deleted already
+    pub fn insert(&mut self, sigpost: SignedPost) -> bool {
+        if self.deleted_accounts.contains(&sigpost.addr) {
+            return false;
+        }
+        match &sigpost.post.content {
+            PostKind::Delete(id) => {
+                let post_ref = PostRef {
+                    addr: sigpost.addr.clone(),
+                    id: *id,
+                };
+                self.posts.retain(|_, p| p.post_ref() != post_ref);
+                self.deleted.insert(post_ref)
+            }
+            PostKind::DeleteAccount => {
+                self.posts.retain(|_, p| p.addr != sigpost.addr);
+                self.deleted_accounts.insert(sigpost.addr)
+            }
+            // nothing to show, a client follows the new address instead
+            PostKind::Migrate(_) | PostKind::DirectMessage(_) | PostKind::GroupMessage(_) => {
+                false
+            }
+            _ => {
+                if self.deleted.contains(&sigpost.post_ref()) {
+                    return false;
+                }
+                let key = feed_key(sigpost.post.created_at, &sigpost.addr, sigpost.post.id);
+                self.posts.insert(key, sigpost).is_none()
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.posts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.posts.is_empty()
+    }
+
+    // Up to limit posts after the cursor, or from the newest without one, and
+    // the cursor of the next page; None once this page reaches the end.
+    pub fn page(
+        &self,
+        after: Option<&FeedCursor>,
+        limit: usize,
+    ) -> (Vec<&SignedPost>, Option<FeedCursor>) {
+        let from = match after {
+            Some(cursor) => Bound::Excluded(cursor.key()),
+            None => Bound::Unbounded,
+        };
+        let mut posts = self.posts.range((from, Bound::Unbounded));
+        let page: Vec<&SignedPost> = posts.by_ref().take(limit).map(|(_, p)| p).collect();
+        let next = match (page.last(), posts.next()) {
+            (Some(last), Some(_)) => Some(FeedCursor {
+                created_at: last.post.created_at,
+                addr: last.addr.clone(),
+                id: last.post.id,
+            }),
+            _ => None,
+        };
+        (page, next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::{test_user, UserHandle};
+
+    #[test]
+    fn merged_feed_test() {
+        let (mut alice, mut bob) = (test_user("alice"), test_user("bob"));
+        let hoot = |user: &mut UserHandle, created_at| {
+            let mut sigpost = user.hoot("hi".to_string(), None, None, vec![]).unwrap();
+            sigpost.post.created_at = created_at;
+            sigpost
+        };
+        let alice_posts = vec![hoot(&mut alice, 100), hoot(&mut alice, 300)];
+        let bob_posts = vec![hoot(&mut bob, 200), hoot(&mut bob, 400)];
+        let deleted = alice_posts[1].clone();
+        let mut feed = MergedFeed::merge([alice_posts.clone(), bob_posts.clone(), alice_posts]);
+        assert_eq!(feed.len(), 4);
+
+        let (first, cursor) = feed.page(None, 2);
+        let times: Vec<u64> = first.iter().map(|p| p.post.created_at).collect();
+        assert_eq!(times, vec![400, 300]);
+        let cursor: FeedCursor = cursor.unwrap().to_string().parse().unwrap();
+
+        // the cursor holds while newer posts come in, and deletions go either way
+        assert!(feed.insert(hoot(&mut bob, 500)));
+        let delete = alice.create_post(PostKind::Delete(deleted.post.id)).unwrap();
+        assert!(feed.insert(delete));
+        assert!(!feed.insert(deleted));
+        assert!(!feed.insert(bob_posts[0].clone()));
+        let (second, next) = feed.page(Some(&cursor), 2);
+        let times: Vec<u64> = second.iter().map(|p| p.post.created_at).collect();
+        assert_eq!(times, vec![200, 100]);
+        assert!(next.is_none());
+        assert_eq!(feed.page(None, 10).0.len(), 4);
+        assert!("1.2".parse::<FeedCursor>().is_err());
+    }
+}
@@ -0,0 +1,349 @@
+use thiserror::Error;
+
+use crate::user::post::{Hoot, PostKind, SignedPost};
+use crate::user::user::{Address, AddressError};
+
+// Filters come from API clients. Both bound how deep the parser, matches()
+// and drop recurse: MAX_DEPTH nesting of not and parentheses, and MAX_LEN
+// the length of and/or chains.
+const MAX_DEPTH: usize = 32;
+const MAX_LEN: usize = 1024;
+
+// Filter expressions, e.g.
+//   author in [addr1, addr2] and not is_reply
+//   contains "rust" or (lang = ja and has_quote)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Filter {
+    Author(Vec<Address>),
+    Contains(String),
+    IsReply,
+    IsRehoot,
+    HasQuote,
+    Lang(String),
+    Not(Box<Filter>),
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+}
+
+impl Filter {
+    pub fn parse(s: &str) -> Result<Filter, FilterError> {
+        if s.len() > MAX_LEN {
+            return Err(FilterError::TooLong(MAX_LEN));
+        }
+        let tokens = tokenize(s)?;
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            depth: 0,
+        };
+        let filter = parser.or()?;
+        match parser.next() {
+            None => Ok(filter),
+            Some(t) => Err(FilterError::UnexpectedToken(t.to_string())),
+        }
+    }
+
+    pub fn matches(&self, sigpost: &SignedPost) -> bool {
+        match self {
+            Filter::Author(addrs) => {
+                addrs.contains(&sigpost.addr)
+                    || match &sigpost.post.content {
+                        PostKind::ReHoot(inner) => addrs.contains(&inner.addr),
+                        PostKind::ReHootRef(original) => addrs.contains(&original.addr),
+                        _ => false,
+                    }
+            }
+            Filter::Contains(keyword) => hoot(sigpost)
+                .map(|h| h.text.to_lowercase().contains(&keyword.to_lowercase()))
+                .unwrap_or(false),
+            Filter::IsReply => hoot(sigpost)
+                .map(|h| h.reply_to.is_some())
+                .unwrap_or(false),
+            Filter::IsRehoot => matches!(
+                sigpost.post.content,
+                PostKind::ReHoot(_) | PostKind::ReHootRef(_)
+            ),
+            Filter::HasQuote => hoot(sigpost)
+                .map(|h| h.quoted_posts.is_some())
+                .unwrap_or(false),
+            Filter::Lang(lang) => hoot(sigpost)
+                .and_then(|h| h.lang.as_ref())
+                .map(|l| l.eq_ignore_ascii_case(lang))
+                .unwrap_or(false),
+            Filter::Not(f) => !f.matches(sigpost),
+            Filter::And(l, r) => l.matches(sigpost) && r.matches(sigpost),
+            Filter::Or(l, r) => l.matches(sigpost) || r.matches(sigpost),
+        }
+    }
+}
+
+// the hoot itself, or the rehooted one
+fn hoot(sigpost: &SignedPost) -> Option<&Hoot> {
+    match &sigpost.post.content {
+        PostKind::Hoot(hoot) => Some(hoot),
+        PostKind::ReHoot(inner) => match &inner.post.content {
+            PostKind::Hoot(hoot) => Some(hoot),
+            _ => None,
+        },
+        PostKind::ReHootRef(_)
+        | PostKind::Delete(_)
+        | PostKind::DeleteAccount
+        | PostKind::Migrate(_)
+        | PostKind::DirectMessage(_)
+        | PostKind::GroupMessage(_) => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Str(String),
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    Comma,
+    Eq,
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Token::Word(w) => write!(f, "{}", w),
+            Token::Str(s) => write!(f, "\"{}\"", s),
+            Token::LParen => write!(f, "("),
+            Token::RParen => write!(f, ")"),
+            Token::LBracket => write!(f, "["),
+            Token::RBracket => write!(f, "]"),
+            Token::Comma => write!(f, ","),
+            Token::Eq => write!(f, "="),
+        }
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, FilterError> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' | '[' | ']' | ',' | '=' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::LParen,
+                    ')' => Token::RParen,
+                    '[' => Token::LBracket,
+                    ']' => Token::RBracket,
+                    ',' => Token::Comma,
+                    _ => Token::Eq,
+                });
+            }
+            '"' => {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => s.push(c),
+                            None => return Err(FilterError::UnterminatedString),
+                        },
+                        Some(c) => s.push(c),
+                        None => return Err(FilterError::UnterminatedString),
+                    }
+                }
+                tokens.push(Token::Str(s));
+            }
+            _ => {
+                let mut w = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "()[],=\"".contains(c) {
+                        break;
+                    }
+                    w.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Word(w));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), FilterError> {
+        match self.next() {
+            Some(t) if t == expected => Ok(()),
+            Some(t) => Err(FilterError::UnexpectedToken(t.to_string())),
+            None => Err(FilterError::UnexpectedEnd),
+        }
+    }
+
+    // around each recursion through not or parentheses
+    fn nested<T>(
+        &mut self,
+        f: impl FnOnce(&mut Parser) -> Result<T, FilterError>,
+    ) -> Result<T, FilterError> {
+        if self.depth >= MAX_DEPTH {
+            return Err(FilterError::TooDeep(MAX_DEPTH));
+        }
+        self.depth += 1;
+        let res = f(self);
+        self.depth -= 1;
+        res
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(w)) if w == keyword)
+    }
+
+    fn or(&mut self) -> Result<Filter, FilterError> {
+        let mut lhs = self.and()?;
+        while self.is_keyword("or") {
+            self.pos += 1;
+            lhs = Filter::Or(Box::new(lhs), Box::new(self.and()?));
+        }
+        Ok(lhs)
+    }
+
+    fn and(&mut self) -> Result<Filter, FilterError> {
+        let mut lhs = self.unary()?;
+        while self.is_keyword("and") {
+            self.pos += 1;
+            lhs = Filter::And(Box::new(lhs), Box::new(self.unary()?));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Filter, FilterError> {
+        match self.next() {
+            Some(Token::Word(w)) => match w.as_str() {
+                "not" => Ok(Filter::Not(Box::new(self.nested(Parser::unary)?))),
+                "is_reply" => Ok(Filter::IsReply),
+                "is_rehoot" => Ok(Filter::IsRehoot),
+                "has_quote" => Ok(Filter::HasQuote),
+                "contains" => Ok(Filter::Contains(self.text()?)),
+                "lang" => {
+                    self.expect(Token::Eq)?;
+                    Ok(Filter::Lang(self.text()?))
+                }
+                "author" => {
+                    match self.next() {
+                        Some(Token::Word(w)) if w == "in" => (),
+                        Some(t) => return Err(FilterError::UnexpectedToken(t.to_string())),
+                        None => return Err(FilterError::UnexpectedEnd),
+                    }
+                    self.expect(Token::LBracket)?;
+                    let mut addrs = Vec::new();
+                    loop {
+                        let s = self.text()?;
+                        addrs.push(
+                            Address::from_str(&s).map_err(|e| FilterError::Address(s, e))?,
+                        );
+                        match self.next() {
+                            Some(Token::Comma) => continue,
+                            Some(Token::RBracket) => break,
+                            Some(t) => return Err(FilterError::UnexpectedToken(t.to_string())),
+                            None => return Err(FilterError::UnexpectedEnd),
+                        }
+                    }
+                    Ok(Filter::Author(addrs))
+                }
+                _ => Err(FilterError::UnknownPredicate(w)),
+            },
+            Some(Token::LParen) => {
+                let f = self.nested(Parser::or)?;
+                self.expect(Token::RParen)?;
+                Ok(f)
+            }
+            Some(t) => Err(FilterError::UnexpectedToken(t.to_string())),
+            None => Err(FilterError::UnexpectedEnd),
+        }
+    }
+
+    fn text(&mut self) -> Result<String, FilterError> {
+        match self.next() {
+            Some(Token::Word(s)) | Some(Token::Str(s)) => Ok(s),
+            Some(t) => Err(FilterError::UnexpectedToken(t.to_string())),
+            None => Err(FilterError::UnexpectedEnd),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum FilterError {
+    #[error("Unexpected end of filter")]
+    UnexpectedEnd,
+    #[error("Unexpected token: {0}")]
+    UnexpectedToken(String),
+    #[error("Unknown predicate: {0}")]
+    UnknownPredicate(String),
+    #[error("Unterminated string")]
+    UnterminatedString,
+    #[error("Invalid address {0}: {1}")]
+    Address(String, AddressError),
+    #[error("Filter longer than {0} bytes")]
+    TooLong(usize),
+    #[error("Filter nested deeper than {0}")]
+    TooDeep(usize),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Filter, FilterError};
+
+    #[test]
+    fn parse_test() {
+        let f = Filter::parse("contains \"a b\" and not (is_reply or lang = ja)").unwrap();
+        assert_eq!(
+            f,
+            Filter::And(
+                Box::new(Filter::Contains("a b".to_string())),
+                Box::new(Filter::Not(Box::new(Filter::Or(
+                    Box::new(Filter::IsReply),
+                    Box::new(Filter::Lang("ja".to_string()))
+                ))))
+            )
+        );
+
+        assert!(Filter::parse("is_reply and").is_err());
+        assert!(Filter::parse("author in [notanaddress]").is_err());
+        assert!(Filter::parse("(is_reply").is_err());
+    }
+
+    #[test]
+    fn limit_test() {
+        let nested = format!("{}is_reply{}", "(".repeat(33), ")".repeat(33));
+        assert!(matches!(Filter::parse(&nested), Err(FilterError::TooDeep(_))));
+        let nested = format!("{}is_reply{}", "(".repeat(32), ")".repeat(32));
+        assert!(Filter::parse(&nested).is_ok());
+        assert!(matches!(
+            Filter::parse(&"not ".repeat(100_000)),
+            Err(FilterError::TooLong(_))
+        ));
+        assert!(matches!(
+            Filter::parse(&format!("{}is_reply", "not ".repeat(200))),
+            Err(FilterError::TooDeep(_))
+        ));
+    }
+}
@@ -0,0 +1,134 @@
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::user::history::HeadRecord;
+use crate::user::post::{PostKind, SignedPost};
+use crate::user::post_id::PostId;
+use crate::user::user::Address;
+
+use super::UserDHT;
+
+#[derive(Debug, Clone)]
+pub enum BackfillEvent {
+    // the head record was found, and this many entries are being fetched
+    Started { total: usize },
+    Progress { done: usize, total: usize },
+    // verified posts, oldest first; empty when the author has no history
+    Finished(Vec<SignedPost>),
+}
+
+// Fetches the last `limit` posts of an author from the user DHT.
+// Deleted posts and entries that cannot be found or verified are left out.
+pub async fn backfill(
+    dht: UserDHT,
+    addr: Address,
+    limit: usize,
+    events: UnboundedSender<BackfillEvent>,
+) {
+    let head = match dht.get_head(&addr).await {
+        Some(head) if limit > 0 => head,
+        _ => {
+            let _ = events.send(BackfillEvent::Finished(Vec::new()));
+            return;
+        }
+    };
+
+    // a legacy history counts down to 0, other ones end where prev does
+    let total = match head.latest.is_legacy() {
+        true => head.latest.0.min(limit as u128 - 1) as usize + 1,
+        false => limit,
+    };
+    let _ = events.send(BackfillEvent::Started { total });
+
+    // entries link back to the previous post, so they are fetched one by one
+    let mut posts = Vec::new();
+    let mut next = Some(head.latest);
+    let mut done = 0;
+    while let Some(id) = next.filter(|_| done < total) {
+        let entry = dht.get_history_entry(&addr, id).await;
+        done += 1;
+        let _ = events.send(BackfillEvent::Progress { done, total });
+        next = entry
+            .as_ref()
+            .and_then(|entry| entry.prev)
+            .or_else(|| id.legacy_prev())
+            // ids only go back in time, so a forged prev cannot make a loop
+            .filter(|prev| *prev < id);
+        let entry = match entry {
+            // the slot of a deleted post holds the Delete
+            Some(entry) if entry.sigpost.post.id == id => entry,
+            _ => continue,
+        };
+        match entry.sigpost.post.content {
+            PostKind::Delete(_)
+            | PostKind::DeleteAccount
+            | PostKind::Migrate(_)
+            | PostKind::DirectMessage(_)
+            | PostKind::GroupMessage(_) => (),
+            _ => posts.push(entry.sigpost),
+        }
+    }
+
+    posts.sort_by_key(|sigpost| sigpost.post.id);
+    let _ = events.send(BackfillEvent::Finished(posts));
+}
+
+// How the newest post seen from an author compares with its head record, to
+// tell whether anything was missed while offline
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SyncStatus {
+    // the head record points at the newest post seen, or an older one
+    Synced,
+    // the author archived posts after local, None when nothing was seen
+    Behind {
+        local: Option<PostId>,
+        remote: PostId,
+    },
+    // there is no head record, the author keeps no history or it was not found
+    Unknown,
+}
+
+impl SyncStatus {
+    pub fn compare(local: Option<PostId>, remote: Option<&HeadRecord>) -> SyncStatus {
+        match remote {
+            None => SyncStatus::Unknown,
+            Some(head) if local.is_some_and(|local| local >= head.latest) => SyncStatus::Synced,
+            Some(head) => SyncStatus::Behind {
+                local,
+                remote: head.latest,
+            },
+        }
+    }
+
+    // Unknown ones too, since nothing tells that they are not behind
+    pub fn needs_backfill(&self) -> bool {
+        *self != SyncStatus::Synced
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::SecretKey;
+
+    #[test]
+    fn sync_status_test() {
+        let sk = SecretKey::random();
+        let head = HeadRecord::new(&sk.public_key(), PostId(10), 0, [0; 64]);
+        assert_eq!(SyncStatus::compare(Some(PostId(10)), Some(&head)), SyncStatus::Synced);
+        // the head record is written after the post is multicast
+        assert_eq!(SyncStatus::compare(Some(PostId(11)), Some(&head)), SyncStatus::Synced);
+        let behind = SyncStatus::compare(Some(PostId(9)), Some(&head));
+        assert_eq!(
+            behind,
+            SyncStatus::Behind {
+                local: Some(PostId(9)),
+                remote: PostId(10)
+            }
+        );
+        assert!(behind.needs_backfill());
+        assert!(SyncStatus::compare(None, Some(&head)).needs_backfill());
+        assert_eq!(SyncStatus::compare(Some(PostId(9)), None), SyncStatus::Unknown);
+        assert!(!SyncStatus::Synced.needs_backfill());
+    }
+}
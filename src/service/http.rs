@@ -0,0 +1,138 @@
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
+
+// A GET of url, http or https, returning the body of a 200 response. At most
+// limit bytes of the response are read. Servers are checked against the
+// webpki roots; there is no redirect following.
+pub async fn get(url: &str, limit: u64, time_out: u64) -> Result<Vec<u8>, HttpError> {
+    let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+        (true, rest)
+    } else if let Some(rest) = url.strip_prefix("http://") {
+        (false, rest)
+    } else {
+        return Err(HttpError::Scheme);
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    // the fragment stays here
+    let path = path.split('#').next().unwrap_or("/");
+    let host = match authority.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => host,
+        _ => authority,
+    };
+    if host.is_empty() {
+        return Err(HttpError::Url);
+    }
+    let addr = if host.len() == authority.len() {
+        format!("{}:{}", host, if tls { 443 } else { 80 })
+    } else {
+        authority.to_string()
+    };
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, authority
+    );
+
+    let fetch = async {
+        let stream = TcpStream::connect(addr).await?;
+        if tls {
+            let name = ServerName::try_from(host).map_err(|_| HttpError::Url)?;
+            let stream = connector().connect(name, stream).await?;
+            exchange(stream, &request, limit).await
+        } else {
+            exchange(stream, &request, limit).await
+        }
+    };
+    let response = tokio::time::timeout(Duration::from_millis(time_out), fetch)
+        .await
+        .map_err(|_| HttpError::TimedOut)??;
+    body(&response)
+}
+
+fn connector() -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+async fn exchange<S>(mut stream: S, request: &str, limit: u64) -> Result<Vec<u8>, HttpError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(request.as_bytes()).await?;
+    let mut buf = Vec::new();
+    stream.take(limit).read_to_end(&mut buf).await?;
+    Ok(buf)
+}
+
+fn body(response: &[u8]) -> Result<Vec<u8>, HttpError> {
+    let end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or(HttpError::Response)?;
+    let head = String::from_utf8_lossy(&response[..end]);
+    match head.split_whitespace().nth(1) {
+        Some("200") => Ok(response[end + 4..].to_vec()),
+        Some(status) => Err(HttpError::Status(status.to_string())),
+        None => Err(HttpError::Response),
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum HttpError {
+    #[error("Only http and https URLs are supported")]
+    Scheme,
+    #[error("Invalid URL")]
+    Url,
+    #[error("Timed out")]
+    TimedOut,
+    #[error("Invalid response")]
+    Response,
+    #[error("Status {0}")]
+    Status(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn get_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/nodes.json#fragment", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            for status in ["200 OK", "404 Not Found"] {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0; 1024];
+                let n = socket.read(&mut buf).await.unwrap();
+                assert!(buf[..n].starts_with(b"GET /nodes.json HTTP/1.1\r\n"));
+                let response = format!("HTTP/1.1 {}\r\nContent-Length: 2\r\n\r\n[]", status);
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        assert_eq!(get(&url, 1024, 1000).await.unwrap(), b"[]");
+        assert!(matches!(get(&url, 1024, 1000).await, Err(HttpError::Status(s)) if s == "404"));
+        assert!(matches!(get("ftp://example.org/", 1024, 1000).await, Err(HttpError::Scheme)));
+    }
+}
@@ -0,0 +1,179 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::crypto::PublicKey;
+use crate::user::user::{Address, MigrationRecord, UserAttribute};
+
+// A key met while reading posts, with the profile its last post came with
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedIdentity {
+    pub pubkey: PublicKey,
+    pub attr: Option<UserAttribute>,
+    // seconds since the epoch; 0 for keys cached before this was recorded
+    pub last_seen: u64,
+}
+
+impl CachedIdentity {
+    pub fn addr(&self) -> Address {
+        Address::from(self.pubkey.clone())
+    }
+}
+
+// The keys and profiles of other accounts kept between sessions, so that
+// their posts are checked without a user DHT lookup. Only keys found in the
+// user DHT or in verified records go in.
+#[derive(Debug, Clone, Default)]
+pub struct IdentityCache {
+    identities: HashMap<Address, CachedIdentity>,
+}
+
+impl IdentityCache {
+    pub fn new() -> IdentityCache {
+        IdentityCache::default()
+    }
+
+    // Reads what to_bytes wrote, or the plain list of keys older clients
+    // kept. Anything else gives an empty cache.
+    pub fn from_bytes(bytes: &[u8]) -> IdentityCache {
+        let identities: Vec<CachedIdentity> = match serde_json::from_slice(bytes) {
+            Ok(identities) => identities,
+            Err(_) => serde_json::from_slice::<Vec<PublicKey>>(bytes)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|pubkey| CachedIdentity {
+                    pubkey,
+                    attr: None,
+                    last_seen: 0,
+                })
+                .collect(),
+        };
+        IdentityCache {
+            identities: identities.into_iter().map(|i| (i.addr(), i)).collect(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(&self.list_cached_identities()).unwrap()
+    }
+
+    pub fn len(&self) -> usize {
+        self.identities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.identities.is_empty()
+    }
+
+    pub fn get(&self, addr: &Address) -> Option<&PublicKey> {
+        self.identities.get(addr).map(|i| &i.pubkey)
+    }
+
+    pub fn addrs(&self) -> impl Iterator<Item = &Address> {
+        self.identities.keys()
+    }
+
+    // the keys by address, as VerificationReport::build takes them
+    pub fn pubkeys(&self) -> HashMap<Address, PublicKey> {
+        self.identities
+            .iter()
+            .map(|(addr, i)| (addr.clone(), i.pubkey.clone()))
+            .collect()
+    }
+
+    pub fn insert(&mut self, pubkey: PublicKey, now: u64) {
+        let identity = CachedIdentity {
+            pubkey,
+            attr: None,
+            last_seen: now,
+        };
+        self.identities.insert(identity.addr(), identity);
+    }
+
+    // a verified post of addr came with attr
+    pub fn saw(&mut self, addr: &Address, attr: &UserAttribute, now: u64) {
+        if let Some(identity) = self.identities.get_mut(addr) {
+            identity.attr = Some(attr.clone());
+            identity.last_seen = now;
+        }
+    }
+
+    // most recently seen first
+    pub fn list_cached_identities(&self) -> Vec<&CachedIdentity> {
+        let mut ret: Vec<_> = self.identities.values().collect();
+        ret.sort_by(|a, b| {
+            b.last_seen
+                .cmp(&a.last_seen)
+                .then_with(|| a.addr().to_string().cmp(&b.addr().to_string()))
+        });
+        ret
+    }
+
+    // drops the identities not seen since before; returns their addresses
+    pub fn prune(&mut self, before: u64) -> Vec<Address> {
+        let stale: Vec<Address> = self
+            .identities
+            .iter()
+            .filter(|(_, i)| i.last_seen < before)
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        for addr in stale.iter() {
+            self.identities.remove(addr);
+        }
+        stale
+    }
+
+    // Swaps the old key of a verified migration for the new one, which keeps
+    // the profile; false if the old key was not cached.
+    pub fn migrate(&mut self, record: &MigrationRecord, now: u64) -> bool {
+        let old = self.identities.remove(&record.old_addr());
+        let identity = CachedIdentity {
+            pubkey: record.new_pubkey.clone(),
+            attr: old.as_ref().and_then(|old| old.attr.clone()),
+            last_seen: now,
+        };
+        self.identities.insert(identity.addr(), identity);
+        old.is_some()
+    }
+
+    // false if addr was not cached
+    pub fn forget(&mut self, addr: &Address) -> bool {
+        self.identities.remove(addr).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::SecretKey;
+
+    #[test]
+    fn identity_cache_test() {
+        let (old, new) = (SecretKey::random().public_key(), SecretKey::random().public_key());
+        let legacy = serde_json::to_vec(&vec![old.clone()]).unwrap();
+        let mut cache = IdentityCache::from_bytes(&legacy);
+        assert_eq!(cache.get(&Address::from(old.clone())), Some(&old));
+
+        let new_addr = Address::from(new.clone());
+        cache.insert(new.clone(), 1000);
+        cache.saw(&new_addr, &UserAttribute::new("bob", 0, ""), 2000);
+        let listed = cache.list_cached_identities();
+        assert_eq!(listed[0].addr(), new_addr);
+        assert_eq!(listed[0].attr.as_ref().unwrap().name, "bob");
+
+        let cache_copy = IdentityCache::from_bytes(&cache.to_bytes());
+        assert_eq!(cache_copy.len(), 2);
+        assert_eq!(cache.prune(1500), vec![Address::from(old)]);
+
+        // a moved account keeps its profile under the new key
+        let newer = SecretKey::random().public_key();
+        let record = MigrationRecord::new(&new, &newer, 3000, [0; 64], [0; 64]);
+        assert!(cache.migrate(&record, 3000));
+        assert!(cache.get(&new_addr).is_none());
+        let listed = cache.list_cached_identities();
+        assert_eq!(listed[0].attr.as_ref().unwrap().name, "bob");
+        let newer_addr = Address::from(newer);
+        assert!(cache.forget(&newer_addr));
+        assert!(!cache.forget(&newer_addr));
+        assert!(cache.is_empty());
+    }
+}
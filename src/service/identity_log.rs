@@ -0,0 +1,115 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::user::time_format::TimeFormat;
+use crate::user::user::Address;
+
+use super::IDENTITY_LOG_LIMIT;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IdentityChangeKind {
+    // a verified migration record moved the account to a new key
+    Moved { old: Address, new: Address },
+    Deleted(Address),
+}
+
+// What happened to a followed account, and what was done about it locally
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdentityChange {
+    // seconds since the epoch, when it was noticed
+    pub at: u64,
+    pub kind: IdentityChangeKind,
+    // the petname of the account, which moves along with it
+    pub petname: Option<String>,
+}
+
+impl fmt::Display for IdentityChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let at = TimeFormat::default().absolute(self.at);
+        let petname = self.petname.as_ref().map_or(String::new(), |p| format!("~{} ", p));
+        match &self.kind {
+            IdentityChangeKind::Moved { old, new } => write!(
+                f,
+                "[{}] {}@{} moved to @{}, followed there",
+                at,
+                petname,
+                old.to_string(),
+                new.to_string()
+            ),
+            IdentityChangeKind::Deleted(addr) => write!(
+                f,
+                "[{}] {}@{} deleted the account",
+                at,
+                petname,
+                addr.to_string()
+            ),
+        }
+    }
+}
+
+// The identity changes applied to followings without asking, kept for the
+// user to look back on: the last IDENTITY_LOG_LIMIT of them, oldest first.
+#[derive(Debug, Clone, Default)]
+pub struct IdentityLog {
+    changes: VecDeque<IdentityChange>,
+}
+
+impl IdentityLog {
+    pub fn new() -> IdentityLog {
+        IdentityLog::default()
+    }
+
+    // anything unreadable gives an empty log
+    pub fn from_bytes(bytes: &[u8]) -> IdentityLog {
+        IdentityLog {
+            changes: serde_json::from_slice(bytes).unwrap_or_default(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(&self.changes).unwrap()
+    }
+
+    pub fn push(&mut self, change: IdentityChange) {
+        self.changes.push_back(change);
+        if self.changes.len() > IDENTITY_LOG_LIMIT {
+            self.changes.pop_front();
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &IdentityChange> {
+        self.changes.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identity_log_test() {
+        let (old, new) = (Address::new([1; 32]), Address::new([2; 32]));
+        let mut log = IdentityLog::new();
+        for at in 0..IDENTITY_LOG_LIMIT as u64 + 1 {
+            log.push(IdentityChange {
+                at,
+                kind: IdentityChangeKind::Moved {
+                    old: old.clone(),
+                    new: new.clone(),
+                },
+                petname: Some("bob".to_string()),
+            });
+        }
+        assert_eq!(log.iter().next().unwrap().at, 1);
+        assert!(log.iter().next().unwrap().to_string().contains("~bob @"));
+
+        let copy = IdentityLog::from_bytes(&log.to_bytes());
+        assert_eq!(copy.iter().count(), IDENTITY_LOG_LIMIT);
+        assert!(IdentityLog::from_bytes(b"garbage").is_empty());
+    }
+}
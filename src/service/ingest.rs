@@ -0,0 +1,220 @@
+use chrono::Utc;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+
+use crate::crypto::PublicKey;
+use crate::user::post::{PostRef, SignedPost, VerifyError};
+use crate::user::user::{Address, MigrationRecord};
+use crate::util::crypto_pool;
+
+use super::author_stats::AuthorStatsBook;
+use super::{
+    AccountStatus, KeySource, UserDHT, INGEST_MAX_AGE, INGEST_MAX_SKEW, INGEST_SEEN_LIMIT,
+    MAX_POST_LEN,
+};
+
+// A post which went through the whole pipeline, with the key it was checked with
+#[derive(Debug, Clone)]
+pub struct VerifiedPost {
+    pub sigpost: SignedPost,
+    pub pubkey: PublicKey,
+    pub key_source: KeySource,
+}
+
+// The one way posts from elsewhere come in: decoded, bounded in size, signed
+// by the key registered for their address, delivered once and not too far
+// from now. Each consumer keeps one, since a post is a duplicate only to
+// whoever ingested it before.
+pub struct Ingestor {
+    dht: UserDHT,
+    keys: Mutex<HashMap<Address, PublicKey>>,
+    seen: Mutex<SeenPosts>,
+    // where what is ingested is counted, see with_stats
+    stats: Option<Arc<Mutex<AuthorStatsBook>>>,
+}
+
+impl Ingestor {
+    pub fn new(dht: UserDHT) -> Ingestor {
+        Ingestor {
+            dht,
+            keys: Mutex::new(HashMap::new()),
+            seen: Mutex::new(SeenPosts::default()),
+            stats: None,
+        }
+    }
+
+    // counts every post ingested in stats, which other ingestors may share
+    pub fn with_stats(mut self, stats: Arc<Mutex<AuthorStatsBook>>) -> Ingestor {
+        self.stats = Some(stats);
+        self
+    }
+
+    // keys known already, like the ones of followed accounts, which are then
+    // not looked up
+    pub fn remember_keys<'a>(&self, pubkeys: impl IntoIterator<Item = &'a PublicKey>) {
+        let mut keys = self.keys.lock().unwrap();
+        for pk in pubkeys {
+            keys.insert(Address::from(pk.clone()), pk.clone());
+        }
+    }
+
+    pub async fn ingest_post(&self, bytes: &[u8]) -> Result<VerifiedPost, IngestError> {
+        if bytes.len() > MAX_POST_LEN {
+            return Err(IngestError::TooLarge(bytes.len()));
+        }
+        let sigpost = SignedPost::from_bytes(bytes).map_err(|_| IngestError::Decode)?;
+        self.ingest_at(sigpost, Utc::now().timestamp() as u64).await
+    }
+
+    // for posts decoded already, like the ones a Subscriber hands out
+    pub async fn ingest_decoded(&self, sigpost: SignedPost) -> Result<VerifiedPost, IngestError> {
+        self.ingest_at(sigpost, Utc::now().timestamp() as u64).await
+    }
+
+    async fn ingest_at(&self, sigpost: SignedPost, now: u64) -> Result<VerifiedPost, IngestError> {
+        let created_at = sigpost.post.created_at;
+        if created_at > now + INGEST_MAX_SKEW {
+            return Err(IngestError::FromFuture(created_at - now));
+        }
+        if now.saturating_sub(created_at) > INGEST_MAX_AGE {
+            return Err(IngestError::Stale(now - created_at));
+        }
+        let post_ref = sigpost.post_ref();
+        if self.seen.lock().unwrap().contains(&post_ref) {
+            return Err(IngestError::Duplicate);
+        }
+
+        let known = self.keys.lock().unwrap().get(&sigpost.addr).cloned();
+        let (pubkey, key_source) = match known {
+            Some(pk) => (pk, KeySource::Cache),
+            None => match self.dht.get_account(sigpost.addr.clone()).await {
+                Some(AccountStatus::Active(pk)) => (pk, KeySource::Dht),
+                Some(AccountStatus::Deleted(_)) => return Err(IngestError::AccountDeleted),
+                Some(AccountStatus::Migrated(record)) => {
+                    return Err(IngestError::AccountMoved(Box::new(record)))
+                }
+                None => return Err(IngestError::UnknownKey),
+            },
+        };
+        let verified = {
+            let (sigpost, pubkey) = (sigpost.clone(), pubkey.clone());
+            crypto_pool::run(move || sigpost.verify(&pubkey)).await
+        };
+        verified.map_err(IngestError::Signature)?;
+
+        // only now, so that a forged copy cannot shut the real post out
+        if !self.seen.lock().unwrap().insert(post_ref) {
+            return Err(IngestError::Duplicate);
+        }
+        if key_source == KeySource::Dht {
+            self.keys
+                .lock()
+                .unwrap()
+                .insert(sigpost.addr.clone(), pubkey.clone());
+        }
+        if let Some(stats) = &self.stats {
+            stats.lock().unwrap().record(&sigpost);
+        }
+        Ok(VerifiedPost {
+            sigpost,
+            pubkey,
+            key_source,
+        })
+    }
+}
+
+// the last INGEST_SEEN_LIMIT posts ingested
+#[derive(Default)]
+pub(super) struct SeenPosts {
+    refs: HashSet<PostRef>,
+    order: VecDeque<PostRef>,
+}
+
+impl SeenPosts {
+    fn contains(&self, post_ref: &PostRef) -> bool {
+        self.refs.contains(post_ref)
+    }
+
+    // false if it was seen already
+    pub(super) fn insert(&mut self, post_ref: PostRef) -> bool {
+        if !self.refs.insert(post_ref.clone()) {
+            return false;
+        }
+        self.order.push_back(post_ref);
+        if self.order.len() > INGEST_SEEN_LIMIT {
+            if let Some(old) = self.order.pop_front() {
+                self.refs.remove(&old);
+            }
+        }
+        true
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum IngestError {
+    #[error("The post is {0} bytes, more than allowed")]
+    TooLarge(usize),
+    #[error("Malformed post")]
+    Decode,
+    #[error("The post is dated {0}s ahead")]
+    FromFuture(u64),
+    #[error("The post is {0}s old")]
+    Stale(u64),
+    #[error("The post was ingested already")]
+    Duplicate,
+    #[error("No key is registered for the address")]
+    UnknownKey,
+    #[error("The account is deleted")]
+    AccountDeleted,
+    #[error("The account moved")]
+    AccountMoved(Box<MigrationRecord>),
+    #[error("{0}")]
+    Signature(VerifyError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kad::Rpc;
+    use crate::service::{test_user, user_network, TESTNET_USER_DHT};
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    #[tokio::test]
+    async fn ingest_test() {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let rpc = Arc::new(Mutex::new(Rpc::new(socket)));
+        let dht = UserDHT::start(rpc, &user_network(TESTNET_USER_DHT, 32), &[], None).await;
+        let ingestor = Ingestor::new(dht);
+
+        let mut alice = test_user("alice");
+        let sigpost = alice.hoot("hi".to_string(), None, None, vec![]).unwrap();
+        let bytes = serde_json::to_vec(&sigpost).unwrap();
+
+        // nobody registered alice's key
+        assert!(matches!(ingestor.ingest_post(&bytes).await, Err(IngestError::UnknownKey)));
+        ingestor.remember_keys(&[alice.pubkey().unwrap()]);
+        let verified = ingestor.ingest_post(&bytes).await.unwrap();
+        assert_eq!(verified.sigpost, sigpost);
+        assert_eq!(verified.key_source, KeySource::Cache);
+        assert!(matches!(ingestor.ingest_post(&bytes).await, Err(IngestError::Duplicate)));
+
+        let mut forged = alice.hoot("again".to_string(), None, None, vec![]).unwrap();
+        forged.post.created_at -= 1;
+        let now = forged.post.created_at;
+        assert!(matches!(ingestor.ingest_at(forged, now).await, Err(IngestError::Signature(_))));
+        let next = alice.hoot("later".to_string(), None, None, vec![]).unwrap();
+        let created_at = next.post.created_at;
+        assert!(matches!(
+            ingestor.ingest_at(next.clone(), created_at + INGEST_MAX_AGE + 1).await,
+            Err(IngestError::Stale(_))
+        ));
+        assert!(matches!(
+            ingestor.ingest_at(next, created_at - INGEST_MAX_SKEW - 1).await,
+            Err(IngestError::FromFuture(_))
+        ));
+        let big = vec![b' '; MAX_POST_LEN + 1];
+        assert!(matches!(ingestor.ingest_post(&big).await, Err(IngestError::TooLarge(_))));
+    }
+}
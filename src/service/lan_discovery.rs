@@ -0,0 +1,150 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashSet;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use tokio::net::UdpSocket;
+use tokio::time::{timeout_at, Duration, Instant};
+
+use super::LAN_DISCOVERY_REPLIES;
+use crate::kad::{NodeInfo, Rpc, MESSAGE_LEN};
+
+// Finds peers on the local network without any bootstrap address: a starting
+// node asks on a multicast group, and every node listening there answers with
+// its node infos. Meant for demos, offline events and tests.
+#[derive(Debug, Serialize, Deserialize)]
+enum LanMessage {
+    Query,
+    Announce(Vec<NodeInfo>),
+}
+
+// Answers queries on group with the node infos of rpcs, until the runtime stops.
+// Every node of a host listens on the same port. Only queries sent to the
+// group from the local network are answered, at most LAN_DISCOVERY_REPLIES a
+// second, so that the node cannot be asked from afar or used to flood someone.
+pub async fn serve(rpcs: Vec<Rpc>, group: SocketAddrV4) -> io::Result<()> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    // bound to the group, the socket only gets what is sent to the group; Windows
+    // cannot bind to a multicast address
+    let bind_ip = if cfg!(windows) { Ipv4Addr::UNSPECIFIED } else { *group.ip() };
+    socket.bind(&SocketAddr::from((bind_ip, group.port())).into())?;
+    socket.join_multicast_v4(group.ip(), &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(socket.into())?;
+
+    tokio::spawn(async move {
+        let mut buf = [0; MESSAGE_LEN];
+        let (mut window, mut replies) = (Instant::now(), 0);
+        loop {
+            let (len, src) = match socket.recv_from(&mut buf).await {
+                Ok(ret) => ret,
+                Err(e) => {
+                    warn!("LAN discovery stopped: {}", e);
+                    return;
+                }
+            };
+            if !is_local(src.ip()) {
+                continue;
+            }
+            if !matches!(serde_json::from_slice(&buf[..len]), Ok(LanMessage::Query)) {
+                continue;
+            }
+            if window.elapsed() >= Duration::from_secs(1) {
+                (window, replies) = (Instant::now(), 0);
+            }
+            if replies >= LAN_DISCOVERY_REPLIES {
+                continue;
+            }
+            replies += 1;
+            // the user and the pubsub DHT may share a socket
+            let mut node_infos = Vec::new();
+            for rpc in rpcs.iter() {
+                node_infos.append(&mut rpc.node_infos().await);
+            }
+            node_infos.sort();
+            node_infos.dedup();
+            let announce = serde_json::to_vec(&LanMessage::Announce(node_infos)).unwrap();
+            let _ = socket.send_to(&announce, src).await;
+        }
+    });
+    Ok(())
+}
+
+// a private, link-local or loopback address, which a query from the local network comes from
+fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_link_local() || ip.is_loopback(),
+        IpAddr::V6(_) => false,
+    }
+}
+
+// The node infos announced on group within wait. Nodes bound to an unspecified
+// address are reached at the address they answered from.
+pub async fn discover(group: SocketAddrV4, wait: Duration) -> io::Result<Vec<NodeInfo>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_multicast_loop_v4(true)?;
+    let query = serde_json::to_vec(&LanMessage::Query).unwrap();
+    socket.send_to(&query, group).await?;
+
+    let deadline = Instant::now() + wait;
+    let mut found = HashSet::new();
+    let mut buf = [0; MESSAGE_LEN];
+    while let Ok(received) = timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, src) = received?;
+        let node_infos = match serde_json::from_slice(&buf[..len]) {
+            Ok(LanMessage::Announce(node_infos)) => node_infos,
+            _ => continue,
+        };
+        for mut node_info in node_infos {
+            if node_info.addr.ip().is_unspecified() {
+                node_info.addr.set_ip(src.ip());
+            }
+            found.insert(node_info);
+        }
+    }
+    info!("Found {} nodes on the local network", found.len());
+    Ok(found.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kad::Key;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn is_local_test() {
+        for ip in ["192.168.1.20", "10.1.2.3", "172.16.0.1", "169.254.0.7", "127.0.0.1"] {
+            assert!(is_local(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["8.8.8.8", "172.32.0.1", "::1"] {
+            assert!(!is_local(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    // needs multicast to reach this host from a private address, which CI
+    // sandboxes often lack; run with --ignored
+    #[tokio::test]
+    #[ignore]
+    async fn lan_discovery_test() {
+        let group = SocketAddrV4::new(Ipv4Addr::new(239, 255, 62, 70), 16273);
+        let socket = UdpSocket::bind("0.0.0.0:0").await.unwrap();
+        let mut rpc = Rpc::new(socket);
+        let node_info = NodeInfo {
+            id: Key::random(32),
+            addr: rpc.socket.local_addr().unwrap(),
+            net_id: "test_user_dht".to_string(),
+        };
+        let (tx, _rx) = mpsc::unbounded_channel();
+        rpc.add(node_info.clone(), tx).await;
+        serve(vec![rpc.clone(), rpc], group).await.unwrap();
+
+        let found = discover(group, Duration::from_millis(500)).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].id, node_info.id);
+        assert!(!found[0].addr.ip().is_unspecified());
+        assert_eq!(found[0].addr.port(), node_info.addr.port());
+    }
+}
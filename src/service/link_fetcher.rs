@@ -0,0 +1,19 @@
+use crate::user::link::{LinkError, LinkPreview};
+
+use super::http;
+use super::{LINK_FETCH_LIMIT, LINK_FETCH_TIME_OUT};
+
+// Fetches the page at url and makes a preview card of it. Only called when
+// the user opted in, since it tells the site who is about to post the link.
+pub async fn fetch_preview(url: &str) -> Result<LinkPreview, LinkError> {
+    let preview = LinkPreview::new(url);
+    preview.validate()?;
+
+    // the head of the page is enough
+    let html = http::get(url, LINK_FETCH_LIMIT, LINK_FETCH_TIME_OUT)
+        .await
+        .map_err(|e| LinkError::Fetch(e.to_string()))?;
+    let preview = LinkPreview::from_html(url, &String::from_utf8_lossy(&html));
+    preview.validate()?;
+    Ok(preview)
+}
@@ -1,15 +1,145 @@
 mod network;
 mod user_handle;
+mod watch_handle;
 mod controller;
+mod filter;
+mod scheduler;
+mod recommend;
+mod history;
+mod user_index;
+mod link_fetcher;
+mod verification;
+mod tunables;
+mod identity_cache;
+mod session;
+mod bootstrap;
+mod http;
+mod port_mapping;
+mod subscriptions;
+mod ingest;
+mod telemetry;
+mod feed;
+mod identity_log;
+mod notifications;
+mod composer;
+mod author_stats;
+pub mod lan_discovery;
+pub mod activitystreams;
 
-pub use user_handle::UserHandle;
-pub use network::{UserDHT,Publisher,Subscriber};
+pub use user_handle::{UnlockedUserHandle, UserHandle};
+#[cfg(test)]
+pub(crate) use user_handle::test_user;
+pub use watch_handle::WatchHandle;
+pub use network::{
+    default_networks, pubsub_network, shard_key, user_network, AccountStatus, PublishError,
+    PublishReport, Publisher, RegionReport, Subscriber, SubscriptionHandle, UserDHT,
+};
 pub use controller::*;
+pub use filter::{Filter, FilterError};
+pub use scheduler::Scheduler;
+pub use recommend::{recommend_follows, Recommendation};
+pub use history::{backfill, BackfillEvent, SyncStatus};
+pub use user_index::{UserIndex, UserMatch};
+pub use link_fetcher::fetch_preview;
+pub use verification::{Embedding, KeySource, SignatureStatus, VerificationReport};
+pub use tunables::{ConfigEvent, Tunables, TunablesError};
+pub use identity_cache::{CachedIdentity, IdentityCache};
+pub use session::Session;
+pub use bootstrap::{
+    fetch_bootstrap, fetch_bootstrap_lists, BootstrapAddr, BootstrapError, BootstrapList,
+    BootstrapListSource, SignedBootstrapList,
+};
+pub use http::HttpError;
+pub use port_mapping::{Gateway, PortMapper, PortMapping, PortMappingError};
+pub use subscriptions::{AccountFeed, SubscriptionManager};
+pub use ingest::{IngestError, Ingestor, VerifiedPost};
+pub use author_stats::{AuthorStats, AuthorStatsBook};
+pub use telemetry::{export_metrics, metrics_request, OtlpEndpoint, TelemetryError};
+pub use feed::{FeedCursor, FeedCursorError, MergedFeed};
+pub use identity_log::{IdentityChange, IdentityChangeKind, IdentityLog};
+pub use composer::{validate_draft, DraftReport, DraftWarning, MentionResolution};
+pub use notifications::{
+    notifications_for, Notification, NotificationHandle, NotificationKind, Notifier,
+};
 
+// key lengths of default_networks
 pub const USER_DHT_KEY_LENGTH: usize= 32;
 pub const PUBSUB_DHT_KEY_LENGTH: usize= 64;
+// every post is multicast to this many group keys per author
+pub const PUBSUB_SHARDS: u8 = 4;
+// bytes of the id kept in Config.node_id_path
+pub const NODE_ID_LEN: usize = 32;
+// the file next to Config.node_id_path keeping the key of delivery receipts
+pub const RECEIPT_KEY_FILE: &str = "receipt_key";
+// posts fetched from the DHT when following someone new
+pub const HISTORY_BACKFILL: usize = 20;
+// the latest posts of an archiving author kept in the pubsub DHT, one per slot
+pub const FEED_SLOTS: u8 = 16;
+// posts a subscriber keeps for receivers which have not read them yet
+pub const SUBSCRIBER_CHANNEL_CAPACITY: usize = 256;
+// mentions and replies kept for local accounts which have not read them yet
+pub const NOTIFICATION_CHANNEL_CAPACITY: usize = 64;
+// migrations followed when resolving an address, so that a cycle ends
+pub const MAX_MIGRATION_HOPS: usize = 4;
+// moves and deletions of followed accounts kept for the user to review
+pub const IDENTITY_LOG_LIMIT: usize = 500;
+// tries to append to a mailbox chunk other senders are appending to
+pub const MAILBOX_APPEND_ATTEMPTS: usize = 3;
+// bytes of the largest post ingested, which fits in one multicast
+pub const MAX_POST_LEN: usize = 8192;
+// seconds a post may be dated ahead of this clock, and how old it may be
+pub const INGEST_MAX_SKEW: u64 = 300;
+pub const INGEST_MAX_AGE: u64 = 86400; // 1 day
+// posts an Ingestor remembers to drop their duplicates
+pub const INGEST_SEEN_LIMIT: usize = 4096;
 
 pub const TESTNET_USER_DHT: &str = "test_user_dht";
 pub const TESTNET_PUBSUB_DHT: &str = "test_pubsub_dht";
 pub const MAINNET_USER_DHT: &str = "user_dht";
-pub const MAINNET_PUBSUB_DHT: &str = "pubsub_dht";
\ No newline at end of file
+pub const MAINNET_PUBSUB_DHT: &str = "pubsub_dht";
+
+// multicast group and port of LAN discovery, and how long a starting node
+// waits for answers
+pub const LAN_DISCOVERY_GROUP: [u8; 4] = [239, 255, 62, 70];
+pub const LAN_DISCOVERY_PORT: u16 = 6273;
+pub const LAN_DISCOVERY_WAIT: u64 = 1000;
+// queries answered per second, over all askers
+pub const LAN_DISCOVERY_REPLIES: u32 = 10;
+
+// port mapping on the home router, see PortMapper
+pub const NATPMP_PORT: u16 = 5351;
+pub const SSDP_GROUP: [u8; 4] = [239, 255, 255, 250];
+pub const SSDP_PORT: u16 = 1900;
+pub const SSDP_WAIT: u64 = 2000;
+pub const PORT_MAPPING_LIFETIME: u32 = 7200; // seconds
+pub const PORT_MAPPING_RETRY: u64 = 60000;
+
+pub const MAINTENANCE_TICK: u64 = 1000;
+// reloads a slow config_events receiver may miss
+pub const CONFIG_EVENTS_CAPACITY: usize = 16;
+// bytes read from a page for its link preview
+pub const LINK_FETCH_LIMIT: u64 = 256 * 1024;
+pub const LINK_FETCH_TIME_OUT: u64 = 5000;
+pub const REPUBLISH_INTERVAL: u64 = 3600000; // 1 hour
+pub const REPUBLISH_JITTER: u64 = 60000;
+// how often bootstrap host names are looked up again
+pub const BOOTSTRAP_RESOLVE_INTERVAL: u64 = 600000; // 10 minutes
+pub const BOOTSTRAP_RESOLVE_JITTER: u64 = 30000;
+// bytes of a signed bootstrap list fetched over http(s), and how long it may take
+pub const BOOTSTRAP_LIST_LIMIT: u64 = 64 * 1024;
+pub const BOOTSTRAP_LIST_TIME_OUT: u64 = 10000;
+// stored peers of each DHT tried first at start
+pub const PEER_STORE_BOOTSTRAP: usize = 16;
+// stored peers of each DHT put into an invite
+pub const INVITE_PEERS_PER_DHT: usize = 2;
+// the changes to the peer store are batched and written this often, if any
+pub const PEER_STORE_SAVE_INTERVAL: u64 = 60000;
+pub const PEER_STORE_SAVE_JITTER: u64 = 10000;
+// how often the stats go to the OpenTelemetry collector, see Tunables.otlp_endpoint
+// how often the lapsed multicast hashes of the local nodes are taken out
+pub const BROADCAST_SWEEP_INTERVAL: u64 = 60000;
+pub const BROADCAST_SWEEP_JITTER: u64 = 5000;
+pub const OTLP_EXPORT_INTERVAL: u64 = 60000;
+pub const OTLP_EXPORT_JITTER: u64 = 5000;
+pub const OTLP_TIME_OUT: u64 = 5000;
+pub const OTLP_SERVICE_NAME: &str = "noktulo";
@@ -1,135 +1,951 @@
 use crate::crypto::PublicKey;
 use crate::kad::Key;
-use crate::kad::{Node, NodeInfo, Rpc};
-use crate::user::post::SignedPost;
-use crate::user::user::Address;
+use crate::kad::{
+    DeliveryReceipt, IdScheme, NetworkDescriptor, NetworkRegistry, Node, NodeInfo, Rpc, Store, StoreBackend,
+    JOIN_INTERVAL,
+};
+use crate::user::backup::BackupRecord;
+use crate::user::block::BlockList;
+use crate::user::history::{HeadRecord, HistoryEntry};
+use crate::user::mailbox::{MailboxChunk, MailboxError, MailboxIndex, MailboxItem};
+use crate::user::post::{PostKind, SignedPost};
+use crate::user::post_id::PostId;
+use crate::user::user::{
+    AccountTombstone, Address, MigrationRecord, ProfileRecord, RegistrationRecord,
+    SignedUserAttribute,
+};
+use crate::util::crypto_pool;
+use crate::util::stats::StatsRecorder;
 use log::info;
-use std::collections::HashMap;
-use std::convert::TryInto;
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::convert::{TryFrom, TryInto};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock as StdRwLock};
+use thiserror::Error;
 use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
 use tokio::sync::{broadcast, Mutex};
+use tokio::time::Duration;
 
-use super::{PUBSUB_DHT_KEY_LENGTH, TESTNET_PUBSUB_DHT, TESTNET_USER_DHT, USER_DHT_KEY_LENGTH};
+use super::filter::Filter;
+use super::{
+    FEED_SLOTS, MAILBOX_APPEND_ATTEMPTS, MAX_MIGRATION_HOPS, PUBSUB_DHT_KEY_LENGTH, PUBSUB_SHARDS,
+    SUBSCRIBER_CHANNEL_CAPACITY, TESTNET_PUBSUB_DHT, TESTNET_USER_DHT, USER_DHT_KEY_LENGTH,
+};
 
+// A user DHT: node ids are random, and only account records, history and
+// mailboxes are stored.
+pub fn user_network(net_id: &str, key_length: usize) -> NetworkDescriptor {
+    NetworkDescriptor::new(net_id, key_length, IdScheme::Random)
+        .with_validator(Arc::new(UserDHT::is_storable))
+}
+
+// A pubsub DHT: the nodes listening for an author share its shard key as
+// the prefix of their ids, and only the feeds of authors archiving their
+// posts are stored, see Publisher::archive_posts.
+pub fn pubsub_network(net_id: &str, key_length: usize) -> NetworkDescriptor {
+    NetworkDescriptor::new(net_id, key_length, IdScheme::Prefixed).with_validator(Arc::new(
+        |data: &[u8]| HistoryEntry::from_bytes(data).is_some_and(|e| e.verify().is_ok()),
+    ))
+}
+
+fn pubsub_store(net: &NetworkDescriptor) -> Store {
+    let net = net.clone();
+    net.store().with_conflict(Arc::new(move |key, old, new| {
+        may_archive(&net, key, old, new)
+    }))
+}
+
+// A feed slot holds a post of its author, replaced only by a later one
+fn may_archive(net: &NetworkDescriptor, key: &Key, old: Option<&[u8]>, new: &[u8]) -> bool {
+    let new = match HistoryEntry::from_bytes(new) {
+        Some(new) => new,
+        None => return false,
+    };
+    let addr = Address::from(new.pubkey.clone());
+    if !(0..FEED_SLOTS).any(|slot| *key == feed_key(net, &addr, slot)) {
+        return false;
+    }
+    match old.map(HistoryEntry::from_bytes) {
+        Some(Some(old)) => {
+            old.pubkey == new.pubkey && old.sigpost.post.created_at <= new.sigpost.post.created_at
+        }
+        Some(None) => false,
+        None => true,
+    }
+}
+
+// one of the FEED_SLOTS places in the pubsub DHT an author's latest posts take turns in
+fn feed_key(net: &NetworkDescriptor, addr: &Address, slot: u8) -> Key {
+    let addr_bytes: [u8; 32] = addr.clone().into();
+    net.key(&[&addr_bytes[..], b"feed", &[slot]].concat())
+}
+
+// The test network. Experiments register other key lengths under the same net_ids.
+pub fn default_networks() -> NetworkRegistry {
+    NetworkRegistry::new()
+        .with(user_network(TESTNET_USER_DHT, USER_DHT_KEY_LENGTH))
+        .with(pubsub_network(TESTNET_PUBSUB_DHT, PUBSUB_DHT_KEY_LENGTH))
+}
+
+// Group key of one delivery shard of an author, a prefix of pubsub node ids.
+// Shard 0 is the address itself, which is where subscribers without sharding listen.
+pub fn shard_key(addr: &Address, shard: u8) -> Key {
+    let addr_bytes: [u8; 32] = addr.clone().into();
+    if shard == 0 {
+        Key::from(addr_bytes)
+    } else {
+        Key::hash(&[&addr_bytes[..], &[shard]].concat(), addr_bytes.len())
+    }
+}
+
+// Where the account records of an address live in the user DHT
+fn account_key(net: &NetworkDescriptor, addr: &Address) -> Key {
+    let addr_bytes: [u8; 32] = addr.clone().into();
+    net.key(&addr_bytes)
+}
+
+// where the profile of an address lives
+fn profile_key(net: &NetworkDescriptor, addr: &Address) -> Key {
+    let addr_bytes: [u8; 32] = addr.clone().into();
+    net.key(&[&addr_bytes[..], b"profile"].concat())
+}
+
+// Where the head record and the archived posts of an author live
+fn head_key(net: &NetworkDescriptor, addr: &Address) -> Key {
+    let addr_bytes: [u8; 32] = addr.clone().into();
+    net.key(&[&addr_bytes[..], b"head"].concat())
+}
+
+fn history_key(net: &NetworkDescriptor, addr: &Address, id: PostId) -> Key {
+    let addr_bytes: [u8; 32] = addr.clone().into();
+    net.key(&[&addr_bytes[..], b"post", &id.to_be_bytes()[..]].concat())
+}
+
+// Where the mailbox index of an address and the chunks of each epoch live
+fn mailbox_index_key(net: &NetworkDescriptor, addr: &Address) -> Key {
+    let addr_bytes: [u8; 32] = addr.clone().into();
+    net.key(&[&addr_bytes[..], b"mailbox"].concat())
+}
+
+fn mailbox_chunk_key(net: &NetworkDescriptor, addr: &Address, epoch: u64, index: u32) -> Key {
+    let addr_bytes: [u8; 32] = addr.clone().into();
+    net.key(
+        &[
+            &addr_bytes[..],
+            b"mailbox",
+            &epoch.to_be_bytes()[..],
+            &index.to_be_bytes()[..],
+        ]
+        .concat(),
+    )
+}
+
+// Where each chunk of an account's backup lives
+fn backup_key(net: &NetworkDescriptor, addr: &Address, index: u16) -> Key {
+    let addr_bytes: [u8; 32] = addr.clone().into();
+    net.key(&[&addr_bytes[..], b"backup", &index.to_be_bytes()[..]].concat())
+}
+
+#[derive(Clone)]
 pub struct UserDHT {
     user_dht: Arc<Node>,
+    net: NetworkDescriptor,
 }
 
 impl UserDHT {
-    pub async fn start(rpc: Arc<Mutex<Rpc>>, bootstrap: &[NodeInfo]) -> UserDHT {
+    // Records kept in backend, like registered keys, are served again after a
+    // restart without waiting for their owners to republish them.
+    pub async fn start(
+        rpc: Arc<Mutex<Rpc>>,
+        network: &NetworkDescriptor,
+        bootstrap: &[NodeInfo],
+        backend: Option<Arc<dyn StoreBackend>>,
+    ) -> UserDHT {
         // As of now, rx is not used
         let (tx, _rx) = mpsc::unbounded_channel();
+        let entropy = rpc.lock().await.entropy();
 
-        let user_dht = Node::start(
-            TESTNET_USER_DHT.to_string(),
-            USER_DHT_KEY_LENGTH,
-            Key::random(USER_DHT_KEY_LENGTH),
-            Arc::new(|data| UserDHT::is_valid_addr_pubkey_pair(data)),
+        let net = network.clone();
+        let mut store = network.store().with_conflict(Arc::new(move |key, old, new| {
+            UserDHT::may_store(&net, key, old, new)
+        }));
+        if let Some(backend) = backend {
+            store = store.with_backend(backend);
+        }
+        let user_dht = Node::start_with_store(
+            network,
+            network.node_id(None, entropy.as_ref()),
+            store,
             rpc.clone(),
             tx.clone(),
-            bootstrap.clone(),
+            bootstrap,
         )
         .await;
         info!("User DHT node started");
 
         UserDHT {
             user_dht: Arc::new(user_dht),
+            net: network.clone(),
         }
     }
 
+    pub async fn add_peers(&self, node_infos: &[NodeInfo]) {
+        self.user_dht.add_peers(node_infos).await;
+    }
+
+    pub async fn sweep_broadcast_tokens(&self) -> usize {
+        self.user_dht.sweep_broadcast_tokens().await
+    }
+
+    pub fn is_storable(data: &[u8]) -> bool {
+        UserDHT::is_valid_addr_pubkey_pair(data)
+            || RegistrationRecord::from_bytes(data).and_then(|r| r.verify()).is_ok()
+            || AccountTombstone::from_bytes(data)
+                .and_then(|t| t.verify())
+                .is_ok()
+            || HeadRecord::from_bytes(data)
+                .and_then(|h| h.verify())
+                .is_ok()
+            || HistoryEntry::from_bytes(data).is_some_and(|e| e.verify().is_ok())
+            || MigrationRecord::from_bytes(data)
+                .and_then(|m| m.verify())
+                .is_ok()
+            || MailboxIndex::from_bytes(data)
+                .and_then(|i| i.verify())
+                .is_ok()
+            || MailboxChunk::from_bytes(data).is_some_and(|c| c.verify().is_ok())
+            || BackupRecord::from_bytes(data)
+                .and_then(|b| b.verify())
+                .is_ok()
+            || ProfileRecord::from_bytes(data).is_some_and(|p| p.verify().is_ok())
+    }
+
     pub fn is_valid_addr_pubkey_pair(data: &[u8]) -> bool {
+        UserDHT::parse_addr_pubkey_pair(data).is_some()
+    }
+
+    // addr(32) | pubkey(32), where addr is derived from pubkey
+    fn parse_addr_pubkey_pair(data: &[u8]) -> Option<PublicKey> {
         if data.len() != 64 {
-            false
+            return None;
+        }
+        let addr = Address::new(data[..32].try_into().unwrap());
+        let pk = PublicKey::try_from(&data[32..]).ok()?;
+        if addr == Address::from(pk.clone()) {
+            Some(pk)
         } else {
-            let addr = Address::new(data[..32].try_into().unwrap());
-            if let Ok(pk) = PublicKey::from_bytes(&data[32..].try_into().unwrap()) {
-                let addr2 = Address::from(pk);
-                addr == addr2
-            } else {
-                false
+            None
+        }
+    }
+
+    // Account records (registrations, tombstones and migrations) live under
+    // the key of their address. The first valid registration there wins:
+    // later records have to be signed by the same key, a registration is only
+    // replaced by one signed as late or later, and a tombstone or a migration
+    // is final. Mailbox chunks are only appended to, a mailbox
+    // index only moves to a later epoch, and backups are only replaced by newer
+    // ones of the same account, profiles by ones of the same key created as
+    // late or later. A head record is only replaced by a newer one of the same
+    // key, and a post of the history by a later post of its author, the Delete
+    // of it, which is final. Values passed the store predicate already.
+    fn may_store(net: &NetworkDescriptor, key: &Key, old: Option<&[u8]>, new: &[u8]) -> bool {
+        if let Some(record) = ProfileRecord::from_bytes(new) {
+            if *key != profile_key(net, &record.profile.addr) {
+                return false;
+            }
+            return match old.map(ProfileRecord::from_bytes) {
+                Some(Some(old)) => {
+                    old.pubkey == record.pubkey
+                        && old.profile.attr.created_at <= record.profile.attr.created_at
+                }
+                Some(None) => false,
+                None => true,
+            };
+        }
+        if let Ok(head) = HeadRecord::from_bytes(new) {
+            if *key != head_key(net, &head.addr()) {
+                return false;
+            }
+            return match old.map(HeadRecord::from_bytes) {
+                Some(Ok(old)) => {
+                    // posts made within the same second move the head by id
+                    old.pubkey == head.pubkey
+                        && (old.updated_at, old.latest) <= (head.updated_at, head.latest)
+                }
+                Some(Err(_)) => false,
+                None => true,
+            };
+        }
+        if let Some(entry) = HistoryEntry::from_bytes(new) {
+            let addr = Address::from(entry.pubkey.clone());
+            let id = match entry.sigpost.post.content {
+                PostKind::Delete(id) if *key == history_key(net, &addr, id) => id,
+                _ => entry.sigpost.post.id,
+            };
+            if entry.sigpost.addr != addr || *key != history_key(net, &addr, id) {
+                return false;
+            }
+            return match old.map(HistoryEntry::from_bytes) {
+                Some(Some(old)) => {
+                    let deleted = matches!(old.sigpost.post.content, PostKind::Delete(_));
+                    old.pubkey == entry.pubkey
+                        && if deleted {
+                            old.sigpost == entry.sigpost
+                        } else {
+                            old.sigpost.post.created_at <= entry.sigpost.post.created_at
+                        }
+                }
+                Some(None) => false,
+                None => true,
+            };
+        }
+        if let Ok(record) = BackupRecord::from_bytes(new) {
+            if *key != backup_key(net, &record.addr(), record.index) {
+                return false;
+            }
+            return match old {
+                Some(old) => BackupRecord::from_bytes(old).is_ok_and(|o| o.is_replaced_by(&record)),
+                None => true,
+            };
+        }
+        if let Some(chunk) = MailboxChunk::from_bytes(new) {
+            if *key != mailbox_chunk_key(net, &chunk.owner, chunk.epoch, chunk.index) {
+                return false;
+            }
+            return match old {
+                Some(old) => MailboxChunk::from_bytes(old).is_some_and(|o| o.is_appended_by(&chunk)),
+                None => true,
+            };
+        }
+        if let Ok(index) = MailboxIndex::from_bytes(new) {
+            if *key != mailbox_index_key(net, &index.addr()) {
+                return false;
+            }
+            return match old {
+                Some(old) => MailboxIndex::from_bytes(old).is_ok_and(|o| {
+                    o.pubkey == index.pubkey
+                        && (o.epoch, o.compacted_at) <= (index.epoch, index.compacted_at)
+                }),
+                None => true,
+            };
+        }
+        let new_owner = UserDHT::account_record(new);
+        if let Some((owner, _)) = &new_owner {
+            if *key != account_key(net, &Address::from(owner.clone())) {
+                return false;
+            }
+        }
+        let old = match old {
+            Some(old) => old,
+            None => return true,
+        };
+        match (UserDHT::account_record(old), new_owner) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some((_, true)), Some(_)) => old == new,
+            (Some((old_owner, false)), Some((new_owner, _))) => {
+                let rolled_back = match (UserDHT::registered_at(old), UserDHT::registered_at(new)) {
+                    (Some(old_at), Some(new_at)) => new_at < old_at,
+                    _ => false,
+                };
+                old_owner == new_owner && !rolled_back
             }
         }
     }
 
-    pub async fn register_pubkey(&self, pubkey: &PublicKey) {
-        let addr_bytes: [u8; 32] = Address::from(pubkey.clone()).into();
-        let pk_bytes: [u8; 32] = pubkey.clone().into();
-        let addr_key_pair = [&addr_bytes[..], &pk_bytes].concat();
-        let key = Key::from(&addr_bytes[..]);
-        self.user_dht.put(key, &addr_key_pair).await;
+    // When a registration was signed. An unsigned address/public key pair
+    // counts as older than any, so that it cannot replace a signed record and
+    // roll the account back. None for records which close the account.
+    fn registered_at(data: &[u8]) -> Option<u64> {
+        if UserDHT::is_valid_addr_pubkey_pair(data) {
+            return Some(0);
+        }
+        RegistrationRecord::from_bytes(data)
+            .ok()
+            .map(|record| record.registered_at)
     }
 
-    pub async fn get_pubkey(&self, addr: Address) -> Option<PublicKey> {
-        let key = Key::from(addr);
-        if let Some(bytes) = self.user_dht.get(key).await {
-            if UserDHT::is_valid_addr_pubkey_pair(&bytes) {
-                return Some(PublicKey::from_bytes(&bytes[32..].try_into().unwrap()).unwrap());
+    // the key owning an account record, and whether the account is closed
+    fn account_record(data: &[u8]) -> Option<(PublicKey, bool)> {
+        if let Some(pk) = UserDHT::parse_addr_pubkey_pair(data) {
+            return Some((pk, false));
+        }
+        if let Ok(record) = RegistrationRecord::from_bytes(data) {
+            return Some((record.pubkey, false));
+        }
+        if let Ok(tombstone) = AccountTombstone::from_bytes(data) {
+            return Some((tombstone.pubkey, true));
+        }
+        MigrationRecord::from_bytes(data)
+            .ok()
+            .map(|record| (record.old_pubkey, true))
+    }
+
+    pub async fn register_pubkey(&self, registration: &RegistrationRecord) {
+        self.user_dht
+            .put(account_key(&self.net, &registration.addr()), &registration.to_bytes())
+            .await;
+    }
+
+    // replaces the address/public key pair, so that the address no longer resolves
+    pub async fn revoke(&self, tombstone: &AccountTombstone) {
+        self.user_dht
+            .put(account_key(&self.net, &tombstone.addr), &tombstone.to_bytes())
+            .await;
+    }
+
+    // replaces the address/public key pair of the old address
+    pub async fn migrate(&self, record: &MigrationRecord) {
+        self.user_dht
+            .put(account_key(&self.net, &record.old_addr()), &record.to_bytes())
+            .await;
+    }
+
+    // Publishes the profile, so that followers learn the name behind an address
+    // before its first post reaches them. pubkey is the key it is signed with.
+    pub async fn register_profile(&self, pubkey: &PublicKey, profile: &SignedUserAttribute) {
+        let record = ProfileRecord::new(pubkey, profile);
+        self.user_dht
+            .put(profile_key(&self.net, &profile.addr), &record.to_bytes())
+            .await;
+    }
+
+    pub async fn get_profile(&self, addr: &Address) -> Option<SignedUserAttribute> {
+        let bytes = self.user_dht.get(profile_key(&self.net, addr)).await?;
+        let record = ProfileRecord::from_bytes(&bytes)?;
+        if record.profile.addr != *addr {
+            return None;
+        }
+        crypto_pool::run(move || record.verify().is_ok().then_some(record.profile)).await
+    }
+
+    pub async fn get_account(&self, addr: Address) -> Option<AccountStatus> {
+        let bytes = self.user_dht.get(account_key(&self.net, &addr)).await?;
+        if let Some(pk) = UserDHT::parse_addr_pubkey_pair(&bytes) {
+            return Some(AccountStatus::Active(pk));
+        }
+        if let Ok(record) = RegistrationRecord::from_bytes(&bytes) {
+            if record.addr() != addr {
+                return None;
+            }
+            return crypto_pool::run(move || record.verify().ok().map(AccountStatus::Active))
+                .await;
+        }
+        if let Ok(record) = MigrationRecord::from_bytes(&bytes) {
+            if record.old_addr() != addr {
+                return None;
             }
+            return crypto_pool::run(move || match record.verify() {
+                Ok(()) => Some(AccountStatus::Migrated(record)),
+                Err(_) => None,
+            })
+            .await;
         }
+        let tombstone = AccountTombstone::from_bytes(&bytes).ok()?;
+        if tombstone.addr != addr {
+            return None;
+        }
+        crypto_pool::run(move || match tombstone.verify() {
+            Ok(_) => Some(AccountStatus::Deleted(tombstone)),
+            Err(_) => None,
+        })
+        .await
+    }
 
+    // Follows migrations to where the account lives now, at most
+    // MAX_MIGRATION_HOPS of them. None if it is gone or cannot be found.
+    pub async fn resolve_user(&self, addr: Address) -> Option<(Address, PublicKey)> {
+        let mut addr = addr;
+        for _ in 0..=MAX_MIGRATION_HOPS {
+            match self.get_account(addr.clone()).await? {
+                AccountStatus::Active(pk) => return Some((addr, pk)),
+                AccountStatus::Deleted(_) => return None,
+                AccountStatus::Migrated(record) => addr = record.new_addr(),
+            }
+        }
         None
     }
+
+    pub async fn get_pubkey(&self, addr: Address) -> Option<PublicKey> {
+        match self.get_account(addr).await {
+            Some(AccountStatus::Active(pk)) => Some(pk),
+            _ => None,
+        }
+    }
+
+    // Stores the post under its id and then moves the head to it.
+    // A Delete post also overwrites the post it deletes, keeping its prev so
+    // that backfill gets past it.
+    pub async fn archive(&self, entry: &HistoryEntry, head: &HeadRecord) {
+        let addr = &entry.sigpost.addr;
+        self.user_dht
+            .put(history_key(&self.net, addr, entry.sigpost.post.id), &entry.to_bytes())
+            .await;
+        if let PostKind::Delete(id) = entry.sigpost.post.content {
+            let mut overwrite = entry.clone();
+            overwrite.prev = match self.get_history_entry(addr, id).await {
+                Some(deleted) => deleted.prev,
+                None => id.legacy_prev(),
+            };
+            self.user_dht
+                .put(history_key(&self.net, addr, id), &overwrite.to_bytes())
+                .await;
+        }
+        self.user_dht.put(head_key(&self.net, addr), &head.to_bytes()).await;
+    }
+
+    pub async fn get_head(&self, addr: &Address) -> Option<HeadRecord> {
+        let bytes = self.user_dht.get(head_key(&self.net, addr)).await?;
+        let head = HeadRecord::from_bytes(&bytes).ok()?;
+        if head.addr() != *addr {
+            return None;
+        }
+        crypto_pool::run(move || head.verify().is_ok().then_some(head)).await
+    }
+
+    pub async fn get_mailbox_index(&self, owner: &Address) -> Option<MailboxIndex> {
+        let bytes = self.user_dht.get(mailbox_index_key(&self.net, owner)).await?;
+        let index = MailboxIndex::from_bytes(&bytes).ok()?;
+        if index.addr() != *owner {
+            return None;
+        }
+        crypto_pool::run(move || index.verify().is_ok().then_some(index)).await
+    }
+
+    async fn get_mailbox_epoch(&self, owner: &Address) -> u64 {
+        self.get_mailbox_index(owner)
+            .await
+            .map_or(0, |index| index.epoch)
+    }
+
+    // a chunk found where it belongs; its items are not checked yet
+    async fn get_mailbox_chunk(&self, owner: &Address, epoch: u64, index: u32) -> Option<MailboxChunk> {
+        let bytes = self
+            .user_dht
+            .get(mailbox_chunk_key(&self.net, owner, epoch, index))
+            .await?;
+        let chunk = MailboxChunk::from_bytes(&bytes)?;
+        (chunk.owner == *owner && chunk.epoch == epoch && chunk.index == index).then_some(chunk)
+    }
+
+    // the chunks of an epoch, up to the first missing one
+    async fn get_mailbox_chunks(&self, owner: &Address, epoch: u64) -> Vec<MailboxChunk> {
+        let mut ret = Vec::new();
+        while let Some(chunk) = self
+            .get_mailbox_chunk(owner, epoch, ret.len() as u32)
+            .await
+        {
+            ret.push(chunk);
+        }
+        ret
+    }
+
+    // Appends the item to the last chunk of the owner's mailbox, or starts a
+    // new chunk once that is full. Another sender may append at the same
+    // time, so the chunk is read back and the append retried.
+    pub async fn append_to_mailbox(
+        &self,
+        owner: &Address,
+        item: &MailboxItem,
+    ) -> Result<(), MailboxError> {
+        if !item.is_for(owner) {
+            return Err(MailboxError::NotAddressed);
+        }
+        let epoch = self.get_mailbox_epoch(owner).await;
+        if !MailboxChunk::new(owner, epoch, 0).fits(item) {
+            return Err(MailboxError::TooLarge);
+        }
+        for _ in 0..MAILBOX_APPEND_ATTEMPTS {
+            let mut chunks = self.get_mailbox_chunks(owner, epoch).await;
+            if chunks.iter().any(|chunk| chunk.contains(item)) {
+                return Ok(());
+            }
+            let mut chunk = match chunks.pop() {
+                Some(last) if last.fits(item) => last,
+                Some(last) => MailboxChunk::new(owner, epoch, last.index + 1),
+                None => MailboxChunk::new(owner, epoch, 0),
+            };
+            chunk.items.push(item.clone());
+            let key = mailbox_chunk_key(&self.net, owner, epoch, chunk.index);
+            self.user_dht.put(key, &chunk.to_bytes()).await;
+            let stored = self.get_mailbox_chunk(owner, epoch, chunk.index).await;
+            if stored.is_some_and(|stored| stored.contains(item)) {
+                return Ok(());
+            }
+        }
+        Err(MailboxError::Contended)
+    }
+
+    // The items in the owner's mailbox in the order they arrived. Items with
+    // a bad signature, or not addressed to the owner, are left out.
+    pub async fn read_mailbox(&self, owner: &Address) -> Vec<MailboxItem> {
+        let epoch = self.get_mailbox_epoch(owner).await;
+        let items = self.get_mailbox_chunks(owner, epoch).await;
+        UserDHT::verify_mailbox_items(owner, items.into_iter().flat_map(|c| c.items).collect())
+            .await
+    }
+
+    async fn verify_mailbox_items(owner: &Address, items: Vec<MailboxItem>) -> Vec<MailboxItem> {
+        let owner = owner.clone();
+        crypto_pool::run(move || {
+            let mut ret: Vec<MailboxItem> = Vec::new();
+            for item in items {
+                if item.is_for(&owner) && item.verify().is_ok() && !ret.contains(&item) {
+                    ret.push(item);
+                }
+            }
+            ret
+        })
+        .await
+    }
+
+    // Writes the items kept by the owner into the chunks of the index's epoch,
+    // then moves the index there. Items which arrived in the old epoch
+    // meanwhile are carried over. Returns how many items were kept.
+    pub async fn compact_mailbox(
+        &self,
+        index: &MailboxIndex,
+        keep: impl Fn(&MailboxItem) -> bool,
+    ) -> usize {
+        let owner = index.addr();
+        let old_epoch = self.get_mailbox_epoch(&owner).await;
+        let items: Vec<_> = self
+            .get_mailbox_chunks(&owner, old_epoch)
+            .await
+            .into_iter()
+            .flat_map(|chunk| chunk.items)
+            .collect();
+        let seen = items.len();
+        let items = UserDHT::verify_mailbox_items(&owner, items).await;
+        let kept: Vec<_> = items.into_iter().filter(|item| keep(item)).collect();
+        let count = kept.len();
+        for chunk in MailboxChunk::pack(&owner, index.epoch, kept) {
+            let key = mailbox_chunk_key(&self.net, &owner, index.epoch, chunk.index);
+            self.user_dht.put(key, &chunk.to_bytes()).await;
+        }
+        self.user_dht
+            .put(mailbox_index_key(&self.net, &owner), &index.to_bytes())
+            .await;
+
+        let late: Vec<_> = self
+            .get_mailbox_chunks(&owner, old_epoch)
+            .await
+            .into_iter()
+            .flat_map(|chunk| chunk.items)
+            .skip(seen)
+            .collect();
+        for item in UserDHT::verify_mailbox_items(&owner, late).await.iter() {
+            if self.append_to_mailbox(&owner, item).await.is_ok() {
+                info!("Carried a late mailbox item over");
+            }
+        }
+        count
+    }
+
+    pub async fn put_backup(&self, records: &[BackupRecord]) {
+        for record in records {
+            let key = backup_key(&self.net, &record.addr(), record.index);
+            self.user_dht.put(key, &record.to_bytes()).await;
+        }
+    }
+
+    // Every chunk of the latest backup of addr, in order. None if a chunk is
+    // missing or belongs to another backup, as while it is being replaced.
+    pub async fn get_backup(&self, addr: &Address) -> Option<Vec<BackupRecord>> {
+        let mut ret: Vec<BackupRecord> = Vec::new();
+        loop {
+            let index = ret.len() as u16;
+            let bytes = self.user_dht.get(backup_key(&self.net, addr, index)).await?;
+            let record = BackupRecord::from_bytes(&bytes).ok()?;
+            let fits = record.addr() == *addr
+                && record.index == index
+                && ret.first().is_none_or(|first| {
+                    (first.updated_at, first.total) == (record.updated_at, record.total)
+                });
+            if !fits {
+                return None;
+            }
+            let total = record.total as usize;
+            ret.push(record);
+            if ret.len() == total {
+                break;
+            }
+        }
+        crypto_pool::run(move || ret.iter().all(|r| r.verify().is_ok()).then_some(ret)).await
+    }
+
+    pub async fn get_history_entry(&self, addr: &Address, id: PostId) -> Option<HistoryEntry> {
+        let bytes = self.user_dht.get(history_key(&self.net, addr, id)).await?;
+        let entry = HistoryEntry::from_bytes(&bytes)?;
+        if !entry.is_entry_of(addr, id) {
+            return None;
+        }
+        crypto_pool::run(move || entry.verify().is_ok().then_some(entry)).await
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum AccountStatus {
+    Active(PublicKey),
+    Deleted(AccountTombstone),
+    Migrated(MigrationRecord),
 }
 
 pub struct Publisher {
     node: Arc<Node>,
-    rx: UnboundedReceiver<Vec<u8>>,
+    net: NetworkDescriptor,
+    rx: Mutex<UnboundedReceiver<Vec<u8>>>,
+    authors: Mutex<HashSet<Address>>,
+    // the key of each archiving author and the feed slot its next post takes
+    archiving: Mutex<HashMap<Address, (PublicKey, u8)>>,
+    stats: Arc<StatsRecorder>,
+    // ask the nodes taking posts for delivery receipts, see PublishReport
+    receipts: AtomicBool,
 }
 
 impl Publisher {
-    pub async fn new(addr: Address, rpc: Arc<Mutex<Rpc>>, bootstrap: &[NodeInfo]) -> Publisher {
-        let mut id: Key = addr.into();
-        id.resize(PUBSUB_DHT_KEY_LENGTH);
+    // A single node publishes on behalf of every local author added to it
+    pub async fn new(
+        rpc: Arc<Mutex<Rpc>>,
+        network: &NetworkDescriptor,
+        bootstrap: &[NodeInfo],
+    ) -> Publisher {
         let (tx, rx) = mpsc::unbounded_channel();
-        let node = Node::start(
-            TESTNET_PUBSUB_DHT.to_string(),
-            PUBSUB_DHT_KEY_LENGTH,
-            id,
-            Arc::new(|_| false),
-            rpc,
-            tx,
-            bootstrap,
-        )
-        .await;
+        let (stats, entropy) = {
+            let rpc = rpc.lock().await;
+            (rpc.stats(), rpc.entropy())
+        };
+        let id = network.node_id(None, entropy.as_ref());
+        let store = pubsub_store(network);
+        let node = Node::start_with_store(network, id, store, rpc, tx, bootstrap).await;
 
         Publisher {
             node: Arc::new(node),
-            rx,
+            net: network.clone(),
+            rx: Mutex::new(rx),
+            authors: Mutex::new(HashSet::new()),
+            archiving: Mutex::new(HashMap::new()),
+            stats,
+            receipts: AtomicBool::new(false),
         }
     }
 
-    pub async fn rx(&mut self) -> &mut UnboundedReceiver<Vec<u8>> {
-        &mut self.rx
+    // Only nodes with KadConfig::delivery_receipts sign them; older nodes do
+    // not answer a request for one, so this is off unless asked for.
+    pub fn request_receipts(&self, on: bool) {
+        self.receipts.store(on, Ordering::Relaxed);
+    }
+
+    pub fn receipts_requested(&self) -> bool {
+        self.receipts.load(Ordering::Relaxed)
     }
 
-    pub async fn publish(&self, msg: &[u8], dst: &Address) {
-        let key = Key::from(dst.clone());
-        self.node.multicast(&key, msg).await;
+    pub fn node_info(&self) -> NodeInfo {
+        self.node.node_info().clone()
+    }
+
+    pub async fn add_peers(&self, node_infos: &[NodeInfo]) {
+        self.node.add_peers(node_infos).await;
+    }
+
+    pub async fn sweep_broadcast_tokens(&self) -> usize {
+        self.node.sweep_broadcast_tokens().await
+    }
+
+    pub async fn recv(&self) -> Option<Vec<u8>> {
+        self.rx.lock().await.recv().await
+    }
+
+    pub async fn add_author(&self, addr: Address) {
+        self.authors.lock().await.insert(addr);
+    }
+
+    pub async fn remove_author(&self, addr: &Address) {
+        self.authors.lock().await.remove(addr);
+        self.archiving.lock().await.remove(addr);
+    }
+
+    // From now on the posts of the author are also stored in the pubsub DHT,
+    // the latest FEED_SLOTS of them, for subscribers which were offline when
+    // they were multicast; see Subscriber::fetch_history. Starts after the
+    // newest post found there, so that a restart does not overwrite it.
+    pub async fn archive_posts(&self, pubkey: &PublicKey) {
+        let addr = Address::from(pubkey.clone());
+        let gets = (0..FEED_SLOTS).map(|slot| self.node.get(feed_key(&self.net, &addr, slot)));
+        let newest = futures::future::join_all(gets)
+            .await
+            .into_iter()
+            .enumerate()
+            .filter_map(|(slot, bytes)| {
+                let entry = HistoryEntry::from_bytes(&bytes?)?;
+                (entry.pubkey == *pubkey).then_some((entry.sigpost.post.created_at, slot))
+            })
+            .max();
+        let next = newest.map_or(0, |(_, slot)| (slot as u8 + 1) % FEED_SLOTS);
+        self.archiving.lock().await.insert(addr, (pubkey.clone(), next));
+    }
+
+    pub async fn authors(&self) -> Vec<Address> {
+        self.authors.lock().await.iter().cloned().collect()
+    }
+
+    pub async fn publish(
+        &self,
+        msg: &[u8],
+        author: &Address,
+    ) -> Result<PublishReport, PublishError> {
+        if !self.authors.lock().await.contains(author) {
+            return Err(PublishError::UnknownAuthor);
+        }
+        let report = self.multicast_shards(msg, author).await;
+        self.stats.record_post_sent();
         info!("Hoot multicast");
+        self.archive(msg, author).await;
+        Ok(report)
+    }
+
+    // to every shard region of addr
+    async fn multicast_shards(&self, msg: &[u8], addr: &Address) -> PublishReport {
+        let receipts = self.receipts.load(Ordering::Relaxed);
+        let sends = (0..PUBSUB_SHARDS).map(|shard| {
+            let node = self.node.clone();
+            let prefix = shard_key(addr, shard);
+            async move {
+                let accepted = if receipts {
+                    node.multicast_with_receipts(&prefix, msg).await
+                } else {
+                    let accepted = node.multicast(&prefix, msg).await;
+                    accepted.into_iter().map(|node_info| (node_info, None)).collect()
+                };
+                RegionReport { prefix, accepted }
+            }
+        });
+        PublishReport {
+            regions: futures::future::join_all(sends).await,
+        }
+    }
+
+    // Multicasts a direct message of author to the region of to, where the
+    // recipient listens with Subscriber::subscribe_direct. Never archived.
+    pub async fn send_direct(
+        &self,
+        msg: &[u8],
+        author: &Address,
+        to: &Address,
+    ) -> Result<PublishReport, PublishError> {
+        if !self.authors.lock().await.contains(author) {
+            return Err(PublishError::UnknownAuthor);
+        }
+        let report = self.multicast_shards(msg, to).await;
+        info!("Direct message multicast");
+        Ok(report)
+    }
+
+    async fn archive(&self, msg: &[u8], author: &Address) {
+        let (pubkey, slot) = match self.archiving.lock().await.get_mut(author) {
+            Some((pubkey, next)) => {
+                let slot = *next;
+                *next = (slot + 1) % FEED_SLOTS;
+                (pubkey.clone(), slot)
+            }
+            None => return,
+        };
+        if let Ok(sigpost) = SignedPost::from_bytes(msg) {
+            let entry = HistoryEntry::new(&pubkey, sigpost, None);
+            let key = feed_key(&self.net, author, slot);
+            self.node.put(key, &entry.to_bytes()).await;
+        }
+    }
+}
+
+// Where a multicast went: for each shard region, the nodes which took it
+// first hand, with the receipts of those which signed one. A region which
+// nobody took it in, or whose nodes all refuse receipts while asked for them,
+// is worth a look.
+#[derive(Debug, Clone, Default)]
+pub struct PublishReport {
+    pub regions: Vec<RegionReport>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RegionReport {
+    pub prefix: Key,
+    pub accepted: Vec<(NodeInfo, Option<DeliveryReceipt>)>,
+}
+
+impl PublishReport {
+    pub fn accepted(&self) -> usize {
+        self.regions.iter().map(|region| region.accepted.len()).sum()
+    }
+
+    pub fn receipts(&self) -> Vec<&DeliveryReceipt> {
+        self.regions
+            .iter()
+            .flat_map(|region| region.accepted.iter())
+            .filter_map(|(_, receipt)| receipt.as_ref())
+            .collect()
+    }
+
+    // the regions no node took the multicast in
+    pub fn unreached(&self) -> Vec<&Key> {
+        self.regions
+            .iter()
+            .filter(|region| region.accepted.is_empty())
+            .map(|region| &region.prefix)
+            .collect()
     }
 }
 
+#[derive(Debug, Error)]
+pub enum PublishError {
+    #[error("Author is not registered on this publisher")]
+    UnknownAuthor,
+}
+
+// the node listening for an author, the shard key it joined, and how many
+// handles use it
+type SubscriptionMap = HashMap<Address, (Node, Key, usize)>;
+
 pub struct Subscriber {
     rpc: Arc<Mutex<Rpc>>,
-    nodes: Arc<Mutex<HashMap<Address, Node>>>,
+    net: NetworkDescriptor,
+    nodes: Arc<Mutex<SubscriptionMap>>,
     tx: UnboundedSender<Vec<u8>>,
     broadcast_tx: broadcast::Sender<SignedPost>,
     broadcast_rx: broadcast::Receiver<SignedPost>,
     bootstrap: Vec<NodeInfo>,
+    // its hash with an author's address picks the shard listened on
+    id: Key,
+    // posts it blocks are dropped before any receiver sees them
+    blocked: Arc<StdRwLock<BlockList>>,
 }
 
 impl Subscriber {
-    pub async fn new(rpc: Arc<Mutex<Rpc>>, bootstrap: &[NodeInfo]) -> Subscriber {
-        let (bc_tx, bc_rx) = broadcast::channel(16);
+    pub async fn new(
+        rpc: Arc<Mutex<Rpc>>,
+        network: &NetworkDescriptor,
+        bootstrap: &[NodeInfo],
+    ) -> Subscriber {
+        let (bc_tx, bc_rx) = broadcast::channel(SUBSCRIBER_CHANNEL_CAPACITY);
         let bc_tx2 = bc_tx.clone();
 
         let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+        let (stats, entropy) = {
+            let rpc = rpc.lock().await;
+            (rpc.stats(), rpc.entropy())
+        };
+        let blocked = Arc::new(StdRwLock::new(BlockList::new()));
+        let blocked2 = blocked.clone();
 
         tokio::spawn(async move {
             while let Some(msg) = rx.recv().await {
                 if let Ok(post) = SignedPost::from_bytes(&msg) {
+                    stats.record_post_received();
+                    if blocked2.read().unwrap().blocks(&post) {
+                        continue;
+                    }
                     bc_tx2.send(post).unwrap();
                 }
             }
@@ -137,41 +953,513 @@ impl Subscriber {
 
         Subscriber {
             rpc,
+            net: network.clone(),
             nodes: Arc::new(Mutex::new(HashMap::new())),
             tx,
             broadcast_tx: bc_tx,
             broadcast_rx: bc_rx,
             bootstrap: bootstrap.to_vec(),
+            id: network.node_id(None, entropy.as_ref()),
+            blocked,
         }
     }
 
-    pub async fn subscribe(&self, addr: Address) {
-        let mut id = Key::from(addr.clone());
-        id.resize_with_random(PUBSUB_DHT_KEY_LENGTH);
+    // replaces the block list, as when another account takes over
+    pub fn set_block_list(&self, blocked: BlockList) {
+        *self.blocked.write().unwrap() = blocked;
+    }
+
+    pub fn block_list(&self) -> BlockList {
+        self.blocked.read().unwrap().clone()
+    }
+
+    // a random one unless set, which moves every subscription to another shard
+    // with each run; set it before subscribing to anything
+    pub fn set_id(&mut self, id: Key) {
+        self.id = id;
+    }
+
+    pub fn shard_of(&self, addr: &Address) -> u8 {
+        let addr_bytes: [u8; 32] = addr.clone().into();
+        let h = Key::hash(&[self.id.as_bytes(), &addr_bytes[..]].concat(), 1);
+        h.as_bytes()[0] % PUBSUB_SHARDS
+    }
+
+    // Listens for posts of addr until every handle for it is dropped or closed.
+    pub async fn subscribe(&self, addr: Address) -> SubscriptionHandle {
+        self.listen(addr, false).await
+    }
+
+    // Listens for the direct messages to owner, which arrive where the posts
+    // of owner do; the two kinds of handle share the node.
+    pub async fn subscribe_direct(&self, owner: Address) -> SubscriptionHandle {
+        self.listen(owner, true).await
+    }
+
+    async fn listen(&self, addr: Address, direct: bool) -> SubscriptionHandle {
         let mut nodes = self.nodes.lock().await;
-        if !nodes.contains_key(&addr) {
-            nodes.insert(
-                addr,
-                Node::start(
-                    TESTNET_PUBSUB_DHT.to_string(),
-                    PUBSUB_DHT_KEY_LENGTH,
+        match nodes.get_mut(&addr) {
+            Some((_, _, count)) => *count += 1,
+            None => {
+                let prefix = shard_key(&addr, self.shard_of(&addr));
+                let entropy = self.rpc.lock().await.entropy();
+                let id = self.net.node_id(Some(&prefix), entropy.as_ref());
+                let node = Node::start_with_store(
+                    &self.net,
                     id,
-                    Arc::new(|_| false),
+                    pubsub_store(&self.net),
                     self.rpc.clone(),
                     self.tx.clone(),
                     &self.bootstrap,
                 )
-                .await,
-            );
+                .await;
+                tokio::spawn(keep_joined(
+                    self.nodes.clone(),
+                    addr.clone(),
+                    node.clone(),
+                    prefix.clone(),
+                ));
+                nodes.insert(addr.clone(), (node, prefix, 1));
+            }
+        }
+        SubscriptionHandle {
+            addr,
+            nodes: self.nodes.clone(),
+            rx: self.broadcast_tx.subscribe(),
+            direct,
+            closed: false,
         }
     }
 
+    pub async fn is_subscribed(&self, addr: &Address) -> bool {
+        self.nodes.lock().await.contains_key(addr)
+    }
+
+    // every subscription node relays the multicasts of its author
+    pub async fn sweep_broadcast_tokens(&self) -> usize {
+        let nodes: Vec<Node> = self
+            .nodes
+            .lock()
+            .await
+            .values()
+            .map(|(node, _, _)| node.clone())
+            .collect();
+        let mut swept = 0;
+        for node in nodes.iter() {
+            swept += node.sweep_broadcast_tokens().await;
+        }
+        swept
+    }
+
+    // The archived posts of addr created after since, oldest first, to catch
+    // up on what was multicast while this node was offline. Empty unless the
+    // author archives its posts, or while nothing is subscribed, since the
+    // lookups go through a subscription node.
+    pub async fn fetch_history(&self, addr: &Address, since: u64) -> Vec<SignedPost> {
+        let node = match self.nodes.lock().await.values().next() {
+            Some((node, _, _)) => node.clone(),
+            None => return Vec::new(),
+        };
+        let gets = (0..FEED_SLOTS).map(|slot| node.get(feed_key(&self.net, addr, slot)));
+        let entries: Vec<HistoryEntry> = futures::future::join_all(gets)
+            .await
+            .into_iter()
+            .filter_map(|bytes| HistoryEntry::from_bytes(&bytes?))
+            .filter(|entry| {
+                let created_at = entry.sigpost.post.created_at;
+                Address::from(entry.pubkey.clone()) == *addr && created_at > since
+            })
+            .collect();
+        let blocked = self.block_list();
+        let mut posts: Vec<SignedPost> = crypto_pool::run(move || {
+            entries
+                .into_iter()
+                .filter(|entry| !blocked.blocks(&entry.sigpost) && entry.verify().is_ok())
+                .map(|entry| entry.sigpost)
+                .collect()
+        })
+        .await;
+        posts.sort_by_key(|sigpost| sigpost.post.id);
+        posts
+    }
+
     pub fn get_receiver(&self) -> broadcast::Receiver<SignedPost> {
         self.broadcast_tx.subscribe()
     }
 
-    pub async fn stop_subscription(&self, addr: &Address) {
-        let mut nodes = self.nodes.lock().await;
-        nodes.remove(addr);
+    pub fn get_filtered_receiver(&self, filter: Filter) -> UnboundedReceiver<SignedPost> {
+        let mut bc_rx = self.broadcast_tx.subscribe();
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            loop {
+                match bc_rx.recv().await {
+                    Ok(post) => {
+                        if filter.matches(&post) && tx.send(post).is_err() {
+                            break;
+                        }
+                    }
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+
+        rx
+    }
+
+    pub async fn get_new_message(&mut self) -> Vec<SignedPost> {
+        let mut ret = Vec::new();
+        loop {
+            match self.broadcast_rx.try_recv() {
+                Ok(post) => ret.push(post),
+                Err(TryRecvError::Lagged(_)) => continue,
+                Err(_) => break,
+            }
+        }
+        ret
+    }
+
+}
+
+// A subscription to one author, returned by Subscriber::subscribe. Dropping
+// it (or close) unsubscribes, once no other handle for the author is left.
+#[must_use = "dropping the handle unsubscribes"]
+pub struct SubscriptionHandle {
+    addr: Address,
+    nodes: Arc<Mutex<SubscriptionMap>>,
+    rx: broadcast::Receiver<SignedPost>,
+    // gives the direct messages to addr instead of the posts of addr
+    direct: bool,
+    closed: bool,
+}
+
+impl SubscriptionHandle {
+    pub fn addr(&self) -> &Address {
+        &self.addr
+    }
+
+    fn accepts(&self, post: &SignedPost) -> bool {
+        match post.direct_to() {
+            Some(to) => self.direct && *to == self.addr,
+            None => !self.direct && post.addr == self.addr,
+        }
+    }
+
+    // the next post of this author; posts missed while lagging are skipped
+    pub async fn recv(&mut self) -> Option<SignedPost> {
+        loop {
+            match self.rx.recv().await {
+                Ok(post) if self.accepts(&post) => return Some(post),
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Option<SignedPost> {
+        loop {
+            match self.rx.try_recv() {
+                Ok(post) if self.accepts(&post) => return Some(post),
+                Ok(_) | Err(TryRecvError::Lagged(_)) => continue,
+                Err(_) => return None,
+            }
+        }
+    }
+
+    pub async fn close(mut self) {
+        self.closed = true;
+        release(&self.nodes, &self.addr).await;
+    }
+}
+
+impl Drop for SubscriptionHandle {
+    fn drop(&mut self) {
+        if self.closed {
+            return;
+        }
+        // without a runtime, the nodes are gone already
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let nodes = self.nodes.clone();
+            let addr = self.addr.clone();
+            runtime.spawn(async move { release(&nodes, &addr).await });
+        }
+    }
+}
+
+async fn release(nodes: &Mutex<SubscriptionMap>, addr: &Address) {
+    let mut nodes = nodes.lock().await;
+    if let Some((_, _, count)) = nodes.get_mut(addr) {
+        *count -= 1;
+        if *count == 0 {
+            if let Some((node, prefix, _)) = nodes.remove(addr) {
+                tokio::spawn(async move { node.leave(&prefix).await });
+            }
+        }
+    }
+}
+
+// Joins the shard region of the node, and again every JOIN_INTERVAL until it
+// stops listening for addr
+async fn keep_joined(nodes: Arc<Mutex<SubscriptionMap>>, addr: Address, node: Node, prefix: Key) {
+    loop {
+        node.join(&prefix).await;
+        tokio::time::sleep(Duration::from_millis(JOIN_INTERVAL)).await;
+        match nodes.lock().await.get(&addr) {
+            Some((current, _, _)) if current.node_info() == node.node_info() => (),
+            _ => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::SecretKey;
+    use crate::service::{test_user, UserHandle};
+    use crate::user::backup::{seal_backup, BackupContents};
+    use crate::user::history::HEAD_RECORD_LEN;
+    use crate::user::user::UserAttribute;
+    use std::collections::HashSet;
+
+    #[tokio::test]
+    async fn subscription_handle_test() {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let rpc = Arc::new(Mutex::new(Rpc::new(socket)));
+        let network = pubsub_network(TESTNET_PUBSUB_DHT, PUBSUB_DHT_KEY_LENGTH);
+        let subscriber = Subscriber::new(rpc, &network, &[]).await;
+        let addr = Address::new([3; 32]);
+
+        let first = subscriber.subscribe(addr.clone()).await;
+        let second = subscriber.subscribe(addr.clone()).await;
+        first.close().await;
+        assert!(subscriber.is_subscribed(&addr).await);
+
+        // the last handle unsubscribes when dropped
+        drop(second);
+        for _ in 0..50 {
+            if !subscriber.is_subscribed(&addr).await {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(!subscriber.is_subscribed(&addr).await);
+    }
+
+    #[tokio::test]
+    async fn direct_handle_test() {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let rpc = Arc::new(Mutex::new(Rpc::new(socket)));
+        let network = pubsub_network(TESTNET_PUBSUB_DHT, PUBSUB_DHT_KEY_LENGTH);
+        let subscriber = Subscriber::new(rpc, &network, &[]).await;
+        let (mut alice, bob) = (test_user("alice"), test_user("bob"));
+
+        let mut posts = subscriber.subscribe(alice.addr()).await;
+        let mut inbox = subscriber.subscribe_direct(bob.addr()).await;
+        let dm = alice.direct_message(&bob.pubkey().unwrap(), "hi bob").unwrap();
+        assert!(alice.posts.is_empty());
+        let hoot = alice.hoot("hi all".to_string(), None, None, vec![]).unwrap();
+        subscriber.broadcast_tx.send(dm.clone()).unwrap();
+        subscriber.broadcast_tx.send(hoot.clone()).unwrap();
+
+        // the message goes to bob's inbox only, not among alice's posts
+        assert_eq!(posts.try_recv(), Some(hoot));
+        assert_eq!(posts.try_recv(), None);
+        assert_eq!(inbox.try_recv(), Some(dm.clone()));
+        assert_eq!(inbox.try_recv(), None);
+        assert_eq!(bob.open_direct(&dm, &alice.pubkey().unwrap()).unwrap(), "hi bob");
+        assert_eq!(alice.open_direct(&dm, &bob.pubkey().unwrap()).unwrap(), "hi bob");
+        // nobody else can pass for the sender
+        let carol = SecretKey::random().public_key();
+        assert!(bob.open_direct(&dm, &carol).is_err());
+    }
+
+    #[tokio::test]
+    async fn block_list_test() {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let rpc = Arc::new(Mutex::new(Rpc::new(socket)));
+        let network = pubsub_network(TESTNET_PUBSUB_DHT, PUBSUB_DHT_KEY_LENGTH);
+        let subscriber = Subscriber::new(rpc, &network, &[]).await;
+        let (mut troll, mut friend) = (test_user("troll"), test_user("friend"));
+        let mut blocked = BlockList::new();
+        blocked.block(troll.addr());
+        subscriber.set_block_list(blocked);
+
+        let mut rx = subscriber.get_receiver();
+        let boo = troll.hoot("boo".to_string(), None, None, vec![]).unwrap();
+        let rehoot = friend.rehoot(boo.clone(), &troll.pubkey().unwrap()).unwrap();
+        let hi = friend.hoot("hi".to_string(), None, None, vec![]).unwrap();
+        for sigpost in [&boo, &rehoot, &hi] {
+            subscriber.tx.send(serde_json::to_vec(sigpost).unwrap()).unwrap();
+        }
+        // only the post which carries nothing of the troll arrives
+        assert_eq!(rx.recv().await.unwrap(), hi);
+
+        subscriber.set_block_list(BlockList::new());
+        subscriber.tx.send(serde_json::to_vec(&boo).unwrap()).unwrap();
+        assert_eq!(rx.recv().await.unwrap(), boo);
+    }
+
+    #[test]
+    fn shard_key_test() {
+        let addr = Address::new([3; 32]);
+        assert_eq!(shard_key(&addr, 0), Key::from([3u8; 32]));
+
+        let keys: HashSet<_> = (0..PUBSUB_SHARDS).map(|i| shard_key(&addr, i)).collect();
+        assert_eq!(keys.len(), PUBSUB_SHARDS as usize);
+        assert!(keys.iter().all(|k| k.len() == 32));
+    }
+
+    #[tokio::test]
+    async fn shard_of_test() {
+        let network = pubsub_network(TESTNET_PUBSUB_DHT, PUBSUB_DHT_KEY_LENGTH);
+        let id = Key::random(32);
+        let mut subscribers = Vec::new();
+        for _ in 0..2 {
+            let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let rpc = Arc::new(Mutex::new(Rpc::new(socket)));
+            let mut subscriber = Subscriber::new(rpc, &network, &[]).await;
+            subscriber.set_id(id.clone());
+            subscribers.push(subscriber);
+        }
+
+        // the same id, as after a restart, listens on the same shards
+        for i in 0..16 {
+            let addr = Address::new([i; 32]);
+            assert_eq!(subscribers[0].shard_of(&addr), subscribers[1].shard_of(&addr));
+        }
+    }
+
+    #[test]
+    fn may_store_test() {
+        let register = |sk: &SecretKey, at: u64| {
+            let message = RegistrationRecord::signed_message(&sk.public_key().into(), at);
+            RegistrationRecord::new(&sk.public_key(), at, sk.sign(&message)).to_bytes()
+        };
+        let (owner, squatter) = (SecretKey::random(), SecretKey::random());
+        let net = user_network(TESTNET_USER_DHT, USER_DHT_KEY_LENGTH);
+        let key = account_key(&net, &Address::from(owner.public_key()));
+        let first = register(&owner, 1000);
+        assert!(RegistrationRecord::from_bytes(&first).unwrap().verify().is_ok());
+
+        // only under the key of its own address
+        assert!(UserDHT::may_store(&net, &key, None, &first));
+        assert!(!UserDHT::may_store(&net, &key, None, &register(&squatter, 1000)));
+
+        // the same key may refresh its registration, nobody else may replace it
+        assert!(UserDHT::may_store(&net, &key, Some(&first), &register(&owner, 2000)));
+        assert!(!UserDHT::may_store(&net, &key, Some(&first), &register(&squatter, 2000)));
+        // but not with an older one, nor with the unsigned pair it superseded
+        assert!(UserDHT::may_store(&net, &key, Some(&first), &first));
+        assert!(!UserDHT::may_store(&net, &key, Some(&first), &register(&owner, 999)));
+        let addr_bytes: [u8; 32] = Address::from(owner.public_key()).into();
+        let pair = [addr_bytes, owner.public_key().to_bytes()].concat();
+        assert!(UserDHT::may_store(&net, &key, Some(&pair), &first));
+        assert!(!UserDHT::may_store(&net, &key, Some(&first), &pair));
+        let head = [0u8; HEAD_RECORD_LEN];
+        assert!(!UserDHT::may_store(&net, &key, Some(&first), &head));
+
+        // a tombstone closes the account for good
+        let addr = Address::from(owner.public_key());
+        let sig = owner.sign(&AccountTombstone::signed_message(&addr, 3000));
+        let tombstone = AccountTombstone::new(&owner.public_key(), 3000, sig).to_bytes();
+        assert!(UserDHT::may_store(&net, &key, Some(&first), &tombstone));
+        assert!(!UserDHT::may_store(&net, &key, Some(&tombstone), &register(&owner, 4000)));
+
+        // mailbox chunks stay where they belong, indexes only move forward
+        let chunk = MailboxChunk::new(&addr, 0, 0).to_bytes();
+        assert!(UserDHT::may_store(&net, &mailbox_chunk_key(&net, &addr, 0, 0), None, &chunk));
+        assert!(!UserDHT::may_store(&net, &mailbox_chunk_key(&net, &addr, 0, 1), None, &chunk));
+        let index = |epoch: u64| {
+            let sig = owner.sign(&MailboxIndex::signed_message(&addr, epoch, 1000));
+            MailboxIndex::new(&owner.public_key(), epoch, 1000, sig).to_bytes()
+        };
+        let index_key = mailbox_index_key(&net, &addr);
+        assert!(UserDHT::may_store(&net, &index_key, Some(&index(1)), &index(2)));
+        assert!(!UserDHT::may_store(&net, &index_key, Some(&index(2)), &index(1)));
+
+        // backups are only replaced by newer ones of the same account
+        let contents = BackupContents::default();
+        let backup = |sk: &SecretKey, at| seal_backup(sk, &contents, at).unwrap()[0].to_bytes();
+        let (old, new) = (backup(&owner, 1000), backup(&owner, 2000));
+        let key = backup_key(&net, &addr, 0);
+        assert!(UserDHT::is_storable(&old));
+        assert!(UserDHT::may_store(&net, &key, Some(&old), &new));
+        assert!(!UserDHT::may_store(&net, &key, Some(&new), &old));
+        assert!(!UserDHT::may_store(&net, &key, None, &backup(&squatter, 1000)));
+
+        // profiles only under their address, replaced by newer ones of the same key
+        let profile = |sk: &SecretKey, created_at| {
+            let attr = UserAttribute::new("alice", created_at, "");
+            let sig = sk.sign(&attr.signed_message());
+            let profile = SignedUserAttribute::new(sk.public_key().into(), attr, sig);
+            ProfileRecord::new(&sk.public_key(), &profile).to_bytes()
+        };
+        let (old, new) = (profile(&owner, 1000), profile(&owner, 2000));
+        let key = profile_key(&net, &addr);
+        assert!(UserDHT::is_storable(&old));
+        assert!(UserDHT::may_store(&net, &key, Some(&old), &new));
+        assert!(!UserDHT::may_store(&net, &key, Some(&new), &old));
+        assert!(!UserDHT::may_store(&net, &key, None, &profile(&squatter, 1000)));
+
+        // heads only under their address, replaced by newer ones of the same key
+        let head = |sk: &SecretKey, updated_at| {
+            let addr = Address::from(sk.public_key());
+            let sig = sk.sign(&HeadRecord::signed_message(&addr, PostId(42), updated_at));
+            HeadRecord::new(&sk.public_key(), PostId(42), updated_at, sig).to_bytes()
+        };
+        let (old, new) = (head(&owner, 1000), head(&owner, 2000));
+        let key = head_key(&net, &addr);
+        let squatter_addr = Address::from(squatter.public_key());
+        assert!(UserDHT::is_storable(&old));
+        assert!(UserDHT::may_store(&net, &key, None, &old));
+        assert!(UserDHT::may_store(&net, &key, Some(&old), &new));
+        assert!(UserDHT::may_store(&net, &key, Some(&new), &new));
+        assert!(!UserDHT::may_store(&net, &key, Some(&new), &old));
+        assert!(!UserDHT::may_store(&net, &key, None, &head(&squatter, 1000)));
+        assert!(!UserDHT::may_store(&net, &head_key(&net, &squatter_addr), None, &old));
+        assert!(!UserDHT::may_store(&net, &key, Some(&old), &head(&squatter, 3000)));
+
+        // posts only under their own id, or that of the post a Delete deletes
+        let (mut alice, mut mallory) = (test_user("alice"), test_user("mallory"));
+        let hoot = alice.hoot("hi".to_string(), None, None, vec![]).unwrap();
+        let id = hoot.post.id;
+        let hoot = alice.history_entry(&hoot).unwrap().to_bytes();
+        let key = history_key(&net, &alice.addr(), id);
+        let elsewhere = history_key(&net, &alice.addr(), PostId(1));
+        assert!(UserDHT::may_store(&net, &key, None, &hoot));
+        assert!(!UserDHT::may_store(&net, &elsewhere, None, &hoot));
+        let delete = alice.create_post(PostKind::Delete(id)).unwrap();
+        let delete = alice.history_entry(&delete).unwrap().to_bytes();
+        assert!(UserDHT::may_store(&net, &key, Some(&hoot), &delete));
+        assert!(!UserDHT::may_store(&net, &key, Some(&delete), &hoot));
+        let forged = mallory.create_post(PostKind::Delete(id)).unwrap();
+        let forged = mallory.history_entry(&forged).unwrap().to_bytes();
+        assert!(!UserDHT::may_store(&net, &key, Some(&hoot), &forged));
+        assert!(!UserDHT::may_store(&net, &key, None, &forged));
+    }
+
+    #[test]
+    fn may_archive_test() {
+        let entry = |handle: &mut UserHandle, created_at| {
+            let sk = handle.signing_key.clone().unwrap();
+            let mut sigpost = handle.hoot("hi".to_string(), None, None, vec![]).unwrap();
+            sigpost.post.created_at = created_at;
+            sigpost.signature = sk.sign(&sigpost.post.signed_message());
+            HistoryEntry::new(&sk.public_key(), sigpost, None).to_bytes()
+        };
+        let (mut owner, mut other) = (test_user("alice"), test_user("alice"));
+        let net = pubsub_network(TESTNET_PUBSUB_DHT, PUBSUB_DHT_KEY_LENGTH);
+        let addr = owner.addr();
+        let (old, new) = (entry(&mut owner, 1000), entry(&mut owner, 2000));
+        assert!((net.validator())(&old));
+
+        // only in the feed slots of its author, and only replaced by later posts
+        let key = feed_key(&net, &addr, FEED_SLOTS - 1);
+        assert!(may_archive(&net, &key, None, &old));
+        assert!(!may_archive(&net, &feed_key(&net, &addr, FEED_SLOTS), None, &old));
+        assert!(may_archive(&net, &key, Some(&old), &new));
+        assert!(!may_archive(&net, &key, Some(&new), &old));
+        assert!(!may_archive(&net, &key, Some(&old), &entry(&mut other, 3000)));
     }
 }
@@ -0,0 +1,230 @@
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::broadcast::{self, Receiver, Sender};
+
+use crate::user::post::{PostKind, SignedPost};
+use crate::user::user::Address;
+
+use super::{Ingestor, NOTIFICATION_CHANNEL_CAPACITY};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NotificationKind {
+    Mention,
+    // a reply to a post of the account; it may mention the account too
+    Reply,
+    // a rehoot of a post of the account, to the followers of the rehooter
+    ReHoot,
+}
+
+// A post of someone else which mentions, replies to or rehoots a local account
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Notification {
+    pub to: Address,
+    pub kind: NotificationKind,
+    pub sigpost: SignedPost,
+}
+
+// what sigpost notifies, and whom, among the addresses watched says yes to
+pub fn notifications_for(
+    sigpost: &SignedPost,
+    watched: impl Fn(&Address) -> bool,
+) -> Vec<Notification> {
+    let addressed: Vec<(&Address, NotificationKind)> = match &sigpost.post.content {
+        PostKind::Hoot(hoot) => {
+            let replied = hoot.reply_to.iter().map(|to| (&to.addr, NotificationKind::Reply));
+            let mentioned = hoot.mention_to.iter().map(|addr| (addr, NotificationKind::Mention));
+            replied.chain(mentioned).collect()
+        }
+        PostKind::ReHoot(inner) => vec![(&inner.addr, NotificationKind::ReHoot)],
+        PostKind::ReHootRef(original) => vec![(&original.addr, NotificationKind::ReHoot)],
+        _ => return Vec::new(),
+    };
+    let mut ret: Vec<Notification> = Vec::new();
+    for (addr, kind) in addressed {
+        if *addr == sigpost.addr || !watched(addr) || ret.iter().any(|n| n.to == *addr) {
+            continue;
+        }
+        ret.push(Notification {
+            to: addr.clone(),
+            kind,
+            sigpost: sigpost.clone(),
+        });
+    }
+    ret
+}
+
+type Watched = Arc<Mutex<HashMap<Address, usize>>>;
+
+// Tells local accounts about the posts which mention, reply to or rehoot them, out of
+// the ones the subscriber receives; the others arrive in the mailbox. Posts
+// are verified before anyone is notified of them.
+pub struct Notifier {
+    // how many handles watch each address
+    watched: Watched,
+    tx: Sender<Notification>,
+}
+
+impl Notifier {
+    pub fn new() -> Notifier {
+        let (tx, _) = broadcast::channel(NOTIFICATION_CHANNEL_CAPACITY);
+        Notifier {
+            watched: Arc::new(Mutex::new(HashMap::new())),
+            tx,
+        }
+    }
+
+    // Notifications for addr, until the handle is dropped
+    pub fn watch(&self, addr: Address) -> NotificationHandle {
+        *self.watched.lock().unwrap().entry(addr.clone()).or_insert(0) += 1;
+        NotificationHandle {
+            addr,
+            watched: self.watched.clone(),
+            rx: self.tx.subscribe(),
+        }
+    }
+
+    pub fn is_watched(&self, addr: &Address) -> bool {
+        self.watched.lock().unwrap().contains_key(addr)
+    }
+
+    // the notifications of every watched address
+    pub fn subscribe(&self) -> Receiver<Notification> {
+        self.tx.subscribe()
+    }
+
+    // Sends what a verified post notifies of; returns how many
+    pub fn notify(&self, sigpost: &SignedPost) -> usize {
+        let notifications = notifications_for(sigpost, |addr| self.is_watched(addr));
+        let n = notifications.len();
+        for notification in notifications {
+            // nobody may be receiving right now
+            let _ = self.tx.send(notification);
+        }
+        n
+    }
+
+    // Notifies of the posts coming from posts, like the ones of a Subscriber.
+    // Only the posts notifying someone are verified, with ingestor.
+    pub fn listen(self: Arc<Self>, mut posts: Receiver<SignedPost>, ingestor: Ingestor) {
+        tokio::spawn(async move {
+            loop {
+                let sigpost = match posts.recv().await {
+                    Ok(sigpost) => sigpost,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                };
+                if notifications_for(&sigpost, |addr| self.is_watched(addr)).is_empty() {
+                    continue;
+                }
+                match ingestor.ingest_decoded(sigpost).await {
+                    Ok(verified) => {
+                        self.notify(&verified.sigpost);
+                    }
+                    Err(e) => debug!("No notification for a post: {}", e),
+                }
+            }
+        });
+    }
+}
+
+impl Default for Notifier {
+    fn default() -> Notifier {
+        Notifier::new()
+    }
+}
+
+// The notifications of one address, returned by Notifier::watch. Dropping it
+// stops watching, once no other handle watches the address.
+pub struct NotificationHandle {
+    addr: Address,
+    watched: Watched,
+    rx: Receiver<Notification>,
+}
+
+impl NotificationHandle {
+    pub fn addr(&self) -> &Address {
+        &self.addr
+    }
+
+    // None once the notifier is gone
+    pub async fn recv(&mut self) -> Option<Notification> {
+        loop {
+            match self.rx.recv().await {
+                Ok(notification) if notification.to == self.addr => return Some(notification),
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    // the notifications received since the last call; ones missed while
+    // lagging are skipped
+    pub fn new_notifications(&mut self) -> Vec<Notification> {
+        let mut ret = Vec::new();
+        loop {
+            match self.rx.try_recv() {
+                Ok(notification) if notification.to == self.addr => ret.push(notification),
+                Ok(_) | Err(TryRecvError::Lagged(_)) => continue,
+                Err(_) => break,
+            }
+        }
+        ret
+    }
+}
+
+impl Drop for NotificationHandle {
+    fn drop(&mut self) {
+        let mut watched = self.watched.lock().unwrap();
+        if let Some(n) = watched.get_mut(&self.addr) {
+            *n -= 1;
+            if *n == 0 {
+                watched.remove(&self.addr);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::test_user;
+
+    #[test]
+    fn notifier_test() {
+        let (mut alice, mut bob, mut carol) =
+            (test_user("alice"), test_user("bob"), test_user("carol"));
+        let notifier = Notifier::new();
+        let mut notifications = notifier.watch(alice.addr());
+        let mut other = notifier.watch(carol.addr());
+
+        // a reply which mentions alice as well notifies her once, as a reply
+        let first = alice.hoot("hi".to_string(), None, None, vec![bob.addr()]).unwrap();
+        assert_eq!(notifier.notify(&first), 0);
+        let mentions = vec![alice.addr(), carol.addr()];
+        let reply = bob.hoot("hey".to_string(), None, Some(first), mentions).unwrap();
+        assert_eq!(notifier.notify(&reply), 2);
+        let received = notifications.new_notifications();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].kind, NotificationKind::Reply);
+        assert_eq!(received[0].sigpost, reply);
+        assert_eq!(other.new_notifications()[0].kind, NotificationKind::Mention);
+
+        // nobody watches alice once her handle is gone
+        let self_mention = alice.hoot("me".to_string(), None, None, vec![alice.addr()]).unwrap();
+        assert_eq!(notifier.notify(&self_mention), 0);
+        drop(notifications);
+        assert!(!notifier.is_watched(&alice.addr()));
+        assert!(notifier.is_watched(&carol.addr()));
+
+        // a rehoot tells carol, and her own rehoot of it tells nobody
+        let carol_post = carol.hoot("news".to_string(), None, None, vec![]).unwrap();
+        let rehoot = bob.rehoot(carol_post.clone(), &carol.pubkey().unwrap()).unwrap();
+        assert_eq!(notifier.notify(&rehoot), 1);
+        assert_eq!(other.new_notifications()[0].kind, NotificationKind::ReHoot);
+        let own = carol.rehoot(carol_post, &carol.pubkey().unwrap()).unwrap();
+        assert_eq!(notifier.notify(&own), 0);
+    }
+}
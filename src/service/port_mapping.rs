@@ -0,0 +1,486 @@
+use log::{info, warn};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout, Duration};
+
+use super::{
+    NATPMP_PORT, PORT_MAPPING_LIFETIME, PORT_MAPPING_RETRY, SSDP_GROUP, SSDP_PORT, SSDP_WAIT,
+};
+
+const NATPMP_VERSION: u8 = 0;
+const NATPMP_OP_ADDRESS: u8 = 0;
+const NATPMP_OP_MAP_UDP: u8 = 1;
+const WAN_SERVICES: [&str; 2] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+// How a port was mapped on the gateway
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Gateway {
+    // NAT-PMP (RFC 6886) at this address
+    NatPmp(Ipv4Addr),
+    // a UPnP IGD, with the control URL of its WAN connection service
+    Upnp {
+        addr: SocketAddr,
+        control_path: String,
+        service: String,
+        local_ip: Ipv4Addr,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortMapping {
+    pub gateway: Gateway,
+    pub internal_port: u16,
+    // where peers outside the NAT reach the port
+    pub external: SocketAddr,
+    // seconds the gateway keeps the mapping; renewed at half of it
+    pub lifetime: u32,
+}
+
+// Maps the UDP ports of the DHT sockets on the home router, so that peers
+// can reach a node behind it without any router configuration. The same
+// external port as the local one is asked for, so that the address peers see
+// on our messages is the one they can answer to. NAT-PMP is tried first,
+// then UPnP.
+#[derive(Clone, Default)]
+pub struct PortMapper {
+    mappings: Arc<Mutex<Vec<PortMapping>>>,
+    renewals: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl PortMapper {
+    pub fn new() -> PortMapper {
+        PortMapper::default()
+    }
+
+    // Maps every port and keeps renewing the mappings until remove_all.
+    // A port which cannot be mapped is only logged.
+    pub async fn start(&self, ports: &[u16]) {
+        for port in ports {
+            match map_port(*port).await {
+                Ok(mapping) => {
+                    info!("Mapped UDP port {} to {}", port, mapping.external);
+                    self.mappings.lock().await.push(mapping.clone());
+                    let mapper = self.clone();
+                    let renewal = tokio::spawn(async move { mapper.renew(mapping).await });
+                    self.renewals.lock().await.push(renewal);
+                }
+                Err(e) => warn!("Cannot map UDP port {}: {}", port, e),
+            }
+        }
+    }
+
+    pub async fn mappings(&self) -> Vec<PortMapping> {
+        self.mappings.lock().await.clone()
+    }
+
+    async fn renew(&self, mut mapping: PortMapping) {
+        loop {
+            // a gateway may grant less than asked for, even nothing
+            sleep(Duration::from_secs(mapping.lifetime.max(120) as u64 / 2)).await;
+            loop {
+                match request_mapping(&mapping.gateway, mapping.internal_port).await {
+                    Ok(renewed) => {
+                        let mut mappings = self.mappings.lock().await;
+                        mappings.retain(|m| m.internal_port != mapping.internal_port);
+                        mappings.push(renewed.clone());
+                        mapping = renewed;
+                        break;
+                    }
+                    Err(e) => {
+                        warn!("Cannot renew the mapping of {}: {}", mapping.internal_port, e);
+                        sleep(Duration::from_millis(PORT_MAPPING_RETRY)).await;
+                    }
+                }
+            }
+        }
+    }
+
+    // Stops renewing, and asks the gateways to drop every mapping
+    pub async fn remove_all(&self) {
+        for renewal in self.renewals.lock().await.drain(..) {
+            renewal.abort();
+        }
+        for mapping in self.mappings.lock().await.drain(..) {
+            if let Err(e) = remove_mapping(&mapping).await {
+                warn!("Cannot remove the mapping of {}: {}", mapping.internal_port, e);
+            }
+        }
+    }
+}
+
+async fn map_port(port: u16) -> Result<PortMapping, PortMappingError> {
+    let natpmp = match default_gateway().await {
+        Some(gateway) => request_mapping(&Gateway::NatPmp(gateway), port).await,
+        None => Err(PortMappingError::NoGateway),
+    };
+    let mapping = match natpmp {
+        Ok(mapping) => mapping,
+        Err(e) => {
+            info!("NAT-PMP is not available ({}), trying UPnP", e);
+            request_mapping(&discover_upnp().await?, port).await?
+        }
+    };
+    // behind a second NAT, like a carrier's, nobody outside reaches the mapping
+    if !is_public(&mapping.external.ip()) {
+        let _ = remove_mapping(&mapping).await;
+        return Err(PortMappingError::NotPublic(mapping.external.ip()));
+    }
+    Ok(mapping)
+}
+
+async fn request_mapping(gateway: &Gateway, port: u16) -> Result<PortMapping, PortMappingError> {
+    match gateway {
+        Gateway::NatPmp(addr) => {
+            let reply = natpmp_request(*addr, &natpmp_map_request(port, PORT_MAPPING_LIFETIME))
+                .await?;
+            let (internal_port, external_port, lifetime) = parse_natpmp_map(&reply)?;
+            if internal_port != port {
+                return Err(PortMappingError::Malformed);
+            }
+            let reply = natpmp_request(*addr, &[NATPMP_VERSION, NATPMP_OP_ADDRESS]).await?;
+            let ip = parse_natpmp_address(&reply)?;
+            Ok(PortMapping {
+                gateway: gateway.clone(),
+                internal_port,
+                external: SocketAddr::from((ip, external_port)),
+                lifetime,
+            })
+        }
+        Gateway::Upnp { local_ip, .. } => {
+            let args = format!(
+                "<NewRemoteHost></NewRemoteHost><NewExternalPort>{port}</NewExternalPort>\
+                 <NewProtocol>UDP</NewProtocol><NewInternalPort>{port}</NewInternalPort>\
+                 <NewInternalClient>{local_ip}</NewInternalClient><NewEnabled>1</NewEnabled>\
+                 <NewPortMappingDescription>noktulo</NewPortMappingDescription>\
+                 <NewLeaseDuration>{lifetime}</NewLeaseDuration>",
+                port = port,
+                local_ip = local_ip,
+                lifetime = PORT_MAPPING_LIFETIME
+            );
+            soap_request(gateway, "AddPortMapping", &args).await?;
+            let body = soap_request(gateway, "GetExternalIPAddress", "").await?;
+            let ip = xml_value(&body, "NewExternalIPAddress")
+                .and_then(|ip| ip.parse::<IpAddr>().ok())
+                .ok_or(PortMappingError::Malformed)?;
+            Ok(PortMapping {
+                gateway: gateway.clone(),
+                internal_port: port,
+                external: SocketAddr::new(ip, port),
+                lifetime: PORT_MAPPING_LIFETIME,
+            })
+        }
+    }
+}
+
+async fn remove_mapping(mapping: &PortMapping) -> Result<(), PortMappingError> {
+    match &mapping.gateway {
+        // a lifetime of 0 deletes the mapping
+        Gateway::NatPmp(addr) => {
+            let reply = natpmp_request(*addr, &natpmp_map_request(mapping.internal_port, 0)).await?;
+            parse_natpmp_map(&reply).map(|_| ())
+        }
+        gateway => {
+            let args = format!(
+                "<NewRemoteHost></NewRemoteHost><NewExternalPort>{}</NewExternalPort>\
+                 <NewProtocol>UDP</NewProtocol>",
+                mapping.external.port()
+            );
+            soap_request(gateway, "DeletePortMapping", &args).await.map(|_| ())
+        }
+    }
+}
+
+// Linux only; elsewhere NAT-PMP is skipped for UPnP
+async fn default_gateway() -> Option<Ipv4Addr> {
+    let routes = tokio::fs::read_to_string("/proc/net/route").await.ok()?;
+    parse_default_gateway(&routes)
+}
+
+fn parse_default_gateway(routes: &str) -> Option<Ipv4Addr> {
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 || fields[1] != "00000000" {
+            return None;
+        }
+        // the address as it is in memory, printed as a number in host byte order
+        let gateway = u32::from_str_radix(fields[2], 16).ok()?;
+        Some(Ipv4Addr::from(gateway.to_ne_bytes())).filter(|ip| !ip.is_unspecified())
+    })
+}
+
+// retried with the doubling timeouts of RFC 6886, cut short
+async fn natpmp_request(gateway: Ipv4Addr, request: &[u8]) -> Result<Vec<u8>, PortMappingError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.connect((gateway, NATPMP_PORT)).await?;
+    let mut wait = Duration::from_millis(250);
+    for _ in 0..4 {
+        socket.send(request).await?;
+        let mut buf = [0; 16];
+        if let Ok(received) = timeout(wait, socket.recv(&mut buf)).await {
+            return Ok(buf[..received?].to_vec());
+        }
+        wait *= 2;
+    }
+    Err(PortMappingError::NoAnswer)
+}
+
+fn natpmp_map_request(port: u16, lifetime: u32) -> [u8; 12] {
+    let mut request = [0; 12];
+    request[0] = NATPMP_VERSION;
+    request[1] = NATPMP_OP_MAP_UDP;
+    request[4..6].copy_from_slice(&port.to_be_bytes());
+    request[6..8].copy_from_slice(&port.to_be_bytes());
+    request[8..12].copy_from_slice(&lifetime.to_be_bytes());
+    request
+}
+
+fn natpmp_result(reply: &[u8], op: u8, len: usize) -> Result<(), PortMappingError> {
+    if reply.len() < len || reply[0] != NATPMP_VERSION || reply[1] != op | 0x80 {
+        return Err(PortMappingError::Malformed);
+    }
+    match u16::from_be_bytes([reply[2], reply[3]]) {
+        0 => Ok(()),
+        code => Err(PortMappingError::Refused(code.to_string())),
+    }
+}
+
+// (internal port, external port, lifetime)
+fn parse_natpmp_map(reply: &[u8]) -> Result<(u16, u16, u32), PortMappingError> {
+    natpmp_result(reply, NATPMP_OP_MAP_UDP, 16)?;
+    Ok((
+        u16::from_be_bytes([reply[8], reply[9]]),
+        u16::from_be_bytes([reply[10], reply[11]]),
+        u32::from_be_bytes([reply[12], reply[13], reply[14], reply[15]]),
+    ))
+}
+
+fn parse_natpmp_address(reply: &[u8]) -> Result<Ipv4Addr, PortMappingError> {
+    natpmp_result(reply, NATPMP_OP_ADDRESS, 12)?;
+    Ok(Ipv4Addr::new(reply[8], reply[9], reply[10], reply[11]))
+}
+
+// the first IGD to answer an SSDP search, with its WAN connection service
+async fn discover_upnp() -> Result<Gateway, PortMappingError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let group = SocketAddrV4::new(SSDP_GROUP.into(), SSDP_PORT);
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\n\
+         ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n",
+        group
+    );
+    socket.send_to(search.as_bytes(), group).await?;
+    let mut buf = [0; 2048];
+    let (len, _) = timeout(Duration::from_millis(SSDP_WAIT), socket.recv_from(&mut buf))
+        .await
+        .map_err(|_| PortMappingError::NoGateway)??;
+    let response = String::from_utf8_lossy(&buf[..len]);
+    let location = response
+        .lines()
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("location").then(|| value.trim().to_string())
+        })
+        .ok_or(PortMappingError::Malformed)?;
+    let (addr, path) = parse_http_url(&location).ok_or(PortMappingError::Malformed)?;
+    let description = http_request(addr, &format!("GET {} HTTP/1.1", path), &[], "").await?;
+    let (service, control_path) =
+        control_path(&description).ok_or(PortMappingError::NoWanService)?;
+
+    // the address the gateway sees us at on the LAN
+    let probe = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    probe.connect(addr).await?;
+    let local_ip = match probe.local_addr()?.ip() {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => return Err(PortMappingError::NoGateway),
+    };
+    Ok(Gateway::Upnp {
+        addr,
+        control_path,
+        service,
+        local_ip,
+    })
+}
+
+// "http://host:port/path" with an IP host, as IGDs announce themselves
+fn parse_http_url(url: &str) -> Option<(SocketAddr, String)> {
+    let rest = url.strip_prefix("http://")?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let addr = host
+        .parse()
+        .ok()
+        .or_else(|| host.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 80)))?;
+    Some((addr, path.to_string()))
+}
+
+// the service type and control path of the first WAN connection service
+fn control_path(description: &str) -> Option<(String, String)> {
+    for service in description.split("<service>").skip(1) {
+        let service_type = match xml_value(service, "serviceType") {
+            Some(service_type) if WAN_SERVICES.contains(&service_type.as_str()) => service_type,
+            _ => continue,
+        };
+        let url = xml_value(service, "controlURL")?;
+        // may be absolute, though it is usually a path
+        let path = match parse_http_url(&url) {
+            Some((_, path)) => path,
+            None => url,
+        };
+        return Some((service_type, path));
+    }
+    None
+}
+
+fn xml_value(xml: &str, tag: &str) -> Option<String> {
+    let start = xml.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", tag))?;
+    Some(xml[start..end].trim().to_string())
+}
+
+async fn soap_request(
+    gateway: &Gateway,
+    action: &str,
+    args: &str,
+) -> Result<String, PortMappingError> {
+    let (addr, control_path, service) = match gateway {
+        Gateway::Upnp {
+            addr,
+            control_path,
+            service,
+            ..
+        } => (*addr, control_path, service),
+        Gateway::NatPmp(_) => return Err(PortMappingError::NoGateway),
+    };
+    let body = format!(
+        "<?xml version=\"1.0\"?>\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body>\
+         <u:{action} xmlns:u=\"{service}\">{args}</u:{action}></s:Body></s:Envelope>",
+        action = action,
+        service = service,
+        args = args
+    );
+    let soap_action = format!("SOAPAction: \"{}#{}\"", service, action);
+    let headers = ["Content-Type: text/xml; charset=\"utf-8\"", soap_action.as_str()];
+    let request_line = format!("POST {} HTTP/1.1", control_path);
+    http_request(addr, &request_line, &headers, &body).await
+}
+
+// the body of a 200 response; IGDs are simple enough for HTTP/1.0 style reads
+async fn http_request(
+    addr: SocketAddr,
+    request_line: &str,
+    headers: &[&str],
+    body: &str,
+) -> Result<String, PortMappingError> {
+    let mut request = format!("{}\r\nHost: {}\r\nConnection: close\r\n", request_line, addr);
+    for header in headers {
+        request.push_str(header);
+        request.push_str("\r\n");
+    }
+    request.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+
+    let exchange = async {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok::<_, io::Error>(response)
+    };
+    let response = timeout(Duration::from_millis(SSDP_WAIT), exchange)
+        .await
+        .map_err(|_| PortMappingError::NoAnswer)??;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response.split_once("\r\n\r\n").ok_or(PortMappingError::Malformed)?;
+    let status = head.split_whitespace().nth(1).unwrap_or("");
+    if status != "200" {
+        let error = xml_value(body, "errorDescription").unwrap_or_else(|| status.to_string());
+        return Err(PortMappingError::Refused(error));
+    }
+    Ok(body.to_string())
+}
+
+fn is_public(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let shared = ip.octets()[0] == 100 && (ip.octets()[1] & 0xc0) == 64;
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || shared)
+        }
+        IpAddr::V6(ip) => !(ip.is_loopback() || ip.is_unspecified()),
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum PortMappingError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("No gateway found")]
+    NoGateway,
+    #[error("The gateway has no WAN connection service")]
+    NoWanService,
+    #[error("The gateway did not answer")]
+    NoAnswer,
+    #[error("Malformed answer from the gateway")]
+    Malformed,
+    #[error("The gateway refused: {0}")]
+    Refused(String),
+    #[error("The external address {0} is not public, so there is another NAT")]
+    NotPublic(IpAddr),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn port_mapping_test() {
+        let routes = "Iface\tDestination\tGateway\tFlags\n\
+                      eth0\t0000A8C0\t00000000\t0001\n\
+                      eth0\t00000000\t0101A8C0\t0003\n";
+        assert_eq!(parse_default_gateway(routes), Some(Ipv4Addr::new(192, 168, 1, 1)));
+
+        let request = natpmp_map_request(6270, 7200);
+        assert_eq!(request[..8], [0, 1, 0, 0, 0x18, 0x7e, 0x18, 0x7e]);
+        let mut reply = vec![0, 129, 0, 0, 0, 0, 0, 9, 0x18, 0x7e, 0x18, 0x7f];
+        reply.extend_from_slice(&3600u32.to_be_bytes());
+        assert_eq!(parse_natpmp_map(&reply).unwrap(), (6270, 6271, 3600));
+        reply[3] = 3;
+        assert!(matches!(parse_natpmp_map(&reply), Err(PortMappingError::Refused(_))));
+        let address = [0, 128, 0, 0, 0, 0, 0, 9, 203, 0, 113, 7];
+        assert_eq!(parse_natpmp_address(&address).unwrap(), Ipv4Addr::new(203, 0, 113, 7));
+
+        let description = "<root><service><serviceType>urn:schemas-upnp-org:service:\
+             WANCommonInterfaceConfig:1</serviceType><controlURL>/wcic</controlURL></service>\
+             <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+             <controlURL>http://192.168.1.1:5000/ctl/IPConn</controlURL></service></root>";
+        let (service, path) = control_path(description).unwrap();
+        assert_eq!(service, WAN_SERVICES[0]);
+        assert_eq!(path, "/ctl/IPConn");
+        assert_eq!(
+            parse_http_url("http://192.168.1.1:5000/rootDesc.xml"),
+            Some(("192.168.1.1:5000".parse().unwrap(), "/rootDesc.xml".to_string()))
+        );
+
+        for ip in ["192.168.1.20", "100.64.3.1", "10.0.0.1", "127.0.0.1"] {
+            assert!(!is_public(&ip.parse().unwrap()), "{}", ip);
+        }
+        assert!(is_public(&"198.51.99.1".parse().unwrap()));
+    }
+}
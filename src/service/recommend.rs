@@ -0,0 +1,118 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::user::post::{PostKind, SignedPost};
+use crate::user::user::Address;
+
+const REHOOT_WEIGHT: usize = 2;
+const MENTION_WEIGHT: usize = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Recommendation {
+    pub addr: Address,
+    pub rehoots: usize,
+    pub mentions: usize,
+}
+
+impl Recommendation {
+    pub fn score(&self) -> usize {
+        self.rehoots * REHOOT_WEIGHT + self.mentions * MENTION_WEIGHT
+    }
+}
+
+// Ranks addresses which the given followings rehoot or mention, but which
+// are not followed yet. Posts by anyone else are ignored.
+pub fn recommend_follows<'a, I>(
+    posts: I,
+    me: &Address,
+    is_following: impl Fn(&Address) -> bool,
+    limit: usize,
+) -> Vec<Recommendation>
+where
+    I: IntoIterator<Item = &'a SignedPost>,
+{
+    let mut counts: HashMap<Address, Recommendation> = HashMap::new();
+    let mut count = |addr: &Address, rehoot: bool| {
+        if addr == me || is_following(addr) {
+            return;
+        }
+        let entry = counts
+            .entry(addr.clone())
+            .or_insert_with(|| Recommendation {
+                addr: addr.clone(),
+                rehoots: 0,
+                mentions: 0,
+            });
+        if rehoot {
+            entry.rehoots += 1;
+        } else {
+            entry.mentions += 1;
+        }
+    };
+
+    for sigpost in posts {
+        if !is_following(&sigpost.addr) {
+            continue;
+        }
+        match &sigpost.post.content {
+            PostKind::ReHoot(inner) => count(&inner.addr, true),
+            PostKind::ReHootRef(original) => count(&original.addr, true),
+            PostKind::Hoot(hoot) => {
+                for addr in hoot.mention_to.iter() {
+                    count(addr, false);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    let mut ret: Vec<_> = counts.into_values().collect();
+    ret.sort_by(|a, b| {
+        b.score()
+            .cmp(&a.score())
+            .then_with(|| a.addr.to_string().cmp(&b.addr.to_string()))
+    });
+    ret.truncate(limit);
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::test_user;
+
+    #[test]
+    fn recommend_test() {
+        let me = test_user("me");
+        let mut friend = test_user("friend");
+        let mut popular = test_user("popular");
+        let mentioned = test_user("mentioned");
+
+        let original = popular.hoot("hi".to_string(), None, None, vec![]).unwrap();
+        let posts = vec![
+            friend.rehoot(original.clone(), &popular.pubkey().unwrap()).unwrap(),
+            friend
+                .hoot(
+                    "hey".to_string(),
+                    None,
+                    None,
+                    vec![mentioned.addr(), me.addr()],
+                )
+                .unwrap(),
+            // not followed, so it does not count
+            popular.rehoot(original, &popular.pubkey().unwrap()).unwrap(),
+        ];
+
+        let friend_addr = friend.addr();
+        let ret = recommend_follows(&posts, &me.addr(), |a| *a == friend_addr, 10);
+        assert_eq!(ret.len(), 2);
+        assert_eq!(ret[0].addr, popular.addr());
+        assert_eq!(ret[0].rehoots, 1);
+        assert_eq!(ret[1].addr, mentioned.addr());
+
+        assert_eq!(
+            recommend_follows(&posts, &me.addr(), |a| *a == friend_addr, 1).len(),
+            1
+        );
+    }
+}
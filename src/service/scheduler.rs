@@ -0,0 +1,207 @@
+use futures::future::BoxFuture;
+use log::{debug, info};
+use rand::prelude::*;
+use rand_chacha::ChaCha20Rng;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+use crate::util::clock::{Clock, SystemClock};
+
+type Job = Arc<dyn Fn() -> BoxFuture<'static, ()> + Send + Sync>;
+
+struct Entry {
+    name: String,
+    interval: Duration,
+    jitter: Duration,
+    next_run: Instant,
+    job: Job,
+}
+
+struct State {
+    entries: Vec<Entry>,
+    paused: bool,
+    is_started: bool,
+}
+
+#[derive(Clone)]
+pub struct Scheduler {
+    tick: Duration,
+    clock: Arc<dyn Clock>,
+    state: Arc<Mutex<State>>,
+}
+
+impl Scheduler {
+    pub fn new(tick: Duration) -> Scheduler {
+        Scheduler::with_clock(tick, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(tick: Duration, clock: Arc<dyn Clock>) -> Scheduler {
+        Scheduler {
+            tick,
+            clock,
+            state: Arc::new(Mutex::new(State {
+                entries: Vec::new(),
+                paused: false,
+                is_started: false,
+            })),
+        }
+    }
+
+    pub async fn register<F, Fut>(&self, name: &str, interval: Duration, jitter: Duration, job: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let job: Job = Arc::new(move || Box::pin(job()));
+        let mut state = self.state.lock().await;
+        state.entries.retain(|e| e.name != name);
+        state.entries.push(Entry {
+            name: name.to_string(),
+            interval,
+            jitter,
+            next_run: next_run(self.clock.now(), interval, jitter),
+            job,
+        });
+    }
+
+    pub async fn unregister(&self, name: &str) {
+        let mut state = self.state.lock().await;
+        state.entries.retain(|e| e.name != name);
+    }
+
+    pub async fn pause(&self) {
+        self.state.lock().await.paused = true;
+        info!("Maintenance paused");
+    }
+
+    // jobs are rescheduled from now, so that everything which fell due while
+    // paused does not fire at once
+    pub async fn resume(&self) {
+        let mut state = self.state.lock().await;
+        state.paused = false;
+        let now = self.clock.now();
+        reschedule(&mut state, now);
+        info!("Maintenance resumed");
+    }
+
+    pub async fn is_paused(&self) -> bool {
+        self.state.lock().await.paused
+    }
+
+    pub async fn run_pending(&self, now: Instant) -> Vec<String> {
+        let mut state = self.state.lock().await;
+        if state.paused {
+            return Vec::new();
+        }
+
+        let mut due = Vec::new();
+        for entry in state.entries.iter_mut() {
+            if entry.next_run <= now {
+                entry.next_run = next_run(now, entry.interval, entry.jitter);
+                due.push((entry.name.clone(), entry.job.clone()));
+            }
+        }
+        drop(state);
+
+        let mut ret = Vec::new();
+        for (name, job) in due {
+            debug!("Running maintenance job: {}", name);
+            job().await;
+            ret.push(name);
+        }
+        ret
+    }
+
+    pub async fn start(&self) {
+        let mut state = self.state.lock().await;
+        if state.is_started {
+            return;
+        }
+        state.is_started = true;
+        drop(state);
+
+        let scheduler = self.clone();
+        tokio::spawn(async move {
+            let mut last = scheduler.clock.now();
+            loop {
+                scheduler.clock.sleep(scheduler.tick).await;
+                let now = scheduler.clock.now();
+                // woken up far too late: the host was most likely suspended
+                if now - last > scheduler.tick * 10 {
+                    info!("Clock jumped, rescheduling maintenance jobs");
+                    reschedule(&mut *scheduler.state.lock().await, now);
+                }
+                last = now;
+                scheduler.run_pending(now).await;
+            }
+        });
+    }
+}
+
+fn next_run(now: Instant, interval: Duration, jitter: Duration) -> Instant {
+    if jitter.is_zero() {
+        now + interval
+    } else {
+        let jitter_ms = ChaCha20Rng::from_entropy().gen_range(0..=jitter.as_millis() as u64);
+        now + interval + Duration::from_millis(jitter_ms)
+    }
+}
+
+fn reschedule(state: &mut State, now: Instant) {
+    for entry in state.entries.iter_mut() {
+        entry.next_run = next_run(now, entry.interval, entry.jitter);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Scheduler;
+    use crate::util::clock::{Clock, MockClock};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::time::Duration;
+
+    #[tokio::test]
+    async fn run_pending_test() {
+        let clock = MockClock::new();
+        let scheduler = Scheduler::with_clock(Duration::from_secs(1), Arc::new(clock.clone()));
+        let count = Arc::new(AtomicUsize::new(0));
+        let c = count.clone();
+        let start = clock.now();
+        scheduler
+            .register(
+                "count",
+                Duration::from_secs(10),
+                Duration::ZERO,
+                move || {
+                    let c = c.clone();
+                    async move {
+                        c.fetch_add(1, Ordering::SeqCst);
+                    }
+                },
+            )
+            .await;
+
+        assert!(scheduler
+            .run_pending(start + Duration::from_secs(5))
+            .await
+            .is_empty());
+        assert_eq!(
+            scheduler.run_pending(start + Duration::from_secs(11)).await,
+            vec!["count".to_string()]
+        );
+        assert!(scheduler
+            .run_pending(start + Duration::from_secs(12))
+            .await
+            .is_empty());
+
+        scheduler.pause().await;
+        assert!(scheduler
+            .run_pending(start + Duration::from_secs(30))
+            .await
+            .is_empty());
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,55 @@
+use std::collections::{HashMap, HashSet};
+
+use super::{UnlockedUserHandle, WatchHandle};
+use crate::user::following::Following;
+use crate::user::user::{Address, MigrationRecord, VerifyError};
+
+// What a timeline is opened for: an account, or browsing without one
+pub enum Session {
+    Account(Box<UnlockedUserHandle>),
+    Watch(WatchHandle),
+}
+
+impl Session {
+    pub fn followings(&self) -> &HashMap<Address, Following> {
+        match self {
+            Session::Account(user_handle) => &user_handle.followings,
+            Session::Watch(watch_handle) => &watch_handle.followings,
+        }
+    }
+
+    pub fn followings_mut(&mut self) -> &mut HashMap<Address, Following> {
+        match self {
+            Session::Account(user_handle) => &mut user_handle.followings,
+            Session::Watch(watch_handle) => &mut watch_handle.followings,
+        }
+    }
+
+    pub fn deleted_accounts(&self) -> &HashSet<Address> {
+        match self {
+            Session::Account(user_handle) => &user_handle.deleted_accounts,
+            Session::Watch(watch_handle) => &watch_handle.deleted_accounts,
+        }
+    }
+
+    pub fn mark_deleted(&mut self, addr: Address) {
+        match self {
+            Session::Account(user_handle) => user_handle.mark_deleted(addr),
+            Session::Watch(watch_handle) => watch_handle.mark_deleted(addr),
+        }
+    }
+
+    pub fn apply_migration(&mut self, record: &MigrationRecord) -> Result<bool, VerifyError> {
+        match self {
+            Session::Account(user_handle) => user_handle.apply_migration(record),
+            Session::Watch(watch_handle) => watch_handle.apply_migration(record),
+        }
+    }
+
+    pub fn collapse_sensitive(&mut self) -> &mut bool {
+        match self {
+            Session::Account(user_handle) => &mut user_handle.collapse_sensitive,
+            Session::Watch(watch_handle) => &mut watch_handle.collapse_sensitive,
+        }
+    }
+}
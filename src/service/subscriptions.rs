@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::TryRecvError;
+use tokio::sync::broadcast::Receiver;
+
+use crate::user::post::SignedPost;
+use crate::user::user::Address;
+
+use super::{Subscriber, SubscriptionHandle};
+
+// One Subscriber for every local account, so that accounts following the same
+// address share its subscription node: the Subscriber counts the handles of
+// each address, and each account's feed picks the posts it follows out of the
+// ones they all receive.
+#[derive(Clone)]
+pub struct SubscriptionManager {
+    subscriber: Arc<Subscriber>,
+}
+
+impl SubscriptionManager {
+    pub fn new(subscriber: Arc<Subscriber>) -> SubscriptionManager {
+        SubscriptionManager { subscriber }
+    }
+
+    pub fn subscriber(&self) -> Arc<Subscriber> {
+        self.subscriber.clone()
+    }
+
+    // a feed following nothing yet, receiving from now on
+    pub fn feed(&self) -> AccountFeed {
+        AccountFeed {
+            subscriber: self.subscriber.clone(),
+            handles: HashMap::new(),
+            rx: self.subscriber.get_receiver(),
+        }
+    }
+
+    pub async fn is_subscribed(&self, addr: &Address) -> bool {
+        self.subscriber.is_subscribed(addr).await
+    }
+}
+
+// The posts of the addresses one account follows. Dropping it gives up its
+// share of their subscriptions.
+pub struct AccountFeed {
+    subscriber: Arc<Subscriber>,
+    handles: HashMap<Address, SubscriptionHandle>,
+    rx: Receiver<SignedPost>,
+}
+
+impl AccountFeed {
+    pub async fn follow(&mut self, addr: Address) {
+        if !self.handles.contains_key(&addr) {
+            let handle = self.subscriber.subscribe(addr.clone()).await;
+            self.handles.insert(addr, handle);
+        }
+    }
+
+    // false if addr was not followed
+    pub async fn unfollow(&mut self, addr: &Address) -> bool {
+        match self.handles.remove(addr) {
+            Some(handle) => {
+                handle.close().await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // The archived posts of followed addresses created after since, oldest
+    // first, for catching up after being offline; see Subscriber::fetch_history
+    pub async fn fetch_missed(&self, since: u64) -> Vec<SignedPost> {
+        self.fetch_missed_of(self.handles.keys(), since).await
+    }
+
+    // the same for some of the followed addresses, like the ones whose
+    // SyncStatus needs backfill
+    pub async fn fetch_missed_of<'a>(
+        &self,
+        addrs: impl IntoIterator<Item = &'a Address>,
+        since: u64,
+    ) -> Vec<SignedPost> {
+        let fetches = addrs
+            .into_iter()
+            .filter(|addr| self.handles.contains_key(*addr))
+            .map(|addr| self.subscriber.fetch_history(addr, since));
+        let mut posts: Vec<SignedPost> =
+            futures::future::join_all(fetches).await.into_iter().flatten().collect();
+        posts.sort_by_key(|sigpost| sigpost.post.id);
+        posts
+    }
+
+    pub fn is_following(&self, addr: &Address) -> bool {
+        self.handles.contains_key(addr)
+    }
+
+    // the posts of followed addresses received since the last call; posts
+    // missed while lagging are skipped
+    pub fn new_posts(&mut self) -> Vec<SignedPost> {
+        let mut ret = Vec::new();
+        loop {
+            match self.rx.try_recv() {
+                // direct messages of followed accounts go to their recipients
+                Ok(post) if post.direct_to().is_none() && self.handles.contains_key(&post.addr) => {
+                    ret.push(post)
+                }
+                Ok(_) | Err(TryRecvError::Lagged(_)) => continue,
+                Err(_) => break,
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kad::Rpc;
+    use crate::service::{pubsub_network, PUBSUB_DHT_KEY_LENGTH, TESTNET_PUBSUB_DHT};
+    use tokio::sync::Mutex;
+
+    #[tokio::test]
+    async fn subscription_manager_test() {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let rpc = Arc::new(Mutex::new(Rpc::new(socket)));
+        let network = pubsub_network(TESTNET_PUBSUB_DHT, PUBSUB_DHT_KEY_LENGTH);
+        let manager = SubscriptionManager::new(Arc::new(Subscriber::new(rpc, &network, &[]).await));
+        let (shared, own) = (Address::new([1; 32]), Address::new([2; 32]));
+
+        let mut alice = manager.feed();
+        let mut bob = manager.feed();
+        alice.follow(shared.clone()).await;
+        alice.follow(shared.clone()).await;
+        bob.follow(shared.clone()).await;
+        bob.follow(own.clone()).await;
+
+        // alice leaving keeps the subscription bob still needs
+        assert!(alice.unfollow(&shared).await);
+        assert!(!alice.unfollow(&shared).await);
+        assert!(manager.is_subscribed(&shared).await);
+        drop(bob);
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        assert!(!manager.is_subscribed(&shared).await);
+        assert!(!manager.is_subscribed(&own).await);
+    }
+}
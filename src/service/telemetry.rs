@@ -0,0 +1,269 @@
+use serde_json::{json, Value};
+use std::io;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::util::stats::{DelayHistogram, NetTraffic, Stats, DELAY_BUCKETS};
+
+use super::{OTLP_SERVICE_NAME, OTLP_TIME_OUT};
+
+// Where an OpenTelemetry collector takes metrics: OTLP over HTTP with JSON
+// bodies, so that no protobuf or gRPC stack is needed. Plain http only, as
+// there is no TLS client in the tree; run the collector next to the relay.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OtlpEndpoint {
+    // host:port to connect to
+    addr: String,
+    host: String,
+    path: String,
+}
+
+impl OtlpEndpoint {
+    // "http://host[:port][/path]"; the port defaults to OTLP's 4318 and the
+    // path to /v1/metrics
+    pub fn parse(url: &str) -> Option<OtlpEndpoint> {
+        let rest = url.strip_prefix("http://")?;
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/v1/metrics"),
+        };
+        if host.is_empty() || host.starts_with(':') {
+            return None;
+        }
+        let addr = match host.rsplit_once(':') {
+            Some((_, port)) => {
+                port.parse::<u16>().ok()?;
+                host.to_string()
+            }
+            None => format!("{}:4318", host),
+        };
+        Some(OtlpEndpoint {
+            addr,
+            host: host.to_string(),
+            path: path.to_string(),
+        })
+    }
+}
+
+// Sends the stats as cumulative sums, a gauge for uptime and a histogram of
+// delivery delays. Only metrics: the node records no spans to export.
+pub async fn export_metrics(
+    endpoint: &OtlpEndpoint,
+    stats: &Stats,
+    start_ns: u64,
+    now_ns: u64,
+) -> Result<(), TelemetryError> {
+    let body = metrics_request(stats, start_ns, now_ns).to_string();
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        endpoint.path,
+        endpoint.host,
+        body.len(),
+        body
+    );
+    let exchange = async {
+        let mut stream = TcpStream::connect(&endpoint.addr).await?;
+        stream.write_all(request.as_bytes()).await?;
+        // the status line is all that matters
+        let mut buf = vec![0; 64];
+        let n = stream.read(&mut buf).await?;
+        buf.truncate(n);
+        Ok::<_, io::Error>(buf)
+    };
+    let response = tokio::time::timeout(Duration::from_millis(OTLP_TIME_OUT), exchange)
+        .await
+        .map_err(|_| TelemetryError::TimedOut)?
+        .map_err(TelemetryError::Io)?;
+    let response = String::from_utf8_lossy(&response);
+    match response.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        Some(status) => Err(TelemetryError::Status(status.to_string())),
+        None => Err(TelemetryError::Status("no answer".to_string())),
+    }
+}
+
+// an ExportMetricsServiceRequest in OTLP's JSON mapping, where 64 bit
+// integers are strings
+pub fn metrics_request(stats: &Stats, start_ns: u64, now_ns: u64) -> Value {
+    let point = |value: u64, attributes: Vec<Value>| {
+        json!({
+            "attributes": attributes,
+            "startTimeUnixNano": start_ns.to_string(),
+            "timeUnixNano": now_ns.to_string(),
+            "asInt": value.to_string(),
+        })
+    };
+    let counter = |name: &str, unit: &str, value: u64| {
+        sum(name, unit, vec![point(value, Vec::new())])
+    };
+
+    let mut metrics = vec![
+        counter("noktulo.posts.sent", "{post}", stats.posts_sent),
+        counter("noktulo.posts.received", "{post}", stats.posts_received),
+        counter("noktulo.bytes.sent", "By", stats.bytes_sent),
+        counter("noktulo.bytes.received", "By", stats.bytes_received),
+        counter("noktulo.peers.seen", "{peer}", stats.peers_seen),
+        counter("noktulo.peers.banned", "{peer}", stats.peers_banned),
+        counter("noktulo.messages.malformed", "{message}", stats.malformed_received),
+        counter("noktulo.stores.overwrites_rejected", "{store}", stats.overwrites_rejected),
+        counter("noktulo.stores.refused_full", "{store}", stats.stores_refused_full),
+        counter("noktulo.messages.dropped", "{message}", stats.messages_dropped),
+        json!({
+            "name": "noktulo.uptime",
+            "unit": "s",
+            "gauge": {"dataPoints": [point(stats.uptime, Vec::new())]},
+        }),
+    ];
+
+    let pending = &stats.pending_requests;
+    let outcomes = [
+        ("inserted", pending.inserted),
+        ("answered", pending.answered),
+        ("timed_out", pending.timed_out),
+        ("evicted", pending.evicted),
+        ("leaked", pending.leaked),
+    ];
+    let points = outcomes
+        .iter()
+        .map(|(outcome, n)| point(*n, vec![attribute("outcome", outcome)]))
+        .collect();
+    metrics.push(sum("noktulo.rpc.pending", "{request}", points));
+
+    let by_net = |value: fn(&NetTraffic) -> u64| {
+        stats
+            .traffic_by_net
+            .iter()
+            .map(|(net_id, traffic)| point(value(traffic), vec![attribute("net_id", net_id)]))
+            .collect::<Vec<_>>()
+    };
+    if !stats.traffic_by_net.is_empty() {
+        let traffic = [
+            ("noktulo.dht.messages.sent", "{message}", by_net(|t| t.messages_sent)),
+            ("noktulo.dht.messages.received", "{message}", by_net(|t| t.messages_received)),
+            ("noktulo.dht.bytes.sent", "By", by_net(|t| t.bytes_sent)),
+            ("noktulo.dht.bytes.received", "By", by_net(|t| t.bytes_received)),
+            ("noktulo.dht.timeouts", "{request}", by_net(|t| t.timeouts)),
+        ];
+        for (name, unit, points) in traffic {
+            metrics.push(sum(name, unit, points));
+        }
+    }
+
+    if !stats.delivery_delay.is_empty() {
+        metrics.push(histogram(
+            "noktulo.delivery.delay",
+            &stats.delivery_delay,
+            start_ns,
+            now_ns,
+        ));
+    }
+
+    json!({
+        "resourceMetrics": [{
+            "resource": {"attributes": [attribute("service.name", OTLP_SERVICE_NAME)]},
+            "scopeMetrics": [{
+                "scope": {"name": "noktulo", "version": env!("CARGO_PKG_VERSION")},
+                "metrics": metrics,
+            }],
+        }],
+    })
+}
+
+fn attribute(key: &str, value: &str) -> Value {
+    json!({"key": key, "value": {"stringValue": value}})
+}
+
+// a monotonic sum with cumulative temporality (2)
+fn sum(name: &str, unit: &str, points: Vec<Value>) -> Value {
+    json!({
+        "name": name,
+        "unit": unit,
+        "sum": {"dataPoints": points, "aggregationTemporality": 2, "isMonotonic": true},
+    })
+}
+
+fn histogram(name: &str, delays: &DelayHistogram, start_ns: u64, now_ns: u64) -> Value {
+    let mut counts = delays.counts.clone();
+    counts.resize(DELAY_BUCKETS.len() + 1, 0);
+    json!({
+        "name": name,
+        "unit": "ms",
+        "histogram": {
+            "dataPoints": [{
+                "startTimeUnixNano": start_ns.to_string(),
+                "timeUnixNano": now_ns.to_string(),
+                "count": delays.count().to_string(),
+                "sum": delays.total_ms as f64,
+                "max": delays.max_ms as f64,
+                "bucketCounts": counts.iter().map(|n| n.to_string()).collect::<Vec<_>>(),
+                "explicitBounds": DELAY_BUCKETS.iter().map(|b| *b as f64).collect::<Vec<_>>(),
+            }],
+            "aggregationTemporality": 2,
+        },
+    })
+}
+
+#[derive(Debug, Error)]
+pub enum TelemetryError {
+    #[error("Cannot reach the collector: {0}")]
+    Io(io::Error),
+    #[error("The collector did not answer in time")]
+    TimedOut,
+    #[error("The collector answered {0}")]
+    Status(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn export_metrics_test() {
+        assert_eq!(
+            OtlpEndpoint::parse("http://collector"),
+            OtlpEndpoint::parse("http://collector/v1/metrics")
+        );
+        assert!(OtlpEndpoint::parse("https://collector:4318").is_none());
+        assert!(OtlpEndpoint::parse("http://collector:port").is_none());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let endpoint = OtlpEndpoint::parse(&url).unwrap();
+        let collector = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = String::new();
+            let mut buf = [0; 4096];
+            // all of it, so that closing does not reset the connection
+            loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.push_str(std::str::from_utf8(&buf[..n]).unwrap());
+                if let Some((head, body)) = request.split_once("\r\n\r\n") {
+                    let len = head.split("Content-Length: ").nth(1).unwrap();
+                    let len: usize = len.split("\r\n").next().unwrap().parse().unwrap();
+                    if body.len() == len {
+                        break;
+                    }
+                }
+            }
+            stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap();
+            request
+        });
+
+        let stats = Stats {
+            posts_sent: 3,
+            ..Stats::default()
+        };
+        export_metrics(&endpoint, &stats, 1, 2).await.unwrap();
+        let request = collector.await.unwrap();
+        assert!(request.starts_with("POST /v1/metrics HTTP/1.1\r\n"));
+
+        let body = metrics_request(&stats, 1, 2);
+        let metrics = &body["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+        assert_eq!(metrics[0]["name"], "noktulo.posts.sent");
+        assert_eq!(metrics[0]["sum"]["dataPoints"][0]["asInt"], "3");
+    }
+}
@@ -0,0 +1,145 @@
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use thiserror::Error;
+
+use super::{BootstrapAddr, Filter, FilterError, OtlpEndpoint};
+
+// Settings that can change while the node runs. They are read from a JSON file
+// at start, and again on SIGHUP or a reload request; absent fields keep their
+// defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tunables {
+    // the most verbose level logged, e.g. "debug"; RUST_LOG still applies
+    pub log_level: Option<String>,
+    // contacted when added, besides the bootstrap of Config
+    pub bootstrap: Vec<BootstrapAddr>,
+    // posts an API client may publish per minute, no limit if None
+    pub posts_per_minute: Option<u32>,
+    // bytes kept by the relay cache, its configured quota if None
+    pub relay_quota: Option<u64>,
+    // posts matching it are neither delivered to API clients nor relayed
+    pub block_filter: Option<String>,
+    // an OpenTelemetry collector the stats are pushed to every
+    // OTLP_EXPORT_INTERVAL, e.g. "http://127.0.0.1:4318"; none if None
+    pub otlp_endpoint: Option<String>,
+}
+
+impl Tunables {
+    pub async fn load(path: &Path) -> Result<Tunables, TunablesError> {
+        let buf = tokio::fs::read(path).await.map_err(TunablesError::Io)?;
+        let tunables: Tunables = serde_json::from_slice(&buf).map_err(TunablesError::Parse)?;
+        tunables.validate()?;
+        Ok(tunables)
+    }
+
+    pub fn validate(&self) -> Result<(), TunablesError> {
+        if let Some(level) = &self.log_level {
+            LevelFilter::from_str(level).map_err(|_| TunablesError::LogLevel(level.clone()))?;
+        }
+        if let Some(filter) = &self.block_filter {
+            Filter::parse(filter).map_err(TunablesError::Filter)?;
+        }
+        if self.posts_per_minute == Some(0) {
+            return Err(TunablesError::Zero("posts_per_minute"));
+        }
+        if self.relay_quota == Some(0) {
+            return Err(TunablesError::Zero("relay_quota"));
+        }
+        if let Some(url) = &self.otlp_endpoint {
+            OtlpEndpoint::parse(url).ok_or_else(|| TunablesError::OtlpEndpoint(url.clone()))?;
+        }
+        Ok(())
+    }
+
+    // only for validated tunables
+    pub fn log_level(&self) -> Option<LevelFilter> {
+        self.log_level.as_ref().and_then(|level| level.parse().ok())
+    }
+
+    pub fn block_filter(&self) -> Option<Filter> {
+        self.block_filter.as_ref().and_then(|filter| Filter::parse(filter).ok())
+    }
+
+    pub fn otlp_endpoint(&self) -> Option<OtlpEndpoint> {
+        self.otlp_endpoint.as_ref().and_then(|url| OtlpEndpoint::parse(url))
+    }
+
+    // names of the fields which differ in new
+    pub fn changes(&self, new: &Tunables) -> Vec<String> {
+        let changed = [
+            ("log_level", self.log_level != new.log_level),
+            ("bootstrap", self.bootstrap != new.bootstrap),
+            ("posts_per_minute", self.posts_per_minute != new.posts_per_minute),
+            ("relay_quota", self.relay_quota != new.relay_quota),
+            ("block_filter", self.block_filter != new.block_filter),
+            ("otlp_endpoint", self.otlp_endpoint != new.otlp_endpoint),
+        ];
+        changed
+            .iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(name, _)| name.to_string())
+            .collect()
+    }
+}
+
+// sent to NetworkController::config_events subscribers on every reload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigEvent {
+    // the names of the changed fields, empty if nothing changed
+    Reloaded(Vec<String>),
+    // the running tunables are kept
+    Rejected(String),
+}
+
+#[derive(Debug, Error)]
+pub enum TunablesError {
+    #[error("No tunables file is configured")]
+    NoFile,
+    #[error("Cannot read the tunables file: {0}")]
+    Io(io::Error),
+    #[error("Malformed tunables file: {0}")]
+    Parse(serde_json::Error),
+    #[error("Unknown log level: {0}")]
+    LogLevel(String),
+    #[error("Invalid block filter: {0}")]
+    Filter(FilterError),
+    #[error("{0} must not be 0")]
+    Zero(&'static str),
+    #[error("Not an http:// URL of a collector: {0}")]
+    OtlpEndpoint(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tunables_test() {
+        let tunables: Tunables =
+            serde_json::from_str(r#"{"log_level":"debug","posts_per_minute":10}"#).unwrap();
+        assert!(tunables.validate().is_ok());
+        assert_eq!(tunables.log_level(), Some(LevelFilter::Debug));
+        assert!(tunables.bootstrap.is_empty());
+
+        let mut new = tunables.clone();
+        new.block_filter = Some("contains \"spam\"".to_string());
+        new.bootstrap = vec!["127.0.0.1:6271".parse().unwrap()];
+        assert!(new.validate().is_ok());
+        assert_eq!(tunables.changes(&new), vec!["bootstrap", "block_filter"]);
+        assert!(tunables.changes(&tunables).is_empty());
+
+        new.block_filter = Some("contains".to_string());
+        assert!(matches!(new.validate(), Err(TunablesError::Filter(_))));
+        new.block_filter = None;
+        new.log_level = Some("loud".to_string());
+        assert!(matches!(new.validate(), Err(TunablesError::LogLevel(_))));
+        new.log_level = None;
+        new.otlp_endpoint = Some("collector:4318".to_string());
+        assert!(matches!(new.validate(), Err(TunablesError::OtlpEndpoint(_))));
+        assert!(serde_json::from_str::<Tunables>(r#"{"rate":1}"#).is_err());
+    }
+}
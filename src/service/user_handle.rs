@@ -1,52 +1,195 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::ops::{Deref, DerefMut};
 
-use crate::crypto::{PublicKey,SecretKey};
-use crate::user::post::{Hoot, Post, PostKind};
-use crate::user::user::{SignedUserAttribute, UserAttribute};
+use crate::crypto::{
+    ExternalSigner, KeystoreError, PublicKey, SealedKey, SecretKey, Signer, SignerError,
+};
+use crate::user::group::{
+    Group, GroupError, GroupMessage, Membership, MembershipChange, MembershipUpdate,
+    SignedMembership, SignedMembershipUpdate,
+};
+use crate::user::backup::BackupContents;
+use crate::user::block::BlockList;
+use crate::user::following::Following;
+use crate::user::direct::{DirectMessage, DirectMessageError};
+use crate::user::history::{HeadRecord, HistoryEntry};
+use crate::user::mailbox::{MailboxIndex, MailboxItem};
+use crate::user::mention::{self, MentionError, MentionPolicy};
+use crate::user::post::{Hoot, Post, PostKind, RehootError};
+use crate::user::post_id::PostId;
+use crate::user::user::{
+    AccountTombstone, MigrationRecord, RegistrationRecord, SignedUserAttribute, VerifyError,
+};
 use crate::user::{post::SignedPost, user::Address};
+use crate::util::rng::Entropy;
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
 
+use super::activitystreams;
+use super::recommend::{self, Recommendation};
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct UserHandle {
     pub sig_attr: SignedUserAttribute,
-    pub signing_key: [u8; 32],
-    pub followings: HashMap<Address, Option<UserAttribute>>,
+    // None until unlocked, see UserHandle::unlock. Never written: only the
+    // sealed key is, and files from before the keystore are read with it in
+    // plaintext until sealed.
+    #[serde(default, skip_serializing)]
+    pub signing_key: Option<SecretKey>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sealed_key: Option<SealedKey>,
+    pub followings: HashMap<Address, Following>,
     pub posts: Vec<SignedPost>,
+    #[serde(default = "default_collapse_sensitive")]
+    pub collapse_sensitive: bool,
+    // when set, signing_key is unused and every signature is made by the external signer
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub external_signer: Option<ExternalSigner>,
+    // followed accounts which have been deleted; never resolved again
+    #[serde(default)]
+    pub deleted_accounts: HashSet<Address>,
+    // group DMs this account is a member of
+    #[serde(default)]
+    pub groups: Vec<Group>,
+    // fetch pages for link previews when composing; off unless asked for
+    #[serde(default)]
+    pub link_previews: bool,
+    #[serde(default)]
+    pub mention_policy: MentionPolicy,
+    // accounts whose posts, and posts carrying theirs, are dropped on arrival
+    #[serde(default)]
+    pub blocked: BlockList,
+}
+
+fn default_collapse_sensitive() -> bool {
+    true
+}
+
+pub(crate) fn follow_moved(
+    followings: &mut HashMap<Address, Following>,
+    record: &MigrationRecord,
+) -> bool {
+    match followings.remove(&record.old_addr()) {
+        Some(following) => {
+            followings.entry(record.new_addr()).or_insert(following);
+            true
+        }
+        None => false,
+    }
 }
 
 impl UserHandle {
     pub fn new(
         sig_attr: SignedUserAttribute,
-        signing_key: [u8; 32],
-        followings: HashMap<Address, Option<UserAttribute>>,
+        signing_key: SecretKey,
+        followings: HashMap<Address, Following>,
         posts: &[SignedPost],
     ) -> UserHandle {
         UserHandle {
             sig_attr,
-            signing_key,
+            signing_key: Some(signing_key),
+            sealed_key: None,
             followings,
             posts: posts.to_vec(),
+            collapse_sensitive: true,
+            external_signer: None,
+            deleted_accounts: HashSet::new(),
+            groups: Vec::new(),
+            link_previews: false,
+            mention_policy: MentionPolicy::default(),
+            blocked: BlockList::new(),
         }
     }
 
-    pub fn pubkey(&self) -> PublicKey {
-        PublicKey::from(SecretKey::from(self.signing_key.clone()))
+    pub fn with_external_signer(
+        sig_attr: SignedUserAttribute,
+        signer: ExternalSigner,
+        followings: HashMap<Address, Following>,
+        posts: &[SignedPost],
+    ) -> UserHandle {
+        let mut handle =
+            UserHandle::new(sig_attr, SecretKey::from([0; 32]), followings, posts);
+        handle.signing_key = None;
+        handle.external_signer = Some(signer);
+        handle
     }
 
-    pub fn addr(&self) -> Address {
-        self.pubkey().into()
+    // A locked key signs nothing, its signatures fail with SignerError::Locked.
+    // An account read without any key, say one saved before it was sealed,
+    // has no signer at all.
+    pub fn signer(&self) -> Result<Box<dyn Signer>, SignerError> {
+        match (&self.external_signer, &self.signing_key, &self.sealed_key) {
+            (Some(signer), _, _) => Ok(Box::new(signer.clone())),
+            (None, Some(key), _) => Ok(Box::new(key.clone())),
+            (None, None, Some(sealed)) => Ok(Box::new(sealed.clone())),
+            (None, None, None) => Err(SignerError::NoKey),
+        }
     }
 
-    pub fn create_post(&mut self, post: PostKind) -> SignedPost {
-        let user_attr = self.sig_attr.attr.clone();
+    pub fn is_locked(&self) -> bool {
+        self.external_signer.is_none() && self.signing_key.is_none()
+    }
+
+    // a local key which would not be written, as it was never sealed
+    pub fn needs_sealing(&self) -> bool {
+        self.external_signer.is_none() && self.sealed_key.is_none()
+    }
+
+    // Seals the key with password, for the next time the account is saved.
+    // The key stays unlocked.
+    pub fn seal_key(&mut self, password: &str) -> Result<(), SignerError> {
+        let key = self.signing_key.as_ref().ok_or(SignerError::Locked)?;
+        self.sealed_key = Some(SealedKey::seal(key, password));
+        Ok(())
+    }
+
+    // A copy of this account with the sealed key opened for signing. The
+    // handle itself stays locked.
+    pub fn unlock(&self, password: &str) -> Result<UnlockedUserHandle, KeystoreError> {
+        let mut handle = self.clone();
+        if let (None, Some(sealed)) = (&handle.signing_key, &handle.sealed_key) {
+            handle.signing_key = Some(sealed.open(password)?);
+        }
+        Ok(UnlockedUserHandle(handle))
+    }
 
-        let mut id = 0;
-        if !self.posts.is_empty() {
-            id = self.posts.last().unwrap().post.id + 1;
+    // Forgets the key once it is sealed, as unlock gives it back. Unsealed
+    // keys are kept: they are not written anywhere else.
+    pub fn lock(&mut self) {
+        if self.sealed_key.is_some() {
+            self.signing_key = None;
         }
+    }
+
+    pub fn pubkey(&self) -> Result<PublicKey, SignerError> {
+        Ok(self.signer()?.public_key())
+    }
 
-        let created_at = Utc::now().timestamp() as u64;
+    // the address of the signed profile, known even without the key
+    pub fn addr(&self) -> Address {
+        self.sig_attr.addr.clone()
+    }
+
+    pub fn create_post(&mut self, post: PostKind) -> Result<SignedPost, SignerError> {
+        let sigpost = self.sign_post(post)?;
+
+        self.posts.push(sigpost.clone());
+
+        Ok(sigpost)
+    }
+
+    // a post which is not kept in posts, as those are public
+    fn sign_post(&self, post: PostKind) -> Result<SignedPost, SignerError> {
+        let user_attr = self.sig_attr.attr.clone();
+
+        let now = Utc::now();
+        let id = PostId::generate(
+            now.timestamp_millis() as u64,
+            self.posts.last().map(|sigpost| sigpost.post.id),
+        );
+        let created_at = now.timestamp() as u64;
 
         let post = Post {
             user_attr,
@@ -55,17 +198,48 @@ impl UserHandle {
             created_at,
         };
 
-        let signature = SecretKey::from(self.signing_key).sign(&serde_json::to_vec(&post).unwrap());
+        let signature = self.signer()?.sign(&post.signed_message())?;
 
-        let sigpost = SignedPost {
-            addr: self.addr(),
-            post,
-            signature,
-        };
+        Ok(SignedPost::new(self.addr(), post, signature))
+    }
 
-        self.posts.push(sigpost.clone());
+    // A direct message to the holder of to, for Publisher::send_direct.
+    // Sealing needs the secret key, which an external signer does not give out.
+    pub fn direct_message(
+        &self,
+        to: &PublicKey,
+        text: &str,
+    ) -> Result<SignedPost, DirectMessageError> {
+        if self.external_signer.is_some() {
+            return Err(DirectMessageError::ExternalSigner);
+        }
+        let key = self.signing_key.as_ref().ok_or(SignerError::Locked)?;
+        let dm = DirectMessage::seal(key, to, text)?;
+        Ok(self.sign_post(PostKind::DirectMessage(dm))?)
+    }
 
-        sigpost
+    // The text of a direct message this account sent or received. peer is the
+    // key of the other side, which sigpost has been verified with if it is the sender.
+    pub fn open_direct(
+        &self,
+        sigpost: &SignedPost,
+        peer: &PublicKey,
+    ) -> Result<String, DirectMessageError> {
+        if self.external_signer.is_some() {
+            return Err(DirectMessageError::ExternalSigner);
+        }
+        let dm = match &sigpost.post.content {
+            PostKind::DirectMessage(dm) => dm,
+            _ => return Err(DirectMessageError::NotForMe),
+        };
+        let peer_addr = Address::from(peer.clone());
+        let (sender, recipient) = (&sigpost.addr, &dm.to);
+        let ours = (*sender == self.addr() && *recipient == peer_addr)
+            || (*recipient == self.addr() && *sender == peer_addr);
+        if !ours {
+            return Err(DirectMessageError::NotForMe);
+        }
+        dm.open(self.signing_key.as_ref().ok_or(SignerError::Locked)?, peer)
     }
 
     pub fn hoot(
@@ -74,27 +248,470 @@ impl UserHandle {
         quoted_posts: Option<SignedPost>,
         reply_to: Option<SignedPost>,
         mention_to: Vec<Address>,
-    ) -> SignedPost {
-        let hoot = Hoot {
+    ) -> Result<SignedPost, SignerError> {
+        let mut hoot = Hoot::new(text);
+        hoot.quoted_posts = quoted_posts.map(Box::new);
+        hoot.reply_to = reply_to.map(Box::new);
+        hoot.mention_to = mention_to;
+
+        self.create_post(PostKind::Hoot(hoot))
+    }
+
+    // the addresses mentioned in text, by address or by the name of a following
+    pub fn resolve_mentions(&self, text: &str) -> Result<Vec<Address>, MentionError> {
+        mention::resolve_mentions(
             text,
-            quoted_posts: quoted_posts.map(|sigpost| Box::new(sigpost)),
-            reply_to: reply_to.map(|sigpost| Box::new(sigpost)),
-            mention_to,
-        };
+            self.followings
+                .iter()
+                .filter_map(|(addr, following)| {
+                    following.attr.as_ref().map(|attr| (attr.name.as_str(), addr))
+                }),
+        )
+    }
+
+    pub fn hoot_with_warning(
+        &mut self,
+        text: String,
+        content_warning: Option<String>,
+    ) -> Result<SignedPost, SignerError> {
+        let mut hoot = Hoot::new(text);
+        hoot.sensitive = content_warning.is_some();
+        hoot.content_warning = content_warning;
 
         self.create_post(PostKind::Hoot(hoot))
     }
 
-    pub fn rehoot(&mut self, post: SignedPost) -> SignedPost {
-        self.create_post(PostKind::ReHoot(Box::new(post)))
+    // post has to be signed with pubkey, see SignedPost::rehoot_content
+    pub fn rehoot(
+        &mut self,
+        post: SignedPost,
+        pubkey: &PublicKey,
+    ) -> Result<SignedPost, RehootError> {
+        let content = post.rehoot_content(pubkey)?;
+        Ok(self.create_post(content)?)
     }
 
-    pub fn del(&mut self, id: u128) -> Option<SignedPost> {
-        let i = self
-            .posts
-            .iter()
-            .position(|sigpost| sigpost.post.id == id)?;
+    // Ok(None) if there is no such post
+    pub fn del(&mut self, id: PostId) -> Result<Option<SignedPost>, SignerError> {
+        let i = match self.posts.iter().position(|sigpost| sigpost.post.id == id) {
+            Some(i) => i,
+            None => return Ok(None),
+        };
+        // sign first, so that a failing signer leaves the post in place
+        let sigpost = self.create_post(PostKind::Delete(id))?;
         self.posts.remove(i);
-        Some(self.create_post(PostKind::Delete(id)))
+        Ok(Some(sigpost))
+    }
+
+    // Registers the account's key in the user DHT, see
+    // NetworkController::register_author.
+    pub fn registration(&self) -> Result<RegistrationRecord, SignerError> {
+        let signer = self.signer()?;
+        let registered_at = Utc::now().timestamp() as u64;
+        let message = RegistrationRecord::signed_message(&self.addr(), registered_at);
+        let signature = signer.sign(&message)?;
+        Ok(RegistrationRecord::new(&signer.public_key(), registered_at, signature))
+    }
+
+    // The tombstone for the user DHT and the last post, which tells followers
+    // that the account is gone. Publish the post first, then the tombstone.
+    pub fn delete_account(&mut self) -> Result<(AccountTombstone, SignedPost), SignerError> {
+        let signer = self.signer()?;
+        let deleted_at = Utc::now().timestamp() as u64;
+        let signature = signer.sign(&AccountTombstone::signed_message(&self.addr(), deleted_at))?;
+        let tombstone = AccountTombstone::new(&signer.public_key(), deleted_at, signature);
+        let sigpost = self.create_post(PostKind::DeleteAccount)?;
+        Ok((tombstone, sigpost))
+    }
+
+    // Moves the account to new_key. Returns the record for the user DHT, the
+    // last post of this account, which tells followers where it moved, and the
+    // new account, following the same accounts. Publish the post first.
+    pub fn migrate(
+        &mut self,
+        new_key: SecretKey,
+    ) -> Result<(MigrationRecord, SignedPost, UserHandle), SignerError> {
+        let signer = self.signer()?;
+        let new_addr = Address::from(new_key.public_key());
+        let migrated_at = Utc::now().timestamp() as u64;
+        let message = MigrationRecord::signed_message(&self.addr(), &new_addr, migrated_at);
+        let record = MigrationRecord::new(
+            &signer.public_key(),
+            &new_key.public_key(),
+            migrated_at,
+            signer.sign(&message)?,
+            new_key.sign(&message),
+        );
+
+        let attr = self.sig_attr.attr.clone();
+        let signature = new_key.sign(&attr.signed_message());
+        let sig_attr = SignedUserAttribute::new(new_addr, attr, signature);
+        let mut moved = UserHandle::new(sig_attr, new_key, self.followings.clone(), &[]);
+        moved.collapse_sensitive = self.collapse_sensitive;
+        moved.mention_policy = self.mention_policy;
+        moved.deleted_accounts = self.deleted_accounts.clone();
+        moved.blocked = self.blocked.clone();
+
+        let sigpost = self.create_post(PostKind::Migrate(Box::new(record.clone())))?;
+        Ok((record, sigpost, moved))
+    }
+
+    // Follows the new address in place of the old one. Ok(false) if the old one
+    // was not followed.
+    pub fn apply_migration(&mut self, record: &MigrationRecord) -> Result<bool, VerifyError> {
+        record.verify()?;
+        Ok(follow_moved(&mut self.followings, record))
+    }
+
+    // The archive entry of one of this account's posts, linked to the post
+    // before it.
+    pub fn history_entry(&self, sigpost: &SignedPost) -> Result<HistoryEntry, SignerError> {
+        let prev = match self.posts.iter().position(|p| p.post.id == sigpost.post.id) {
+            Some(i) => i.checked_sub(1).map(|i| self.posts[i].post.id),
+            None => self.posts.last().map(|p| p.post.id),
+        };
+        Ok(HistoryEntry::new(&self.pubkey()?, sigpost.clone(), prev))
+    }
+
+    // points new followers at the newest post
+    pub fn head_record(&self) -> Result<Option<HeadRecord>, SignerError> {
+        let latest = match self.posts.last() {
+            Some(sigpost) => sigpost.post.id,
+            None => return Ok(None),
+        };
+        let signer = self.signer()?;
+        let updated_at = Utc::now().timestamp() as u64;
+        let message = HeadRecord::signed_message(&self.addr(), latest, updated_at);
+        let signature = signer.sign(&message)?;
+        Ok(Some(HeadRecord::new(
+            &signer.public_key(),
+            latest,
+            updated_at,
+            signature,
+        )))
+    }
+
+    // The signed member list to hand to every member.
+    // moves this account's mailbox to epoch, for compaction
+    pub fn mailbox_index(&self, epoch: u64) -> Result<MailboxIndex, SignerError> {
+        let signer = self.signer()?;
+        let compacted_at = Utc::now().timestamp() as u64;
+        let message = MailboxIndex::signed_message(&self.addr(), epoch, compacted_at);
+        let signature = signer.sign(&message)?;
+        Ok(MailboxIndex::new(&signer.public_key(), epoch, compacted_at, signature))
+    }
+
+    // entropy draws the group id, NetworkController::entropy for a node
+    pub fn create_group(
+        &mut self,
+        members: &[Address],
+        entropy: &dyn Entropy,
+    ) -> Result<SignedMembership, GroupError> {
+        let membership = Membership::new(self.addr(), members, entropy);
+        let signature = self.signer()?.sign(&serde_json::to_vec(&membership).unwrap())?;
+        let signed = SignedMembership {
+            membership,
+            signature,
+        };
+        self.groups.push(Group::from_signed(&signed, &self.pubkey()?)?);
+        Ok(signed)
+    }
+
+    // Ok(None) if not in the group. The update tells the other members.
+    pub fn leave_group(
+        &mut self,
+        group_id: &[u8; 16],
+    ) -> Result<Option<SignedMembershipUpdate>, SignerError> {
+        let i = match self.groups.iter().position(|g| g.group_id == *group_id) {
+            Some(i) => i,
+            None => return Ok(None),
+        };
+        let update = MembershipUpdate {
+            group_id: *group_id,
+            version: self.groups[i].next_version(),
+            change: MembershipChange::Leave(self.addr()),
+        };
+        let signature = self.signer()?.sign(&serde_json::to_vec(&update).unwrap())?;
+        self.groups.remove(i);
+        Ok(Some(SignedMembershipUpdate {
+            signer: self.addr(),
+            update,
+            signature,
+        }))
+    }
+
+    // Takes the member list a creator handed out, if this account is on it.
+    pub fn join_group(
+        &mut self,
+        signed: &SignedMembership,
+        creator: &PublicKey,
+    ) -> Result<(), GroupError> {
+        let group = Group::from_signed(signed, creator)?;
+        if !group.is_member(&self.addr()) {
+            return Err(GroupError::NotMember);
+        }
+        self.groups.retain(|g| g.group_id != group.group_id);
+        self.groups.push(group);
+        Ok(())
+    }
+
+    // An update from the creator or a leaving member, signer the key it is signed with
+    pub fn apply_group_update(
+        &mut self,
+        update: &SignedMembershipUpdate,
+        signer: &PublicKey,
+    ) -> Result<(), GroupError> {
+        let addr = self.addr();
+        let group = self
+            .groups
+            .iter_mut()
+            .find(|g| g.group_id == update.update.group_id)
+            .ok_or(GroupError::UnknownGroup)?;
+        group.apply(update, signer)?;
+        if !group.is_member(&addr) {
+            let group_id = update.update.group_id;
+            self.groups.retain(|g| g.group_id != group_id);
+        }
+        Ok(())
+    }
+
+    // A copy of the message for each other member of the group, for
+    // Controller::deliver_mentions to leave in their mailboxes. members holds
+    // their keys. Like a direct message, it needs the secret key.
+    pub fn send_group_dm(
+        &self,
+        group_id: &[u8; 16],
+        members: &[PublicKey],
+        text: &str,
+        entropy: &dyn Entropy,
+    ) -> Result<Vec<SignedPost>, GroupError> {
+        if self.external_signer.is_some() {
+            return Err(DirectMessageError::ExternalSigner.into());
+        }
+        let group = self
+            .groups
+            .iter()
+            .find(|g| g.group_id == *group_id)
+            .ok_or(GroupError::UnknownGroup)?;
+        let key = self.signing_key.as_ref().ok_or(SignerError::Locked)?;
+        GroupMessage::seal(key, group, members, text, entropy)?
+            .into_iter()
+            .map(|gm| Ok(self.sign_post(PostKind::GroupMessage(gm))?))
+            .collect()
+    }
+
+    // The text of a group message sent to this account. sender is the key
+    // sigpost has been verified with.
+    pub fn open_group_dm(
+        &self,
+        sigpost: &SignedPost,
+        sender: &PublicKey,
+    ) -> Result<String, GroupError> {
+        if self.external_signer.is_some() {
+            return Err(DirectMessageError::ExternalSigner.into());
+        }
+        let gm = match &sigpost.post.content {
+            PostKind::GroupMessage(gm) if gm.to == self.addr() => gm,
+            _ => return Err(DirectMessageError::NotForMe.into()),
+        };
+        let group = self
+            .groups
+            .iter()
+            .find(|g| g.group_id == gm.group_id)
+            .ok_or(GroupError::UnknownGroup)?;
+        if sigpost.addr != Address::from(sender.clone()) || !group.is_member(&sigpost.addr) {
+            return Err(GroupError::NotMember);
+        }
+        gm.open(self.signing_key.as_ref().ok_or(SignerError::Locked)?, sender)
+    }
+
+    pub fn backup(&self) -> BackupContents {
+        BackupContents {
+            profile: Some(self.sig_attr.clone()),
+            followings: self.followings.clone().into_iter().collect(),
+            deleted_accounts: self.deleted_accounts.clone(),
+            collapse_sensitive: self.collapse_sensitive,
+            link_previews: self.link_previews,
+            mention_policy: self.mention_policy,
+            blocked: self.blocked.clone(),
+        }
+    }
+
+    // Takes over a backup of this account: followings are added to the ones
+    // here, and the profile and settings replace them.
+    pub fn restore(&mut self, contents: BackupContents) {
+        if let Some(profile) = contents.profile {
+            let verified = self.pubkey().is_ok_and(|pk| profile.verify(&pk).is_ok());
+            if profile.addr == self.addr() && verified {
+                self.sig_attr = profile;
+            }
+        }
+        for (addr, following) in contents.followings {
+            if !contents.deleted_accounts.contains(&addr) {
+                self.followings.entry(addr).or_insert(following);
+            }
+        }
+        for addr in contents.deleted_accounts {
+            self.mark_deleted(addr);
+        }
+        self.collapse_sensitive = contents.collapse_sensitive;
+        self.link_previews = contents.link_previews;
+        self.mention_policy = contents.mention_policy;
+        for addr in contents.blocked.iter() {
+            self.blocked.block(addr.clone());
+        }
+    }
+
+    // The mailbox items this account takes under its mention policy, and the
+    // others, to be reviewed as requests
+    pub fn sort_mailbox(&self, items: Vec<MailboxItem>) -> (Vec<MailboxItem>, Vec<MailboxItem>) {
+        items.into_iter().partition(|item| {
+            self.mention_policy
+                .accepts(&item.sigpost.addr, |addr| self.followings.contains_key(addr))
+        })
+    }
+
+    pub fn mark_deleted(&mut self, addr: Address) {
+        self.followings.remove(&addr);
+        self.deleted_accounts.insert(addr);
+    }
+
+    // who to follow next, judging from what the followings rehoot and mention in posts
+    pub fn recommend_follows(&self, posts: &[SignedPost], limit: usize) -> Vec<Recommendation> {
+        recommend::recommend_follows(
+            posts,
+            &self.addr(),
+            |addr| self.followings.contains_key(addr),
+            limit,
+        )
+    }
+
+    pub fn export_activitystreams(&self) -> serde_json::Value {
+        activitystreams::outbox(&self.addr(), &self.posts)
+    }
+}
+
+// An account whose key has been unlocked with its password, for a session.
+// It is not Serialize: what is written is the UserHandle lock gives back,
+// which holds the key only sealed.
+pub struct UnlockedUserHandle(UserHandle);
+
+impl UnlockedUserHandle {
+    // an account whose key is not sealed, like a new one or one held by an external signer
+    pub fn new(handle: UserHandle) -> Result<UnlockedUserHandle, SignerError> {
+        if handle.is_locked() {
+            return Err(SignerError::Locked);
+        }
+        Ok(UnlockedUserHandle(handle))
+    }
+
+    pub fn lock(self) -> UserHandle {
+        let mut handle = self.0;
+        handle.lock();
+        handle
+    }
+}
+
+impl Deref for UnlockedUserHandle {
+    type Target = UserHandle;
+
+    fn deref(&self) -> &UserHandle {
+        &self.0
+    }
+}
+
+impl DerefMut for UnlockedUserHandle {
+    fn deref_mut(&mut self) -> &mut UserHandle {
+        &mut self.0
+    }
+}
+
+// an account with a fresh key and a signed profile named name
+#[cfg(test)]
+pub(crate) fn test_user(name: &str) -> UserHandle {
+    let sk = SecretKey::random();
+    let attr = crate::user::user::UserAttribute::new(name, 0, "");
+    let signature = sk.sign(&attr.signed_message());
+    let sig_attr = SignedUserAttribute::new(sk.public_key().into(), attr, signature);
+    UserHandle::new(sig_attr, sk, HashMap::new(), &[])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::rng::{SeededEntropy, SystemEntropy};
+
+    #[test]
+    fn sealed_key_test() {
+        let mut handle = test_user("alice");
+        let sk = handle.signing_key.clone().unwrap();
+        assert!(handle.needs_sealing());
+        // seal_key takes KEYSTORE_MEMORY, too slow for a debug build
+        let entropy = SeededEntropy::new(1);
+        handle.sealed_key = Some(SealedKey::seal_with(&sk, "hunter2", 8, 1, &entropy).unwrap());
+
+        let json = serde_json::to_string(&handle).unwrap();
+        assert!(!json.contains("signing_key"));
+        let mut loaded: UserHandle = serde_json::from_str(&json).unwrap();
+        assert!(loaded.is_locked() && !loaded.needs_sealing());
+        assert_eq!(loaded.addr(), handle.addr());
+        assert!(matches!(loaded.hoot("hi".to_string(), None, None, vec![]), Err(SignerError::Locked)));
+        assert!(matches!(UnlockedUserHandle::new(loaded.clone()), Err(SignerError::Locked)));
+
+        assert!(matches!(loaded.unlock("hunter3"), Err(KeystoreError::WrongPassword)));
+        let mut unlocked = loaded.unlock("hunter2").unwrap();
+        assert_eq!(unlocked.signing_key, Some(sk.clone()));
+        assert!(unlocked.hoot("hi".to_string(), None, None, vec![]).is_ok());
+        assert!(loaded.is_locked());
+        loaded = unlocked.lock();
+        assert!(loaded.is_locked() && loaded.posts.len() == 1);
+
+        // written before the keystore
+        let mut legacy: serde_json::Value = serde_json::from_str(&json).unwrap();
+        legacy["signing_key"] = serde_json::to_value(&sk).unwrap();
+        legacy.as_object_mut().unwrap().remove("sealed_key");
+        let legacy: UserHandle = serde_json::from_value(legacy).unwrap();
+        assert!(!legacy.is_locked() && legacy.needs_sealing());
+
+        // saved before it was sealed, the key is lost
+        let mut keyless = legacy.clone();
+        keyless.signing_key = None;
+        assert!(matches!(keyless.signer(), Err(SignerError::NoKey)));
+        assert!(matches!(keyless.pubkey(), Err(SignerError::NoKey)));
+        assert_eq!(keyless.addr(), handle.addr());
+    }
+
+    #[test]
+    fn group_dm_test() {
+        let (mut alice, mut bob, mut carol) =
+            (test_user("alice"), test_user("bob"), test_user("carol"));
+        let signed = alice.create_group(&[bob.addr(), carol.addr()], &SystemEntropy).unwrap();
+        let group_id = signed.membership.group_id;
+        bob.join_group(&signed, &alice.pubkey().unwrap()).unwrap();
+        carol.join_group(&signed, &alice.pubkey().unwrap()).unwrap();
+
+        // a copy for each other member, each left in that member's mailbox
+        let keys = [bob.pubkey().unwrap(), carol.pubkey().unwrap()];
+        let copies = alice.send_group_dm(&group_id, &keys, "lunch?", &SystemEntropy).unwrap();
+        assert_eq!(copies.len(), 2);
+        for copy in &copies {
+            assert_eq!(MailboxItem::recipients(copy), vec![copy.direct_to().unwrap().clone()]);
+        }
+        let (to_bob, to_carol) = (&copies[0], &copies[1]);
+        assert_eq!(bob.open_group_dm(to_bob, &alice.pubkey().unwrap()).unwrap(), "lunch?");
+        assert_eq!(carol.open_group_dm(to_carol, &alice.pubkey().unwrap()).unwrap(), "lunch?");
+        assert!(bob.open_group_dm(to_carol, &alice.pubkey().unwrap()).is_err());
+
+        // once carol leaves, her messages are not taken
+        let leave = carol.leave_group(&group_id).unwrap().unwrap();
+        bob.apply_group_update(&leave, &carol.pubkey().unwrap()).unwrap();
+        carol.join_group(&signed, &alice.pubkey().unwrap()).unwrap();
+        let keys = [alice.pubkey().unwrap(), bob.pubkey().unwrap()];
+        let late = carol.send_group_dm(&group_id, &keys, "hi", &SystemEntropy).unwrap();
+        let to_bob = late.iter().find(|c| c.direct_to() == Some(&bob.addr())).unwrap();
+        assert!(matches!(
+            bob.open_group_dm(to_bob, &carol.pubkey().unwrap()),
+            Err(GroupError::NotMember)
+        ));
     }
 }
@@ -0,0 +1,156 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::crypto::PublicKey;
+use crate::user::user::{edit_distance, Address, SignedUserAttribute, UserAttribute, VerifyError};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserMatch {
+    pub addr: Address,
+    pub name: String,
+    // 1.0 for an exact name match, lower for looser ones
+    pub confidence: f32,
+}
+
+// Profiles seen so far (from verified posts or signed attributes), searchable
+// by name, description and address.
+#[derive(Debug, Clone, Default)]
+pub struct UserIndex {
+    users: HashMap<Address, UserAttribute>,
+}
+
+impl UserIndex {
+    pub fn new() -> UserIndex {
+        UserIndex::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    // the attribute has to come from something verified, like the post carrying it
+    pub fn insert(&mut self, addr: Address, attr: UserAttribute) {
+        self.users.insert(addr, attr);
+    }
+
+    pub fn insert_signed(
+        &mut self,
+        sig_attr: &SignedUserAttribute,
+        pubkey: &PublicKey,
+    ) -> Result<(), VerifyError> {
+        sig_attr.verify(pubkey)?;
+        self.insert(sig_attr.addr.clone(), sig_attr.attr.clone());
+        Ok(())
+    }
+
+    pub fn remove(&mut self, addr: &Address) {
+        self.users.remove(addr);
+    }
+
+    pub fn get(&self, addr: &Address) -> Option<&UserAttribute> {
+        self.users.get(addr)
+    }
+
+    // every display name with its address, to resolve mentions with
+    pub fn names(&self) -> impl Iterator<Item = (&str, &Address)> {
+        self.users.iter().map(|(addr, attr)| (attr.name.as_str(), addr))
+    }
+
+    // Best matches first, at most limit of them.
+    pub fn find_user(&self, query: &str, limit: usize) -> Vec<UserMatch> {
+        let raw = query.trim().trim_start_matches('@');
+        let query = raw.to_lowercase();
+        if query.is_empty() {
+            return Vec::new();
+        }
+        let mut matches: Vec<UserMatch> = self
+            .users
+            .iter()
+            .filter_map(|(addr, attr)| {
+                let confidence = score(&query, raw, addr, attr);
+                (confidence > 0.0).then(|| UserMatch {
+                    addr: addr.clone(),
+                    name: attr.name.clone(),
+                    confidence,
+                })
+            })
+            .collect();
+        matches.sort_by(|a, b| {
+            b.confidence
+                .total_cmp(&a.confidence)
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        matches.truncate(limit);
+        matches
+    }
+}
+
+// query is lowercased, addresses are compared with the raw one
+fn score(query: &str, raw: &str, addr: &Address, attr: &UserAttribute) -> f32 {
+    let name = attr.name.to_lowercase();
+    if name == query {
+        return 1.0;
+    }
+    if name.starts_with(query) {
+        return 0.9;
+    }
+    if addr.to_string().starts_with(raw) {
+        return 0.8;
+    }
+    if name.contains(query) {
+        return 0.7;
+    }
+
+    // typos: compare with the part of the name as long as the query
+    let len = query.chars().count();
+    let head: String = name.chars().take(len).collect();
+    let distance = edit_distance(query, &head).min(edit_distance(query, &name));
+    if distance <= (len / 4).max(1) && distance < len {
+        return 0.6 * (1.0 - distance as f32 / len as f32);
+    }
+
+    if attr
+        .description
+        .to_lowercase()
+        .split_whitespace()
+        .any(|word| word.starts_with(query))
+    {
+        return 0.3;
+    }
+    0.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::SecretKey;
+
+    #[test]
+    fn find_user_test() {
+        let mut index = UserIndex::new();
+        let addrs: Vec<Address> = (0..4)
+            .map(|_| Address::from(SecretKey::random().public_key()))
+            .collect();
+        index.insert(addrs[0].clone(), UserAttribute::new("alice", 0, ""));
+        index.insert(addrs[1].clone(), UserAttribute::new("Alicia", 0, ""));
+        index.insert(addrs[2].clone(), UserAttribute::new("bob", 0, "friend of alice"));
+        index.insert(addrs[3].clone(), UserAttribute::new("malice", 0, ""));
+
+        let found = index.find_user("Alice", 10);
+        let order: Vec<_> = found.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(order, vec!["alice", "malice", "Alicia", "bob"]);
+        assert_eq!(found[0].confidence, 1.0);
+
+        // a typo still finds the account
+        let found = index.find_user("alise", 1);
+        assert_eq!(found[0].addr, addrs[0]);
+
+        let prefix = addrs[2].to_string()[..6].to_string();
+        assert_eq!(index.find_user(&format!("@{}", prefix), 1)[0].addr, addrs[2]);
+        assert!(index.find_user("", 10).is_empty());
+    }
+}
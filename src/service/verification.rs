@@ -0,0 +1,240 @@
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::crypto::PublicKey;
+use crate::user::post::{PostKind, SignedPost};
+use crate::user::post_id::PostId;
+use crate::user::time_format::TimeFormat;
+use crate::user::user::Address;
+use crate::util::crypto_pool;
+
+use super::{AccountStatus, UserDHT};
+
+// where the key a post was checked with came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeySource {
+    // known already, like the keys of followed accounts
+    Cache,
+    // looked up in the user DHT for this report
+    Dht,
+    // carried by the rehoot embedding the post, see SignedPost::attached_key
+    Attached,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SignatureStatus {
+    Valid,
+    Invalid,
+    // no key is registered for the address
+    UnknownKey,
+    AccountDeleted,
+    AccountMoved(Address),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Embedding {
+    Quote,
+    Reply,
+    ReHoot,
+}
+
+// How a post checks out: its signature, where the key came from, the posts it
+// quotes, replies to or rehoots, and when it was delivered.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VerificationReport {
+    pub addr: Address,
+    pub id: PostId,
+    pub signature: SignatureStatus,
+    pub key_source: Option<KeySource>,
+    pub created_at: u64,
+    // ms since the epoch, when this client got the post; None for embedded posts
+    pub received_at: Option<u64>,
+    pub embedded: Vec<(Embedding, VerificationReport)>,
+}
+
+impl VerificationReport {
+    // Checks sigpost and the posts embedded in it. Keys missing from known
+    // are looked up in the user DHT.
+    pub async fn build(
+        dht: &UserDHT,
+        sigpost: &SignedPost,
+        known: &HashMap<Address, PublicKey>,
+        received_at: Option<u64>,
+    ) -> VerificationReport {
+        let mut report = VerificationReport::build_embedded(dht, sigpost, known).await;
+        report.received_at = received_at;
+        report
+    }
+
+    fn build_embedded<'a>(
+        dht: &'a UserDHT,
+        sigpost: &'a SignedPost,
+        known: &'a HashMap<Address, PublicKey>,
+    ) -> BoxFuture<'a, VerificationReport> {
+        Box::pin(async move {
+            let key = match known.get(&sigpost.addr) {
+                Some(pk) => Some((pk, KeySource::Cache)),
+                None => sigpost.attached_key().map(|pk| (pk, KeySource::Attached)),
+            };
+            let (signature, key_source) = match key {
+                Some((pk, source)) => (check(sigpost, pk.clone()).await, Some(source)),
+                None => match dht.get_account(sigpost.addr.clone()).await {
+                    Some(AccountStatus::Active(pk)) => {
+                        (check(sigpost, pk).await, Some(KeySource::Dht))
+                    }
+                    Some(AccountStatus::Deleted(_)) => (SignatureStatus::AccountDeleted, None),
+                    Some(AccountStatus::Migrated(record)) => {
+                        (SignatureStatus::AccountMoved(record.new_addr()), None)
+                    }
+                    None => (SignatureStatus::UnknownKey, None),
+                },
+            };
+
+            let mut embedded = Vec::new();
+            match &sigpost.post.content {
+                PostKind::Hoot(hoot) => {
+                    if let Some(quoted) = &hoot.quoted_posts {
+                        let report = VerificationReport::build_embedded(dht, quoted, known).await;
+                        embedded.push((Embedding::Quote, report));
+                    }
+                    if let Some(to) = &hoot.reply_to {
+                        let report = VerificationReport::build_embedded(dht, to, known).await;
+                        embedded.push((Embedding::Reply, report));
+                    }
+                }
+                PostKind::ReHoot(inner) => {
+                    let report = VerificationReport::build_embedded(dht, inner, known).await;
+                    embedded.push((Embedding::ReHoot, report));
+                }
+                _ => (),
+            }
+
+            VerificationReport {
+                addr: sigpost.addr.clone(),
+                id: sigpost.post.id,
+                signature,
+                key_source,
+                created_at: sigpost.post.created_at,
+                received_at: None,
+                embedded,
+            }
+        })
+    }
+
+    // the post and everything embedded in it are signed by their authors
+    pub fn is_valid(&self) -> bool {
+        self.signature == SignatureStatus::Valid
+            && self.embedded.iter().all(|(_, report)| report.is_valid())
+    }
+
+    pub fn delivery_delay_ms(&self) -> Option<u64> {
+        self.received_at
+            .map(|received_at| received_at.saturating_sub(self.created_at * 1000))
+    }
+
+    fn write_indented(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        let indent = "  ".repeat(depth);
+        writeln!(f, "{}post {} by @{}", indent, self.id, self.addr.to_string())?;
+        let signature = match &self.signature {
+            SignatureStatus::Valid => "valid".to_string(),
+            SignatureStatus::Invalid => "INVALID".to_string(),
+            SignatureStatus::UnknownKey => "unchecked, no key registered".to_string(),
+            SignatureStatus::AccountDeleted => "unchecked, the account is deleted".to_string(),
+            SignatureStatus::AccountMoved(addr) => {
+                format!("unchecked, the account moved to @{}", addr.to_string())
+            }
+        };
+        writeln!(f, "{}  signature: {}", indent, signature)?;
+        match self.key_source {
+            Some(KeySource::Cache) => writeln!(f, "{}  key: known", indent)?,
+            Some(KeySource::Dht) => writeln!(f, "{}  key: user DHT", indent)?,
+            Some(KeySource::Attached) => writeln!(f, "{}  key: carried by the rehoot", indent)?,
+            None => (),
+        }
+        let format = TimeFormat::default();
+        writeln!(f, "{}  created: {}", indent, format.absolute(self.created_at))?;
+        if let (Some(received_at), Some(delay)) = (self.received_at, self.delivery_delay_ms()) {
+            writeln!(
+                f,
+                "{}  received: {} ({:.1}s later)",
+                indent,
+                format.absolute_ms(received_at),
+                delay as f64 / 1000.0
+            )?;
+        }
+        for (embedding, report) in &self.embedded {
+            let kind = match embedding {
+                Embedding::Quote => "quotes",
+                Embedding::Reply => "replies to",
+                Embedding::ReHoot => "rehoots",
+            };
+            writeln!(f, "{}  {}:", indent, kind)?;
+            report.write_indented(f, depth + 2)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for VerificationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_indented(f, 0)
+    }
+}
+
+async fn check(sigpost: &SignedPost, pubkey: PublicKey) -> SignatureStatus {
+    let sigpost = sigpost.clone();
+    crypto_pool::run(move || match sigpost.verify(&pubkey) {
+        Ok(()) => SignatureStatus::Valid,
+        Err(_) => SignatureStatus::Invalid,
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kad::Rpc;
+    use crate::service::{test_user, user_network, TESTNET_USER_DHT};
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    #[tokio::test]
+    async fn verification_report_test() {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let rpc = Arc::new(Mutex::new(Rpc::new(socket)));
+        let dht = UserDHT::start(rpc, &user_network(TESTNET_USER_DHT, 32), &[], None).await;
+        let (mut alice, mut bob) = (test_user("alice"), test_user("bob"));
+        let known = HashMap::from([(alice.addr(), alice.pubkey().unwrap())]);
+
+        let quoted = bob.hoot("original".to_string(), None, None, vec![]).unwrap();
+        let sigpost = alice
+            .hoot("look".to_string(), Some(quoted.clone()), None, vec![])
+            .unwrap();
+        let received_at = sigpost.post.created_at * 1000 + 1500;
+        let report = VerificationReport::build(&dht, &sigpost, &known, Some(received_at)).await;
+        assert_eq!(report.signature, SignatureStatus::Valid);
+        assert_eq!(report.key_source, Some(KeySource::Cache));
+        assert_eq!(report.delivery_delay_ms(), Some(1500));
+        // nobody registered bob's key
+        let (embedding, quote) = &report.embedded[0];
+        assert_eq!(*embedding, Embedding::Quote);
+        assert_eq!(quote.signature, SignatureStatus::UnknownKey);
+        assert!(!report.is_valid());
+
+        // a rehoot of bob's post carries his key, so it checks without a lookup
+        let rehoot = alice.rehoot(quoted, &bob.pubkey().unwrap()).unwrap();
+        let report = VerificationReport::build(&dht, &rehoot, &known, None).await;
+        let (embedding, rehooted) = &report.embedded[0];
+        assert_eq!(*embedding, Embedding::ReHoot);
+        assert_eq!(rehooted.key_source, Some(KeySource::Attached));
+        assert!(report.is_valid());
+
+        let mut forged = sigpost;
+        forged.post.created_at += 1;
+        let report = VerificationReport::build(&dht, &forged, &known, None).await;
+        assert_eq!(report.signature, SignatureStatus::Invalid);
+        assert!(report.to_string().contains("INVALID"));
+    }
+}
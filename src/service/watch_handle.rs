@@ -0,0 +1,91 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::crypto::{ExternalSigner, SecretKey};
+use crate::user::following::Following;
+use crate::user::user::{Address, MigrationRecord, SignedUserAttribute, VerifyError};
+use serde::{Deserialize, Serialize};
+
+use super::user_handle::follow_moved;
+use super::UserHandle;
+
+// A session without an identity: it can follow and read, but never signs.
+// Upgrading keeps the followings, so nothing has to be followed again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WatchHandle {
+    pub followings: HashMap<Address, Following>,
+    pub collapse_sensitive: bool,
+    pub deleted_accounts: HashSet<Address>,
+}
+
+impl WatchHandle {
+    pub fn new() -> WatchHandle {
+        WatchHandle {
+            followings: HashMap::new(),
+            collapse_sensitive: true,
+            deleted_accounts: HashSet::new(),
+        }
+    }
+
+    pub fn mark_deleted(&mut self, addr: Address) {
+        self.followings.remove(&addr);
+        self.deleted_accounts.insert(addr);
+    }
+
+    // Ok(false) if the old address was not followed
+    pub fn apply_migration(&mut self, record: &MigrationRecord) -> Result<bool, VerifyError> {
+        record.verify()?;
+        Ok(follow_moved(&mut self.followings, record))
+    }
+
+    pub fn upgrade(self, sig_attr: SignedUserAttribute, signing_key: SecretKey) -> UserHandle {
+        let mut handle = UserHandle::new(sig_attr, signing_key, self.followings, &[]);
+        handle.collapse_sensitive = self.collapse_sensitive;
+        handle.deleted_accounts = self.deleted_accounts;
+        handle
+    }
+
+    pub fn upgrade_with_external_signer(
+        self,
+        sig_attr: SignedUserAttribute,
+        signer: ExternalSigner,
+    ) -> UserHandle {
+        let mut handle = UserHandle::with_external_signer(sig_attr, signer, self.followings, &[]);
+        handle.collapse_sensitive = self.collapse_sensitive;
+        handle.deleted_accounts = self.deleted_accounts;
+        handle
+    }
+}
+
+impl Default for WatchHandle {
+    fn default() -> WatchHandle {
+        WatchHandle::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::test_user;
+
+    #[test]
+    fn upgrade_test() {
+        let followed = Address::from(SecretKey::random().public_key());
+        let deleted = Address::from(SecretKey::random().public_key());
+        let mut watch = WatchHandle::new();
+        watch.followings.insert(followed.clone(), Following::default());
+        watch.followings.insert(deleted.clone(), Following::default());
+        watch.mark_deleted(deleted.clone());
+        watch.collapse_sensitive = false;
+
+        let user = test_user("watcher");
+        let mut handle = watch.upgrade(user.sig_attr, user.signing_key.unwrap());
+
+        assert!(handle.followings.contains_key(&followed));
+        assert!(!handle.followings.contains_key(&deleted));
+        assert!(handle.deleted_accounts.contains(&deleted));
+        assert!(!handle.collapse_sensitive);
+        assert!(handle
+            .hoot("now with an account".to_string(), None, None, vec![])
+            .is_ok());
+    }
+}
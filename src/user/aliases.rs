@@ -0,0 +1,117 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+use super::user::Address;
+
+// Petnames: what this user calls the accounts they know, kept locally and
+// never published. Unlike the name in a profile, which its owner picks and
+// anyone may copy, a petname cannot be spoofed. One petname per address.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AliasBook {
+    aliases: BTreeMap<String, Address>,
+}
+
+impl AliasBook {
+    pub fn new() -> AliasBook {
+        AliasBook::default()
+    }
+
+    // anything unreadable gives an empty book
+    pub fn from_bytes(bytes: &[u8]) -> AliasBook {
+        serde_json::from_slice(bytes).unwrap_or_default()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+
+    // Names addr, replacing its earlier petname; the address the name was
+    // given to before, if any, loses it.
+    pub fn set(&mut self, name: &str, addr: Address) -> Result<Option<Address>, AliasError> {
+        if name.is_empty() {
+            return Err(AliasError::Empty);
+        }
+        if name.chars().any(char::is_whitespace) {
+            return Err(AliasError::Whitespace);
+        }
+        // so that an address is never taken for a petname
+        if Address::parse_strict(name).is_ok() {
+            return Err(AliasError::Address);
+        }
+        self.aliases.retain(|_, a| *a != addr);
+        Ok(self.aliases.insert(name.to_string(), addr))
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Address> {
+        self.aliases.remove(name)
+    }
+
+    // moves the petname of old, if it has one, to new
+    pub fn rebind(&mut self, old: &Address, new: Address) -> Option<String> {
+        let name = self.name_of(old)?.to_string();
+        self.aliases.retain(|_, a| *a != new);
+        self.aliases.insert(name.clone(), new);
+        Some(name)
+    }
+
+    pub fn resolve(&self, name: &str) -> Option<&Address> {
+        self.aliases.get(name)
+    }
+
+    pub fn name_of(&self, addr: &Address) -> Option<&str> {
+        self.aliases
+            .iter()
+            .find(|(_, a)| *a == addr)
+            .map(|(name, _)| name.as_str())
+    }
+
+    // by petname
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Address)> {
+        self.aliases.iter().map(|(name, addr)| (name.as_str(), addr))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.aliases.is_empty()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum AliasError {
+    #[error("A petname cannot be empty")]
+    Empty,
+    #[error("A petname cannot contain spaces")]
+    Whitespace,
+    #[error("A petname cannot be an address")]
+    Address,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alias_book_test() {
+        let (alice, bob) = (Address::new([1; 32]), Address::new([2; 32]));
+        let mut book = AliasBook::new();
+        assert!(book.set("al", alice.clone()).unwrap().is_none());
+        assert!(matches!(book.set("a b", bob.clone()), Err(AliasError::Whitespace)));
+        assert!(matches!(book.set(&bob.to_string(), bob.clone()), Err(AliasError::Address)));
+
+        // renaming drops the old petname, taking a petname moves it, and so
+        // does the account the petname is for when it moves
+        book.set("alice", alice.clone()).unwrap();
+        assert_eq!(book.resolve("al"), None);
+        assert_eq!(book.set("alice", bob.clone()).unwrap(), Some(alice.clone()));
+        assert_eq!(book.name_of(&alice), None);
+        assert_eq!(book.name_of(&bob), Some("alice"));
+
+        let saved = AliasBook::from_bytes(&book.to_bytes());
+        assert_eq!(saved, book);
+        assert_eq!(book.rebind(&bob, alice.clone()).as_deref(), Some("alice"));
+        assert_eq!(book.rebind(&bob, alice.clone()), None);
+        assert_eq!(book.resolve("alice"), Some(&alice));
+        assert_eq!(book.remove("alice"), Some(alice));
+        assert!(book.is_empty());
+    }
+}
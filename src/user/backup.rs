@@ -0,0 +1,233 @@
+use super::block::BlockList;
+use super::following::Following;
+use super::mention::MentionPolicy;
+use super::user::{Address, SignedUserAttribute, VerifyError};
+use crate::crypto::secretbox::{self, SecretBoxError};
+use crate::crypto::{PublicKey, Signer, SignerError};
+
+use serde::{Deserialize, Serialize};
+use serde_big_array::BigArray;
+use std::collections::HashSet;
+use std::convert::{TryFrom, TryInto};
+use thiserror::Error;
+
+// A record has to fit in one DHT message, like a mailbox chunk
+pub const BACKUP_RECORD_LEN: usize = 1800;
+pub const BACKUP_HEADER_LEN: usize = 122;
+pub const BACKUP_MAX_CHUNKS: u16 = 64;
+const BACKUP_MAGIC: &[u8; 14] = b"noktulo:backup";
+
+// What an account keeps besides its key, so that a new device which imports
+// the key can carry on with the same followings and settings.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BackupContents {
+    pub profile: Option<SignedUserAttribute>,
+    // pairs, as JSON map keys have to be strings
+    pub followings: Vec<(Address, Following)>,
+    pub deleted_accounts: HashSet<Address>,
+    pub collapse_sensitive: bool,
+    pub link_previews: bool,
+    #[serde(default)]
+    pub mention_policy: MentionPolicy,
+    pub blocked: BlockList,
+}
+
+// One chunk of an encrypted backup. Every chunk is signed by the account, so
+// DHT nodes only let its owner replace it, and only with a newer backup.
+// Binary layout: magic(14) | pubkey(32) | signature(64) | updated_at(8, BE) |
+// index(2, BE) | total(2, BE) | data
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct BackupRecord {
+    pub pubkey: PublicKey,
+    #[serde(with = "BigArray")]
+    pub signature: [u8; 64],
+    pub updated_at: u64,
+    pub index: u16,
+    pub total: u16,
+    pub data: Vec<u8>,
+}
+
+impl BackupRecord {
+    pub fn addr(&self) -> Address {
+        Address::from(self.pubkey.clone())
+    }
+
+    // the message signed by the account key
+    pub fn signed_message(
+        addr: &Address,
+        updated_at: u64,
+        index: u16,
+        total: u16,
+        data: &[u8],
+    ) -> Vec<u8> {
+        let addr_bytes: [u8; 32] = addr.clone().into();
+        [
+            &b"noktulo:backup:"[..],
+            &addr_bytes[..],
+            &updated_at.to_be_bytes()[..],
+            &index.to_be_bytes()[..],
+            &total.to_be_bytes()[..],
+            data,
+        ]
+        .concat()
+    }
+
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        let message = BackupRecord::signed_message(
+            &self.addr(),
+            self.updated_at,
+            self.index,
+            self.total,
+            &self.data,
+        );
+        self.pubkey
+            .verify(&self.signature, &message)
+            .map_err(VerifyError::Signature)
+    }
+
+    // whether new is a chunk of the same account's backup, made no earlier
+    pub fn is_replaced_by(&self, new: &BackupRecord) -> bool {
+        self.pubkey == new.pubkey && self.index == new.index && self.updated_at <= new.updated_at
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [
+            &BACKUP_MAGIC[..],
+            &self.pubkey.to_bytes()[..],
+            &self.signature[..],
+            &self.updated_at.to_be_bytes()[..],
+            &self.index.to_be_bytes()[..],
+            &self.total.to_be_bytes()[..],
+            &self.data[..],
+        ]
+        .concat()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<BackupRecord, VerifyError> {
+        if bytes.len() <= BACKUP_HEADER_LEN
+            || bytes.len() > BACKUP_RECORD_LEN
+            || !bytes.starts_with(BACKUP_MAGIC)
+        {
+            return Err(VerifyError::Size);
+        }
+        let bytes = &bytes[BACKUP_MAGIC.len()..];
+        let record = BackupRecord {
+            pubkey: PublicKey::try_from(&bytes[..32]).map_err(VerifyError::Signature)?,
+            signature: bytes[32..96].try_into().unwrap(),
+            updated_at: u64::from_be_bytes(bytes[96..104].try_into().unwrap()),
+            index: u16::from_be_bytes(bytes[104..106].try_into().unwrap()),
+            total: u16::from_be_bytes(bytes[106..108].try_into().unwrap()),
+            data: bytes[108..].to_vec(),
+        };
+        if record.index >= record.total || record.total > BACKUP_MAX_CHUNKS {
+            return Err(VerifyError::Size);
+        }
+        Ok(record)
+    }
+}
+
+// The key backups are encrypted with, derived from the account key, so any
+// device holding the account key derives the same one. External signers
+// only sign, so accounts using one have no backups.
+pub fn backup_key(signer: &dyn Signer) -> Result<[u8; 32], SignerError> {
+    signer.derive_key(b"noktulo:backup")
+}
+
+// Encrypts contents and splits it into signed records
+pub fn seal_backup(
+    signer: &dyn Signer,
+    contents: &BackupContents,
+    updated_at: u64,
+) -> Result<Vec<BackupRecord>, BackupError> {
+    let sealed = secretbox::seal(&backup_key(signer)?, &serde_json::to_vec(contents).unwrap());
+    let chunks: Vec<&[u8]> = sealed.chunks(BACKUP_RECORD_LEN - BACKUP_HEADER_LEN).collect();
+    if chunks.len() > BACKUP_MAX_CHUNKS as usize {
+        return Err(BackupError::TooLarge);
+    }
+    let (pubkey, total) = (signer.public_key(), chunks.len() as u16);
+    let addr = Address::from(pubkey.clone());
+    let mut ret = Vec::new();
+    for (index, data) in chunks.into_iter().enumerate() {
+        let index = index as u16;
+        let message = BackupRecord::signed_message(&addr, updated_at, index, total, data);
+        ret.push(BackupRecord {
+            pubkey: pubkey.clone(),
+            signature: signer.sign(&message)?,
+            updated_at,
+            index,
+            total,
+            data: data.to_vec(),
+        });
+    }
+    Ok(ret)
+}
+
+// records have to be every chunk of one backup, in order
+pub fn open_backup(
+    signer: &dyn Signer,
+    records: &[BackupRecord],
+) -> Result<BackupContents, BackupError> {
+    let first = records.first().ok_or(BackupError::Incomplete)?;
+    let complete = records.len() == first.total as usize
+        && records.iter().enumerate().all(|(i, r)| {
+            r.index as usize == i && r.updated_at == first.updated_at && r.pubkey == first.pubkey
+        });
+    if !complete {
+        return Err(BackupError::Incomplete);
+    }
+    let sealed: Vec<u8> = records.iter().flat_map(|r| r.data.iter().copied()).collect();
+    let plaintext =
+        secretbox::open(&backup_key(signer)?, &sealed).map_err(BackupError::Decrypt)?;
+    serde_json::from_slice(&plaintext).map_err(|_| BackupError::Malformed)
+}
+
+#[derive(Debug, Error)]
+pub enum BackupError {
+    #[error(transparent)]
+    Signer(#[from] SignerError),
+    #[error("The backup is too large")]
+    TooLarge,
+    #[error("Not every part of the backup was found")]
+    Incomplete,
+    #[error("Cannot decrypt the backup: {0}")]
+    Decrypt(SecretBoxError),
+    #[error("Malformed backup")]
+    Malformed,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::SecretKey;
+    use crate::user::user::UserAttribute;
+
+    #[test]
+    fn backup_test() {
+        let sk = SecretKey::random();
+        let mut contents = BackupContents::default();
+        // large enough for a few chunks
+        for i in 0..40u8 {
+            let attr = UserAttribute::new(&format!("user {}", i), 0, "a profile");
+            contents.followings.push((Address::new([i; 32]), Following::new(Some(attr), 0)));
+        }
+        let records = seal_backup(&sk, &contents, 1000).unwrap();
+        assert!(records.len() > 1);
+        for record in records.iter() {
+            let bytes = record.to_bytes();
+            assert!(bytes.len() <= BACKUP_RECORD_LEN);
+            let decoded = BackupRecord::from_bytes(&bytes).unwrap();
+            assert!(decoded.verify().is_ok());
+        }
+        assert_eq!(open_backup(&sk, &records).unwrap(), contents);
+
+        // another key cannot read it
+        let other = SecretKey::random();
+        assert!(matches!(open_backup(&other, &records), Err(BackupError::Decrypt(_))));
+        assert!(matches!(open_backup(&sk, &records[1..]), Err(BackupError::Incomplete)));
+
+        let newer = seal_backup(&sk, &contents, 2000).unwrap();
+        assert!(records[0].is_replaced_by(&newer[0]));
+        assert!(!newer[0].is_replaced_by(&records[0]));
+    }
+}
@@ -0,0 +1,109 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+use super::post::{PostKind, SignedPost};
+use super::user::Address;
+
+// The accounts a user never wants to see. Kept locally with the account and
+// never published; the blocked side is not told.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockList {
+    addrs: HashSet<Address>,
+}
+
+impl BlockList {
+    pub fn new() -> BlockList {
+        BlockList::default()
+    }
+
+    // false if addr was blocked already
+    pub fn block(&mut self, addr: Address) -> bool {
+        self.addrs.insert(addr)
+    }
+
+    // false if addr was not blocked
+    pub fn unblock(&mut self, addr: &Address) -> bool {
+        self.addrs.remove(addr)
+    }
+
+    pub fn is_blocked(&self, addr: &Address) -> bool {
+        self.addrs.contains(addr)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addrs.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Address> {
+        self.addrs.iter()
+    }
+
+    // Whether sigpost is written by a blocked account or carries one: a
+    // rehoot of it, or a quote of or a reply to it, at any depth.
+    pub fn blocks(&self, sigpost: &SignedPost) -> bool {
+        if self.addrs.is_empty() {
+            return false;
+        }
+        if self.is_blocked(&sigpost.addr) {
+            return true;
+        }
+        match &sigpost.post.content {
+            PostKind::Hoot(hoot) => hoot
+                .quoted_posts
+                .iter()
+                .chain(hoot.reply_to.iter())
+                .any(|embedded| self.blocks(embedded)),
+            PostKind::ReHoot(inner) => self.blocks(inner),
+            PostKind::ReHootRef(original) => self.is_blocked(&original.addr),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::SecretKey;
+    use crate::user::post::{Hoot, Post};
+    use crate::user::post_id::PostId;
+    use crate::user::user::UserAttribute;
+
+    fn sign(sk: &SecretKey, content: PostKind) -> SignedPost {
+        let post = Post {
+            user_attr: UserAttribute::new("name", 0, ""),
+            id: PostId::from(1),
+            content,
+            created_at: 0,
+        };
+        let signature = sk.sign(&post.signed_message());
+        SignedPost::new(Address::from(sk.public_key()), post, signature)
+    }
+
+    #[test]
+    fn block_list_test() {
+        let (troll, friend) = (SecretKey::random(), SecretKey::random());
+        let troll_addr = Address::from(troll.public_key());
+        let mut blocked = BlockList::new();
+        let post = sign(&troll, PostKind::Hoot(Hoot::new("boo".to_string())));
+        assert!(!blocked.blocks(&post));
+
+        assert!(blocked.block(troll_addr.clone()));
+        assert!(!blocked.block(troll_addr.clone()));
+        assert!(blocked.blocks(&post));
+
+        // carried by an account which is not blocked
+        let rehoot = sign(&friend, PostKind::ReHoot(Box::new(post.clone())));
+        assert!(blocked.blocks(&rehoot));
+        let pointer = sign(&friend, PostKind::ReHootRef(post.post_ref()));
+        assert!(blocked.blocks(&pointer));
+        let mut quote = Hoot::new("look".to_string());
+        quote.quoted_posts = Some(Box::new(rehoot));
+        assert!(blocked.blocks(&sign(&friend, PostKind::Hoot(quote))));
+        let own = sign(&friend, PostKind::Hoot(Hoot::new("hi".to_string())));
+        assert!(!blocked.blocks(&own));
+
+        assert!(blocked.unblock(&troll_addr));
+        assert!(!blocked.unblock(&troll_addr));
+        assert!(!blocked.blocks(&pointer));
+    }
+}
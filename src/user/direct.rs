@@ -0,0 +1,73 @@
+use super::user::Address;
+use crate::crypto::secretbox::{self, SecretBoxError};
+use crate::crypto::{Ed25519Error, PublicKey, SecretKey, SignerError};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+// The text of a direct message, sealed with the secret its sender and its
+// recipient share (see SecretKey::shared_secret), so that only the two of
+// them read it. Multicast to the region of the recipient like a post.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DirectMessage {
+    pub to: Address,
+    pub sealed: Vec<u8>,
+}
+
+impl DirectMessage {
+    pub fn seal(
+        sk: &SecretKey,
+        to: &PublicKey,
+        text: &str,
+    ) -> Result<DirectMessage, DirectMessageError> {
+        let key = sk.shared_secret(to)?;
+        Ok(DirectMessage {
+            to: Address::from(to.clone()),
+            sealed: secretbox::seal(&key, text.as_bytes()),
+        })
+    }
+
+    // peer is the other side: the sender for the recipient, and the
+    // recipient for the sender
+    pub fn open(&self, sk: &SecretKey, peer: &PublicKey) -> Result<String, DirectMessageError> {
+        let key = sk.shared_secret(peer)?;
+        let text = secretbox::open(&key, &self.sealed)?;
+        String::from_utf8(text).map_err(|_| DirectMessageError::Text)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum DirectMessageError {
+    #[error("Invalid key: {0}")]
+    Key(#[from] Ed25519Error),
+    #[error("Cannot open the message: {0}")]
+    Sealed(#[from] SecretBoxError),
+    #[error("The message is not text")]
+    Text,
+    #[error("Not a direct message between this account and the other one")]
+    NotForMe,
+    #[error("An external signer cannot seal or open direct messages")]
+    ExternalSigner,
+    #[error(transparent)]
+    Signer(#[from] SignerError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn direct_message_test() {
+        let (alice, bob, carol) = (SecretKey::random(), SecretKey::random(), SecretKey::random());
+        let dm = DirectMessage::seal(&alice, &bob.public_key(), "see you").unwrap();
+        assert_eq!(dm.to, Address::from(bob.public_key()));
+
+        // both ends read it, nobody else
+        assert_eq!(dm.open(&bob, &alice.public_key()).unwrap(), "see you");
+        assert_eq!(dm.open(&alice, &bob.public_key()).unwrap(), "see you");
+        assert!(matches!(
+            dm.open(&carol, &alice.public_key()),
+            Err(DirectMessageError::Sealed(SecretBoxError::Tag))
+        ));
+    }
+}
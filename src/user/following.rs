@@ -0,0 +1,129 @@
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use super::post_id::PostId;
+use super::user::{Address, UserAttribute};
+
+// What is kept about a followed account. All of it is local: notes and
+// lists are never published.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Following {
+    // the profile of its latest post, or looked up when followed
+    pub attr: Option<UserAttribute>,
+    // seconds since the epoch; 0 if followed before this was kept
+    pub followed_at: u64,
+    pub note: Option<String>,
+    // the names of the lists it is in, like "work" or "news"
+    pub lists: BTreeSet<String>,
+    // the newest post seen from it, to compare with its head record after
+    // being offline
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub head: Option<PostId>,
+}
+
+impl Following {
+    pub fn new(attr: Option<UserAttribute>, followed_at: u64) -> Following {
+        Following {
+            attr,
+            followed_at,
+            note: None,
+            lists: BTreeSet::new(),
+            head: None,
+        }
+    }
+
+    // ids only go forward, so a post received late leaves the head alone
+    pub fn saw_post(&mut self, id: PostId) {
+        if self.head.is_none_or(|head| head < id) {
+            self.head = Some(id);
+        }
+    }
+
+    pub fn in_list(&self, list: &str) -> bool {
+        self.lists.contains(list)
+    }
+}
+
+// the followings in list
+pub fn list_members(followings: &HashMap<Address, Following>, list: &str) -> HashSet<Address> {
+    followings
+        .iter()
+        .filter(|(_, following)| following.in_list(list))
+        .map(|(addr, _)| addr.clone())
+        .collect()
+}
+
+// every list with how many followings are in it
+pub fn list_sizes(followings: &HashMap<Address, Following>) -> BTreeMap<String, usize> {
+    let mut sizes = BTreeMap::new();
+    for list in followings.values().flat_map(|following| following.lists.iter()) {
+        *sizes.entry(list.clone()).or_insert(0) += 1;
+    }
+    sizes
+}
+
+// Followings were saved as the profile alone, which still loads. Not an
+// untagged enum, which cannot read numbers with arbitrary_precision.
+impl<'de> Deserialize<'de> for Following {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Following, D::Error> {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        if value.get("followed_at").is_none() {
+            let attr: Option<UserAttribute> =
+                serde_json::from_value(value).map_err(D::Error::custom)?;
+            return Ok(Following::new(attr, 0));
+        }
+        let stored: StoredFollowing = serde_json::from_value(value).map_err(D::Error::custom)?;
+        Ok(Following {
+            attr: stored.attr,
+            followed_at: stored.followed_at,
+            note: stored.note,
+            lists: stored.lists,
+            head: stored.head,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct StoredFollowing {
+    #[serde(default)]
+    attr: Option<UserAttribute>,
+    followed_at: u64,
+    #[serde(default)]
+    note: Option<String>,
+    #[serde(default)]
+    lists: BTreeSet<String>,
+    #[serde(default)]
+    head: Option<PostId>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn following_test() {
+        let attr = UserAttribute::new("alice", 0, "");
+        let mut following = Following::new(Some(attr.clone()), 100);
+        following.note = Some("met at the meetup".to_string());
+        following.lists.insert("work".to_string());
+        following.saw_post(PostId(7));
+        following.saw_post(PostId(5));
+        assert_eq!(following.head, Some(PostId(7)));
+        let json = serde_json::to_string(&following).unwrap();
+        assert_eq!(serde_json::from_str::<Following>(&json).unwrap(), following);
+        assert!(following.in_list("work") && !following.in_list("news"));
+
+        // as saved before
+        let old = serde_json::to_string(&Some(attr.clone())).unwrap();
+        assert_eq!(serde_json::from_str::<Following>(&old).unwrap(), Following::new(Some(attr), 0));
+        assert_eq!(serde_json::from_str::<Following>("null").unwrap(), Following::default());
+
+        let mut followings = HashMap::new();
+        followings.insert(Address::new([1; 32]), following);
+        followings.insert(Address::new([2; 32]), Following::new(None, 200));
+        let members = list_members(&followings, "work");
+        assert_eq!(members, vec![Address::new([1; 32])].into_iter().collect());
+        assert_eq!(list_sizes(&followings), vec![("work".to_string(), 1)].into_iter().collect());
+    }
+}
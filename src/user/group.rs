@@ -0,0 +1,360 @@
+use super::direct::DirectMessageError;
+use super::user::{Address, VerifyError};
+use crate::crypto::secretbox;
+use crate::crypto::{PublicKey, SecretKey, SignerError};
+use crate::util::rng::Entropy;
+
+use serde::{Deserialize, Serialize};
+use serde_big_array::BigArray;
+use std::convert::TryInto;
+use thiserror::Error;
+
+pub const MAX_GROUP_MEMBERS: usize = 32;
+
+// The member list of a group as first published by its creator.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Membership {
+    pub group_id: [u8; 16],
+    pub creator: Address,
+    pub members: Vec<Address>,
+    pub version: u64,
+}
+
+impl Membership {
+    // the creator is always a member; the group id is drawn from entropy
+    pub fn new(creator: Address, members: &[Address], entropy: &dyn Entropy) -> Membership {
+        let mut group_id = [0; 16];
+        entropy.fill_bytes(&mut group_id);
+        let mut all = vec![creator.clone()];
+        for member in members {
+            if !all.contains(member) {
+                all.push(member.clone());
+            }
+        }
+        Membership {
+            group_id,
+            creator,
+            members: all,
+            version: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SignedMembership {
+    pub membership: Membership,
+    #[serde(with = "BigArray")]
+    pub signature: [u8; 64],
+}
+
+impl SignedMembership {
+    pub fn verify(&self, creator_pubkey: &PublicKey) -> Result<(), VerifyError> {
+        if self.membership.creator != Address::from(creator_pubkey.clone()) {
+            return Err(VerifyError::Address);
+        }
+        creator_pubkey
+            .verify(
+                &self.signature,
+                &serde_json::to_vec(&self.membership).unwrap(),
+            )
+            .map_err(VerifyError::Signature)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum MembershipChange {
+    // only the creator adds and removes
+    Add(Address),
+    Remove(Address),
+    // signed by the member who leaves
+    Leave(Address),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct MembershipUpdate {
+    pub group_id: [u8; 16],
+    pub version: u64,
+    pub change: MembershipChange,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SignedMembershipUpdate {
+    pub signer: Address,
+    pub update: MembershipUpdate,
+    #[serde(with = "BigArray")]
+    pub signature: [u8; 64],
+}
+
+impl SignedMembershipUpdate {
+    pub fn verify(&self, pubkey: &PublicKey) -> Result<(), VerifyError> {
+        if self.signer != Address::from(pubkey.clone()) {
+            return Err(VerifyError::Address);
+        }
+        pubkey
+            .verify(&self.signature, &serde_json::to_vec(&self.update).unwrap())
+            .map_err(VerifyError::Signature)
+    }
+}
+
+// The current member list, after applying updates in version order.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Group {
+    pub group_id: [u8; 16],
+    pub creator: Address,
+    pub members: Vec<Address>,
+    pub version: u64,
+}
+
+impl Group {
+    pub fn from_signed(
+        signed: &SignedMembership,
+        creator_pubkey: &PublicKey,
+    ) -> Result<Group, GroupError> {
+        signed.verify(creator_pubkey)?;
+        let membership = &signed.membership;
+        if membership.members.len() > MAX_GROUP_MEMBERS {
+            return Err(GroupError::TooManyMembers);
+        }
+        Ok(Group {
+            group_id: membership.group_id,
+            creator: membership.creator.clone(),
+            members: membership.members.clone(),
+            version: membership.version,
+        })
+    }
+
+    pub fn is_member(&self, addr: &Address) -> bool {
+        self.members.contains(addr)
+    }
+
+    // the version a new update has to carry
+    pub fn next_version(&self) -> u64 {
+        self.version + 1
+    }
+
+    pub fn apply(
+        &mut self,
+        signed: &SignedMembershipUpdate,
+        signer_pubkey: &PublicKey,
+    ) -> Result<(), GroupError> {
+        signed.verify(signer_pubkey)?;
+        let update = &signed.update;
+        if update.group_id != self.group_id {
+            return Err(GroupError::OtherGroup);
+        }
+        if update.version != self.next_version() {
+            return Err(GroupError::Version(update.version));
+        }
+
+        match &update.change {
+            MembershipChange::Add(addr) => {
+                if signed.signer != self.creator {
+                    return Err(GroupError::NotAllowed);
+                }
+                if self.members.len() >= MAX_GROUP_MEMBERS {
+                    return Err(GroupError::TooManyMembers);
+                }
+                if !self.is_member(addr) {
+                    self.members.push(addr.clone());
+                }
+            }
+            MembershipChange::Remove(addr) => {
+                if signed.signer != self.creator || *addr == self.creator {
+                    return Err(GroupError::NotAllowed);
+                }
+                self.members.retain(|member| member != addr);
+            }
+            MembershipChange::Leave(addr) => {
+                if signed.signer != *addr || !self.is_member(addr) {
+                    return Err(GroupError::NotAllowed);
+                }
+                self.members.retain(|member| member != addr);
+            }
+        }
+        self.version = update.version;
+        Ok(())
+    }
+}
+
+// One member's copy of a group message. The text is sealed once under a key
+// of its own, and that key is sealed for the member with the secret the
+// sender and the member share (see SecretKey::shared_secret). Every other
+// member gets a copy with the same sealed text, left in their mailbox.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct GroupMessage {
+    pub group_id: [u8; 16],
+    pub to: Address,
+    pub wrapped_key: Vec<u8>,
+    pub sealed: Vec<u8>,
+}
+
+impl GroupMessage {
+    // a copy for each of the members but the sender; members holds their keys.
+    // The message key is drawn from entropy, which only simulations seed
+    pub fn seal(
+        sk: &SecretKey,
+        group: &Group,
+        members: &[PublicKey],
+        text: &str,
+        entropy: &dyn Entropy,
+    ) -> Result<Vec<GroupMessage>, GroupError> {
+        let sender = Address::from(sk.public_key());
+        let mut message_key = [0u8; 32];
+        entropy.fill_bytes(&mut message_key);
+        let sealed = secretbox::seal(&message_key, text.as_bytes());
+
+        let mut ret = Vec::new();
+        for member in group.members.iter().filter(|member| **member != sender) {
+            let pubkey = members
+                .iter()
+                .find(|pubkey| Address::from((*pubkey).clone()) == *member)
+                .ok_or(GroupError::MissingKey)?;
+            let shared = sk.shared_secret(pubkey).map_err(DirectMessageError::from)?;
+            ret.push(GroupMessage {
+                group_id: group.group_id,
+                to: member.clone(),
+                wrapped_key: secretbox::seal(&shared, &message_key),
+                sealed: sealed.clone(),
+            });
+        }
+        Ok(ret)
+    }
+
+    // sk is the recipient's, sender the key of the member who sent it
+    pub fn open(&self, sk: &SecretKey, sender: &PublicKey) -> Result<String, GroupError> {
+        let shared = sk.shared_secret(sender).map_err(DirectMessageError::from)?;
+        let message_key = secretbox::open(&shared, &self.wrapped_key)
+            .map_err(DirectMessageError::from)?;
+        let message_key: [u8; 32] = message_key
+            .try_into()
+            .map_err(|_| GroupError::Direct(DirectMessageError::Text))?;
+        let text =
+            secretbox::open(&message_key, &self.sealed).map_err(DirectMessageError::from)?;
+        String::from_utf8(text).map_err(|_| GroupError::Direct(DirectMessageError::Text))
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum GroupError {
+    #[error("Invalid signature on the membership record")]
+    Verify(#[from] VerifyError),
+    #[error("Update is for another group")]
+    OtherGroup,
+    #[error("Out of order update (version {0})")]
+    Version(u64),
+    #[error("Signer may not make this change")]
+    NotAllowed,
+    #[error("Too many members")]
+    TooManyMembers,
+    #[error("Not a group this account is in")]
+    UnknownGroup,
+    #[error("Not sent by a member of the group")]
+    NotMember,
+    #[error("The key of a member is missing")]
+    MissingKey,
+    #[error(transparent)]
+    Direct(#[from] DirectMessageError),
+    #[error(transparent)]
+    Signer(#[from] SignerError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::SecretKey;
+    use crate::util::rng::{SeededEntropy, SystemEntropy};
+
+    fn update(sk: &SecretKey, group: &Group, change: MembershipChange) -> SignedMembershipUpdate {
+        let update = MembershipUpdate {
+            group_id: group.group_id,
+            version: group.next_version(),
+            change,
+        };
+        SignedMembershipUpdate {
+            signer: Address::from(sk.public_key()),
+            signature: sk.sign(&serde_json::to_vec(&update).unwrap()),
+            update,
+        }
+    }
+
+    #[test]
+    fn membership_test() {
+        let creator = SecretKey::random();
+        let alice = SecretKey::random();
+        let bob = SecretKey::random();
+        let alice_addr = Address::from(alice.public_key());
+        let bob_addr = Address::from(bob.public_key());
+
+        let membership = Membership::new(
+            Address::from(creator.public_key()),
+            std::slice::from_ref(&alice_addr),
+            &SystemEntropy,
+        );
+        let signed = SignedMembership {
+            signature: creator.sign(&serde_json::to_vec(&membership).unwrap()),
+            membership,
+        };
+        assert!(Group::from_signed(&signed, &alice.public_key()).is_err());
+        let mut group = Group::from_signed(&signed, &creator.public_key()).unwrap();
+        assert_eq!(group.members.len(), 2);
+
+        // only the creator adds members
+        let add = update(&alice, &group, MembershipChange::Add(bob_addr.clone()));
+        assert!(matches!(
+            group.apply(&add, &alice.public_key()),
+            Err(GroupError::NotAllowed)
+        ));
+        let add = update(&creator, &group, MembershipChange::Add(bob_addr.clone()));
+        group.apply(&add, &creator.public_key()).unwrap();
+        assert!(group.is_member(&bob_addr));
+
+        // replaying an update does nothing
+        assert!(matches!(
+            group.apply(&add, &creator.public_key()),
+            Err(GroupError::Version(_))
+        ));
+
+        // members leave by themselves only
+        let leave = update(&bob, &group, MembershipChange::Leave(alice_addr.clone()));
+        assert!(group.apply(&leave, &bob.public_key()).is_err());
+        let leave = update(&alice, &group, MembershipChange::Leave(alice_addr.clone()));
+        group.apply(&leave, &alice.public_key()).unwrap();
+        assert!(!group.is_member(&alice_addr));
+        assert_eq!(group.version, 2);
+
+        // a seeded simulation draws the same group ids again
+        let creator_addr = Address::from(creator.public_key());
+        let group_id = |seed| {
+            Membership::new(creator_addr.clone(), &[], &SeededEntropy::new(seed)).group_id
+        };
+        assert_eq!(group_id(1), group_id(1));
+        assert_ne!(group_id(1), group_id(2));
+    }
+
+    #[test]
+    fn group_message_test() {
+        let (creator, alice, mallory) = (SecretKey::random(), SecretKey::random(), SecretKey::random());
+        let alice_addr = Address::from(alice.public_key());
+        let membership = Membership::new(
+            Address::from(creator.public_key()),
+            std::slice::from_ref(&alice_addr),
+            &SystemEntropy,
+        );
+        let signed = SignedMembership {
+            signature: creator.sign(&serde_json::to_vec(&membership).unwrap()),
+            membership,
+        };
+        let group = Group::from_signed(&signed, &creator.public_key()).unwrap();
+
+        assert!(matches!(
+            GroupMessage::seal(&creator, &group, &[], "hi", &SystemEntropy),
+            Err(GroupError::MissingKey)
+        ));
+        let members = [alice.public_key()];
+        let copies = GroupMessage::seal(&creator, &group, &members, "hi", &SystemEntropy).unwrap();
+        assert_eq!(copies.len(), 1);
+        assert_eq!(copies[0].to, alice_addr);
+        assert_eq!(copies[0].open(&alice, &creator.public_key()).unwrap(), "hi");
+        assert!(copies[0].open(&mallory, &creator.public_key()).is_err());
+    }
+}
@@ -0,0 +1,176 @@
+use super::post::{self, PostKind, SignedPost};
+use super::post_id::PostId;
+use super::user::{Address, VerifyError};
+use crate::crypto::PublicKey;
+
+use serde::{Deserialize, Serialize};
+use serde_big_array::BigArray;
+use std::convert::{TryFrom, TryInto};
+
+// Points at the newest post of an author, so that new followers know which
+// history entries to fetch.
+// Binary layout: pubkey(32) | signature(64) | latest(16, BE) | updated_at(8, BE)
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct HeadRecord {
+    pub pubkey: PublicKey,
+    #[serde(with = "BigArray")]
+    pub signature: [u8; 64],
+    pub latest: PostId,
+    pub updated_at: u64,
+}
+
+pub const HEAD_RECORD_LEN: usize = 120;
+
+impl HeadRecord {
+    pub fn new(
+        pubkey: &PublicKey,
+        latest: PostId,
+        updated_at: u64,
+        signature: [u8; 64],
+    ) -> HeadRecord {
+        HeadRecord {
+            pubkey: pubkey.clone(),
+            signature,
+            latest,
+            updated_at,
+        }
+    }
+
+    pub fn addr(&self) -> Address {
+        Address::from(self.pubkey.clone())
+    }
+
+    // the message signed by the account key
+    pub fn signed_message(addr: &Address, latest: PostId, updated_at: u64) -> Vec<u8> {
+        let addr_bytes: [u8; 32] = addr.clone().into();
+        [
+            &b"noktulo:head:"[..],
+            &addr_bytes[..],
+            &latest.to_be_bytes()[..],
+            &updated_at.to_be_bytes()[..],
+        ]
+        .concat()
+    }
+
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        self.pubkey
+            .verify(
+                &self.signature,
+                &HeadRecord::signed_message(&self.addr(), self.latest, self.updated_at),
+            )
+            .map_err(VerifyError::Signature)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [
+            &self.pubkey.to_bytes()[..],
+            &self.signature[..],
+            &self.latest.to_be_bytes()[..],
+            &self.updated_at.to_be_bytes()[..],
+        ]
+        .concat()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<HeadRecord, VerifyError> {
+        if bytes.len() != HEAD_RECORD_LEN {
+            return Err(VerifyError::Size);
+        }
+        Ok(HeadRecord {
+            pubkey: PublicKey::try_from(&bytes[..32]).map_err(VerifyError::Signature)?,
+            signature: bytes[32..96].try_into().unwrap(),
+            latest: PostId::from_be_bytes(bytes[96..112].try_into().unwrap()),
+            updated_at: u64::from_be_bytes(bytes[112..].try_into().unwrap()),
+        })
+    }
+}
+
+// One archived post of an author, stored under its post id.
+// Carries the public key so that DHT nodes can check it before storing.
+// prev is the id of the author's post before it, which backfill follows back
+// from the head. It is not signed, a bad one only cuts the history short.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub pubkey: PublicKey,
+    pub sigpost: SignedPost,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prev: Option<PostId>,
+}
+
+impl HistoryEntry {
+    pub fn new(pubkey: &PublicKey, sigpost: SignedPost, prev: Option<PostId>) -> HistoryEntry {
+        HistoryEntry {
+            pubkey: pubkey.clone(),
+            sigpost,
+            prev,
+        }
+    }
+
+    pub fn verify(&self) -> Result<(), post::VerifyError> {
+        self.sigpost.verify(&self.pubkey)
+    }
+
+    // A deleted post is overwritten by the Delete post, so either may be found under its id.
+    pub fn is_entry_of(&self, addr: &Address, id: PostId) -> bool {
+        self.sigpost.addr == *addr
+            && (self.sigpost.post.id == id || self.sigpost.post.content == PostKind::Delete(id))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<HistoryEntry> {
+        serde_json::from_slice(bytes).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::SecretKey;
+    use crate::user::post::{Hoot, Post};
+    use crate::user::user::UserAttribute;
+
+    #[test]
+    fn head_record_test() {
+        let sk = SecretKey::random();
+        let addr = Address::from(sk.public_key());
+        let signature = sk.sign(&HeadRecord::signed_message(&addr, PostId(42), 1000));
+        let head = HeadRecord::new(&sk.public_key(), PostId(42), 1000, signature);
+        assert!(head.verify().is_ok());
+
+        let decoded = HeadRecord::from_bytes(&head.to_bytes()).unwrap();
+        assert_eq!(decoded, head);
+
+        let mut forged = head.clone();
+        forged.latest = PostId(43);
+        assert!(forged.verify().is_err());
+    }
+
+    #[test]
+    fn history_entry_test() {
+        let sk = SecretKey::random();
+        let addr = Address::from(sk.public_key());
+        let post = Post {
+            user_attr: UserAttribute::new("a", 0, ""),
+            id: PostId(3),
+            content: PostKind::Hoot(Hoot::new("hi".to_string())),
+            created_at: 0,
+        };
+        let signature = sk.sign(&post.signed_message());
+        let entry = HistoryEntry::new(
+            &sk.public_key(),
+            SignedPost::new(addr.clone(), post, signature),
+            Some(PostId(2)),
+        );
+        let entry = HistoryEntry::from_bytes(&entry.to_bytes()).unwrap();
+        assert!(entry.verify().is_ok());
+        assert!(entry.is_entry_of(&addr, PostId(3)));
+        assert!(!entry.is_entry_of(&addr, PostId(4)));
+        assert_eq!(entry.prev, Some(PostId(2)));
+
+        let other = HistoryEntry::new(&SecretKey::random().public_key(), entry.sigpost, None);
+        assert!(other.verify().is_err());
+    }
+}
@@ -0,0 +1,215 @@
+use sha3::{Digest, Sha3_256};
+use std::convert::TryInto;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use thiserror::Error;
+
+use super::user::{Address, UserAttribute};
+use crate::kad::{Key, NodeInfo};
+use crate::util::base64;
+
+pub const INVITE_URI_PREFIX: &str = "noktulo:invite:";
+pub const INVITE_VERSION: u8 = 1;
+// peers carried by an invite, so that the token stays short enough to paste
+pub const MAX_INVITE_PEERS: usize = 4;
+const PROFILE_DIGEST_LEN: usize = 16;
+const CHECKSUM_LEN: usize = 4;
+
+// An account to follow and peers to bootstrap from, in one token for a new
+// user to paste. The profile digest lets them tell whether the account they
+// reach is the one they were invited to, since anyone can post any name.
+//
+// The token is INVITE_URI_PREFIX and the base64 of: version, address (32),
+// profile digest (16), peer count, then for each peer its net_id and id
+// (length prefixed), 4 or 6 followed by the IP, and the port (big endian),
+// and last the first 4 bytes of the SHA3-256 of all that.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invite {
+    pub addr: Address,
+    pub profile_digest: [u8; PROFILE_DIGEST_LEN],
+    pub peers: Vec<NodeInfo>,
+}
+
+impl Invite {
+    // peers beyond MAX_INVITE_PEERS are left out
+    pub fn new(addr: Address, attr: &UserAttribute, peers: &[NodeInfo]) -> Invite {
+        Invite {
+            addr,
+            profile_digest: profile_digest(attr),
+            peers: peers.iter().take(MAX_INVITE_PEERS).cloned().collect(),
+        }
+    }
+
+    pub fn matches_profile(&self, attr: &UserAttribute) -> bool {
+        self.profile_digest == profile_digest(attr)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let addr: [u8; 32] = self.addr.clone().into();
+        let mut buf = vec![INVITE_VERSION];
+        buf.extend_from_slice(&addr);
+        buf.extend_from_slice(&self.profile_digest);
+        buf.push(self.peers.len() as u8);
+        for peer in &self.peers {
+            buf.push(peer.net_id.len() as u8);
+            buf.extend_from_slice(peer.net_id.as_bytes());
+            buf.push(peer.id.len() as u8);
+            buf.extend_from_slice(peer.id.as_bytes());
+            match peer.addr.ip() {
+                IpAddr::V4(ip) => {
+                    buf.push(4);
+                    buf.extend_from_slice(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    buf.push(6);
+                    buf.extend_from_slice(&ip.octets());
+                }
+            }
+            buf.extend_from_slice(&peer.addr.port().to_be_bytes());
+        }
+        let checksum = Sha3_256::digest(&buf);
+        buf.extend_from_slice(&checksum[..CHECKSUM_LEN]);
+        buf
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Invite, InviteError> {
+        if bytes.len() < CHECKSUM_LEN {
+            return Err(InviteError::Truncated);
+        }
+        let (body, checksum) = bytes.split_at(bytes.len() - CHECKSUM_LEN);
+        if Sha3_256::digest(body)[..CHECKSUM_LEN] != *checksum {
+            return Err(InviteError::Checksum);
+        }
+        let mut reader = Reader(body);
+        let version = reader.take(1)?[0];
+        if version != INVITE_VERSION {
+            return Err(InviteError::Version(version));
+        }
+        let addr: [u8; 32] = reader.take(32)?.try_into().unwrap();
+        let profile_digest = reader.take(PROFILE_DIGEST_LEN)?.try_into().unwrap();
+        let count = reader.take(1)?[0] as usize;
+        if count > MAX_INVITE_PEERS {
+            return Err(InviteError::TooManyPeers(count));
+        }
+        let mut peers = Vec::with_capacity(count);
+        for _ in 0..count {
+            let len = reader.take(1)?[0] as usize;
+            let net_id = String::from_utf8(reader.take(len)?.to_vec())
+                .map_err(|_| InviteError::Malformed)?;
+            let len = reader.take(1)?[0] as usize;
+            let id = Key::from(reader.take(len)?);
+            let ip = match reader.take(1)?[0] {
+                4 => {
+                    let octets: [u8; 4] = reader.take(4)?.try_into().unwrap();
+                    IpAddr::from(octets)
+                }
+                6 => {
+                    let octets: [u8; 16] = reader.take(16)?.try_into().unwrap();
+                    IpAddr::from(octets)
+                }
+                _ => return Err(InviteError::Malformed),
+            };
+            let port = u16::from_be_bytes(reader.take(2)?.try_into().unwrap());
+            peers.push(NodeInfo {
+                id,
+                addr: SocketAddr::new(ip, port),
+                net_id,
+            });
+        }
+        if !reader.0.is_empty() {
+            return Err(InviteError::Malformed);
+        }
+        Ok(Invite {
+            addr: Address::new(addr),
+            profile_digest,
+            peers,
+        })
+    }
+
+    // with or without INVITE_URI_PREFIX, and surrounding whitespace
+    pub fn parse(s: &str) -> Result<Invite, InviteError> {
+        let s = s.trim();
+        let s = s.strip_prefix(INVITE_URI_PREFIX).unwrap_or(s);
+        let bytes = base64::decode(s.as_bytes()).map_err(InviteError::Base64)?;
+        Invite::from_bytes(&bytes)
+    }
+}
+
+impl fmt::Display for Invite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let token = String::from_utf8(base64::encode(&self.to_bytes())).unwrap();
+        write!(f, "{}{}", INVITE_URI_PREFIX, token)
+    }
+}
+
+fn profile_digest(attr: &UserAttribute) -> [u8; PROFILE_DIGEST_LEN] {
+    let json = serde_json::to_vec(attr).unwrap();
+    Sha3_256::digest(&json)[..PROFILE_DIGEST_LEN].try_into().unwrap()
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], InviteError> {
+        if self.0.len() < n {
+            return Err(InviteError::Truncated);
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum InviteError {
+    #[error("Invalid character in the invite")]
+    Base64(base64::Base64Error),
+    #[error("The invite is truncated")]
+    Truncated,
+    #[error("The invite is mistyped (checksum mismatch)")]
+    Checksum,
+    #[error("Unsupported invite version {0}")]
+    Version(u8),
+    #[error("The invite carries {0} peers, more than allowed")]
+    TooManyPeers(usize),
+    #[error("Malformed invite")]
+    Malformed,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::{TESTNET_PUBSUB_DHT, TESTNET_USER_DHT};
+
+    #[test]
+    fn invite_test() {
+        let attr = UserAttribute::new("alice", 1000, "hello");
+        let peers = vec![
+            NodeInfo {
+                id: Key::random(32),
+                addr: "203.0.113.5:6270".parse().unwrap(),
+                net_id: TESTNET_USER_DHT.to_string(),
+            },
+            NodeInfo {
+                id: Key::random(64),
+                addr: "[2001:db8::1]:6272".parse().unwrap(),
+                net_id: TESTNET_PUBSUB_DHT.to_string(),
+            },
+        ];
+        let invite = Invite::new(Address::new([7; 32]), &attr, &peers);
+        let token = invite.to_string();
+        assert!(token.starts_with(INVITE_URI_PREFIX));
+        assert_eq!(Invite::parse(&format!(" {}\n", token)).unwrap(), invite);
+        let bare = &token[INVITE_URI_PREFIX.len()..];
+        assert_eq!(Invite::parse(bare).unwrap(), invite);
+        assert!(invite.matches_profile(&attr));
+        assert!(!invite.matches_profile(&UserAttribute::new("alice", 1000, "hi")));
+
+        let mut bytes = invite.to_bytes();
+        bytes[40] ^= 1;
+        assert!(matches!(Invite::from_bytes(&bytes), Err(InviteError::Checksum)));
+        let many = vec![peers[0].clone(); MAX_INVITE_PEERS + 1];
+        let invite = Invite::new(Address::new([7; 32]), &attr, &many);
+        assert_eq!(invite.peers.len(), MAX_INVITE_PEERS);
+    }
+}
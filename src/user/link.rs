@@ -0,0 +1,174 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use thiserror::Error;
+
+pub const MAX_URL_LEN: usize = 2048;
+pub const MAX_TITLE_LEN: usize = 200;
+pub const MAX_DESCRIPTION_LEN: usize = 500;
+
+// A preview card for the link in a hoot, made by the author's client.
+// The image is not embedded, only the SHA-256 of it (hex), for clients
+// which fetch and check it themselves.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct LinkPreview {
+    pub url: String,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_hash: Option<String>,
+}
+
+impl LinkPreview {
+    pub fn new(url: &str) -> LinkPreview {
+        LinkPreview {
+            url: url.to_string(),
+            title: None,
+            description: None,
+            image_hash: None,
+        }
+    }
+
+    // Anyone can sign anything, so clients check this before showing a card.
+    pub fn validate(&self) -> Result<(), LinkError> {
+        if !(self.url.starts_with("http://") || self.url.starts_with("https://")) {
+            return Err(LinkError::Scheme);
+        }
+        if self.url.len() > MAX_URL_LEN || self.url.contains(char::is_whitespace) {
+            return Err(LinkError::Url);
+        }
+        let too_long =
+            |s: &Option<String>, max: usize| s.as_ref().is_some_and(|s| s.chars().count() > max);
+        if too_long(&self.title, MAX_TITLE_LEN)
+            || too_long(&self.description, MAX_DESCRIPTION_LEN)
+        {
+            return Err(LinkError::TooLong);
+        }
+        if let Some(hash) = &self.image_hash {
+            if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(LinkError::ImageHash);
+            }
+        }
+        Ok(())
+    }
+
+    // Fills title and description from the page's OpenGraph tags, or from
+    // <title>. Overlong values are cut, so the result validates.
+    pub fn from_html(url: &str, html: &str) -> LinkPreview {
+        let mut preview = LinkPreview::new(url);
+        preview.title = meta_content(html, "og:title")
+            .or_else(|| tag_text(html, "title"))
+            .map(|s| truncate(&s, MAX_TITLE_LEN));
+        preview.description = meta_content(html, "og:description")
+            .or_else(|| meta_content(html, "description"))
+            .map(|s| truncate(&s, MAX_DESCRIPTION_LEN));
+        preview
+    }
+}
+
+impl fmt::Display for LinkPreview {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.title {
+            Some(title) => write!(f, "[{}] <{}>", title, self.url)?,
+            None => write!(f, "<{}>", self.url)?,
+        }
+        if let Some(description) = &self.description {
+            write!(f, "\n  {}", description)?;
+        }
+        Ok(())
+    }
+}
+
+// the first http(s) URL in a text
+pub fn find_url(text: &str) -> Option<&str> {
+    text.split_whitespace()
+        .find(|word| word.starts_with("http://") || word.starts_with("https://"))
+        .map(|word| word.trim_end_matches(&[',', '.', ')', '!', '?', ';', ':'][..]))
+}
+
+fn truncate(s: &str, max: usize) -> String {
+    s.trim().chars().take(max).collect()
+}
+
+// the content of <meta property="name" content="..."> or <meta name="name" ...>
+fn meta_content(html: &str, name: &str) -> Option<String> {
+    let lower = html.to_ascii_lowercase();
+    let mut rest = 0;
+    while let Some(i) = lower[rest..].find("<meta") {
+        let start = rest + i;
+        let end = start + lower[start..].find('>')?;
+        let tag = &html[start..end];
+        let tag_lower = &lower[start..end];
+        rest = end;
+        let names = [format!("property=\"{}\"", name), format!("name=\"{}\"", name)];
+        if names.iter().any(|n| tag_lower.contains(n.as_str())) {
+            let i = tag_lower.find("content=\"")? + "content=\"".len();
+            let len = tag[i..].find('"')?;
+            return Some(unescape(&tag[i..i + len]));
+        }
+    }
+    None
+}
+
+fn tag_text(html: &str, tag: &str) -> Option<String> {
+    let lower = html.to_ascii_lowercase();
+    let open = lower.find(&format!("<{}", tag))?;
+    let start = open + lower[open..].find('>')? + 1;
+    let len = lower[start..].find(&format!("</{}", tag))?;
+    Some(unescape(&html[start..start + len]))
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+        .trim()
+        .to_string()
+}
+
+#[derive(Debug, Error)]
+pub enum LinkError {
+    #[error("Only http and https links are supported")]
+    Scheme,
+    #[error("Invalid URL")]
+    Url,
+    #[error("Title or description is too long")]
+    TooLong,
+    #[error("Invalid image hash")]
+    ImageHash,
+    #[error("Fetching the page failed: {0}")]
+    Fetch(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn link_preview_test() {
+        let text = "look at https://example.com/a?b=1, it's nice";
+        let url = find_url(text).unwrap();
+        assert_eq!(url, "https://example.com/a?b=1");
+
+        let html = r#"<html><head><TITLE>Fallback</TITLE>
+            <meta property="og:title" content="Tom &amp; Jerry">
+            <meta name="description" content="A cat and a mouse"></head></html>"#;
+        let preview = LinkPreview::from_html(url, html);
+        assert_eq!(preview.title.as_deref(), Some("Tom & Jerry"));
+        assert_eq!(preview.description.as_deref(), Some("A cat and a mouse"));
+        assert!(preview.validate().is_ok());
+        let preview = LinkPreview::from_html(url, &html.replace("og:title", "x"));
+        assert_eq!(preview.title.as_deref(), Some("Fallback"));
+
+        assert!(LinkPreview::new("javascript:alert(1)").validate().is_err());
+        let mut preview = LinkPreview::new(url);
+        preview.image_hash = Some("00".repeat(31));
+        assert!(matches!(preview.validate(), Err(LinkError::ImageHash)));
+    }
+}
@@ -0,0 +1,316 @@
+use super::post::{self, PostKind, SignedPost};
+use super::user::{Address, VerifyError};
+use crate::crypto::PublicKey;
+
+use serde::{Deserialize, Serialize};
+use serde_big_array::BigArray;
+use std::convert::{TryFrom, TryInto};
+use thiserror::Error;
+
+// A chunk has to fit in one DHT message, where values are sent as JSON arrays
+pub const MAILBOX_CHUNK_LEN: usize = 1800;
+pub const MAILBOX_INDEX_LEN: usize = 112;
+
+// A post left in the mailbox of an address it mentions, replies to or rehoots, with
+// the author's key so that DHT nodes can check it before storing.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct MailboxItem {
+    pub pubkey: PublicKey,
+    pub sigpost: SignedPost,
+}
+
+impl MailboxItem {
+    pub fn new(pubkey: &PublicKey, sigpost: SignedPost) -> MailboxItem {
+        MailboxItem {
+            pubkey: pubkey.clone(),
+            sigpost,
+        }
+    }
+
+    pub fn verify(&self) -> Result<(), post::VerifyError> {
+        self.sigpost.verify(&self.pubkey)
+    }
+
+    // whose mailboxes a post goes to, the author's own never: the ones it
+    // mentions or replies to, the author of the post it rehoots, or the member
+    // a copy of a group message is for
+    pub fn recipients(sigpost: &SignedPost) -> Vec<Address> {
+        let addrs: Vec<&Address> = match &sigpost.post.content {
+            PostKind::Hoot(hoot) => {
+                let reply_to = hoot.reply_to.iter().map(|to| &to.addr);
+                reply_to.chain(hoot.mention_to.iter()).collect()
+            }
+            PostKind::ReHoot(inner) => vec![&inner.addr],
+            PostKind::ReHootRef(original) => vec![&original.addr],
+            PostKind::GroupMessage(gm) => vec![&gm.to],
+            _ => Vec::new(),
+        };
+        let mut ret = Vec::new();
+        for addr in addrs {
+            if *addr != sigpost.addr && !ret.contains(addr) {
+                ret.push(addr.clone());
+            }
+        }
+        ret
+    }
+
+    pub fn is_for(&self, owner: &Address) -> bool {
+        MailboxItem::recipients(&self.sigpost).contains(owner)
+    }
+
+    fn is_same_post(&self, other: &MailboxItem) -> bool {
+        self.sigpost.addr == other.sigpost.addr && self.sigpost.post.id == other.sigpost.post.id
+    }
+}
+
+// Points at the chunks of a mailbox. Only the owner writes it, when compacting:
+// the kept items are written into the chunks of a new epoch, and the old ones
+// are left behind. Without an index, the mailbox is at epoch 0.
+// Binary layout: pubkey(32) | signature(64) | epoch(8, BE) | compacted_at(8, BE)
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct MailboxIndex {
+    pub pubkey: PublicKey,
+    #[serde(with = "BigArray")]
+    pub signature: [u8; 64],
+    pub epoch: u64,
+    pub compacted_at: u64,
+}
+
+impl MailboxIndex {
+    pub fn new(
+        pubkey: &PublicKey,
+        epoch: u64,
+        compacted_at: u64,
+        signature: [u8; 64],
+    ) -> MailboxIndex {
+        MailboxIndex {
+            pubkey: pubkey.clone(),
+            signature,
+            epoch,
+            compacted_at,
+        }
+    }
+
+    pub fn addr(&self) -> Address {
+        Address::from(self.pubkey.clone())
+    }
+
+    // the message signed by the account key
+    pub fn signed_message(addr: &Address, epoch: u64, compacted_at: u64) -> Vec<u8> {
+        let addr_bytes: [u8; 32] = addr.clone().into();
+        [
+            &b"noktulo:mailbox:"[..],
+            &addr_bytes[..],
+            &epoch.to_be_bytes()[..],
+            &compacted_at.to_be_bytes()[..],
+        ]
+        .concat()
+    }
+
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        self.pubkey
+            .verify(
+                &self.signature,
+                &MailboxIndex::signed_message(&self.addr(), self.epoch, self.compacted_at),
+            )
+            .map_err(VerifyError::Signature)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [
+            &self.pubkey.to_bytes()[..],
+            &self.signature[..],
+            &self.epoch.to_be_bytes()[..],
+            &self.compacted_at.to_be_bytes()[..],
+        ]
+        .concat()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<MailboxIndex, VerifyError> {
+        if bytes.len() != MAILBOX_INDEX_LEN {
+            return Err(VerifyError::Size);
+        }
+        Ok(MailboxIndex {
+            pubkey: PublicKey::try_from(&bytes[..32]).map_err(VerifyError::Signature)?,
+            signature: bytes[32..96].try_into().unwrap(),
+            epoch: u64::from_be_bytes(bytes[96..104].try_into().unwrap()),
+            compacted_at: u64::from_be_bytes(bytes[104..].try_into().unwrap()),
+        })
+    }
+}
+
+// One chunk of a mailbox, numbered from 0 in its epoch. Anyone may append
+// to a chunk, but nobody can take items out of it: a stored chunk is only
+// replaced by one starting with the same items.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct MailboxChunk {
+    pub owner: Address,
+    pub epoch: u64,
+    pub index: u32,
+    pub items: Vec<MailboxItem>,
+}
+
+impl MailboxChunk {
+    pub fn new(owner: &Address, epoch: u64, index: u32) -> MailboxChunk {
+        MailboxChunk {
+            owner: owner.clone(),
+            epoch,
+            index,
+            items: Vec::new(),
+        }
+    }
+
+    // Packs items into as few chunks as they fit in, for compaction.
+    // An item too large for any chunk is dropped.
+    pub fn pack(owner: &Address, epoch: u64, items: Vec<MailboxItem>) -> Vec<MailboxChunk> {
+        let mut ret = Vec::new();
+        let mut chunk = MailboxChunk::new(owner, epoch, 0);
+        for item in items {
+            if !chunk.fits(&item) && !chunk.items.is_empty() {
+                let index = chunk.index + 1;
+                ret.push(std::mem::replace(&mut chunk, MailboxChunk::new(owner, epoch, index)));
+            }
+            if chunk.fits(&item) {
+                chunk.items.push(item);
+            }
+        }
+        if !chunk.items.is_empty() {
+            ret.push(chunk);
+        }
+        ret
+    }
+
+    pub fn fits(&self, item: &MailboxItem) -> bool {
+        let mut appended = self.clone();
+        appended.items.push(item.clone());
+        appended.to_bytes().len() <= MAILBOX_CHUNK_LEN
+    }
+
+    pub fn contains(&self, item: &MailboxItem) -> bool {
+        self.items.iter().any(|i| i.is_same_post(item))
+    }
+
+    // whether new keeps every item of this chunk, in the same order
+    pub fn is_appended_by(&self, new: &MailboxChunk) -> bool {
+        self.owner == new.owner
+            && self.epoch == new.epoch
+            && self.index == new.index
+            && new.items.starts_with(&self.items)
+    }
+
+    // every item is signed by its author and addressed to the owner
+    pub fn verify(&self) -> Result<(), MailboxError> {
+        for item in self.items.iter() {
+            if !item.is_for(&self.owner) {
+                return Err(MailboxError::NotAddressed);
+            }
+            item.verify().map_err(|_| MailboxError::Signature)?;
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<MailboxChunk> {
+        if bytes.len() > MAILBOX_CHUNK_LEN {
+            return None;
+        }
+        serde_json::from_slice(bytes).ok()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum MailboxError {
+    #[error("The post is too large for a mailbox")]
+    TooLarge,
+    #[error("The post is not addressed to the owner of the mailbox")]
+    NotAddressed,
+    #[error("Invalid signature")]
+    Signature,
+    #[error("The mailbox kept changing, the post was not delivered")]
+    Contended,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::SecretKey;
+    use crate::user::post::{Hoot, Post};
+    use crate::user::post_id::PostId;
+    use crate::user::user::UserAttribute;
+
+    fn mention(sk: &SecretKey, id: u128, to: &Address) -> MailboxItem {
+        let mut hoot = Hoot::new("hi".to_string());
+        hoot.mention_to = vec![to.clone()];
+        let post = Post {
+            user_attr: UserAttribute::new("a", 0, ""),
+            id: PostId(id),
+            content: PostKind::Hoot(hoot),
+            created_at: 0,
+        };
+        let signature = sk.sign(&post.signed_message());
+        let sigpost = SignedPost::new(Address::from(sk.public_key()), post, signature);
+        MailboxItem::new(&sk.public_key(), sigpost)
+    }
+
+    #[test]
+    fn mailbox_chunk_test() {
+        let (sender, owner) = (SecretKey::random(), Address::new([7; 32]));
+        let items: Vec<_> = (0..20).map(|id| mention(&sender, id, &owner)).collect();
+        let chunks = MailboxChunk::pack(&owner, 1, items.clone());
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|c| c.to_bytes().len() <= MAILBOX_CHUNK_LEN));
+        assert!(chunks.iter().enumerate().all(|(i, c)| c.index == i as u32));
+        let unpacked: Vec<_> = chunks.iter().flat_map(|c| c.items.clone()).collect();
+        assert_eq!(unpacked, items);
+
+        let chunk = MailboxChunk::from_bytes(&chunks[0].to_bytes()).unwrap();
+        assert!(chunk.verify().is_ok());
+        let mut appended = chunk.clone();
+        appended.items.push(items[19].clone());
+        assert!(chunk.is_appended_by(&appended));
+        let mut dropped = chunk.clone();
+        dropped.items.remove(0);
+        assert!(!chunk.is_appended_by(&dropped));
+
+        let mut elsewhere = chunk;
+        elsewhere.owner = Address::new([8; 32]);
+        assert!(matches!(elsewhere.verify(), Err(MailboxError::NotAddressed)));
+    }
+
+    #[test]
+    fn rehoot_recipients_test() {
+        let (author, rehooter) = (SecretKey::random(), SecretKey::random());
+        let original = mention(&author, 1, &Address::new([7; 32])).sigpost;
+        let content = original.rehoot_content(&author.public_key()).unwrap();
+        let post = Post {
+            user_attr: UserAttribute::new("b", 0, ""),
+            id: PostId(2),
+            content,
+            created_at: 0,
+        };
+        let signature = rehooter.sign(&post.signed_message());
+        let rehoot = SignedPost::new(Address::from(rehooter.public_key()), post, signature);
+        let item = MailboxItem::new(&rehooter.public_key(), rehoot);
+        assert_eq!(MailboxItem::recipients(&item.sigpost), vec![original.addr.clone()]);
+        assert!(item.is_for(&Address::from(author.public_key())));
+        assert!(!item.is_for(&Address::new([7; 32])));
+    }
+
+    #[test]
+    fn mailbox_index_test() {
+        let sk = SecretKey::random();
+        let addr = Address::from(sk.public_key());
+        let signature = sk.sign(&MailboxIndex::signed_message(&addr, 2, 1000));
+        let index = MailboxIndex::new(&sk.public_key(), 2, 1000, signature);
+        let decoded = MailboxIndex::from_bytes(&index.to_bytes()).unwrap();
+        assert_eq!(decoded, index);
+        assert!(decoded.verify().is_ok());
+
+        let mut forged = index;
+        forged.epoch = 3;
+        assert!(forged.verify().is_err());
+    }
+}
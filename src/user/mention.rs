@@ -0,0 +1,174 @@
+use super::user::Address;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+// punctuation which may follow a mention in running text
+const TRAILING: &[char] = &[',', '.', ':', ';', '!', '?', ')'];
+
+// The `@` tokens of a text, without the `@`. A name with spaces is quoted
+// (`@"Jane Doe"`).
+pub fn mention_tokens(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut rest = text;
+    while let Some(i) = rest.find('@') {
+        // `a@b` is not a mention
+        let at_start = rest[..i].chars().last().is_none_or(char::is_whitespace);
+        rest = &rest[i + 1..];
+        if !at_start {
+            continue;
+        }
+        if let Some(quoted) = rest.strip_prefix('"') {
+            if let Some(end) = quoted.find('"') {
+                if !quoted[..end].trim().is_empty() {
+                    tokens.push(quoted[..end].trim().to_string());
+                }
+                rest = &quoted[end + 1..];
+                continue;
+            }
+        }
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let token = rest[..end].trim_end_matches(TRAILING);
+        if !token.is_empty() {
+            tokens.push(token.to_string());
+        }
+        rest = &rest[end..];
+    }
+    tokens
+}
+
+// Resolves the mentions of a text to addresses, in order and without
+// duplicates. A token is an address, or a name from `known` (a petname or a
+// display name), matched case-insensitively.
+pub fn resolve_mentions<'a, I>(text: &str, known: I) -> Result<Vec<Address>, MentionError>
+where
+    I: IntoIterator<Item = (&'a str, &'a Address)>,
+{
+    let mut resolved: Vec<Address> = Vec::new();
+    for (_, addr) in resolve_each(text, known) {
+        let addr = addr?;
+        if !resolved.contains(&addr) {
+            resolved.push(addr);
+        }
+    }
+    Ok(resolved)
+}
+
+// Every token of a text with what it resolves to, failed ones included, for
+// a composer to show them all at once
+pub fn resolve_each<'a, I>(text: &str, known: I) -> Vec<(String, Result<Address, MentionError>)>
+where
+    I: IntoIterator<Item = (&'a str, &'a Address)>,
+{
+    let known: Vec<(String, &Address)> = known
+        .into_iter()
+        .map(|(name, addr)| (name.to_lowercase(), addr))
+        .collect();
+
+    mention_tokens(text)
+        .into_iter()
+        .map(|token| {
+            let addr = match Address::parse_strict(&token) {
+                Ok(addr) => Ok(addr),
+                Err(_) => resolve_name(&token, &known),
+            };
+            (token, addr)
+        })
+        .collect()
+}
+
+// Whose mentions and replies an account takes. The others are not dropped
+// from the mailbox but kept apart, as requests to review.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum MentionPolicy {
+    #[default]
+    Everyone,
+    Followings,
+}
+
+impl MentionPolicy {
+    pub fn accepts(&self, author: &Address, is_following: impl Fn(&Address) -> bool) -> bool {
+        match self {
+            MentionPolicy::Everyone => true,
+            MentionPolicy::Followings => is_following(author),
+        }
+    }
+}
+
+fn resolve_name(token: &str, known: &[(String, &Address)]) -> Result<Address, MentionError> {
+    let name = token.to_lowercase();
+    let mut found: Vec<Address> = Vec::new();
+    for (_, addr) in known.iter().filter(|(n, _)| *n == name) {
+        if !found.contains(addr) {
+            found.push((*addr).clone());
+        }
+    }
+    match found.len() {
+        0 => {
+            let mut suggestions: Vec<Address> = Vec::new();
+            for (_, addr) in known.iter().filter(|(n, _)| n.starts_with(&name)) {
+                if !suggestions.contains(addr) {
+                    suggestions.push((*addr).clone());
+                }
+            }
+            Err(MentionError::Unknown(token.to_string(), suggestions))
+        }
+        1 => Ok(found.remove(0)),
+        _ => Err(MentionError::Ambiguous(token.to_string(), found)),
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum MentionError {
+    // with the accounts whose names start with the token
+    #[error("Unknown name @{0}")]
+    Unknown(String, Vec<Address>),
+    #[error("@{0} matches {} accounts", .1.len())]
+    Ambiguous(String, Vec<Address>),
+}
+
+impl MentionError {
+    pub fn suggestions(&self) -> &[Address] {
+        match self {
+            MentionError::Unknown(_, addrs) | MentionError::Ambiguous(_, addrs) => addrs,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::SecretKey;
+
+    #[test]
+    fn resolve_test() {
+        let alice = Address::from(SecretKey::random().public_key());
+        let bob = Address::from(SecretKey::random().public_key());
+        let bob2 = Address::from(SecretKey::random().public_key());
+        let carol = Address::from(SecretKey::random().public_key());
+        let known = vec![
+            ("Alice", &alice),
+            ("Bob", &bob),
+            ("bob", &bob2),
+            ("Jane Doe", &alice),
+        ];
+
+        assert_eq!(
+            mention_tokens("hi @alice, @\"Jane Doe\" and me@example.com"),
+            vec!["alice", "Jane Doe"]
+        );
+
+        let text = format!("@ALICE @\"jane doe\" @{}.", carol.to_string());
+        let resolved = resolve_mentions(&text, known.clone()).unwrap();
+        assert_eq!(resolved, vec![alice.clone(), carol]);
+
+        match resolve_mentions("@bob", known.clone()) {
+            Err(e @ MentionError::Ambiguous(_, _)) => assert_eq!(e.suggestions().len(), 2),
+            _ => panic!("bob is ambiguous"),
+        }
+        match resolve_mentions("@al", known) {
+            Err(e @ MentionError::Unknown(_, _)) => assert_eq!(e.suggestions(), &[alice]),
+            _ => panic!("al is unknown"),
+        }
+    }
+}
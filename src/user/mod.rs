@@ -1,2 +1,15 @@
 pub mod post;
+pub mod post_id;
 pub mod user;
+pub mod history;
+pub mod group;
+pub mod mention;
+pub mod link;
+pub mod mailbox;
+pub mod backup;
+pub mod invite;
+pub mod aliases;
+pub mod direct;
+pub mod block;
+pub mod following;
+pub mod time_format;
@@ -1,24 +1,59 @@
-use super::user::{Address, UserAttribute};
-//use crate::crypto::Ed25519Error;
-//use crate::crypto::PublicKey;
-use ed25519_dalek::PublicKey;
-use chrono::Local;
-use chrono::TimeZone;
+use super::direct::DirectMessage;
+use super::group::GroupMessage;
+use super::link::LinkPreview;
+use super::post_id::PostId;
+use super::time_format::TimeFormat;
+use super::user::{
+    is_legacy_signature, signed_bytes, Address, MigrationRecord, UserAttribute,
+    CANONICAL_SIGNATURE,
+};
+use crate::crypto::{Ed25519Error, PublicKey, SignerError};
+use crate::util::canonical;
 use serde::{Deserialize, Serialize};
 use serde_big_array::BigArray;
 use std::convert::TryInto;
 use std::fmt;
 use thiserror::Error;
 
+// How deep a rehoot carries posts; deeper ones are only pointed at
+pub const MAX_EMBED_DEPTH: usize = 1;
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct SignedPost {
     pub addr: Address,
     pub post: Post,
     #[serde(with = "BigArray")]
-    pub signature: [u8; 64]
+    pub signature: [u8; 64],
+    // see user::CANONICAL_SIGNATURE
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_legacy_signature")]
+    pub version: u8,
+    // the key of addr, carried by a rehooted post so that it can be checked
+    // without looking the key up; see attached_key
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author_key: Option<PublicKey>,
 }
 
 impl SignedPost {
+    // signature is over post.signed_message()
+    pub fn new(addr: Address, post: Post, signature: [u8; 64]) -> SignedPost {
+        SignedPost {
+            addr,
+            post,
+            signature,
+            version: CANONICAL_SIGNATURE,
+            author_key: None,
+        }
+    }
+
+    // the key carried with the post, if it is the one of its address
+    pub fn attached_key(&self) -> Option<&PublicKey> {
+        self.author_key
+            .as_ref()
+            .filter(|pubkey| Address::from((*pubkey).clone()) == self.addr)
+    }
+
     pub fn verify(&self, pubkey: &PublicKey) -> Result<(), VerifyError> {
         let addr = Address::from(pubkey.clone());
 
@@ -31,7 +66,8 @@ impl SignedPost {
                 pubkey
                     .verify(
                         &self.signature[..].try_into().unwrap(),
-                        &serde_json::to_vec(&self.post).unwrap(),
+                        &signed_bytes(&self.post, self.version)
+                            .map_err(|_| VerifyError::Version(self.version))?,
                     )
                     .map_err(|e| VerifyError::Signature(e))
             }
@@ -47,19 +83,107 @@ impl SignedPost {
     }
 }
 
-impl fmt::Display for SignedPost {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "{} @{} [{}]:\n{}",
+impl SignedPost {
+    pub fn header(&self) -> String {
+        self.header_with(&TimeFormat::default(), 0)
+    }
+
+    // the header with created_at written in format, now_secs for relative times
+    pub fn header_with(&self, format: &TimeFormat, now_secs: u64) -> String {
+        format!(
+            "{} @{} [{}]:",
             self.post.user_attr.name,
             self.addr.to_string(),
-            Local
-                .timestamp(self.post.created_at as i64, 0)
-                .format("%Y/%m/%d %H:%M:%S"),
-            self.post
+            format.format(self.post.created_at, now_secs),
         )
     }
+
+    // the content warning shown in place of a collapsed post
+    pub fn content_warning(&self) -> Option<String> {
+        match &self.post.content {
+            PostKind::Hoot(hoot) => hoot.content_warning(),
+            PostKind::ReHoot(inner) => inner.content_warning(),
+            PostKind::ReHootRef(_)
+            | PostKind::Delete(_)
+            | PostKind::DeleteAccount
+            | PostKind::Migrate(_)
+            | PostKind::DirectMessage(_)
+            | PostKind::GroupMessage(_) => None,
+        }
+    }
+
+    // the recipient, if this is a direct message or a copy of a group message
+    pub fn direct_to(&self) -> Option<&Address> {
+        match &self.post.content {
+            PostKind::DirectMessage(dm) => Some(&dm.to),
+            PostKind::GroupMessage(gm) => Some(&gm.to),
+            _ => None,
+        }
+    }
+
+    pub fn post_ref(&self) -> PostRef {
+        PostRef {
+            addr: self.addr.clone(),
+            id: self.post.id,
+        }
+    }
+
+    // how many levels of posts are embedded in this one; 0 if none
+    pub fn depth(&self) -> usize {
+        match &self.post.content {
+            PostKind::Hoot(hoot) => hoot
+                .quoted_posts
+                .iter()
+                .chain(hoot.reply_to.iter())
+                .map(|embedded| embedded.depth() + 1)
+                .max()
+                .unwrap_or(0),
+            PostKind::ReHoot(inner) => inner.depth() + 1,
+            _ => 0,
+        }
+    }
+
+    // What a rehoot of this post carries. The post has to be a hoot or a rehoot
+    // signed with pubkey. A rehoot of a rehoot points at the original, and a
+    // post embedding others is pointed at rather than carried, so that chains
+    // do not grow with every hop. A carried post takes pubkey along, for the
+    // followers of the rehooter to check it in one step.
+    pub fn rehoot_content(&self, pubkey: &PublicKey) -> Result<PostKind, RehootError> {
+        match &self.post.content {
+            PostKind::Hoot(_) | PostKind::ReHoot(_) | PostKind::ReHootRef(_) => (),
+            PostKind::Delete(_) => return Err(RehootError::Delete),
+            PostKind::DeleteAccount
+            | PostKind::Migrate(_)
+            | PostKind::DirectMessage(_)
+            | PostKind::GroupMessage(_) => {
+                return Err(RehootError::Unsupported)
+            }
+        }
+        self.verify(pubkey).map_err(RehootError::Verify)?;
+        Ok(match &self.post.content {
+            PostKind::ReHoot(inner) => PostKind::ReHootRef(inner.post_ref()),
+            PostKind::ReHootRef(original) => PostKind::ReHootRef(original.clone()),
+            _ if self.depth() < MAX_EMBED_DEPTH => {
+                let mut carried = self.clone();
+                carried.author_key = Some(pubkey.clone());
+                PostKind::ReHoot(Box::new(carried))
+            }
+            _ => PostKind::ReHootRef(self.post_ref()),
+        })
+    }
+}
+
+// A post by its author and id, to be fetched from the author's history
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PostRef {
+    pub addr: Address,
+    pub id: PostId,
+}
+
+impl fmt::Display for SignedPost {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}\n{}", self.header(), self.post)
+    }
 }
 
 #[derive(Debug, Error)]
@@ -70,16 +194,37 @@ pub enum VerifyError {
     Signature(Ed25519Error),
     #[error("Invalid size")]
     Size,
+    #[error("Unknown signature version {0}")]
+    Version(u8),
+}
+
+#[derive(Debug, Error)]
+pub enum RehootError {
+    #[error("A deletion cannot be rehooted")]
+    Delete,
+    #[error("Only hoots and rehoots can be rehooted")]
+    Unsupported,
+    #[error("The post does not verify: {0}")]
+    Verify(VerifyError),
+    #[error(transparent)]
+    Signer(#[from] SignerError),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Post {
     pub user_attr: UserAttribute,
-    pub id: u128,
+    pub id: PostId,
     pub content: PostKind,
     pub created_at: u64,
 }
 
+impl Post {
+    // what a SignedPost::new signature is over
+    pub fn signed_message(&self) -> Vec<u8> {
+        canonical::to_vec(self)
+    }
+}
+
 impl fmt::Display for Post {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match &self.content {
@@ -89,9 +234,24 @@ impl fmt::Display for Post {
             PostKind::ReHoot(sigpost) => {
                 write!(f, "\"{}\"", sigpost)
             }
+            PostKind::ReHootRef(original) => {
+                write!(f, "REHOOT OF {} BY @{}", original.id, original.addr.to_string())
+            }
             PostKind::Delete(id) => {
                 write!(f, "DELETE HOOT ID: {}", id)
             }
+            PostKind::DeleteAccount => {
+                write!(f, "ACCOUNT DELETED")
+            }
+            PostKind::Migrate(record) => {
+                write!(f, "MOVED TO @{}", record.new_addr().to_string())
+            }
+            PostKind::DirectMessage(dm) => {
+                write!(f, "DIRECT MESSAGE TO @{}", dm.to.to_string())
+            }
+            PostKind::GroupMessage(gm) => {
+                write!(f, "GROUP MESSAGE TO @{}", gm.to.to_string())
+            }
         }
     }
 }
@@ -100,7 +260,17 @@ impl fmt::Display for Post {
 pub enum PostKind {
     Hoot(Hoot),
     ReHoot(Box<SignedPost>),
-    Delete(u128),
+    // a rehoot of a post too deep to carry, see MAX_EMBED_DEPTH
+    ReHootRef(PostRef),
+    Delete(PostId),
+    // the last post of an account, telling followers it is gone
+    DeleteAccount,
+    // the last post of an account, telling followers where it moved
+    Migrate(Box<MigrationRecord>),
+    // readable by its recipient only; never archived or shown in timelines
+    DirectMessage(DirectMessage),
+    // one member's copy of a message to a group, left in their mailbox
+    GroupMessage(GroupMessage),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -115,10 +285,58 @@ pub struct Hoot {
     #[serde(default)]
     #[serde(skip_serializing_if="Vec::is_empty")]
     pub mention_to: Vec<Address>,
+    #[serde(default)]
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub lang: Option<String>,
+    // both are omitted when unset, so posts without them keep the same signed encoding
+    #[serde(default)]
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub content_warning: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if="is_false")]
+    pub sensitive: bool,
+    #[serde(default)]
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub link: Option<LinkPreview>,
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+impl Hoot {
+    pub fn new(text: String) -> Hoot {
+        Hoot {
+            text,
+            quoted_posts: None,
+            reply_to: None,
+            mention_to: Vec::new(),
+            lang: None,
+            content_warning: None,
+            sensitive: false,
+            link: None,
+        }
+    }
+
+    // the preview card, if it is sane enough to show
+    pub fn link_preview(&self) -> Option<&LinkPreview> {
+        self.link.as_ref().filter(|link| link.validate().is_ok())
+    }
+
+    pub fn content_warning(&self) -> Option<String> {
+        match &self.content_warning {
+            Some(cw) => Some(cw.clone()),
+            None if self.sensitive => Some("sensitive".to_string()),
+            None => None,
+        }
+    }
 }
 
 impl fmt::Display for Hoot {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(cw) = self.content_warning() {
+            let _ = writeln!(f, "CW: {}", cw);
+        }
         if let Some(to) = &self.quoted_posts {
             let _ = writeln!(f, "\"{}\"", to);
         }
@@ -130,7 +348,11 @@ impl fmt::Display for Hoot {
         }
         let _ = writeln!(f, "");
 
-        writeln!(f, "{}", self.text)
+        writeln!(f, "{}", self.text)?;
+        if let Some(link) = self.link_preview() {
+            writeln!(f, "{}", link)?;
+        }
+        Ok(())
     }
 }
 
@@ -139,10 +361,115 @@ mod tests {
     #[test]
     fn serde_test() {
         use super::Hoot;
-        let hoot = Hoot {text: "aaa".to_string(),quoted_posts:None,reply_to:None,mention_to:Vec::new()};
+        let hoot = Hoot::new("aaa".to_string());
         let ser=serde_json::to_string(&hoot).unwrap();
         println!("{}",ser);
         let de:Hoot = serde_json::from_str(&ser).unwrap();
         println!("{:?}",de);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn content_warning_compat_test() {
+        use super::Hoot;
+        let old = r#"{"text":"aaa"}"#;
+        let hoot: Hoot = serde_json::from_str(old).unwrap();
+        assert_eq!(hoot.content_warning, None);
+        assert!(!hoot.sensitive);
+        assert_eq!(serde_json::to_string(&hoot).unwrap(), old);
+
+        let mut hoot = Hoot::new("aaa".to_string());
+        hoot.sensitive = true;
+        assert_eq!(hoot.content_warning(), Some("sensitive".to_string()));
+    }
+    #[test]
+    fn rehoot_content_test() {
+        use super::*;
+        use crate::crypto::SecretKey;
+
+        let sk = SecretKey::random();
+        let sign = |id: u128, content: PostKind| {
+            let post = Post {
+                user_attr: UserAttribute::new("alice", 0, ""),
+                id: PostId::from(id),
+                content,
+                created_at: 0,
+            };
+            let signature = sk.sign(&post.signed_message());
+            SignedPost::new(Address::from(sk.public_key()), post, signature)
+        };
+        let pk = sk.public_key();
+        let hoot = sign(1, PostKind::Hoot(Hoot::new("hi".to_string())));
+        let carried = hoot.rehoot_content(&pk).unwrap();
+        let mut with_key = hoot.clone();
+        with_key.author_key = Some(pk.clone());
+        assert_eq!(carried, PostKind::ReHoot(Box::new(with_key.clone())));
+        assert_eq!(with_key.attached_key(), Some(&pk));
+        with_key.author_key = Some(SecretKey::random().public_key());
+        assert_eq!(with_key.attached_key(), None);
+
+        // a rehoot of it points at the hoot, however long the chain
+        let rehoot = sign(2, carried);
+        assert_eq!(rehoot.depth(), 1);
+        let pointed = rehoot.rehoot_content(&pk).unwrap();
+        assert_eq!(pointed, PostKind::ReHootRef(hoot.post_ref()));
+        assert_eq!(sign(3, pointed.clone()).rehoot_content(&pk).unwrap(), pointed);
+
+        // a reply carries the post it replies to, so it is only pointed at
+        let mut reply = Hoot::new("yes".to_string());
+        reply.reply_to = Some(Box::new(hoot.clone()));
+        let reply = sign(4, PostKind::Hoot(reply));
+        assert_eq!(reply.rehoot_content(&pk).unwrap(), PostKind::ReHootRef(reply.post_ref()));
+
+        let other = SecretKey::random().public_key();
+        assert!(matches!(hoot.rehoot_content(&other), Err(RehootError::Verify(_))));
+        let delete = sign(5, PostKind::Delete(hoot.post.id));
+        assert!(matches!(delete.rehoot_content(&pk), Err(RehootError::Delete)));
+        let gone = sign(6, PostKind::DeleteAccount);
+        assert!(matches!(gone.rehoot_content(&pk), Err(RehootError::Unsupported)));
+    }
+
+    #[test]
+    fn signature_version_test() {
+        use super::*;
+        use crate::crypto::SecretKey;
+        use crate::user::user::LEGACY_SIGNATURE;
+
+        let sk = SecretKey::random();
+        let post = Post {
+            user_attr: UserAttribute::new("alice", 0, ""),
+            id: PostId::from(1),
+            content: PostKind::Hoot(Hoot::new("hi".to_string())),
+            created_at: 0,
+        };
+        let signature = sk.sign(&post.signed_message());
+        let sigpost = SignedPost::new(Address::from(sk.public_key()), post.clone(), signature);
+        sigpost.verify(&sk.public_key()).unwrap();
+
+        // the fields of another implementation in another order give the same bytes
+        let reordered = r#"{"created_at":0,"content":{"Hoot":{"text":"hi"}},"id":1,
+            "user_attr":{"description":"","created_at":0,"name":"alice"}}"#;
+        let reordered: Post = serde_json::from_str(reordered).unwrap();
+        assert_eq!(reordered.signed_message(), post.signed_message());
+
+        // posts signed before the version was there still verify
+        let legacy = r#"{"addr":ADDR,"post":POST,"signature":SIG}"#
+            .replace("ADDR", &serde_json::to_string(&sigpost.addr).unwrap())
+            .replace("POST", &serde_json::to_string(&post).unwrap())
+            .replace(
+                "SIG",
+                &serde_json::to_string(&sk.sign(&serde_json::to_vec(&post).unwrap()).to_vec())
+                    .unwrap(),
+            );
+        let legacy = SignedPost::from_bytes(legacy.as_bytes()).unwrap();
+        assert_eq!(legacy.version, LEGACY_SIGNATURE);
+        legacy.verify(&sk.public_key()).unwrap();
+        assert!(!serde_json::to_string(&legacy).unwrap().contains("version"));
+
+        // but not under another version than the one they were signed with
+        let mut downgraded = sigpost.clone();
+        downgraded.version = LEGACY_SIGNATURE;
+        assert!(downgraded.verify(&sk.public_key()).is_err());
+        downgraded.version = 9;
+        assert!(matches!(downgraded.verify(&sk.public_key()), Err(VerifyError::Version(9))));
+    }
+}
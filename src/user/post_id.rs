@@ -0,0 +1,133 @@
+use rand::prelude::*;
+use rand_chacha::ChaCha20Rng;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+const ULID_STR_LEN: usize = 26;
+const RANDOM_BITS: u32 = 80;
+
+// A post id in the ULID layout: milliseconds since the epoch (48 bits), then
+// 80 random bits, so ids sort by time and do not tell how many posts an
+// account has. Older clients counted ids up from 0; those have no time part,
+// are still valid, and serialize the same way, so their signatures verify.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct PostId(pub u128);
+
+impl PostId {
+    // the id of a post made at now_ms, after last even if the clock went back
+    pub fn generate(now_ms: u64, last: Option<PostId>) -> PostId {
+        let mut random = [0u8; 16];
+        ChaCha20Rng::from_entropy().fill_bytes(&mut random[6..]);
+        let time = (now_ms as u128 & 0xffff_ffff_ffff) << RANDOM_BITS;
+        let id = PostId(time | u128::from_be_bytes(random));
+        match last {
+            Some(last) if id <= last => PostId(last.0 + 1),
+            _ => id,
+        }
+    }
+
+    // a sequential id of an older client
+    pub fn is_legacy(&self) -> bool {
+        self.0 >> RANDOM_BITS == 0
+    }
+
+    pub fn timestamp_ms(&self) -> Option<u64> {
+        (!self.is_legacy()).then_some((self.0 >> RANDOM_BITS) as u64)
+    }
+
+    // The id before a legacy one, which is where its author's previous post
+    // was archived. Nothing can be told for other ids.
+    pub fn legacy_prev(&self) -> Option<PostId> {
+        (self.is_legacy() && self.0 > 0).then(|| PostId(self.0 - 1))
+    }
+
+    pub fn to_be_bytes(self) -> [u8; 16] {
+        self.0.to_be_bytes()
+    }
+
+    pub fn from_be_bytes(bytes: [u8; 16]) -> PostId {
+        PostId(u128::from_be_bytes(bytes))
+    }
+}
+
+impl From<u128> for PostId {
+    fn from(id: u128) -> PostId {
+        PostId(id)
+    }
+}
+
+// Crockford base32 like other ULIDs; legacy ids stay numbers
+impl fmt::Display for PostId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_legacy() {
+            return write!(f, "{}", self.0);
+        }
+        let s: String = (0..ULID_STR_LEN)
+            .rev()
+            .map(|i| CROCKFORD[(self.0 >> (i * 5)) as usize & 0x1f] as char)
+            .collect();
+        f.write_str(&s)
+    }
+}
+
+impl FromStr for PostId {
+    type Err = PostIdError;
+
+    fn from_str(s: &str) -> Result<PostId, PostIdError> {
+        if s.len() != ULID_STR_LEN {
+            return s.parse::<u128>().map(PostId).map_err(|_| PostIdError);
+        }
+        let mut id: u128 = 0;
+        for (i, c) in s.bytes().enumerate() {
+            let digit = CROCKFORD
+                .iter()
+                .position(|d| *d == c.to_ascii_uppercase())
+                .ok_or(PostIdError)?;
+            // 26 digits hold 130 bits, the first one only 3 of them
+            if i == 0 && digit > 7 {
+                return Err(PostIdError);
+            }
+            id = id << 5 | digit as u128;
+        }
+        Ok(PostId(id))
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Invalid post id")]
+pub struct PostIdError;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn post_id_test() {
+        let first = PostId::generate(1_700_000_000_000, None);
+        assert_eq!(first.timestamp_ms(), Some(1_700_000_000_000));
+        assert!(!first.is_legacy());
+        let s = first.to_string();
+        assert_eq!(s.len(), ULID_STR_LEN);
+        assert_eq!(s.to_lowercase().parse::<PostId>().unwrap(), first);
+        let json = serde_json::to_string(&first).unwrap();
+        assert_eq!(serde_json::from_str::<PostId>(&json).unwrap(), first);
+
+        // still after the last one when the clock goes back
+        let second = PostId::generate(1_600_000_000_000, Some(first));
+        assert!(second > first);
+
+        let legacy = PostId(41);
+        assert!(legacy.is_legacy());
+        assert_eq!(legacy.to_string(), "41");
+        assert_eq!("41".parse::<PostId>().unwrap(), legacy);
+        assert_eq!(legacy.legacy_prev(), Some(PostId(40)));
+        assert_eq!(serde_json::to_string(&legacy).unwrap(), "41");
+        assert!("8ZZZZZZZZZZZZZZZZZZZZZZZZZ".parse::<PostId>().is_err());
+    }
+}
@@ -0,0 +1,217 @@
+use chrono::format::{Item, StrftimeItems};
+use chrono::{FixedOffset, Local, Offset, TimeZone};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt::{self, Write};
+use thiserror::Error;
+
+// times at most this far from now are written like "5m ago" when asked for
+pub const RELATIVE_LIMIT: u64 = 7 * 86400; // 1 week
+
+// the zone times are shown in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Zone {
+    #[default]
+    Local,
+    Utc,
+    // seconds east of UTC
+    Fixed(i32),
+}
+
+impl Zone {
+    // "local", "utc", or an offset like "+09:00" or "-0530"
+    pub fn parse(s: &str) -> Result<Zone, TimeFormatError> {
+        let s = s.trim();
+        match s.to_ascii_lowercase().as_str() {
+            "local" => return Ok(Zone::Local),
+            "utc" | "z" => return Ok(Zone::Utc),
+            _ => (),
+        }
+        let invalid = || TimeFormatError::Zone(s.to_string());
+        let (sign, rest) = match s.chars().next() {
+            Some('+') => (1, &s[1..]),
+            Some('-') => (-1, &s[1..]),
+            _ => return Err(invalid()),
+        };
+        let digits: String = rest.chars().filter(|c| *c != ':').collect();
+        if digits.len() != 4 || !digits.chars().all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let hours: i32 = digits[..2].parse().unwrap();
+        let minutes: i32 = digits[2..].parse().unwrap();
+        let offset = sign * (hours * 3600 + minutes * 60);
+        match FixedOffset::east_opt(offset) {
+            Some(_) if minutes < 60 => Ok(Zone::Fixed(offset)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Zone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Zone::Local => write!(f, "local"),
+            Zone::Utc => write!(f, "utc"),
+            Zone::Fixed(offset) => {
+                let sign = if *offset < 0 { '-' } else { '+' };
+                let offset = offset.abs();
+                write!(f, "{}{:02}:{:02}", sign, offset / 3600, offset % 3600 / 60)
+            }
+        }
+    }
+}
+
+// How times are written for people. The pattern is strftime's, and carries
+// the order of the fields a locale writes them in; the zone is applied first.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeFormat {
+    zone: Zone,
+    pattern: String,
+    // times within RELATIVE_LIMIT of now are written like "5m ago"
+    relative: bool,
+}
+
+impl Default for TimeFormat {
+    fn default() -> TimeFormat {
+        TimeFormat {
+            zone: Zone::Local,
+            pattern: "%Y/%m/%d %H:%M:%S".to_string(),
+            relative: false,
+        }
+    }
+}
+
+impl TimeFormat {
+    pub fn new(zone: Zone, pattern: &str) -> Result<TimeFormat, TimeFormatError> {
+        if StrftimeItems::new(pattern).any(|item| matches!(item, Item::Error)) {
+            return Err(TimeFormatError::Pattern(pattern.to_string()));
+        }
+        Ok(TimeFormat {
+            zone,
+            pattern: pattern.to_string(),
+            relative: false,
+        })
+    }
+
+    // the dates of the default format, without the time of day
+    pub fn date() -> TimeFormat {
+        TimeFormat {
+            pattern: "%Y/%m/%d".to_string(),
+            ..TimeFormat::default()
+        }
+    }
+
+    pub fn with_zone(self, zone: Zone) -> TimeFormat {
+        TimeFormat { zone, ..self }
+    }
+
+    pub fn with_relative(self, relative: bool) -> TimeFormat {
+        TimeFormat { relative, ..self }
+    }
+
+    pub fn zone(&self) -> Zone {
+        self.zone
+    }
+
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn is_relative(&self) -> bool {
+        self.relative
+    }
+
+    // secs since the epoch, relative to now_secs if asked for and recent enough
+    pub fn format(&self, secs: u64, now_secs: u64) -> String {
+        if self.relative && secs.abs_diff(now_secs) <= RELATIVE_LIMIT {
+            relative(secs, now_secs)
+        } else {
+            self.absolute(secs)
+        }
+    }
+
+    // secs since the epoch in the zone; "?" if it cannot be represented there
+    pub fn absolute(&self, secs: u64) -> String {
+        let secs = match i64::try_from(secs) {
+            Ok(secs) => secs,
+            Err(_) => return "?".to_string(),
+        };
+        let offset = match self.zone {
+            Zone::Local => Local.timestamp_opt(secs, 0).single().map(|t| t.offset().fix()),
+            Zone::Utc => FixedOffset::east_opt(0),
+            Zone::Fixed(offset) => FixedOffset::east_opt(offset),
+        };
+        let mut s = String::new();
+        match offset.and_then(|zone| zone.timestamp_opt(secs, 0).single()) {
+            // a pattern read from an old setting may not parse, which fails the write
+            Some(t) if write!(s, "{}", t.format(&self.pattern)).is_ok() => s,
+            _ => "?".to_string(),
+        }
+    }
+
+    // the same for ms since the epoch
+    pub fn absolute_ms(&self, ms: u64) -> String {
+        self.absolute(ms / 1000)
+    }
+}
+
+// like "5m ago", or "in 2h" for a time ahead of now_secs, in the largest whole unit
+pub fn relative(secs: u64, now_secs: u64) -> String {
+    let diff = secs.abs_diff(now_secs);
+    let amount = match diff {
+        0 => return "just now".to_string(),
+        d if d < 60 => format!("{}s", d),
+        d if d < 3600 => format!("{}m", d / 60),
+        d if d < 86400 => format!("{}h", d / 3600),
+        d if d < 365 * 86400 => format!("{}d", d / 86400),
+        d => format!("{}y", d / (365 * 86400)),
+    };
+    if secs > now_secs {
+        format!("in {}", amount)
+    } else {
+        format!("{} ago", amount)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum TimeFormatError {
+    #[error("Invalid time zone {0}, use local, utc or an offset like +09:00")]
+    Zone(String),
+    #[error("Invalid time pattern {0}")]
+    Pattern(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_format_test() {
+        let utc = TimeFormat::new(Zone::Utc, "%Y-%m-%d %H:%M").unwrap();
+        assert_eq!(utc.absolute(0), "1970-01-01 00:00");
+        let tokyo = utc.clone().with_zone(Zone::parse("+09:00").unwrap());
+        assert_eq!(tokyo.absolute(0), "1970-01-01 09:00");
+        assert_eq!(tokyo.absolute_ms(3600 * 1000), "1970-01-01 10:00");
+
+        // out of range, instead of a panic
+        assert_eq!(utc.absolute(u64::MAX), "?");
+        assert_eq!(utc.absolute(i64::MAX as u64), "?");
+        assert!(TimeFormat::new(Zone::Utc, "%Q").is_err());
+
+        let now = 1_000_000;
+        let relative = utc.clone().with_relative(true);
+        assert_eq!(relative.format(now - 300, now), "5m ago");
+        assert_eq!(relative.format(now + 7200, now), "in 2h");
+        assert_eq!(relative.format(now, now), "just now");
+        let old = now - RELATIVE_LIMIT - 1;
+        assert_eq!(relative.format(old, now), utc.absolute(old));
+        assert_eq!(utc.format(now - 300, now), utc.absolute(now - 300));
+
+        assert_eq!(Zone::parse("UTC").unwrap(), Zone::Utc);
+        assert_eq!(Zone::parse("-0530").unwrap(), Zone::Fixed(-(5 * 3600 + 30 * 60)));
+        assert_eq!(Zone::Fixed(-(5 * 3600 + 30 * 60)).to_string(), "-05:30");
+        assert!(Zone::parse("+25:00").is_err());
+        assert!(Zone::parse("+09:75").is_err());
+        assert!(Zone::parse("Asia/Tokyo").is_err());
+    }
+}
@@ -1,26 +1,51 @@
 use crate::crypto::{Ed25519Error, PublicKey};
 use crate::kad::Key;
-use crate::util::base64;
+use crate::util::{base64, canonical};
 
 use serde::{Deserialize, Serialize};
 use serde_big_array::BigArray;
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 use thiserror::Error;
 
+// How the signature of a post or a profile was made. Legacy ones are over the
+// bytes serde_json gave for the struct, which depend on the field order of
+// this implementation; canonical ones are over util::canonical. Records
+// without a version are legacy, and new ones are always canonical.
+pub const LEGACY_SIGNATURE: u8 = 0;
+pub const CANONICAL_SIGNATURE: u8 = 1;
+
+pub(crate) fn is_legacy_signature(version: &u8) -> bool {
+    *version == LEGACY_SIGNATURE
+}
+
+// the bytes signed for value under the signature version
+pub fn signed_bytes<T: Serialize>(value: &T, version: u8) -> Result<Vec<u8>, VerifyError> {
+    match version {
+        LEGACY_SIGNATURE => Ok(serde_json::to_vec(value).unwrap()),
+        CANONICAL_SIGNATURE => Ok(canonical::to_vec(value)),
+        _ => Err(VerifyError::Version(version)),
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct SignedUserAttribute {
     pub addr: Address,
     pub attr: UserAttribute,
     #[serde(with = "BigArray")]
-    pub signature: [u8; 64]
+    pub signature: [u8; 64],
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_legacy_signature")]
+    pub version: u8,
 }
 
 impl SignedUserAttribute {
+    // signature is over attr.signed_message()
     pub fn new(addr: Address, attr: UserAttribute, signature: [u8; 64]) -> SignedUserAttribute {
         SignedUserAttribute {
             addr,
             attr,
             signature,
+            version: CANONICAL_SIGNATURE,
         }
     }
 
@@ -36,7 +61,7 @@ impl SignedUserAttribute {
                 pubkey
                     .verify(
                         &self.signature[..].try_into().unwrap(),
-                        &serde_json::to_vec(&self.attr).unwrap(),
+                        &signed_bytes(&self.attr, self.version)?,
                     )
                     .map_err(|e| VerifyError::Signature(e))
             }
@@ -44,6 +69,265 @@ impl SignedUserAttribute {
     }
 }
 
+// A profile as stored in the user DHT, with the key it is signed with, so that
+// nodes storing it can check it without looking the key up
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProfileRecord {
+    pub pubkey: PublicKey,
+    pub profile: SignedUserAttribute,
+}
+
+impl ProfileRecord {
+    pub fn new(pubkey: &PublicKey, profile: &SignedUserAttribute) -> ProfileRecord {
+        ProfileRecord {
+            pubkey: pubkey.clone(),
+            profile: profile.clone(),
+        }
+    }
+
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        self.profile.verify(&self.pubkey)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<ProfileRecord> {
+        serde_json::from_slice(bytes).ok()
+    }
+}
+
+// Stored in the user DHT in place of the address/public key pair once an
+// account is deleted. Binary layout: addr(32) | pubkey(32) | signature(64) | deleted_at(8, BE)
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct AccountTombstone {
+    pub addr: Address,
+    pub pubkey: PublicKey,
+    #[serde(with = "BigArray")]
+    pub signature: [u8; 64],
+    pub deleted_at: u64,
+}
+
+pub const ACCOUNT_TOMBSTONE_LEN: usize = 136;
+
+impl AccountTombstone {
+    pub fn new(pubkey: &PublicKey, deleted_at: u64, signature: [u8; 64]) -> AccountTombstone {
+        AccountTombstone {
+            addr: Address::from(pubkey.clone()),
+            pubkey: pubkey.clone(),
+            signature,
+            deleted_at,
+        }
+    }
+
+    // the message signed by the account key
+    pub fn signed_message(addr: &Address, deleted_at: u64) -> Vec<u8> {
+        [
+            &b"noktulo:delete-account:"[..],
+            &addr.address[..],
+            &deleted_at.to_be_bytes()[..],
+        ]
+        .concat()
+    }
+
+    pub fn verify(&self) -> Result<PublicKey, VerifyError> {
+        if self.addr != Address::from(self.pubkey.clone()) {
+            return Err(VerifyError::Address);
+        }
+        self.pubkey
+            .verify(
+                &self.signature,
+                &AccountTombstone::signed_message(&self.addr, self.deleted_at),
+            )
+            .map_err(VerifyError::Signature)?;
+        Ok(self.pubkey.clone())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [
+            &self.addr.address[..],
+            &self.pubkey.to_bytes()[..],
+            &self.signature[..],
+            &self.deleted_at.to_be_bytes()[..],
+        ]
+        .concat()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<AccountTombstone, VerifyError> {
+        if bytes.len() != ACCOUNT_TOMBSTONE_LEN {
+            return Err(VerifyError::Size);
+        }
+        Ok(AccountTombstone {
+            addr: Address::new(bytes[..32].try_into().unwrap()),
+            pubkey: PublicKey::try_from(&bytes[32..64]).map_err(VerifyError::Signature)?,
+            signature: bytes[64..128].try_into().unwrap(),
+            deleted_at: u64::from_be_bytes(bytes[128..].try_into().unwrap()),
+        })
+    }
+}
+
+// Registers the public key of an address in the user DHT. Signed by the key
+// itself, so only its owner can register it. The first valid registration of
+// an address wins; later ones are only accepted from the same key.
+// Binary layout: pubkey(32) | signature(64) | registered_at(8, BE)
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RegistrationRecord {
+    pub pubkey: PublicKey,
+    #[serde(with = "BigArray")]
+    pub signature: [u8; 64],
+    pub registered_at: u64,
+}
+
+pub const REGISTRATION_RECORD_LEN: usize = 104;
+
+impl RegistrationRecord {
+    pub fn new(pubkey: &PublicKey, registered_at: u64, signature: [u8; 64]) -> RegistrationRecord {
+        RegistrationRecord {
+            pubkey: pubkey.clone(),
+            signature,
+            registered_at,
+        }
+    }
+
+    pub fn addr(&self) -> Address {
+        Address::from(self.pubkey.clone())
+    }
+
+    // the message signed by the account key
+    pub fn signed_message(addr: &Address, registered_at: u64) -> Vec<u8> {
+        [
+            &b"noktulo:register:"[..],
+            &addr.address[..],
+            &registered_at.to_be_bytes()[..],
+        ]
+        .concat()
+    }
+
+    pub fn verify(&self) -> Result<PublicKey, VerifyError> {
+        self.pubkey
+            .verify(
+                &self.signature,
+                &RegistrationRecord::signed_message(&self.addr(), self.registered_at),
+            )
+            .map_err(VerifyError::Signature)?;
+        Ok(self.pubkey.clone())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [
+            &self.pubkey.to_bytes()[..],
+            &self.signature[..],
+            &self.registered_at.to_be_bytes()[..],
+        ]
+        .concat()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<RegistrationRecord, VerifyError> {
+        if bytes.len() != REGISTRATION_RECORD_LEN {
+            return Err(VerifyError::Size);
+        }
+        Ok(RegistrationRecord {
+            pubkey: PublicKey::try_from(&bytes[..32]).map_err(VerifyError::Signature)?,
+            signature: bytes[32..96].try_into().unwrap(),
+            registered_at: u64::from_be_bytes(bytes[96..].try_into().unwrap()),
+        })
+    }
+}
+
+// Moves an account to a new key, in place of the address/public key pair of
+// the old address. Signed by both keys, so neither side can be claimed alone.
+// Binary layout: old_pubkey(32) | new_pubkey(32) | old_signature(64) | new_signature(64)
+// | migrated_at(8, BE)
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct MigrationRecord {
+    pub old_pubkey: PublicKey,
+    pub new_pubkey: PublicKey,
+    #[serde(with = "BigArray")]
+    pub old_signature: [u8; 64],
+    #[serde(with = "BigArray")]
+    pub new_signature: [u8; 64],
+    pub migrated_at: u64,
+}
+
+pub const MIGRATION_RECORD_LEN: usize = 200;
+
+impl MigrationRecord {
+    pub fn new(
+        old_pubkey: &PublicKey,
+        new_pubkey: &PublicKey,
+        migrated_at: u64,
+        old_signature: [u8; 64],
+        new_signature: [u8; 64],
+    ) -> MigrationRecord {
+        MigrationRecord {
+            old_pubkey: old_pubkey.clone(),
+            new_pubkey: new_pubkey.clone(),
+            old_signature,
+            new_signature,
+            migrated_at,
+        }
+    }
+
+    pub fn old_addr(&self) -> Address {
+        Address::from(self.old_pubkey.clone())
+    }
+
+    pub fn new_addr(&self) -> Address {
+        Address::from(self.new_pubkey.clone())
+    }
+
+    // the message signed by both keys
+    pub fn signed_message(old_addr: &Address, new_addr: &Address, migrated_at: u64) -> Vec<u8> {
+        [
+            &b"noktulo:migrate:"[..],
+            &old_addr.address[..],
+            &new_addr.address[..],
+            &migrated_at.to_be_bytes()[..],
+        ]
+        .concat()
+    }
+
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        let old_addr = self.old_addr();
+        let new_addr = self.new_addr();
+        if old_addr == new_addr {
+            return Err(VerifyError::Address);
+        }
+        let message = MigrationRecord::signed_message(&old_addr, &new_addr, self.migrated_at);
+        self.old_pubkey
+            .verify(&self.old_signature, &message)
+            .map_err(VerifyError::Signature)?;
+        self.new_pubkey
+            .verify(&self.new_signature, &message)
+            .map_err(VerifyError::Signature)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [
+            &self.old_pubkey.to_bytes()[..],
+            &self.new_pubkey.to_bytes()[..],
+            &self.old_signature[..],
+            &self.new_signature[..],
+            &self.migrated_at.to_be_bytes()[..],
+        ]
+        .concat()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<MigrationRecord, VerifyError> {
+        if bytes.len() != MIGRATION_RECORD_LEN {
+            return Err(VerifyError::Size);
+        }
+        Ok(MigrationRecord {
+            old_pubkey: PublicKey::try_from(&bytes[..32]).map_err(VerifyError::Signature)?,
+            new_pubkey: PublicKey::try_from(&bytes[32..64]).map_err(VerifyError::Signature)?,
+            old_signature: bytes[64..128].try_into().unwrap(),
+            new_signature: bytes[128..192].try_into().unwrap(),
+            migrated_at: u64::from_be_bytes(bytes[192..].try_into().unwrap()),
+        })
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum VerifyError {
     #[error("Invalid address")]
@@ -52,6 +336,8 @@ pub enum VerifyError {
     Signature(Ed25519Error),
     #[error("Invalid size")]
     Size,
+    #[error("Unknown signature version {0}")]
+    Version(u8),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -69,8 +355,15 @@ impl UserAttribute {
             description: description.to_string(),
         }
     }
+
+    // what a SignedUserAttribute::new signature is over
+    pub fn signed_message(&self) -> Vec<u8> {
+        canonical::to_vec(self)
+    }
 }
 
+pub const ADDRESS_STR_LEN: usize = 48;
+
 #[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Address {
     address: [u8; 32],
@@ -103,6 +396,39 @@ impl Address {
         }
     }
 
+    // Unlike from_str, every failure is reported precisely: the string must be
+    // exactly ADDRESS_STR_LEN base64 characters with a valid checksum.
+    pub fn parse_strict(s: &str) -> Result<Address, AddressError> {
+        if let Some((i, c)) = s
+            .chars()
+            .enumerate()
+            .find(|(_, c)| !(c.is_ascii_alphanumeric() || *c == '+' || *c == '/'))
+        {
+            return Err(AddressError::Character(i, c));
+        }
+
+        let len = s.chars().count();
+        if len < ADDRESS_STR_LEN {
+            Err(AddressError::Truncated(len))
+        } else if len > ADDRESS_STR_LEN {
+            Err(AddressError::TooLong(len))
+        } else {
+            Address::from_str(s)
+        }
+    }
+
+    // known addresses which are at most two edits away from s, closest first
+    pub fn suggest(s: &str, known: &[Address]) -> Vec<Address> {
+        let mut candidates: Vec<_> = known
+            .iter()
+            .map(|addr| (edit_distance(s, &addr.to_string()), addr))
+            .filter(|(d, _)| *d <= 2)
+            .collect();
+        candidates.sort_by_key(|(d, _)| *d);
+        candidates.dedup_by(|a, b| a.1 == b.1);
+        candidates.into_iter().map(|(_, addr)| addr.clone()).collect()
+    }
+
     pub fn to_string(&self) -> String {
         let payload = [
             &self.address,
@@ -134,6 +460,20 @@ impl Address {
     }
 }
 
+pub(crate) fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            cur.push((prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1));
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
 impl From<PublicKey> for Address {
     fn from(pubkey: PublicKey) -> Address {
         Address {
@@ -171,4 +511,93 @@ pub enum AddressError {
     Checksum,
     #[error("Invalid character")]
     Base64(base64::Base64Error),
+    #[error("Address is truncated ({0} of {len} characters)", len = ADDRESS_STR_LEN)]
+    Truncated(usize),
+    #[error("Address is too long ({0} of {len} characters)", len = ADDRESS_STR_LEN)]
+    TooLong(usize),
+    #[error("Invalid character {1:?} at position {0}")]
+    Character(usize, char),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AccountTombstone, Address, AddressError, MigrationRecord};
+    use crate::crypto::SecretKey;
+
+    #[test]
+    fn tombstone_test() {
+        let sk = SecretKey::random();
+        let addr = Address::from(sk.public_key());
+        let sig = sk.sign(&AccountTombstone::signed_message(&addr, 1000));
+        let tombstone = AccountTombstone::new(&sk.public_key(), 1000, sig);
+
+        let bytes = tombstone.to_bytes();
+        let decoded = AccountTombstone::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded, tombstone);
+        assert_eq!(decoded.verify().unwrap(), sk.public_key());
+
+        let mut forged = decoded;
+        forged.deleted_at = 2000;
+        assert!(forged.verify().is_err());
+    }
+
+    #[test]
+    fn migration_record_test() {
+        let old = SecretKey::random();
+        let new = SecretKey::random();
+        let message = MigrationRecord::signed_message(
+            &Address::from(old.public_key()),
+            &Address::from(new.public_key()),
+            1000,
+        );
+        let record = MigrationRecord::new(
+            &old.public_key(),
+            &new.public_key(),
+            1000,
+            old.sign(&message),
+            new.sign(&message),
+        );
+        let decoded = MigrationRecord::from_bytes(&record.to_bytes()).unwrap();
+        assert_eq!(decoded, record);
+        assert!(decoded.verify().is_ok());
+
+        // the new key has to agree
+        let mut forged = record;
+        forged.new_signature = old.sign(&message);
+        assert!(forged.verify().is_err());
+    }
+
+    #[test]
+    fn parse_strict_test() {
+        let addr = Address::new([7; 32]);
+        let s = addr.to_string();
+        assert_eq!(Address::parse_strict(&s).unwrap(), addr);
+
+        assert!(matches!(
+            Address::parse_strict(&s[..40]),
+            Err(AddressError::Truncated(40))
+        ));
+        assert!(matches!(
+            Address::parse_strict(&format!("{}A", s)),
+            Err(AddressError::TooLong(49))
+        ));
+        assert_eq!(
+            AddressError::Truncated(40).to_string(),
+            "Address is truncated (40 of 48 characters)"
+        );
+        assert!(matches!(
+            Address::parse_strict(&format!("{}!", &s[..47])),
+            Err(AddressError::Character(47, '!'))
+        ));
+
+        let mut typo: Vec<char> = s.chars().collect();
+        typo[10] = if typo[10] == 'x' { 'y' } else { 'x' };
+        let typo: String = typo.into_iter().collect();
+        assert!(matches!(
+            Address::parse_strict(&typo),
+            Err(AddressError::Checksum)
+        ));
+        let other = Address::new([8; 32]);
+        assert_eq!(Address::suggest(&typo, &[other, addr.clone()]), vec![addr]);
+    }
 }
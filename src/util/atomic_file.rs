@@ -0,0 +1,140 @@
+use log::warn;
+use sha2::{Digest, Sha256};
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+
+// Files written here start with the magic and a SHA-256 of the rest, so that
+// a torn or damaged file is told apart from a good one. Files without the
+// magic are from before, and read as they are.
+const MAGIC: &[u8; 12] = b"noktulo:sum1";
+const HEADER_LEN: usize = 44;
+
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name: OsString = path.file_name().unwrap_or_default().to_owned();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+// where the copy before the last write is kept
+pub fn backup_path(path: &Path) -> PathBuf {
+    sibling(path, ".bak")
+}
+
+fn seal(data: &[u8]) -> Vec<u8> {
+    [&MAGIC[..], &Sha256::digest(data)[..], data].concat()
+}
+
+// the data of a sealed file, or of one from before; None if it is damaged
+fn open(bytes: &[u8]) -> Option<&[u8]> {
+    if !bytes.starts_with(MAGIC) {
+        return Some(bytes);
+    }
+    if bytes.len() < HEADER_LEN {
+        return None;
+    }
+    let (sum, data) = bytes[MAGIC.len()..].split_at(HEADER_LEN - MAGIC.len());
+    (Sha256::digest(data)[..] == *sum).then_some(data)
+}
+
+// Replaces the file at path so that a crash leaves either the old or the new
+// contents: the data goes to a temporary file which is synced and renamed over
+// path. The old file is kept as the backup, read when path turns out damaged.
+pub async fn write(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = sibling(path, ".tmp");
+    let mut file = File::create(&tmp).await?;
+    file.write_all(&seal(data)).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    match fs::rename(path, backup_path(path)).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+    fs::rename(&tmp, path).await?;
+    // the renames are only durable once the directory is
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        File::open(dir).await?.sync_all().await?;
+    }
+    Ok(())
+}
+
+// The data last written to path. A missing or damaged file falls back to the
+// backup; NotFound if there is neither, InvalidData if both are damaged.
+pub async fn read(path: &Path) -> io::Result<Vec<u8>> {
+    let mut damaged = false;
+    for (i, candidate) in [path.to_path_buf(), backup_path(path)].iter().enumerate() {
+        match fs::read(candidate).await {
+            Ok(bytes) => match open(&bytes) {
+                Some(data) => {
+                    if i > 0 {
+                        warn!("{} is missing or damaged, read the backup", path.display());
+                    }
+                    return Ok(data.to_vec());
+                }
+                None => {
+                    warn!("{} is damaged", candidate.display());
+                    damaged = true;
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Err(if damaged {
+        io::Error::new(io::ErrorKind::InvalidData, "the file and its backup are damaged")
+    } else {
+        io::Error::from(io::ErrorKind::NotFound)
+    })
+}
+
+// like a removal with remove_file, but the backup goes too
+pub async fn remove(path: &Path) -> io::Result<()> {
+    for candidate in [path.to_path_buf(), backup_path(path)] {
+        match fs::remove_file(candidate).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn atomic_file_test() {
+        let dir = std::env::temp_dir().join(format!("noktulo_atomic_{}", std::process::id()));
+        fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("users");
+
+        // a file from before is read as it is
+        fs::write(&path, b"[]").await.unwrap();
+        assert_eq!(read(&path).await.unwrap(), b"[]");
+
+        write(&path, b"first").await.unwrap();
+        write(&path, b"second").await.unwrap();
+        assert_eq!(read(&path).await.unwrap(), b"second");
+
+        // a torn write falls back to the copy before it
+        let mut torn = fs::read(&path).await.unwrap();
+        torn.truncate(torn.len() - 2);
+        fs::write(&path, &torn).await.unwrap();
+        assert_eq!(read(&path).await.unwrap(), b"first");
+        fs::remove_file(&path).await.unwrap();
+        assert_eq!(read(&path).await.unwrap(), b"first");
+
+        fs::write(backup_path(&path), &torn).await.unwrap();
+        let e = read(&path).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        remove(&path).await.unwrap();
+        assert_eq!(read(&path).await.unwrap_err().kind(), io::ErrorKind::NotFound);
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,63 @@
+use serde::Serialize;
+use serde_json::Value;
+
+// A canonical JSON encoding, for the bytes which get signed: object keys are
+// sorted by their UTF-8 bytes, there is no whitespace, and strings are escaped
+// the way serde_json does it (only '"', '\' and control characters). The same
+// value gives the same bytes whatever order the fields are declared or read
+// in, so another implementation can reproduce them from the parsed document.
+pub fn to_vec<T: Serialize + ?Sized>(value: &T) -> Vec<u8> {
+    let value = serde_json::to_value(value).unwrap();
+    let mut out = Vec::new();
+    write_value(&value, &mut out);
+    out
+}
+
+fn write_value(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<(&String, &Value)> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+            out.push(b'{');
+            for (i, (key, value)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(b',');
+                }
+                serde_json::to_writer(&mut *out, key).unwrap();
+                out.push(b':');
+                write_value(value, out);
+            }
+            out.push(b'}');
+        }
+        Value::Array(values) => {
+            out.push(b'[');
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    out.push(b',');
+                }
+                write_value(value, out);
+            }
+            out.push(b']');
+        }
+        scalar => serde_json::to_writer(&mut *out, scalar).unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::to_vec;
+
+    #[test]
+    fn canonical_test() {
+        let a: serde_json::Value =
+            serde_json::from_str(r#"{ "b": [1, {"y": "é", "x": null}], "a": "q\"\n" }"#)
+                .unwrap();
+        let b: serde_json::Value =
+            serde_json::from_str(r#"{"a":"q\"\n","b":[1,{"x":null,"y":"é"}]}"#).unwrap();
+        assert_eq!(to_vec(&a), to_vec(&b));
+        assert_eq!(
+            String::from_utf8(to_vec(&a)).unwrap(),
+            r#"{"a":"q\"\n","b":[1,{"x":null,"y":"é"}]}"#
+        );
+    }
+}
@@ -0,0 +1,115 @@
+use futures::future::BoxFuture;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+use tokio::time::{Duration, Instant};
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+// Time only moves forward when advance() is called.
+// Sleeps are registered when sleep() is called, not when the future is first polled.
+#[derive(Clone)]
+pub struct MockClock {
+    state: Arc<Mutex<MockState>>,
+}
+
+struct MockState {
+    now: Instant,
+    sleepers: Vec<(Instant, oneshot::Sender<()>)>,
+}
+
+impl MockClock {
+    pub fn new() -> MockClock {
+        MockClock {
+            state: Arc::new(Mutex::new(MockState {
+                now: Instant::now(),
+                sleepers: Vec::new(),
+            })),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.now += duration;
+        let now = state.now;
+        let (due, pending) = state
+            .sleepers
+            .drain(..)
+            .partition::<Vec<_>, _>(|(deadline, _)| *deadline <= now);
+        state.sleepers = pending;
+        drop(state);
+
+        for (_, tx) in due {
+            let _ = tx.send(());
+        }
+    }
+
+    pub fn sleepers(&self) -> usize {
+        self.state.lock().unwrap().sleepers.len()
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> MockClock {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.state.lock().unwrap().now
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        let (tx, rx) = oneshot::channel();
+        let mut state = self.state.lock().unwrap();
+        if duration.is_zero() {
+            let _ = tx.send(());
+        } else {
+            let deadline = state.now + duration;
+            state.sleepers.push((deadline, tx));
+        }
+        Box::pin(async move {
+            let _ = rx.await;
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Clock, MockClock};
+    use futures::FutureExt;
+    use tokio::time::Duration;
+
+    #[test]
+    fn mock_clock_test() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let mut short = clock.sleep(Duration::from_secs(1));
+        let mut long = clock.sleep(Duration::from_secs(10));
+        assert!((&mut short).now_or_never().is_none());
+
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(clock.now() - start, Duration::from_secs(2));
+        assert!((&mut short).now_or_never().is_some());
+        assert!((&mut long).now_or_never().is_none());
+        assert_eq!(clock.sleepers(), 1);
+
+        clock.advance(Duration::from_secs(8));
+        assert!(long.now_or_never().is_some());
+    }
+}
@@ -0,0 +1,39 @@
+use once_cell::sync::Lazy;
+use std::thread;
+use tokio::sync::Semaphore;
+use tokio::task;
+
+// Signature checks take milliseconds, which is long enough to delay RPC timers
+// when they run on the async workers. They run on the blocking threads instead,
+// and only as many at once as there are cores, so that a flood of store
+// requests queues up here rather than starving the runtime.
+static PERMITS: Lazy<Semaphore> = Lazy::new(|| {
+    Semaphore::new(thread::available_parallelism().map_or(1, |n| n.get()))
+});
+
+pub async fn run<F, T>(job: F) -> T
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let _permit = PERMITS.acquire().await.unwrap();
+    match task::spawn_blocking(job).await {
+        Ok(ret) => ret,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn run_test() {
+        let jobs: Vec<_> = (0..8u64).map(|i| tokio::spawn(run(move || i * 2))).collect();
+        let mut sum = 0;
+        for job in jobs {
+            sum += job.await.unwrap();
+        }
+        assert_eq!(sum, 56);
+    }
+}
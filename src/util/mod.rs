@@ -1 +1,7 @@
-pub mod base64;
\ No newline at end of file
+pub mod atomic_file;
+pub mod base64;
+pub mod canonical;
+pub mod clock;
+pub(crate) mod crypto_pool;
+pub mod rng;
+pub mod stats;
\ No newline at end of file
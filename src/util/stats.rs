@@ -0,0 +1,575 @@
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Totals over every session. peers_seen counts distinct peers per session.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stats {
+    pub posts_sent: u64,
+    pub posts_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub peers_seen: u64,
+    pub uptime: u64, // seconds
+    // missing in stats saved by older versions
+    #[serde(default)]
+    pub malformed_received: u64,
+    #[serde(default)]
+    pub peers_banned: u64,
+    // protocol version -> peers seen speaking it
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub peer_versions: BTreeMap<u32, u64>,
+    // from created_at to verified receipt, over all authors and per author address
+    #[serde(default, skip_serializing_if = "DelayHistogram::is_empty")]
+    pub delivery_delay: DelayHistogram,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub delivery_delay_by_author: BTreeMap<String, DelayHistogram>,
+    // DHT messages per net_id
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub traffic_by_net: BTreeMap<String, NetTraffic>,
+    // stores refused because they would replace a registered account record
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub overwrites_rejected: u64,
+    // stores refused because the node's store was at its limit
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub stores_refused_full: u64,
+    // DHT messages dropped because their queue was full
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub messages_dropped: u64,
+    // what became of the requests waiting for a reply
+    #[serde(default, skip_serializing_if = "PendingCounts::is_empty")]
+    pub pending_requests: PendingCounts,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetTraffic {
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+    pub messages_received: u64,
+    pub bytes_received: u64,
+    // requests which got no reply in time
+    pub timeouts: u64,
+}
+
+impl NetTraffic {
+    pub fn merge(&mut self, other: &NetTraffic) {
+        self.messages_sent += other.messages_sent;
+        self.bytes_sent += other.bytes_sent;
+        self.messages_received += other.messages_received;
+        self.bytes_received += other.bytes_received;
+        self.timeouts += other.timeouts;
+    }
+}
+
+// Requests leave the pending map answered, timed out, or evicted when it was
+// full. Leaked ones were still there past the expiry, having lost their
+// timeout; any of those is a bug.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingCounts {
+    pub inserted: u64,
+    pub answered: u64,
+    pub timed_out: u64,
+    pub evicted: u64,
+    pub leaked: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PendingEvent {
+    Inserted,
+    Answered,
+    TimedOut,
+    Evicted,
+    Leaked,
+}
+
+impl PendingCounts {
+    pub fn is_empty(&self) -> bool {
+        *self == PendingCounts::default()
+    }
+
+    pub fn merge(&mut self, other: &PendingCounts) {
+        self.inserted += other.inserted;
+        self.answered += other.answered;
+        self.timed_out += other.timed_out;
+        self.evicted += other.evicted;
+        self.leaked += other.leaked;
+    }
+
+    fn record(&mut self, event: PendingEvent) {
+        match event {
+            PendingEvent::Inserted => self.inserted += 1,
+            PendingEvent::Answered => self.answered += 1,
+            PendingEvent::TimedOut => self.timed_out += 1,
+            PendingEvent::Evicted => self.evicted += 1,
+            PendingEvent::Leaked => self.leaked += 1,
+        }
+    }
+}
+
+// upper bounds of the delay buckets in milliseconds; one more bucket holds the rest
+pub const DELAY_BUCKETS: [u64; 6] = [1000, 2000, 5000, 10000, 30000, 60000];
+
+// Posts only carry created_at in seconds, so finer buckets would mean nothing.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DelayHistogram {
+    pub counts: Vec<u64>,
+    pub total_ms: u64,
+    pub max_ms: u64,
+}
+
+impl DelayHistogram {
+    pub fn record(&mut self, delay_ms: u64) {
+        self.counts.resize(DELAY_BUCKETS.len() + 1, 0);
+        let bucket = DELAY_BUCKETS
+            .iter()
+            .position(|bound| delay_ms <= *bound)
+            .unwrap_or(DELAY_BUCKETS.len());
+        self.counts[bucket] += 1;
+        self.total_ms += delay_ms;
+        self.max_ms = self.max_ms.max(delay_ms);
+    }
+
+    pub fn merge(&mut self, other: &DelayHistogram) {
+        self.counts.resize(DELAY_BUCKETS.len() + 1, 0);
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.total_ms += other.total_ms;
+        self.max_ms = self.max_ms.max(other.max_ms);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count() == 0
+    }
+
+    pub fn mean_ms(&self) -> Option<u64> {
+        self.total_ms.checked_div(self.count())
+    }
+
+    // the upper bound of the bucket holding the p-th percentile; max_ms past the last bound
+    pub fn percentile_ms(&self, p: u64) -> Option<u64> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = (count * p).div_ceil(100).max(1);
+        let mut seen = 0;
+        for (i, n) in self.counts.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Some(DELAY_BUCKETS.get(i).copied().unwrap_or(self.max_ms));
+            }
+        }
+        None
+    }
+}
+
+impl fmt::Display for DelayHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.mean_ms(), self.percentile_ms(50), self.percentile_ms(99)) {
+            (Some(mean), Some(p50), Some(p99)) => write!(
+                f,
+                "{} posts, mean {:.1}s, p50 <= {}s, p99 <= {}s, max {:.1}s",
+                self.count(),
+                mean as f64 / 1000.0,
+                p50 / 1000,
+                p99 / 1000,
+                self.max_ms as f64 / 1000.0
+            ),
+            _ => write!(f, "no posts"),
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Posts sent:     {}", self.posts_sent)?;
+        writeln!(f, "Posts received: {}", self.posts_received)?;
+        writeln!(f, "Data sent:      {}", human_bytes(self.bytes_sent))?;
+        writeln!(f, "Data received:  {}", human_bytes(self.bytes_received))?;
+        writeln!(f, "Peers seen:     {}", self.peers_seen)?;
+        writeln!(f, "Malformed msgs: {}", self.malformed_received)?;
+        writeln!(f, "Peers banned:   {}", self.peers_banned)?;
+        writeln!(f, "Overwrites:     {} refused", self.overwrites_rejected)?;
+        if self.stores_refused_full > 0 {
+            writeln!(f, "Store full:     {} refused", self.stores_refused_full)?;
+        }
+        if self.messages_dropped > 0 {
+            writeln!(f, "Queue full:     {} dropped", self.messages_dropped)?;
+        }
+        let versions: Vec<_> = self
+            .peer_versions
+            .iter()
+            .map(|(version, peers)| format!("v{}: {}", version, peers))
+            .collect();
+        writeln!(f, "Peer versions:  {}", versions.join(", "))?;
+        writeln!(f, "Delivery delay: {}", self.delivery_delay)?;
+        for (author, delay) in &self.delivery_delay_by_author {
+            writeln!(f, "  @{}: {}", author, delay)?;
+        }
+        for (net_id, traffic) in &self.traffic_by_net {
+            writeln!(
+                f,
+                "{}: {} msgs / {} out, {} msgs / {} in, {} timeouts",
+                net_id,
+                traffic.messages_sent,
+                human_bytes(traffic.bytes_sent),
+                traffic.messages_received,
+                human_bytes(traffic.bytes_received),
+                traffic.timeouts
+            )?;
+        }
+        let pending = &self.pending_requests;
+        if !pending.is_empty() {
+            writeln!(
+                f,
+                "Requests:       {} sent, {} answered, {} timed out, {} evicted, {} leaked",
+                pending.inserted,
+                pending.answered,
+                pending.timed_out,
+                pending.evicted,
+                pending.leaked
+            )?;
+        }
+        write!(
+            f,
+            "Uptime:         {}h {}m {}s",
+            self.uptime / 3600,
+            self.uptime / 60 % 60,
+            self.uptime % 60
+        )
+    }
+}
+
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+// HyperLogLog over peer addresses. Sources are whatever a UDP packet claims,
+// so a set of them would grow with every spoofed one; this stays at
+// PEER_COUNTER_REGISTERS bytes and is off by about 3%.
+const PEER_COUNTER_BITS: u32 = 10;
+const PEER_COUNTER_REGISTERS: usize = 1 << PEER_COUNTER_BITS;
+// peers whose protocol version is kept for Stats::peer_versions
+const PEER_VERSIONS_LIMIT: usize = 4096;
+
+struct PeerCounter {
+    registers: Vec<u8>,
+}
+
+impl PeerCounter {
+    fn new() -> PeerCounter {
+        PeerCounter {
+            registers: vec![0; PEER_COUNTER_REGISTERS],
+        }
+    }
+
+    fn insert(&mut self, peer: SocketAddr) {
+        let mut hasher = DefaultHasher::new();
+        peer.hash(&mut hasher);
+        let h = hasher.finish();
+        let register = (h >> (64 - PEER_COUNTER_BITS)) as usize;
+        // leading zeros of the rest, plus one
+        let rank = ((h << PEER_COUNTER_BITS) | (1 << (PEER_COUNTER_BITS - 1))).leading_zeros() + 1;
+        self.registers[register] = self.registers[register].max(rank as u8);
+    }
+
+    fn estimate(&self) -> u64 {
+        let m = PEER_COUNTER_REGISTERS as f64;
+        let sum: f64 = self.registers.iter().map(|r| 2f64.powi(-(*r as i32))).sum();
+        let estimate = 0.7213 / (1.0 + 1.079 / m) * m * m / sum;
+        let empty = self.registers.iter().filter(|r| **r == 0).count();
+        // few peers: count the empty registers instead
+        if estimate <= 2.5 * m && empty > 0 {
+            (m * (m / empty as f64).ln()).round() as u64
+        } else {
+            estimate.round() as u64
+        }
+    }
+}
+
+// Counters of the running session, on top of the totals loaded at startup.
+pub struct StatsRecorder {
+    base: Mutex<Stats>,
+    started: Instant,
+    posts_sent: AtomicU64,
+    posts_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    malformed_received: AtomicU64,
+    peers_banned: AtomicU64,
+    peers: Mutex<PeerCounter>,
+    peer_versions: Mutex<HashMap<SocketAddr, u32>>,
+    delivery_delay: Mutex<DelayHistogram>,
+    delivery_delay_by_author: Mutex<HashMap<String, DelayHistogram>>,
+    traffic_by_net: Mutex<HashMap<String, NetTraffic>>,
+    overwrites_rejected: AtomicU64,
+    stores_refused_full: AtomicU64,
+    messages_dropped: AtomicU64,
+    pending_requests: Mutex<PendingCounts>,
+}
+
+impl StatsRecorder {
+    pub fn new() -> StatsRecorder {
+        StatsRecorder {
+            base: Mutex::new(Stats::default()),
+            started: Instant::now(),
+            posts_sent: AtomicU64::new(0),
+            posts_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            malformed_received: AtomicU64::new(0),
+            peers_banned: AtomicU64::new(0),
+            peers: Mutex::new(PeerCounter::new()),
+            peer_versions: Mutex::new(HashMap::new()),
+            delivery_delay: Mutex::new(DelayHistogram::default()),
+            delivery_delay_by_author: Mutex::new(HashMap::new()),
+            traffic_by_net: Mutex::new(HashMap::new()),
+            overwrites_rejected: AtomicU64::new(0),
+            stores_refused_full: AtomicU64::new(0),
+            messages_dropped: AtomicU64::new(0),
+            pending_requests: Mutex::new(PendingCounts::default()),
+        }
+    }
+
+    // totals of the previous sessions
+    pub fn set_base(&self, base: Stats) {
+        *self.base.lock().unwrap() = base;
+    }
+
+    pub fn record_post_sent(&self) {
+        self.posts_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_post_received(&self) {
+        self.posts_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_received(&self, bytes: usize, peer: SocketAddr) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.peers.lock().unwrap().insert(peer);
+    }
+
+    pub fn record_malformed(&self) {
+        self.malformed_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_ban(&self) {
+        self.peers_banned.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_rejected_overwrite(&self) {
+        self.overwrites_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_store_full(&self) {
+        self.stores_refused_full.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_dropped(&self) {
+        self.messages_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_pending(&self, event: PendingEvent) {
+        self.pending_requests.lock().unwrap().record(event);
+    }
+
+    // The latest version a peer spoke. Past PEER_VERSIONS_LIMIT peers, one is
+    // forgotten for each new one, so spoofed sources cannot grow the map.
+    pub fn record_peer_version(&self, peer: SocketAddr, version: u32) {
+        let mut peer_versions = self.peer_versions.lock().unwrap();
+        if peer_versions.len() >= PEER_VERSIONS_LIMIT && !peer_versions.contains_key(&peer) {
+            let forgotten = peer_versions.keys().next().copied();
+            if let Some(forgotten) = forgotten {
+                peer_versions.remove(&forgotten);
+            }
+        }
+        peer_versions.insert(peer, version);
+    }
+
+    pub fn record_net_sent(&self, net_id: &str, bytes: usize) {
+        let mut traffic = self.traffic_by_net.lock().unwrap();
+        let traffic = traffic.entry(net_id.to_string()).or_default();
+        traffic.messages_sent += 1;
+        traffic.bytes_sent += bytes as u64;
+    }
+
+    pub fn record_net_received(&self, net_id: &str, bytes: usize) {
+        let mut traffic = self.traffic_by_net.lock().unwrap();
+        let traffic = traffic.entry(net_id.to_string()).or_default();
+        traffic.messages_received += 1;
+        traffic.bytes_received += bytes as u64;
+    }
+
+    pub fn record_net_timeout(&self, net_id: &str) {
+        let mut traffic = self.traffic_by_net.lock().unwrap();
+        traffic.entry(net_id.to_string()).or_default().timeouts += 1;
+    }
+
+    // a verified post of author, delay_ms after it was created
+    pub fn record_delivery(&self, author: &str, delay_ms: u64) {
+        self.delivery_delay.lock().unwrap().record(delay_ms);
+        self.delivery_delay_by_author
+            .lock()
+            .unwrap()
+            .entry(author.to_string())
+            .or_default()
+            .record(delay_ms);
+    }
+
+    pub fn snapshot(&self) -> Stats {
+        let base = self.base.lock().unwrap().clone();
+        let mut peer_versions = base.peer_versions.clone();
+        for version in self.peer_versions.lock().unwrap().values() {
+            *peer_versions.entry(*version).or_insert(0) += 1;
+        }
+        let mut delivery_delay = base.delivery_delay.clone();
+        delivery_delay.merge(&self.delivery_delay.lock().unwrap());
+        let mut delivery_delay_by_author = base.delivery_delay_by_author.clone();
+        for (author, delay) in self.delivery_delay_by_author.lock().unwrap().iter() {
+            delivery_delay_by_author
+                .entry(author.clone())
+                .or_default()
+                .merge(delay);
+        }
+        let mut traffic_by_net = base.traffic_by_net.clone();
+        for (net_id, traffic) in self.traffic_by_net.lock().unwrap().iter() {
+            traffic_by_net
+                .entry(net_id.clone())
+                .or_default()
+                .merge(traffic);
+        }
+        let mut pending_requests = base.pending_requests.clone();
+        pending_requests.merge(&self.pending_requests.lock().unwrap());
+        Stats {
+            posts_sent: base.posts_sent + self.posts_sent.load(Ordering::Relaxed),
+            posts_received: base.posts_received + self.posts_received.load(Ordering::Relaxed),
+            bytes_sent: base.bytes_sent + self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: base.bytes_received + self.bytes_received.load(Ordering::Relaxed),
+            peers_seen: base.peers_seen + self.peers.lock().unwrap().estimate(),
+            uptime: base.uptime + self.started.elapsed().as_secs(),
+            malformed_received: base.malformed_received
+                + self.malformed_received.load(Ordering::Relaxed),
+            peers_banned: base.peers_banned + self.peers_banned.load(Ordering::Relaxed),
+            peer_versions,
+            delivery_delay,
+            delivery_delay_by_author,
+            traffic_by_net,
+            overwrites_rejected: base.overwrites_rejected
+                + self.overwrites_rejected.load(Ordering::Relaxed),
+            stores_refused_full: base.stores_refused_full
+                + self.stores_refused_full.load(Ordering::Relaxed),
+            messages_dropped: base.messages_dropped + self.messages_dropped.load(Ordering::Relaxed),
+            pending_requests,
+        }
+    }
+}
+
+impl Default for StatsRecorder {
+    fn default() -> StatsRecorder {
+        StatsRecorder::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_test() {
+        let recorder = StatsRecorder::new();
+        recorder.set_base(Stats {
+            posts_sent: 2,
+            peers_seen: 5,
+            ..Stats::default()
+        });
+        recorder.record_post_sent();
+        recorder.record_received(100, "127.0.0.1:1".parse().unwrap());
+        recorder.record_received(100, "127.0.0.1:1".parse().unwrap());
+        recorder.record_received(2000, "127.0.0.1:2".parse().unwrap());
+        recorder.record_peer_version("127.0.0.1:1".parse().unwrap(), 0);
+        recorder.record_peer_version("127.0.0.1:1".parse().unwrap(), 1);
+        recorder.record_peer_version("127.0.0.1:2".parse().unwrap(), 1);
+        recorder.record_net_sent("test_user_dht", 300);
+        recorder.record_net_received("test_user_dht", 200);
+        recorder.record_net_timeout("test_pubsub_dht");
+        recorder.record_rejected_overwrite();
+
+        let stats = recorder.snapshot();
+        assert_eq!(stats.posts_sent, 3);
+        assert_eq!(stats.bytes_received, 2200);
+        assert_eq!(stats.peers_seen, 7);
+        assert_eq!(stats.peer_versions.get(&1), Some(&2));
+        assert_eq!(stats.peer_versions.get(&0), None);
+        assert_eq!(human_bytes(stats.bytes_received), "2.1 KiB");
+        assert_eq!(stats.traffic_by_net["test_user_dht"].bytes_sent, 300);
+        assert_eq!(stats.traffic_by_net["test_user_dht"].messages_received, 1);
+        assert_eq!(stats.traffic_by_net["test_pubsub_dht"].timeouts, 1);
+
+        // many spoofed sources are estimated, not kept
+        let recorder = StatsRecorder::new();
+        for port in 0..20000 {
+            recorder.record_received(1, SocketAddr::from(([10, 0, 0, 1], port)));
+        }
+        let peers_seen = recorder.snapshot().peers_seen;
+        assert!((19000..21000).contains(&peers_seen), "{}", peers_seen);
+        for port in 0..20000 {
+            recorder.record_peer_version(SocketAddr::from(([10, 0, 0, 1], port)), 1);
+        }
+        let versions = recorder.snapshot().peer_versions;
+        assert_eq!(versions.get(&1), Some(&(PEER_VERSIONS_LIMIT as u64)));
+        assert_eq!(stats.overwrites_rejected, 1);
+    }
+
+    #[test]
+    fn delivery_delay_test() {
+        let recorder = StatsRecorder::new();
+        let mut base = DelayHistogram::default();
+        base.record(500);
+        recorder.set_base(Stats {
+            delivery_delay: base,
+            ..Stats::default()
+        });
+        for delay in [800, 1500, 1500, 4000] {
+            recorder.record_delivery("alice", delay);
+        }
+        recorder.record_delivery("bob", 90000);
+
+        let stats = recorder.snapshot();
+        assert_eq!(stats.delivery_delay.count(), 6);
+        assert_eq!(stats.delivery_delay.percentile_ms(50), Some(2000));
+        // past the last bucket, the maximum is all there is
+        assert_eq!(stats.delivery_delay.percentile_ms(100), Some(90000));
+        assert_eq!(stats.delivery_delay_by_author["alice"].count(), 4);
+        assert_eq!(stats.delivery_delay_by_author["alice"].mean_ms(), Some(1950));
+    }
+}
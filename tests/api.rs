@@ -0,0 +1,696 @@
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpListener};
+
+use futures::{SinkExt, StreamExt};
+
+use noktulo::api_server::{
+    AdminCommand, ChallengeReply, ChallengeRequest, ErrorCode, GalleryConfig, RestError,
+    SignedAdminCommand, TokenReply, TokenRequest, UserInfo, WireEncoding,
+    REST_CHALLENGES_PER_MINUTE,
+};
+use noktulo::prelude::*;
+use noktulo::kad::Key;
+use noktulo::service::{BackfillEvent, BootstrapAddr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn config(bootstrap: Vec<SocketAddr>) -> (Config, SocketAddr) {
+    let nodeinfo_addr: SocketAddr = format!("127.0.0.1:{}", free_port()).parse().unwrap();
+    let config = Config {
+        bind_addr: "127.0.0.1:0".parse().unwrap(),
+        // the seed shares one socket, the others do not, so both have to work together
+        pubsub_bind_addr: (!bootstrap.is_empty()).then(|| "127.0.0.1:0".parse().unwrap()),
+        nodeinfo_addr: Some(nodeinfo_addr),
+        bootstrap: bootstrap.into_iter().map(BootstrapAddr::from).collect(),
+        bootstrap_lists: Vec::new(),
+        lan_discovery: false,
+        networks: default_networks(),
+        kad: KadConfig::default(),
+        tunables_path: None,
+        port_mapping: false,
+        peer_store_path: None,
+        store_limit: None,
+        bandwidth_limit: None,
+        store_dir: None,
+        rng_seed: None,
+        archive_posts: false,
+        operator_key: None,
+        local_reload: false,
+        node_id_path: None,
+    };
+    (config, nodeinfo_addr)
+}
+
+// a handful of peers on localhost, all bootstrapped from the first one
+async fn simulated_dht(size: usize) -> (Vec<NetworkController>, SocketAddr) {
+    let (seed_config, seed_addr) = config(Vec::new());
+    let mut peers = vec![NetworkController::init(seed_config).await];
+    for _ in 1..size {
+        let (config, _) = config(vec![seed_addr]);
+        peers.push(NetworkController::init(config).await);
+    }
+    (peers, seed_addr)
+}
+
+fn user(name: &str) -> (UserHandle, SecretKey) {
+    let sk = SecretKey::random();
+    let attr = UserAttribute::new(name, 0, "");
+    let signature = sk.sign(&serde_json::to_vec(&attr).unwrap());
+    let sig_attr = SignedUserAttribute::new(sk.public_key().into(), attr, signature);
+    (
+        UserHandle::new(sig_attr, sk.clone(), HashMap::new(), &[]),
+        sk,
+    )
+}
+
+#[tokio::test]
+async fn post_and_subscribe_test() {
+    let (_peers, seed_addr) = simulated_dht(3).await;
+    let (config, _) = config(vec![seed_addr]);
+    let server_addr = ApiServer::new(config)
+        .await
+        .start("127.0.0.1:0".to_string())
+        .await
+        .unwrap();
+    let url = format!("ws://{}", server_addr);
+
+    let (mut alice, alice_sk) = user("alice");
+    let (_, bob_sk) = user("bob");
+
+    let mut alice_client = ApiClient::connect(&url).await.unwrap();
+    let mut bob_client = ApiClient::connect(&url).await.unwrap();
+
+    // nothing is allowed before the handshake
+    assert!(matches!(
+        bob_client.subscribe(alice.addr()).await,
+        Err(ApiClientError::Server(ErrorCode::NotEstablished, _))
+    ));
+
+    // bob reads keys and signatures as base64, alice as numbers
+    bob_client.hello(WireEncoding::Base64, true, false).await.unwrap();
+    alice_client.establish(&alice_sk).await.unwrap();
+    bob_client.establish(&bob_sk).await.unwrap();
+    bob_client.subscribe(alice.addr()).await.unwrap();
+
+    let sigpost = alice
+        .hoot("hello from alice".to_string(), None, None, vec![])
+        .unwrap();
+    alice_client.post(sigpost.clone()).await.unwrap();
+
+    let received = timeout(Duration::from_secs(10), bob_client.recv_post())
+        .await
+        .expect("post was not delivered")
+        .unwrap();
+    assert_eq!(received, sigpost);
+
+    let stats = alice_client.stats().await.unwrap();
+    assert_eq!(stats.posts_sent, 1);
+    assert!(stats.posts_received >= 1);
+
+    // a post signed by someone else is refused
+    let (mut mallory, _) = user("mallory");
+    let forged = mallory
+        .hoot("not alice".to_string(), None, None, vec![])
+        .unwrap();
+    assert!(matches!(
+        alice_client.post(forged).await,
+        Err(ApiClientError::Server(ErrorCode::UnknownAddress, _))
+    ));
+}
+
+// the replies on the wire, without ApiClient: an accepted Post is answered
+// with Success, like the other requests which return nothing
+#[tokio::test]
+async fn post_reply_test() {
+    let (config, _) = config(Vec::new());
+    let server_addr = ApiServer::new(config)
+        .await
+        .start("127.0.0.1:0".to_string())
+        .await
+        .unwrap();
+    let (mut socket, _) = connect_async(format!("ws://{}", server_addr))
+        .await
+        .unwrap();
+    let (mut alice, alice_sk) = user("alice");
+    let establish = ClientMessage::EstablishReq {
+        addr: alice.addr().into(),
+        pubkey: alice_sk.public_key(),
+    };
+    let challenge = match request(&mut socket, establish).await {
+        ServerMessage::Challenge(challenge) => challenge,
+        msg => panic!("{:?}", msg),
+    };
+    let response = ClientMessage::ChallengeResponce(alice_sk.sign(&challenge));
+    assert!(matches!(
+        request(&mut socket, response).await,
+        ServerMessage::Established
+    ));
+
+    let sigpost = alice
+        .hoot("hello from alice".to_string(), None, None, vec![])
+        .unwrap();
+    assert!(matches!(
+        request(&mut socket, ClientMessage::Post(Box::new(sigpost))).await,
+        ServerMessage::Success
+    ));
+}
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+// sends msg and waits for the next message of the server
+async fn request(socket: &mut Socket, msg: ClientMessage) -> ServerMessage {
+    let text = serde_json::to_string(&msg).unwrap();
+    socket.send(Message::Text(text)).await.unwrap();
+    loop {
+        let message = socket.next().await.unwrap();
+        if let Message::Text(text) = message.unwrap() {
+            return serde_json::from_str(&text).unwrap();
+        }
+    }
+}
+
+#[tokio::test]
+async fn history_backfill_test() {
+    let (_peers, seed_addr) = simulated_dht(3).await;
+    let author_net = NetworkController::init(config(vec![seed_addr]).0).await;
+    let follower_net = NetworkController::init(config(vec![seed_addr]).0).await;
+
+    let (mut alice, _) = user("alice");
+    let first = alice.hoot("first".to_string(), None, None, vec![]).unwrap();
+    let entry = alice.history_entry(&first).unwrap();
+    author_net.archive(&entry, &alice.head_record().unwrap().unwrap()).await;
+    let second = alice.hoot("second".to_string(), None, None, vec![]).unwrap();
+    let entry = alice.history_entry(&second).unwrap();
+    author_net.archive(&entry, &alice.head_record().unwrap().unwrap()).await;
+    let third = alice.hoot("third".to_string(), None, None, vec![]).unwrap();
+    let entry = alice.history_entry(&third).unwrap();
+    author_net.archive(&entry, &alice.head_record().unwrap().unwrap()).await;
+    let delete = alice.del(second.post.id).unwrap().unwrap();
+    let entry = alice.history_entry(&delete).unwrap();
+    author_net.archive(&entry, &alice.head_record().unwrap().unwrap()).await;
+
+    let mut events = follower_net.backfill(alice.addr(), 10);
+    let mut progress = 0;
+    let posts = loop {
+        match timeout(Duration::from_secs(60), events.recv()).await.unwrap() {
+            // how long the history is cannot be told up front
+            Some(BackfillEvent::Started { total }) => assert_eq!(total, 10),
+            Some(BackfillEvent::Progress { done, .. }) => progress = done,
+            Some(BackfillEvent::Finished(posts)) => break posts,
+            None => panic!("backfill stopped without finishing"),
+        }
+    };
+    // the deletion, third, the slot of second and first
+    assert_eq!(progress, 4);
+    // the second post and its deletion are left out
+    assert_eq!(posts, vec![first, third]);
+}
+
+#[tokio::test]
+async fn migration_test() {
+    let (_peers, seed_addr) = simulated_dht(3).await;
+    let author_net = NetworkController::init(config(vec![seed_addr]).0).await;
+    let follower_net = NetworkController::init(config(vec![seed_addr]).0).await;
+
+    let (mut alice, _) = user("alice");
+    let old_addr = alice.addr();
+    author_net.register_author(&alice.registration().unwrap()).await;
+    let (record, sigpost, moved) = alice.migrate(SecretKey::random()).unwrap();
+    author_net.register_author(&moved.registration().unwrap()).await;
+    author_net.migrate_account(&record).await;
+
+    // stores are verified in the background, so the record may take a while to land
+    let expected = Some((moved.addr(), moved.pubkey().unwrap()));
+    let resolved = timeout(Duration::from_secs(120), async {
+        loop {
+            let resolved = follower_net
+                .resolve_user(old_addr.clone())
+                .await
+                .map(|user| (user.addr, user.pubkey));
+            if resolved == expected {
+                break resolved;
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    })
+    .await;
+    assert_eq!(resolved.ok().flatten(), expected);
+
+    // followers learn about it from the last post as well
+    let (mut bob, _) = user("bob");
+    bob.followings.insert(old_addr.clone(), Following::default());
+    match &sigpost.post.content {
+        PostKind::Migrate(record) => assert!(bob.apply_migration(record).unwrap()),
+        _ => panic!("not a migration post"),
+    }
+    assert!(!bob.followings.contains_key(&old_addr));
+    assert!(bob.followings.contains_key(&moved.addr()));
+}
+
+#[tokio::test]
+async fn rate_limit_test() {
+    let (_peers, seed_addr) = simulated_dht(3).await;
+    let tunables_path = std::env::temp_dir().join(format!("noktulo-rate-{}.json", free_port()));
+    std::fs::write(&tunables_path, r#"{"posts_per_minute":1}"#).unwrap();
+    let (mut config, _) = config(vec![seed_addr]);
+    config.tunables_path = Some(tunables_path.clone());
+    let server_addr = ApiServer::new(config)
+        .await
+        .start("127.0.0.1:0".to_string())
+        .await
+        .unwrap();
+    let url = format!("ws://{}", server_addr);
+
+    let (mut alice, alice_sk) = user("alice");
+    let mut alice_client = ApiClient::connect(&url).await.unwrap();
+    alice_client.establish(&alice_sk).await.unwrap();
+    let first = alice.hoot("first".to_string(), None, None, vec![]).unwrap();
+    alice_client.post(first).await.unwrap();
+    let second = alice.hoot("second".to_string(), None, None, vec![]).unwrap();
+    assert!(matches!(
+        alice_client.post(second).await,
+        Err(ApiClientError::Server(ErrorCode::RateLimited, _))
+    ));
+    std::fs::remove_file(tunables_path).unwrap();
+}
+
+async fn http_get(addr: SocketAddr, path: &str) -> String {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes())
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn gallery_test() {
+    let (_peers, seed_addr) = simulated_dht(3).await;
+    let author_net = NetworkController::init(config(vec![seed_addr]).0).await;
+    let server = ApiServer::new(config(vec![seed_addr]).0).await;
+    let gallery_addr = server
+        .start_gallery("127.0.0.1:0".to_string(), GalleryConfig::default())
+        .await
+        .unwrap();
+
+    let (mut alice, _) = user("alice");
+    author_net.register_author(&alice.registration().unwrap()).await;
+    let sigpost = alice
+        .hoot("<b>public</b> hello".to_string(), None, None, vec![])
+        .unwrap();
+    let entry = alice.history_entry(&sigpost).unwrap();
+    author_net
+        .archive(&entry, &alice.head_record().unwrap().unwrap())
+        .await;
+
+    let page = timeout(
+        Duration::from_secs(120),
+        http_get(gallery_addr, &format!("/u/{}", alice.addr().to_string())),
+    )
+    .await
+    .unwrap();
+    assert!(page.starts_with("HTTP/1.1 200 OK"));
+    assert!(page.contains("X-Robots-Tag: noindex"));
+    assert!(page.contains("&lt;b&gt;public&lt;/b&gt; hello"));
+
+    let robots = http_get(gallery_addr, "/robots.txt").await;
+    assert!(robots.ends_with("Disallow: /\n"));
+    let invalid = http_get(gallery_addr, "/u/not-an-address").await;
+    assert!(invalid.starts_with("HTTP/1.1 400"));
+}
+
+#[tokio::test]
+async fn unsubscribe_and_user_info_test() {
+    let (_peers, seed_addr) = simulated_dht(3).await;
+    let author_net = NetworkController::init(config(vec![seed_addr]).0).await;
+    let server_addr = ApiServer::new(config(vec![seed_addr]).0)
+        .await
+        .start("127.0.0.1:0".to_string())
+        .await
+        .unwrap();
+    let url = format!("ws://{}", server_addr);
+
+    let (mut alice, alice_sk) = user("alice");
+    let (_, bob_sk) = user("bob");
+    let attr = UserAttribute::new("alice", 0, "");
+    let signature = alice_sk.sign(&attr.signed_message());
+    let profile = SignedUserAttribute::new(alice.addr(), attr.clone(), signature);
+    author_net.register_profile(&alice.pubkey().unwrap(), &profile).await;
+
+    let mut alice_client = ApiClient::connect(&url).await.unwrap();
+    let mut bob_client = ApiClient::connect(&url).await.unwrap();
+    alice_client.establish(&alice_sk).await.unwrap();
+    bob_client.establish(&bob_sk).await.unwrap();
+
+    // stores are verified in the background, so the profile may take a while
+    // to land; lookups are rate limited, so they are spaced out
+    let info = timeout(Duration::from_secs(120), async {
+        loop {
+            match bob_client.user_info(alice.addr()).await {
+                Ok(info) => break info,
+                Err(ApiClientError::Server(ErrorCode::NotFound, _)) => {
+                    tokio::time::sleep(Duration::from_secs(3)).await
+                }
+                Err(e) => panic!("{}", e),
+            }
+        }
+    })
+    .await
+    .expect("profile was not found");
+    assert_eq!(info.addr, alice.addr());
+    assert_eq!(info.attr, attr);
+
+    assert_eq!(info.stats, None);
+
+    // what the server verified of alice comes with her profile
+    bob_client.subscribe(alice.addr()).await.unwrap();
+    let sigpost = alice.hoot("hello".to_string(), None, None, vec![]).unwrap();
+    alice_client.post(sigpost.clone()).await.unwrap();
+    timeout(Duration::from_secs(10), bob_client.recv_post())
+        .await
+        .expect("post was not delivered");
+    let stats = timeout(Duration::from_secs(10), async {
+        loop {
+            if let Some(stats) = bob_client.user_info(alice.addr()).await.unwrap().stats {
+                break stats;
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    })
+    .await
+    .expect("no stats for alice");
+    assert_eq!(stats.posts, 1);
+    assert_eq!(stats.last_post_at, sigpost.post.created_at);
+
+    bob_client.unsubscribe(alice.addr()).await.unwrap();
+    let sigpost = alice
+        .hoot("nobody is listening".to_string(), None, None, vec![])
+        .unwrap();
+    alice_client.post(sigpost.clone()).await.unwrap();
+    // copies of the earlier post may still come in, the new one does not
+    let delivered = timeout(Duration::from_secs(5), async {
+        while let Some(received) = bob_client.recv_post().await {
+            if received == sigpost {
+                return;
+            }
+        }
+        std::future::pending::<()>().await
+    })
+    .await;
+    assert!(delivered.is_err());
+}
+
+#[tokio::test]
+async fn resume_test() {
+    let (_peers, seed_addr) = simulated_dht(3).await;
+    let server_addr = ApiServer::new(config(vec![seed_addr]).0)
+        .await
+        .start("127.0.0.1:0".to_string())
+        .await
+        .unwrap();
+    let url = format!("ws://{}", server_addr);
+
+    let (mut alice, alice_sk) = user("alice");
+    let (_, bob_sk) = user("bob");
+
+    let mut alice_client = ApiClient::connect(&url).await.unwrap();
+    let mut bob_client = ApiClient::connect(&url).await.unwrap();
+    alice_client.establish(&alice_sk).await.unwrap();
+    assert_eq!(alice_client.session(), None);
+    bob_client.hello(WireEncoding::Numbers, false, true).await.unwrap();
+    bob_client.establish(&bob_sk).await.unwrap();
+    bob_client.subscribe(alice.addr()).await.unwrap();
+    let (token, _) = bob_client.session().cloned().expect("no session token");
+    bob_client.close().await.unwrap();
+    // the server saves the session once it reads the close
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    // no handshake and no subscribing again
+    let mut bob_client = ApiClient::connect(&url).await.unwrap();
+    bob_client.resume(&token).await.unwrap();
+    assert_ne!(bob_client.session().unwrap().0, token);
+    let sigpost = alice.hoot("welcome back".to_string(), None, None, vec![]).unwrap();
+    alice_client.post(sigpost.clone()).await.unwrap();
+    let received = timeout(Duration::from_secs(10), bob_client.recv_post())
+        .await
+        .expect("post was not delivered")
+        .unwrap();
+    assert_eq!(received, sigpost);
+
+    let mut mallory_client = ApiClient::connect(&url).await.unwrap();
+    assert!(matches!(
+        mallory_client.resume("bm90IGEgdG9rZW4=").await,
+        Err(ApiClientError::Server(ErrorCode::InvalidToken, _))
+    ));
+}
+
+#[tokio::test]
+async fn disconnect_test() {
+    let (_peers, seed_addr) = simulated_dht(3).await;
+    let server = ApiServer::new(config(vec![seed_addr]).0).await;
+    let net = server.controller();
+    let server_addr = server.start("127.0.0.1:0".to_string()).await.unwrap();
+    let url = format!("ws://{}", server_addr);
+
+    let (alice, _) = user("alice");
+    let (_, bob_sk) = user("bob");
+
+    let mut bob_client = ApiClient::connect(&url).await.unwrap();
+    bob_client.establish(&bob_sk).await.unwrap();
+    bob_client.subscribe(alice.addr()).await.unwrap();
+    bob_client.subscribe_filtered(alice.addr(), "is_reply").await.unwrap();
+    assert!(net.subscriptions().await.is_subscribed(&alice.addr()).await);
+
+    // the subscription stops with the connection, without waiting for a post
+    bob_client.close().await.unwrap();
+    timeout(Duration::from_secs(10), async {
+        while net.subscriptions().await.is_subscribed(&alice.addr()).await {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .expect("still subscribed after the client left");
+}
+
+#[tokio::test]
+async fn admin_test() {
+    let operator = SecretKey::random();
+    let (mut config, _) = config(Vec::new());
+    config.operator_key = Some(operator.public_key());
+    // commands are signed for the node id kept here
+    let node_id_path = std::env::temp_dir().join(format!("noktulo-admin-{}", free_port()));
+    std::fs::write(&node_id_path, [7u8; 32]).unwrap();
+    config.node_id_path = Some(node_id_path.clone());
+    let node_id = Key::from([7u8; 32]);
+    let server_addr = ApiServer::new(config)
+        .await
+        .start("127.0.0.1:0".to_string())
+        .await
+        .unwrap();
+    let url = format!("ws://{}", server_addr);
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let sign = |command, issued_at| {
+        SignedAdminCommand::new(command, node_id.clone(), issued_at, &operator).unwrap()
+    };
+
+    // no handshake, the signature is what counts
+    let mut client = ApiClient::connect(&url).await.unwrap();
+    let ban = sign(AdminCommand::BanPeer("203.0.113.7".parse().unwrap()), now_ms);
+    assert!(matches!(client.admin(ban.clone()).await, Ok(ServerMessage::Success)));
+    match client.admin(sign(AdminCommand::DumpStats, now_ms + 1)).await {
+        Ok(ServerMessage::Stats(stats)) => assert_eq!(stats.peers_banned, 1),
+        reply => panic!("{:?}", reply),
+    }
+    assert!(matches!(
+        client.admin(sign(AdminCommand::RotateLogs, now_ms + 2)).await,
+        Err(ApiClientError::Server(ErrorCode::Unsupported, _))
+    ));
+
+    // replayed, or signed by another key
+    assert!(matches!(
+        client.admin(ban).await,
+        Err(ApiClientError::Server(ErrorCode::StaleCommand, _))
+    ));
+    let (_, mallory_sk) = user("mallory");
+    let forged =
+        SignedAdminCommand::new(AdminCommand::DumpStats, node_id.clone(), now_ms + 3, &mallory_sk);
+    assert!(matches!(
+        client.admin(forged.unwrap()).await,
+        Err(ApiClientError::Server(ErrorCode::InvalidSignature, _))
+    ));
+    // signed for another node
+    let other_node = Key::from([8u8; 32]);
+    let elsewhere =
+        SignedAdminCommand::new(AdminCommand::DumpStats, other_node, now_ms + 3, &operator);
+    assert!(matches!(
+        client.admin(elsewhere.unwrap()).await,
+        Err(ApiClientError::Server(ErrorCode::OtherNode, _))
+    ));
+
+    // connecting from loopback is not enough unless Config::local_reload says so
+    assert!(matches!(
+        client.reload_config().await,
+        Err(ApiClientError::Server(ErrorCode::Forbidden, _))
+    ));
+    std::fs::remove_file(node_id_path).unwrap();
+}
+
+// the status line and the body of the response
+async fn http_request(
+    addr: SocketAddr,
+    request: &str,
+    token: Option<&str>,
+    body: &str,
+) -> (String, String) {
+    let auth = token.map_or(String::new(), |t| {
+        format!("Authorization: Bearer {}\r\n", t)
+    });
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(
+            format!(
+                "{} HTTP/1.1\r\nHost: localhost\r\n{}Content-Length: {}\r\n\r\n{}",
+                request,
+                auth,
+                body.len(),
+                body
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    (head.lines().next().unwrap().to_string(), body.to_string())
+}
+
+#[tokio::test]
+async fn rest_test() {
+    let (_peers, seed_addr) = simulated_dht(3).await;
+    let author_net = NetworkController::init(config(vec![seed_addr]).0).await;
+    let server = ApiServer::new(config(vec![seed_addr]).0).await;
+    let rest_addr = server.start_rest("127.0.0.1:0".to_string()).await.unwrap();
+
+    let (mut alice, alice_sk) = user("alice");
+    let attr = UserAttribute::new("alice", 0, "");
+    let signature = alice_sk.sign(&attr.signed_message());
+    let profile = SignedUserAttribute::new(alice.addr(), attr.clone(), signature);
+    author_net.register_profile(&alice.pubkey().unwrap(), &profile).await;
+    let first = alice
+        .hoot("archived".to_string(), None, None, vec![])
+        .unwrap();
+    let entry = alice.history_entry(&first).unwrap();
+    author_net
+        .archive(&entry, &alice.head_record().unwrap().unwrap())
+        .await;
+
+    let alice_path = alice.addr().to_string().replace('/', "%2F");
+    let user_path = format!("GET /users/{}", alice_path);
+    let (status, body) = http_request(rest_addr, &user_path, None, "").await;
+    assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+    let error: RestError = serde_json::from_str(&body).unwrap();
+    assert_eq!(error.code, ErrorCode::NotEstablished);
+
+    // a token for signing a challenge, like establishing a connection
+    let req = serde_json::to_string(&ChallengeRequest {
+        pubkey: alice.pubkey().unwrap(),
+    })
+    .unwrap();
+    let (_, body) = http_request(rest_addr, "POST /challenge", None, &req).await;
+    let ChallengeReply { nonce, challenge } = serde_json::from_str(&body).unwrap();
+    // asking again leaves the first challenge pending
+    let (status, _) = http_request(rest_addr, "POST /challenge", None, &req).await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    let req = serde_json::to_string(&TokenRequest {
+        nonce,
+        pubkey: alice.pubkey().unwrap(),
+        signature: alice_sk.sign(&challenge),
+    })
+    .unwrap();
+    let (status, body) = http_request(rest_addr, "POST /token", None, &req).await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    let TokenReply { token, .. } = serde_json::from_str(&body).unwrap();
+    let token = Some(token.as_str());
+    // the challenge is used up
+    let (status, _) = http_request(rest_addr, "POST /token", None, &req).await;
+    assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+    // and a peer only gets so many
+    let req = serde_json::to_string(&ChallengeRequest {
+        pubkey: alice.pubkey().unwrap(),
+    })
+    .unwrap();
+    for _ in 2..REST_CHALLENGES_PER_MINUTE {
+        let (status, _) = http_request(rest_addr, "POST /challenge", None, &req).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+    }
+    let (status, _) = http_request(rest_addr, "POST /challenge", None, &req).await;
+    assert_eq!(status, "HTTP/1.1 429 Too Many Requests");
+
+    // stores are verified in the background, so they may take a while to land
+    let info = timeout(Duration::from_secs(120), async {
+        loop {
+            let (status, body) = http_request(rest_addr, &user_path, token, "").await;
+            if status == "HTTP/1.1 200 OK" {
+                break serde_json::from_str::<UserInfo>(&body).unwrap();
+            }
+            assert_eq!(status, "HTTP/1.1 404 Not Found");
+            tokio::time::sleep(Duration::from_secs(3)).await;
+        }
+    })
+    .await
+    .expect("profile was not found");
+    assert_eq!(info.attr, attr);
+
+    let feed_path = format!("GET /feed/{}", alice_path);
+    let posts = timeout(Duration::from_secs(120), async {
+        loop {
+            let (_, body) = http_request(rest_addr, &feed_path, token, "").await;
+            let posts: Vec<SignedPost> = serde_json::from_str(&body).unwrap();
+            if !posts.is_empty() {
+                break posts;
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    })
+    .await
+    .expect("feed stayed empty");
+    assert_eq!(posts, vec![first.clone()]);
+    let since = format!("{}?since={}", feed_path, first.post.id);
+    let (_, body) = http_request(rest_addr, &since, token, "").await;
+    assert!(serde_json::from_str::<Vec<SignedPost>>(&body)
+        .unwrap()
+        .is_empty());
+
+    let sigpost = alice
+        .hoot("over http".to_string(), None, None, vec![])
+        .unwrap();
+    let body = serde_json::to_string(&sigpost).unwrap();
+    let (status, _) = http_request(rest_addr, "POST /posts", token, &body).await;
+    assert_eq!(status, "HTTP/1.1 201 Created");
+    let (mut mallory, _) = user("mallory");
+    let other = mallory
+        .hoot("not alice".to_string(), None, None, vec![])
+        .unwrap();
+    let body = serde_json::to_string(&other).unwrap();
+    let (status, _) = http_request(rest_addr, "POST /posts", token, &body).await;
+    assert_eq!(status, "HTTP/1.1 403 Forbidden");
+    let (status, _) = http_request(rest_addr, "DELETE /posts", token, "").await;
+    assert_eq!(status, "HTTP/1.1 405 Method Not Allowed");
+}
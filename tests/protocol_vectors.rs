@@ -0,0 +1,89 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use noktulo::api_server::{ClientMessage, ServerMessage, SignedAdminCommand};
+use noktulo::crypto::SecretKey;
+use noktulo::kad::RpcMessage;
+use noktulo::user::post::{PostKind, SignedPost};
+
+// Golden messages in tests/vectors, one compact json document per file.
+// Each one must decode and encode back to exactly the same bytes, since
+// signatures are made over the json encoding.
+fn vectors(kind: &str) -> Vec<(PathBuf, String)> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/vectors")
+        .join(kind);
+    let mut ret: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .map(|path| {
+            let content = fs::read_to_string(&path).unwrap();
+            (path, content.trim_end().to_string())
+        })
+        .collect();
+    ret.sort();
+    assert!(!ret.is_empty(), "no vectors in {:?}", dir);
+    ret
+}
+
+fn round_trip<T: Serialize + DeserializeOwned>(kind: &str) -> Vec<T> {
+    vectors(kind)
+        .into_iter()
+        .map(|(path, content)| {
+            let decoded: T = serde_json::from_str(&content)
+                .unwrap_or_else(|e| panic!("{:?} does not decode: {}", path, e));
+            assert_eq!(
+                serde_json::to_string(&decoded).unwrap(),
+                content,
+                "{:?} does not encode back to the same bytes",
+                path
+            );
+            decoded
+        })
+        .collect()
+}
+
+#[test]
+fn rpc_message_vectors() {
+    round_trip::<RpcMessage>("rpc_message");
+}
+
+#[test]
+fn signed_post_vectors() {
+    // every vector is signed with the secret key 0x0101..01
+    let pubkey = SecretKey::from_bytes(&[1; 32]).public_key();
+    for sigpost in round_trip::<SignedPost>("signed_post") {
+        sigpost.verify(&pubkey).unwrap();
+        if let PostKind::ReHoot(inner) = &sigpost.post.content {
+            // and the posts they carry with 0x0202..02
+            let other = SecretKey::from_bytes(&[2; 32]).public_key();
+            inner.verify(&other).unwrap();
+        }
+        if let PostKind::Migrate(record) = &sigpost.post.content {
+            // moving to 0x0202..02
+            record.verify().unwrap();
+        }
+    }
+}
+
+#[test]
+fn client_message_vectors() {
+    // admin commands are signed with the secret key 0x0101..01, for the node
+    // 0x0303..03
+    let operator = SecretKey::from_bytes(&[1; 32]).public_key();
+    for msg in round_trip::<ClientMessage>("client_message") {
+        if let ClientMessage::Admin(signed) = msg {
+            let message =
+                SignedAdminCommand::signed_message(&signed.command, &signed.node_id, signed.issued_at);
+            operator.verify(&signed.signature, &message).unwrap();
+        }
+    }
+}
+
+#[test]
+fn server_message_vectors() {
+    round_trip::<ServerMessage>("server_message");
+}